@@ -1,7 +1,14 @@
+use crate::{
+    metadata::{put_varint, Metadata},
+    signer::{signed_envelope, Signer, SignerError},
+};
+use cid::Cid;
 use forest_ipld::Ipld;
 use libp2p::{
-    core::{signed_envelope, SignedEnvelope},
-    identity::Keypair,
+    core::{
+        signed_envelope::{DecodingError, ReadPayloadError},
+        SignedEnvelope,
+    },
     PeerId,
 };
 use multihash::MultihashDigest;
@@ -14,16 +21,6 @@ pub const MAX_ENTRIES: usize = 6250000;
 const AD_SIGNATURE_CODEC: &'static str = "/indexer/ingest/adSignature";
 const AD_SIGNATURE_DOMAIN: &'static str = "indexer";
 
-#[allow(non_snake_case)]
-#[derive(Serialize)]
-struct Metadata {
-    // ProtocolID defines the protocol used for data retrieval.
-    ProtocolID: u128,
-    // Data is specific to the identified protocol, and provides data, or a
-    // link to data, necessary for retrieval.
-    Data: Vec<u8>,
-}
-
 #[allow(non_snake_case)]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Advertisement {
@@ -45,15 +42,38 @@ pub struct Advertisement {
     pub Metadata: Ipld,
     /// IsRm specifies whether this advertisement represents the content are no longer retrievable fom the provider.
     pub IsRm: bool,
+    /// ExtendedProvider lists further endpoints serving the advertised content.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ExtendedProvider: Option<ExtendedProvider>,
+}
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+/// ExtendedProvider lets a single advertisement announce several retrieval endpoints,
+/// e.g. the bitswap peer and an http gateway of the same operator.
+pub struct ExtendedProvider {
+    /// Providers of the content, each signing the advertisement with its own key.
+    pub Providers: Vec<ExtendedProviderEntry>,
+    /// Override specifies whether the providers replace those of earlier advertisements
+    /// with the same ContextID instead of being added to them.
+    pub Override: bool,
+}
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExtendedProviderEntry {
+    /// ID is the peer ID of the provider.
+    pub ID: String,
+    /// Addresses is the list of multiaddrs as strings the provider is reachable on.
+    pub Addresses: Vec<String>,
+    /// Metadata captures how to retrieve the content from this provider.
+    pub Metadata: Ipld,
+    /// Signature of the advertisement by the provider.
+    pub Signature: Ipld,
 }
 impl Advertisement {
     pub fn new(context_id: Vec<u8>, provider: PeerId, addresses: Vec<String>, is_rm: bool) -> Self {
-        // prtocolid for bitswap
-        let raw_metadata = Metadata {
-            ProtocolID: 0x0900,
-            Data: vec![],
-        };
-        let metadata = bincode::serialize(&raw_metadata).unwrap();
+        let metadata = Metadata::bitswap().encode();
 
         Self {
             PreviousID: None,
@@ -64,16 +84,101 @@ impl Advertisement {
             Metadata: Ipld::Bytes(metadata),
             ContextID: Ipld::Bytes(context_id),
             IsRm: is_rm,
+            ExtendedProvider: None,
+        }
+    }
+
+    /// Replace the retrieval protocols of the main provider, bitswap by default.
+    pub fn with_metadata(mut self, metadata: &Metadata) -> Self {
+        self.Metadata = Ipld::Bytes(metadata.encode());
+        self
+    }
+
+    /// Advertise the content as retrievable from `provider` too, with its own
+    /// retrieval `metadata`.
+    pub fn add_extended_provider(
+        &mut self,
+        provider: PeerId,
+        addresses: Vec<String>,
+        metadata: &Metadata,
+    ) {
+        self.ExtendedProvider
+            .get_or_insert_with(Default::default)
+            .Providers
+            .push(ExtendedProviderEntry {
+                ID: provider.to_base58(),
+                Addresses: addresses,
+                Metadata: Ipld::Bytes(metadata.encode()),
+                Signature: Ipld::Bytes(vec![]),
+            });
+    }
+
+    /// Sign the advertisement as every extended provider, with the key among `keys`
+    /// matching its peer id.
+    pub async fn sign_extended_providers<K: Signer>(
+        &mut self,
+        keys: &[K],
+    ) -> Result<(), AdSigError> {
+        let payload = self.sig_payload()?;
+        let providers = match &mut self.ExtendedProvider {
+            Some(extended) => &mut extended.Providers,
+            None => return Ok(()),
+        };
+
+        for provider in providers.iter_mut() {
+            let key = keys
+                .iter()
+                .find(|key| key.peer_id().to_base58() == provider.ID)
+                .ok_or_else(|| AdSigError::MissingExtendedProviderKey(provider.ID.clone()))?;
+            let envelope = signed_envelope(
+                key,
+                AD_SIGNATURE_DOMAIN,
+                AD_SIGNATURE_CODEC.as_bytes(),
+                &payload,
+            )
+            .await
+            .map_err(AdSigError::SigningError)?;
+            provider.Signature = Ipld::Bytes(envelope);
+        }
+        Ok(())
+    }
+
+    /// Cid of the previous advertisement in the chain, if any.
+    pub fn previous(&self) -> Option<Cid> {
+        match &self.PreviousID {
+            Some(Ipld::Link(link)) => Some(*link),
+            _ => None,
         }
     }
-    pub fn sign(&self, signing_key: &Keypair) -> Result<SignedEnvelope, AdSigError> {
-        Ok(SignedEnvelope::new(
-            signing_key,
-            AD_SIGNATURE_DOMAIN.into(),
-            AD_SIGNATURE_CODEC.into(),
-            self.sig_payload()?,
+
+    /// Protobuf encoded envelope of the signature of the advertisement by `signer`.
+    pub async fn sign<S: Signer + ?Sized>(&self, signer: &S) -> Result<Vec<u8>, AdSigError> {
+        signed_envelope(
+            signer,
+            AD_SIGNATURE_DOMAIN,
+            AD_SIGNATURE_CODEC.as_bytes(),
+            &self.sig_payload()?,
         )
-        .map_err(AdSigError::SigningError)?)
+        .await
+        .map_err(AdSigError::SigningError)
+    }
+
+    /// Check the signature of the advertisement was made by `signer` over its current
+    /// fields.
+    pub fn verify(&self, signer: &PeerId) -> Result<(), AdSigError> {
+        let sig = match &self.Signature {
+            Ipld::Bytes(sig) if !sig.is_empty() => sig,
+            _ => return Err(AdSigError::MissingSig),
+        };
+        let envelope =
+            SignedEnvelope::from_protobuf_encoding(sig).map_err(AdSigError::DecodingError)?;
+        let (payload, key) = envelope
+            .payload_and_signing_key(AD_SIGNATURE_DOMAIN.into(), AD_SIGNATURE_CODEC.as_bytes())
+            .map_err(AdSigError::ReadPayloadError)?;
+        if key.to_peer_id() != *signer || payload != self.sig_payload()?.as_slice() {
+            return Err(AdSigError::PayloadDidNotMatch);
+        }
+        Ok(())
     }
 
     /// computes a signature over all of these fields
@@ -116,10 +221,35 @@ impl Advertisement {
         payload.extend_from_slice(metadata);
         payload.extend_from_slice(&is_rm_payload);
 
+        // the extended providers are covered by every signature of the advertisement.
+        // The fields above keep the concatenated layout indexers check, those below are
+        // each prefixed with their length so no two lists of providers sign the same
+        if let Some(extended) = &self.ExtendedProvider {
+            payload.push(if extended.Override { 1 } else { 0 });
+            put_varint(&mut payload, extended.Providers.len() as u64);
+            for provider in &extended.Providers {
+                let metadata = match &provider.Metadata {
+                    Ipld::Bytes(b) => b,
+                    _ => return Err(AdSigError::InvalidMetadata),
+                };
+                put_field(&mut payload, provider.ID.as_bytes());
+                put_varint(&mut payload, provider.Addresses.len() as u64);
+                for address in &provider.Addresses {
+                    put_field(&mut payload, address.as_bytes());
+                }
+                put_field(&mut payload, metadata);
+            }
+        }
+
         Ok(multihash::Code::Sha2_256.digest(&payload).to_bytes())
     }
 }
 
+fn put_field(payload: &mut Vec<u8>, field: &[u8]) {
+    put_varint(payload, field.len() as u64);
+    payload.extend_from_slice(field);
+}
+
 #[derive(Debug, Error)]
 pub enum AdSigError {
     #[error("Invalid Previous ID")]
@@ -130,12 +260,14 @@ pub enum AdSigError {
     InvalidMetadata,
     #[error("Missing Signature")]
     MissingSig,
+    #[error("Missing key of extended provider {0}")]
+    MissingExtendedProviderKey(String),
     #[error("Failed to sign advertisement: {0}")]
-    SigningError(libp2p::identity::error::SigningError),
+    SigningError(SignerError),
     #[error("Failed to decode sig: {0}")]
-    DecodingError(signed_envelope::DecodingError),
+    DecodingError(DecodingError),
     #[error("Failed to read signed payload: {0}")]
-    ReadPayloadError(signed_envelope::ReadPayloadError),
+    ReadPayloadError(ReadPayloadError),
     #[error("Payload did not match expected")]
     PayloadDidNotMatch,
 }
@@ -159,4 +291,72 @@ impl EntryChunk {
             Next: next,
         }
     }
+
+    pub fn entries(&self) -> &[Ipld] {
+        &self.Entries
+    }
+
+    /// Cid of the next chunk in the chain, if any.
+    pub fn next(&self) -> Option<Cid> {
+        match &self.Next {
+            Some(Ipld::Link(link)) => Some(*link),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::{gateway_address, Transport};
+    use libp2p::identity::Keypair;
+
+    #[async_std::test]
+    async fn test_sign_extended_providers() {
+        let keypair = Keypair::generate_ed25519();
+        let gateway = Keypair::generate_ed25519();
+        let peer_id = PeerId::from(keypair.public());
+
+        let mut ad = Advertisement::new(b"ursa".to_vec(), peer_id, vec![], false);
+        let unsigned = ad.sig_payload().unwrap();
+        ad.add_extended_provider(peer_id, vec![], &Metadata::bitswap());
+        ad.add_extended_provider(
+            PeerId::from(gateway.public()),
+            gateway_address("https://gateway.ursa.earth")
+                .into_iter()
+                .collect(),
+            &Metadata::default().with(Transport::Http),
+        );
+        assert_ne!(ad.sig_payload().unwrap(), unsigned);
+
+        assert!(matches!(
+            ad.sign_extended_providers(&[keypair.clone()]).await,
+            Err(AdSigError::MissingExtendedProviderKey(_))
+        ));
+        ad.sign_extended_providers(&[keypair.clone(), gateway.clone()])
+            .await
+            .unwrap();
+        ad.Signature = Ipld::Bytes(ad.sign(&keypair).await.unwrap());
+        ad.verify(&peer_id).unwrap();
+        assert!(ad.verify(&PeerId::from(gateway.public())).is_err());
+        for provider in &ad.ExtendedProvider.unwrap().Providers {
+            assert!(matches!(&provider.Signature, Ipld::Bytes(sig) if !sig.is_empty()));
+        }
+    }
+
+    #[test]
+    fn test_extended_providers_payload_is_unambiguous() {
+        let peer_id = PeerId::from(Keypair::generate_ed25519().public());
+        let payload = |addresses: &[&str]| {
+            let mut ad = Advertisement::new(b"ursa".to_vec(), peer_id, vec![], false);
+            let addresses = addresses.iter().map(|s| s.to_string()).collect();
+            ad.add_extended_provider(peer_id, addresses, &Metadata::bitswap());
+            ad.sig_payload().unwrap()
+        };
+        assert_ne!(
+            payload(&["/ip4/1.2.3.4", "/tcp/80"]),
+            payload(&["/ip4/1.2.3.4/tcp/80"])
+        );
+        assert_ne!(payload(&["/ip4/1.2.3.4", ""]), payload(&["/ip4/1.2.3.4"]));
+    }
 }
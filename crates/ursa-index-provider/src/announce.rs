@@ -0,0 +1,144 @@
+//! Indexer announcement tracking.
+//!
+//! Http announcements are retried with exponential backoff until the indexer accepts
+//! them or the retry budget runs out. Every configured indexer has its own
+//! [`AnnounceTracker`] keeping the pending and failed announcements around, so
+//! `ursa_provider_status` can tell publishers whether their content actually reached
+//! each indexer.
+
+use cid::Cid;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+
+/// Failed announcements kept for reporting.
+const MAX_FAILED: usize = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingAnnounce {
+    /// Head advertisement the announcement points to.
+    pub cid: Option<String>,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FailedAnnounce {
+    pub cid: Option<String>,
+    pub attempts: u32,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnnounceStatus {
+    /// Latest published advertisement.
+    pub head: Option<String>,
+    pub indexers: Vec<IndexerStatus>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexerStatus {
+    pub url: String,
+    /// Latest advertisement the indexer accepted an announcement for.
+    pub last_announced: Option<String>,
+    pub pending: Vec<PendingAnnounce>,
+    /// Announcements that ran out of retries, oldest first.
+    pub failed: Vec<FailedAnnounce>,
+}
+
+#[derive(Default)]
+pub struct AnnounceTracker {
+    next_id: u64,
+    pending: BTreeMap<u64, PendingAnnounce>,
+    failed: VecDeque<FailedAnnounce>,
+    last_announced: Option<String>,
+}
+
+impl AnnounceTracker {
+    /// Track a new announcement of `cid`.
+    pub fn push(&mut self, cid: Option<Cid>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.insert(
+            id,
+            PendingAnnounce {
+                cid: cid.map(|cid| cid.to_string()),
+                attempts: 0,
+                last_error: None,
+            },
+        );
+        id
+    }
+
+    /// Whether announcement `id` still needs to be made.
+    pub fn is_pending(&self, id: u64) -> bool {
+        self.pending.contains_key(&id)
+    }
+
+    pub fn retrying(&mut self, id: u64, error: String) {
+        if let Some(pending) = self.pending.get_mut(&id) {
+            pending.attempts += 1;
+            pending.last_error = Some(error);
+        }
+    }
+
+    /// Record that the indexer accepted announcement `id`.
+    ///
+    /// Older pending announcements are dropped, the indexer reaches their
+    /// advertisements by walking the chain down from the newer head.
+    pub fn succeeded(&mut self, id: u64) {
+        if let Some(pending) = self.pending.remove(&id) {
+            self.last_announced = pending.cid;
+        }
+        self.pending = self.pending.split_off(&id);
+    }
+
+    pub fn failed(&mut self, id: u64, error: String) {
+        if let Some(pending) = self.pending.remove(&id) {
+            self.failed.push_back(FailedAnnounce {
+                cid: pending.cid,
+                attempts: pending.attempts + 1,
+                error,
+            });
+            if self.failed.len() > MAX_FAILED {
+                self.failed.pop_front();
+            }
+        }
+    }
+
+    pub fn status(&self, url: &str) -> IndexerStatus {
+        IndexerStatus {
+            url: url.to_string(),
+            last_announced: self.last_announced.clone(),
+            pending: self.pending.values().cloned().collect(),
+            failed: self.failed.iter().cloned().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_newer_announce_supersedes_pending() {
+        let cid =
+            Cid::from_str("bafkreif2opfibjypwkjzzry3jbibcjqcjwnpoqpeiqw75eu3s3u3zbdszq").unwrap();
+        let mut tracker = AnnounceTracker::default();
+
+        let old = tracker.push(None);
+        tracker.retrying(old, "connection refused".to_string());
+        let new = tracker.push(Some(cid));
+        assert_eq!(tracker.status("").pending.len(), 2);
+
+        tracker.succeeded(new);
+        assert!(!tracker.is_pending(old));
+        let status = tracker.status("");
+        assert_eq!(status.last_announced, Some(cid.to_string()));
+        assert!(status.pending.is_empty());
+
+        let failing = tracker.push(Some(cid));
+        tracker.failed(failing, "bad gateway".to_string());
+        assert_eq!(tracker.status("").failed[0].attempts, 1);
+    }
+}
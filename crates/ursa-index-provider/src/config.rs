@@ -1,20 +1,122 @@
+use crate::metadata::Transport;
 use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, OneOrMany};
 use std::path::PathBuf;
 
 const DEFAULT_DB_PATH_STR: &str = ".ursa/data/index_provider_db";
 
+#[serde_as]
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
 pub struct ProviderConfig {
     /// local address
     pub local_address: String,
     /// port where provider is listening
     pub port: u16,
-    /// a domain where provider is listening dns/test-node.provider.ursa.earth
+    /// a domain where provider is listening dns/test-node.provider.ursa.earth, announced
+    /// next to the public and listen addresses of the node, empty to announce only those
     pub domain: String,
-    /// indexer url to point to e.g. https://dev.cid.contact
-    pub indexer_url: String,
-    /// database_path for index provider db
+    /// indexer urls to announce to e.g. https://dev.cid.contact, a single url or a list
+    #[serde_as(as = "OneOrMany<_>")]
+    pub indexer_url: Vec<String>,
+    /// database the advertisements were kept in before they moved to the provider_ads
+    /// column family of the node database, imported once on start
     pub database_path: PathBuf,
+    /// pem file of the key advertisements and the signed head are signed with, created
+    /// if missing. The libp2p identity of the node is used when unset
+    pub key_path: Option<PathBuf>,
+    /// sign the `/head` cid under the ursa signature domain instead of bare, as IPNI
+    /// indexers expect it, for providers mirrored by ursa nodes only
+    pub head_domain: bool,
+    /// signing service holding the provider key, e.g. in front of an HSM, used
+    /// instead of key_path when set
+    pub signer: Option<RemoteSignerConfig>,
+    /// how new advertisements are announced to the indexer
+    pub announce: AnnounceMode,
+    /// indexer network, announced on the `/indexer/ingest/<network>` gossipsub topic
+    pub network: String,
+    /// times a failed http announcement is retried
+    pub announce_retries: u32,
+    /// delay in milliseconds before the first retry, doubled after every attempt
+    pub announce_backoff_ms: u64,
+    /// most multihashes stored in a single entry chunk
+    pub max_chunk_entries: usize,
+    /// window in milliseconds root cids are collected in before they are advertised
+    /// together, zero publishes every root right away
+    pub publish_batch_ms: u64,
+    /// advertisements in the chain after which it is compacted into a snapshot, zero
+    /// never compacts
+    pub compact_after: usize,
+    /// keep the advertisements of compacted chains in the provider db for audit
+    pub retain_compacted_ads: bool,
+    /// retrieval protocols the node is advertised with, `bitswap` or `http` when its
+    /// trustless gateway is reachable on the announced addresses
+    pub transports: Vec<Transport>,
+    /// further endpoints serving the content of the node, e.g. an https gateway of the
+    /// same operator, advertised next to the node as extended providers
+    pub extended_providers: Vec<ExtendedProviderConfig>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ExtendedProviderConfig {
+    /// pem file of the key of the provider, created if missing. Every advertisement
+    /// is signed with it on behalf of the provider
+    pub key_path: PathBuf,
+    /// multiaddrs the provider is reachable on
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    /// https url of a trustless gateway of the provider, announced as one more address
+    #[serde(default)]
+    pub gateway_url: Option<String>,
+    /// retrieval protocols of the provider, `bitswap` or `http`
+    #[serde(default = "default_extended_transports")]
+    pub transports: Vec<Transport>,
+}
+
+fn default_extended_transports() -> Vec<Transport> {
+    vec![Transport::Http]
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RemoteSignerConfig {
+    /// base url of the signing service
+    pub url: String,
+    /// id of the provider key within the service
+    pub key_id: String,
+    /// bearer token sent to the service
+    #[serde(default)]
+    pub token: Option<String>,
+    /// milliseconds a signature may take
+    #[serde(default = "default_signer_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_signer_timeout_ms() -> u64 {
+    5_000
+}
+
+impl ProviderConfig {
+    /// gossipsub topic indexers listen to for announcements
+    pub fn announce_topic(&self) -> String {
+        format!("/indexer/ingest/{}", self.network)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AnnounceMode {
+    /// Put the announcement to the indexer http endpoint.
+    Http,
+    /// Gossip the announcement, falling back to http when gossiping fails.
+    Gossipsub,
+    /// Gossip the announcement and put it to the http endpoint.
+    Both,
+}
+
+impl Default for AnnounceMode {
+    fn default() -> Self {
+        AnnounceMode::Both
+    }
 }
 
 impl Default for ProviderConfig {
@@ -23,8 +125,21 @@ impl Default for ProviderConfig {
             local_address: "0.0.0.0".to_string(),
             port: 8070,
             domain: "".to_string(),
-            indexer_url: "https://dev.cid.contact".to_string(),
+            indexer_url: vec!["https://dev.cid.contact".to_string()],
             database_path: PathBuf::from(env!("HOME")).join(DEFAULT_DB_PATH_STR),
+            key_path: None,
+            head_domain: false,
+            signer: None,
+            announce: AnnounceMode::default(),
+            network: "mainnet".to_string(),
+            announce_retries: 8,
+            announce_backoff_ms: 1_000,
+            max_chunk_entries: 16_384,
+            publish_batch_ms: 5_000,
+            compact_after: 10_000,
+            retain_compacted_ads: false,
+            transports: vec![Transport::Bitswap],
+            extended_providers: vec![],
         }
     }
 }
@@ -1,4 +1,7 @@
 pub mod advertisement;
+pub mod announce;
 pub mod config;
+pub mod metadata;
 pub mod provider;
 pub mod signed_head;
+pub mod signer;
@@ -0,0 +1,157 @@
+//! Advertisement metadata.
+//!
+//! The `Metadata` of an advertisement lists the protocols the content can be retrieved
+//! with, each as a varint multicodec code followed by its protocol specific data, so
+//! indexer clients can pick a retrieval method they support. Whether http goes over
+//! libp2p or to a plain https gateway follows from the provider addresses, see
+//! [`gateway_address`].
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Multicodec of `transport-bitswap`.
+const TRANSPORT_BITSWAP: u64 = 0x0900;
+/// Multicodec of `transport-ipfs-gateway-http`.
+const TRANSPORT_IPFS_GATEWAY_HTTP: u64 = 0x0920;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    /// Bitswap over libp2p.
+    Bitswap,
+    /// Trustless ipfs gateway http, over libp2p or at an https url.
+    Http,
+}
+
+impl Transport {
+    fn code(&self) -> u64 {
+        match self {
+            Transport::Bitswap => TRANSPORT_BITSWAP,
+            Transport::Http => TRANSPORT_IPFS_GATEWAY_HTTP,
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum MetadataError {
+    #[error("Truncated varint in metadata")]
+    TruncatedVarint,
+    #[error("Unknown transport code {0:#x} in metadata")]
+    UnknownTransport(u64),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    transports: Vec<Transport>,
+}
+
+impl Metadata {
+    /// Metadata of content retrievable over bitswap only.
+    pub fn bitswap() -> Self {
+        Self::default().with(Transport::Bitswap)
+    }
+
+    pub fn with(mut self, transport: Transport) -> Self {
+        if !self.transports.contains(&transport) {
+            self.transports.push(transport);
+        }
+        self
+    }
+
+    pub fn transports(&self) -> &[Transport] {
+        &self.transports
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        // neither transport carries protocol specific data
+        for transport in &self.transports {
+            put_varint(&mut buf, transport.code());
+        }
+        buf
+    }
+
+    pub fn decode(mut bytes: &[u8]) -> Result<Self, MetadataError> {
+        let mut metadata = Self::default();
+        while !bytes.is_empty() {
+            let transport = match read_varint(&mut bytes)? {
+                TRANSPORT_BITSWAP => Transport::Bitswap,
+                TRANSPORT_IPFS_GATEWAY_HTTP => Transport::Http,
+                code => return Err(MetadataError::UnknownTransport(code)),
+            };
+            metadata = metadata.with(transport);
+        }
+        Ok(metadata)
+    }
+}
+
+impl FromIterator<Transport> for Metadata {
+    fn from_iter<I: IntoIterator<Item = Transport>>(transports: I) -> Self {
+        transports
+            .into_iter()
+            .fold(Self::default(), |metadata, transport| {
+                metadata.with(transport)
+            })
+    }
+}
+
+/// Provider address of the https gateway at `url`, e.g. `https://gateway.ursa.earth`
+/// becomes `/dns/gateway.ursa.earth/tcp/443/https`.
+pub fn gateway_address(url: &str) -> Option<String> {
+    let authority = url.strip_prefix("https://")?.split('/').next()?;
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse::<u16>().ok()?),
+        None => (authority, 443),
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some(format!("/dns/{host}/tcp/{port}/https"))
+}
+
+pub(crate) fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Result<u64, MetadataError> {
+    let mut value = 0;
+    for (i, byte) in bytes.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            *bytes = &bytes[i + 1..];
+            return Ok(value);
+        }
+    }
+    Err(MetadataError::TruncatedVarint)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_encoding() {
+        assert_eq!(Metadata::bitswap().encode(), vec![0x80, 0x12]);
+
+        let metadata = Metadata::bitswap().with(Transport::Http);
+        assert_eq!(Metadata::decode(&metadata.encode()), Ok(metadata.clone()));
+        let collected: Metadata = [Transport::Bitswap, Transport::Http, Transport::Bitswap]
+            .into_iter()
+            .collect();
+        assert_eq!(collected, metadata);
+        assert_eq!(
+            Metadata::decode(&[0x80]),
+            Err(MetadataError::TruncatedVarint)
+        );
+
+        assert_eq!(
+            gateway_address("https://gateway.ursa.earth/ipfs"),
+            Some("/dns/gateway.ursa.earth/tcp/443/https".to_string())
+        );
+        assert_eq!(gateway_address("http://gateway.ursa.earth"), None);
+    }
+}
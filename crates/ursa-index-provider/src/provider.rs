@@ -1,7 +1,10 @@
 use crate::{
     advertisement::{self, EntryChunk},
-    config::ProviderConfig,
+    announce::{AnnounceStatus, AnnounceTracker},
+    config::{AnnounceMode, ProviderConfig},
+    metadata::{gateway_address, Metadata},
     signed_head::SignedHead,
+    signer::Signer,
 };
 
 use advertisement::Advertisement;
@@ -9,11 +12,12 @@ use anyhow::{anyhow, Error, Result};
 use async_std::{
     self,
     sync::{Arc, RwLock},
+    task,
 };
 use async_trait::async_trait;
 use axum::{
     body::Body,
-    extract::Path,
+    extract::{Path, Query},
     http::StatusCode,
     response::{IntoResponse, Response},
     routing::get,
@@ -23,28 +27,47 @@ use cid::Cid;
 use forest_encoding::Cbor;
 use forest_ipld::Ipld;
 use ipld_blockstore::{BlockStore, BlockStoreExt};
-use libipld::codec::Encode;
+use libipld::codec::{Codec, Encode};
 use libipld_cbor::DagCborCodec;
-use libp2p::{identity::Keypair, Multiaddr, PeerId};
-use multihash::Code;
+use libp2p::{identity::Keypair, multiaddr::Protocol, Multiaddr, PeerId};
+use multihash::{Code, MultihashDigest};
 use rand;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     io::Write,
     str::FromStr,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
 };
 use tracing::{error, info, warn};
-use ursa_utils::convert_cid;
+
+/// Advertisements listed by `/ads` when no limit is given.
+const DEFAULT_ADS_LIMIT: usize = 100;
+/// Most advertisements listed by a single `/ads` request.
+const MAX_ADS_LIMIT: usize = 1000;
+/// Longest wait between two attempts of an http announcement.
+const MAX_ANNOUNCE_BACKOFF: Duration = Duration::from_secs(300);
+/// Key the head of the ad chain is kept under, next to the advertisements.
+const HEAD_KEY: &[u8] = b"provider/head";
+/// Key the length of the ad chain since it was last compacted is kept under.
+const CHAIN_LEN_KEY: &[u8] = b"provider/chain_len";
+/// Prefix of the keys the context id of a grouped root cid is kept under.
+const CONTEXT_KEY: &[u8] = b"provider/context/";
+/// Prefix of the keys the root cids grouped under a context id are kept under.
+const CONTEXT_ROOTS_KEY: &[u8] = b"provider/context_roots/";
 
 // handlers
 async fn head<S: BlockStore + Sync + Send + 'static>(
     Extension(state): Extension<Provider<S>>,
 ) -> Result<Json<SignedHead>, ProviderError> {
     if let Some(head) = *state.head.read().await {
-        let signed_head = SignedHead::new(&state.keypair, head)
-            .map_err(|e| return ProviderError::InternalError(anyhow!(e.to_string())))?;
+        let signed_head = match state.config.head_domain {
+            true => SignedHead::new_with_domain(&*state.signer, head).await,
+            false => SignedHead::new(&*state.signer, head).await,
+        }
+        .map_err(|e| return ProviderError::InternalError(anyhow!(e.to_string())))?;
         Ok(Json(signed_head))
     } else {
         Err(ProviderError::NotFoundError(anyhow!("No head found")))
@@ -56,7 +79,7 @@ async fn get_block<S: BlockStore + Sync + Send + 'static>(
     Path(cid): Path<String>,
 ) -> Result<Response<Body>, ProviderError> {
     let cid = Cid::from_str(&cid)
-        .map_err(|e| return ProviderError::InternalError(anyhow!(e.to_string())))?;
+        .map_err(|e| return ProviderError::BadRequestError(anyhow!(e.to_string())))?;
     let store = state.blockstore.read().await;
     match store.get_bytes(&cid) {
         Ok(Some(d)) => Ok(Response::builder().body(Body::from(d)).unwrap()),
@@ -65,12 +88,70 @@ async fn get_block<S: BlockStore + Sync + Send + 'static>(
     }
 }
 
+#[derive(Deserialize)]
+struct AdsQuery {
+    limit: Option<usize>,
+    /// List the advertisements published before this one, from the head when unset.
+    before: Option<String>,
+}
+
+async fn list_ads<S: BlockStore + Sync + Send + 'static>(
+    Extension(state): Extension<Provider<S>>,
+    Query(query): Query<AdsQuery>,
+) -> Result<Json<AdsPage>, ProviderError> {
+    let limit = query.limit.unwrap_or(DEFAULT_ADS_LIMIT).min(MAX_ADS_LIMIT);
+    let start = match query.before {
+        Some(before) => {
+            let cid = Cid::from_str(&before)
+                .map_err(|e| return ProviderError::BadRequestError(anyhow!(e.to_string())))?;
+            state
+                .advertisement(&cid)
+                .await
+                .map_err(ProviderError::InternalError)?
+                .ok_or_else(|| ProviderError::NotFoundError(anyhow!("Advertisement not found")))?
+                .previous()
+        }
+        None => *state.head.read().await,
+    };
+
+    state
+        .ads_page(start, limit)
+        .await
+        .map(Json)
+        .map_err(ProviderError::InternalError)
+}
+
+/// A page of the advertisement chain, newest first.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct AdsPage {
+    pub ads: Vec<String>,
+    /// Pass as `before` to list the next page, unset once the chain is exhausted.
+    pub next: Option<String>,
+}
+
 pub struct Provider<S> {
     head: Arc<RwLock<Option<Cid>>>,
     root_cids: Arc<RwLock<VecDeque<Cid>>>,
-    keypair: Keypair,
+    /// Context ids no longer stored, to announce as removed on the next publish.
+    removed_contexts: Arc<RwLock<VecDeque<Vec<u8>>>>,
+    /// Whether a publish is waiting on its batching window.
+    batch_pending: Arc<AtomicBool>,
+    /// Batching window in milliseconds, `publish_batch_ms` until changed.
+    batch_ms: Arc<AtomicU64>,
+    /// Advertisements in the chain since it was last compacted.
+    chain_len: Arc<AtomicUsize>,
+    /// Whether the head was loaded from the store and is yet to be announced.
+    restored: Arc<AtomicBool>,
+    /// Key the advertisements and the head are signed with.
+    signer: Arc<dyn Signer>,
+    /// Keys of the extended providers advertised next to this node.
+    extended_keys: Arc<Vec<Keypair>>,
     blockstore: Arc<RwLock<S>>,
     temp_ads: Arc<RwLock<HashMap<usize, Advertisement>>>,
+    /// Announcement state of every indexer, by url.
+    announcements: Arc<RwLock<BTreeMap<String, AnnounceTracker>>>,
+    /// Addresses the node is reachable on, kept up to date by the network service.
+    network_addrs: Arc<RwLock<Vec<Multiaddr>>>,
     config: Arc<ProviderConfig>,
 }
 
@@ -80,24 +161,596 @@ where
 {
     pub fn new(keypair: Keypair, blockstore: Arc<RwLock<S>>, config: ProviderConfig) -> Self {
         Provider {
-            keypair,
+            signer: Arc::new(keypair),
+            extended_keys: Arc::new(vec![]),
             root_cids: Arc::new(RwLock::new(VecDeque::new())),
+            removed_contexts: Arc::new(RwLock::new(VecDeque::new())),
+            batch_pending: Arc::new(AtomicBool::new(false)),
+            batch_ms: Arc::new(AtomicU64::new(config.publish_batch_ms)),
+            chain_len: Arc::new(AtomicUsize::new(0)),
+            restored: Arc::new(AtomicBool::new(false)),
             blockstore,
             head: Arc::new(RwLock::new(None)),
             temp_ads: Arc::new(RwLock::new(HashMap::new())),
+            announcements: Arc::new(RwLock::new(
+                config
+                    .indexer_url
+                    .iter()
+                    .map(|url| (url.clone(), AnnounceTracker::default()))
+                    .collect(),
+            )),
+            network_addrs: Arc::new(RwLock::new(vec![])),
             config: Arc::new(config),
         }
     }
 
+    /// Sign the advertisements and the head with `signer` instead of the key given to
+    /// [`Provider::new`], e.g. a [`RemoteSigner`](crate::signer::RemoteSigner).
+    pub fn with_signer(mut self, signer: Arc<dyn Signer>) -> Self {
+        self.signer = signer;
+        self
+    }
+
+    /// Keys of `config.extended_providers`, in the same order, signing the published
+    /// advertisements on their behalf.
+    pub fn with_extended_keys(mut self, keys: Vec<Keypair>) -> Self {
+        self.extended_keys = Arc::new(keys);
+        self
+    }
+
+    /// Replace the addresses of the node the provider http server is announced on,
+    /// returning whether they changed.
+    pub async fn set_network_addrs(&self, addrs: Vec<Multiaddr>) -> bool {
+        let mut network_addrs = self.network_addrs.write().await;
+        if *network_addrs == addrs {
+            return false;
+        }
+        info!("provider network addresses changed to {:?}", addrs);
+        *network_addrs = addrs;
+        true
+    }
+
+    /// Latest published advertisement.
+    pub async fn head(&self) -> Option<Cid> {
+        *self.head.read().await
+    }
+
+    /// Peer id advertisements are published under, that of the signing key.
+    pub fn peer_id(&self) -> PeerId {
+        self.signer.peer_id()
+    }
+
+    /// Pick the ad chain up where the last run of the node left it, returning its
+    /// head.
+    ///
+    /// The length of the chain is kept next to the head. Chains written before it was
+    /// are walked once to count their advertisements.
+    pub async fn load_head(&self) -> Result<Option<Cid>> {
+        let bs = self.blockstore.read().await;
+        let cid = match bs.read(HEAD_KEY).map_err(|e| anyhow!(e.to_string()))? {
+            Some(bytes) => Cid::try_from(bytes.as_slice())?,
+            None => return Ok(None),
+        };
+        let stored_len = bs
+            .read(CHAIN_LEN_KEY)
+            .map_err(|e| anyhow!(e.to_string()))?
+            .and_then(|bytes| Some(u64::from_be_bytes(bytes.try_into().ok()?) as usize));
+        let len = match stored_len {
+            Some(len) => {
+                if !bs
+                    .exists(cid.to_bytes())
+                    .map_err(|e| anyhow!(e.to_string()))?
+                {
+                    return Err(anyhow!("Advertisement {} missing from the chain", cid));
+                }
+                len
+            }
+            None => {
+                let mut len = 0;
+                let mut current = Some(cid);
+                while let Some(cid) = current {
+                    let ad: Advertisement = bs
+                        .get_obj(&cid)
+                        .map_err(|e| anyhow!(e.to_string()))?
+                        .ok_or_else(|| {
+                        anyhow!("Advertisement {} missing from the chain", cid)
+                    })?;
+                    current = ad.previous();
+                    len += 1;
+                }
+                len
+            }
+        };
+        *self.head.write().await = Some(cid);
+        self.chain_len.store(len, Ordering::SeqCst);
+        self.restored.store(true, Ordering::SeqCst);
+        info!(
+            "loaded the ad chain of {} advertisements, head {}",
+            len, cid
+        );
+        Ok(Some(cid))
+    }
+
+    /// Whether the head loaded by [`Provider::load_head`] still has to be announced,
+    /// true only once.
+    pub fn take_restored(&self) -> bool {
+        self.restored.swap(false, Ordering::SeqCst)
+    }
+
+    /// Publish the ad chain signed by `previous` again under the key of this
+    /// provider, returning the new head.
+    ///
+    /// Every advertisement is checked against `previous` before it is signed again,
+    /// and those naming `previous` as their provider name this provider instead. The
+    /// entry chunks are shared by both chains, the advertisements of the old chain are
+    /// kept for the indexers still reading it. Like [`Provider::compact`], the chain is
+    /// signed without holding the head, and the new one dropped when a publish moved
+    /// the head meanwhile.
+    pub async fn republish(&self, previous: &Keypair) -> Result<Option<Cid>> {
+        let start = *self.head.read().await;
+        let previous_id = PeerId::from(previous.public());
+
+        let mut chain = vec![];
+        {
+            let bs = self.blockstore.read().await;
+            let mut current = start;
+            while let Some(cid) = current {
+                let ad: Advertisement = bs
+                    .get_obj(&cid)
+                    .map_err(|e| anyhow!(e.to_string()))?
+                    .ok_or_else(|| anyhow!("Advertisement {} missing from the chain", cid))?;
+                ad.verify(&previous_id).map_err(|e| {
+                    anyhow!(
+                        "Advertisement {} is not signed by {}: {}",
+                        cid,
+                        previous_id,
+                        e
+                    )
+                })?;
+                current = ad.previous();
+                chain.push(ad);
+            }
+        }
+        if chain.is_empty() {
+            return Ok(None);
+        }
+
+        let (from, to) = (previous_id.to_base58(), self.peer_id().to_base58());
+        let mut keys = self.signers();
+        keys.push(Arc::new(previous.clone()));
+        let mut written = HashSet::new();
+        let signed: Result<Option<Cid>> = async {
+            let mut republished: Option<Cid> = None;
+            for mut ad in chain.into_iter().rev() {
+                if ad.Provider == from {
+                    ad.Provider = to.clone();
+                }
+                ad.PreviousID = republished.map(Ipld::Link);
+                ad.sign_extended_providers(&keys).await?;
+                ad.Signature = Ipld::Bytes(ad.sign(&*self.signer).await?);
+                let cid = self
+                    .blockstore
+                    .write()
+                    .await
+                    .put_obj(&forest_ipld::to_ipld(&ad)?, Code::Blake2b256)?;
+                written.insert(cid);
+                republished = Some(cid);
+            }
+            Ok(republished)
+        }
+        .await;
+        let republished = match signed {
+            Ok(republished) => republished,
+            Err(e) => {
+                self.delete_blocks(written.iter()).await;
+                return Err(e);
+            }
+        };
+
+        {
+            let mut head = self.head.write().await;
+            if *head != start {
+                drop(head);
+                self.delete_blocks(written.iter()).await;
+                return Err(anyhow!(
+                    "the ad chain grew while it was republished, republish it again"
+                ));
+            }
+            if let Some(cid) = republished {
+                write_head(
+                    &*self.blockstore.write().await,
+                    cid,
+                    self.chain_len.load(Ordering::SeqCst),
+                )?;
+            }
+            *head = republished;
+        }
+        if let Some(cid) = republished {
+            info!(
+                "republished the ad chain of {} under {}, head {}",
+                from, to, cid
+            );
+        }
+        Ok(republished)
+    }
+
+    pub fn announce_mode(&self) -> AnnounceMode {
+        self.config.announce
+    }
+
+    pub fn announce_topic(&self) -> String {
+        self.config.announce_topic()
+    }
+
     pub fn get_mut_root_cids(&self) -> Arc<RwLock<VecDeque<Cid>>> {
         Arc::clone(&self.root_cids)
     }
 
+    pub fn get_mut_removed_contexts(&self) -> Arc<RwLock<VecDeque<Vec<u8>>>> {
+        Arc::clone(&self.removed_contexts)
+    }
+
+    /// Queue a removal advertisement for the content under `root_cid`, dropping it
+    /// from the roots waiting to be advertised.
+    ///
+    /// A removal covers the whole context, so the other roots grouped with `root_cid`
+    /// are advertised again after it.
+    pub async fn remove_root_cid(&self, root_cid: Cid) -> Result<()> {
+        let (context_id, siblings) = {
+            let bs = self.blockstore.write().await;
+            ungroup(&*bs, &root_cid)?.unwrap_or_else(|| (root_cid.to_bytes(), vec![]))
+        };
+
+        let mut root_cids = self.root_cids.write().await;
+        root_cids.retain(|cid| *cid != root_cid);
+        for cid in siblings {
+            if !root_cids.contains(&cid) {
+                root_cids.push_back(cid);
+            }
+        }
+        drop(root_cids);
+        self.queue_removal(context_id).await;
+        Ok(())
+    }
+
+    pub fn batch_window(&self) -> Duration {
+        Duration::from_millis(self.batch_ms.load(Ordering::SeqCst))
+    }
+
+    /// Collect the roots queued from now on for `window`, zero publishing them right
+    /// away.
+    pub fn set_batch_window(&self, window: Duration) {
+        self.batch_ms
+            .store(window.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    /// Claim the next publish, false when one is already waiting on its batching window.
+    pub fn begin_batch(&self) -> bool {
+        self.batch_ms.load(Ordering::SeqCst) == 0
+            || self
+                .batch_pending
+                .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+    }
+
+    /// Let the roots queued from now on start a new batch.
+    pub fn end_batch(&self) {
+        self.batch_pending.store(false, Ordering::SeqCst);
+    }
+
+    /// Drain the queued root cids, grouped by the context id they are advertised under.
+    ///
+    /// Ungrouped roots queued together are put under a context of their own, derived
+    /// from all of them, so the whole batch fits a single advertisement. The roots are
+    /// queued again when their contexts cannot be read.
+    pub async fn take_batch(&self) -> Result<Vec<(Vec<u8>, Vec<Cid>)>> {
+        let roots: Vec<Cid> = self.root_cids.write().await.drain(..).collect();
+        let batches = {
+            let bs = self.blockstore.write().await;
+            group(&*bs, &roots)
+        };
+        if batches.is_err() {
+            let mut root_cids = self.root_cids.write().await;
+            for cid in roots.into_iter().rev() {
+                root_cids.push_front(cid);
+            }
+        }
+        batches
+    }
+
+    /// Advertise the content of `ad` as retrievable from the extended providers of the
+    /// config too, listing the node first so indexers keep it as a provider as well.
+    fn extend(&self, ad: &mut Advertisement) {
+        if ad.IsRm || self.config.extended_providers.is_empty() {
+            return;
+        }
+        let addresses = ad.Addresses.clone();
+        ad.add_extended_provider(self.peer_id(), addresses, &self.metadata());
+        for (config, key) in self
+            .config
+            .extended_providers
+            .iter()
+            .zip(self.extended_keys.iter())
+        {
+            let mut addresses = config.addresses.clone();
+            addresses.extend(config.gateway_url.as_deref().and_then(gateway_address));
+            let metadata = config.transports.iter().copied().collect();
+            ad.add_extended_provider(key.public().to_peer_id(), addresses, &metadata);
+        }
+    }
+
+    /// Retrieval protocols of the node, from `config.transports`.
+    fn metadata(&self) -> Metadata {
+        self.config.transports.iter().copied().collect()
+    }
+
+    /// Signers of the extended providers, the main one first.
+    fn signers(&self) -> Vec<Arc<dyn Signer>> {
+        let mut signers = vec![Arc::clone(&self.signer)];
+        for key in self.extended_keys.iter() {
+            signers.push(Arc::new(key.clone()));
+        }
+        signers
+    }
+
+    async fn queue_removal(&self, context_id: Vec<u8>) {
+        let mut removed = self.removed_contexts.write().await;
+        if !removed.contains(&context_id) {
+            removed.push_back(context_id);
+        }
+    }
+
+    async fn advertisement(&self, cid: &Cid) -> Result<Option<Advertisement>> {
+        let store = self.blockstore.read().await;
+        store.get_obj(cid).map_err(|e| anyhow!(e.to_string()))
+    }
+
+    /// Walk at most `limit` advertisements down the chain, starting at `start`.
+    pub async fn ads_page(&self, start: Option<Cid>, limit: usize) -> Result<AdsPage> {
+        let mut ads = vec![];
+        let mut current = start;
+        while let Some(cid) = current {
+            if ads.len() == limit {
+                break;
+            }
+            let ad = self
+                .advertisement(&cid)
+                .await?
+                .ok_or_else(|| anyhow!("Advertisement {} missing from the chain", cid))?;
+            ads.push(cid.to_string());
+            current = ad.previous();
+        }
+
+        Ok(AdsPage {
+            next: current.and(ads.last().cloned()),
+            ads,
+        })
+    }
+
+    /// Whether the ad chain grew past `compact_after` advertisements.
+    pub fn needs_compaction(&self) -> bool {
+        self.config.compact_after > 0
+            && self.chain_len.load(Ordering::SeqCst) > self.config.compact_after
+    }
+
+    /// Replace the ad chain by a snapshot of it, returning the new head.
+    ///
+    /// The snapshot holds a single advertisement per context id: the latest one, with
+    /// every entry advertised under the context since it was last removed. Removed
+    /// contexts are kept as removal advertisements, so indexers still holding their
+    /// entries drop them. The old chain is deleted unless `retain_compacted_ads` is
+    /// set. Returns `None` when the chain has nothing to compact.
+    pub async fn compact(&self) -> Result<Option<Cid>> {
+        // the snapshot is built without holding the head, so publishes go on meanwhile,
+        // and dropped when one of them moved the head before it is swapped in
+        let start = *self.head.read().await;
+        let chain = {
+            let bs = self.blockstore.read().await;
+            let mut chain = vec![];
+            let mut current = start;
+            while let Some(cid) = current {
+                let ad: Advertisement = bs
+                    .get_obj(&cid)
+                    .map_err(|e| anyhow!(e.to_string()))?
+                    .ok_or_else(|| anyhow!("Advertisement {} missing from the chain", cid))?;
+                current = ad.previous();
+                chain.push((cid, ad));
+            }
+            chain
+        };
+
+        // latest advertisement and live entry chunks of every context, oldest first
+        let mut live: Vec<(Ipld, Advertisement, Vec<Cid>)> = vec![];
+        for (_, ad) in chain.iter().rev() {
+            let pos = match live
+                .iter()
+                .position(|(context, ..)| *context == ad.ContextID)
+            {
+                Some(pos) => pos,
+                None => {
+                    live.push((ad.ContextID.clone(), ad.clone(), vec![]));
+                    live.len() - 1
+                }
+            };
+            let (_, latest, chunks) = &mut live[pos];
+            if ad.IsRm || latest.IsRm {
+                chunks.clear();
+            }
+            *latest = ad.clone();
+            chunks.extend(entries_link(ad));
+        }
+        let compacted = live.len();
+        if chain.len() <= compacted {
+            return Ok(None);
+        }
+
+        let mut kept = HashSet::new();
+        let snapshot = match self.write_snapshot(live, &mut kept).await {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                self.delete_blocks(kept.iter()).await;
+                return Err(e);
+            }
+        };
+
+        {
+            let mut head = self.head.write().await;
+            if *head != start {
+                drop(head);
+                info!("the ad chain grew while it was compacted, compacting it later");
+                self.delete_blocks(kept.iter()).await;
+                return Ok(None);
+            }
+            if let Some(cid) = snapshot {
+                write_head(&*self.blockstore.write().await, cid, compacted)?;
+            }
+            *head = snapshot;
+            self.chain_len.store(compacted, Ordering::SeqCst);
+        }
+        info!(
+            "compacted {} advertisements into {}",
+            chain.len(),
+            compacted
+        );
+
+        if !self.config.retain_compacted_ads {
+            // ads may share chunks, collect them all before deleting any
+            let mut stale = HashSet::new();
+            {
+                let bs = self.blockstore.read().await;
+                for (cid, ad) in &chain {
+                    stale.insert(*cid);
+                    if let Some(first) = entries_link(ad) {
+                        stale.extend(entry_chunks(&*bs, first)?.into_iter().map(|(cid, _)| cid));
+                    }
+                }
+            }
+            self.delete_blocks(stale.difference(&kept)).await;
+        }
+        Ok(snapshot)
+    }
+
+    /// Write the advertisements of the `live` contexts as a new chain, returning its
+    /// head. Every block written is added to `kept`, for its caller to delete the
+    /// snapshot when it is not used. The store is only held for each write, not while
+    /// the advertisements are signed.
+    async fn write_snapshot(
+        &self,
+        live: Vec<(Ipld, Advertisement, Vec<Cid>)>,
+        kept: &mut HashSet<Cid>,
+    ) -> Result<Option<Cid>> {
+        let keys = self.signers();
+        let mut previous: Option<Cid> = None;
+        for (_, mut ad, chunks) in live {
+            let mut seen = HashSet::new();
+            let mut entries = vec![];
+            {
+                let bs = self.blockstore.read().await;
+                for first in chunks {
+                    for (_, chunk) in entry_chunks(&*bs, first)? {
+                        for entry in chunk.entries() {
+                            if let Ipld::Bytes(mh) = entry {
+                                if seen.insert(mh.clone()) {
+                                    entries.push(entry.clone());
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            ad.Entries = None;
+            for entries in entries.chunks(self.config.max_chunk_entries.max(1)) {
+                let chunk = EntryChunk::new(entries.to_vec(), ad.Entries.clone());
+                let cid = self
+                    .blockstore
+                    .write()
+                    .await
+                    .put_obj(&chunk, Code::Blake2b256)?;
+                kept.insert(cid);
+                ad.Entries = Some(Ipld::Link(cid));
+            }
+            ad.PreviousID = previous.map(Ipld::Link);
+            ad.sign_extended_providers(&keys).await?;
+            ad.Signature = Ipld::Bytes(ad.sign(&*self.signer).await?);
+            let cid = self
+                .blockstore
+                .write()
+                .await
+                .put_obj(&forest_ipld::to_ipld(&ad)?, Code::Blake2b256)?;
+            kept.insert(cid);
+            previous = Some(cid);
+        }
+        Ok(previous)
+    }
+
+    /// Delete `cids` from the provider db, logging the failures.
+    async fn delete_blocks<'a>(&self, cids: impl Iterator<Item = &'a Cid>) {
+        for cid in cids {
+            if let Err(e) = self.blockstore.write().await.delete(cid.to_bytes()) {
+                warn!("failed to delete {} from the provider db: {}", cid, e);
+            }
+        }
+    }
+
+    /// Put `announce_msg` to the indexer at `url`, retrying until it is accepted.
+    async fn announce_to(&self, url: String, head: Option<Cid>, announce_msg: Vec<u8>) {
+        let id = match self.announcements.write().await.get_mut(&url) {
+            Some(tracker) => tracker.push(head),
+            None => return,
+        };
+        let mut backoff = Duration::from_millis(self.config.announce_backoff_ms);
+        let mut attempts = 0;
+
+        // a newer announcement that went through makes this one moot
+        while self.announcements.read().await[&url].is_pending(id) {
+            let res = surf::put(format!("{}/ingest/announce", url))
+                .body(announce_msg.clone())
+                .await;
+            let err = match res {
+                Ok(r) if r.status().is_success() => {
+                    info!("http announce to {} successful {:?}", url, r.status());
+                    if let Some(tracker) = self.announcements.write().await.get_mut(&url) {
+                        tracker.succeeded(id);
+                    }
+                    return;
+                }
+                Ok(r) => format!("indexer answered {}", r.status()),
+                Err(e) => e.to_string(),
+            };
+
+            let mut announcements = self.announcements.write().await;
+            let tracker = match announcements.get_mut(&url) {
+                Some(tracker) => tracker,
+                None => return,
+            };
+            if attempts >= self.config.announce_retries {
+                error!(
+                    "error: http announce to {} failed after {} retries: {}",
+                    url, attempts, err
+                );
+                tracker.failed(id, err);
+                return;
+            }
+            attempts += 1;
+            warn!(
+                "http announce to {} failed, retrying in {:?}: {}",
+                url, backoff, err
+            );
+            tracker.retrying(id, err);
+            drop(announcements);
+
+            task::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_ANNOUNCE_BACKOFF);
+        }
+    }
+
     pub async fn start(self, provider_config: &ProviderConfig) -> Result<()> {
         info!("index provider starting up");
 
         let app_router = Router::new()
             .route("/head", get(head::<S>))
+            .route("/ads", get(list_ads::<S>))
             .route("/:cid", get(get_block::<S>))
             .layer(Extension(self.clone()));
 
@@ -121,9 +774,17 @@ where
         Self {
             head: Arc::clone(&self.head),
             root_cids: Arc::clone(&self.root_cids),
-            keypair: self.keypair.clone(),
+            removed_contexts: Arc::clone(&self.removed_contexts),
+            batch_pending: Arc::clone(&self.batch_pending),
+            batch_ms: Arc::clone(&self.batch_ms),
+            chain_len: Arc::clone(&self.chain_len),
+            restored: Arc::clone(&self.restored),
+            signer: Arc::clone(&self.signer),
+            extended_keys: Arc::clone(&self.extended_keys),
             blockstore: Arc::clone(&self.blockstore),
             temp_ads: Arc::clone(&self.temp_ads),
+            announcements: Arc::clone(&self.announcements),
+            network_addrs: Arc::clone(&self.network_addrs),
             config: Arc::clone(&self.config),
         }
     }
@@ -131,6 +792,7 @@ where
 
 pub enum ProviderError {
     NotFoundError(Error),
+    BadRequestError(Error),
     InternalError(Error),
 }
 impl IntoResponse for ProviderError {
@@ -139,6 +801,9 @@ impl IntoResponse for ProviderError {
             ProviderError::NotFoundError(e) => {
                 return (StatusCode::NOT_FOUND, e.to_string()).into_response()
             }
+            ProviderError::BadRequestError(e) => {
+                return (StatusCode::BAD_REQUEST, e.to_string()).into_response()
+            }
             ProviderError::InternalError(e) => {
                 return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
             }
@@ -153,6 +818,17 @@ pub trait ProviderInterface: Sync + Send + 'static {
     async fn publish(&self, id: usize) -> Result<()>;
     async fn create_announce_msg(&self, peer_id: PeerId) -> Result<Vec<u8>>;
     async fn announce_http_message(&self, announce_msg: Vec<u8>);
+    async fn announce_status(&self) -> AnnounceStatus;
+    /// Group `root_cid` under `context_id`, advertising it as part of that context.
+    ///
+    /// The grouping is kept in the provider db, removals after a restart still cover
+    /// the whole context.
+    async fn set_context(&self, root_cid: Cid, context_id: Vec<u8>) -> Result<()>;
+    /// Context id `root_cid` is advertised under, the root cid itself unless grouped.
+    async fn context_id(&self, root_cid: &Cid) -> Result<Vec<u8>>;
+    /// Queue a single removal advertisement for every root grouped under `context_id`,
+    /// returning those roots.
+    async fn remove_context(&self, context_id: Vec<u8>) -> Result<Vec<Cid>>;
 }
 
 #[async_trait]
@@ -163,6 +839,10 @@ where
     async fn create(&self, mut ad: Advertisement) -> Result<usize> {
         let id: usize = rand::thread_rng().gen();
         ad.Entries = None;
+        if !ad.IsRm {
+            ad = ad.with_metadata(&self.metadata());
+        }
+        self.extend(&mut ad);
         let mut temp_ads = self.temp_ads.write().await;
         temp_ads.insert(id, ad);
         info!("ad created with id : {}", id);
@@ -171,49 +851,72 @@ where
     }
 
     async fn add_chunk(&self, bytes: Vec<u8>, id: usize) -> Result<()> {
-        let entries = forest_encoding::from_slice(&bytes).unwrap();
+        let entries: Vec<Ipld> = forest_encoding::from_slice(&bytes).unwrap();
 
         let bs = self.blockstore.write().await;
         let mut temp_ads = self.temp_ads.write().await;
         if let Some(ad) = temp_ads.get_mut(&id) {
-            let entry_head_clone = ad.Entries.clone();
-            let chunk = EntryChunk::new(entries, entry_head_clone);
-            match bs.put_obj(&chunk, Code::Blake2b256) {
-                Ok(cid) => {
-                    ad.Entries = Some(Ipld::Link(convert_cid(cid.to_bytes())));
-                    return Ok(());
+            // split large lists so every chunk stays within the indexer limits
+            for entries in entries.chunks(self.config.max_chunk_entries.max(1)) {
+                let entry_head_clone = ad.Entries.clone();
+                let chunk = EntryChunk::new(entries.to_vec(), entry_head_clone);
+                match bs.put_obj(&chunk, Code::Blake2b256) {
+                    Ok(cid) => ad.Entries = Some(Ipld::Link(cid)),
+                    Err(e) => return Err(anyhow!(format!("{}", e))),
                 }
-                Err(e) => return Err(anyhow!(format!("{}", e))),
             }
+            return Ok(());
         }
 
         Err(anyhow!("ad not found"))
     }
 
     async fn publish(&self, id: usize) -> Result<()> {
-        let mut head = self.head.write().await;
-        let keypair = self.keypair.clone();
-        let current_head = head.take();
-        let mut temp_ads = self.temp_ads.write().await;
-        if let Some(mut ad) = temp_ads.remove(&id) {
-            let bs = self.blockstore.write().await;
-            ad.PreviousID =
-                current_head.map(|h| forest_ipld::Ipld::Link(convert_cid(h.to_bytes())));
-            let sig = ad.sign(&keypair)?;
-            ad.Signature = Ipld::Bytes(sig.into_protobuf_encoding());
+        let mut ad = self
+            .temp_ads
+            .write()
+            .await
+            .remove(&id)
+            .ok_or_else(|| anyhow!("ad not found"))?;
+        // the ad is signed without holding the head or the store, as a remote signer
+        // may take a while, and signed again when another publish moved the head
+        // meanwhile. The head stays as it is when signing fails, e.g. on a signer outage
+        loop {
+            let current_head = *self.head.read().await;
+            ad.PreviousID = current_head.map(forest_ipld::Ipld::Link);
+            ad.sign_extended_providers(&self.signers()).await?;
+            ad.Signature = Ipld::Bytes(ad.sign(&*self.signer).await?);
             let ipld_ad = forest_ipld::to_ipld(&ad)?;
+
+            let mut head = self.head.write().await;
+            if *head != current_head {
+                continue;
+            }
+            let bs = self.blockstore.write().await;
             let cid = bs.put_obj(&ipld_ad, Code::Blake2b256)?;
+            write_head(&*bs, cid, self.chain_len.load(Ordering::SeqCst) + 1)?;
             *head = Some(cid);
+            self.chain_len.fetch_add(1, Ordering::SeqCst);
             return Ok(());
         }
-        return Err(anyhow!("ad not found"));
     }
 
     async fn create_announce_msg(&self, peer_id: PeerId) -> Result<Vec<u8>> {
-        let mut multiaddrs = Multiaddr::from_str(&self.config.domain)?;
-        multiaddrs =
-            Multiaddr::try_from(format!("{}/http/p2p/{}", multiaddrs.to_string(), peer_id))?;
-        let msg_addrs = [multiaddrs].to_vec();
+        let mut msg_addrs = vec![];
+        if !self.config.domain.is_empty() {
+            let domain = Multiaddr::from_str(&self.config.domain)?;
+            msg_addrs.push(Multiaddr::try_from(format!("{domain}/http/p2p/{peer_id}"))?);
+        }
+        for addr in self.network_addrs.read().await.iter() {
+            if let Some(addr) = http_address(addr, self.config.port, peer_id) {
+                if !msg_addrs.contains(&addr) {
+                    msg_addrs.push(addr);
+                }
+            }
+        }
+        if msg_addrs.is_empty() {
+            return Err(anyhow!("no address to announce the provider on"));
+        }
         let head = self.head.read().await;
         let head_cid: Cid = (*head).expect("no head found for announcement");
         let message = Message {
@@ -227,13 +930,62 @@ where
     }
 
     async fn announce_http_message(&self, announce_msg: Vec<u8>) {
-        let res = surf::put(format!("{}/ingest/announce", self.config.indexer_url))
-            .body(announce_msg)
-            .await;
-        match res {
-            Ok(r) => info!("http announce successful {:?}", r.status()),
-            Err(e) => error!("error: http announce failed {:?}", e),
+        let head = *self.head.read().await;
+        let announces: Vec<_> = self
+            .config
+            .indexer_url
+            .iter()
+            .map(|url| {
+                let provider = self.clone();
+                let (url, announce_msg) = (url.clone(), announce_msg.clone());
+                task::spawn(async move { provider.announce_to(url, head, announce_msg).await })
+            })
+            .collect();
+        for announce in announces {
+            announce.await;
+        }
+    }
+
+    async fn set_context(&self, root_cid: Cid, context_id: Vec<u8>) -> Result<()> {
+        let bs = self.blockstore.write().await;
+        regroup(&*bs, root_cid, &context_id)
+    }
+
+    async fn context_id(&self, root_cid: &Cid) -> Result<Vec<u8>> {
+        let bs = self.blockstore.read().await;
+        Ok(read_context(&*bs, root_cid)?.unwrap_or_else(|| root_cid.to_bytes()))
+    }
+
+    async fn remove_context(&self, context_id: Vec<u8>) -> Result<Vec<Cid>> {
+        let roots = {
+            let bs = self.blockstore.write().await;
+            let roots = read_context_roots(&*bs, &context_id)?;
+            for cid in &roots {
+                bs.delete(context_key(cid))
+                    .map_err(|e| anyhow!(e.to_string()))?;
+            }
+            write_context_roots(&*bs, &context_id, &[])?;
+            roots
         };
+
+        self.root_cids
+            .write()
+            .await
+            .retain(|cid| !roots.contains(cid));
+        self.queue_removal(context_id).await;
+        Ok(roots)
+    }
+
+    async fn announce_status(&self) -> AnnounceStatus {
+        let head = *self.head.read().await;
+        let announcements = self.announcements.read().await;
+        AnnounceStatus {
+            head: head.map(|cid| cid.to_string()),
+            indexers: announcements
+                .iter()
+                .map(|(url, tracker)| tracker.status(url))
+                .collect(),
+        }
     }
 }
 
@@ -265,9 +1017,206 @@ impl Cbor for Message {
     }
 }
 
+impl Message {
+    /// Decode an announcement gossiped on the ingest topic, skipping the addresses that
+    /// do not parse.
+    pub fn unmarshal_cbor(bytes: &[u8]) -> Result<Self> {
+        let fields = match DagCborCodec
+            .decode::<libipld::Ipld>(bytes)
+            .map_err(|e| anyhow!(e.to_string()))?
+        {
+            libipld::Ipld::List(fields) => fields,
+            _ => return Err(anyhow!("announcement is not a list")),
+        };
+        match fields.as_slice() {
+            [libipld::Ipld::Link(cid), libipld::Ipld::List(addrs), ..] => Ok(Self {
+                Cid: *cid,
+                Addrs: addrs
+                    .iter()
+                    .filter_map(|addr| match addr {
+                        libipld::Ipld::Bytes(bytes) => Multiaddr::try_from(bytes.clone()).ok(),
+                        _ => None,
+                    })
+                    .collect(),
+                ExtraData: *b"",
+            }),
+            _ => Err(anyhow!("announcement misses the cid or the addresses")),
+        }
+    }
+}
+
+/// Cid of the first entry chunk of `ad`.
+fn entries_link(ad: &Advertisement) -> Option<Cid> {
+    match &ad.Entries {
+        Some(Ipld::Link(link)) => Some(*link),
+        _ => None,
+    }
+}
+
+/// Every chunk in the chain of entry chunks starting at `first`.
+fn entry_chunks<S: BlockStore>(bs: &S, first: Cid) -> Result<Vec<(Cid, EntryChunk)>> {
+    let mut chunks = vec![];
+    let mut next = Some(first);
+    while let Some(cid) = next {
+        let chunk: EntryChunk = bs
+            .get_obj(&cid)
+            .map_err(|e| anyhow!(e.to_string()))?
+            .ok_or_else(|| anyhow!("Entry chunk {} missing from the chain", cid))?;
+        next = chunk.next();
+        chunks.push((cid, chunk));
+    }
+    Ok(chunks)
+}
+
+/// Address of the provider http server at `port` on the host of `addr`, `None` for
+/// relayed addresses the server cannot be reached through.
+pub fn http_address(addr: &Multiaddr, port: u16, peer_id: PeerId) -> Option<Multiaddr> {
+    if addr.iter().any(|protocol| protocol == Protocol::P2pCircuit) {
+        return None;
+    }
+    let host = addr.iter().find(|protocol| {
+        matches!(
+            protocol,
+            Protocol::Ip4(_)
+                | Protocol::Ip6(_)
+                | Protocol::Dns(_)
+                | Protocol::Dns4(_)
+                | Protocol::Dns6(_)
+        )
+    })?;
+    Some(
+        Multiaddr::empty()
+            .with(host)
+            .with(Protocol::Tcp(port))
+            .with(Protocol::Http)
+            .with(Protocol::P2p(peer_id.into())),
+    )
+}
+
+/// Point the head at `head`, a chain of `chain_len` advertisements, in one write.
+fn write_head<S: BlockStore>(bs: &S, head: Cid, chain_len: usize) -> Result<()> {
+    bs.bulk_write(&[
+        (HEAD_KEY.to_vec(), head.to_bytes()),
+        (
+            CHAIN_LEN_KEY.to_vec(),
+            (chain_len as u64).to_be_bytes().to_vec(),
+        ),
+    ])
+    .map_err(|e| anyhow!(e.to_string()))
+}
+
+fn context_key(root_cid: &Cid) -> Vec<u8> {
+    [CONTEXT_KEY, &root_cid.to_bytes()].concat()
+}
+
+/// Context id `root_cid` was grouped under, if any.
+fn read_context<S: BlockStore>(bs: &S, root_cid: &Cid) -> Result<Option<Vec<u8>>> {
+    bs.read(context_key(root_cid))
+        .map_err(|e| anyhow!(e.to_string()))
+}
+
+/// Root cids grouped under `context_id`.
+fn read_context_roots<S: BlockStore>(bs: &S, context_id: &[u8]) -> Result<Vec<Cid>> {
+    let key = [CONTEXT_ROOTS_KEY, context_id].concat();
+    let bytes = match bs.read(key).map_err(|e| anyhow!(e.to_string()))? {
+        Some(bytes) => bytes,
+        None => return Ok(vec![]),
+    };
+    let links: Vec<Ipld> = forest_encoding::from_slice(&bytes)?;
+    Ok(links
+        .into_iter()
+        .filter_map(|link| match link {
+            Ipld::Link(cid) => Some(cid),
+            _ => None,
+        })
+        .collect())
+}
+
+fn write_context_roots<S: BlockStore>(bs: &S, context_id: &[u8], roots: &[Cid]) -> Result<()> {
+    let key = [CONTEXT_ROOTS_KEY, context_id].concat();
+    if roots.is_empty() {
+        return bs.delete(key).map_err(|e| anyhow!(e.to_string()));
+    }
+    let links: Vec<Ipld> = roots.iter().map(|cid| Ipld::Link(*cid)).collect();
+    bs.write(key, forest_encoding::to_vec(&links)?)
+        .map_err(|e| anyhow!(e.to_string()))
+}
+
+/// Move `root_cid` to `context_id`, out of the context it was grouped under before.
+fn regroup<S: BlockStore>(bs: &S, root_cid: Cid, context_id: &[u8]) -> Result<()> {
+    match read_context(bs, &root_cid)? {
+        Some(previous) if previous == context_id => return Ok(()),
+        Some(_) => {
+            ungroup(bs, &root_cid)?;
+        }
+        None => {}
+    }
+    let mut roots = read_context_roots(bs, context_id)?;
+    roots.push(root_cid);
+    write_context_roots(bs, context_id, &roots)?;
+    bs.write(context_key(&root_cid), context_id)
+        .map_err(|e| anyhow!(e.to_string()))
+}
+
+/// Take `root_cid` out of its context, returning the context and the roots left in it.
+fn ungroup<S: BlockStore>(bs: &S, root_cid: &Cid) -> Result<Option<(Vec<u8>, Vec<Cid>)>> {
+    let context_id = match read_context(bs, root_cid)? {
+        Some(context_id) => context_id,
+        None => return Ok(None),
+    };
+    let mut roots = read_context_roots(bs, &context_id)?;
+    roots.retain(|cid| cid != root_cid);
+    write_context_roots(bs, &context_id, &roots)?;
+    bs.delete(context_key(root_cid))
+        .map_err(|e| anyhow!(e.to_string()))?;
+    Ok(Some((context_id, roots)))
+}
+
+/// Group `roots` by the context id they are advertised under, see
+/// [`Provider::take_batch`].
+fn group<S: BlockStore>(bs: &S, roots: &[Cid]) -> Result<Vec<(Vec<u8>, Vec<Cid>)>> {
+    let mut batches: Vec<(Vec<u8>, Vec<Cid>)> = vec![];
+    let mut ungrouped: Vec<Cid> = vec![];
+    for cid in roots {
+        let context_id = match read_context(bs, cid)? {
+            Some(context_id) => context_id,
+            None => {
+                if !ungrouped.contains(cid) {
+                    ungrouped.push(*cid);
+                }
+                continue;
+            }
+        };
+        match batches
+            .iter_mut()
+            .find(|(context, _)| *context == context_id)
+        {
+            Some((_, cids)) if cids.contains(cid) => {}
+            Some((_, cids)) => cids.push(*cid),
+            None => batches.push((context_id, vec![*cid])),
+        }
+    }
+
+    match ungrouped.len() {
+        0 => {}
+        1 => batches.push((ungrouped[0].to_bytes(), ungrouped)),
+        _ => {
+            // not the cid of any of the roots, which may be advertised alone later on
+            let bytes: Vec<u8> = ungrouped.iter().flat_map(|cid| cid.to_bytes()).collect();
+            let context_id = Code::Sha2_256.digest(&bytes).to_bytes();
+            for cid in &ungrouped {
+                regroup(bs, *cid, &context_id)?;
+            }
+            batches.push((context_id, ungrouped));
+        }
+    }
+    Ok(batches)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{config::ExtendedProviderConfig, metadata::Transport, signed_head};
     use db::{rocks::RocksDb, rocks_config::RocksDbConfig};
     use libp2p::PeerId;
     use multihash::MultihashDigest;
@@ -305,6 +1254,7 @@ mod tests {
             Metadata: Ipld::Bytes(vec![]),
             ContextID: Ipld::Bytes("ursa".into()),
             IsRm: false,
+            ExtendedProvider: None,
         };
 
         let id = provider_interface.create(ad).await.unwrap();
@@ -322,8 +1272,419 @@ mod tests {
         let _ = provider_interface.publish(id).await;
         let t_head = provider_interface.head.read().await;
 
-        let signed_head: SignedHead = surf::get("http://0.0.0.0:8070/head").recv_json().await?;
-        assert_eq!(signed_head.open()?.1, t_head.unwrap());
+        let head = signed_head::fetch_head("http://0.0.0.0:8070", &peer_id, false).await?;
+        assert_eq!(head, t_head.unwrap());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_ads_page() -> Result<(), Box<dyn std::error::Error>> {
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = PeerId::from(keypair.public());
+        let provider_db = RocksDb::open("index_provider_ads_db", &RocksDbConfig::default())
+            .expect("Opening RocksDB must succeed");
+        let provider = Provider::new(
+            keypair,
+            Arc::new(RwLock::new(provider_db)),
+            ProviderConfig::default(),
+        );
+
+        let mut published = vec![];
+        for i in 0..3u8 {
+            let ad = Advertisement::new(vec![i], peer_id, vec![], false);
+            let id = provider.create(ad).await?;
+            provider.publish(id).await?;
+            published.push(provider.head.read().await.unwrap().to_string());
+        }
+        published.reverse();
+
+        let head = *provider.head.read().await;
+        let page = provider.ads_page(head, 2).await?;
+        assert_eq!(page.ads, published[..2]);
+        assert_eq!(page.next, Some(published[1].clone()));
+
+        let before = provider
+            .advertisement(&Cid::from_str(&published[1])?)
+            .await?
+            .unwrap()
+            .previous();
+        let page = provider.ads_page(before, 2).await?;
+        assert_eq!(page.ads, published[2..]);
+        assert_eq!(page.next, None);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_remove_context() -> Result<(), Box<dyn std::error::Error>> {
+        let provider_db = RocksDb::open("index_provider_contexts_db", &RocksDbConfig::default())
+            .expect("Opening RocksDB must succeed");
+        let provider_db = Arc::new(RwLock::new(provider_db));
+        let provider = Provider::new(
+            Keypair::generate_ed25519(),
+            Arc::clone(&provider_db),
+            ProviderConfig::default(),
+        );
+
+        let cids: Vec<Cid> = [
+            "bafkreif2opfibjypwkjzzry3jbibcjqcjwnpoqpeiqw75eu3s3u3zbdszq",
+            "bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku",
+            "bafkreidgvpkjawlxz6sffxzwgooowe5yt7i6wsyg236mfoks77nywkptdy",
+        ]
+        .iter()
+        .map(|cid| Cid::from_str(cid).unwrap())
+        .collect();
+        let context_id = b"videos".to_vec();
+        for cid in &cids[..2] {
+            provider.set_context(*cid, context_id.clone()).await?;
+        }
+        provider.set_context(cids[2], b"other".to_vec()).await?;
+        provider.set_context(cids[2], b"other".to_vec()).await?;
+        assert_eq!(provider.context_id(&cids[0]).await?, context_id);
+        assert_eq!(provider.context_id(&cids[2]).await?, b"other".to_vec());
+
+        // regrouping moves the root out of its previous context
+        provider.set_context(cids[2], context_id.clone()).await?;
+        provider.remove_context(b"other".to_vec()).await?;
+        assert_eq!(provider.context_id(&cids[2]).await?, context_id);
+
+        // removing a grouped root advertises its siblings again
+        provider.remove_root_cid(cids[0]).await?;
+        assert_eq!(
+            *provider.root_cids.read().await,
+            VecDeque::from([cids[1], cids[2]])
+        );
+        assert_eq!(provider.context_id(&cids[0]).await?, cids[0].to_bytes());
+
+        // the groups outlive the provider
+        let restarted = Provider::new(
+            Keypair::generate_ed25519(),
+            provider_db,
+            ProviderConfig::default(),
+        );
+        let removed = restarted.remove_context(context_id.clone()).await?;
+        assert_eq!(removed, vec![cids[1], cids[2]]);
+        assert!(restarted.root_cids.read().await.is_empty());
+        assert_eq!(
+            *restarted.removed_contexts.read().await,
+            VecDeque::from([context_id.clone()])
+        );
+        assert!(restarted.remove_context(context_id).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_take_batch() -> Result<(), Box<dyn std::error::Error>> {
+        let provider_db = RocksDb::open("index_provider_batch_db", &RocksDbConfig::default())
+            .expect("Opening RocksDB must succeed");
+        let provider = Provider::new(
+            Keypair::generate_ed25519(),
+            Arc::new(RwLock::new(provider_db)),
+            ProviderConfig::default(),
+        );
+        assert!(provider.begin_batch());
+        assert!(!provider.begin_batch());
+        provider.end_batch();
+
+        let cids: Vec<Cid> = [
+            "bafkreif2opfibjypwkjzzry3jbibcjqcjwnpoqpeiqw75eu3s3u3zbdszq",
+            "bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku",
+            "bafkreidgvpkjawlxz6sffxzwgooowe5yt7i6wsyg236mfoks77nywkptdy",
+        ]
+        .iter()
+        .map(|cid| Cid::from_str(cid).unwrap())
+        .collect();
+        provider.set_context(cids[2], b"videos".to_vec()).await?;
+        provider
+            .get_mut_root_cids()
+            .write()
+            .await
+            .extend(cids.iter().chain(&cids[..1]));
+
+        let batches = provider.take_batch().await?;
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0], (b"videos".to_vec(), vec![cids[2]]));
+        // ungrouped roots of the batch share a context of their own
+        let (batch_context, roots) = &batches[1];
+        assert_eq!(roots, &vec![cids[0], cids[1]]);
+        assert!(cids.iter().all(|cid| cid.to_bytes() != *batch_context));
+        for cid in &cids[..2] {
+            assert_eq!(provider.context_id(cid).await?, *batch_context);
+        }
+
+        // a single root keeps its own cid as context
+        let single = Cid::from_str("bafkreihwsnuregceqh263vgdathcprnbvatyat6h6mu7ipjhhodcdbyhoy")?;
+        provider.get_mut_root_cids().write().await.push_back(single);
+        assert_eq!(
+            provider.take_batch().await?,
+            vec![(single.to_bytes(), vec![single])]
+        );
+
+        // the groups are kept in the db between runs
+        provider.remove_context(batch_context.clone()).await?;
+        provider.remove_context(b"videos".to_vec()).await?;
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_extended_providers() -> Result<(), Box<dyn std::error::Error>> {
+        let (keypair, gateway) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let peer_id = PeerId::from(keypair.public());
+        let provider_db = RocksDb::open("index_provider_extended_db", &RocksDbConfig::default())
+            .expect("Opening RocksDB must succeed");
+        let provider_config = ProviderConfig {
+            extended_providers: vec![ExtendedProviderConfig {
+                key_path: "gateway.pem".into(),
+                addresses: vec![],
+                gateway_url: Some("https://gateway.ursa.earth".to_string()),
+                transports: vec![Transport::Http],
+            }],
+            transports: vec![Transport::Bitswap, Transport::Http],
+            ..Default::default()
+        };
+        let provider = Provider::new(keypair, Arc::new(RwLock::new(provider_db)), provider_config)
+            .with_extended_keys(vec![gateway.clone()]);
+
+        let addresses = vec!["/ip4/1.2.3.4/tcp/4890".to_string()];
+        let id = provider
+            .create(Advertisement::new(
+                vec![1],
+                peer_id,
+                addresses.clone(),
+                false,
+            ))
+            .await?;
+        provider.publish(id).await?;
+
+        let head = provider.head().await.unwrap();
+        let ad: Advertisement = provider.blockstore.read().await.get_obj(&head)?.unwrap();
+        ad.verify(&peer_id)?;
+        let metadata = Metadata::bitswap().with(Transport::Http).encode();
+        assert!(matches!(&ad.Metadata, Ipld::Bytes(bytes) if *bytes == metadata));
+        let providers = ad.ExtendedProvider.unwrap().Providers;
+        assert_eq!(providers.len(), 2);
+        assert_eq!(
+            (providers[0].ID.clone(), providers[0].Addresses.clone()),
+            (peer_id.to_base58(), addresses)
+        );
+        assert_eq!(providers[1].ID, PeerId::from(gateway.public()).to_base58());
+        assert_eq!(
+            providers[1].Addresses,
+            vec!["/dns/gateway.ursa.earth/tcp/443/https".to_string()]
+        );
+        for provider in &providers {
+            assert!(matches!(&provider.Signature, Ipld::Bytes(sig) if !sig.is_empty()));
+        }
+
+        // removals only withdraw the content of the node
+        let id = provider
+            .create(Advertisement::new(vec![1], peer_id, vec![], true))
+            .await?;
+        assert!(provider.temp_ads.read().await[&id]
+            .ExtendedProvider
+            .is_none());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_add_chunk_splits_entries() -> Result<(), Box<dyn std::error::Error>> {
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = PeerId::from(keypair.public());
+        let provider_db = RocksDb::open("index_provider_chunks_db", &RocksDbConfig::default())
+            .expect("Opening RocksDB must succeed");
+        let provider_config = ProviderConfig {
+            max_chunk_entries: 4,
+            ..Default::default()
+        };
+        let provider = Provider::new(keypair, Arc::new(RwLock::new(provider_db)), provider_config);
+
+        let id = provider
+            .create(Advertisement::new(vec![], peer_id, vec![], false))
+            .await?;
+        let entries: Vec<Ipld> = (0..10u8)
+            .map(|i| Ipld::Bytes(multihash::Code::Blake2b256.digest(&[i]).to_bytes()))
+            .collect();
+        provider
+            .add_chunk(forest_encoding::to_vec(&entries)?, id)
+            .await?;
+
+        let mut sizes = vec![];
+        let mut next = match &provider.temp_ads.read().await[&id].Entries {
+            Some(Ipld::Link(link)) => Some(*link),
+            _ => None,
+        };
+        while let Some(cid) = next {
+            let store = provider.blockstore.read().await;
+            let chunk: EntryChunk = store.get_obj(&cid)?.unwrap();
+            sizes.push(chunk.entries().len());
+            next = chunk.next();
+        }
+        assert_eq!(sizes, vec![2, 4, 4]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_http_address() {
+        let peer_id = PeerId::random();
+        let addr = Multiaddr::from_str("/ip4/1.2.3.4/tcp/6009").unwrap();
+        assert_eq!(
+            http_address(&addr, 8070, peer_id),
+            Some(
+                Multiaddr::from_str(&format!("/ip4/1.2.3.4/tcp/8070/http/p2p/{peer_id}")).unwrap()
+            )
+        );
+
+        let relayed = addr
+            .with(Protocol::P2p(PeerId::random().into()))
+            .with(Protocol::P2pCircuit);
+        assert_eq!(http_address(&relayed, 8070, peer_id), None);
+    }
+
+    #[test]
+    fn test_announce_message() {
+        let peer_id = PeerId::random();
+        let message = Message {
+            Cid: Cid::new_v1(0x71, Code::Blake2b256.digest(b"ad")),
+            Addrs: vec![
+                Multiaddr::from_str(&format!("/ip4/1.2.3.4/tcp/8070/http/p2p/{peer_id}")).unwrap(),
+            ],
+            ExtraData: *b"",
+        };
+        let decoded = Message::unmarshal_cbor(&message.marshal_cbor().unwrap()).unwrap();
+        assert_eq!(decoded.Cid, message.Cid);
+        assert_eq!(decoded.Addrs, message.Addrs);
+        assert!(Message::unmarshal_cbor(b"not cbor").is_err());
+    }
+
+    #[async_std::test]
+    async fn test_compact() -> Result<(), Box<dyn std::error::Error>> {
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = PeerId::from(keypair.public());
+        let provider_db = RocksDb::open("index_provider_compact_db", &RocksDbConfig::default())
+            .expect("Opening RocksDB must succeed");
+        let provider_config = ProviderConfig {
+            compact_after: 2,
+            ..Default::default()
+        };
+        let provider_db = Arc::new(RwLock::new(provider_db));
+        let provider = Provider::new(
+            keypair.clone(),
+            Arc::clone(&provider_db),
+            provider_config.clone(),
+        );
+
+        let ads: [(&[u8], &[u8], bool); 4] = [
+            (b"a", &[0, 1], false),
+            (b"a", &[2], false),
+            (b"b", &[3], false),
+            (b"b", &[], true),
+        ];
+        for (context_id, entries, is_rm) in ads {
+            let id = provider
+                .create(Advertisement::new(
+                    context_id.to_vec(),
+                    peer_id,
+                    vec![],
+                    is_rm,
+                ))
+                .await?;
+            if !entries.is_empty() {
+                let entries: Vec<Ipld> = entries.iter().map(|i| Ipld::Bytes(vec![*i])).collect();
+                provider
+                    .add_chunk(forest_encoding::to_vec(&entries)?, id)
+                    .await?;
+            }
+            provider.publish(id).await?;
+        }
+        let old_head = provider.head.read().await.unwrap();
+        assert!(provider.needs_compaction());
+
+        let head = provider.compact().await?.unwrap();
+        assert!(!provider.needs_compaction());
+        assert!(provider.advertisement(&old_head).await?.is_none());
+
+        // the removal of b is kept, a holds the entries of both its ads
+        let removal = provider.advertisement(&head).await?.unwrap();
+        assert!(removal.IsRm);
+        let live = provider
+            .advertisement(&removal.previous().unwrap())
+            .await?
+            .unwrap();
+        assert_eq!(live.ContextID, Ipld::Bytes(b"a".to_vec()));
+        assert!(live.previous().is_none());
+        let store = provider.blockstore.read().await;
+        let chunks = entry_chunks(&*store, entries_link(&live).unwrap())?;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].1.entries().len(), 3);
+        drop(store);
+
+        // the length of the chain is kept with its head
+        let restarted = Provider::new(keypair, provider_db, provider_config);
+        assert_eq!(restarted.load_head().await?, Some(head));
+        assert_eq!(restarted.chain_len.load(Ordering::SeqCst), 2);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_republish() -> Result<(), Box<dyn std::error::Error>> {
+        let _ = std::fs::remove_dir_all("index_provider_republish_db");
+        let provider_db = Arc::new(RwLock::new(
+            RocksDb::open("index_provider_republish_db", &RocksDbConfig::default())
+                .expect("Opening RocksDB must succeed"),
+        ));
+        let old = Keypair::generate_ed25519();
+        let old_id = PeerId::from(old.public());
+        let provider = Provider::new(
+            old.clone(),
+            Arc::clone(&provider_db),
+            ProviderConfig::default(),
+        );
+        for context_id in [b"a", b"b"] {
+            let id = provider
+                .create(Advertisement::new(
+                    context_id.to_vec(),
+                    old_id,
+                    vec![],
+                    false,
+                ))
+                .await?;
+            let entries = vec![Ipld::Bytes(context_id.to_vec())];
+            provider
+                .add_chunk(forest_encoding::to_vec(&entries)?, id)
+                .await?;
+            provider.publish(id).await?;
+        }
+        let old_head = provider.head().await.unwrap();
+
+        // the new key picks the chain up from the store
+        let new = Keypair::generate_ed25519();
+        let new_id = PeerId::from(new.public());
+        let rotated = Provider::new(new.clone(), provider_db, ProviderConfig::default());
+        assert_eq!(rotated.load_head().await?, Some(old_head));
+        assert!(rotated.take_restored() && !rotated.take_restored());
+        assert!(rotated.republish(&new).await.is_err());
+
+        let head = rotated.republish(&old).await?.unwrap();
+        assert_ne!(head, old_head);
+        let latest = rotated.advertisement(&head).await?.unwrap();
+        latest.verify(&new_id)?;
+        assert_eq!(latest.Provider, new_id.to_base58());
+        assert_eq!(latest.ContextID, Ipld::Bytes(b"b".to_vec()));
+        let first = rotated
+            .advertisement(&latest.previous().unwrap())
+            .await?
+            .unwrap();
+        first.verify(&new_id)?;
+        assert!(first.previous().is_none());
+        // the old chain is kept
+        assert!(rotated.advertisement(&old_head).await?.is_some());
+        assert_eq!(rotated.load_head().await?, Some(head));
 
         Ok(())
     }
@@ -2,15 +2,21 @@
 
 use base64;
 use cid::Cid;
-use libp2p::{
-    core::{identity::Keypair, PublicKey},
-    identity::error::SigningError,
-};
+use libp2p::{core::PublicKey, PeerId};
 use serde::{Deserialize, Serialize};
 use serde_json::Map;
 use serde_with::serde_as;
 use thiserror::Error;
 
+use crate::signer::{Signer, SignerError};
+
+/// Domain the head signature is separated with, so a head signature cannot be
+/// replayed as a signature over anything else signed with the same key.
+///
+/// IPNI indexers only check heads signing the bare cid, the domain is used when
+/// `provider_config.head_domain` is set, for providers mirrored by ursa nodes only.
+pub const SIGNED_HEAD_DOMAIN: &str = "ursa-index-provider-head";
+
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SignedHead {
@@ -20,6 +26,9 @@ pub struct SignedHead {
     sig: Vec<u8>,
     #[serde_as(as = "BytesAsMap")]
     pubkey: Vec<u8>,
+    /// Signature domain, heads of providers without one sign the bare cid.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    domain: Option<String>,
 }
 
 #[derive(Debug, Error)]
@@ -28,28 +37,100 @@ pub enum SignedHeadError {
     InvalidSignature,
     #[error("Invalid public key")]
     InvalidPublicKey,
+    #[error("Unknown signature domain {0}")]
+    UnknownDomain(String),
+    #[error("Head signed without the signature domain")]
+    MissingDomain,
+    #[error("Head signed by {found} instead of {expected}")]
+    UnexpectedSigner { expected: PeerId, found: PeerId },
 }
 
 impl SignedHead {
-    pub fn new(signing_key: &Keypair, cid: Cid) -> Result<Self, SigningError> {
-        let sig = signing_key.sign(&cid.to_bytes())?;
+    /// Head signing the bare cid, the envelope IPNI indexers check.
+    pub async fn new<S: Signer + ?Sized>(signer: &S, cid: Cid) -> Result<Self, SignerError> {
+        Self::sign(signer, cid, None).await
+    }
+
+    /// Head signed under [`SIGNED_HEAD_DOMAIN`].
+    pub async fn new_with_domain<S: Signer + ?Sized>(
+        signer: &S,
+        cid: Cid,
+    ) -> Result<Self, SignerError> {
+        Self::sign(signer, cid, Some(SIGNED_HEAD_DOMAIN.to_string())).await
+    }
+
+    async fn sign<S: Signer + ?Sized>(
+        signer: &S,
+        cid: Cid,
+        domain: Option<String>,
+    ) -> Result<Self, SignerError> {
+        let payload = Self::payload(domain.as_deref(), &cid).expect("the domain is known");
+        let sig = signer.sign(&payload).await?;
         Ok(SignedHead {
             head: cid,
-            pubkey: signing_key.public().to_protobuf_encoding(),
+            pubkey: signer.public().to_protobuf_encoding(),
             sig,
+            domain,
         })
     }
 
     pub fn open(self) -> Result<(PublicKey, Cid), SignedHeadError> {
         let pk = PublicKey::from_protobuf_encoding(&self.pubkey)
             .map_err(|_| SignedHeadError::InvalidPublicKey)?;
-        let valid = pk.verify(&self.head.to_bytes(), &self.sig);
+        let payload = Self::payload(self.domain.as_deref(), &self.head).ok_or_else(|| {
+            SignedHeadError::UnknownDomain(self.domain.clone().unwrap_or_default())
+        })?;
+        let valid = pk.verify(&payload, &self.sig);
         if !valid {
             return Err(SignedHeadError::InvalidSignature);
         }
 
         Ok((pk, self.head))
     }
+
+    /// Open the signed head, checking it was signed by `peer_id`, and under
+    /// [`SIGNED_HEAD_DOMAIN`] when `require_domain` is set, so a signature of the bare
+    /// cid made for anything else is not taken for a head.
+    pub fn verify_against(
+        self,
+        peer_id: &PeerId,
+        require_domain: bool,
+    ) -> Result<Cid, SignedHeadError> {
+        if require_domain && self.domain.is_none() {
+            return Err(SignedHeadError::MissingDomain);
+        }
+        let (pk, head) = self.open()?;
+        let found = PeerId::from(pk);
+        if found != *peer_id {
+            return Err(SignedHeadError::UnexpectedSigner {
+                expected: *peer_id,
+                found,
+            });
+        }
+        Ok(head)
+    }
+
+    /// Bytes signed for `cid` under `domain`, `None` for domains not known.
+    fn payload(domain: Option<&str>, cid: &Cid) -> Option<Vec<u8>> {
+        match domain {
+            None => Some(cid.to_bytes()),
+            Some(SIGNED_HEAD_DOMAIN) => {
+                Some([SIGNED_HEAD_DOMAIN.as_bytes(), &cid.to_bytes()].concat())
+            }
+            Some(_) => None,
+        }
+    }
+}
+
+/// Fetch the head advertisement of the provider serving `url`, e.g.
+/// `http://127.0.0.1:8070`, checking it is signed by `peer_id`, see
+/// [`SignedHead::verify_against`] for `require_domain`.
+pub async fn fetch_head(url: &str, peer_id: &PeerId, require_domain: bool) -> anyhow::Result<Cid> {
+    let signed_head: SignedHead = surf::get(format!("{}/head", url.trim_end_matches('/')))
+        .recv_json()
+        .await
+        .map_err(|e| anyhow::anyhow!(e.to_string()))?;
+    Ok(signed_head.verify_against(peer_id, require_domain)?)
 }
 
 serde_with::serde_conv!(BytesAsMap, Vec<u8>, from_bytes_to_map, from_map_to_bytes);
@@ -103,6 +184,7 @@ fn from_map_to_cid(value: serde_json::Map<String, serde_json::Value>) -> Result<
 #[cfg(test)]
 mod tests {
     use super::*;
+    use libp2p::identity::Keypair;
 
     #[test]
     fn test_decode_signed_msg() {
@@ -121,12 +203,14 @@ mod tests {
         println!("{:?}", pk);
     }
 
-    #[test]
-    fn test_sign_head() {
+    #[async_std::test]
+    async fn test_sign_head() {
         let kp = Keypair::generate_ed25519();
         let cid = Cid::try_from("bafybeicyhbhhklw3kdwgrxmf67mhkgjbsjauphsvrzywav63kn7bkpmqfa")
             .expect("failed to parse cid");
-        let signed_head = SignedHead::new(&kp, cid).expect("failed to sign head");
+        let signed_head = SignedHead::new(&kp, cid)
+            .await
+            .expect("failed to sign head");
         let signed_head_encoded = serde_json::to_string(&signed_head).expect("ser failed");
         let signed_head: SignedHead =
             serde_json::from_str(&signed_head_encoded).expect("deser failed");
@@ -136,4 +220,50 @@ mod tests {
         assert_eq!(head, cid);
         assert_eq!(pk, kp.public());
     }
+
+    #[async_std::test]
+    async fn test_verify_against() {
+        let kp = Keypair::generate_ed25519();
+        let cid = Cid::try_from("bafybeicyhbhhklw3kdwgrxmf67mhkgjbsjauphsvrzywav63kn7bkpmqfa")
+            .expect("failed to parse cid");
+
+        let peer_id = kp.public().to_peer_id();
+        let signed_head = SignedHead::new_with_domain(&kp, cid)
+            .await
+            .expect("failed to sign head");
+        assert_eq!(signed_head.domain.as_deref(), Some(SIGNED_HEAD_DOMAIN));
+        assert_eq!(signed_head.verify_against(&peer_id, true).unwrap(), cid);
+
+        let signed_head = SignedHead::new_with_domain(&kp, cid)
+            .await
+            .expect("failed to sign head");
+        assert!(matches!(
+            signed_head.verify_against(&PeerId::random(), false),
+            Err(SignedHeadError::UnexpectedSigner { .. })
+        ));
+
+        // the default head signs the bare cid, refused where the domain is required
+        let signed_head = SignedHead::new(&kp, cid)
+            .await
+            .expect("failed to sign head");
+        assert_eq!(signed_head.domain, None);
+        let encoded = serde_json::to_value(&signed_head).unwrap();
+        assert!(encoded.get("domain").is_none());
+        assert!(matches!(
+            signed_head.verify_against(&peer_id, true),
+            Err(SignedHeadError::MissingDomain)
+        ));
+        let signed_head: SignedHead = serde_json::from_value(encoded).unwrap();
+        assert_eq!(signed_head.verify_against(&peer_id, false).unwrap(), cid);
+
+        // a domain separated signature does not verify as a bare one
+        let mut signed_head = SignedHead::new_with_domain(&kp, cid)
+            .await
+            .expect("failed to sign head");
+        signed_head.domain = None;
+        assert!(matches!(
+            signed_head.open(),
+            Err(SignedHeadError::InvalidSignature)
+        ));
+    }
 }
@@ -0,0 +1,328 @@
+//! Advertisement signers.
+//!
+//! The advertisements and the signed head are signed through a [`Signer`], a local
+//! [`Keypair`] by default. Operators keeping their provider key in an HSM or a KMS
+//! point `provider_config.signer` to a [`RemoteSigner`] instead, an http service in
+//! front of the key answering two requests:
+//!
+//! - `GET <url>/keys/<key_id>` with `{"public_key": ...}`, the base64 protobuf
+//!   encoding of the libp2p public key
+//! - `POST <url>/keys/<key_id>/sign` of `{"message": ...}` with `{"signature": ...}`,
+//!   both base64
+//!
+//! The key never reaches the node, which checks every signature it gets back against
+//! the public key before using it.
+
+use async_std::future;
+use async_trait::async_trait;
+use libp2p::{
+    core::PublicKey,
+    identity::{error::SigningError, Keypair},
+    PeerId,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+use std::{sync::Arc, time::Duration};
+use thiserror::Error;
+
+use crate::config::RemoteSignerConfig;
+
+#[derive(Debug, Error)]
+pub enum SignerError {
+    #[error("Failed to sign with the local key: {0}")]
+    Local(SigningError),
+    #[error("Remote signer failed: {0}")]
+    Remote(String),
+    #[error("Remote signer returned a signature not matching its public key")]
+    InvalidSignature,
+}
+
+/// Key signing advertisements, wherever it is kept.
+#[async_trait]
+pub trait Signer: Send + Sync {
+    fn public(&self) -> PublicKey;
+
+    async fn sign(&self, msg: &[u8]) -> Result<Vec<u8>, SignerError>;
+
+    fn peer_id(&self) -> PeerId {
+        self.public().to_peer_id()
+    }
+}
+
+#[async_trait]
+impl Signer for Keypair {
+    fn public(&self) -> PublicKey {
+        Keypair::public(self)
+    }
+
+    async fn sign(&self, msg: &[u8]) -> Result<Vec<u8>, SignerError> {
+        Keypair::sign(self, msg).map_err(SignerError::Local)
+    }
+}
+
+#[async_trait]
+impl<S: Signer + ?Sized> Signer for Arc<S> {
+    fn public(&self) -> PublicKey {
+        (**self).public()
+    }
+
+    async fn sign(&self, msg: &[u8]) -> Result<Vec<u8>, SignerError> {
+        (**self).sign(msg).await
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct PublicKeyResponse {
+    public_key: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SignResponse {
+    signature: String,
+}
+
+/// Key held by a signing service, see the module docs for its api.
+pub struct RemoteSigner {
+    config: RemoteSignerConfig,
+    public: PublicKey,
+}
+
+impl RemoteSigner {
+    /// Fetch the public key of the signer of `config`.
+    pub async fn connect(config: RemoteSignerConfig) -> Result<Self, SignerError> {
+        let url = format!(
+            "{}/keys/{}",
+            config.url.trim_end_matches('/'),
+            config.key_id
+        );
+        let response: PublicKeyResponse = request(&config, surf::get(url)).await?;
+        let bytes = base64::decode(response.public_key)
+            .map_err(|e| SignerError::Remote(format!("invalid public key: {e}")))?;
+        let public = PublicKey::from_protobuf_encoding(&bytes)
+            .map_err(|e| SignerError::Remote(format!("invalid public key: {e}")))?;
+        Ok(Self { config, public })
+    }
+}
+
+#[async_trait]
+impl Signer for RemoteSigner {
+    fn public(&self) -> PublicKey {
+        self.public.clone()
+    }
+
+    async fn sign(&self, msg: &[u8]) -> Result<Vec<u8>, SignerError> {
+        let url = format!(
+            "{}/keys/{}/sign",
+            self.config.url.trim_end_matches('/'),
+            self.config.key_id
+        );
+        let body = json!({ "message": base64::encode(msg) });
+        let req = surf::post(url)
+            .body_json(&body)
+            .map_err(|e| SignerError::Remote(e.to_string()))?;
+        let response: SignResponse = request(&self.config, req).await?;
+        let signature = base64::decode(response.signature)
+            .map_err(|e| SignerError::Remote(format!("invalid signature: {e}")))?;
+        if !self.public.verify(msg, &signature) {
+            return Err(SignerError::InvalidSignature);
+        }
+        Ok(signature)
+    }
+}
+
+/// Send `req` to the signer of `config`, parsing its json answer.
+async fn request<T: DeserializeOwned>(
+    config: &RemoteSignerConfig,
+    mut req: surf::RequestBuilder,
+) -> Result<T, SignerError> {
+    if let Some(token) = &config.token {
+        req = req.header("Authorization", format!("Bearer {token}"));
+    }
+    let timeout = Duration::from_millis(config.timeout_ms);
+    future::timeout(timeout, req.recv_json())
+        .await
+        .map_err(|_| SignerError::Remote(format!("no answer within {timeout:?}")))?
+        .map_err(|e| SignerError::Remote(e.to_string()))
+}
+
+/// Protobuf encoding of a libp2p signed envelope of `payload`, signed by `signer`.
+///
+/// Built by hand rather than by `SignedEnvelope::new`, which only takes a local key.
+pub async fn signed_envelope<S: Signer + ?Sized>(
+    signer: &S,
+    domain: &str,
+    payload_type: &[u8],
+    payload: &[u8],
+) -> Result<Vec<u8>, SignerError> {
+    // the domain separated bytes a libp2p envelope signature covers
+    let mut signed = vec![];
+    for field in [domain.as_bytes(), payload_type, payload] {
+        put_varint(&mut signed, field.len());
+        signed.extend_from_slice(field);
+    }
+    let signature = signer.sign(&signed).await?;
+
+    let mut envelope = vec![];
+    for (tag, field) in [
+        (0x0a, signer.public().to_protobuf_encoding()),
+        (0x12, payload_type.to_vec()),
+        (0x1a, payload.to_vec()),
+        (0x2a, signature),
+    ] {
+        envelope.push(tag);
+        put_varint(&mut envelope, field.len());
+        envelope.extend(field);
+    }
+    Ok(envelope)
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::{
+        io::{ReadExt, WriteExt},
+        net::{TcpListener, TcpStream},
+        task,
+    };
+    use libp2p::core::SignedEnvelope;
+
+    #[derive(Deserialize)]
+    struct SignRequest {
+        message: String,
+    }
+
+    /// Head and body of the http request read from `stream`.
+    async fn read_request(stream: &mut TcpStream) -> (String, Vec<u8>) {
+        let mut data = vec![];
+        let mut buffer = [0; 4096];
+        loop {
+            let read = stream.read(&mut buffer).await.unwrap_or(0);
+            if read == 0 {
+                return (String::from_utf8_lossy(&data).to_string(), vec![]);
+            }
+            data.extend_from_slice(&buffer[..read]);
+            let end = match data.windows(4).position(|w| w == b"\r\n\r\n") {
+                Some(end) => end,
+                None => continue,
+            };
+            let head = String::from_utf8_lossy(&data[..end]).to_string();
+            let len = head
+                .lines()
+                .find_map(|line| {
+                    let line = line.to_ascii_lowercase();
+                    let len = line.strip_prefix("content-length:")?;
+                    len.trim().parse::<usize>().ok()
+                })
+                .unwrap_or(0);
+            if data.len() >= end + 4 + len {
+                return (head, data[end + 4..end + 4 + len].to_vec());
+            }
+        }
+    }
+
+    /// Signing service holding `public`, signing with `signing`, or never answering a
+    /// signing request when it is unset.
+    async fn serve(public: PublicKey, signing: Option<Keypair>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        task::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let (public, signing) = (public.clone(), signing.clone());
+                task::spawn(async move {
+                    let (head, body) = read_request(&mut stream).await;
+                    let body = match (head.starts_with("POST"), signing) {
+                        (true, Some(signing)) => {
+                            let request: SignRequest = serde_json::from_slice(&body).unwrap();
+                            let message = base64::decode(request.message).unwrap();
+                            let signature = signing.sign(&message).unwrap();
+                            json!({ "signature": base64::encode(signature) })
+                        }
+                        (true, None) => return task::sleep(Duration::from_secs(60)).await,
+                        (false, _) => {
+                            json!({ "public_key": base64::encode(public.to_protobuf_encoding()) })
+                        }
+                    }
+                    .to_string();
+                    let head = format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                        body.len()
+                    );
+                    let _ = stream.write_all(head.as_bytes()).await;
+                    let _ = stream.write_all(body.as_bytes()).await;
+                });
+            }
+        });
+        format!("http://{addr}")
+    }
+
+    fn config(url: String) -> RemoteSignerConfig {
+        RemoteSignerConfig {
+            url,
+            key_id: "provider".to_string(),
+            token: None,
+            timeout_ms: 500,
+        }
+    }
+
+    #[async_std::test]
+    async fn test_remote_signer() {
+        let keypair = Keypair::generate_ed25519();
+        let url = serve(keypair.public(), Some(keypair.clone())).await;
+        let signer = RemoteSigner::connect(config(url)).await.unwrap();
+        assert_eq!(signer.peer_id(), PeerId::from(keypair.public()));
+        let signature = signer.sign(b"head").await.unwrap();
+        assert!(keypair.public().verify(b"head", &signature));
+
+        // a signature by another key than the one the service names is refused
+        let other = Keypair::generate_ed25519();
+        let url = serve(keypair.public(), Some(other)).await;
+        let signer = RemoteSigner::connect(config(url)).await.unwrap();
+        assert!(matches!(
+            signer.sign(b"head").await,
+            Err(SignerError::InvalidSignature)
+        ));
+
+        // and a service not answering in time fails the signature
+        let url = serve(keypair.public(), None).await;
+        let signer = RemoteSigner::connect(config(url)).await.unwrap();
+        assert!(matches!(
+            signer.sign(b"head").await,
+            Err(SignerError::Remote(_))
+        ));
+    }
+
+    #[async_std::test]
+    async fn test_signed_envelope() {
+        let keypair = Keypair::generate_ed25519();
+        let payload = vec![7; 300];
+        let signer: Arc<dyn Signer> = Arc::new(keypair.clone());
+        let envelope = signed_envelope(&signer, "indexer", b"/test", &payload)
+            .await
+            .unwrap();
+
+        // ed25519 signatures are deterministic, the envelopes match byte for byte
+        let expected = SignedEnvelope::new(
+            &keypair,
+            "indexer".to_string(),
+            b"/test".to_vec(),
+            payload.clone(),
+        )
+        .unwrap();
+        assert_eq!(envelope, expected.into_protobuf_encoding());
+
+        let decoded = SignedEnvelope::from_protobuf_encoding(&envelope).unwrap();
+        let (read, key) = decoded
+            .payload_and_signing_key("indexer".to_string(), b"/test")
+            .unwrap();
+        assert_eq!((read, key), (payload.as_slice(), &keypair.public()));
+        assert_eq!(signer.peer_id(), PeerId::from(keypair.public()));
+    }
+}
@@ -4,6 +4,7 @@ const DEFAULT_METRICS_PORT: &str = "4070";
 const DEFAULT_API_PATH: &str = "/metrics";
 
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
 pub struct MetricsServiceConfig {
     /// Optional. Port to run metrics server. Default port 4070.
     pub port: String,
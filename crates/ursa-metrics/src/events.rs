@@ -1,33 +1,68 @@
-use metrics::{decrement_gauge, histogram, increment_counter, increment_gauge, Label};
+use metrics::{
+    counter, decrement_gauge, gauge, histogram, increment_counter, increment_gauge, Label,
+};
 use std::fmt::{Display, Formatter};
 use std::str::FromStr;
-use tracing::{error, info};
+use tracing::{debug, error, info};
 
 #[derive(Debug, Clone)]
 pub enum MetricEvent {
     PeerConnected,
     PeerDisconnected,
+    /// A dial failed with the peer unreachable.
+    DialFailed,
     RelayReservationOpened,
     RelayReservationClosed,
     RelayCircuitOpened,
     RelayCircuitClosed,
+    /// A reservation or circuit was denied, labeled with the peer and the limit.
+    RelayRequestDenied,
     Bitswap,
     GossipMessage,
     RequestMessage,
     RpcRequestReceived,
     RpcResponseSent,
+    WorkerJobQueued,
+    WorkerJobStarted,
+    WorkerQueueFull,
+    CommandQueueDepth,
+    CommandRejected,
+    /// A block was read from the store, labeled with whether it was found.
+    StoreGet,
+    StorePut,
+    /// Bytes of a car file uploaded over http.
+    UploadBytes,
+    /// Blocks hashed by the scrubber.
+    BlocksScrubbed,
+    /// Blocks the scrubber found corrupt and quarantined.
+    CorruptBlocks,
+    StreamOpened,
+    StreamClosed,
 }
 
 #[derive(Debug, Clone)]
 pub enum Metric {
     ActiveConnectedPeers,
+    DialFailures,
     ActiveRelayReservations,
     ActiveRelayCircuits,
+    RelayRequestsDenied,
     HttpRpcRequests,
     NodeBitswapOperations,
     NodeGossipMessages,
     NodeRequestMessages,
     NodeResponseInfo,
+    NodeWorkerQueueDepth,
+    NodeWorkerQueueFull,
+    NodeCommandQueueDepth,
+    NodeCommandsRejected,
+    StoreGets,
+    StoreGetLatency,
+    StorePutLatency,
+    HttpUploadBytes,
+    StoreScrubbedBlocks,
+    StoreCorruptBlocks,
+    ActiveStreams,
     Unknown(String),
 }
 
@@ -35,13 +70,26 @@ impl Display for Metric {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Metric::ActiveConnectedPeers => write!(f, "active_connected_peers"),
+            Metric::DialFailures => write!(f, "dial_failures"),
             Metric::ActiveRelayReservations => write!(f, "active_relay_reservations"),
             Metric::ActiveRelayCircuits => write!(f, "active_relay_circuits"),
+            Metric::RelayRequestsDenied => write!(f, "relay_requests_denied"),
             Metric::HttpRpcRequests => write!(f, "http_rpc_requests"),
             Metric::NodeBitswapOperations => write!(f, "node_bitswap_operations"),
             Metric::NodeGossipMessages => write!(f, "node_gossip_messages"),
             Metric::NodeRequestMessages => write!(f, "node_request_messages"),
             Metric::NodeResponseInfo => write!(f, "node_response_info"),
+            Metric::NodeWorkerQueueDepth => write!(f, "node_worker_queue_depth"),
+            Metric::NodeWorkerQueueFull => write!(f, "node_worker_queue_full"),
+            Metric::NodeCommandQueueDepth => write!(f, "node_command_queue_depth"),
+            Metric::NodeCommandsRejected => write!(f, "node_commands_rejected"),
+            Metric::StoreGets => write!(f, "store_gets"),
+            Metric::StoreGetLatency => write!(f, "store_get_latency"),
+            Metric::StorePutLatency => write!(f, "store_put_latency"),
+            Metric::HttpUploadBytes => write!(f, "http_upload_bytes"),
+            Metric::StoreScrubbedBlocks => write!(f, "store_scrubbed_blocks"),
+            Metric::StoreCorruptBlocks => write!(f, "store_corrupt_blocks"),
+            Metric::ActiveStreams => write!(f, "active_streams"),
             Metric::Unknown(s) => write!(f, "{}", s),
         }
     }
@@ -53,11 +101,24 @@ impl FromStr for Metric {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "active_connected_peers" => Ok(Metric::ActiveConnectedPeers),
+            "dial_failures" => Ok(Metric::DialFailures),
+            "relay_requests_denied" => Ok(Metric::RelayRequestsDenied),
             "http_rpc_requests" => Ok(Metric::HttpRpcRequests),
             "node_bitswap_operations" => Ok(Metric::NodeBitswapOperations),
             "node_gossip_messages" => Ok(Metric::NodeGossipMessages),
             "node_request_messages" => Ok(Metric::NodeRequestMessages),
             "node_response_info" => Ok(Metric::NodeResponseInfo),
+            "node_worker_queue_depth" => Ok(Metric::NodeWorkerQueueDepth),
+            "node_worker_queue_full" => Ok(Metric::NodeWorkerQueueFull),
+            "node_command_queue_depth" => Ok(Metric::NodeCommandQueueDepth),
+            "node_commands_rejected" => Ok(Metric::NodeCommandsRejected),
+            "store_gets" => Ok(Metric::StoreGets),
+            "store_get_latency" => Ok(Metric::StoreGetLatency),
+            "store_put_latency" => Ok(Metric::StorePutLatency),
+            "http_upload_bytes" => Ok(Metric::HttpUploadBytes),
+            "store_scrubbed_blocks" => Ok(Metric::StoreScrubbedBlocks),
+            "store_corrupt_blocks" => Ok(Metric::StoreCorruptBlocks),
+            "active_streams" => Ok(Metric::ActiveStreams),
             _ => Ok(Metric::Unknown(s.to_string())),
         }
     }
@@ -65,7 +126,7 @@ impl FromStr for Metric {
 
 pub fn track(event_name: MetricEvent, labels: Option<Vec<Label>>, value: Option<f64>) {
     if let Some(label) = labels {
-        info!("capturing event {:?} with labels {:?}", event_name, label);
+        debug!("capturing event {:?} with labels {:?}", event_name, label);
         match event_name {
             MetricEvent::Bitswap => {
                 increment_counter!(Metric::NodeBitswapOperations.to_string(), label);
@@ -76,6 +137,19 @@ pub fn track(event_name: MetricEvent, labels: Option<Vec<Label>>, value: Option<
             MetricEvent::RequestMessage => {
                 increment_counter!(Metric::NodeRequestMessages.to_string(), label);
             }
+            MetricEvent::RelayRequestDenied => {
+                increment_counter!(Metric::RelayRequestsDenied.to_string(), label);
+            }
+            MetricEvent::RpcRequestReceived => {
+                increment_counter!(Metric::HttpRpcRequests.to_string(), label);
+            }
+            MetricEvent::StoreGet => {
+                // hits over all gets is the hit ratio of the store
+                increment_counter!(Metric::StoreGets.to_string(), label);
+                if let Some(latency) = value {
+                    histogram!(Metric::StoreGetLatency.to_string(), latency);
+                }
+            }
             MetricEvent::RpcResponseSent => match value {
                 Some(latency) => histogram!(Metric::NodeResponseInfo.to_string(), latency, label),
                 None => error!(
@@ -86,7 +160,7 @@ pub fn track(event_name: MetricEvent, labels: Option<Vec<Label>>, value: Option<
             _ => error!("label on non-labeled event {:?}", event_name),
         }
     } else {
-        info!("capturing event {:?}", event_name);
+        debug!("capturing event {:?}", event_name);
         match event_name {
             MetricEvent::PeerConnected => {
                 increment_gauge!(Metric::ActiveConnectedPeers.to_string(), 1.0);
@@ -94,8 +168,11 @@ pub fn track(event_name: MetricEvent, labels: Option<Vec<Label>>, value: Option<
             MetricEvent::PeerDisconnected => {
                 decrement_gauge!(Metric::ActiveConnectedPeers.to_string(), 1.0);
             }
+            MetricEvent::DialFailed => {
+                increment_counter!(Metric::DialFailures.to_string());
+            }
             MetricEvent::RpcRequestReceived => {
-                increment_counter!(Metric::ActiveConnectedPeers.to_string());
+                increment_counter!(Metric::HttpRpcRequests.to_string());
             }
             MetricEvent::RelayReservationOpened => {
                 increment_gauge!(Metric::ActiveRelayReservations.to_string(), 1.0);
@@ -109,6 +186,59 @@ pub fn track(event_name: MetricEvent, labels: Option<Vec<Label>>, value: Option<
             MetricEvent::RelayCircuitClosed => {
                 decrement_gauge!(Metric::ActiveRelayCircuits.to_string(), 1.0);
             }
+            MetricEvent::WorkerJobQueued => {
+                increment_gauge!(Metric::NodeWorkerQueueDepth.to_string(), 1.0);
+            }
+            MetricEvent::WorkerJobStarted => {
+                decrement_gauge!(Metric::NodeWorkerQueueDepth.to_string(), 1.0);
+            }
+            MetricEvent::WorkerQueueFull => {
+                increment_counter!(Metric::NodeWorkerQueueFull.to_string());
+            }
+            MetricEvent::CommandQueueDepth => match value {
+                Some(depth) => gauge!(Metric::NodeCommandQueueDepth.to_string(), depth),
+                None => error!(
+                    "missing required value for {} event",
+                    Metric::NodeCommandQueueDepth
+                ),
+            },
+            MetricEvent::CommandRejected => {
+                increment_counter!(Metric::NodeCommandsRejected.to_string());
+            }
+            MetricEvent::StorePut => match value {
+                Some(latency) => histogram!(Metric::StorePutLatency.to_string(), latency),
+                None => error!(
+                    "missing required value for {} event",
+                    Metric::StorePutLatency
+                ),
+            },
+            MetricEvent::UploadBytes => match value {
+                Some(bytes) => counter!(Metric::HttpUploadBytes.to_string(), bytes as u64),
+                None => error!(
+                    "missing required value for {} event",
+                    Metric::HttpUploadBytes
+                ),
+            },
+            MetricEvent::BlocksScrubbed => match value {
+                Some(blocks) => counter!(Metric::StoreScrubbedBlocks.to_string(), blocks as u64),
+                None => error!(
+                    "missing required value for {} event",
+                    Metric::StoreScrubbedBlocks
+                ),
+            },
+            MetricEvent::CorruptBlocks => match value {
+                Some(blocks) => counter!(Metric::StoreCorruptBlocks.to_string(), blocks as u64),
+                None => error!(
+                    "missing required value for {} event",
+                    Metric::StoreCorruptBlocks
+                ),
+            },
+            MetricEvent::StreamOpened => {
+                increment_gauge!(Metric::ActiveStreams.to_string(), 1.0);
+            }
+            MetricEvent::StreamClosed => {
+                decrement_gauge!(Metric::ActiveStreams.to_string(), 1.0);
+            }
             _ => info!("missing label for {:?}", event_name),
         }
     }
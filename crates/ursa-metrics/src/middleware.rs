@@ -21,14 +21,15 @@ pub async fn track_metrics<B>(req: Request<B>, next: Next<B>) -> impl IntoRespon
     let latency = start.elapsed().as_secs_f64();
     let status = response.status().as_u16().to_string();
 
+    // requests by route and status, the latency goes in the histogram instead of a
+    // label so every request does not make a series of its own
     let labels = vec![
         Label::new("method", method.to_string()),
         Label::new("path", path),
         Label::new("status", status),
-        Label::new("latency", format!("{}", latency)),
     ];
 
-    track(MetricEvent::RpcRequestReceived, None, None);
+    track(MetricEvent::RpcRequestReceived, Some(labels.clone()), None);
     track(MetricEvent::RpcResponseSent, Some(labels), Some(latency));
 
     response
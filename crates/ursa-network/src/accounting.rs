@@ -0,0 +1,360 @@
+//! Bandwidth accounting for settlement.
+//!
+//! The bytes of every retrieval are added up per cid, and per api key for http or
+//! per peer id for car files pulled over the exchange protocol, into rollups of
+//! `rollup_secs`. Totals are kept in memory and added to the rollups stored under
+//! `accounting/<start>` once a minute, so a restart loses a minute of accounting at
+//! most. Blocks served over bitswap are not accounted, bitswap does not tell the
+//! store which peer asks for them.
+
+use anyhow::{anyhow, Result};
+use async_std::task;
+use fnv::FnvHashMap;
+use ipld_blockstore::BlockStore;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::warn;
+use ursa_store::{columns::Column, Store};
+use ursa_utils::unix_now;
+
+/// Database key of the starts of the stored rollups.
+const ROLLUP_STARTS: &str = "accounting";
+
+/// How often the totals in memory are written to the store.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct AccountingConfig {
+    pub enabled: bool,
+    /// Length of a rollup period.
+    pub rollup_secs: u64,
+    /// Days rollups are kept for, 0 keeps them forever.
+    pub retention_days: u64,
+}
+
+impl Default for AccountingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            rollup_secs: 60 * 60,
+            retention_days: 90,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UsageKind {
+    ApiKey,
+    Peer,
+    Cid,
+}
+
+impl UsageKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            UsageKind::ApiKey => "api_key",
+            UsageKind::Peer => "peer",
+            UsageKind::Cid => "cid",
+        }
+    }
+}
+
+impl FromStr for UsageKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "api_key" => Ok(UsageKind::ApiKey),
+            "peer" => Ok(UsageKind::Peer),
+            "cid" => Ok(UsageKind::Cid),
+            _ => Err(anyhow!(
+                "Unknown usage kind {s}, expected api_key, peer or cid"
+            )),
+        }
+    }
+}
+
+/// Bytes served to one api key, peer or of one cid in a period.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rollup {
+    /// Unix seconds the period starts at.
+    pub start: u64,
+    pub end: u64,
+    pub kind: UsageKind,
+    pub id: String,
+    pub bytes: u64,
+    pub requests: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UsageQuery {
+    pub kind: Option<UsageKind>,
+    pub id: Option<String>,
+    /// Only rollups starting at or after this unix time.
+    pub since: Option<u64>,
+    /// Only rollups starting before this unix time.
+    pub until: Option<u64>,
+}
+
+impl UsageQuery {
+    fn matches(&self, rollup: &Rollup) -> bool {
+        self.kind.map_or(true, |kind| kind == rollup.kind)
+            && self.id.as_ref().map_or(true, |id| *id == rollup.id)
+            && self.since.map_or(true, |since| rollup.start >= since)
+            && self.until.map_or(true, |until| rollup.start < until)
+    }
+}
+
+struct Period {
+    end: u64,
+    totals: FnvHashMap<(UsageKind, String), (u64, u64)>,
+}
+
+#[derive(Default)]
+pub struct Accounting {
+    config: AccountingConfig,
+    /// Totals not written to the store yet, by the start of their period.
+    pending: Mutex<BTreeMap<u64, Period>>,
+    /// Held while the stored rollups are read and written back.
+    flushing: Mutex<()>,
+}
+
+impl Accounting {
+    pub fn new(config: AccountingConfig) -> Self {
+        Self {
+            config,
+            pending: Default::default(),
+            flushing: Default::default(),
+        }
+    }
+
+    /// Account `bytes` of `cid` served to `api_key`, the id of the key, or to `peer`.
+    pub fn record(&self, cid: &str, api_key: Option<&str>, peer: Option<&str>, bytes: u64) {
+        self.record_at(cid, api_key, peer, bytes, unix_now())
+    }
+
+    fn record_at(
+        &self,
+        cid: &str,
+        api_key: Option<&str>,
+        peer: Option<&str>,
+        bytes: u64,
+        now: u64,
+    ) {
+        if !self.config.enabled {
+            return;
+        }
+        let length = self.config.rollup_secs.max(1);
+        let start = now - now % length;
+        let mut pending = self.pending.lock().unwrap();
+        let period = pending.entry(start).or_insert_with(|| Period {
+            end: start + length,
+            totals: Default::default(),
+        });
+        let subjects = [
+            Some((UsageKind::Cid, cid)),
+            api_key.map(|id| (UsageKind::ApiKey, id)),
+            peer.map(|id| (UsageKind::Peer, id)),
+        ];
+        for (kind, id) in subjects.into_iter().flatten() {
+            let total = period.totals.entry((kind, id.to_string())).or_default();
+            total.0 += bytes;
+            total.1 += 1;
+        }
+    }
+
+    /// Add the totals in memory to the rollups in `store`, dropping the rollups past
+    /// the retention.
+    pub fn flush<S: BlockStore + Sync + Send + 'static>(&self, store: &Store<S>) -> Result<()> {
+        self.flush_at(store, unix_now())
+    }
+
+    fn flush_at<S: BlockStore + Sync + Send + 'static>(
+        &self,
+        store: &Store<S>,
+        now: u64,
+    ) -> Result<()> {
+        let _flushing = self.flushing.lock().unwrap();
+        let periods = std::mem::take(&mut *self.pending.lock().unwrap());
+        let column = store.column(Column::Metadata);
+        let mut starts = rollup_starts(store)?;
+        let before = starts.clone();
+
+        for (start, period) in periods {
+            let mut rollups = stored_rollups(store, start)?;
+            for ((kind, id), (bytes, requests)) in period.totals {
+                match rollups.iter_mut().find(|r| r.kind == kind && r.id == id) {
+                    Some(rollup) => {
+                        rollup.bytes += bytes;
+                        rollup.requests += requests;
+                    }
+                    None => rollups.push(Rollup {
+                        start,
+                        end: period.end,
+                        kind,
+                        id,
+                        bytes,
+                        requests,
+                    }),
+                }
+            }
+            column.write(rollup_key(start), serde_json::to_vec(&rollups)?)?;
+            if !starts.contains(&start) {
+                starts.push(start);
+            }
+        }
+
+        if self.config.retention_days > 0 {
+            let oldest = now.saturating_sub(self.config.retention_days * SECS_PER_DAY);
+            for start in starts.iter().filter(|start| **start < oldest) {
+                column.delete(rollup_key(*start))?;
+            }
+            starts.retain(|start| *start >= oldest);
+        }
+        if starts != before {
+            starts.sort_unstable();
+            column.write(ROLLUP_STARTS, serde_json::to_vec(&starts)?)?;
+        }
+        Ok(())
+    }
+
+    /// Stored rollups matching `query`, oldest first, after flushing the totals in
+    /// memory.
+    pub fn rollups<S: BlockStore + Sync + Send + 'static>(
+        &self,
+        store: &Store<S>,
+        query: &UsageQuery,
+    ) -> Result<Vec<Rollup>> {
+        self.flush(store)?;
+        let mut rollups = vec![];
+        for start in rollup_starts(store)? {
+            if query.until.map_or(false, |until| start >= until) {
+                continue;
+            }
+            let mut period: Vec<Rollup> = stored_rollups(store, start)?
+                .into_iter()
+                .filter(|rollup| query.matches(rollup))
+                .collect();
+            period.sort_by(|a, b| (a.kind, &a.id).cmp(&(b.kind, &b.id)));
+            rollups.extend(period);
+        }
+        Ok(rollups)
+    }
+
+    /// Write the totals to `store` every minute.
+    pub fn schedule<S: BlockStore + Sync + Send + 'static>(self: Arc<Self>, store: Arc<Store<S>>) {
+        if !self.config.enabled {
+            return;
+        }
+        task::spawn(async move {
+            loop {
+                task::sleep(FLUSH_INTERVAL).await;
+                if let Err(err) = self.flush(&store) {
+                    warn!("Failed to write the bandwidth accounting: {:?}", err);
+                }
+            }
+        });
+    }
+}
+
+/// `rollups` as csv, with a header line.
+pub fn to_csv(rollups: &[Rollup]) -> String {
+    let mut csv = String::from("start,end,kind,id,bytes,requests\n");
+    for rollup in rollups {
+        csv.push_str(&format!(
+            "{},{},{},{},{},{}\n",
+            rollup.start,
+            rollup.end,
+            rollup.kind.name(),
+            rollup.id,
+            rollup.bytes,
+            rollup.requests
+        ));
+    }
+    csv
+}
+
+fn rollup_key(start: u64) -> String {
+    format!("accounting/{start}")
+}
+
+fn rollup_starts<S: BlockStore + Sync + Send + 'static>(store: &Store<S>) -> Result<Vec<u64>> {
+    match store.column(Column::Metadata).read(ROLLUP_STARTS)? {
+        Some(starts) => Ok(serde_json::from_slice(&starts)?),
+        None => Ok(vec![]),
+    }
+}
+
+fn stored_rollups<S: BlockStore + Sync + Send + 'static>(
+    store: &Store<S>,
+    start: u64,
+) -> Result<Vec<Rollup>> {
+    match store.column(Column::Metadata).read(rollup_key(start))? {
+        Some(rollups) => Ok(serde_json::from_slice(&rollups)?),
+        None => Ok(vec![]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::{rocks::RocksDb, rocks_config::RocksDbConfig};
+
+    #[test]
+    fn test_accounting() {
+        let _ = std::fs::remove_dir_all("accounting_db");
+        let db = RocksDb::open("accounting_db", &RocksDbConfig::default()).unwrap();
+        let store = Store::new(Arc::new(db));
+        let accounting = Accounting::new(AccountingConfig {
+            enabled: true,
+            rollup_secs: 3600,
+            retention_days: 1,
+        });
+        // rollups are dropped relative to the clock when they are queried
+        let day = unix_now() - unix_now() % SECS_PER_DAY;
+
+        accounting.record_at("bafy", Some("key"), None, 100, day + 10);
+        accounting.flush_at(&store, day + 10).unwrap();
+        // later totals of the period are added to the stored rollup
+        accounting.record_at("bafy", Some("key"), None, 50, day + 20);
+        accounting.record_at("bafy", None, Some("peer"), 30, day + 3600);
+        accounting.flush_at(&store, day + 3600).unwrap();
+
+        let query = UsageQuery {
+            kind: Some(UsageKind::ApiKey),
+            ..Default::default()
+        };
+        let rollups = accounting.rollups(&store, &query).unwrap();
+        assert_eq!(
+            rollups,
+            vec![Rollup {
+                start: day,
+                end: day + 3600,
+                kind: UsageKind::ApiKey,
+                id: "key".to_string(),
+                bytes: 150,
+                requests: 2,
+            }]
+        );
+        let cids = UsageQuery {
+            kind: Some(UsageKind::Cid),
+            since: Some(day + 3600),
+            ..Default::default()
+        };
+        assert_eq!(accounting.rollups(&store, &cids).unwrap()[0].bytes, 30);
+        assert!(to_csv(&rollups).ends_with("api_key,key,150,2\n"));
+
+        // past the retention
+        accounting.flush_at(&store, day + 2 * SECS_PER_DAY).unwrap();
+        assert!(rollup_starts(&store).unwrap().is_empty());
+    }
+}
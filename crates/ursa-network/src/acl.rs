@@ -0,0 +1,400 @@
+//! Per-cid access control.
+//!
+//! Dags marked private are only served to the peers and api keys on their
+//! [`AclEntry`]. The http content routes check the api key of the request, and the
+//! request-response server checks the peer asking for a car file or for providers.
+//! Bitswap does not tell the store which peer wants a block, so the blocks of private
+//! dags are not served over bitswap at all, allow-listed peers pull them as car
+//! files instead.
+//!
+//! Entries are kept in the node database under a single key. The blocks of each
+//! private dag are listed again when the node starts, so children of a private root
+//! cannot be fetched by their own cid. Blocks a private dag links to but the store
+//! lacks are listed too, and once one is stored over bitswap its own links join the
+//! dag. [`Acl::refresh`] lists the incomplete dags again after other writes.
+
+use anyhow::Result;
+use cid::Cid;
+use fnv::{FnvHashMap, FnvHashSet};
+use ipld_blockstore::BlockStore;
+use libipld::{store::DefaultParams, Block, Cid as lCid};
+use libp2p::PeerId;
+use libp2p_bitswap::BitswapStore;
+use serde::{Deserialize, Serialize};
+use std::{
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+use ursa_store::{columns::Column, BitswapStorage, Store};
+use ursa_utils::{ToCid, ToIpldCid};
+
+/// Database key of the acl entries.
+pub(crate) const ACL_KEY: &str = "acl";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AclEntry {
+    /// Root cid of the private dag.
+    pub cid: String,
+    /// Peer ids allowed to pull the dag over the request-response protocol.
+    pub peers: Vec<String>,
+    /// Ids of the api keys allowed to retrieve the dag over http.
+    pub api_keys: Vec<String>,
+}
+
+#[derive(Default)]
+struct AclState {
+    entries: FnvHashMap<Cid, AclEntry>,
+    /// Private roots each block belongs to, stored or not.
+    blocks: FnvHashMap<Cid, Vec<Cid>>,
+    /// Private roots whose dag was not fully stored when it was listed.
+    partial: FnvHashSet<Cid>,
+}
+
+impl AclState {
+    fn insert(&mut self, root: Cid, entry: AclEntry, (blocks, complete): (Vec<Cid>, bool)) {
+        self.remove(&root);
+        for block in blocks {
+            self.add_block(block, root);
+        }
+        if !complete {
+            self.partial.insert(root);
+        }
+        self.entries.insert(root, entry);
+    }
+
+    fn add_block(&mut self, block: Cid, root: Cid) {
+        let roots = self.blocks.entry(block).or_default();
+        if !roots.contains(&root) {
+            roots.push(root);
+        }
+    }
+
+    fn remove(&mut self, root: &Cid) -> bool {
+        if self.entries.remove(root).is_none() {
+            return false;
+        }
+        self.partial.remove(root);
+        self.blocks.retain(|_, roots| {
+            roots.retain(|r| r != root);
+            !roots.is_empty()
+        });
+        true
+    }
+
+    /// Entries of the private dags `cid` belongs to.
+    fn entries_of(&self, cid: &Cid) -> Vec<&AclEntry> {
+        match self.blocks.get(cid) {
+            Some(roots) => roots
+                .iter()
+                .filter_map(|root| self.entries.get(root))
+                .collect(),
+            None => self.entries.get(cid).into_iter().collect(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Acl {
+    state: RwLock<AclState>,
+}
+
+impl Acl {
+    /// Load the entries stored in `store`.
+    pub fn load<S: BlockStore + Sync + Send + 'static>(store: &Store<S>) -> Result<Self> {
+        let entries: Vec<AclEntry> = match store.column(Column::Metadata).read(ACL_KEY)? {
+            Some(entries) => serde_json::from_slice(&entries)?,
+            None => vec![],
+        };
+
+        let mut state = AclState::default();
+        for entry in entries {
+            let root = Cid::from_str(&entry.cid)?;
+            state.insert(root, entry, dag_blocks(store, root));
+        }
+        Ok(Self {
+            state: RwLock::new(state),
+        })
+    }
+
+    /// Make the dag under `root` private to `peers` and `api_keys`, replacing its
+    /// previous entry.
+    pub fn set<S: BlockStore + Sync + Send + 'static>(
+        &self,
+        store: &Store<S>,
+        root: Cid,
+        peers: Vec<PeerId>,
+        api_keys: Vec<String>,
+    ) -> Result<AclEntry> {
+        let entry = AclEntry {
+            cid: root.to_string(),
+            peers: peers.iter().map(PeerId::to_string).collect(),
+            api_keys,
+        };
+        let blocks = dag_blocks(store, root);
+
+        let mut state = self.state.write().unwrap();
+        state.insert(root, entry.clone(), blocks);
+        save(store, &state)?;
+        Ok(entry)
+    }
+
+    /// Make the dag under `root` public again, returning whether it was private.
+    pub fn remove<S: BlockStore + Sync + Send + 'static>(
+        &self,
+        store: &Store<S>,
+        root: &Cid,
+    ) -> Result<bool> {
+        let mut state = self.state.write().unwrap();
+        if !state.remove(root) {
+            return Ok(false);
+        }
+        save(store, &state)?;
+        Ok(true)
+    }
+
+    /// List the private dags that were incomplete again, picking up the blocks written
+    /// since, e.g. by a put.
+    pub fn refresh<S: BlockStore + Sync + Send + 'static>(&self, store: &Store<S>) {
+        let partial: Vec<(Cid, AclEntry)> = {
+            let state = self.state.read().unwrap();
+            state
+                .partial
+                .iter()
+                .filter_map(|root| Some((*root, state.entries.get(root)?.clone())))
+                .collect()
+        };
+        for (root, entry) in partial {
+            let blocks = dag_blocks(store, root);
+            let mut state = self.state.write().unwrap();
+            // removed or replaced in the meantime
+            if state.entries.get(&root) == Some(&entry) {
+                state.insert(root, entry, blocks);
+            }
+        }
+    }
+
+    /// Add the links of `block` to the private dags it belongs to, once it is stored.
+    pub fn stored(&self, block: &Block<DefaultParams>) {
+        let cid = block.cid().to_cid();
+        let roots = match self.state.read().unwrap().blocks.get(&cid) {
+            Some(roots) => roots.clone(),
+            None => return,
+        };
+        let mut links = FnvHashSet::default();
+        if block.references(&mut links).is_err() {
+            return;
+        }
+        let mut state = self.state.write().unwrap();
+        for link in links {
+            for root in &roots {
+                state.add_block(link.to_cid(), *root);
+            }
+        }
+    }
+
+    pub fn entries(&self) -> Vec<AclEntry> {
+        self.state
+            .read()
+            .unwrap()
+            .entries
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    pub fn is_private(&self, cid: &Cid) -> bool {
+        !self.state.read().unwrap().entries_of(cid).is_empty()
+    }
+
+    /// Whether `peer` may retrieve `cid`.
+    pub fn allows_peer(&self, cid: &Cid, peer: &PeerId) -> bool {
+        let state = self.state.read().unwrap();
+        let entries = state.entries_of(cid);
+        let peer = peer.to_string();
+        entries.is_empty() || entries.iter().any(|entry| entry.peers.contains(&peer))
+    }
+
+    /// Whether a request made with api key `key_id` may retrieve `cid`.
+    pub fn allows_key(&self, cid: &Cid, key_id: Option<&str>) -> bool {
+        let state = self.state.read().unwrap();
+        let entries = state.entries_of(cid);
+        entries.is_empty()
+            || key_id.map_or(false, |id| {
+                entries
+                    .iter()
+                    .any(|entry| entry.api_keys.iter().any(|key| key == id))
+            })
+    }
+}
+
+/// Blocks of the dag under `root`, with the missing blocks linked to from the stored
+/// ones, and whether all of them are stored.
+fn dag_blocks<S: BlockStore + Sync + Send + 'static>(
+    store: &Store<S>,
+    root: Cid,
+) -> (Vec<Cid>, bool) {
+    let (mut blocks, mut complete) = (vec![], true);
+    let mut stack = vec![root.to_ipld_cid()];
+    let mut seen = FnvHashSet::default();
+    while let Some(cid) = stack.pop() {
+        if !seen.insert(cid) {
+            continue;
+        }
+        blocks.push(cid.to_cid());
+        match store.blockstore().read(cid.to_bytes()) {
+            Ok(Some(data)) => {
+                if let Ok(block) = Block::<DefaultParams>::new(cid, data) {
+                    let _ = block.references(&mut stack);
+                }
+            }
+            _ => complete = false,
+        }
+    }
+    (blocks, complete)
+}
+
+fn save<S: BlockStore + Sync + Send + 'static>(store: &Store<S>, state: &AclState) -> Result<()> {
+    let entries: Vec<&AclEntry> = state.entries.values().collect();
+    store
+        .column(Column::Metadata)
+        .write(ACL_KEY, serde_json::to_vec(&entries)?)?;
+    Ok(())
+}
+
+/// Bitswap store withholding the blocks of private dags.
+pub struct AclStorage<S>(pub BitswapStorage<S>, pub Arc<Acl>)
+where
+    S: BlockStore + Sync + Send + 'static;
+
+impl<S> BitswapStore for AclStorage<S>
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    type Params = DefaultParams;
+
+    fn contains(&mut self, cid: &lCid) -> libipld::Result<bool> {
+        if self.1.is_private(&cid.to_cid()) {
+            return Ok(false);
+        }
+        self.0.contains(cid)
+    }
+
+    fn get(&mut self, cid: &lCid) -> libipld::Result<Option<Vec<u8>>> {
+        if self.1.is_private(&cid.to_cid()) {
+            return Ok(None);
+        }
+        self.0.get(cid)
+    }
+
+    fn insert(&mut self, block: &Block<Self::Params>) -> libipld::Result<()> {
+        self.0.insert(block)?;
+        self.1.stored(block);
+        Ok(())
+    }
+
+    fn missing_blocks(&mut self, cid: &lCid) -> libipld::Result<Vec<lCid>> {
+        self.0.missing_blocks(cid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::{rocks::RocksDb, rocks_config::RocksDbConfig, MemoryDB};
+    use libipld::{cbor::DagCborCodec, ipld, multihash::Code};
+
+    #[test]
+    fn test_private_dag() {
+        let db = RocksDb::open("acl_db", &RocksDbConfig::default()).unwrap();
+        let store = Arc::new(Store::new(Arc::new(db)));
+        let mut bitswap = BitswapStorage(Arc::clone(&store));
+
+        let leaf =
+            Block::<DefaultParams>::encode(DagCborCodec, Code::Blake3_256, &ipld!("leaf")).unwrap();
+        let root = Block::<DefaultParams>::encode(
+            DagCborCodec,
+            Code::Blake3_256,
+            &ipld!({ "leaf": *leaf.cid() }),
+        )
+        .unwrap();
+        bitswap.insert(&leaf).unwrap();
+        bitswap.insert(&root).unwrap();
+        let (root_cid, leaf_cid): (Cid, Cid) = (root.cid().to_cid(), leaf.cid().to_cid());
+
+        let acl = Arc::new(Acl::load(&store).unwrap());
+        let allowed = PeerId::random();
+        acl.set(&store, root_cid, vec![allowed], vec!["key".to_string()])
+            .unwrap();
+
+        assert!(acl.allows_peer(&leaf_cid, &allowed));
+        assert!(!acl.allows_peer(&leaf_cid, &PeerId::random()));
+        assert!(acl.allows_key(&root_cid, Some("key")));
+        assert!(!acl.allows_key(&root_cid, None));
+
+        let mut storage = AclStorage(bitswap, Arc::clone(&acl));
+        assert_eq!(storage.get(leaf.cid()).unwrap(), None);
+
+        // entries survive a restart
+        assert!(Acl::load(&store).unwrap().is_private(&leaf_cid));
+
+        assert!(acl.remove(&store, &root_cid).unwrap());
+        assert!(storage.get(leaf.cid()).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_corrupt_entries() {
+        let store = Arc::new(Store::new(Arc::new(MemoryDB::default())));
+        let column = store.column(Column::Metadata);
+
+        column.write(ACL_KEY, b"not json".to_vec()).unwrap();
+        assert!(Acl::load(&store).is_err());
+
+        let entries = vec![AclEntry {
+            cid: "not a cid".to_string(),
+            peers: vec![],
+            api_keys: vec![],
+        }];
+        column
+            .write(ACL_KEY, serde_json::to_vec(&entries).unwrap())
+            .unwrap();
+        assert!(Acl::load(&store).is_err());
+    }
+    #[test]
+    fn test_blocks_stored_later() {
+        let store = Arc::new(Store::new(Arc::new(MemoryDB::default())));
+        let encode =
+            |ipld| Block::<DefaultParams>::encode(DagCborCodec, Code::Blake3_256, &ipld).unwrap();
+        let leaf = encode(ipld!("leaf"));
+        let middle = encode(ipld!({ "leaf": *leaf.cid() }));
+        let root = encode(ipld!({ "middle": *middle.cid() }));
+        let mut storage = AclStorage(BitswapStorage(Arc::clone(&store)), Arc::default());
+        storage.insert(&root).unwrap();
+
+        let acl = Arc::clone(&storage.1);
+        acl.set(&store, root.cid().to_cid(), vec![], vec![])
+            .unwrap();
+        assert!(acl.is_private(&middle.cid().to_cid()));
+        assert!(!acl.is_private(&leaf.cid().to_cid()));
+
+        // the links of a block synced over bitswap join the dag
+        storage.insert(&middle).unwrap();
+        assert!(acl.is_private(&leaf.cid().to_cid()));
+
+        // as do those of the blocks written otherwise, once refreshed
+        let other = encode(ipld!("other"));
+        let list = encode(ipld!([*other.cid()]));
+        let extended = encode(ipld!({ "middle": *middle.cid(), "list": *list.cid() }));
+        let blocks = [&extended, &list, &other];
+        acl.set(&store, extended.cid().to_cid(), vec![], vec![])
+            .unwrap();
+        for block in blocks {
+            store
+                .blockstore()
+                .write(block.cid().to_bytes(), block.data())
+                .unwrap();
+        }
+        assert!(!acl.is_private(&other.cid().to_cid()));
+        acl.refresh(&store);
+        assert!(acl.is_private(&other.cid().to_cid()));
+    }
+}
@@ -0,0 +1,161 @@
+//! Automatic relay selection.
+//!
+//! Once autonat finds the node behind a NAT, it listens through a relay so peers can
+//! still reach it. The static `relays` of the config are tried in order. Without
+//! them, peers advertising the relay hop protocol in identify become candidates,
+//! and the most reputable one is picked. A relay refusing the reservation is skipped
+//! for the next candidate until the NAT status changes.
+
+use fnv::FnvHashMap;
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use std::collections::HashSet;
+
+use crate::{info::RelayInfo, reputation::ReputationStore};
+
+/// Protocol of the relay v2 servers that accept reservations.
+pub const RELAY_HOP_PROTOCOL: &str = "/libp2p/circuit/relay/0.2.0/hop";
+
+#[derive(Default)]
+pub struct AutoRelay {
+    /// Relays from the config, with their `/p2p/<peer id>`.
+    static_relays: Vec<Multiaddr>,
+    /// Discovered relays and an address to reach them on.
+    candidates: FnvHashMap<PeerId, Multiaddr>,
+    /// Relays that refused a reservation since the node went private.
+    failed: HashSet<PeerId>,
+    /// Relay the node listens through, and the circuit address.
+    selected: Option<(PeerId, Multiaddr)>,
+    /// Whether the node is behind a NAT.
+    wanted: bool,
+}
+
+impl AutoRelay {
+    pub fn new(static_relays: Vec<Multiaddr>) -> Self {
+        Self {
+            static_relays,
+            ..Default::default()
+        }
+    }
+
+    /// Remember `peer` as a relay when it runs a relay server, returns whether it is
+    /// a new candidate.
+    pub fn add_candidate(
+        &mut self,
+        peer: PeerId,
+        protocols: &[String],
+        addrs: &[Multiaddr],
+    ) -> bool {
+        if !protocols.iter().any(|p| p == RELAY_HOP_PROTOCOL) {
+            return false;
+        }
+        let addr = addrs
+            .iter()
+            .find(|addr| !RelayInfo::is_relayed(addr) && !is_loopback(addr));
+        match addr {
+            Some(addr) => self.candidates.insert(peer, addr.clone()).is_none(),
+            None => false,
+        }
+    }
+
+    /// Start or stop looking for a relay as the node goes private or public.
+    pub fn set_wanted(&mut self, wanted: bool) {
+        if wanted != self.wanted {
+            self.wanted = wanted;
+            self.failed.clear();
+            if !wanted {
+                self.selected = None;
+            }
+        }
+    }
+
+    /// The relay to listen through next and the circuit address, when the node
+    /// needs one and has none yet.
+    pub fn select(&mut self, reputation: &ReputationStore) -> Option<(PeerId, Multiaddr)> {
+        if !self.wanted || self.selected.is_some() {
+            return None;
+        }
+        let selected = if self.static_relays.is_empty() {
+            let candidates = self
+                .candidates
+                .keys()
+                .filter(|peer| !self.failed.contains(peer))
+                .copied();
+            reputation.rank(candidates).into_iter().next().map(|peer| {
+                let addr = self.candidates[&peer]
+                    .clone()
+                    .with(Protocol::P2p(peer.into()));
+                (peer, addr)
+            })
+        } else {
+            self.static_relays
+                .iter()
+                .find_map(|addr| match addr.iter().last() {
+                    Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash)
+                        .ok()
+                        .filter(|peer| !self.failed.contains(peer))
+                        .map(|peer| (peer, addr.clone())),
+                    _ => None,
+                })
+        };
+        self.selected = selected.map(|(peer, addr)| (peer, addr.with(Protocol::P2pCircuit)));
+        self.selected.clone()
+    }
+
+    /// The reservation on `peer` failed or was closed, a different relay is selected
+    /// next.
+    pub fn failed(&mut self, peer: PeerId) {
+        self.failed.insert(peer);
+        if matches!(&self.selected, Some((selected, _)) if *selected == peer) {
+            self.selected = None;
+        }
+    }
+
+    pub fn selected(&self) -> Option<PeerId> {
+        self.selected.as_ref().map(|(peer, _)| *peer)
+    }
+}
+
+fn is_loopback(addr: &Multiaddr) -> bool {
+    addr.iter().any(|protocol| match protocol {
+        Protocol::Ip4(ip) => ip.is_loopback(),
+        Protocol::Ip6(ip) => ip.is_loopback(),
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_relay() {
+        let reputation = ReputationStore::default();
+        let hop = vec![RELAY_HOP_PROTOCOL.to_string()];
+        let addrs: Vec<Multiaddr> = vec![
+            "/ip4/127.0.0.1/tcp/6009".parse().unwrap(),
+            "/ip4/1.2.3.4/tcp/6009".parse().unwrap(),
+        ];
+        let (first, second) = (PeerId::random(), PeerId::random());
+
+        let mut autorelay = AutoRelay::default();
+        assert!(!autorelay.add_candidate(first, &[], &addrs));
+        assert!(autorelay.add_candidate(first, &hop, &addrs));
+        assert!(autorelay.select(&reputation).is_none());
+
+        autorelay.set_wanted(true);
+        let (relay, addr) = autorelay.select(&reputation).unwrap();
+        assert_eq!(relay, first);
+        assert_eq!(
+            addr,
+            format!("/ip4/1.2.3.4/tcp/6009/p2p/{first}/p2p-circuit")
+                .parse()
+                .unwrap()
+        );
+        assert!(autorelay.select(&reputation).is_none());
+
+        autorelay.failed(first);
+        assert!(autorelay.select(&reputation).is_none());
+        assert!(autorelay.add_candidate(second, &hop, &addrs));
+        assert_eq!(autorelay.select(&reputation).unwrap().0, second);
+    }
+}
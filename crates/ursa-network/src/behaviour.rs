@@ -13,10 +13,12 @@
 //!   request/response protocol or protocol family, whereby each request is
 //!   sent over a new substream on a connection.
 
-use anyhow::{Error, Result};
+use anyhow::{anyhow, Error, Result};
 use cid::Cid;
 use fnv::FnvHashMap;
-use futures::channel::oneshot;
+use futures::channel::{mpsc, oneshot};
+use futures::FutureExt;
+use futures_timer::Delay;
 use libipld::store::StoreParams;
 use libp2p::autonat::{Event, NatStatus};
 use libp2p::dcutr;
@@ -27,8 +29,8 @@ use libp2p::{
     dcutr::behaviour::Event as DcutrEvent,
     gossipsub::{
         error::{PublishError, SubscriptionError},
-        Gossipsub, GossipsubEvent, GossipsubMessage, IdentTopic as Topic, MessageId,
-        PeerScoreParams, PeerScoreThresholds, TopicHash,
+        Gossipsub, GossipsubEvent, GossipsubMessage, IdentTopic as Topic, MessageAcceptance,
+        MessageId, PeerScoreParams, PeerScoreThresholds, TopicHash,
     },
     identify::{Identify, IdentifyConfig, IdentifyEvent},
     identity::Keypair,
@@ -36,33 +38,51 @@ use libp2p::{
     ping::{Ping, PingEvent, PingFailure, PingSuccess},
     relay::v2::{
         client::{Client as RelayClient, Event as RelayClientEvent},
-        relay::{Config as RelayConfig, Event as RelayServerEvent, Relay as RelayServer},
+        relay::{Event as RelayServerEvent, Relay as RelayServer},
     },
     request_response::{
         ProtocolSupport, RequestId, RequestResponse, RequestResponseConfig, RequestResponseEvent,
         RequestResponseMessage, ResponseChannel,
     },
-    swarm::{
-        NetworkBehaviour, NetworkBehaviourAction, NetworkBehaviourEventProcess, PollParameters,
-    },
+    swarm::{CloseConnection, NetworkBehaviour, NetworkBehaviourAction, PollParameters},
     Multiaddr, NetworkBehaviour, PeerId,
 };
 use libp2p_bitswap::{Bitswap, BitswapConfig, BitswapEvent, BitswapStore, QueryId};
 use std::{
     collections::{HashMap, HashSet, VecDeque},
-    iter,
+    sync::Arc,
     task::{Context, Poll},
-    time::Duration,
+    time::{Duration, Instant},
 };
 use tracing::{debug, error, trace, warn};
-use ursa_utils::convert_cid;
+use ursa_utils::ToIpldCid;
 
 use crate::discovery::URSA_KAD_PROTOCOL;
 use crate::{
-    codec::protocol::{UrsaExchangeCodec, UrsaExchangeRequest, UrsaExchangeResponse, UrsaProtocol},
+    autorelay::AutoRelay,
+    cache_summary::{CacheSummary, CachedRoots},
+    codec::protocol::{
+        ContentProvider, DagSelector, RequestType, ResponseType, UrsaExchangeCodec,
+        UrsaExchangeRequest, UrsaExchangeResponse, UrsaProtocol,
+    },
+    compat::{self, AgentVersion, Feature},
     config::NetworkConfig,
     discovery::{DiscoveryBehaviour, DiscoveryEvent},
-    gossipsub::UrsaGossipsub,
+    gossipsub::{
+        GossipConfig, GossipTopicStat, TopicCounters, TopicPeers, TopicSubscriptions,
+        UrsaGossipsub, FANOUT_PEERS,
+    },
+    hints::{ContentAnnouncement, ProviderHints, URSA_CONTENT},
+    info::{NatInfo, NodeInfo, RelayInfo},
+    inventory::{PeerIdentity, ProtocolInventory},
+    names::{self, NameCache, NameRecord, URSA_NAMES},
+    peer_tags::{ConnectionConfig, PeerTag, PeerTags},
+    purge::{PurgeConfig, PurgeMessage, URSA_PURGE},
+    quota::{QuotaCheck, RequestQuotas},
+    relay::{RelayCircuit, RelayCircuits, RelayLimitsConfig},
+    reputation::ReputationStore,
+    senders::BlockSenders,
+    shaping::{Shaper, TrafficClass},
 };
 
 pub type BlockSenderChannel<T> = oneshot::Sender<Result<T, Error>>;
@@ -74,8 +94,34 @@ pub struct BitswapInfo {
     pub block_found: bool,
 }
 
+/// Providers of a bitswap query. They are asked one after the other, bitswap does not
+/// tell which peer sent a block and a single one asked is the peer it came from.
+struct QueryProviders {
+    sync: bool,
+    /// The provider asked by the running query first.
+    peers: VecDeque<PeerId>,
+    /// When the running query asked its provider or last made progress.
+    asked_at: Instant,
+}
+
 pub const IPFS_PROTOCOL: &str = "ipfs/0.1.0";
 
+/// Distinct observed addresses kept for the node info.
+const MAX_OBSERVED_ADDRS: usize = 8;
+
+/// Age after which a peer's cache summary is requested again.
+const SUMMARY_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Time a cache summary request is given before the peer is asked again.
+const SUMMARY_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often connections are checked for the idle timeout.
+const IDLE_SWEEP: Duration = Duration::from_secs(10);
+
+/// Time a provider is given before a bitswap query falls through to the next one, the
+/// last provider is given the full bitswap timeout.
+const PROVIDER_TIMEOUT: Duration = Duration::from_secs(5);
+
 fn ursa_agent() -> String {
     format!("ursa/{}", env!("CARGO_PKG_VERSION"))
 }
@@ -101,11 +147,43 @@ pub enum BehaviourEvent {
         peer_id: PeerId,
     },
     /// An event trigger when a relay circuit is opened
-    RelayCircuitOpened,
+    RelayCircuitOpened {
+        src_peer_id: PeerId,
+        dst_peer_id: PeerId,
+        /// Limits the circuit is closed at.
+        max_duration: Duration,
+        max_bytes: u64,
+    },
     /// An event trigger when a relay circuit is closed
-    RelayCircuitClosed,
+    RelayCircuitClosed {
+        src_peer_id: PeerId,
+        dst_peer_id: PeerId,
+        duration: Duration,
+        /// Why the circuit failed, unset when it was closed cleanly or hit a limit.
+        error: Option<String>,
+    },
+    /// The protocols the node answers on changed since it started.
+    ProtocolsChanged,
+    /// Autorelay picked a relay to listen through on `addr`, the circuit address.
+    RelaySelected {
+        relay_peer_id: PeerId,
+        addr: Multiaddr,
+    },
+    /// The relay server denied a reservation, or a circuit when `dst_peer_id` is set.
+    RelayDenied {
+        src_peer_id: PeerId,
+        dst_peer_id: Option<PeerId>,
+        /// Name and value of the limit the request most likely hit.
+        limit: &'static str,
+        max: usize,
+    },
     /// A Gossip message request was received from a peer.
     Bitswap(BitswapInfo),
+    /// A bitswap sync query received a block.
+    BitswapProgress {
+        cid: Cid,
+        missing: usize,
+    },
     GossipMessage {
         peer: PeerId,
         topic: TopicHash,
@@ -121,6 +199,109 @@ pub enum BehaviourEvent {
     StartPublish {
         public_address: Multiaddr,
     },
+    /// A peer asked for a dag as a car file.
+    CarRequest {
+        peer: PeerId,
+        root: String,
+        selector: DagSelector,
+        channel: ResponseChannel<UrsaExchangeResponse>,
+    },
+    /// A peer asked for the providers of a cid.
+    FindContent {
+        peer: PeerId,
+        cid: String,
+        channel: ResponseChannel<UrsaExchangeResponse>,
+    },
+    /// A peer went over its request quota, emitted for the first request dropped in
+    /// a window and when the peer is disconnected.
+    PeerThrottled {
+        peer: PeerId,
+        /// Requests of the peer in the current window.
+        requests: u32,
+        disconnected: bool,
+    },
+    /// A purge signed by an admin key was received.
+    Purge(PurgeMessage),
+}
+
+type UrsaRequestResponseEvent = RequestResponseEvent<UrsaExchangeRequest, UrsaExchangeResponse>;
+
+/// Events emitted by the swarm, the raw events of the sub-behaviours and the
+/// [`BehaviourEvent`]s they are turned into by [`Behaviour::handle_event`].
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum NetworkEvent {
+    Ping(PingEvent),
+    Identify(IdentifyEvent),
+    Autonat(AutonatEvent),
+    RelayServer(RelayServerEvent),
+    RelayClient(RelayClientEvent),
+    Dcutr(DcutrEvent),
+    Bitswap(BitswapEvent),
+    Gossipsub(GossipsubEvent),
+    Discovery(DiscoveryEvent),
+    RequestResponse(UrsaRequestResponseEvent),
+    Ursa(BehaviourEvent),
+}
+
+impl From<PingEvent> for NetworkEvent {
+    fn from(event: PingEvent) -> Self {
+        Self::Ping(event)
+    }
+}
+
+impl From<IdentifyEvent> for NetworkEvent {
+    fn from(event: IdentifyEvent) -> Self {
+        Self::Identify(event)
+    }
+}
+
+impl From<AutonatEvent> for NetworkEvent {
+    fn from(event: AutonatEvent) -> Self {
+        Self::Autonat(event)
+    }
+}
+
+impl From<RelayServerEvent> for NetworkEvent {
+    fn from(event: RelayServerEvent) -> Self {
+        Self::RelayServer(event)
+    }
+}
+
+impl From<RelayClientEvent> for NetworkEvent {
+    fn from(event: RelayClientEvent) -> Self {
+        Self::RelayClient(event)
+    }
+}
+
+impl From<DcutrEvent> for NetworkEvent {
+    fn from(event: DcutrEvent) -> Self {
+        Self::Dcutr(event)
+    }
+}
+
+impl From<BitswapEvent> for NetworkEvent {
+    fn from(event: BitswapEvent) -> Self {
+        Self::Bitswap(event)
+    }
+}
+
+impl From<GossipsubEvent> for NetworkEvent {
+    fn from(event: GossipsubEvent) -> Self {
+        Self::Gossipsub(event)
+    }
+}
+
+impl From<DiscoveryEvent> for NetworkEvent {
+    fn from(event: DiscoveryEvent) -> Self {
+        Self::Discovery(event)
+    }
+}
+
+impl From<UrsaRequestResponseEvent> for NetworkEvent {
+    fn from(event: UrsaRequestResponseEvent) -> Self {
+        Self::RequestResponse(event)
+    }
 }
 
 /// A `Networkbehaviour` that handles Ursa's different protocol implementations.
@@ -128,12 +309,13 @@ pub enum BehaviourEvent {
 /// The poll function must have the same signature as the NetworkBehaviour
 /// function and will be called last within the generated NetworkBehaviour implementation.
 ///
-/// The events generated [`BehaviourEvent`].
+/// The events of the sub-behaviours are not processed here but handed to the swarm as
+/// [`NetworkEvent`]s, the service passes them back to [`Behaviour::handle_event`].
 #[derive(NetworkBehaviour)]
 #[behaviour(
-    out_event = "BehaviourEvent",
+    out_event = "NetworkEvent",
     poll_method = "poll",
-    event_process = true
+    event_process = false
 )]
 pub struct Behaviour<P: StoreParams> {
     /// Alive checks.
@@ -178,8 +360,140 @@ pub struct Behaviour<P: StoreParams> {
     #[behaviour(ignore)]
     pending_responses: HashMap<RequestId, oneshot::Sender<Result<UrsaExchangeResponse>>>,
 
+    /// When pending requests were sent, to measure the throughput of their responses.
+    #[behaviour(ignore)]
+    request_started: HashMap<RequestId, (PeerId, Instant)>,
+
     #[behaviour(ignore)]
     queries: FnvHashMap<QueryId, BitswapInfo>,
+
+    /// Agents and protocols reported by identified peers.
+    #[behaviour(ignore)]
+    peer_identities: HashMap<PeerId, PeerIdentity>,
+
+    /// Last ping round trip time of connected peers.
+    #[behaviour(ignore)]
+    peer_rtt: HashMap<PeerId, Duration>,
+
+    /// Persisted peer reputations used to rank bitswap providers.
+    #[behaviour(ignore)]
+    reputation: ReputationStore,
+
+    /// Providers of in-flight bitswap queries, the asked one is credited once a query
+    /// completes.
+    #[behaviour(ignore)]
+    query_providers: FnvHashMap<QueryId, QueryProviders>,
+
+    /// Message counters per gossipsub topic.
+    #[behaviour(ignore)]
+    gossip_stats: FnvHashMap<TopicHash, TopicCounters>,
+
+    /// Subscribers of every gossipsub topic.
+    #[behaviour(ignore)]
+    subscriptions: TopicSubscriptions,
+
+    /// Bootstrappers keep no mesh, their messages go to the subscribers directly.
+    #[behaviour(ignore)]
+    bootstrapper: bool,
+
+    /// Root cids cached by this node, summarized for other peers.
+    #[behaviour(ignore)]
+    cached_roots: CachedRoots,
+
+    /// Cache summaries received from peers and when they were received.
+    #[behaviour(ignore)]
+    peer_summaries: HashMap<PeerId, (Instant, CacheSummary)>,
+
+    /// Peers asked for their cache summary and when, so a summary is requested once.
+    #[behaviour(ignore)]
+    summary_requests: HashMap<PeerId, Instant>,
+
+    /// Pending Kademlia provider lookups.
+    #[behaviour(ignore)]
+    provider_queries: HashMap<kad::QueryId, mpsc::UnboundedSender<ContentProvider>>,
+
+    /// Newest name records seen on the DHT and gossipsub.
+    #[behaviour(ignore)]
+    names: NameCache,
+
+    /// Providers of freshly cached roots, as announced on the content topic.
+    #[behaviour(ignore)]
+    provider_hints: ProviderHints,
+
+    /// Pending Kademlia name lookups.
+    #[behaviour(ignore)]
+    name_queries: HashMap<kad::QueryId, (String, oneshot::Sender<Option<NameRecord>>)>,
+
+    /// Addresses identified peers observed this node on, most recent last.
+    #[behaviour(ignore)]
+    observed_addrs: VecDeque<Multiaddr>,
+
+    /// Protocols supported by the swarm, filled in on the first poll.
+    #[behaviour(ignore)]
+    protocols: Vec<String>,
+
+    /// Optional behaviours enabled in the config.
+    #[behaviour(ignore)]
+    features: Vec<String>,
+
+    /// Peers holding a reservation on the relay server.
+    #[behaviour(ignore)]
+    relay_reservations: HashSet<PeerId>,
+
+    /// Circuits open on the relay server.
+    #[behaviour(ignore)]
+    relay_circuits: RelayCircuits,
+
+    /// Limits the relay server was built with.
+    #[behaviour(ignore)]
+    relay_limits: RelayLimitsConfig,
+
+    /// Relays the node may listen through behind a NAT.
+    #[behaviour(ignore)]
+    autorelay: AutoRelay,
+
+    /// Roles of the peers, deciding which ones are disconnected first.
+    #[behaviour(ignore)]
+    peer_tags: PeerTags,
+
+    #[behaviour(ignore)]
+    connections: ConnectionConfig,
+
+    /// Peers to disconnect to get back under the connection limit.
+    #[behaviour(ignore)]
+    to_prune: VecDeque<PeerId>,
+
+    /// Last traffic with each connected peer, for the idle timeout.
+    #[behaviour(ignore)]
+    last_active: HashMap<PeerId, Instant>,
+
+    #[behaviour(ignore)]
+    last_idle_sweep: Instant,
+
+    /// Wakes the behaviour when the provider of a running query runs out of time.
+    #[behaviour(ignore)]
+    provider_timer: Option<Delay>,
+
+    /// Peers the blocks of the running queries come from, charged for the blocks the
+    /// bitswap store refused.
+    #[behaviour(ignore)]
+    senders: BlockSenders,
+
+    /// Upload caps, the gossip one checked on publish.
+    #[behaviour(ignore)]
+    shaper: Arc<Shaper>,
+
+    /// Size limit and topic allowlist gossip messages are validated against.
+    #[behaviour(ignore)]
+    gossip: GossipConfig,
+
+    /// Inbound exchange requests per peer.
+    #[behaviour(ignore)]
+    quotas: RequestQuotas,
+
+    /// Admin keys and topic of purges, unset when purges are not obeyed.
+    #[behaviour(ignore)]
+    purge: Option<PurgeConfig>,
 }
 
 impl<P: StoreParams> Behaviour<P> {
@@ -187,12 +501,16 @@ impl<P: StoreParams> Behaviour<P> {
         keypair: &Keypair,
         config: &NetworkConfig,
         bitswap_store: S,
+        senders: BlockSenders,
         relay_client: Option<libp2p::relay::v2::client::Client>,
+        shaper: Arc<Shaper>,
     ) -> Self {
         let local_public_key = keypair.public();
         let local_peer_id = PeerId::from(local_public_key.clone());
 
-        // Setup the ping behaviour
+        // Setup the ping behaviour. Ping holds every connection open, which ones are
+        // idle is decided by `close_idle` rather than the short idle timeouts of the
+        // protocol handlers
         let ping = Ping::new(PingConfig::new().with_keep_alive(true));
 
         // Setup the gossip behaviour
@@ -205,6 +523,11 @@ impl<P: StoreParams> Behaviour<P> {
         // Setup the discovery behaviour
         let discovery = DiscoveryBehaviour::new(keypair, config);
 
+        let mut peer_tags = PeerTags::default();
+        for (peer_id, _) in discovery.bootstrap_addrs() {
+            peer_tags.tag(peer_id, PeerTag::Bootstrap);
+        }
+
         // Setup the bitswap behaviour
         let bitswap = Bitswap::new(BitswapConfig::default(), bitswap_store);
 
@@ -220,7 +543,11 @@ impl<P: StoreParams> Behaviour<P> {
             // todo(botch): calculate an upper limit to allow for large files
             cfg.set_request_timeout(Duration::from_secs(60));
 
-            let protocols = iter::once((UrsaProtocol, ProtocolSupport::Full));
+            // newest first, streams are negotiated on the first one the peer knows
+            let protocols = [
+                (UrsaProtocol::V1, ProtocolSupport::Full),
+                (UrsaProtocol::V0, ProtocolSupport::Full),
+            ];
 
             RequestResponse::new(UrsaExchangeCodec, protocols, cfg)
         };
@@ -239,7 +566,7 @@ impl<P: StoreParams> Behaviour<P> {
 
         let relay_server = config
             .relay_server
-            .then(|| RelayServer::new(local_public_key.into(), RelayConfig::default()))
+            .then(|| RelayServer::new(local_public_key.into(), config.relay_limits.relay_config()))
             .into();
 
         let dcutr = config
@@ -252,6 +579,17 @@ impl<P: StoreParams> Behaviour<P> {
             })
             .into();
 
+        let features = [
+            ("mdns", config.mdns),
+            ("autonat", config.autonat),
+            ("relay_server", config.relay_server),
+            ("relay_client", config.relay_client),
+            ("bootstrapper", config.bootstrapper),
+        ]
+        .into_iter()
+        .filter_map(|(feature, enabled)| enabled.then(|| feature.to_string()))
+        .collect();
+
         Behaviour {
             ping,
             autonat,
@@ -266,16 +604,148 @@ impl<P: StoreParams> Behaviour<P> {
             events: VecDeque::new(),
             pending_requests: HashMap::default(),
             pending_responses: HashMap::default(),
+            request_started: HashMap::default(),
             queries: Default::default(),
+            peer_identities: HashMap::default(),
+            peer_rtt: HashMap::default(),
+            reputation: Default::default(),
+            query_providers: Default::default(),
+            gossip_stats: Default::default(),
+            subscriptions: Default::default(),
+            bootstrapper: config.bootstrapper,
+            cached_roots: Default::default(),
+            peer_summaries: Default::default(),
+            summary_requests: Default::default(),
+            provider_queries: Default::default(),
+            names: Default::default(),
+            provider_hints: ProviderHints::new(&config.content_hints),
+            name_queries: Default::default(),
+            observed_addrs: Default::default(),
+            protocols: Default::default(),
+            features,
+            relay_reservations: Default::default(),
+            relay_circuits: Default::default(),
+            relay_limits: config.relay_limits.clone(),
+            autorelay: AutoRelay::new(config.relays.clone()),
+            peer_tags,
+            connections: config.connections.clone(),
+            to_prune: Default::default(),
+            last_active: Default::default(),
+            last_idle_sweep: Instant::now(),
+            provider_timer: None,
+            senders,
+            shaper,
+            gossip: config.gossip.clone(),
+            quotas: RequestQuotas::new(config.request_quotas.clone()),
+            purge: config.purge.clone(),
         }
     }
 
-    pub fn publish(
-        &mut self,
-        topic: Topic,
-        data: GossipsubMessage,
-    ) -> Result<MessageId, PublishError> {
-        self.gossipsub.publish(topic, data.data)
+    pub fn publish(&mut self, topic: Topic, data: GossipsubMessage) -> Result<MessageId> {
+        // a refused message takes no tokens from the cap
+        let peers = self.publish_peers(&topic.hash());
+        if !self.gossip.can_publish(peers) {
+            return Err(anyhow!(
+                "Only {} peers to publish to on {}, {} needed",
+                peers,
+                topic,
+                self.gossip.min_publish_peers
+            ));
+        }
+        if !self.shaper.try_take(TrafficClass::Gossip, data.data.len()) {
+            return Err(anyhow!(
+                "The gossip upload cap is reached, not publishing to {}",
+                topic
+            ));
+        }
+        let id = self.gossipsub.publish(topic.clone(), data.data)?;
+        self.gossip_stats
+            .entry(topic.hash())
+            .or_default()
+            .record_published();
+        Ok(id)
+    }
+
+    /// Peers a message published on `topic` is sent to.
+    fn publish_peers(&self, topic: &TopicHash) -> usize {
+        if self.gossipsub.topics().any(|t| t == topic) && !self.bootstrapper {
+            self.gossipsub.mesh_peers(topic).count()
+        } else {
+            self.subscriptions.count(topic)
+        }
+    }
+
+    /// Whether a message published on `topic` now reaches `min_publish_peers`.
+    pub fn can_publish(&self, topic: &TopicHash) -> bool {
+        self.subscriptions.count(topic) > 0 && self.gossip.can_publish(self.publish_peers(topic))
+    }
+
+    /// Subscribers of every known topic, or of `topic`.
+    pub fn topic_peers(&self, topic: Option<&str>) -> Vec<TopicPeers> {
+        let subscribed: HashSet<&TopicHash> = self.gossipsub.topics().collect();
+        let mut topics: Vec<&TopicHash> = self
+            .subscriptions
+            .topics()
+            .chain(subscribed.iter().copied())
+            .filter(|hash| topic.map_or(true, |topic| hash.as_str() == topic))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        topics.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        topics
+            .into_iter()
+            .map(|topic| {
+                let mut peers: Vec<String> = self
+                    .subscriptions
+                    .peers(topic)
+                    .map(PeerId::to_string)
+                    .collect();
+                peers.sort();
+                TopicPeers {
+                    topic: topic.to_string(),
+                    subscribed: subscribed.contains(topic),
+                    peers,
+                    mesh_peers: self.gossipsub.mesh_peers(topic).count(),
+                    publishable: self.can_publish(topic),
+                }
+            })
+            .collect()
+    }
+
+    /// Mesh, fanout and message statistics of every known gossipsub topic.
+    pub fn gossip_stat(&mut self) -> Vec<GossipTopicStat> {
+        let subscribed: HashSet<TopicHash> = self.gossipsub.topics().cloned().collect();
+
+        let topics: HashSet<TopicHash> = subscribed
+            .iter()
+            .chain(self.subscriptions.topics())
+            .chain(self.gossip_stats.keys())
+            .cloned()
+            .collect();
+
+        topics
+            .into_iter()
+            .map(|topic| {
+                let is_subscribed = subscribed.contains(&topic);
+                let mesh_peers = self.gossipsub.mesh_peers(&topic).copied().collect();
+                // the subscribers a publish fans out to, the most reputable first
+                let fanout_peers = if is_subscribed {
+                    vec![]
+                } else {
+                    let mut peers = self
+                        .reputation
+                        .rank(self.subscriptions.peers(&topic).copied());
+                    peers.truncate(FANOUT_PEERS);
+                    peers
+                };
+                self.gossip_stats.entry(topic.clone()).or_default().stat(
+                    &topic,
+                    is_subscribed,
+                    mesh_peers,
+                    fanout_peers,
+                )
+            })
+            .collect()
     }
 
     pub fn public_address(&self) -> Option<&Multiaddr> {
@@ -286,10 +756,158 @@ impl<P: StoreParams> Behaviour<P> {
         self.discovery.peers().clone()
     }
 
+    /// Agent version of a peer, if it has been identified.
+    pub fn peer_version(&self, peer: &PeerId) -> Option<AgentVersion> {
+        self.peer_identities
+            .get(peer)
+            .map(|identity| identity.version)
+    }
+
+    /// Agents and protocols of the identified peers, listing only the peers speaking
+    /// `protocol` when set.
+    pub fn peer_protocols(&self, protocol: Option<&str>) -> ProtocolInventory {
+        ProtocolInventory::new(&self.peer_identities, protocol)
+    }
+
+    /// Whether `peer` understands `feature`, going by the exchange protocols it lists.
+    ///
+    /// Peers that have not been identified yet are assumed to be up to date, the
+    /// stream negotiation still refuses requests an older peer cannot decode.
+    pub fn supports(&self, peer: &PeerId, feature: Feature) -> bool {
+        self.peer_identities
+            .get(peer)
+            .map(|identity| compat::supports(&identity.protocols, feature))
+            .unwrap_or(true)
+    }
+
+    pub fn reputation(&self) -> &ReputationStore {
+        &self.reputation
+    }
+
+    pub fn reputation_mut(&mut self) -> &mut ReputationStore {
+        &mut self.reputation
+    }
+
+    /// Replace the reputations by those persisted.
+    pub fn set_reputation(&mut self, reputation: ReputationStore) {
+        self.reputation = reputation;
+    }
+
+    pub fn tag_peer(&mut self, peer: PeerId, tag: PeerTag) {
+        self.peer_tags.tag(peer, tag);
+    }
+
+    pub fn untag_peer(&mut self, peer: &PeerId, tag: PeerTag) {
+        self.peer_tags.untag(peer, tag);
+    }
+
+    pub fn peer_tags(&self, peer: &PeerId) -> Vec<PeerTag> {
+        self.peer_tags.tags(peer)
+    }
+
     pub fn is_relay_client_enabled(&self) -> bool {
         self.relay_client.is_enabled()
     }
 
+    /// Send the identify info of this node to `peers` now rather than at their next
+    /// periodic identify.
+    pub fn push_identify<I: IntoIterator<Item = PeerId>>(&mut self, peers: I) {
+        self.identify.push(peers);
+    }
+
+    /// Record traffic with `peer`, keeping its connection out of the idle timeout.
+    fn touch(&mut self, peer: PeerId) {
+        if let Some(active) = self.last_active.get_mut(&peer) {
+            *active = Instant::now();
+        }
+    }
+
+    /// Queue the connections idle for longer than the idle timeout for closing. Peers
+    /// with requests or bitswap queries in flight and gossip mesh peers are kept.
+    fn close_idle(&mut self) {
+        // peers queued earlier are still being disconnected
+        if !self.to_prune.is_empty() {
+            return;
+        }
+        let busy: HashSet<PeerId> = self
+            .request_started
+            .values()
+            .map(|(peer, _)| *peer)
+            .chain(
+                self.query_providers
+                    .values()
+                    .flat_map(|providers| providers.peers.iter())
+                    .copied(),
+            )
+            .chain(self.gossipsub.all_mesh_peers().copied())
+            .collect();
+        let idle = self
+            .peer_tags
+            .idle(&self.last_active, &busy, &self.connections);
+        if !idle.is_empty() {
+            debug!("Closing {} idle connections", idle.len());
+            self.to_prune.extend(idle);
+        }
+    }
+
+    /// Look for a relay to listen through while the node is behind a NAT.
+    pub fn want_relay(&mut self, wanted: bool) {
+        if self.relay_client.is_enabled() {
+            self.autorelay.set_wanted(wanted);
+            self.select_relay();
+        }
+    }
+
+    /// Listening through `relay_peer_id` failed, select another relay.
+    pub fn relay_failed(&mut self, relay_peer_id: PeerId) {
+        self.autorelay.failed(relay_peer_id);
+        self.select_relay();
+    }
+
+    fn select_relay(&mut self) {
+        if let Some((relay_peer_id, addr)) = self.autorelay.select(&self.reputation) {
+            self.events.push_back(BehaviourEvent::RelaySelected {
+                relay_peer_id,
+                addr,
+            });
+        }
+    }
+
+    /// Node info of this node, listening on `listen_addrs`.
+    pub fn node_info(&self, peer_id: PeerId, listen_addrs: Vec<Multiaddr>) -> NodeInfo {
+        let nat = match self.autonat.as_ref() {
+            Some(autonat) => autonat.nat_status().into(),
+            None => NatInfo::Disabled,
+        };
+        let relay = RelayInfo {
+            server: self.relay_server.is_enabled(),
+            client: self.relay_client.is_enabled(),
+            reservations: self.relay_reservations.len(),
+            circuits: self.relay_circuits.len(),
+            relay: self.autorelay.selected().map(|peer| peer.to_string()),
+            relayed_addrs: listen_addrs
+                .iter()
+                .filter(|addr| RelayInfo::is_relayed(addr))
+                .map(Multiaddr::to_string)
+                .collect(),
+        };
+
+        NodeInfo {
+            peer_id: peer_id.to_string(),
+            agent_version: ursa_agent(),
+            listen_addrs: listen_addrs.iter().map(Multiaddr::to_string).collect(),
+            observed_addrs: self
+                .observed_addrs
+                .iter()
+                .map(Multiaddr::to_string)
+                .collect(),
+            nat,
+            relay,
+            protocols: self.protocols.clone(),
+            features: self.features.clone(),
+        }
+    }
+
     pub fn discovery(&mut self) -> &mut DiscoveryBehaviour {
         &mut self.discovery
     }
@@ -299,6 +917,9 @@ impl<P: StoreParams> Behaviour<P> {
     }
 
     pub fn subscribe(&mut self, topic: &Topic) -> Result<bool, SubscriptionError> {
+        if !self.gossip.allows(&topic.hash()) {
+            return Err(SubscriptionError::NotAllowed);
+        }
         self.gossipsub.subscribe(topic)
     }
 
@@ -318,24 +939,268 @@ impl<P: StoreParams> Behaviour<P> {
         request: UrsaExchangeRequest,
         sender: oneshot::Sender<Result<UrsaExchangeResponse>>,
     ) -> Result<()> {
+        let feature = request.feature();
+        if !self.supports(&peer, feature) {
+            let version = self.peer_version(&peer).unwrap_or(AgentVersion::LEGACY);
+            warn!(
+                "peer {} on agent version {} does not support {:?}",
+                peer, version, feature
+            );
+            let _ = sender.send(Err(anyhow!("Peer {} does not support {:?}", peer, feature)));
+            return Ok(());
+        }
+
         let request_id = self.request_response.send_request(&peer, request);
         self.pending_responses.insert(request_id, sender);
+        self.request_started
+            .insert(request_id, (peer, Instant::now()));
+        self.touch(peer);
 
         Ok(())
     }
 
-    pub fn get_block(&mut self, cid: Cid, providers: impl Iterator<Item = PeerId>) {
-        debug!("get block via rpc called, the requested cid is: {:?}", cid);
-        let id = self.bitswap.get(convert_cid(cid.to_bytes()), providers);
+    pub fn send_response(
+        &mut self,
+        channel: ResponseChannel<UrsaExchangeResponse>,
+        response: UrsaExchangeResponse,
+    ) -> Result<()> {
+        self.request_response
+            .send_response(channel, response)
+            .map_err(|_| anyhow!("Failed to send response, the channel is closed"))
+    }
 
-        self.queries.insert(
-            id,
-            BitswapInfo {
-                query_id: id,
+    /// Connected peers ordered by their last ping round trip time.
+    ///
+    /// Peers without a measured rtt come last.
+    pub fn nearest_peers(&self) -> Vec<PeerId> {
+        let mut peers: Vec<PeerId> = self.peers().into_iter().collect();
+        peers.sort_by_key(|peer| self.peer_rtt.get(peer).copied().unwrap_or(Duration::MAX));
+        peers
+    }
+
+    /// Ask up to `replicas` of the nearest peers to replicate the dag under `cid`.
+    ///
+    /// Returns the peers the request was sent to.
+    pub fn replicate(&mut self, cid: Cid, replicas: usize) -> Vec<PeerId> {
+        let request = UrsaExchangeRequest(RequestType::Replicate(cid.to_string()));
+        let peers: Vec<PeerId> = self
+            .nearest_peers()
+            .into_iter()
+            .filter(|peer| self.supports(peer, request.feature()))
+            .take(replicas)
+            .collect();
+
+        for peer in &peers {
+            debug!("asking peer {} to replicate {}", peer, cid);
+            self.request_response.send_request(peer, request.clone());
+        }
+
+        peers
+    }
+
+    pub fn add_cached_root(&mut self, cid: Cid) {
+        self.cached_roots.insert(cid);
+    }
+
+    pub fn remove_cached_root(&mut self, cid: &Cid) {
+        self.cached_roots.remove(cid);
+    }
+
+    /// Replace the cached roots by those persisted.
+    pub fn set_cached_roots(&mut self, roots: CachedRoots) {
+        self.cached_roots = roots;
+    }
+
+    pub fn cached_roots_mut(&mut self) -> &mut CachedRoots {
+        &mut self.cached_roots
+    }
+
+    /// Ask `peer` for a summary of its cached root cids, unless it was just asked.
+    fn request_cache_summary(&mut self, peer: PeerId) {
+        let pending = self
+            .summary_requests
+            .get(&peer)
+            .map_or(false, |at| at.elapsed() < SUMMARY_REQUEST_TIMEOUT);
+        let request = UrsaExchangeRequest(RequestType::CacheSummary);
+        if !pending && self.supports(&peer, request.feature()) {
+            self.request_response.send_request(&peer, request);
+            self.summary_requests.insert(peer, Instant::now());
+        }
+    }
+
+    /// Narrow `peers` down to those whose cache summary contains `cid`.
+    ///
+    /// Falls back to all `peers` when none of them is known to have it. Stale
+    /// summaries are refreshed in the background.
+    pub fn route(&mut self, cid: &Cid, peers: HashSet<PeerId>) -> HashSet<PeerId> {
+        let stale: Vec<PeerId> = peers
+            .iter()
+            .filter(|peer| {
+                self.peer_summaries
+                    .get(peer)
+                    .map_or(true, |(at, _)| at.elapsed() > SUMMARY_TTL)
+            })
+            .copied()
+            .collect();
+        for peer in stale {
+            self.request_cache_summary(peer);
+        }
+
+        let providers: HashSet<PeerId> = peers
+            .iter()
+            .filter(|peer| {
+                self.peer_summaries
+                    .get(peer)
+                    .map_or(false, |(_, summary)| summary.contains(cid))
+            })
+            .copied()
+            .collect();
+
+        if providers.is_empty() {
+            peers
+        } else {
+            debug!(
+                "routing {} to {} peers by cache summary",
                 cid,
-                block_found: false,
-            },
+                providers.len()
+            );
+            providers
+        }
+    }
+
+    /// Providers of `cid` known to this node without a lookup.
+    ///
+    /// Combines the provider records of the DHT, the peers that announced `cid` on
+    /// the content topic and the connected peers whose cache summary contains it.
+    pub fn known_providers(&mut self, cid: &Cid) -> Vec<ContentProvider> {
+        let mut providers = self.discovery.providers(cid);
+        for (peer, addrs) in self.provider_hints.providers(cid) {
+            if !providers.iter().any(|(p, _)| *p == peer) {
+                providers.push((peer, addrs));
+            }
+        }
+        let cached: Vec<PeerId> = self
+            .peer_summaries
+            .iter()
+            .filter(|(peer, (_, summary))| {
+                summary.contains(cid) && !providers.iter().any(|(p, _)| p == *peer)
+            })
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in cached {
+            let addrs = self.discovery.addresses_of_peer(&peer);
+            providers.push((peer, addrs));
+        }
+
+        providers
+            .into_iter()
+            .map(|(peer, addrs)| ContentProvider {
+                peer_id: peer.to_string(),
+                addrs: addrs.iter().map(Multiaddr::to_string).collect(),
+            })
+            .collect()
+    }
+
+    /// Keep `peer` as a provider hint of `cid`, reachable on `addrs`.
+    fn content_announced(&mut self, cid: Cid, peer: PeerId, addrs: Vec<Multiaddr>) {
+        if !self.provider_hints.is_enabled() {
+            return;
+        }
+        debug!("{} announced {}", peer, cid);
+        for addr in &addrs {
+            self.discovery.add_address(&peer, addr.clone());
+        }
+        self.provider_hints.insert(cid, peer, addrs);
+    }
+
+    /// Look up the providers of `cid` with a full Kademlia walk.
+    ///
+    /// Providers already known are sent right away, the ones found by the walk follow
+    /// and the sender is dropped once the walk completes.
+    pub fn find_providers(&mut self, cid: &Cid, sender: mpsc::UnboundedSender<ContentProvider>) {
+        for provider in self.known_providers(cid) {
+            if sender.unbounded_send(provider).is_err() {
+                return;
+            }
+        }
+        let id = self.discovery.get_providers(cid);
+        self.provider_queries.insert(id, sender);
+    }
+
+    /// Circuits relayed by this node, oldest first.
+    pub fn relay_circuits(&self) -> Vec<RelayCircuit> {
+        self.relay_circuits.list(&self.relay_limits)
+    }
+
+    /// Gossip a signed purge to the nodes subscribed to the purge topic.
+    pub fn publish_purge(&mut self, purge: &PurgeMessage) -> Result<()> {
+        let topic = Topic::new(
+            self.purge
+                .as_ref()
+                .map_or(URSA_PURGE, |config| config.topic()),
         );
+        let message = GossipsubMessage {
+            source: None,
+            data: serde_json::to_vec(purge)?,
+            sequence_number: None,
+            topic: topic.hash(),
+        };
+        self.publish(topic, message)?;
+        Ok(())
+    }
+
+    /// Put `record` in the DHT and gossip it to the subscribers of the names topic.
+    pub fn publish_name(&mut self, record: NameRecord) -> Result<()> {
+        let value = serde_json::to_vec(&record)?;
+        let key = names::record_key(&record.name);
+        self.names.insert(record);
+        if let Err(err) = self.discovery.put_record(key, value.clone()) {
+            warn!(
+                "[Behaviour::publish_name] - failed to put the record: {:?}",
+                err
+            );
+        }
+        let topic = Topic::new(URSA_NAMES);
+        let message = GossipsubMessage {
+            source: None,
+            data: value,
+            sequence_number: None,
+            topic: topic.hash(),
+        };
+        if let Err(err) = self.publish(topic, message) {
+            // nobody is subscribed yet, the dht record still resolves
+            debug!(
+                "[Behaviour::publish_name] - failed to gossip the record: {:?}",
+                err
+            );
+        }
+        Ok(())
+    }
+
+    /// Find the newest record of `name`, from the records seen or with a Kademlia
+    /// lookup.
+    pub fn resolve_name(&mut self, name: String, sender: oneshot::Sender<Option<NameRecord>>) {
+        if let Some(record) = self.names.get(&name) {
+            if sender.send(Some(record.clone())).is_err() {
+                warn!(
+                    "[Behaviour::resolve_name] - failed to send the record of {}",
+                    name
+                );
+            }
+            return;
+        }
+        let id = self.discovery.get_record(names::record_key(&name));
+        self.name_queries.insert(id, (name, sender));
+    }
+
+    pub fn get_block(&mut self, cid: Cid, providers: impl Iterator<Item = PeerId>) {
+        debug!("get block via rpc called, the requested cid is: {:?}", cid);
+        let providers = QueryProviders {
+            sync: false,
+            peers: self.reputation.rank(providers).into(),
+            asked_at: Instant::now(),
+        };
+        self.query(cid, providers);
     }
 
     pub fn sync_block(&mut self, cid: Cid, providers: Vec<PeerId>) {
@@ -343,8 +1208,29 @@ impl<P: StoreParams> Behaviour<P> {
             "sync block via http called, the requested root cid is: {:?}",
             cid
         );
-        let c_cid = convert_cid(cid.to_bytes());
-        let id = self.bitswap.sync(c_cid, providers, std::iter::once(c_cid));
+        let providers = QueryProviders {
+            sync: true,
+            peers: self.reputation.rank(providers).into(),
+            asked_at: Instant::now(),
+        };
+        self.query(cid, providers);
+    }
+
+    /// Query the first of `providers` for `cid`.
+    fn query(&mut self, cid: Cid, mut providers: QueryProviders) {
+        providers.asked_at = Instant::now();
+        let c_cid = cid.to_ipld_cid();
+        let peer = providers.peers.front().copied();
+        if let Some(peer) = peer {
+            self.senders.expect(c_cid, peer);
+        }
+        let id = if providers.sync {
+            self.bitswap
+                .sync(c_cid, peer.into_iter().collect(), std::iter::once(c_cid))
+        } else {
+            self.bitswap.get(c_cid, peer.into_iter())
+        };
+        self.query_providers.insert(id, providers);
         self.queries.insert(
             id,
             BitswapInfo {
@@ -356,27 +1242,132 @@ impl<P: StoreParams> Behaviour<P> {
     }
 
     pub fn cancel(&mut self, id: QueryId) {
-        self.queries.remove(&id);
+        if let Some(info) = self.queries.remove(&id) {
+            self.senders.finish(&info.cid.to_ipld_cid());
+        }
+        self.query_providers.remove(&id);
         self.bitswap.cancel(id);
     }
 
+    /// Give up on the providers that did not answer within [`PROVIDER_TIMEOUT`] and ask
+    /// the next ranked one, so a slow peer does not hold the query for the bitswap timeout.
+    fn skip_slow_providers(&mut self, cx: &mut Context) {
+        let slow: Vec<QueryId> = self
+            .query_providers
+            .iter()
+            .filter(|(_, providers)| {
+                providers.peers.len() > 1 && providers.asked_at.elapsed() >= PROVIDER_TIMEOUT
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in slow {
+            let (mut providers, info) =
+                match (self.query_providers.remove(&id), self.queries.remove(&id)) {
+                    (Some(providers), Some(info)) => (providers, info),
+                    _ => continue,
+                };
+            self.bitswap.cancel(id);
+            self.senders.finish(&info.cid.to_ipld_cid());
+            if let Some(peer) = providers.peers.pop_front() {
+                debug!(
+                    "[BitswapEvent] - {} did not send {} in time, asking the next provider",
+                    peer, info.cid
+                );
+                self.reputation.record_bitswap(peer, false);
+            }
+            self.query(info.cid, providers);
+        }
+
+        let next = self
+            .query_providers
+            .values()
+            .filter(|providers| providers.peers.len() > 1)
+            .map(|providers| PROVIDER_TIMEOUT.saturating_sub(providers.asked_at.elapsed()))
+            .min();
+        self.provider_timer = next.map(Delay::new);
+        if let Some(timer) = self.provider_timer.as_mut() {
+            if timer.poll_unpin(cx).is_ready() {
+                cx.waker().wake_by_ref();
+            }
+        }
+    }
+
+    /// Cancel the bitswap queries for `cid`, returns whether one was running.
+    pub fn cancel_block(&mut self, cid: &Cid) -> bool {
+        let ids: Vec<QueryId> = self
+            .queries
+            .iter()
+            .filter(|(_, info)| info.cid == *cid)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &ids {
+            self.cancel(*id);
+        }
+        !ids.is_empty()
+    }
+
     fn poll(
         &mut self,
-        _: &mut Context,
-        _: &mut impl PollParameters,
+        cx: &mut Context,
+        params: &mut impl PollParameters,
     ) -> Poll<
         NetworkBehaviourAction<
             <Self as NetworkBehaviour>::OutEvent,
             <Self as NetworkBehaviour>::ConnectionHandler,
         >,
     > {
+        if self.protocols.len() != params.supported_protocols().len() {
+            let started = !self.protocols.is_empty();
+            self.protocols = params
+                .supported_protocols()
+                .map(|protocol| String::from_utf8_lossy(&protocol).into_owned())
+                .collect();
+            if started {
+                self.events.push_back(BehaviourEvent::ProtocolsChanged);
+            }
+        }
+
+        if self.last_idle_sweep.elapsed() >= IDLE_SWEEP {
+            self.last_idle_sweep = Instant::now();
+            self.close_idle();
+        }
+
+        self.skip_slow_providers(cx);
+
+        if let Some(peer_id) = self.to_prune.pop_front() {
+            return Poll::Ready(NetworkBehaviourAction::CloseConnection {
+                peer_id,
+                connection: CloseConnection::All,
+            });
+        }
+
         if let Some(event) = self.events.pop_front() {
-            return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
+            return Poll::Ready(NetworkBehaviourAction::GenerateEvent(NetworkEvent::Ursa(
+                event,
+            )));
         }
 
         Poll::Pending
     }
 
+    /// Process an event of a sub-behaviour, the [`BehaviourEvent`]s it results in are
+    /// emitted on the next poll.
+    pub fn handle_event(&mut self, event: NetworkEvent) {
+        match event {
+            NetworkEvent::Ping(event) => self.handle_ping(event),
+            NetworkEvent::Identify(event) => self.handle_identify(event),
+            NetworkEvent::Autonat(event) => self.handle_autonat(event),
+            NetworkEvent::RelayServer(event) => self.handle_relay_server(event),
+            NetworkEvent::RelayClient(event) => self.handle_relay_client(event),
+            NetworkEvent::Dcutr(event) => self.handle_dcutr(event),
+            NetworkEvent::Bitswap(event) => self.handle_bitswap(event),
+            NetworkEvent::Gossipsub(event) => self.handle_gossipsub(event),
+            NetworkEvent::Discovery(event) => self.handle_discovery(event),
+            NetworkEvent::RequestResponse(event) => self.handle_request_response(event),
+            NetworkEvent::Ursa(event) => self.events.push_front(event),
+        }
+    }
+
     fn handle_ping(&mut self, event: PingEvent) {
         let peer = event.peer.to_base58();
 
@@ -394,10 +1385,15 @@ impl<P: StoreParams> Behaviour<P> {
                         rtt.as_millis(),
                         peer
                     );
-                    // perhaps we can set rtt for each peer
+                    self.peer_rtt.insert(event.peer, rtt);
+                    self.reputation.record_ping(event.peer, true);
+                    self.reputation.record_rtt(event.peer, rtt);
                 }
             },
             Err(err) => {
+                if !matches!(err, PingFailure::Unsupported) {
+                    self.reputation.record_ping(event.peer, false);
+                }
                 match err {
                     PingFailure::Timeout => {
                         debug!(
@@ -430,6 +1426,27 @@ impl<P: StoreParams> Behaviour<P> {
                     peer_id
                 );
 
+                let version = AgentVersion::from_agent(&info.agent_version);
+                debug!(
+                    "[IdentifyEvent::Received] - peer {} is running agent version {}",
+                    peer_id, version
+                );
+                self.peer_identities.insert(
+                    peer_id,
+                    PeerIdentity {
+                        agent: info.agent_version.clone(),
+                        version,
+                        protocols: info.protocols.clone(),
+                    },
+                );
+
+                if !self.observed_addrs.contains(&info.observed_addr) {
+                    if self.observed_addrs.len() == MAX_OBSERVED_ADDRS {
+                        self.observed_addrs.pop_front();
+                    }
+                    self.observed_addrs.push_back(info.observed_addr.clone());
+                }
+
                 if self.peers().contains(&peer_id) {
                     trace!(
                         "[IdentifyEvent::Received] - peer {} already known!",
@@ -437,18 +1454,32 @@ impl<P: StoreParams> Behaviour<P> {
                     );
                 }
 
+                if self
+                    .autorelay
+                    .add_candidate(peer_id, &info.protocols, &info.listen_addrs)
+                {
+                    debug!("[IdentifyEvent::Received] - peer {} runs a relay", peer_id);
+                    self.select_relay();
+                }
+
                 // check if received identify is from a peer on the same network
                 if info
                     .protocols
                     .iter()
                     .any(|name| name.as_bytes() == URSA_KAD_PROTOCOL)
                 {
-                    self.gossipsub.add_explicit_peer(&peer_id);
+                    if !self.peer_tags.has_tag(&peer_id, PeerTag::NoGossip) {
+                        self.gossipsub.add_explicit_peer(&peer_id);
+                    }
 
                     for address in info.listen_addrs {
                         self.discovery.add_address(&peer_id, address.clone());
                         self.request_response.add_address(&peer_id, address.clone());
                     }
+
+                    if !self.peer_summaries.contains_key(&peer_id) {
+                        self.request_cache_summary(peer_id);
+                    }
                 }
             }
             IdentifyEvent::Sent { .. }
@@ -476,6 +1507,7 @@ impl<P: StoreParams> Behaviour<P> {
                 src_peer_id,
                 renewed,
             } => {
+                self.relay_reservations.insert(src_peer_id);
                 if !renewed {
                     self.events
                         .push_back(BehaviourEvent::RelayReservationOpened {
@@ -484,16 +1516,63 @@ impl<P: StoreParams> Behaviour<P> {
                 }
             }
             RelayServerEvent::ReservationTimedOut { src_peer_id } => {
+                self.relay_reservations.remove(&src_peer_id);
                 self.events
                     .push_back(BehaviourEvent::RelayReservationClosed {
                         peer_id: src_peer_id,
                     });
             }
-            RelayServerEvent::CircuitReqAccepted { .. } => {
-                self.events.push_back(BehaviourEvent::RelayCircuitOpened);
+            RelayServerEvent::CircuitReqAccepted {
+                src_peer_id,
+                dst_peer_id,
+            } => {
+                self.relay_circuits.open(src_peer_id, dst_peer_id);
+                self.events.push_back(BehaviourEvent::RelayCircuitOpened {
+                    src_peer_id,
+                    dst_peer_id,
+                    max_duration: Duration::from_secs(self.relay_limits.max_circuit_duration_secs),
+                    max_bytes: self.relay_limits.max_circuit_bytes,
+                });
+            }
+            RelayServerEvent::CircuitClosed {
+                src_peer_id,
+                dst_peer_id,
+                error,
+            } => {
+                if let Some(duration) = self.relay_circuits.close(&src_peer_id, &dst_peer_id) {
+                    self.events.push_back(BehaviourEvent::RelayCircuitClosed {
+                        src_peer_id,
+                        dst_peer_id,
+                        duration,
+                        error: error.map(|e| e.to_string()),
+                    });
+                }
+            }
+            RelayServerEvent::ReservationReqDenied { src_peer_id } => {
+                let (limit, max) = self
+                    .relay_limits
+                    .reservation_limit(self.relay_reservations.len());
+                self.events.push_back(BehaviourEvent::RelayDenied {
+                    src_peer_id,
+                    dst_peer_id: None,
+                    limit,
+                    max,
+                });
             }
-            RelayServerEvent::CircuitClosed { .. } => {
-                self.events.push_back(BehaviourEvent::RelayCircuitClosed);
+            RelayServerEvent::CircuitReqDenied {
+                src_peer_id,
+                dst_peer_id,
+            } => {
+                let (limit, max) = self.relay_limits.circuit_limit(
+                    self.relay_circuits.len(),
+                    self.relay_reservations.contains(&dst_peer_id),
+                );
+                self.events.push_back(BehaviourEvent::RelayDenied {
+                    src_peer_id,
+                    dst_peer_id: Some(dst_peer_id),
+                    limit,
+                    max,
+                });
             }
             _ => {}
         }
@@ -501,6 +1580,19 @@ impl<P: StoreParams> Behaviour<P> {
 
     fn handle_relay_client(&mut self, event: RelayClientEvent) {
         debug!("[RelayClientEvent] {:?}", event);
+        match event {
+            RelayClientEvent::ReservationReqAccepted { relay_peer_id, .. } => {
+                self.peer_tags.tag(relay_peer_id, PeerTag::Relay);
+            }
+            RelayClientEvent::ReservationReqFailed { relay_peer_id, .. } => {
+                warn!(
+                    "[RelayClientEvent] - reservation on {} failed",
+                    relay_peer_id
+                );
+                self.relay_failed(relay_peer_id);
+            }
+            _ => {}
+        }
     }
 
     fn handle_dcutr(&mut self, event: DcutrEvent) {
@@ -510,22 +1602,52 @@ impl<P: StoreParams> Behaviour<P> {
     fn handle_bitswap(&mut self, event: BitswapEvent) {
         match event {
             BitswapEvent::Progress(id, missing) => {
+                self.charge_rejected();
                 debug!(
                     "progress in bitswap sync query, id: {}, missing: {}",
                     id, missing
                 );
+                // the provider is sending, it keeps the query
+                if let Some(providers) = self.query_providers.get_mut(&id) {
+                    providers.asked_at = Instant::now();
+                }
+                if let Some(info) = self.queries.get(&id) {
+                    self.events.push_back(BehaviourEvent::BitswapProgress {
+                        cid: info.cid,
+                        missing,
+                    });
+                }
             }
             BitswapEvent::Complete(id, result) => {
                 debug!(
                     "[BitswapEvent::Complete] - Bitswap Event complete for query id: {:?}",
                     id
                 );
+                let providers = self.query_providers.remove(&id);
+                let corrupt = self.charge_rejected();
                 match self.queries.remove(&id) {
                     Some(mut info) => {
                         match result {
                             Err(err) => error!("{:?}", err),
                             Ok(_res) => info.block_found = true,
                         }
+                        self.senders.finish(&info.cid.to_ipld_cid());
+                        if let Some(mut providers) = providers {
+                            if let Some(peer) = providers.peers.pop_front() {
+                                self.reputation.record_bitswap(peer, info.block_found);
+                                if info.block_found && !corrupt.contains(&peer) {
+                                    self.peer_tags.tag(peer, PeerTag::Provider);
+                                }
+                            }
+                            if !info.block_found && !providers.peers.is_empty() {
+                                // completes once a provider has it or all failed
+                                self.query(info.cid, providers);
+                                return;
+                            }
+                        }
+                        if info.block_found {
+                            self.cached_roots.insert(info.cid);
+                        }
                         self.events.push_back(BehaviourEvent::Bitswap(info));
                     }
                     _ => {
@@ -539,13 +1661,109 @@ impl<P: StoreParams> Behaviour<P> {
         }
     }
 
+    /// Charge the senders of the blocks the store rejected, returning them.
+    fn charge_rejected(&mut self) -> Vec<PeerId> {
+        let mut senders = vec![];
+        for (cid, sender) in self.senders.take() {
+            match sender {
+                Some(peer) => {
+                    warn!("[BitswapEvent] - {} sent the invalid block {}", peer, cid);
+                    self.reputation.record_invalid_block(peer);
+                    self.peer_tags.untag(&peer, PeerTag::Provider);
+                    senders.push(peer);
+                }
+                None => warn!(
+                    "[BitswapEvent] - received the invalid block {} from an unknown peer",
+                    cid
+                ),
+            }
+        }
+        senders
+    }
+
     fn handle_gossipsub(&mut self, event: GossipsubEvent) {
         match event {
             GossipsubEvent::Message {
                 propagation_source,
+                message_id,
                 message,
-                ..
             } => {
+                self.touch(propagation_source);
+                let violation = self.gossip.violation(&message);
+                if let Some(violation) = violation {
+                    debug!(
+                        "[GossipsubEvent::Message] - rejecting a message from {}: {}",
+                        propagation_source, violation
+                    );
+                    // the rejection lowers the gossipsub score of the peer too
+                    self.reputation.record_invalid_gossip(propagation_source);
+                }
+                // messages are only forwarded once validated
+                let is_name = message.topic == Topic::new(URSA_NAMES).hash();
+                let purge = self
+                    .purge
+                    .as_ref()
+                    .filter(|config| message.topic == Topic::new(config.topic()).hash());
+                let is_purge = purge.is_some();
+                let is_content = message.topic == Topic::new(URSA_CONTENT).hash();
+                let accepted = violation.is_none()
+                    && if is_name {
+                        match serde_json::from_slice::<NameRecord>(&message.data) {
+                            Ok(record) if record.verify().is_ok() => {
+                                self.names.insert(record);
+                                true
+                            }
+                            _ => false,
+                        }
+                    } else if let Some(config) = purge {
+                        match serde_json::from_slice::<PurgeMessage>(&message.data) {
+                            Ok(purge) if purge.verify(&config.admin_keys).is_ok() => {
+                                self.events.push_back(BehaviourEvent::Purge(purge));
+                                true
+                            }
+                            _ => false,
+                        }
+                    } else if is_content {
+                        let announcement =
+                            serde_json::from_slice::<ContentAnnouncement>(&message.data)
+                                .map_err(anyhow::Error::from)
+                                .and_then(|announcement| announcement.parse());
+                        match announcement {
+                            // a signed announcement only vouches for its author
+                            Ok((cid, peer, addrs))
+                                if message.source.map_or(true, |source| source == peer) =>
+                            {
+                                self.content_announced(cid, peer, addrs);
+                                true
+                            }
+                            _ => false,
+                        }
+                    } else {
+                        !message.data.is_empty()
+                    };
+                let acceptance = if accepted {
+                    MessageAcceptance::Accept
+                } else {
+                    MessageAcceptance::Reject
+                };
+                if let Err(e) = self.gossipsub.report_message_validation_result(
+                    &message_id,
+                    &propagation_source,
+                    acceptance,
+                ) {
+                    warn!(
+                        "[GossipsubEvent::Message] - failed to report validation: {:?}",
+                        e
+                    );
+                }
+                self.gossip_stats
+                    .entry(message.topic.clone())
+                    .or_default()
+                    .record_received(accepted);
+
+                if !accepted || is_name || is_purge || is_content {
+                    return;
+                }
                 self.events.push_back(BehaviourEvent::GossipMessage {
                     peer: propagation_source,
                     topic: message.topic.clone(),
@@ -553,17 +1771,39 @@ impl<P: StoreParams> Behaviour<P> {
                 });
             }
             GossipsubEvent::Subscribed { peer_id, topic } => {
-                // A remote subscribed to a topic.
-                // subscribe to new topic.
+                if self.subscriptions.subscribe(peer_id, topic.clone()) {
+                    debug!(
+                        "[GossipsubEvent::Subscribed] - {} subscribed to {}",
+                        peer_id, topic
+                    );
+                }
             }
             GossipsubEvent::Unsubscribed { peer_id, topic } => {
-                // A remote unsubscribed from a topic.
-                // remove subscription.
+                if self.subscriptions.unsubscribe(&peer_id, &topic) {
+                    debug!(
+                        "[GossipsubEvent::Unsubscribed] - {} unsubscribed from {}",
+                        peer_id, topic
+                    );
+                }
             }
             GossipsubEvent::GossipsubNotSupported { peer_id } => {
-                // A peer that does not support gossipsub has connected.
-                // the scoring/rating should happen here.
-                // disconnect.
+                debug!(
+                    "[GossipsubEvent::GossipsubNotSupported] - {} does not support gossipsub",
+                    peer_id
+                );
+                self.peer_tags.tag(peer_id, PeerTag::NoGossip);
+                // explicit peers are redialed to keep them in the mesh
+                self.gossipsub.remove_explicit_peer(&peer_id);
+                if self.connections.disconnect_no_gossip
+                    && self.connections.is_full(self.discovery.peers().len())
+                    && !self.peer_tags.is_protected(&peer_id, &self.connections)
+                {
+                    debug!(
+                        "Disconnecting {} without gossipsub at the connection limit",
+                        peer_id
+                    );
+                    self.to_prune.push_back(peer_id);
+                }
             }
         }
     }
@@ -571,22 +1811,77 @@ impl<P: StoreParams> Behaviour<P> {
     fn handle_discovery(&mut self, event: DiscoveryEvent) {
         match event {
             DiscoveryEvent::Connected(peer_id) => {
+                // peers queued earlier are still being disconnected
+                if self.to_prune.is_empty() {
+                    let prune =
+                        self.peer_tags
+                            .prune(self.discovery.peers(), &self.connections, |peer| {
+                                self.reputation.score(peer)
+                            });
+                    if !prune.is_empty() {
+                        debug!("Pruning {} peers over the connection limit", prune.len());
+                        self.to_prune.extend(prune);
+                    }
+                }
+                self.last_active.insert(peer_id, Instant::now());
                 self.events
                     .push_back(BehaviourEvent::PeerConnected(peer_id));
             }
             DiscoveryEvent::Disconnected(peer_id) => {
+                if let Some(score) = self.gossipsub.peer_score(&peer_id) {
+                    self.reputation.record_gossip_score(peer_id, score);
+                }
+                self.peer_summaries.remove(&peer_id);
+                self.summary_requests.remove(&peer_id);
+                self.peer_identities.remove(&peer_id);
+                self.subscriptions.remove_peer(&peer_id);
+                self.peer_rtt.remove(&peer_id);
+                self.last_active.remove(&peer_id);
+                self.peer_tags.untag(&peer_id, PeerTag::Client);
+                self.peer_tags.untag(&peer_id, PeerTag::NoGossip);
                 self.events
                     .push_back(BehaviourEvent::PeerDisconnected(peer_id));
             }
+            DiscoveryEvent::Providers { id, providers } => {
+                if let Some(sender) = self.provider_queries.remove(&id) {
+                    for (peer, addrs) in providers {
+                        let provider = ContentProvider {
+                            peer_id: peer.to_string(),
+                            addrs: addrs.iter().map(Multiaddr::to_string).collect(),
+                        };
+                        if sender.unbounded_send(provider).is_err() {
+                            warn!(
+                                "[DiscoveryEvent::Providers] - failed to send providers for {:?}",
+                                id
+                            );
+                            break;
+                        }
+                    }
+                }
+            }
+            DiscoveryEvent::Record { id, values } => {
+                if let Some((name, sender)) = self.name_queries.remove(&id) {
+                    let record = names::newest(&name, &values);
+                    if let Some(record) = &record {
+                        self.names.insert(record.clone());
+                    }
+                    // a record gossiped while the lookup ran may be newer
+                    let record = self.names.get(&name).cloned().or(record);
+                    if sender.send(record).is_err() {
+                        warn!(
+                            "[DiscoveryEvent::Record] - failed to send the record of {}",
+                            name
+                        );
+                    }
+                }
+            }
         }
     }
 
-    fn handle_request_response(
-        &mut self,
-        event: RequestResponseEvent<UrsaExchangeRequest, UrsaExchangeResponse>,
-    ) {
+    fn handle_request_response(&mut self, event: UrsaRequestResponseEvent) {
         match event {
             RequestResponseEvent::Message { peer, message } => {
+                self.touch(peer);
                 match message {
                     RequestResponseMessage::Request {
                         request_id,
@@ -597,13 +1892,66 @@ impl<P: StoreParams> Behaviour<P> {
                             "[RequestResponseMessage::Request] - {} {}: {:?}",
                             request_id, peer, request
                         );
+                        match self.quotas.check(peer) {
+                            QuotaCheck::Allowed => {}
+                            QuotaCheck::Throttled { requests, first } => {
+                                // dropping the channel fails the request of the peer
+                                if first {
+                                    debug!(
+                                        "[RequestResponseMessage::Request] - {} is over its quota",
+                                        peer
+                                    );
+                                    self.events.push_back(BehaviourEvent::PeerThrottled {
+                                        peer,
+                                        requests,
+                                        disconnected: false,
+                                    });
+                                }
+                                return;
+                            }
+                            QuotaCheck::Disconnect { requests } => {
+                                warn!("[RequestResponseMessage::Request] - disconnecting {} after {} requests", peer, requests);
+                                self.to_prune.push_back(peer);
+                                self.events.push_back(BehaviourEvent::PeerThrottled {
+                                    peer,
+                                    requests,
+                                    disconnected: true,
+                                });
+                                return;
+                            }
+                        }
+                        self.peer_tags.tag(peer, PeerTag::Client);
                         // self.pending_requests.insert(request_id, channel);
 
-                        self.events.push_back(BehaviourEvent::RequestMessage {
-                            peer,
-                            request,
-                            channel,
-                        });
+                        let event = match request {
+                            UrsaExchangeRequest(RequestType::CacheSummary) => {
+                                let summary = self.cached_roots.summary();
+                                let response = UrsaExchangeResponse(
+                                    ResponseType::CacheSummaryResponse(summary),
+                                );
+                                if let Err(e) = self.send_response(channel, response) {
+                                    warn!("[RequestResponseMessage::Request] - {:?}", e);
+                                }
+                                return;
+                            }
+                            UrsaExchangeRequest(RequestType::GetCar { root, selector }) => {
+                                BehaviourEvent::CarRequest {
+                                    peer,
+                                    root,
+                                    selector,
+                                    channel,
+                                }
+                            }
+                            UrsaExchangeRequest(RequestType::FindContent { cid }) => {
+                                BehaviourEvent::FindContent { peer, cid, channel }
+                            }
+                            request => BehaviourEvent::RequestMessage {
+                                peer,
+                                request,
+                                channel,
+                            },
+                        };
+                        self.events.push_back(event);
                     }
                     RequestResponseMessage::Response {
                         request_id,
@@ -614,6 +1962,24 @@ impl<P: StoreParams> Behaviour<P> {
                             request_id, peer, response
                         );
 
+                        if let Some((_, started)) = self.request_started.remove(&request_id) {
+                            let bytes = match &response.0 {
+                                ResponseType::CarResponse(car) => car.data.len(),
+                                ResponseType::GetCarResponse(car) => car.len() as usize,
+                                _ => 0,
+                            };
+                            self.reputation
+                                .record_transfer(peer, bytes as u64, started.elapsed());
+                        }
+
+                        if let UrsaExchangeResponse(ResponseType::CacheSummaryResponse(summary)) =
+                            response
+                        {
+                            self.summary_requests.remove(&peer);
+                            self.peer_summaries.insert(peer, (Instant::now(), summary));
+                            return;
+                        }
+
                         if let Some(request) = self.pending_responses.remove(&request_id) {
                             if request.send(Ok(response)).is_err() {
                                 warn!("[RequestResponseMessage::Response] - failed to send request: {:?}", request_id);
@@ -636,6 +2002,7 @@ impl<P: StoreParams> Behaviour<P> {
                     error.to_string()
                 );
 
+                self.request_started.remove(&request_id);
                 if let Some(request) = self.pending_responses.remove(&request_id) {
                     if request.send(Err(error.into())).is_err() {
                         warn!("[RequestResponseMessage::OutboundFailure] - failed to send request: {:?}", request_id);
@@ -667,68 +2034,41 @@ impl<P: StoreParams> Behaviour<P> {
     }
 }
 
-impl<P: StoreParams> NetworkBehaviourEventProcess<PingEvent> for Behaviour<P> {
-    fn inject_event(&mut self, event: PingEvent) {
-        self.handle_ping(event)
-    }
-}
-
-impl<P: StoreParams> NetworkBehaviourEventProcess<IdentifyEvent> for Behaviour<P> {
-    fn inject_event(&mut self, event: IdentifyEvent) {
-        self.handle_identify(event)
-    }
-}
-
-impl<P: StoreParams> NetworkBehaviourEventProcess<GossipsubEvent> for Behaviour<P> {
-    fn inject_event(&mut self, event: GossipsubEvent) {
-        self.handle_gossipsub(event)
-    }
-}
-
-impl<P: StoreParams> NetworkBehaviourEventProcess<BitswapEvent> for Behaviour<P> {
-    fn inject_event(&mut self, event: BitswapEvent) {
-        self.handle_bitswap(event)
-    }
-}
-
-impl<P: StoreParams> NetworkBehaviourEventProcess<DiscoveryEvent> for Behaviour<P> {
-    fn inject_event(&mut self, event: DiscoveryEvent) {
-        self.handle_discovery(event)
-    }
-}
-
-impl<P: StoreParams> NetworkBehaviourEventProcess<AutonatEvent> for Behaviour<P> {
-    fn inject_event(&mut self, event: AutonatEvent) {
-        self.handle_autonat(event)
-    }
-}
-
-impl<P: StoreParams> NetworkBehaviourEventProcess<RelayServerEvent> for Behaviour<P> {
-    fn inject_event(&mut self, event: RelayServerEvent) {
-        self.handle_relay_server(event)
-    }
-}
-
-impl<P: StoreParams> NetworkBehaviourEventProcess<RelayClientEvent> for Behaviour<P> {
-    fn inject_event(&mut self, event: RelayClientEvent) {
-        self.handle_relay_client(event)
-    }
-}
-
-impl<P: StoreParams> NetworkBehaviourEventProcess<DcutrEvent> for Behaviour<P> {
-    fn inject_event(&mut self, event: DcutrEvent) {
-        self.handle_dcutr(event)
-    }
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::{rocks::RocksDb, rocks_config::RocksDbConfig};
+    use libipld::DefaultParams;
+    use ursa_store::{BitswapStorage, Store};
+
+    #[test]
+    fn test_handle_event() {
+        let db = RocksDb::open("behaviour_db", &RocksDbConfig::default()).unwrap();
+        let store = Arc::new(Store::new(Arc::new(db)));
+        let config = NetworkConfig {
+            reputation_path: None,
+            ..Default::default()
+        };
+        let mut behaviour: Behaviour<DefaultParams> = Behaviour::new(
+            &Keypair::generate_ed25519(),
+            &config,
+            BitswapStorage(store.clone()),
+            Default::default(),
+            None,
+            Default::default(),
+        );
 
-impl<P: StoreParams>
-    NetworkBehaviourEventProcess<RequestResponseEvent<UrsaExchangeRequest, UrsaExchangeResponse>>
-    for Behaviour<P>
-{
-    fn inject_event(
-        &mut self,
-        event: RequestResponseEvent<UrsaExchangeRequest, UrsaExchangeResponse>,
-    ) {
-        self.handle_request_response(event)
+        let peer = PeerId::random();
+        behaviour.handle_event(DiscoveryEvent::Connected(peer).into());
+        behaviour.handle_event(DiscoveryEvent::Disconnected(peer).into());
+        assert!(matches!(
+            behaviour.events.pop_front(),
+            Some(BehaviourEvent::PeerConnected(connected)) if connected == peer
+        ));
+        assert!(matches!(
+            behaviour.events.pop_front(),
+            Some(BehaviourEvent::PeerDisconnected(disconnected)) if disconnected == peer
+        ));
+        assert!(behaviour.events.is_empty());
     }
 }
@@ -0,0 +1,194 @@
+//! Ursa cache summaries.
+//!
+//! A [`CacheSummary`] is a bloom filter over the root cids a node has cached. Peers
+//! exchange them over the exchange protocol so bitswap and car requests can be sent
+//! to the peers that are likely to have the content instead of to every peer.
+//!
+//! The summarized [`CachedRoots`] are kept in the node database under a single key,
+//! written by the service on a timer rather than on every change, so a restarted node
+//! still tells its peers what it has.
+
+use anyhow::Result;
+use cid::Cid;
+use fnv::FnvHasher;
+use ipld_blockstore::BlockStore;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, hash::Hasher, str::FromStr};
+use ursa_store::{columns::Column, Store};
+
+/// Database key of the cached roots.
+const CACHED_ROOTS_KEY: &str = "cached_roots";
+
+/// Targeted false positive rate of a summary.
+const FALSE_POSITIVE_RATE: f64 = 0.01;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheSummary {
+    bits: Vec<u8>,
+    hashes: u32,
+}
+
+impl CacheSummary {
+    /// An empty summary sized for `capacity` cids.
+    pub fn with_capacity(capacity: usize) -> Self {
+        let capacity = capacity.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-capacity * FALSE_POSITIVE_RATE.ln() / (ln2 * ln2)).ceil() as usize;
+        let hashes = ((bits as f64 / capacity) * ln2).round().max(1.0) as u32;
+
+        Self {
+            bits: vec![0; (bits + 7) / 8],
+            hashes,
+        }
+    }
+
+    pub fn from_cids<'a, I>(cids: I) -> Self
+    where
+        I: IntoIterator<Item = &'a Cid>,
+        I::IntoIter: ExactSizeIterator,
+    {
+        let cids = cids.into_iter();
+        let mut summary = Self::with_capacity(cids.len());
+        for cid in cids {
+            summary.insert(cid);
+        }
+        summary
+    }
+
+    pub fn insert(&mut self, cid: &Cid) {
+        for bit in self.bit_indices(cid) {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    /// Whether `cid` may be cached. False positives are possible, false negatives are not.
+    pub fn contains(&self, cid: &Cid) -> bool {
+        !self.bits.is_empty()
+            && self
+                .bit_indices(cid)
+                .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// Bit positions of `cid`, derived with double hashing from two fnv hashes so they
+    /// are stable across nodes and builds.
+    fn bit_indices(&self, cid: &Cid) -> impl Iterator<Item = usize> {
+        let bytes = cid.to_bytes();
+        let hash = |seed: u8| {
+            let mut hasher = FnvHasher::default();
+            hasher.write_u8(seed);
+            hasher.write(&bytes);
+            hasher.finish()
+        };
+        let (h1, h2) = (hash(0), hash(1));
+        let len = (self.bits.len() * 8) as u64;
+
+        (0..self.hashes as u64).map(move |i| {
+            if len == 0 {
+                0
+            } else {
+                (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize
+            }
+        })
+    }
+}
+
+/// Root cids cached by the node.
+#[derive(Debug, Default)]
+pub struct CachedRoots {
+    roots: HashSet<Cid>,
+    /// Whether the roots changed since they were last written.
+    dirty: bool,
+}
+
+impl CachedRoots {
+    /// Load the roots stored in `store`.
+    pub fn load<S: BlockStore + Sync + Send + 'static>(store: &Store<S>) -> Result<Self> {
+        let roots: Vec<String> = match store.column(Column::Metadata).read(CACHED_ROOTS_KEY)? {
+            Some(roots) => serde_json::from_slice(&roots)?,
+            None => vec![],
+        };
+        Ok(Self {
+            roots: roots
+                .iter()
+                .filter_map(|root| Cid::from_str(root).ok())
+                .collect(),
+            dirty: false,
+        })
+    }
+
+    pub fn insert(&mut self, cid: Cid) {
+        self.dirty |= self.roots.insert(cid);
+    }
+
+    pub fn remove(&mut self, cid: &Cid) {
+        self.dirty |= self.roots.remove(cid);
+    }
+
+    pub fn summary(&self) -> CacheSummary {
+        CacheSummary::from_cids(&self.roots)
+    }
+
+    /// Encoding of the roots to write, if they changed since the last snapshot.
+    pub fn snapshot(&mut self) -> Option<Vec<u8>> {
+        if !self.dirty {
+            return None;
+        }
+        self.dirty = false;
+        let roots: Vec<String> = self.roots.iter().map(Cid::to_string).collect();
+        serde_json::to_vec(&roots).ok()
+    }
+
+    /// Write a [`snapshot`](Self::snapshot) to `store`.
+    pub fn save<S: BlockStore + Sync + Send + 'static>(
+        store: &Store<S>,
+        snapshot: &[u8],
+    ) -> Result<()> {
+        store
+            .column(Column::Metadata)
+            .write(CACHED_ROOTS_KEY, snapshot)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::MemoryDB;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_contains() {
+        let cached =
+            Cid::from_str("bafkreif2opfibjypwkjzzry3jbibcjqcjwnpoqpeiqw75eu3s3u3zbdszq").unwrap();
+        let missing =
+            Cid::from_str("bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku").unwrap();
+
+        let summary = CacheSummary::from_cids(&[cached]);
+        assert!(summary.contains(&cached));
+        assert!(!summary.contains(&missing));
+
+        // summaries survive the wire format
+        let decoded: CacheSummary =
+            serde_json::from_slice(&serde_json::to_vec(&summary).unwrap()).unwrap();
+        assert!(decoded.contains(&cached));
+    }
+
+    #[test]
+    fn test_cached_roots_persist() {
+        let store = Store::new(Arc::new(MemoryDB::default()));
+        let root =
+            Cid::from_str("bafkreif2opfibjypwkjzzry3jbibcjqcjwnpoqpeiqw75eu3s3u3zbdszq").unwrap();
+
+        let mut roots = CachedRoots::load(&store).unwrap();
+        roots.insert(root);
+        roots.insert(root);
+        let snapshot = roots.snapshot().unwrap();
+        assert!(roots.snapshot().is_none());
+        CachedRoots::save(&store, &snapshot).unwrap();
+
+        let mut roots = CachedRoots::load(&store).unwrap();
+        assert!(roots.summary().contains(&root));
+        roots.remove(&root);
+        assert!(roots.snapshot().is_some());
+    }
+}
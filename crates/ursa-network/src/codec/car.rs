@@ -0,0 +1,374 @@
+//! Car files pulled with [`RequestType::GetCar`](super::protocol::RequestType::GetCar).
+//!
+//! The car file following a response is copied to a temporary file as it arrives, so
+//! a peer streaming up to [`MAX_CAR_SIZE`](super::protocol::MAX_CAR_SIZE) of data
+//! does not hold it in memory. [`import_car`] then reads the file back frame by frame,
+//! writing the blocks that hash to their cid and refusing a car file that is not of
+//! the requested root, or does not carry its block. Only blocks linked from the root,
+//! or from a block before them, are written, so a peer cannot slip other blocks into
+//! the store; the blocks are expected in the traversal order a [`CarSource`] sends.
+//!
+//! On the serving side a [`CarSource`] lists the blocks of the dag and reads each one
+//! from the store as the substream takes it, so answering a peer holds one block in
+//! memory rather than the whole car file.
+
+use anyhow::{anyhow, Result};
+use async_std::{fs::File, io::BufReader};
+use cid::Cid;
+use fnv::FnvHashSet;
+use futures::{
+    future::{self, BoxFuture},
+    io, stream, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, FutureExt, StreamExt,
+};
+use fvm_ipld_car::{CarHeader, CarReader};
+use ipld_blockstore::BlockStore;
+use libipld::{store::DefaultParams, Block};
+use std::{fmt, mem, path::PathBuf, sync::Arc};
+use ursa_store::{validate_block, Store};
+use ursa_utils::ToIpldCid;
+
+/// Blocks written to the store at once.
+const BATCH_SIZE: usize = 256;
+
+/// A car file spooled to a temporary file, removed once dropped.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SpooledCar {
+    path: PathBuf,
+    len: u64,
+}
+
+impl SpooledCar {
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub async fn open(&self) -> io::Result<File> {
+        File::open(&self.path).await
+    }
+}
+
+impl Drop for SpooledCar {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Copy the rest of `io` to a temporary file, failing when it goes over `max_size`.
+pub async fn spool<R>(io: &mut R, max_size: u64) -> io::Result<SpooledCar>
+where
+    R: AsyncRead + Unpin,
+{
+    let path = std::env::temp_dir().join(format!("ursa-car-{:016x}", rand::random::<u64>()));
+    let mut file = File::create(&path).await?;
+    // removes the file on the errors below
+    let mut car = SpooledCar { path, len: 0 };
+
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = io.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        car.len += read as u64;
+        if car.len > max_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("The car file is over {max_size} bytes"),
+            ));
+        }
+        file.write_all(&buffer[..read]).await?;
+    }
+    file.flush().await?;
+    Ok(car)
+}
+
+/// Reads the data of a block to send.
+pub type BlockLoader = Arc<dyn Fn(Cid) -> BoxFuture<'static, Result<Vec<u8>>> + Send + Sync>;
+
+/// The blocks of a car file to send, each read with the loader once the previous one
+/// was written.
+#[derive(Clone)]
+pub struct CarSource {
+    root: Cid,
+    /// Cids of the blocks in traversal order, with their sizes.
+    blocks: Arc<Vec<(Cid, usize)>>,
+    load: BlockLoader,
+}
+
+impl CarSource {
+    pub fn new(root: Cid, blocks: Vec<(Cid, usize)>, load: BlockLoader) -> Self {
+        Self {
+            root,
+            blocks: Arc::new(blocks),
+            load,
+        }
+    }
+
+    /// A source of the blocks of `root` stored in `store`.
+    pub fn from_store<S>(store: &Arc<Store<S>>, root: Cid, blocks: Vec<(Cid, usize)>) -> Self
+    where
+        S: BlockStore + Sync + Send + 'static,
+    {
+        let store = Arc::clone(store);
+        let load: BlockLoader = Arc::new(move |cid: Cid| {
+            let store = Arc::clone(&store);
+            async move {
+                let data: Option<Vec<u8>> = store
+                    .blocking(move |store| store.blockstore().get(&cid))
+                    .await??;
+                data.ok_or_else(|| anyhow!("The block {} is no longer stored", cid))
+            }
+            .boxed()
+        });
+        Self::new(root, blocks, load)
+    }
+
+    /// Bytes of the data of the blocks.
+    pub fn len(&self) -> u64 {
+        self.blocks.iter().map(|(_, size)| *size as u64).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Write the car file to `io`, failing when a block can no longer be read.
+    pub async fn write_to<W>(&self, io: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Send + Unpin,
+    {
+        let header = CarHeader {
+            roots: vec![self.root],
+            version: 1,
+        };
+        let mut error = None;
+        let mut blocks = Box::pin(
+            stream::iter(self.blocks.iter())
+                .then(|(cid, _)| (self.load)(*cid).map(move |data| (*cid, data)))
+                .scan(&mut error, |error, (cid, data)| {
+                    future::ready(match data {
+                        Ok(data) => Some((cid, data)),
+                        Err(err) => {
+                            **error = Some(err);
+                            None
+                        }
+                    })
+                }),
+        );
+        header
+            .write_stream_async(io, &mut blocks)
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        drop(blocks);
+        match error {
+            Some(err) => Err(io::Error::new(io::ErrorKind::Other, err.to_string())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Debug for CarSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CarSource")
+            .field("root", &self.root)
+            .field("blocks", &self.blocks.len())
+            .finish()
+    }
+}
+
+impl PartialEq for CarSource {
+    fn eq(&self, other: &Self) -> bool {
+        self.root == other.root && self.blocks == other.blocks
+    }
+}
+
+impl Eq for CarSource {}
+
+/// Store the blocks of the dag of `root` in the car file read from `reader`, returning
+/// their cids. Blocks not linked from the blocks before them are skipped.
+pub async fn import_car<S, R>(store: &Arc<Store<S>>, reader: R, root: Cid) -> Result<Vec<Cid>>
+where
+    S: BlockStore + Sync + Send + 'static,
+    R: AsyncRead + Send + Unpin,
+{
+    let mut car = CarReader::new(reader).await?;
+    if !car.header.roots.contains(&root) {
+        return Err(anyhow!("The car file is not of the root {}", root));
+    }
+
+    let (mut cids, mut has_root) = (vec![], false);
+    let mut linked = FnvHashSet::default();
+    linked.insert(root.to_ipld_cid());
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    while let Some(fvm_ipld_car::Block { cid, data }) = car.next_block().await? {
+        let block = Block::<DefaultParams>::new_unchecked(cid.to_ipld_cid(), data);
+        validate_block(&block)?;
+        if !linked.contains(block.cid()) {
+            continue;
+        }
+        block.references(&mut linked)?;
+        has_root |= cid == root;
+        cids.push(cid);
+        batch.push(block);
+        if batch.len() == BATCH_SIZE {
+            write_batch(store, mem::take(&mut batch)).await?;
+        }
+    }
+    write_batch(store, batch).await?;
+
+    if !has_root {
+        return Err(anyhow!("The car file does not contain its root {}", root));
+    }
+    Ok(cids)
+}
+
+/// Import the car file of `root` spooled by the codec.
+pub async fn import_spooled<S>(
+    store: &Arc<Store<S>>,
+    car: &SpooledCar,
+    root: Cid,
+) -> Result<Vec<Cid>>
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    import_car(store, BufReader::new(car.open().await?), root).await
+}
+
+async fn write_batch<S>(store: &Arc<Store<S>>, batch: Vec<Block<DefaultParams>>) -> Result<()>
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    if batch.is_empty() {
+        return Ok(());
+    }
+    store
+        .blocking(move |store| -> Result<()> {
+            for block in batch {
+                store
+                    .blockstore()
+                    .write(&block.cid().to_bytes(), block.data())?;
+            }
+            Ok(())
+        })
+        .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::MemoryDB;
+    use fvm_ipld_car::CarHeader;
+    use libipld::{cbor::DagCborCodec, ipld, multihash::Code, Ipld};
+    use ursa_utils::ToCid;
+
+    async fn car(roots: Vec<Cid>, blocks: Vec<(Cid, Vec<u8>)>) -> Vec<u8> {
+        let (tx, mut rx) = async_std::channel::unbounded();
+        for block in blocks {
+            tx.send(block).await.unwrap();
+        }
+        drop(tx);
+        let mut buffer = vec![];
+        CarHeader { roots, version: 1 }
+            .write_stream_async(&mut buffer, &mut rx)
+            .await
+            .unwrap();
+        buffer
+    }
+
+    fn block(ipld: &Ipld) -> (Cid, Vec<u8>) {
+        let block = Block::<DefaultParams>::encode(DagCborCodec, Code::Blake3_256, ipld).unwrap();
+        (block.cid().to_cid(), block.data().to_vec())
+    }
+
+    #[async_std::test]
+    async fn test_import_car() {
+        let store = Arc::new(Store::new(Arc::new(MemoryDB::default())));
+        let leaf = block(&ipld!("leaf"));
+        let root = block(&Ipld::List(vec![Ipld::Link(leaf.0.to_ipld_cid())]));
+
+        let data = car(vec![root.0], vec![root.clone(), leaf.clone()]).await;
+        let spooled = spool(&mut data.as_slice(), data.len() as u64)
+            .await
+            .unwrap();
+        assert_eq!(spooled.len(), data.len() as u64);
+        let cids = import_spooled(&store, &spooled, root.0).await.unwrap();
+        assert_eq!(cids, vec![root.0, leaf.0]);
+        assert!(store.blockstore().has(&leaf.0).unwrap());
+
+        // the temporary file goes with the spooled car
+        let path = spooled.path.clone();
+        drop(spooled);
+        assert!(!path.exists());
+        assert!(spool(&mut data.as_slice(), data.len() as u64 - 1)
+            .await
+            .is_err());
+    }
+
+    #[async_std::test]
+    async fn test_import_car_checks_the_root() {
+        let store = Arc::new(Store::new(Arc::new(MemoryDB::default())));
+        let (asked, other) = (block(&ipld!("asked")), block(&ipld!("other")));
+
+        // of another root
+        let data = car(vec![other.0], vec![other.clone()]).await;
+        assert!(import_car(&store, data.as_slice(), asked.0).await.is_err());
+
+        // naming the root without its block
+        let data = car(vec![asked.0], vec![other.clone()]).await;
+        assert!(import_car(&store, data.as_slice(), asked.0).await.is_err());
+
+        // with a block not hashing to its cid
+        let data = car(vec![asked.0], vec![(asked.0, b"corrupt".to_vec())]).await;
+        assert!(import_car(&store, data.as_slice(), asked.0).await.is_err());
+        assert!(!store.blockstore().has(&asked.0).unwrap());
+    }
+
+    #[async_std::test]
+    async fn test_import_car_skips_unlinked_blocks() {
+        let store = Arc::new(Store::new(Arc::new(MemoryDB::default())));
+        let leaf = block(&ipld!("leaf"));
+        let root = block(&Ipld::List(vec![Ipld::Link(leaf.0.to_ipld_cid())]));
+        let (other, orphan) = (block(&ipld!("other")), block(&ipld!("orphan")));
+
+        // the valid blocks outside of the dag of the root are not written
+        let data = car(
+            vec![root.0],
+            vec![other.clone(), root.clone(), orphan.clone(), leaf.clone()],
+        )
+        .await;
+        let cids = import_car(&store, data.as_slice(), root.0).await.unwrap();
+        assert_eq!(cids, vec![root.0, leaf.0]);
+        assert!(store.blockstore().has(&leaf.0).unwrap());
+        assert!(!store.blockstore().has(&other.0).unwrap());
+        assert!(!store.blockstore().has(&orphan.0).unwrap());
+    }
+
+    #[async_std::test]
+    async fn test_car_source() {
+        let store = Arc::new(Store::new(Arc::new(MemoryDB::default())));
+        let leaf = block(&ipld!("leaf"));
+        let root = block(&Ipld::List(vec![Ipld::Link(leaf.0.to_ipld_cid())]));
+        for (cid, data) in [&root, &leaf] {
+            store.blockstore().write(cid.to_bytes(), data).unwrap();
+        }
+        let blocks = vec![(root.0, root.1.len()), (leaf.0, leaf.1.len())];
+        let source = CarSource::from_store(&store, root.0, blocks.clone());
+        assert_eq!(source.len(), (root.1.len() + leaf.1.len()) as u64);
+
+        let mut data = vec![];
+        source.write_to(&mut data).await.unwrap();
+        assert_eq!(
+            data,
+            car(vec![root.0], vec![root.clone(), leaf.clone()]).await
+        );
+
+        // a block removed in the meantime fails the write
+        store.blockstore().delete(leaf.0.to_bytes()).unwrap();
+        let source = CarSource::from_store(&store, root.0, blocks);
+        assert!(source.write_to(&mut vec![]).await.is_err());
+    }
+}
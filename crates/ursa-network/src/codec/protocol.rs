@@ -8,24 +8,56 @@ use libp2p::{
     request_response::RequestResponseCodec,
 };
 use serde::{Deserialize, Serialize};
-use std::io;
+use std::{io, sync::Arc};
+
+use super::car::{spool, CarSource, SpooledCar};
+use crate::{
+    cache_summary::CacheSummary,
+    compat::{Feature, EXCHANGE_V0, EXCHANGE_V1},
+};
 
 /// Max request size in bytes
 const MAX_REQUEST_SIZE: usize = 4 * 1024 * 1024; // 1 << 22
 /// Max response size in bytes
 const MAX_RESPONSE_SIZE: usize = 10 * 1024 * 1024;
+/// Max size in bytes of a car file streamed after a [`ResponseType::GetCarResponse`]
+pub const MAX_CAR_SIZE: u64 = 1024 * 1024 * 1024;
 
-pub const PROTOCOL_NAME: &[u8] = b"/ursa/txrx/0.0.1";
+pub const PROTOCOL_NAME: &[u8] = EXCHANGE_V0.as_bytes();
 
-#[derive(Debug, Clone)]
-pub struct UrsaProtocol;
+/// Versions of the exchange protocol, a stream is negotiated on the newest both
+/// peers answer on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UrsaProtocol {
+    /// Car requests only, for peers on the first release.
+    V0,
+    /// Every [`RequestType`].
+    V1,
+}
+
+impl UrsaProtocol {
+    /// Whether requests for `feature` can be sent over this version.
+    pub fn supports(&self, feature: Feature) -> bool {
+        match self {
+            UrsaProtocol::V0 => feature == Feature::Exchange,
+            UrsaProtocol::V1 => true,
+        }
+    }
+}
 
 impl ProtocolName for UrsaProtocol {
     fn protocol_name(&self) -> &[u8] {
-        PROTOCOL_NAME
+        match self {
+            UrsaProtocol::V0 => PROTOCOL_NAME,
+            UrsaProtocol::V1 => EXCHANGE_V1.as_bytes(),
+        }
     }
 }
 
+fn invalid_data<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
 #[derive(Debug, Clone)]
 pub struct UrsaExchangeCodec;
 
@@ -34,11 +66,46 @@ pub struct UrsaExchangeCodec;
 pub enum RequestType {
     // change this to the final cid version
     CarRequest(String),
+    /// Ask the peer to replicate the dag under a root cid from the sender.
+    Replicate(String),
+    /// Ask the peer for the blocks under a root cid as a single car file.
+    GetCar {
+        root: String,
+        selector: DagSelector,
+    },
+    /// Ask the peer for a summary of the root cids it has cached.
+    CacheSummary,
+    /// Ask the peer for the providers it knows of a cid.
+    FindContent {
+        cid: String,
+    },
+}
+
+/// The blocks of a dag included in a car transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DagSelector {
+    /// Every block reachable from the root.
+    All,
+    /// Only the root block.
+    Root,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct UrsaExchangeRequest(pub RequestType);
 
+impl UrsaExchangeRequest {
+    /// The protocol feature a peer must support to decode this request.
+    pub fn feature(&self) -> Feature {
+        match self.0 {
+            RequestType::CarRequest(_) => Feature::Exchange,
+            RequestType::Replicate(_) => Feature::Replicate,
+            RequestType::GetCar { .. } => Feature::GetCar,
+            RequestType::CacheSummary => Feature::CacheSummary,
+            RequestType::FindContent { .. } => Feature::FindContent,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct CarResponse {
     // change this to the final cid version
@@ -49,6 +116,56 @@ pub struct CarResponse {
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ResponseType {
     CarResponse(CarResponse),
+    /// Whether the peer accepted a replication request.
+    ReplicateResponse(bool),
+    GetCarResponse(CarStream),
+    CacheSummaryResponse(CacheSummary),
+    FindContentResponse(Vec<ContentProvider>),
+}
+
+/// A peer known to provide some content, returned for [`RequestType::FindContent`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContentProvider {
+    pub peer_id: String,
+    pub addrs: Vec<String>,
+}
+
+/// A car file sent in response to [`RequestType::GetCar`].
+///
+/// Only the root is json encoded, the car file itself is streamed as raw bytes
+/// after the response header. An empty car file means the peer does not have the dag.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CarStream {
+    pub root: String,
+    /// The blocks sent, read from the store as they are written.
+    #[serde(skip)]
+    pub source: Option<CarSource>,
+    /// The car file received, spooled to disk rather than memory.
+    #[serde(skip)]
+    pub file: Option<Arc<SpooledCar>>,
+}
+
+impl CarStream {
+    pub fn new(root: String, source: Option<CarSource>) -> Self {
+        Self {
+            root,
+            source,
+            file: None,
+        }
+    }
+
+    /// Size of the car file received in bytes, or of the block data sent.
+    pub fn len(&self) -> u64 {
+        match (&self.file, &self.source) {
+            (Some(file), _) => file.len(),
+            (None, Some(source)) => source.len(),
+            (None, None) => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -62,7 +179,11 @@ impl RequestResponseCodec for UrsaExchangeCodec {
 
     type Response = UrsaExchangeResponse;
 
-    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+    async fn read_request<T>(
+        &mut self,
+        protocol: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Request>
     where
         T: AsyncRead + Unpin + Send,
     {
@@ -72,8 +193,15 @@ impl RequestResponseCodec for UrsaExchangeCodec {
             return Err(io::ErrorKind::UnexpectedEof.into());
         }
 
-        let request: UrsaExchangeRequest =
-            serde_json::from_str(&String::from_utf8(vec).unwrap()).unwrap();
+        // unknown request types of newer peers fail the stream, not the node
+        let request: UrsaExchangeRequest = serde_json::from_slice(&vec).map_err(invalid_data)?;
+        if !protocol.supports(request.feature()) {
+            return Err(invalid_data(format!(
+                "{:?} requests are not part of {:?}",
+                request.feature(),
+                protocol
+            )));
+        }
 
         Ok(request)
     }
@@ -92,22 +220,33 @@ impl RequestResponseCodec for UrsaExchangeCodec {
             return Err(io::ErrorKind::UnexpectedEof.into());
         }
 
-        let response: UrsaExchangeResponse =
-            serde_json::from_str(&String::from_utf8(vec).unwrap()).unwrap();
+        let mut response: UrsaExchangeResponse =
+            serde_json::from_slice(&vec).map_err(invalid_data)?;
+
+        if let UrsaExchangeResponse(ResponseType::GetCarResponse(car)) = &mut response {
+            car.file = Some(Arc::new(spool(io, MAX_CAR_SIZE).await?));
+        }
 
         Ok(response)
     }
 
     async fn write_request<T>(
         &mut self,
-        _: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
         req: Self::Request,
     ) -> io::Result<()>
     where
         T: AsyncWrite + Unpin + Send,
     {
-        let data = serde_json::to_vec(&req).unwrap();
+        // the peer was not identified yet and only took the older protocol
+        if !protocol.supports(req.feature()) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("The peer does not answer {:?} requests", req.feature()),
+            ));
+        }
+        let data = serde_json::to_vec(&req).map_err(invalid_data)?;
         write_length_prefixed(io, &data).await?;
         io.close().await?;
 
@@ -123,8 +262,15 @@ impl RequestResponseCodec for UrsaExchangeCodec {
     where
         T: futures::AsyncWrite + Unpin + Send,
     {
-        let data = serde_json::to_vec(&res).unwrap();
+        let data = serde_json::to_vec(&res).map_err(invalid_data)?;
         write_length_prefixed(io, &data).await?;
+        if let UrsaExchangeResponse(ResponseType::GetCarResponse(CarStream {
+            source: Some(source),
+            ..
+        })) = &res
+        {
+            source.write_to(io).await?;
+        }
         io.close().await?;
 
         Ok(())
@@ -134,6 +280,13 @@ impl RequestResponseCodec for UrsaExchangeCodec {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::codec::car::BlockLoader;
+    use cid::{
+        multihash::{Code, MultihashDigest},
+        Cid,
+    };
+    use futures::FutureExt;
+    use fvm_ipld_car::CarReader;
 
     #[async_std::test]
     async fn test_read_request() {
@@ -154,4 +307,73 @@ mod tests {
     async fn test_write_response() {
         todo!()
     }
+
+    #[async_std::test]
+    async fn test_unknown_requests_are_errors() {
+        let mut buf = futures::io::Cursor::new(Vec::new());
+        write_length_prefixed(&mut buf, br#"{"NotARequest":1}"#)
+            .await
+            .unwrap();
+        buf.set_position(0);
+        let err = UrsaExchangeCodec
+            .read_request(&UrsaProtocol::V1, &mut buf)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // newer request types are refused on the first protocol version
+        let request = UrsaExchangeRequest(RequestType::CacheSummary);
+        let mut buf = futures::io::Cursor::new(Vec::new());
+        UrsaExchangeCodec
+            .write_request(&UrsaProtocol::V1, &mut buf, request.clone())
+            .await
+            .unwrap();
+        buf.set_position(0);
+        assert!(UrsaExchangeCodec
+            .read_request(&UrsaProtocol::V0, &mut buf)
+            .await
+            .is_err());
+        assert!(UrsaExchangeCodec
+            .write_request(&UrsaProtocol::V0, &mut Vec::new(), request)
+            .await
+            .is_err());
+    }
+
+    #[async_std::test]
+    async fn test_car_response_is_streamed_raw() {
+        let data = vec![7; 1024];
+        let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&data));
+        let load: BlockLoader = Arc::new(|_| async { Ok(vec![7; 1024]) }.boxed());
+        let source = CarSource::new(cid, vec![(cid, data.len())], load);
+        let response = UrsaExchangeResponse(ResponseType::GetCarResponse(CarStream::new(
+            "root".to_string(),
+            Some(source),
+        )));
+
+        let mut buf = futures::io::Cursor::new(Vec::new());
+        UrsaExchangeCodec
+            .write_response(&UrsaProtocol::V1, &mut buf, response.clone())
+            .await
+            .unwrap();
+        // the car bytes are not json encoded
+        assert!(buf.get_ref().len() < 1024 + 256);
+
+        buf.set_position(0);
+        let decoded = UrsaExchangeCodec
+            .read_response(&UrsaProtocol::V1, &mut buf)
+            .await
+            .unwrap();
+        let car = match decoded {
+            UrsaExchangeResponse(ResponseType::GetCarResponse(car)) => car,
+            response => panic!("unexpected response {:?}", response),
+        };
+        assert_eq!(car.root, "root");
+        assert!(car.len() > 1024);
+        let file = car.file.unwrap().open().await.unwrap();
+        let mut reader = CarReader::new(file).await.unwrap();
+        assert_eq!(reader.header.roots, vec![cid]);
+        let block = reader.next_block().await.unwrap().unwrap();
+        assert_eq!((block.cid, block.data), (cid, data));
+        assert!(reader.next_block().await.unwrap().is_none());
+    }
 }
@@ -0,0 +1,177 @@
+//! Ursa protocol compatibility.
+//!
+//! Every ursa node lists the exchange protocols it answers on over Identify. The
+//! [`COMPATIBILITY_MATRIX`] maps the protocol [`Feature`]s to the protocol id that
+//! introduced them, so a node can keep talking to peers on older releases during a
+//! rolling upgrade instead of sending them messages they cannot decode. The
+//! `ursa/<version>` agent string is only reported, releases do not bump it in step
+//! with the protocols.
+
+use std::{fmt, str::FromStr};
+
+const URSA_AGENT_PREFIX: &str = "ursa/";
+
+/// Exchange protocol of the first release, which only knows car requests.
+pub const EXCHANGE_V0: &str = "/ursa/txrx/0.0.1";
+/// Exchange protocol of the request types added since.
+pub const EXCHANGE_V1: &str = "/ursa/txrx/0.1.0";
+
+/// Protocol features that were introduced after the first ursa release.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Feature {
+    /// The `/ursa/txrx` request-response exchange protocol.
+    Exchange,
+    /// Replication requests over the exchange protocol.
+    Replicate,
+    /// Car file transfers over the exchange protocol.
+    GetCar,
+    /// Cache summary exchange over the exchange protocol.
+    CacheSummary,
+    /// Provider lookups over the exchange protocol.
+    FindContent,
+}
+
+/// Exchange protocol a peer has to answer on for each [`Feature`].
+pub const COMPATIBILITY_MATRIX: &[(Feature, &str)] = &[
+    (Feature::Exchange, EXCHANGE_V0),
+    (Feature::Replicate, EXCHANGE_V1),
+    (Feature::GetCar, EXCHANGE_V1),
+    (Feature::CacheSummary, EXCHANGE_V1),
+    (Feature::FindContent, EXCHANGE_V1),
+];
+
+impl Feature {
+    /// The exchange protocol that introduced the feature.
+    pub fn protocol(self) -> &'static str {
+        COMPATIBILITY_MATRIX
+            .iter()
+            .find(|(f, _)| *f == self)
+            .map(|(_, protocol)| *protocol)
+            .unwrap_or(EXCHANGE_V1)
+    }
+}
+
+/// Whether a peer listing `protocols` over Identify understands `feature`.
+///
+/// Newer exchange protocols answer the requests of the older ones, so a peer on
+/// [`EXCHANGE_V1`] alone still takes car requests.
+pub fn supports(protocols: &[String], feature: Feature) -> bool {
+    let required = feature.protocol();
+    protocols.iter().any(|protocol| {
+        protocol == required || (required == EXCHANGE_V0 && protocol == EXCHANGE_V1)
+    })
+}
+
+/// A parsed `major.minor.patch` ursa agent version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct AgentVersion {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+}
+
+impl AgentVersion {
+    /// Version assumed for peers that are not running ursa at all.
+    pub const LEGACY: AgentVersion = AgentVersion::new(0, 0, 0);
+
+    pub const fn new(major: u64, minor: u64, patch: u64) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// The version of the running node.
+    pub fn current() -> Self {
+        env!("CARGO_PKG_VERSION")
+            .parse()
+            .expect("crate version is valid semver")
+    }
+
+    /// Parse the identify agent string, e.g. `ursa/0.1.0`.
+    ///
+    /// Agents that are not ursa nodes map to [`AgentVersion::LEGACY`].
+    pub fn from_agent(agent: &str) -> Self {
+        agent
+            .strip_prefix(URSA_AGENT_PREFIX)
+            .and_then(|version| version.parse().ok())
+            .unwrap_or(Self::LEGACY)
+    }
+}
+
+impl FromStr for AgentVersion {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // ignore pre-release and build metadata, e.g. `0.2.0-rc.1+abc`
+        let core = s.split(|c| c == '-' || c == '+').next().ok_or(())?;
+        let mut parts = core.split('.').map(|p| p.parse::<u64>().map_err(|_| ()));
+
+        let major = parts.next().ok_or(())??;
+        let minor = parts.next().unwrap_or(Ok(0))?;
+        let patch = parts.next().unwrap_or(Ok(0))?;
+
+        if parts.next().is_some() {
+            return Err(());
+        }
+
+        Ok(Self::new(major, minor, patch))
+    }
+}
+
+impl fmt::Display for AgentVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_agent() {
+        assert_eq!(
+            AgentVersion::from_agent("ursa/0.1.0"),
+            AgentVersion::new(0, 1, 0)
+        );
+        assert_eq!(
+            AgentVersion::from_agent("ursa/1.2.3-rc.1"),
+            AgentVersion::new(1, 2, 3)
+        );
+        assert_eq!(
+            AgentVersion::from_agent("ursa/0.2"),
+            AgentVersion::new(0, 2, 0)
+        );
+        assert_eq!(
+            AgentVersion::from_agent("go-ipfs/0.15.0"),
+            AgentVersion::LEGACY
+        );
+        assert_eq!(
+            AgentVersion::from_agent("ursa/not-a-version"),
+            AgentVersion::LEGACY
+        );
+    }
+
+    #[test]
+    fn test_current_protocols_support_all_features() {
+        let current = vec![EXCHANGE_V1.to_string(), EXCHANGE_V0.to_string()];
+        for (feature, _) in COMPATIBILITY_MATRIX {
+            assert!(supports(&current, *feature), "{:?}", feature);
+        }
+        assert!(supports(&[EXCHANGE_V1.to_string()], Feature::Exchange));
+    }
+
+    #[test]
+    fn test_legacy_protocols_are_restricted() {
+        let legacy = vec![EXCHANGE_V0.to_string(), "/ipfs/bitswap/1.2.0".to_string()];
+        assert!(supports(&legacy, Feature::Exchange));
+        assert!(!supports(&legacy, Feature::Replicate));
+        assert!(!supports(&legacy, Feature::GetCar));
+        assert!(!supports(
+            &["/ipfs/bitswap/1.2.0".to_string()],
+            Feature::Exchange
+        ));
+    }
+}
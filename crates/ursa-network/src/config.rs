@@ -1,17 +1,30 @@
-use libp2p::Multiaddr;
+use anyhow::{anyhow, Result};
+use libp2p::{multiaddr::Protocol, Multiaddr};
 use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, OneOrMany};
 use std::path::PathBuf;
 
+use crate::{
+    accounting::AccountingConfig, dial::DialConfig, faults::FaultConfig, gossipsub::GossipConfig,
+    hints::ContentHintsConfig, ingest::IngestConfig, peer_tags::ConnectionConfig,
+    proxy::ProxyConfig, purge::PurgeConfig, quota::RequestQuotaConfig, registry::RegistryConfig,
+    relay::RelayLimitsConfig, replication::ReplicationConfig, shaping::BandwidthConfig,
+    worker::WorkerConfig,
+};
+
 pub const DEFAULT_BOOTSTRAP: [&'static str; 2] = [
     "/ip4/159.223.211.234/tcp/6009/p2p/12D3KooWDji7xMLia6GAsyr4oiEFD2dd3zSryqNhfxU3Grzs1r9p",
     "/ip4/146.190.232.131/tcp/6009/p2p/12D3KooWGw8vCj9XayJDMXUiox6pCUFm7oVuWkDJeE2H9SDQVEcM",
 ];
 
 const DEFAULT_DB_PATH_STR: &str = ".ursa/data/ursa_db";
+const DEFAULT_REPUTATION_PATH_STR: &str = ".ursa/data/reputation.json";
 pub const DEFAULT_KEYSTORE_PATH_STR: &str = ".ursa/keystore";
 
 /// Ursa Configuration
+#[serde_as]
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
 pub struct NetworkConfig {
     /// Optional mdns local discovery.
     pub mdns: bool,
@@ -24,16 +37,63 @@ pub struct NetworkConfig {
     pub relay_client: bool,
     /// set true if it is a bootstrap node. default = false
     pub bootstrapper: bool,
-    /// Swarm listening Address.
-    pub swarm_addr: Multiaddr,
+    /// Swarm listening addresses, tcp on several interfaces or ports. QUIC and
+    /// websocket addresses are refused, the node only has a tcp transport. A single
+    /// `swarm_addr` of older configs is read as the only one.
+    #[serde(alias = "swarm_addr")]
+    #[serde_as(as = "OneOrMany<_>")]
+    pub swarm_addrs: Vec<Multiaddr>,
     /// Bootstrap nodes.
     pub bootstrap_nodes: Vec<Multiaddr>,
+    /// Relays listened through behind a NAT, with their `/p2p/<peer id>`. Peers
+    /// running a relay server are discovered and used when empty.
+    pub relays: Vec<Multiaddr>,
     /// Database path.
     pub database_path: PathBuf,
     /// user identity name
     pub identity: String,
     /// Keystore path. Defaults to ~/.ursa/keystore
     pub keystore_path: PathBuf,
+    /// Optional. Json file earlier versions persisted peer reputations to, imported
+    /// into the peerstore while it holds none.
+    pub reputation_path: Option<PathBuf>,
+    /// Commands queued for the network loop before senders have to wait.
+    pub command_queue_size: usize,
+    /// Blocks wanted concurrently while syncing a dag. 0 syncs a dag with a single
+    /// bitswap query instead.
+    pub sync_parallelism: usize,
+    // tables go last, toml cannot emit plain values after them
+    /// Connection limits, and the peer tags protected from pruning.
+    pub connections: ConnectionConfig,
+    /// Dial concurrency, timeout and the backoff of unreachable peers.
+    pub dialing: DialConfig,
+    /// Replication of hot content to nearby peers.
+    pub replication: ReplicationConfig,
+    /// Announcements of newly stored dags, and the provider hints taken from them.
+    pub content_hints: ContentHintsConfig,
+    /// Pool running store heavy work off the network loop.
+    pub workers: WorkerConfig,
+    /// Reservation and circuit limits of the relay server.
+    pub relay_limits: RelayLimitsConfig,
+    /// Upload caps, global and per traffic class.
+    pub bandwidth: BandwidthConfig,
+    /// Gossip message size limit and topic allowlist.
+    pub gossip: GossipConfig,
+    /// Caps on the exchange requests of each peer.
+    pub request_quotas: RequestQuotaConfig,
+    /// Rollups of the bytes served, for settlement.
+    pub accounting: AccountingConfig,
+    /// Optional. SOCKS5 proxy every outbound tcp dial goes through.
+    pub proxy: Option<ProxyConfig>,
+    /// Optional. Registry service the node registers and sends heartbeats to.
+    pub registry: Option<RegistryConfig>,
+    /// Optional. Admin keys whose purges gossiped on the purge topic are obeyed.
+    pub purge: Option<PurgeConfig>,
+    /// Optional. Follow the indexer announcements and ask the announced providers first.
+    pub ingest: Option<IngestConfig>,
+    /// Optional. Latency, drops and bandwidth limits injected into every connection,
+    /// for tests.
+    pub faults: Option<FaultConfig>,
 }
 
 impl Default for NetworkConfig {
@@ -49,11 +109,81 @@ impl Default for NetworkConfig {
             relay_client: true,
             relay_server: true,
             bootstrap_nodes,
+            relays: vec![],
             bootstrapper: false,
-            swarm_addr: "/ip4/0.0.0.0/tcp/6009".parse().unwrap(),
+            swarm_addrs: vec!["/ip4/0.0.0.0/tcp/6009".parse().unwrap()],
             database_path: PathBuf::from(env!("HOME")).join(DEFAULT_DB_PATH_STR),
             identity: "default".to_string(),
             keystore_path: PathBuf::from(env!("HOME")).join(DEFAULT_KEYSTORE_PATH_STR),
+            connections: ConnectionConfig::default(),
+            dialing: DialConfig::default(),
+            replication: ReplicationConfig::default(),
+            content_hints: ContentHintsConfig::default(),
+            reputation_path: Some(PathBuf::from(env!("HOME")).join(DEFAULT_REPUTATION_PATH_STR)),
+            workers: WorkerConfig::default(),
+            relay_limits: RelayLimitsConfig::default(),
+            bandwidth: BandwidthConfig::default(),
+            gossip: GossipConfig::default(),
+            request_quotas: RequestQuotaConfig::default(),
+            accounting: AccountingConfig::default(),
+            proxy: None,
+            registry: None,
+            purge: None,
+            ingest: None,
+            faults: None,
+            command_queue_size: 1024,
+            sync_parallelism: 8,
+        }
+    }
+}
+
+impl NetworkConfig {
+    /// Refuse settings the node cannot start with, rather than failing to listen later.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(addr) = self.swarm_addrs.iter().find(|addr| !is_tcp(addr)) {
+            return Err(anyhow!(
+                "swarm_addrs entry {} is not a tcp address, only tcp is supported",
+                addr
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Whether `addr` is a plain tcp address, an ip or dns name and a port.
+fn is_tcp(addr: &Multiaddr) -> bool {
+    let protocols: Vec<Protocol> = addr.iter().collect();
+    matches!(
+        protocols.as_slice(),
+        [
+            Protocol::Ip4(_)
+                | Protocol::Ip6(_)
+                | Protocol::Dns(_)
+                | Protocol::Dns4(_)
+                | Protocol::Dns6(_),
+            Protocol::Tcp(_)
+        ]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_swarm_addrs() {
+        let mut config = NetworkConfig::default();
+        assert!(config.validate().is_ok());
+
+        config.swarm_addrs = vec![
+            "/ip4/0.0.0.0/tcp/6009".parse().unwrap(),
+            "/ip6/::/tcp/6010".parse().unwrap(),
+        ];
+        assert!(config.validate().is_ok());
+
+        for addr in ["/ip4/0.0.0.0/udp/6009/quic", "/ip4/0.0.0.0/tcp/6009/ws"] {
+            config.swarm_addrs = vec![addr.parse().unwrap()];
+            assert!(config.validate().is_err());
         }
     }
 }
@@ -0,0 +1,242 @@
+//! Ursa dag sync.
+//!
+//! The [`DagSyncManager`] fetches a dag over bitswap one block per query, keeping up
+//! to `parallelism` wants in flight for each root. Every received block expands into
+//! the blocks still missing under it, so the sibling branches of wide dags such as
+//! chunked videos are fetched concurrently instead of one after the other.
+
+use cid::Cid;
+use fnv::FnvHashMap;
+use libp2p::PeerId;
+use std::collections::{HashSet, VecDeque};
+
+struct DagSync {
+    providers: Vec<PeerId>,
+    pending: VecDeque<Cid>,
+    inflight: HashSet<Cid>,
+}
+
+/// Work resulting from starting a sync or from a block arriving.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SyncStep {
+    /// Blocks to want next, with the providers to ask for them.
+    pub wants: Vec<(Cid, Vec<PeerId>)>,
+    /// Roots whose sync finished, and whether every block was found.
+    pub done: Vec<(Cid, bool)>,
+    /// Roots that received a block, with the number of their blocks still missing.
+    pub progress: Vec<(Cid, usize)>,
+}
+
+/// Progress of a dag sync, reported for every block it fetched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncProgress {
+    pub root: Cid,
+    /// Size of the fetched block, zero when unknown.
+    pub bytes: u64,
+    /// Blocks of the dag known to be missing still.
+    pub missing: usize,
+}
+
+pub struct DagSyncManager {
+    parallelism: usize,
+    syncs: FnvHashMap<Cid, DagSync>,
+    /// Roots waiting on each wanted block.
+    blocks: FnvHashMap<Cid, Vec<Cid>>,
+}
+
+impl DagSyncManager {
+    pub fn new(parallelism: usize) -> Self {
+        Self {
+            parallelism: parallelism.max(1),
+            syncs: Default::default(),
+            blocks: Default::default(),
+        }
+    }
+
+    /// Whether a sync is waiting on the bitswap query for `cid`.
+    pub fn is_wanted(&self, cid: &Cid) -> bool {
+        self.blocks.contains_key(cid)
+    }
+
+    /// Start syncing the dag under `root` from `providers`.
+    ///
+    /// Starting a root that is already syncing does nothing.
+    pub fn start(&mut self, root: Cid, providers: Vec<PeerId>) -> SyncStep {
+        let mut step = SyncStep::default();
+        if self.syncs.contains_key(&root) {
+            return step;
+        }
+
+        self.syncs.insert(
+            root,
+            DagSync {
+                providers,
+                pending: VecDeque::from([root]),
+                inflight: HashSet::new(),
+            },
+        );
+        self.fill(root, &mut step);
+        step
+    }
+
+    /// Record the outcome of the bitswap query for `block`.
+    ///
+    /// `missing` are the blocks under `block` that are not stored yet.
+    pub fn on_block(&mut self, block: Cid, found: bool, missing: Vec<Cid>) -> SyncStep {
+        let mut step = SyncStep::default();
+
+        for root in self.blocks.remove(&block).unwrap_or_default() {
+            let sync = match self.syncs.get_mut(&root) {
+                Some(sync) => sync,
+                None => continue,
+            };
+            sync.inflight.remove(&block);
+
+            if !found {
+                // the dag cannot be completed without this block
+                self.abort(root);
+                step.done.push((root, false));
+                continue;
+            }
+
+            for cid in &missing {
+                if !sync.inflight.contains(cid) && !sync.pending.contains(cid) {
+                    sync.pending.push_back(*cid);
+                }
+            }
+            self.fill(root, &mut step);
+
+            if let Some(sync) = self.syncs.get(&root) {
+                step.progress
+                    .push((root, sync.pending.len() + sync.inflight.len()));
+            }
+            if self
+                .syncs
+                .get(&root)
+                .map_or(false, |sync| sync.inflight.is_empty())
+            {
+                self.syncs.remove(&root);
+                step.done.push((root, true));
+            }
+        }
+
+        step
+    }
+
+    /// Want pending blocks of `root` until its parallelism is used up.
+    fn fill(&mut self, root: Cid, step: &mut SyncStep) {
+        let sync = match self.syncs.get_mut(&root) {
+            Some(sync) => sync,
+            None => return,
+        };
+
+        while sync.inflight.len() < self.parallelism {
+            let cid = match sync.pending.pop_front() {
+                Some(cid) => cid,
+                None => break,
+            };
+            sync.inflight.insert(cid);
+
+            let roots = self.blocks.entry(cid).or_default();
+            // blocks shared with another sync are already wanted
+            if roots.is_empty() {
+                step.wants.push((cid, sync.providers.clone()));
+            }
+            roots.push(root);
+        }
+    }
+
+    /// Stop syncing `root`, returning the wanted blocks no other sync waits on, or
+    /// `None` when `root` is not syncing.
+    pub fn cancel(&mut self, root: &Cid) -> Option<Vec<Cid>> {
+        self.syncs.contains_key(root).then(|| self.abort(*root))
+    }
+
+    fn abort(&mut self, root: Cid) -> Vec<Cid> {
+        let mut unwanted = vec![];
+        if let Some(sync) = self.syncs.remove(&root) {
+            for cid in sync.inflight {
+                if let Some(roots) = self.blocks.get_mut(&cid) {
+                    roots.retain(|r| *r != root);
+                    if roots.is_empty() {
+                        self.blocks.remove(&cid);
+                        unwanted.push(cid);
+                    }
+                }
+            }
+        }
+        unwanted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn cids() -> Vec<Cid> {
+        [
+            "bafkreif2opfibjypwkjzzry3jbibcjqcjwnpoqpeiqw75eu3s3u3zbdszq",
+            "bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku",
+            "bafkreidgvpkjawlxz6sffxzwgooowe5yt7i6wsyg236mfoks77nywkptdy",
+            "bafkreibme22gw2h7y2h7tg2fhqotaqjucnbc24deqo72b6mkl2egezxhvy",
+        ]
+        .iter()
+        .map(|cid| Cid::from_str(cid).unwrap())
+        .collect()
+    }
+
+    #[test]
+    fn test_parallel_branches() {
+        let cids = cids();
+        let (root, children) = (cids[0], &cids[1..]);
+        let mut syncs = DagSyncManager::new(2);
+
+        let step = syncs.start(root, vec![]);
+        assert_eq!(step.wants, vec![(root, vec![])]);
+
+        // two of the three siblings are wanted at once
+        let step = syncs.on_block(root, true, children.to_vec());
+        assert_eq!(step.wants.len(), 2);
+        assert!(step.done.is_empty());
+
+        let step = syncs.on_block(children[0], true, vec![]);
+        assert_eq!(step.wants, vec![(children[2], vec![])]);
+        assert_eq!(step.progress, vec![(root, 2)]);
+
+        syncs.on_block(children[1], true, vec![]);
+        let step = syncs.on_block(children[2], true, vec![]);
+        assert_eq!(step.done, vec![(root, true)]);
+        assert!(!syncs.is_wanted(&root));
+    }
+
+    #[test]
+    fn test_missing_block_fails_sync() {
+        let cids = cids();
+        let mut syncs = DagSyncManager::new(4);
+
+        syncs.start(cids[0], vec![]);
+        syncs.on_block(cids[0], true, vec![cids[1], cids[2]]);
+
+        let step = syncs.on_block(cids[1], false, vec![]);
+        assert_eq!(step.done, vec![(cids[0], false)]);
+        assert!(!syncs.is_wanted(&cids[2]));
+    }
+
+    #[test]
+    fn test_cancel_sync() {
+        let cids = cids();
+        let mut syncs = DagSyncManager::new(4);
+        assert_eq!(syncs.cancel(&cids[0]), None);
+
+        syncs.start(cids[0], vec![]);
+        syncs.start(cids[3], vec![]);
+        syncs.on_block(cids[0], true, vec![cids[1], cids[3]]);
+
+        // the block shared with the other sync stays wanted
+        assert_eq!(syncs.cancel(&cids[0]), Some(vec![cids[1]]));
+        assert!(!syncs.is_wanted(&cids[1]));
+        assert!(syncs.is_wanted(&cids[3]));
+        assert!(syncs.on_block(cids[1], true, vec![]).done.is_empty());
+    }
+}
@@ -0,0 +1,132 @@
+//! Dial limits and backoff.
+//!
+//! Every failed dial of a peer doubles the time before the discovery layer dials it
+//! again, from `backoff_secs` up to `max_backoff_secs`, and a connection to the peer
+//! resets it. Dials kademlia asks for during the backoff are aborted, so its queries
+//! move on to other peers instead of hammering an unreachable address.
+
+use fnv::FnvHashMap;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct DialConfig {
+    /// Addresses of a peer dialed concurrently.
+    pub concurrency_factor: u8,
+    /// Seconds a dial may take, connection upgrades included.
+    pub timeout_secs: u64,
+    /// Seconds before a peer is dialed again after its first failed dial. 0 disables
+    /// the backoff.
+    pub backoff_secs: u64,
+    pub max_backoff_secs: u64,
+}
+
+impl Default for DialConfig {
+    fn default() -> Self {
+        Self {
+            concurrency_factor: 8,
+            timeout_secs: 20,
+            backoff_secs: 10,
+            max_backoff_secs: 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DialStats {
+    /// Failed dials since the last connection.
+    pub failures: u32,
+    /// Failed dials since the node started.
+    pub total_failures: u64,
+    /// The peer is not dialed before then.
+    pub retry_at: Instant,
+}
+
+#[derive(Default)]
+pub struct DialBackoff {
+    config: DialConfig,
+    peers: FnvHashMap<PeerId, DialStats>,
+}
+
+impl DialBackoff {
+    pub fn new(config: DialConfig) -> Self {
+        Self {
+            config,
+            peers: Default::default(),
+        }
+    }
+
+    /// Record a failed dial of `peer`, returning its stats.
+    pub fn failed(&mut self, peer: PeerId) -> DialStats {
+        let stats = self.peers.entry(peer).or_insert(DialStats {
+            failures: 0,
+            total_failures: 0,
+            retry_at: Instant::now(),
+        });
+        stats.failures += 1;
+        stats.total_failures += 1;
+        let backoff = self
+            .config
+            .backoff_secs
+            .saturating_mul(1 << (stats.failures - 1).min(20))
+            .min(self.config.max_backoff_secs);
+        stats.retry_at = Instant::now() + Duration::from_secs(backoff);
+        *stats
+    }
+
+    /// A connection to `peer` was established.
+    pub fn connected(&mut self, peer: &PeerId) {
+        if let Some(stats) = self.peers.get_mut(peer) {
+            stats.failures = 0;
+            stats.retry_at = Instant::now();
+        }
+    }
+
+    /// Whether `peer` failed recently enough not to be dialed yet.
+    pub fn is_backed_off(&self, peer: &PeerId) -> bool {
+        self.peers.get(peer).map_or(false, |stats| {
+            stats.failures > 0 && stats.retry_at > Instant::now()
+        })
+    }
+
+    pub fn stats(&self, peer: &PeerId) -> Option<DialStats> {
+        self.peers.get(peer).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = DialBackoff::new(DialConfig {
+            backoff_secs: 10,
+            max_backoff_secs: 30,
+            ..Default::default()
+        });
+        let peer = PeerId::random();
+        assert!(!backoff.is_backed_off(&peer));
+
+        backoff.failed(peer);
+        assert!(backoff.is_backed_off(&peer));
+        backoff.failed(peer);
+        let stats = backoff.failed(peer);
+        assert_eq!(stats.failures, 3);
+        // 40 seconds capped to 30
+        assert!(stats.retry_at <= Instant::now() + Duration::from_secs(30));
+
+        backoff.connected(&peer);
+        assert!(!backoff.is_backed_off(&peer));
+        assert_eq!(backoff.stats(&peer).unwrap().total_failures, 3);
+
+        let mut disabled = DialBackoff::new(DialConfig {
+            backoff_secs: 0,
+            ..Default::default()
+        });
+        disabled.failed(peer);
+        assert!(!disabled.is_backed_off(&peer));
+    }
+}
@@ -7,11 +7,13 @@ use std::{
     collections::{HashMap, HashSet, VecDeque},
     num::NonZeroUsize,
     task::{Context, Poll},
+    time::Instant,
 };
 
-use crate::config::NetworkConfig;
+use crate::{config::NetworkConfig, dial::DialBackoff};
 use anyhow::{anyhow, Error, Result};
 use async_std::task::block_on;
+use cid::Cid;
 use libp2p::core::transport::ListenerId;
 use libp2p::kad::KademliaBucketInserts;
 use libp2p::swarm::DialError;
@@ -19,8 +21,11 @@ use libp2p::{
     core::{connection::ConnectionId, ConnectedPoint},
     identity::Keypair,
     kad::{
-        handler::KademliaHandlerProto, store::MemoryStore, Kademlia, KademliaConfig, KademliaEvent,
-        QueryId, QueryResult,
+        handler::KademliaHandlerProto,
+        record::{Key, Record},
+        store::{MemoryStore, RecordStore},
+        GetProvidersError, GetProvidersOk, GetRecordError, GetRecordOk, Kademlia, KademliaConfig,
+        KademliaEvent, QueryId, QueryResult, Quorum,
     },
     mdns::{Mdns, MdnsConfig, MdnsEvent},
     multiaddr::Protocol,
@@ -30,7 +35,8 @@ use libp2p::{
     },
     Multiaddr, PeerId,
 };
-use tracing::{info, warn};
+use tracing::{debug, info, warn};
+use ursa_metrics::events::{track, MetricEvent};
 
 pub const URSA_KAD_PROTOCOL: &[u8] = b"/ursa/kad/0.0.1";
 
@@ -43,6 +49,16 @@ pub struct PeerInfo {
 pub enum DiscoveryEvent {
     Connected(PeerId),
     Disconnected(PeerId),
+    /// A provider lookup started with [`DiscoveryBehaviour::get_providers`] finished.
+    Providers {
+        id: QueryId,
+        providers: Vec<(PeerId, Vec<Multiaddr>)>,
+    },
+    /// A record lookup started with [`DiscoveryBehaviour::get_record`] finished.
+    Record {
+        id: QueryId,
+        values: Vec<Vec<u8>>,
+    },
 }
 
 pub struct DiscoveryBehaviour {
@@ -59,6 +75,8 @@ pub struct DiscoveryBehaviour {
     events: VecDeque<DiscoveryEvent>,
     /// Optional MDNS protocol.
     mdns: Toggle<Mdns>,
+    /// Failed dials per peer, and when they may be dialed again.
+    dials: DialBackoff,
 }
 
 impl DiscoveryBehaviour {
@@ -112,6 +130,7 @@ impl DiscoveryBehaviour {
             peer_info: HashMap::new(),
             events: VecDeque::new(),
             mdns: mdns.into(),
+            dials: DialBackoff::new(config.dialing.clone()),
         }
     }
 
@@ -145,16 +164,96 @@ impl DiscoveryBehaviour {
             .map_err(|err| anyhow!("{:?}", err))
     }
 
+    /// Providers of `cid` known without a lookup, from the local provider records.
+    pub fn providers(&mut self, cid: &Cid) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        let key = Key::new(&cid.to_bytes());
+        let records = self.kademlia.store_mut().providers(&key);
+
+        records
+            .into_iter()
+            .filter(|record| record.provider != self.local_peer_id)
+            .map(|record| {
+                let mut addrs = record.addresses;
+                for addr in self.kademlia.addresses_of_peer(&record.provider) {
+                    if !addrs.contains(&addr) {
+                        addrs.push(addr);
+                    }
+                }
+                (record.provider, addrs)
+            })
+            .collect()
+    }
+
+    /// Start a Kademlia walk for the providers of `cid`.
+    ///
+    /// The result is emitted as a [`DiscoveryEvent::Providers`] with the returned id.
+    pub fn get_providers(&mut self, cid: &Cid) -> QueryId {
+        self.kademlia.get_providers(Key::new(&cid.to_bytes()))
+    }
+
+    /// Announce this node as a provider of `cid` in the DHT.
+    pub fn start_providing(&mut self, cid: &Cid) -> Result<QueryId> {
+        self.kademlia
+            .start_providing(Key::new(&cid.to_bytes()))
+            .map_err(|err| anyhow!("{:?}", err))
+    }
+
+    /// Stop announcing this node as a provider of `cid` in the DHT.
+    pub fn stop_providing(&mut self, cid: &Cid) {
+        self.kademlia.stop_providing(&Key::new(&cid.to_bytes()));
+    }
+
+    /// Store `value` under `key` on the peers closest to it.
+    pub fn put_record(&mut self, key: Key, value: Vec<u8>) -> Result<QueryId> {
+        self.kademlia
+            .put_record(Record::new(key, value), Quorum::One)
+            .map_err(|err| anyhow!("{:?}", err))
+    }
+
+    /// Look up the values stored under `key`.
+    ///
+    /// The result is emitted as a [`DiscoveryEvent::Record`] with the returned id.
+    pub fn get_record(&mut self, key: Key) -> QueryId {
+        self.kademlia.get_record(key, Quorum::One)
+    }
+
     pub fn bootstrap_addrs(&self) -> Vec<(PeerId, Multiaddr)> {
         self.bootstrap_nodes.clone()
     }
 
-    fn handle_kad_event(&self, event: KademliaEvent) {
+    fn handle_kad_event(&mut self, event: KademliaEvent) {
         info!("[KademliaEvent] {:?}", event);
 
-        if let KademliaEvent::OutboundQueryCompleted { result, .. } = event {
-            if let QueryResult::GetClosestPeers(Ok(closest_peers)) = result {
-                let _peers = closest_peers.peers;
+        if let KademliaEvent::OutboundQueryCompleted { id, result, .. } = event {
+            match result {
+                QueryResult::GetClosestPeers(Ok(closest_peers)) => {
+                    let _peers = closest_peers.peers;
+                }
+                QueryResult::GetProviders(Ok(GetProvidersOk { providers, .. }))
+                | QueryResult::GetProviders(Err(GetProvidersError::Timeout {
+                    providers, ..
+                })) => {
+                    let providers = providers
+                        .into_iter()
+                        .map(|peer| (peer, self.kademlia.addresses_of_peer(&peer)))
+                        .collect();
+                    self.events
+                        .push_back(DiscoveryEvent::Providers { id, providers });
+                }
+                QueryResult::GetRecord(result) => {
+                    let records = match result {
+                        Ok(GetRecordOk { records, .. })
+                        | Err(GetRecordError::QuorumFailed { records, .. })
+                        | Err(GetRecordError::Timeout { records, .. }) => records,
+                        Err(GetRecordError::NotFound { .. }) => vec![],
+                    };
+                    let values = records
+                        .into_iter()
+                        .map(|peer_record| peer_record.record.value)
+                        .collect();
+                    self.events.push_back(DiscoveryEvent::Record { id, values });
+                }
+                _ => {}
             }
         }
     }
@@ -195,6 +294,7 @@ impl NetworkBehaviour for DiscoveryBehaviour {
         failed_addresses: Option<&Vec<Multiaddr>>,
         other_established: usize,
     ) {
+        self.dials.connected(peer_id);
         if self.peers.insert(*peer_id) {
             self.kademlia.inject_connection_established(
                 peer_id,
@@ -230,6 +330,35 @@ impl NetworkBehaviour for DiscoveryBehaviour {
         }
     }
 
+    fn inject_dial_failure(
+        &mut self,
+        peer_id: Option<PeerId>,
+        handler: Self::ConnectionHandler,
+        error: &DialError,
+    ) {
+        if let Some(peer) = peer_id {
+            let unreachable = matches!(
+                error,
+                DialError::Transport(_)
+                    | DialError::ConnectionIo(_)
+                    | DialError::NoAddresses
+                    | DialError::WrongPeerId { .. }
+            );
+            if unreachable {
+                let stats = self.dials.failed(peer);
+                track(MetricEvent::DialFailed, None, None);
+                debug!(
+                    "Dialing {} failed {} times in a row, retrying in {:?}: {}",
+                    peer,
+                    stats.failures,
+                    stats.retry_at.saturating_duration_since(Instant::now()),
+                    error
+                );
+            }
+        }
+        self.kademlia.inject_dial_failure(peer_id, handler, error);
+    }
+
     fn inject_address_change(
         &mut self,
         peer_id: &PeerId,
@@ -264,7 +393,17 @@ impl NetworkBehaviour for DiscoveryBehaviour {
             match action {
                 NetworkBehaviourAction::GenerateEvent(event) => self.handle_kad_event(event),
                 NetworkBehaviourAction::Dial { opts, handler } => {
-                    return Poll::Ready(NetworkBehaviourAction::Dial { opts, handler })
+                    match opts.get_peer_id() {
+                        Some(peer) if self.dials.is_backed_off(&peer) => {
+                            // let the queries move on to other peers
+                            self.kademlia.inject_dial_failure(
+                                Some(peer),
+                                handler,
+                                &DialError::Aborted,
+                            );
+                        }
+                        _ => return Poll::Ready(NetworkBehaviourAction::Dial { opts, handler }),
+                    }
                 }
                 NetworkBehaviourAction::NotifyHandler {
                     peer_id,
@@ -319,6 +458,11 @@ impl NetworkBehaviour for DiscoveryBehaviour {
             }
         }
 
+        // kademlia events handled above may have queued new events
+        if let Some(event) = self.events.pop_front() {
+            return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
+        }
+
         Poll::Pending
     }
 }
@@ -0,0 +1,130 @@
+//! Node events for dashboards.
+//!
+//! [`UrsaEvent`](crate::UrsaEvent)s carry response channels and raw messages for the
+//! code driving the node. The [`NodeEvent`]s broadcast here are their serializable
+//! summary, served to dashboards as server-sent events on `/events`.
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Buffered events per subscriber, slow subscribers skip the oldest beyond that.
+const EVENT_BUFFER: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NodeEvent {
+    PeerConnected {
+        peer: String,
+    },
+    PeerDisconnected {
+        peer: String,
+    },
+    NatStatusChanged {
+        /// `public`, `private` or `unknown`.
+        status: String,
+        /// Public address confirmed by autonat.
+        address: Option<String>,
+    },
+    /// A bitswap query completed.
+    BitswapCompleted {
+        cid: String,
+        found: bool,
+    },
+    /// An announcement of the head advertisement was sent to the indexers.
+    ProviderAnnounced {
+        head: Option<String>,
+        /// `gossipsub` or `http`.
+        via: String,
+    },
+    /// Hot content was pushed to more providers.
+    ContentReplicated {
+        cid: String,
+        /// This node and the replicas.
+        providers: Vec<String>,
+    },
+    /// A peer went over its request quota.
+    PeerThrottled {
+        peer: String,
+        requests: u32,
+        disconnected: bool,
+    },
+    /// Autorelay picked a relay, the node listens through it on `address`.
+    RelaySelected {
+        relay: String,
+        address: String,
+    },
+    /// The relay server opened a circuit from `src` to `dst`.
+    RelayCircuitOpened {
+        src: String,
+        dst: String,
+        max_duration_secs: u64,
+        max_bytes: u64,
+    },
+    RelayCircuitClosed {
+        src: String,
+        dst: String,
+        duration_secs: u64,
+        error: Option<String>,
+    },
+    /// The relay server denied a reservation, or a circuit to `dst`.
+    RelayDenied {
+        peer: String,
+        dst: Option<String>,
+        /// Name of the limit the request most likely hit, or `no_reservation` when
+        /// `dst` holds no reservation.
+        limit: String,
+        max: usize,
+    },
+    /// A purge signed by an admin key was received, the content is to be evicted.
+    PurgeReceived {
+        cids: Vec<String>,
+        context_ids: Vec<String>,
+    },
+}
+
+#[derive(Clone)]
+pub struct NodeEvents(broadcast::Sender<NodeEvent>);
+
+impl Default for NodeEvents {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        Self(sender)
+    }
+}
+
+impl NodeEvents {
+    pub fn publish(&self, event: NodeEvent) {
+        // nobody listening is fine
+        let _ = self.0.send(event);
+    }
+
+    /// Receive every event from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<NodeEvent> {
+        self.0.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn test_node_events() {
+        let events = NodeEvents::default();
+        events.publish(NodeEvent::PeerConnected {
+            peer: "unheard".to_string(),
+        });
+
+        let mut receiver = events.subscribe();
+        let event = NodeEvent::BitswapCompleted {
+            cid: "bafkreif2opfibjypwkjzzry3jbibcjqcjwnpoqpeiqw75eu3s3u3zbdszq".to_string(),
+            found: true,
+        };
+        events.publish(event.clone());
+        assert_eq!(receiver.recv().await.unwrap(), event);
+        assert_eq!(
+            serde_json::to_value(&event).unwrap()["type"],
+            "bitswap_completed"
+        );
+    }
+}
@@ -0,0 +1,311 @@
+//! Fault injection.
+//!
+//! With [`FaultConfig`] set, the transport wraps every connection in [`Faulty`],
+//! which holds back each write for `latency_ms`, caps the bytes written per second,
+//! cuts the connection after `drop_after_bytes`, and fails a `drop_rate` share of
+//! the connections right away. This exercises the retrieval timeouts and retries and
+//! the relay fallback under bad network conditions, in the tests over the memory
+//! transport or on a test network. The faults of a peer are taken from the first
+//! entry of `peers` matching its peer id or address, and from `default` otherwise.
+//!
+//! Drops are drawn from a random generator seeded with `seed`, so the same
+//! connections are dropped on every run as long as they are made in the same order.
+//! Faults are decided by the remote address of a connection: the peer id of an
+//! outbound connection is known from a `/p2p` dial address, the peer id of an
+//! inbound one is not.
+
+use futures::{ready, AsyncRead, AsyncWrite};
+use futures_timer::Delay;
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+use tracing::warn;
+
+use crate::shaping::{Shaped, TokenBucket};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct FaultConfig {
+    /// Seed of the drops, the same seed drops the same connections.
+    #[serde(default)]
+    pub seed: u64,
+    /// Faults of the peers not listed in `peers`.
+    #[serde(default)]
+    pub default: Faults,
+    /// Faults of single peers.
+    #[serde(default)]
+    pub peers: Vec<PeerFaults>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Faults {
+    /// Milliseconds every write is held back before it is written.
+    #[serde(default)]
+    pub latency_ms: u64,
+    /// Share of the connections failing right away, from 0 to 1.
+    #[serde(default)]
+    pub drop_rate: f64,
+    /// Bytes read and written before the connection fails.
+    #[serde(default)]
+    pub drop_after_bytes: Option<u64>,
+    /// Bytes written per second on each connection.
+    #[serde(default)]
+    pub bandwidth: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PeerFaults {
+    /// Peer id, or address prefix like `/ip4/1.2.3.4`, of the peer.
+    pub peer: String,
+    #[serde(flatten)]
+    pub faults: Faults,
+}
+
+enum Matcher {
+    Peer(PeerId),
+    Addr(Multiaddr),
+}
+
+impl Matcher {
+    fn parse(peer: &str) -> Option<Self> {
+        if let Ok(peer) = PeerId::from_str(peer) {
+            return Some(Matcher::Peer(peer));
+        }
+        Multiaddr::from_str(peer).ok().map(Matcher::Addr)
+    }
+
+    fn matches(&self, addr: &Multiaddr) -> bool {
+        match self {
+            Matcher::Peer(peer) => addr.iter().any(|protocol| match protocol {
+                Protocol::P2p(hash) => PeerId::from_multihash(hash).ok() == Some(*peer),
+                _ => false,
+            }),
+            Matcher::Addr(prefix) => addr.iter().take(prefix.iter().count()).eq(prefix.iter()),
+        }
+    }
+}
+
+/// Decides the faults of every new connection.
+pub struct FaultInjector {
+    peers: Vec<(Matcher, Faults)>,
+    default: Faults,
+    rng: Mutex<StdRng>,
+}
+
+impl FaultInjector {
+    pub fn new(config: &FaultConfig) -> Self {
+        let peers = config
+            .peers
+            .iter()
+            .filter_map(|peer| match Matcher::parse(&peer.peer) {
+                Some(matcher) => Some((matcher, peer.faults.clone())),
+                None => {
+                    warn!(
+                        "Ignoring the faults of {}, not a peer id or address",
+                        peer.peer
+                    );
+                    None
+                }
+            })
+            .collect();
+        Self {
+            peers,
+            default: config.default.clone(),
+            rng: Mutex::new(StdRng::seed_from_u64(config.seed)),
+        }
+    }
+
+    /// Faults of a new connection to or from `addr`.
+    pub fn connect(&self, addr: &Multiaddr) -> ConnectionFaults {
+        let faults = self
+            .peers
+            .iter()
+            .find(|(matcher, _)| matcher.matches(addr))
+            .map_or(&self.default, |(_, faults)| faults);
+        let dropped =
+            faults.drop_rate > 0.0 && self.rng.lock().unwrap().gen_bool(faults.drop_rate.min(1.0));
+        ConnectionFaults {
+            latency: Duration::from_millis(faults.latency_ms),
+            dropped,
+            remaining: faults.drop_after_bytes,
+            bucket: faults
+                .bandwidth
+                .map(|rate| Arc::new(TokenBucket::new(rate))),
+        }
+    }
+}
+
+/// Faults of a single connection.
+pub struct ConnectionFaults {
+    latency: Duration,
+    dropped: bool,
+    /// Bytes left before the connection fails.
+    remaining: Option<u64>,
+    bucket: Option<Arc<TokenBucket>>,
+}
+
+impl ConnectionFaults {
+    fn check(&self) -> io::Result<()> {
+        if self.dropped || self.remaining == Some(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "connection dropped by fault injection",
+            ));
+        }
+        Ok(())
+    }
+
+    /// How many of `wanted` bytes may go through before the connection fails.
+    fn allowed(&self, wanted: usize) -> usize {
+        self.remaining
+            .map_or(wanted, |remaining| wanted.min(remaining as usize))
+    }
+
+    /// Count `bytes` against `remaining`.
+    fn count(&mut self, bytes: usize) {
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining = remaining.saturating_sub(bytes as u64);
+        }
+    }
+}
+
+/// Connection suffering the faults it was given, a plain connection without.
+pub struct Faulty<C> {
+    inner: Shaped<C>,
+    faults: Option<ConnectionFaults>,
+    delay: Option<Delay>,
+    /// Whether the latency of the next write has been waited for.
+    delayed: bool,
+}
+
+impl<C> Faulty<C> {
+    pub fn new(inner: C, faults: Option<ConnectionFaults>) -> Self {
+        let bucket = faults.as_ref().and_then(|faults| faults.bucket.clone());
+        Self {
+            inner: Shaped::new(inner, bucket),
+            faults,
+            delay: None,
+            delayed: false,
+        }
+    }
+}
+
+impl<C: AsyncRead + Unpin> AsyncRead for Faulty<C> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let faults = match this.faults.as_mut() {
+            Some(faults) => faults,
+            None => return Pin::new(&mut this.inner).poll_read(cx, buf),
+        };
+        faults.check()?;
+        let len = faults.allowed(buf.len());
+        let read = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut buf[..len]))?;
+        faults.count(read);
+        Poll::Ready(Ok(read))
+    }
+}
+
+impl<C: AsyncWrite + Unpin> AsyncWrite for Faulty<C> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let faults = match this.faults.as_mut() {
+            Some(faults) => faults,
+            None => return Pin::new(&mut this.inner).poll_write(cx, buf),
+        };
+        faults.check()?;
+        if !faults.latency.is_zero() && !this.delayed {
+            let delay = this.delay.get_or_insert_with(|| Delay::new(faults.latency));
+            ready!(Pin::new(delay).poll(cx));
+            this.delay = None;
+            this.delayed = true;
+        }
+        let len = faults.allowed(buf.len());
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..len]))?;
+        this.delayed = false;
+        faults.count(written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{io::Cursor, AsyncWriteExt};
+    use std::time::Instant;
+
+    #[async_std::test]
+    async fn test_faults() {
+        let slow = PeerId::random();
+        let config = FaultConfig {
+            seed: 7,
+            default: Faults {
+                drop_rate: 0.5,
+                ..Default::default()
+            },
+            peers: vec![
+                PeerFaults {
+                    peer: slow.to_string(),
+                    faults: Faults {
+                        latency_ms: 50,
+                        drop_after_bytes: Some(4),
+                        ..Default::default()
+                    },
+                },
+                PeerFaults {
+                    peer: "/ip4/10.0.0.1".to_string(),
+                    faults: Faults::default(),
+                },
+            ],
+        };
+
+        // the same seed drops the same connections
+        let addr: Multiaddr = "/ip4/1.2.3.4/tcp/6009".parse().unwrap();
+        let drops = |injector: FaultInjector| -> Vec<bool> {
+            (0..16).map(|_| injector.connect(&addr).dropped).collect()
+        };
+        let first = drops(FaultInjector::new(&config));
+        assert_eq!(first, drops(FaultInjector::new(&config)));
+        assert!(first.contains(&true) && first.contains(&false));
+
+        let injector = FaultInjector::new(&config);
+        let listed: Multiaddr = "/ip4/10.0.0.1/tcp/6009".parse().unwrap();
+        assert!((0..16).all(|_| !injector.connect(&listed).dropped));
+
+        let addr: Multiaddr = format!("/ip4/1.2.3.4/tcp/6009/p2p/{slow}").parse().unwrap();
+        let mut conn = Faulty::new(Cursor::new(vec![]), Some(injector.connect(&addr)));
+        let start = Instant::now();
+        conn.write_all(b"ping").await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        // cut after 4 bytes
+        assert_eq!(
+            conn.write_all(b"pong").await.unwrap_err().kind(),
+            io::ErrorKind::ConnectionReset
+        );
+    }
+}
@@ -1,21 +1,169 @@
 use crate::config::NetworkConfig;
-use anyhow::anyhow;
+use anyhow::{anyhow, Result};
+use fnv::FnvHashMap;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::hash_map::DefaultHasher,
+    collections::{hash_map::DefaultHasher, HashSet, VecDeque},
     hash::{Hash, Hasher},
-    time::Duration,
+    time::{Duration, Instant},
 };
+use tracing::warn;
 
 use libp2p::{
     gossipsub::{
-        Gossipsub, GossipsubConfigBuilder, GossipsubMessage, MessageAuthenticity, MessageId,
-        ValidationMode,
+        Gossipsub, GossipsubConfigBuilder, GossipsubMessage, IdentTopic as Topic,
+        MessageAuthenticity, MessageId, TopicHash, ValidationMode,
     },
     identity::Keypair,
+    PeerId,
 };
 
 const URSA_GOSSIP_PROTOCOL: &str = "ursa/gossipsub/0.0.1";
 
+/// Room left in a gossipsub frame for the envelope and control messages of a message
+/// of the maximum size.
+const RPC_OVERHEAD: usize = 64 * 1024;
+
+/// Peers a publish to a topic the node is not subscribed to fans out to, mesh_n of
+/// the gossipsub config.
+pub const FANOUT_PEERS: usize = 8;
+
+/// Wait before the first retry of a queued publish, doubled on every further retry.
+const PUBLISH_RETRY: Duration = Duration::from_secs(1);
+
+/// What the messages published by the node carry about their origin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GossipAuthenticity {
+    /// The peer id of the node, a sequence number and a signature.
+    Signed,
+    /// The peer id of the node and a sequence number, unsigned.
+    Author,
+    /// A random peer id and sequence number, unsigned.
+    RandomAuthor,
+    /// Neither an author nor a sequence number.
+    Anonymous,
+}
+
+/// What received messages must carry to be accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GossipValidation {
+    /// An author, a sequence number and a valid signature.
+    Strict,
+    /// Any fields present are checked, a signature when there is one.
+    Permissive,
+    /// Neither an author, a sequence number nor a signature.
+    Anonymous,
+    /// Nothing is checked.
+    None,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct GossipConfig {
+    /// Largest message data accepted, larger messages are rejected and their sender
+    /// penalized.
+    pub max_message_size: usize,
+    /// Topics the node subscribes to and accepts messages on, any topic when empty.
+    pub allowed_topics: Vec<String>,
+    /// Peers a message is published to at least, the mesh peers of a subscribed topic
+    /// or the subscribers of another one. Publishing with fewer fails. 0 disables.
+    pub min_publish_peers: usize,
+    /// Publishes that failed for lack of peers queued for a retry, the oldest dropped
+    /// past it. 0 disables the retries.
+    pub max_queued_publishes: usize,
+    /// Seconds a queued publish is retried for before it is dropped.
+    pub max_publish_age_secs: u64,
+    /// Signing every message costs a signature per publish and a check per receipt,
+    /// trusted meshes may leave messages unsigned or anonymous.
+    pub authenticity: GossipAuthenticity,
+    /// Optional. The validation matching `authenticity` when unset.
+    pub validation: Option<GossipValidation>,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self {
+            max_message_size: 1024 * 1024,
+            allowed_topics: vec![],
+            min_publish_peers: 1,
+            max_queued_publishes: 256,
+            max_publish_age_secs: 60,
+            authenticity: GossipAuthenticity::Signed,
+            validation: None,
+        }
+    }
+}
+
+impl GossipConfig {
+    pub fn allows(&self, topic: &TopicHash) -> bool {
+        self.allowed_topics.is_empty()
+            || self
+                .allowed_topics
+                .iter()
+                .any(|allowed| allowed == topic.as_str())
+    }
+
+    pub fn message_authenticity(&self, keypair: &Keypair) -> MessageAuthenticity {
+        match self.authenticity {
+            GossipAuthenticity::Signed => MessageAuthenticity::Signed(keypair.clone()),
+            GossipAuthenticity::Author => {
+                MessageAuthenticity::Author(keypair.public().to_peer_id())
+            }
+            GossipAuthenticity::RandomAuthor => MessageAuthenticity::RandomAuthor,
+            GossipAuthenticity::Anonymous => MessageAuthenticity::Anonymous,
+        }
+    }
+
+    /// The validation of received messages, failing when the messages the node
+    /// publishes would not pass it on nodes of the same config.
+    pub fn validation_mode(&self) -> Result<ValidationMode> {
+        let validation = self.validation.unwrap_or(match self.authenticity {
+            GossipAuthenticity::Signed => GossipValidation::Strict,
+            GossipAuthenticity::Author | GossipAuthenticity::RandomAuthor => {
+                GossipValidation::Permissive
+            }
+            GossipAuthenticity::Anonymous => GossipValidation::Anonymous,
+        });
+        let passes = match validation {
+            GossipValidation::Strict => self.authenticity == GossipAuthenticity::Signed,
+            GossipValidation::Anonymous => self.authenticity == GossipAuthenticity::Anonymous,
+            GossipValidation::Permissive | GossipValidation::None => true,
+        };
+        if !passes {
+            return Err(anyhow!(
+                "Gossip messages published {:?} fail {:?} validation",
+                self.authenticity,
+                validation
+            ));
+        }
+        Ok(match validation {
+            GossipValidation::Strict => ValidationMode::Strict,
+            GossipValidation::Permissive => ValidationMode::Permissive,
+            GossipValidation::Anonymous => ValidationMode::Anonymous,
+            GossipValidation::None => ValidationMode::None,
+        })
+    }
+
+    /// Whether a message reaches enough peers to be published, given the peers it is
+    /// sent to.
+    pub fn can_publish(&self, peers: usize) -> bool {
+        peers >= self.min_publish_peers
+    }
+
+    /// Why `message` is rejected, if it is.
+    pub fn violation(&self, message: &GossipsubMessage) -> Option<&'static str> {
+        if message.data.len() > self.max_message_size {
+            Some("the message is over the maximum size")
+        } else if !self.allows(&message.topic) {
+            Some("the topic is not allowed")
+        } else {
+            None
+        }
+    }
+}
+
 ///
 #[derive(Debug)]
 pub struct UrsaGossipsub;
@@ -31,7 +179,8 @@ impl UrsaGossipsub {
         let fanout_ttl = Duration::from_secs(60);
         // D_out
         let mesh_outbound_min = if is_bootstrapper { 0 } else { (mesh_n / 2) - 1 };
-        let max_transmit_size = 4 * 1024 * 1024;
+        // oversized frames are dropped before they are decoded
+        let max_transmit_size = config.gossip.max_message_size + RPC_OVERHEAD;
         // todo(botch): should we limit the number here?
         let max_msgs_per_rpc = 1;
         let cache_size = Duration::from_secs(60);
@@ -54,7 +203,7 @@ impl UrsaGossipsub {
             .max_transmit_size(max_transmit_size)
             .duplicate_cache_time(cache_size)
             .validate_messages()
-            .validation_mode(ValidationMode::Strict)
+            .validation_mode(config.gossip.validation_mode().expect("gossip validation"))
             .message_id_fn(message_id_fn)
             .allow_self_origin(true)
             .mesh_outbound_min(mesh_outbound_min)
@@ -62,8 +211,396 @@ impl UrsaGossipsub {
             .build()
             .expect("gossipsub config");
 
-        Gossipsub::new(MessageAuthenticity::Signed(keypair.clone()), gossip_config)
+        Gossipsub::new(config.gossip.message_authenticity(keypair), gossip_config)
             .map_err(|err| anyhow!("{}", err))
             .unwrap()
     }
 }
+
+/// Subscribers of every topic, kept from the subscription events of gossipsub.
+#[derive(Debug, Default)]
+pub struct TopicSubscriptions(FnvHashMap<TopicHash, HashSet<PeerId>>);
+
+impl TopicSubscriptions {
+    /// Returns whether `peer` was not subscribed to `topic` yet.
+    pub fn subscribe(&mut self, peer: PeerId, topic: TopicHash) -> bool {
+        self.0.entry(topic).or_default().insert(peer)
+    }
+
+    /// Returns whether `peer` was subscribed to `topic`.
+    pub fn unsubscribe(&mut self, peer: &PeerId, topic: &TopicHash) -> bool {
+        match self.0.get_mut(topic) {
+            Some(peers) => {
+                let removed = peers.remove(peer);
+                if peers.is_empty() {
+                    self.0.remove(topic);
+                }
+                removed
+            }
+            None => false,
+        }
+    }
+
+    /// Forget the subscriptions of a disconnected peer.
+    pub fn remove_peer(&mut self, peer: &PeerId) {
+        self.0.retain(|_, peers| {
+            peers.remove(peer);
+            !peers.is_empty()
+        });
+    }
+
+    pub fn peers(&self, topic: &TopicHash) -> impl Iterator<Item = &PeerId> {
+        self.0.get(topic).into_iter().flatten()
+    }
+
+    pub fn count(&self, topic: &TopicHash) -> usize {
+        self.0.get(topic).map_or(0, HashSet::len)
+    }
+
+    pub fn topics(&self) -> impl Iterator<Item = &TopicHash> {
+        self.0.keys()
+    }
+}
+
+struct QueuedPublish {
+    topic: Topic,
+    message: GossipsubMessage,
+    queued_at: Instant,
+    retry_at: Instant,
+    retries: u32,
+}
+
+/// Gossip messages waiting for the mesh of their topic to form, retried with a
+/// doubling backoff until they are published or too old.
+pub struct PublishQueue {
+    max_queued: usize,
+    max_age: Duration,
+    queue: VecDeque<QueuedPublish>,
+}
+
+impl PublishQueue {
+    pub fn new(config: &GossipConfig) -> Self {
+        Self {
+            max_queued: config.max_queued_publishes,
+            max_age: Duration::from_secs(config.max_publish_age_secs),
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// Queue a publish that failed for lack of peers, returns false when retries
+    /// are disabled.
+    pub fn push(&mut self, topic: Topic, message: GossipsubMessage) -> bool {
+        if self.max_queued == 0 {
+            return false;
+        }
+        if self.queue.len() >= self.max_queued {
+            if let Some(dropped) = self.queue.pop_front() {
+                warn!(
+                    "The publish queue is full, dropping a message to {}",
+                    dropped.topic
+                );
+            }
+        }
+        let now = Instant::now();
+        self.queue.push_back(QueuedPublish {
+            topic,
+            message,
+            queued_at: now,
+            retry_at: now + PUBLISH_RETRY,
+            retries: 0,
+        });
+        true
+    }
+
+    /// Retry the due publishes with `publish`, which returns whether it published the
+    /// message, and drop the expired ones. Returns the published and dropped counts.
+    pub fn retry<F>(&mut self, mut publish: F) -> (usize, usize)
+    where
+        F: FnMut(&Topic, &GossipsubMessage) -> bool,
+    {
+        self.retry_at(Instant::now(), &mut publish)
+    }
+
+    fn retry_at<F>(&mut self, now: Instant, publish: &mut F) -> (usize, usize)
+    where
+        F: FnMut(&Topic, &GossipsubMessage) -> bool,
+    {
+        let (mut published, mut dropped) = (0, 0);
+        let max_age = self.max_age;
+        self.queue.retain_mut(|queued| {
+            if now.duration_since(queued.queued_at) >= max_age {
+                dropped += 1;
+                return false;
+            }
+            if queued.retry_at > now {
+                return true;
+            }
+            if publish(&queued.topic, &queued.message) {
+                published += 1;
+                return false;
+            }
+            queued.retries += 1;
+            queued.retry_at = now + PUBLISH_RETRY * 2u32.pow(queued.retries.min(16));
+            true
+        });
+        (published, dropped)
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+/// Subscribers of a topic, as returned by `ursa_topic_peers`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TopicPeers {
+    pub topic: String,
+    /// Whether this node is subscribed to the topic.
+    pub subscribed: bool,
+    pub peers: Vec<String>,
+    pub mesh_peers: usize,
+    /// Whether a message published now reaches `min_publish_peers`.
+    pub publishable: bool,
+}
+
+/// Window over which gossip message rates are measured.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Per-topic gossipsub introspection, as returned by `ursa_gossip_stat`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GossipTopicStat {
+    pub topic: String,
+    /// Whether this node is subscribed to the topic.
+    pub subscribed: bool,
+    pub mesh_peers: Vec<String>,
+    /// Peers a publish to an unsubscribed topic fans out to, its most reputable subscribers.
+    pub fanout_peers: Vec<String>,
+    pub received: u64,
+    pub accepted: u64,
+    pub rejected: u64,
+    pub published: u64,
+    /// Messages received per second over the last minute.
+    pub receive_rate: f64,
+}
+
+/// Message counters of a single topic.
+#[derive(Debug, Default)]
+pub struct TopicCounters {
+    received: u64,
+    accepted: u64,
+    rejected: u64,
+    published: u64,
+    recent: VecDeque<Instant>,
+}
+
+impl TopicCounters {
+    pub fn record_received(&mut self, accepted: bool) {
+        self.record_received_at(accepted, Instant::now());
+    }
+
+    fn record_received_at(&mut self, accepted: bool, now: Instant) {
+        self.received += 1;
+        if accepted {
+            self.accepted += 1;
+        } else {
+            self.rejected += 1;
+        }
+        self.recent.push_back(now);
+        self.prune(now);
+    }
+
+    pub fn record_published(&mut self) {
+        self.published += 1;
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while let Some(first) = self.recent.front() {
+            if now.duration_since(*first) < RATE_WINDOW {
+                break;
+            }
+            self.recent.pop_front();
+        }
+    }
+
+    pub fn stat(
+        &mut self,
+        topic: &TopicHash,
+        subscribed: bool,
+        mesh_peers: Vec<PeerId>,
+        fanout_peers: Vec<PeerId>,
+    ) -> GossipTopicStat {
+        self.prune(Instant::now());
+
+        GossipTopicStat {
+            topic: topic.to_string(),
+            subscribed,
+            mesh_peers: mesh_peers.iter().map(PeerId::to_string).collect(),
+            fanout_peers: fanout_peers.iter().map(PeerId::to_string).collect(),
+            received: self.received,
+            accepted: self.accepted,
+            rejected: self.rejected,
+            published: self.published,
+            receive_rate: self.recent.len() as f64 / RATE_WINDOW.as_secs_f64(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topic_counters() {
+        let mut counters = TopicCounters::default();
+        let start = Instant::now();
+
+        counters.record_received_at(true, start);
+        counters.record_received_at(false, start);
+        counters.record_published();
+        assert_eq!(counters.recent.len(), 2);
+
+        // messages older than the window no longer count towards the rate
+        counters.record_received_at(true, start + RATE_WINDOW);
+        assert_eq!(counters.recent.len(), 1);
+
+        let stat = counters.stat(&TopicHash::from_raw("test"), true, vec![], vec![]);
+        assert_eq!(stat.received, 3);
+        assert_eq!(stat.accepted, 2);
+        assert_eq!(stat.rejected, 1);
+        assert_eq!(stat.published, 1);
+    }
+
+    #[test]
+    fn test_gossip_violation() {
+        let config = GossipConfig {
+            max_message_size: 4,
+            allowed_topics: vec!["/ursa/global".into()],
+            min_publish_peers: 1,
+            ..Default::default()
+        };
+        let message = |topic: &str, data: &[u8]| GossipsubMessage {
+            source: None,
+            data: data.to_vec(),
+            sequence_number: None,
+            topic: TopicHash::from_raw(topic),
+        };
+        assert_eq!(config.violation(&message("/ursa/global", b"ad")), None);
+        assert!(config
+            .violation(&message("/ursa/global", b"large"))
+            .is_some());
+        assert!(config.violation(&message("/spam", b"ad")).is_some());
+        assert!(GossipConfig::default().allows(&TopicHash::from_raw("/spam")));
+        assert!(!config.can_publish(0));
+        assert!(config.can_publish(1));
+    }
+
+    #[test]
+    fn test_validation_mode() {
+        let config = |authenticity, validation| GossipConfig {
+            authenticity,
+            validation,
+            ..Default::default()
+        };
+        assert_eq!(
+            GossipConfig::default().validation_mode().unwrap(),
+            ValidationMode::Strict
+        );
+        assert_eq!(
+            config(GossipAuthenticity::Anonymous, None)
+                .validation_mode()
+                .unwrap(),
+            ValidationMode::Anonymous
+        );
+        assert_eq!(
+            config(GossipAuthenticity::Author, Some(GossipValidation::None))
+                .validation_mode()
+                .unwrap(),
+            ValidationMode::None
+        );
+        assert!(
+            config(GossipAuthenticity::Author, Some(GossipValidation::Strict))
+                .validation_mode()
+                .is_err()
+        );
+        assert!(config(
+            GossipAuthenticity::Signed,
+            Some(GossipValidation::Anonymous)
+        )
+        .validation_mode()
+        .is_err());
+    }
+
+    #[test]
+    fn test_publish_queue() {
+        let config = GossipConfig {
+            max_queued_publishes: 2,
+            max_publish_age_secs: 10,
+            ..Default::default()
+        };
+        let topic = Topic::new("/ursa/global");
+        let message = GossipsubMessage {
+            source: None,
+            data: b"ad".to_vec(),
+            sequence_number: None,
+            topic: topic.hash(),
+        };
+        let mut queue = PublishQueue::new(&config);
+        for _ in 0..3 {
+            assert!(queue.push(topic.clone(), message.clone()));
+        }
+        assert_eq!(queue.len(), 2);
+
+        let start = Instant::now();
+        // nothing is due before the first backoff
+        assert_eq!(queue.retry_at(start, &mut |_, _| true), (0, 0));
+        // the mesh is not ready yet, the next retry waits twice as long
+        let due = start + PUBLISH_RETRY;
+        assert_eq!(queue.retry_at(due, &mut |_, _| false), (0, 0));
+        assert_eq!(
+            queue.retry_at(due + PUBLISH_RETRY, &mut |_, _| true),
+            (0, 0)
+        );
+        assert_eq!(
+            queue.retry_at(due + PUBLISH_RETRY * 2, &mut |_, _| true),
+            (2, 0)
+        );
+        assert!(queue.is_empty());
+
+        queue.push(topic.clone(), message.clone());
+        assert_eq!(
+            queue.retry_at(Instant::now() + Duration::from_secs(10), &mut |_, _| true),
+            (0, 1)
+        );
+
+        let mut disabled = PublishQueue::new(&GossipConfig {
+            max_queued_publishes: 0,
+            ..config
+        });
+        assert!(!disabled.push(topic, message));
+    }
+
+    #[test]
+    fn test_topic_subscriptions() {
+        let (first, second) = (PeerId::random(), PeerId::random());
+        let (global, names) = (
+            TopicHash::from_raw("/ursa/global"),
+            TopicHash::from_raw("/ursa/names"),
+        );
+        let mut subscriptions = TopicSubscriptions::default();
+        assert!(subscriptions.subscribe(first, global.clone()));
+        assert!(!subscriptions.subscribe(first, global.clone()));
+        subscriptions.subscribe(second, global.clone());
+        subscriptions.subscribe(first, names.clone());
+        assert_eq!(subscriptions.count(&global), 2);
+
+        assert!(subscriptions.unsubscribe(&second, &global));
+        assert!(!subscriptions.unsubscribe(&second, &names));
+        subscriptions.remove_peer(&first);
+        assert_eq!(subscriptions.count(&global), 0);
+        assert_eq!(subscriptions.topics().count(), 0);
+    }
+}
@@ -0,0 +1,190 @@
+//! Provider hints of freshly cached content.
+//!
+//! Once a node ingests or syncs a dag, it gossips a [`ContentAnnouncement`] with the
+//! root cid, the size of the dag and its addresses on the content topic. The other
+//! nodes keep the announcer as a provider hint of the root for `ttl_secs`, so looking
+//! the content up finds it without walking the DHT while its provider record is
+//! still spreading.
+
+use anyhow::{anyhow, Result};
+use cid::Cid;
+use fnv::FnvHashMap;
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// Topic content announcements are gossiped on.
+pub const URSA_CONTENT: &str = "/ursa/content";
+
+/// Providers kept per root cid, the latest announcers.
+const MAX_PROVIDERS: usize = 8;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct ContentHintsConfig {
+    /// Gossip an announcement of every dag the node ingests or syncs.
+    pub announce: bool,
+    /// Root cids hints are kept for, the least recently announced dropped past it.
+    /// 0 ignores announcements.
+    pub max_hints: usize,
+    /// Seconds a hint is used for after it was announced.
+    pub ttl_secs: u64,
+}
+
+impl Default for ContentHintsConfig {
+    fn default() -> Self {
+        Self {
+            announce: true,
+            max_hints: 10_000,
+            ttl_secs: 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContentAnnouncement {
+    pub cid: String,
+    /// Bytes of all blocks of the dag.
+    pub size: u64,
+    pub peer_id: String,
+    pub addrs: Vec<String>,
+}
+
+impl ContentAnnouncement {
+    /// The root, the provider and its addresses, skipping the addresses that do not
+    /// parse.
+    pub fn parse(&self) -> Result<(Cid, PeerId, Vec<Multiaddr>)> {
+        let cid = Cid::from_str(&self.cid)
+            .map_err(|err| anyhow!("Invalid announced cid {}: {}", self.cid, err))?;
+        let peer = PeerId::from_str(&self.peer_id)
+            .map_err(|err| anyhow!("Invalid announcing peer {}: {}", self.peer_id, err))?;
+        let addrs = self
+            .addrs
+            .iter()
+            .filter_map(|addr| addr.parse().ok())
+            .collect();
+        Ok((cid, peer, addrs))
+    }
+}
+
+struct Hint {
+    announced: Instant,
+    providers: Vec<(PeerId, Vec<Multiaddr>)>,
+}
+
+pub struct ProviderHints {
+    max_hints: usize,
+    ttl: Duration,
+    hints: FnvHashMap<Cid, Hint>,
+}
+
+impl ProviderHints {
+    pub fn new(config: &ContentHintsConfig) -> Self {
+        Self {
+            max_hints: config.max_hints,
+            ttl: Duration::from_secs(config.ttl_secs),
+            hints: Default::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_hints > 0
+    }
+
+    /// Record `peer` as a provider of `cid`.
+    pub fn insert(&mut self, cid: Cid, peer: PeerId, addrs: Vec<Multiaddr>) {
+        self.insert_at(cid, peer, addrs, Instant::now())
+    }
+
+    fn insert_at(&mut self, cid: Cid, peer: PeerId, addrs: Vec<Multiaddr>, now: Instant) {
+        if !self.is_enabled() {
+            return;
+        }
+        if !self.hints.contains_key(&cid) && self.hints.len() >= self.max_hints {
+            let ttl = self.ttl;
+            self.hints
+                .retain(|_, hint| now.duration_since(hint.announced) < ttl);
+            if self.hints.len() >= self.max_hints {
+                let oldest = self
+                    .hints
+                    .iter()
+                    .min_by_key(|(_, hint)| hint.announced)
+                    .map(|(cid, _)| *cid);
+                if let Some(oldest) = oldest {
+                    self.hints.remove(&oldest);
+                }
+            }
+        }
+        let hint = self.hints.entry(cid).or_insert(Hint {
+            announced: now,
+            providers: vec![],
+        });
+        hint.announced = now;
+        hint.providers.retain(|(provider, _)| *provider != peer);
+        hint.providers.push((peer, addrs));
+        if hint.providers.len() > MAX_PROVIDERS {
+            hint.providers.remove(0);
+        }
+    }
+
+    /// The providers announced for `cid` within the ttl, the latest last.
+    pub fn providers(&self, cid: &Cid) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        self.providers_at(cid, Instant::now())
+    }
+
+    fn providers_at(&self, cid: &Cid, now: Instant) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        self.hints
+            .get(cid)
+            .filter(|hint| now.duration_since(hint.announced) < self.ttl)
+            .map(|hint| hint.providers.clone())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_provider_hints() {
+        let cids: Vec<Cid> = [
+            "bafkreif2opfibjypwkjzzry3jbibcjqcjwnpoqpeiqw75eu3s3u3zbdszq",
+            "bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku",
+        ]
+        .iter()
+        .map(|cid| Cid::from_str(cid).unwrap())
+        .collect();
+        let peer = PeerId::random();
+        let addr: Multiaddr = "/ip4/1.2.3.4/tcp/6009".parse().unwrap();
+
+        let announcement = ContentAnnouncement {
+            cid: cids[0].to_string(),
+            size: 42,
+            peer_id: peer.to_string(),
+            addrs: vec![addr.to_string(), "not an address".to_string()],
+        };
+        let (cid, provider, addrs) = announcement.parse().unwrap();
+        assert_eq!((cid, provider, addrs.clone()), (cids[0], peer, vec![addr]));
+
+        let mut hints = ProviderHints::new(&ContentHintsConfig {
+            max_hints: 1,
+            ttl_secs: 10,
+            ..Default::default()
+        });
+        let start = Instant::now();
+        hints.insert_at(cid, peer, addrs.clone(), start);
+        hints.insert_at(cid, peer, addrs.clone(), start);
+        assert_eq!(hints.providers_at(&cid, start).len(), 1);
+        assert!(hints
+            .providers_at(&cid, start + Duration::from_secs(10))
+            .is_empty());
+
+        // the oldest root makes room
+        hints.insert_at(cids[1], peer, addrs, start + Duration::from_secs(1));
+        assert!(hints.providers_at(&cids[0], start).is_empty());
+        assert_eq!(hints.providers_at(&cids[1], start).len(), 1);
+    }
+}
@@ -0,0 +1,94 @@
+//! Ursa node info.
+//!
+//! A [`NodeInfo`] is a snapshot of how the node presents itself to the network, put
+//! together from the swarm listeners and the Identify, AutoNAT and relay state kept
+//! by the behaviour. It is returned by `ursa_node_info`.
+
+use libp2p::{autonat::NatStatus, multiaddr::Protocol, Multiaddr};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeInfo {
+    pub peer_id: String,
+    pub agent_version: String,
+    pub listen_addrs: Vec<String>,
+    /// Addresses remote peers reported seeing this node on.
+    pub observed_addrs: Vec<String>,
+    pub nat: NatInfo,
+    pub relay: RelayInfo,
+    /// Protocols the node answers on.
+    pub protocols: Vec<String>,
+    /// Optional behaviours enabled in the network config.
+    pub features: Vec<String>,
+}
+
+/// NAT status as determined by AutoNAT.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum NatInfo {
+    /// AutoNAT is turned off.
+    Disabled,
+    Unknown,
+    Private,
+    Public {
+        address: String,
+    },
+}
+
+impl From<NatStatus> for NatInfo {
+    fn from(status: NatStatus) -> Self {
+        match status {
+            NatStatus::Unknown => Self::Unknown,
+            NatStatus::Private => Self::Private,
+            NatStatus::Public(address) => Self::Public {
+                address: address.to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayInfo {
+    /// Whether the node relays traffic for other peers.
+    pub server: bool,
+    /// Whether the node may listen through a relay when it is behind a NAT.
+    pub client: bool,
+    /// Peers holding a reservation on this node's relay.
+    pub reservations: usize,
+    /// Circuits currently relayed by this node.
+    pub circuits: usize,
+    /// Relay this node listens through behind a NAT.
+    pub relay: Option<String>,
+    /// Listen addresses that go through a relay.
+    pub relayed_addrs: Vec<String>,
+}
+
+impl RelayInfo {
+    pub(crate) fn is_relayed(addr: &Multiaddr) -> bool {
+        addr.iter()
+            .any(|protocol| matches!(protocol, Protocol::P2pCircuit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nat_info() {
+        let addr: Multiaddr = "/ip4/1.2.3.4/tcp/4890".parse().unwrap();
+        let nat = NatInfo::from(NatStatus::Public(addr));
+        assert_eq!(
+            serde_json::to_value(&nat).unwrap(),
+            serde_json::json!({ "status": "public", "address": "/ip4/1.2.3.4/tcp/4890" })
+        );
+
+        let relayed: Multiaddr = "/ip4/1.2.3.4/tcp/4890/p2p/12D3KooWH2ZRwu6DpZpRbUWu4pcFZB3sFUSNqaBtZyM2XyQCiLJ7/p2p-circuit"
+            .parse()
+            .unwrap();
+        assert!(RelayInfo::is_relayed(&relayed));
+        assert!(!RelayInfo::is_relayed(
+            &"/ip4/1.2.3.4/tcp/4890".parse().unwrap()
+        ));
+    }
+}
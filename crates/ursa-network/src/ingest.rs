@@ -0,0 +1,323 @@
+//! Cache of the providers announced to the indexers.
+//!
+//! Index providers gossip the cid of every new advertisement on the ingest topic,
+//! along with the address of the http server it is published on. With an `ingest`
+//! table the node follows the topic, fetches each announced advertisement and up to
+//! `max_chunks` of its entry chunks, and keeps the provider of every multihash listed
+//! for `ttl_secs`. `get` and `sync` ask the cached provider first, before routing the
+//! query over the connected peers. Only advertisements announced while the node is
+//! running are fetched, not the chains announced before.
+
+use anyhow::{anyhow, Result};
+use async_std::future;
+use cid::Cid;
+use fnv::FnvHashMap;
+use forest_ipld::Ipld;
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+use ursa_index_provider::{
+    advertisement::{Advertisement, EntryChunk},
+    provider::Message,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct IngestConfig {
+    /// Multihashes providers are kept for, the earliest announced dropped past it.
+    pub max_entries: usize,
+    /// Seconds a provider is used for after it was announced.
+    pub ttl_secs: u64,
+    /// Entry chunks fetched per advertisement.
+    pub max_chunks: usize,
+    /// Seconds the fetch of an advertisement and its entries may take.
+    pub timeout_secs: u64,
+}
+
+impl Default for IngestConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 100_000,
+            ttl_secs: 60 * 60,
+            max_chunks: 16,
+            timeout_secs: 30,
+        }
+    }
+}
+
+/// An announcement of the ingest topic, with the http server to fetch it from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IngestAnnouncement {
+    pub ad: Cid,
+    pub provider: PeerId,
+    pub url: String,
+}
+
+impl IngestAnnouncement {
+    /// Decode a gossiped announcement, `None` when none of its addresses is an http
+    /// server of the provider.
+    pub fn parse(data: &[u8]) -> Result<Option<Self>> {
+        let message = Message::unmarshal_cbor(data)?;
+        Ok(message
+            .Addrs
+            .iter()
+            .find_map(http_url)
+            .map(|(provider, url)| Self {
+                ad: message.Cid,
+                provider,
+                url,
+            }))
+    }
+}
+
+/// The provider and the url of an `/http/p2p/<peer id>` address.
+fn http_url(addr: &Multiaddr) -> Option<(PeerId, String)> {
+    let (mut host, mut port, mut scheme, mut peer) = (None, None, None, None);
+    for protocol in addr.iter() {
+        match protocol {
+            Protocol::Ip4(ip) => host = Some(ip.to_string()),
+            Protocol::Ip6(ip) => host = Some(format!("[{ip}]")),
+            Protocol::Dns(name) | Protocol::Dns4(name) | Protocol::Dns6(name) => {
+                host = Some(name.to_string())
+            }
+            Protocol::Tcp(tcp) => port = Some(tcp),
+            Protocol::Http => scheme = Some("http"),
+            Protocol::Https => scheme = Some("https"),
+            Protocol::P2p(hash) => peer = PeerId::from_multihash(hash).ok(),
+            _ => {}
+        }
+    }
+    let url = match (scheme?, host?, port) {
+        (scheme, host, Some(port)) => format!("{scheme}://{host}:{port}"),
+        (scheme, host, None) => format!("{scheme}://{host}"),
+    };
+    Some((peer?, url))
+}
+
+/// What an advertisement provides, once fetched.
+#[derive(Debug, Clone)]
+pub struct Ingested {
+    pub provider: PeerId,
+    /// Addresses of the advertisement, the content is retrieved from.
+    pub addrs: Vec<Multiaddr>,
+    /// Multihashes of the fetched entry chunks.
+    pub entries: Vec<Vec<u8>>,
+}
+
+async fn fetch_block(url: &str, cid: &Cid) -> Result<Vec<u8>> {
+    surf::get(format!("{}/{}", url.trim_end_matches('/'), cid))
+        .recv_bytes()
+        .await
+        .map_err(|e| anyhow!(e.to_string()))
+}
+
+/// Fetch the advertisement of `announcement` and its first entry chunks, `None` for
+/// removals.
+pub async fn fetch(
+    announcement: IngestAnnouncement,
+    config: &IngestConfig,
+) -> Result<Option<Ingested>> {
+    future::timeout(
+        Duration::from_secs(config.timeout_secs),
+        fetch_advertisement(announcement, config.max_chunks),
+    )
+    .await
+    .map_err(|_| anyhow!("fetching the advertisement timed out"))?
+}
+
+async fn fetch_advertisement(
+    announcement: IngestAnnouncement,
+    max_chunks: usize,
+) -> Result<Option<Ingested>> {
+    let bytes = fetch_block(&announcement.url, &announcement.ad).await?;
+    let ad: Advertisement = forest_encoding::from_slice(&bytes)?;
+    if ad.Provider != announcement.provider.to_string() {
+        return Err(anyhow!(
+            "advertisement {} of {} is signed for {}",
+            announcement.ad,
+            announcement.provider,
+            ad.Provider
+        ));
+    }
+    if ad.IsRm {
+        return Ok(None);
+    }
+
+    let mut entries = vec![];
+    let mut next = match &ad.Entries {
+        Some(Ipld::Link(link)) => Some(*link),
+        _ => None,
+    };
+    for _ in 0..max_chunks {
+        let cid = match next {
+            Some(cid) => cid,
+            None => break,
+        };
+        let bytes = fetch_block(&announcement.url, &cid).await?;
+        let chunk: EntryChunk = forest_encoding::from_slice(&bytes)?;
+        for entry in chunk.entries() {
+            if let Ipld::Bytes(multihash) = entry {
+                entries.push(multihash.clone());
+            }
+        }
+        next = chunk.next();
+    }
+
+    Ok(Some(Ingested {
+        provider: announcement.provider,
+        addrs: ad
+            .Addresses
+            .iter()
+            .filter_map(|addr| addr.parse().ok())
+            .collect(),
+        entries,
+    }))
+}
+
+pub struct IngestCache {
+    config: IngestConfig,
+    /// Provider of every multihash and when it was announced.
+    entries: FnvHashMap<Vec<u8>, (PeerId, Instant)>,
+    /// Multihashes in the order they were announced in, for the eviction.
+    order: VecDeque<(Vec<u8>, Instant)>,
+    /// Latest addresses of every provider.
+    addrs: FnvHashMap<PeerId, Vec<Multiaddr>>,
+    /// Latest advertisement announced by every provider.
+    heads: FnvHashMap<PeerId, Cid>,
+}
+
+impl IngestCache {
+    pub fn new(config: &IngestConfig) -> Self {
+        Self {
+            config: config.clone(),
+            entries: Default::default(),
+            order: Default::default(),
+            addrs: Default::default(),
+            heads: Default::default(),
+        }
+    }
+
+    pub fn config(&self) -> &IngestConfig {
+        &self.config
+    }
+
+    /// Record `ad` as the head of `provider`, returns whether it was not seen before.
+    pub fn announced(&mut self, provider: PeerId, ad: Cid) -> bool {
+        self.heads.insert(provider, ad) != Some(ad)
+    }
+
+    pub fn insert(&mut self, ingested: Ingested) {
+        self.insert_at(ingested, Instant::now())
+    }
+
+    fn insert_at(&mut self, ingested: Ingested, now: Instant) {
+        if !ingested.addrs.is_empty() {
+            self.addrs.insert(ingested.provider, ingested.addrs);
+        }
+        for multihash in ingested.entries {
+            self.entries
+                .insert(multihash.clone(), (ingested.provider, now));
+            self.order.push_back((multihash, now));
+        }
+        while self.order.len() > self.config.max_entries {
+            if let Some((multihash, at)) = self.order.pop_front() {
+                // a multihash announced again later stays
+                if matches!(self.entries.get(&multihash), Some((_, announced)) if *announced == at)
+                {
+                    self.entries.remove(&multihash);
+                }
+            }
+        }
+    }
+
+    /// The provider announced for `cid` within the ttl, and its addresses.
+    pub fn provider(&self, cid: &Cid) -> Option<(PeerId, Vec<Multiaddr>)> {
+        self.provider_at(cid, Instant::now())
+    }
+
+    fn provider_at(&self, cid: &Cid, now: Instant) -> Option<(PeerId, Vec<Multiaddr>)> {
+        self.entries
+            .get(&cid.hash().to_bytes())
+            .filter(|(_, announced)| {
+                now.duration_since(*announced) < Duration::from_secs(self.config.ttl_secs)
+            })
+            .map(|(peer, _)| (*peer, self.addrs.get(peer).cloned().unwrap_or_default()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_http_url() {
+        let peer = PeerId::random();
+        let addr = Multiaddr::from_str(&format!("/ip4/1.2.3.4/tcp/8070/http/p2p/{peer}")).unwrap();
+        assert_eq!(
+            http_url(&addr),
+            Some((peer, "http://1.2.3.4:8070".to_string()))
+        );
+        let addr =
+            Multiaddr::from_str(&format!("/dns4/provider.ursa.earth/https/p2p/{peer}")).unwrap();
+        assert_eq!(
+            http_url(&addr),
+            Some((peer, "https://provider.ursa.earth".to_string()))
+        );
+        let addr = Multiaddr::from_str(&format!("/ip4/1.2.3.4/tcp/6009/p2p/{peer}")).unwrap();
+        assert_eq!(http_url(&addr), None);
+    }
+
+    #[test]
+    fn test_ingest_cache() {
+        let cids: Vec<Cid> = [
+            "bafkreif2opfibjypwkjzzry3jbibcjqcjwnpoqpeiqw75eu3s3u3zbdszq",
+            "bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku",
+        ]
+        .iter()
+        .map(|cid| Cid::from_str(cid).unwrap())
+        .collect();
+        let peer = PeerId::random();
+        let addr: Multiaddr = "/ip4/1.2.3.4/tcp/6009".parse().unwrap();
+        let ingested = |cid: &Cid| Ingested {
+            provider: peer,
+            addrs: vec![addr.clone()],
+            entries: vec![cid.hash().to_bytes()],
+        };
+
+        let mut cache = IngestCache::new(&IngestConfig {
+            max_entries: 1,
+            ttl_secs: 10,
+            ..Default::default()
+        });
+        assert!(cache.announced(peer, cids[0]));
+        assert!(!cache.announced(peer, cids[0]));
+
+        let start = Instant::now();
+        cache.insert_at(ingested(&cids[0]), start);
+        assert_eq!(
+            cache.provider_at(&cids[0], start),
+            Some((peer, vec![addr.clone()]))
+        );
+        assert!(cache
+            .provider_at(&cids[0], start + Duration::from_secs(10))
+            .is_none());
+
+        // the earliest multihash makes room
+        cache.insert_at(ingested(&cids[1]), start);
+        assert_eq!(cache.len(), 1);
+        assert!(cache.provider_at(&cids[0], start).is_none());
+        assert!(cache.provider_at(&cids[1], start).is_some());
+    }
+}
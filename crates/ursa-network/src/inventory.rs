@@ -0,0 +1,100 @@
+//! Agent and protocol inventory of the connected peers.
+//!
+//! Identify tells the agent and the protocols of every connected peer. The counts
+//! returned by `ursa_peer_protocols` show how much of the network already speaks a
+//! protocol, e.g. a new exchange protocol version, before a feature relying on it is
+//! turned on fleet-wide.
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::compat::AgentVersion;
+
+/// What a peer reported about itself in identify.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerIdentity {
+    pub agent: String,
+    pub version: AgentVersion,
+    pub protocols: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerProtocols {
+    pub peer_id: String,
+    pub agent_version: String,
+    pub protocols: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProtocolInventory {
+    /// Connected peers that were identified.
+    pub identified: usize,
+    /// Peers per agent version string.
+    pub agents: BTreeMap<String, usize>,
+    /// Peers per protocol.
+    pub protocols: BTreeMap<String, usize>,
+    /// The peers, only those speaking the requested protocol when one was given.
+    pub peers: Vec<PeerProtocols>,
+}
+
+impl ProtocolInventory {
+    pub fn new(identities: &HashMap<PeerId, PeerIdentity>, protocol: Option<&str>) -> Self {
+        let mut inventory = Self {
+            identified: identities.len(),
+            ..Default::default()
+        };
+        for (peer, identity) in identities {
+            *inventory.agents.entry(identity.agent.clone()).or_default() += 1;
+            for name in &identity.protocols {
+                *inventory.protocols.entry(name.clone()).or_default() += 1;
+            }
+            if protocol.map_or(true, |p| identity.protocols.iter().any(|name| name == p)) {
+                inventory.peers.push(PeerProtocols {
+                    peer_id: peer.to_string(),
+                    agent_version: identity.agent.clone(),
+                    protocols: identity.protocols.clone(),
+                });
+            }
+        }
+        inventory.peers.sort_by(|a, b| a.peer_id.cmp(&b.peer_id));
+        inventory
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inventory() {
+        let identity = |agent: &str, protocols: &[&str]| PeerIdentity {
+            agent: agent.to_string(),
+            version: AgentVersion::from_agent(agent),
+            protocols: protocols.iter().map(|p| p.to_string()).collect(),
+        };
+        let identities = HashMap::from([
+            (
+                PeerId::random(),
+                identity("ursa/0.1.0", &["/ursa/kad/0.0.1", "/graphsync/1.0.0"]),
+            ),
+            (
+                PeerId::random(),
+                identity("ursa/0.1.0", &["/ursa/kad/0.0.1"]),
+            ),
+            (
+                PeerId::random(),
+                identity("go-ipfs/0.16.0", &["/ipfs/kad/1.0.0"]),
+            ),
+        ]);
+
+        let inventory = ProtocolInventory::new(&identities, Some("/graphsync/1.0.0"));
+        assert_eq!(inventory.identified, 3);
+        assert_eq!(inventory.agents["ursa/0.1.0"], 2);
+        assert_eq!(inventory.protocols["/ursa/kad/0.0.1"], 2);
+        assert_eq!(inventory.protocols["/graphsync/1.0.0"], 1);
+        assert_eq!(inventory.peers.len(), 1);
+
+        assert_eq!(ProtocolInventory::new(&identities, None).peers.len(), 3);
+    }
+}
@@ -1,10 +1,38 @@
+pub mod accounting;
+pub mod acl;
+pub mod autorelay;
 mod behaviour;
+pub mod cache_summary;
 mod codec;
+pub mod compat;
 pub mod config;
+pub mod dag_sync;
+pub mod dial;
 mod discovery;
-mod gossipsub;
+pub mod events;
+pub mod faults;
+pub mod gossipsub;
+pub mod hints;
+pub mod info;
+pub mod ingest;
+pub mod inventory;
+pub mod names;
+pub mod peer_tags;
+pub mod proxy;
+pub mod purge;
+pub mod quota;
+pub mod registry;
+pub mod relay;
+pub mod replication;
+pub mod reputation;
+pub mod senders;
 pub mod service;
+pub mod shaping;
+#[cfg(test)]
+mod testing;
 mod transport;
+pub mod worker;
 
+pub use self::codec::protocol::ContentProvider;
 pub use self::config::*;
 pub use self::service::*;
@@ -0,0 +1,263 @@
+//! Mutable names.
+//!
+//! A name is the peer id of the node publishing it, and points at a root cid through
+//! a signed [`NameRecord`]. Every publish bumps the sequence of the record, so
+//! publishers can move a name to new content without changing the links handed out.
+//! Records are put in the DHT and gossiped on [`URSA_NAMES`], resolving a name takes
+//! the valid record with the highest sequence seen through either.
+
+use anyhow::{anyhow, Result};
+use cid::Cid;
+use fnv::FnvHashMap;
+use ipld_blockstore::BlockStore;
+use libp2p::{
+    identity::{Keypair, PublicKey},
+    kad::record::Key,
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use ursa_store::{columns::Column, Store};
+use ursa_utils::{hex, unhex, unix_now};
+
+/// Topic name records are gossiped on.
+pub const URSA_NAMES: &str = "/ursa/names";
+
+/// Database key of the record last published by this node.
+const PUBLISHED_KEY: &str = "published_name";
+
+/// Seconds a published record stays valid unless asked otherwise.
+pub const DEFAULT_NAME_TTL_SECS: u64 = 48 * 60 * 60;
+
+/// Names whose records are kept, the least recently used one is dropped past it.
+const MAX_NAMES: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NameRecord {
+    /// Peer id of the publisher.
+    pub name: String,
+    /// Root cid the name points at.
+    pub cid: String,
+    /// Incremented with every publish of the name.
+    pub sequence: u64,
+    /// Unix time in seconds the record expires at.
+    pub expires_at: u64,
+    /// Hex of the protobuf encoded public key of the publisher.
+    pub public_key: String,
+    /// Hex of the signature of the publisher over the other fields.
+    pub signature: String,
+}
+
+impl NameRecord {
+    /// Sign a record pointing the name of `keypair` at `cid` for `ttl_secs`.
+    pub fn sign(keypair: &Keypair, cid: &Cid, sequence: u64, ttl_secs: u64) -> Result<Self> {
+        let public_key = keypair.public();
+        let mut record = Self {
+            name: PeerId::from(public_key.clone()).to_string(),
+            cid: cid.to_string(),
+            sequence,
+            expires_at: unix_now()
+                .checked_add(ttl_secs)
+                .ok_or_else(|| anyhow!("A ttl of {ttl_secs} seconds is too long"))?,
+            public_key: hex(&public_key.to_protobuf_encoding()),
+            signature: String::new(),
+        };
+        record.signature = hex(&keypair.sign(&record.signed_bytes())?);
+        Ok(record)
+    }
+
+    /// Check the signature, that the key belongs to the name, and that the record
+    /// has not expired.
+    pub fn verify(&self) -> Result<()> {
+        let public_key = unhex(&self.public_key)
+            .and_then(|key| PublicKey::from_protobuf_encoding(&key).ok())
+            .ok_or_else(|| anyhow!("The public key of {} does not decode", self.name))?;
+        if PeerId::from(public_key.clone()).to_string() != self.name {
+            return Err(anyhow!(
+                "The record of {} is signed by another key",
+                self.name
+            ));
+        }
+        Cid::from_str(&self.cid)?;
+        let signed = unhex(&self.signature)
+            .map_or(false, |sig| public_key.verify(&self.signed_bytes(), &sig));
+        if !signed {
+            return Err(anyhow!(
+                "The record of {} has an invalid signature",
+                self.name
+            ));
+        }
+        if self.expires_at <= unix_now() {
+            return Err(anyhow!("The record of {} expired", self.name));
+        }
+        Ok(())
+    }
+
+    pub fn root(&self) -> Result<Cid> {
+        Ok(Cid::from_str(&self.cid)?)
+    }
+
+    /// Whether this record supersedes `other`.
+    pub fn is_newer(&self, other: &NameRecord) -> bool {
+        (self.sequence, self.expires_at) > (other.sequence, other.expires_at)
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        format!(
+            "ursa-name:{}:{}:{}:{}",
+            self.name, self.cid, self.sequence, self.expires_at
+        )
+        .into_bytes()
+    }
+}
+
+/// DHT key of the record of `name`.
+pub fn record_key(name: &str) -> Key {
+    Key::new(&format!("/ursa/name/{name}"))
+}
+
+/// Valid records seen for each name, the newest one kept. Holds the records of up to
+/// [`MAX_NAMES`] names, dropping the least recently inserted or resolved one.
+#[derive(Debug, Default)]
+pub struct NameCache {
+    /// Records with the tick they were last used at.
+    records: FnvHashMap<String, (NameRecord, u64)>,
+    tick: u64,
+}
+
+impl NameCache {
+    /// Keep `record` when it is valid and newer than the one known, returning whether
+    /// it was kept.
+    pub fn insert(&mut self, record: NameRecord) -> bool {
+        if record.verify().is_err() {
+            return false;
+        }
+        match self.records.get(&record.name) {
+            Some((known, _)) if !record.is_newer(known) => false,
+            known => {
+                if known.is_none() && self.records.len() >= MAX_NAMES {
+                    self.evict();
+                }
+                self.tick += 1;
+                self.records
+                    .insert(record.name.clone(), (record, self.tick));
+                true
+            }
+        }
+    }
+
+    /// Newest record of `name` that has not expired yet.
+    pub fn get(&mut self, name: &str) -> Option<&NameRecord> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.records
+            .get_mut(name)
+            .filter(|(record, _)| record.expires_at > unix_now())
+            .map(|(record, used)| {
+                *used = tick;
+                &*record
+            })
+    }
+
+    /// Drop the expired records, or the least recently used one when none expired.
+    fn evict(&mut self) {
+        let now = unix_now();
+        self.records
+            .retain(|_, (record, _)| record.expires_at > now);
+        if self.records.len() < MAX_NAMES {
+            return;
+        }
+        let oldest = self
+            .records
+            .iter()
+            .min_by_key(|(_, (_, used))| *used)
+            .map(|(name, _)| name.clone());
+        if let Some(oldest) = oldest {
+            self.records.remove(&oldest);
+        }
+    }
+}
+
+/// Record last published by this node, to continue its sequence after a restart.
+pub fn load_published<S: BlockStore + Sync + Send + 'static>(
+    store: &Store<S>,
+) -> Result<Option<NameRecord>> {
+    match store.column(Column::Metadata).read(PUBLISHED_KEY)? {
+        Some(record) => Ok(Some(serde_json::from_slice(&record)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn save_published<S: BlockStore + Sync + Send + 'static>(
+    store: &Store<S>,
+    record: &NameRecord,
+) -> Result<()> {
+    store
+        .column(Column::Metadata)
+        .write(PUBLISHED_KEY, serde_json::to_vec(record)?)?;
+    Ok(())
+}
+
+/// Newest valid record of `name` among the encoded `values`.
+pub fn newest(name: &str, values: &[Vec<u8>]) -> Option<NameRecord> {
+    values
+        .iter()
+        .filter_map(|value| serde_json::from_slice::<NameRecord>(value).ok())
+        .filter(|record| record.name == name && record.verify().is_ok())
+        .reduce(|a, b| if b.is_newer(&a) { b } else { a })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_name_records() {
+        let keypair = Keypair::generate_ed25519();
+        let first =
+            Cid::from_str("bafkreif2opfibjypwkjzzry3jbibcjqcjwnpoqpeiqw75eu3s3u3zbdszq").unwrap();
+        let second =
+            Cid::from_str("bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku").unwrap();
+
+        let old = NameRecord::sign(&keypair, &first, 1, 60).unwrap();
+        let new = NameRecord::sign(&keypair, &second, 2, 60).unwrap();
+        assert!(old.verify().is_ok());
+        assert_eq!(old.name, PeerId::from(keypair.public()).to_string());
+
+        let mut forged = new.clone();
+        forged.cid = first.to_string();
+        assert!(forged.verify().is_err());
+
+        let mut cache = NameCache::default();
+        assert!(cache.insert(new.clone()));
+        // an older sequence does not replace the newer record
+        assert!(!cache.insert(old.clone()));
+        assert_eq!(cache.get(&new.name).unwrap().cid, second.to_string());
+        assert!(NameRecord::sign(&keypair, &first, 3, u64::MAX).is_err());
+
+        let values: Vec<Vec<u8>> = [&old, &forged, &new]
+            .iter()
+            .map(|record| serde_json::to_vec(record).unwrap())
+            .collect();
+        assert_eq!(newest(&new.name, &values), Some(new));
+    }
+
+    #[test]
+    fn test_name_cache_eviction() {
+        let cid =
+            Cid::from_str("bafkreif2opfibjypwkjzzry3jbibcjqcjwnpoqpeiqw75eu3s3u3zbdszq").unwrap();
+        let mut cache = NameCache::default();
+        let records: Vec<NameRecord> = (0..=MAX_NAMES)
+            .map(|_| NameRecord::sign(&Keypair::generate_ed25519(), &cid, 1, 60).unwrap())
+            .collect();
+        for record in &records[..MAX_NAMES] {
+            assert!(cache.insert(record.clone()));
+        }
+        // the first name was used last, the second one makes room
+        assert!(cache.get(&records[0].name).is_some());
+        assert!(cache.insert(records[MAX_NAMES].clone()));
+        assert_eq!(cache.records.len(), MAX_NAMES);
+        assert!(cache.get(&records[0].name).is_some());
+        assert!(cache.get(&records[1].name).is_none());
+    }
+}
@@ -0,0 +1,234 @@
+//! Ursa peer tags.
+//!
+//! Peers are tagged with the role they play for the node: the bootstrap nodes, the
+//! relays it holds reservations on, the providers that served it blocks, and the
+//! clients that asked it for content. Once more peers are connected than the
+//! configured high water mark, the node disconnects peers down to the low water
+//! mark. Untagged peers go first, then tagged ones, the least reputable first
+//! within each group, and peers with a protected tag are never disconnected. Peers
+//! that do not speak gossipsub are tagged `no_gossip` and go before the untagged ones,
+//! or are disconnected right away once the node is at the high water mark.
+//!
+//! Connections without traffic for `idle_timeout_secs` are closed as well, to free
+//! their file descriptors, except to protected peers, gossip mesh peers and peers
+//! with queries in flight.
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerTag {
+    Bootstrap,
+    Relay,
+    Provider,
+    Client,
+    /// Peers without gossipsub, which cannot join the mesh.
+    NoGossip,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct ConnectionConfig {
+    /// Connected peers above which peers are disconnected. 0 disables pruning.
+    pub high_water: usize,
+    /// Connected peers kept once pruning starts.
+    pub low_water: usize,
+    /// Tags of the peers that are never disconnected to make room.
+    pub protected: Vec<PeerTag>,
+    /// Seconds without traffic after which a connection is closed. 0 keeps idle
+    /// connections open.
+    pub idle_timeout_secs: u64,
+    /// Whether a peer without gossipsub is disconnected as soon as it connects to a
+    /// node at the high water mark.
+    pub disconnect_no_gossip: bool,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            high_water: 400,
+            low_water: 300,
+            protected: vec![PeerTag::Bootstrap, PeerTag::Relay],
+            idle_timeout_secs: 120,
+            disconnect_no_gossip: true,
+        }
+    }
+}
+
+impl ConnectionConfig {
+    /// Whether `connected` peers reach the high water mark.
+    pub fn is_full(&self, connected: usize) -> bool {
+        self.high_water != 0 && connected >= self.high_water
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PeerTags {
+    tags: HashMap<PeerId, HashSet<PeerTag>>,
+}
+
+impl PeerTags {
+    pub fn tag(&mut self, peer: PeerId, tag: PeerTag) {
+        self.tags.entry(peer).or_default().insert(tag);
+    }
+
+    pub fn untag(&mut self, peer: &PeerId, tag: PeerTag) {
+        if let Some(tags) = self.tags.get_mut(peer) {
+            tags.remove(&tag);
+            if tags.is_empty() {
+                self.tags.remove(peer);
+            }
+        }
+    }
+
+    pub fn tags(&self, peer: &PeerId) -> Vec<PeerTag> {
+        self.tags
+            .get(peer)
+            .map(|tags| tags.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn has_tag(&self, peer: &PeerId, tag: PeerTag) -> bool {
+        self.tags
+            .get(peer)
+            .map_or(false, |tags| tags.contains(&tag))
+    }
+
+    pub fn is_protected(&self, peer: &PeerId, config: &ConnectionConfig) -> bool {
+        self.tags.get(peer).map_or(false, |tags| {
+            config.protected.iter().any(|tag| tags.contains(tag))
+        })
+    }
+
+    /// Peers of `connected` to disconnect to get back under the high water mark,
+    /// ranked by `score` within the peers only tagged `no_gossip`, the untagged and
+    /// then the tagged peers.
+    pub fn prune<'a, F>(
+        &self,
+        connected: impl IntoIterator<Item = &'a PeerId>,
+        config: &ConnectionConfig,
+        score: F,
+    ) -> Vec<PeerId>
+    where
+        F: Fn(&PeerId) -> f64,
+    {
+        let connected: Vec<&PeerId> = connected.into_iter().collect();
+        if config.high_water == 0 || connected.len() <= config.high_water {
+            return vec![];
+        }
+        let excess = connected.len() - config.low_water.min(config.high_water);
+
+        let mut candidates: Vec<(u8, f64, PeerId)> = connected
+            .into_iter()
+            .filter(|peer| !self.is_protected(peer, config))
+            .map(|peer| {
+                let group = match self.tags.get(peer) {
+                    Some(tags) if tags.iter().all(|tag| *tag == PeerTag::NoGossip) => 0,
+                    None => 1,
+                    Some(_) => 2,
+                };
+                (group, score(peer), *peer)
+            })
+            .collect();
+        candidates.sort_by(|a, b| {
+            a.0.cmp(&b.0)
+                .then(a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+        });
+        candidates
+            .into_iter()
+            .take(excess)
+            .map(|(_, _, peer)| peer)
+            .collect()
+    }
+
+    /// Peers of `last_active` without traffic for the idle timeout, leaving out the
+    /// protected peers and the `busy` ones.
+    pub fn idle(
+        &self,
+        last_active: &HashMap<PeerId, Instant>,
+        busy: &HashSet<PeerId>,
+        config: &ConnectionConfig,
+    ) -> Vec<PeerId> {
+        if config.idle_timeout_secs == 0 {
+            return vec![];
+        }
+        let timeout = Duration::from_secs(config.idle_timeout_secs);
+        last_active
+            .iter()
+            .filter(|(peer, active)| {
+                active.elapsed() >= timeout
+                    && !busy.contains(peer)
+                    && !self.is_protected(peer, config)
+            })
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prune() {
+        let peers: Vec<PeerId> = (0..5).map(|_| PeerId::random()).collect();
+        let mut tags = PeerTags::default();
+        tags.tag(peers[0], PeerTag::Bootstrap);
+        tags.tag(peers[1], PeerTag::Provider);
+        tags.tag(peers[2], PeerTag::Client);
+        tags.untag(&peers[2], PeerTag::Client);
+        assert!(tags.tags(&peers[2]).is_empty());
+
+        let config = ConnectionConfig {
+            high_water: 4,
+            low_water: 2,
+            ..Default::default()
+        };
+        // untagged peers go first, the lowest score first
+        let score = |peer: &PeerId| if *peer == peers[3] { 0.1 } else { 0.9 };
+        let pruned = tags.prune(&peers, &config, score);
+        assert_eq!(pruned.len(), 3);
+        assert_eq!(pruned[0], peers[3]);
+        assert!(!pruned.contains(&peers[0]));
+        assert!(!pruned.contains(&peers[1]));
+
+        assert!(tags.prune(&peers[..4], &config, score).is_empty());
+
+        // peers without gossipsub go before the untagged ones
+        tags.tag(peers[4], PeerTag::NoGossip);
+        assert!(tags.has_tag(&peers[4], PeerTag::NoGossip));
+        assert_eq!(tags.prune(&peers, &config, score)[0], peers[4]);
+        assert!(config.is_full(4));
+        assert!(!config.is_full(3));
+    }
+
+    #[test]
+    fn test_idle() {
+        let peers: Vec<PeerId> = (0..4).map(|_| PeerId::random()).collect();
+        let mut tags = PeerTags::default();
+        tags.tag(peers[0], PeerTag::Relay);
+
+        let config = ConnectionConfig {
+            idle_timeout_secs: 60,
+            ..Default::default()
+        };
+        let long_ago = Instant::now() - Duration::from_secs(61);
+        let mut last_active: HashMap<PeerId, Instant> =
+            peers.iter().map(|peer| (*peer, long_ago)).collect();
+        last_active.insert(peers[3], Instant::now());
+        let busy = HashSet::from([peers[1]]);
+
+        assert_eq!(tags.idle(&last_active, &busy, &config), vec![peers[2]]);
+
+        let keep_alive = ConnectionConfig {
+            idle_timeout_secs: 0,
+            ..config
+        };
+        assert!(tags.idle(&last_active, &busy, &keep_alive).is_empty());
+    }
+}
@@ -0,0 +1,363 @@
+//! SOCKS5 proxy for outbound dials.
+//!
+//! With [`ProxyConfig`] set, every tcp dial is made through the proxy instead of
+//! directly, for nodes in networks that only reach out through one, or behind Tor.
+//! Domain names are handed to the proxy unresolved, so no dns query leaves the node.
+//! Listening is unaffected, relayed connections are dialed through the proxy as
+//! they go over tcp to the relay.
+
+use async_std::net::TcpStream;
+use futures::{
+    future::{BoxFuture, FutureExt, TryFutureExt},
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+};
+use libp2p::{
+    core::{
+        either::{EitherError, EitherOutput},
+        multiaddr::Protocol,
+        transport::{ListenerId, TransportError, TransportEvent},
+    },
+    Multiaddr, Transport,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    net::IpAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const PASSWORD_AUTH: u8 = 2;
+const CONNECT: u8 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ProxyConfig {
+    /// Address of the SOCKS5 proxy, `host:port`.
+    pub address: String,
+    /// Username and password to authenticate to the proxy with, if it asks them.
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+/// Host and port a dial asks the proxy to connect to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Target {
+    Ip(IpAddr, u16),
+    Domain(String, u16),
+}
+
+impl Target {
+    /// Target of a `/ip4`, `/ip6` or `/dns` address followed by `/tcp`, and at most
+    /// the `/p2p` of the peer. Relayed `/p2p-circuit` addresses are left to the relay
+    /// transport, which dials the relay through here.
+    fn from_multiaddr(addr: &Multiaddr) -> Option<Self> {
+        let mut protocols = addr.iter();
+        let host = protocols.next()?;
+        let port = match protocols.next()? {
+            Protocol::Tcp(port) => port,
+            _ => return None,
+        };
+        if !protocols.all(|protocol| matches!(protocol, Protocol::P2p(_))) {
+            return None;
+        }
+        match host {
+            Protocol::Ip4(ip) => Some(Target::Ip(ip.into(), port)),
+            Protocol::Ip6(ip) => Some(Target::Ip(ip.into(), port)),
+            Protocol::Dns(name) | Protocol::Dns4(name) | Protocol::Dns6(name) => {
+                Some(Target::Domain(name.into_owned(), port))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Tcp transport dialing through a SOCKS5 proxy when one is configured.
+pub struct ProxyTransport<T> {
+    inner: T,
+    proxy: Option<ProxyConfig>,
+}
+
+impl<T> ProxyTransport<T> {
+    pub fn new(inner: T, proxy: Option<ProxyConfig>) -> Self {
+        Self { inner, proxy }
+    }
+
+    fn dial_proxy(
+        &self,
+        addr: Multiaddr,
+    ) -> Option<Result<BoxFuture<'static, io::Result<TcpStream>>, TransportError<io::Error>>> {
+        let proxy = self.proxy.clone()?;
+        let target = match Target::from_multiaddr(&addr) {
+            Some(target) => target,
+            None => return Some(Err(TransportError::MultiaddrNotSupported(addr))),
+        };
+        Some(Ok(async move {
+            let mut stream = TcpStream::connect(&proxy.address).await?;
+            stream.set_nodelay(true)?;
+            connect(&mut stream, &target, &proxy).await?;
+            Ok(stream)
+        }
+        .boxed()))
+    }
+}
+
+type ProxyOutput<T> = EitherOutput<<T as Transport>::Output, TcpStream>;
+type ProxyError<T> = EitherError<<T as Transport>::Error, io::Error>;
+
+impl<T> Transport for ProxyTransport<T>
+where
+    T: Transport + Unpin,
+    T::Output: Send + 'static,
+    T::Error: Send + 'static,
+    T::Dial: Send + 'static,
+    T::ListenerUpgrade: Send + 'static,
+{
+    type Output = ProxyOutput<T>;
+    type Error = ProxyError<T>;
+    type ListenerUpgrade = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+    type Dial = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    fn listen_on(&mut self, addr: Multiaddr) -> Result<ListenerId, TransportError<Self::Error>> {
+        self.inner
+            .listen_on(addr)
+            .map_err(|e| e.map(EitherError::A))
+    }
+
+    fn remove_listener(&mut self, id: ListenerId) -> bool {
+        self.inner.remove_listener(id)
+    }
+
+    fn dial(&mut self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        match self.dial_proxy(addr.clone()) {
+            Some(dial) => proxied(dial),
+            None => direct(self.inner.dial(addr)),
+        }
+    }
+
+    fn dial_as_listener(
+        &mut self,
+        addr: Multiaddr,
+    ) -> Result<Self::Dial, TransportError<Self::Error>> {
+        match self.dial_proxy(addr.clone()) {
+            Some(dial) => proxied(dial),
+            None => direct(self.inner.dial_as_listener(addr)),
+        }
+    }
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<TransportEvent<Self::ListenerUpgrade, Self::Error>> {
+        Pin::new(&mut self.inner).poll(cx).map(|event| {
+            event
+                .map_upgrade(|upgrade| {
+                    upgrade
+                        .map_ok(EitherOutput::First)
+                        .map_err(EitherError::A)
+                        .boxed()
+                })
+                .map_err(EitherError::A)
+        })
+    }
+
+    fn address_translation(&self, listen: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
+        self.inner.address_translation(listen, observed)
+    }
+}
+
+type DialResult<T> = Result<
+    BoxFuture<'static, Result<ProxyOutput<T>, ProxyError<T>>>,
+    TransportError<ProxyError<T>>,
+>;
+
+fn proxied<T>(
+    dial: Result<BoxFuture<'static, io::Result<TcpStream>>, TransportError<io::Error>>,
+) -> DialResult<T>
+where
+    T: Transport,
+    T::Output: Send + 'static,
+    T::Error: Send + 'static,
+{
+    dial.map(|dial| {
+        dial.map_ok(EitherOutput::Second)
+            .map_err(EitherError::B)
+            .boxed()
+    })
+    .map_err(|e| e.map(EitherError::B))
+}
+
+fn direct<T>(dial: Result<T::Dial, TransportError<T::Error>>) -> DialResult<T>
+where
+    T: Transport,
+    T::Output: Send + 'static,
+    T::Error: Send + 'static,
+    T::Dial: Send + 'static,
+{
+    dial.map(|dial| {
+        dial.map_ok(EitherOutput::First)
+            .map_err(EitherError::A)
+            .boxed()
+    })
+    .map_err(|e| e.map(EitherError::A))
+}
+
+/// Authenticate to the proxy on `stream` and ask it to connect to `target`.
+async fn connect<S>(stream: &mut S, target: &Target, proxy: &ProxyConfig) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let credentials = proxy.username.as_deref().zip(proxy.password.as_deref());
+    let methods: &[u8] = match credentials {
+        Some(_) => &[NO_AUTH, PASSWORD_AUTH],
+        None => &[NO_AUTH],
+    };
+    let mut greeting = vec![SOCKS_VERSION, methods.len() as u8];
+    greeting.extend_from_slice(methods);
+    stream.write_all(&greeting).await?;
+
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice[0] != SOCKS_VERSION {
+        return Err(proxy_error("The proxy does not speak SOCKS5"));
+    }
+    match (choice[1], credentials) {
+        (NO_AUTH, _) => {}
+        (PASSWORD_AUTH, Some((username, password))) => {
+            if username.len() > 255 || password.len() > 255 {
+                return Err(proxy_error("The proxy username or password is too long"));
+            }
+            let mut auth = vec![1, username.len() as u8];
+            auth.extend_from_slice(username.as_bytes());
+            auth.push(password.len() as u8);
+            auth.extend_from_slice(password.as_bytes());
+            stream.write_all(&auth).await?;
+
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0 {
+                return Err(proxy_error("The proxy rejected the username or password"));
+            }
+        }
+        _ => {
+            return Err(proxy_error(
+                "The proxy accepts none of the authentication methods offered",
+            ))
+        }
+    }
+
+    let mut request = vec![SOCKS_VERSION, CONNECT, 0];
+    let port = match target {
+        Target::Ip(IpAddr::V4(ip), port) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+            port
+        }
+        Target::Ip(IpAddr::V6(ip), port) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+            port
+        }
+        Target::Domain(name, port) => {
+            if name.len() > 255 {
+                return Err(proxy_error("The domain name is too long for the proxy"));
+            }
+            request.push(3);
+            request.push(name.len() as u8);
+            request.extend_from_slice(name.as_bytes());
+            port
+        }
+    };
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(proxy_error(match reply[1] {
+            2 => "The proxy does not allow the connection",
+            3 => "The network is unreachable from the proxy",
+            4 => "The host is unreachable from the proxy",
+            5 => "The connection was refused by the host",
+            _ => "The proxy failed to connect",
+        }));
+    }
+    // skip the address the proxy bound, then the stream is the connection
+    let bound = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+        _ => {
+            return Err(proxy_error(
+                "The proxy replied with an unknown address type",
+            ))
+        }
+    };
+    let mut skipped = vec![0u8; bound + 2];
+    stream.read_exact(&mut skipped).await?;
+    Ok(())
+}
+
+fn proxy_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::{net::TcpListener, task};
+
+    #[async_std::test]
+    async fn test_socks5_connect() {
+        let addr: Multiaddr = "/dns4/peer.ursa.earth/tcp/6009/p2p/12D3KooWDji7xMLia6GAsyr4oiEFD2dd3zSryqNhfxU3Grzs1r9p"
+            .parse()
+            .unwrap();
+        let target = Target::from_multiaddr(&addr).unwrap();
+        assert_eq!(target, Target::Domain("peer.ursa.earth".into(), 6009));
+        assert!(Target::from_multiaddr(&"/ip4/127.0.0.1/udp/6009".parse().unwrap()).is_none());
+        let relayed: Multiaddr = format!("{addr}/p2p-circuit").parse().unwrap();
+        assert!(Target::from_multiaddr(&relayed).is_none());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = ProxyConfig {
+            address: listener.local_addr().unwrap().to_string(),
+            username: Some("ursa".into()),
+            password: Some("secret".into()),
+        };
+        let server = task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 4];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 2, NO_AUTH, PASSWORD_AUTH]);
+            stream.write_all(&[5, PASSWORD_AUTH]).await.unwrap();
+
+            let mut auth = [0u8; 3 + 4 + 6];
+            stream.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth[..], b"\x01\x04ursa\x06secret");
+            stream.write_all(&[1, 0]).await.unwrap();
+
+            let mut request = [0u8; 5 + 15 + 2];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request[..5], &[5, CONNECT, 0, 3, 15]);
+            assert_eq!(&request[5..20], b"peer.ursa.earth");
+            assert_eq!(&request[20..], &6009u16.to_be_bytes());
+            stream
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0x17, 0x79])
+                .await
+                .unwrap();
+            stream.write_all(b"hello").await.unwrap();
+        });
+
+        let mut stream = TcpStream::connect(&proxy.address).await.unwrap();
+        connect(&mut stream, &target, &proxy).await.unwrap();
+        let mut hello = [0u8; 5];
+        stream.read_exact(&mut hello).await.unwrap();
+        assert_eq!(&hello, b"hello");
+        server.await;
+    }
+}
@@ -0,0 +1,151 @@
+//! Network-wide content purges.
+//!
+//! An operator node signs a [`PurgeMessage`] naming root cids and index provider
+//! context ids and gossips it on the purge topic. Nodes with a [`PurgeConfig`]
+//! subscribe to the topic, and only accept and forward messages signed by one of the
+//! configured admin keys, evicting the named content. Messages older than a day are
+//! dropped, so a captured purge can only be replayed within that day.
+
+use anyhow::{anyhow, Result};
+use libp2p::identity::{Keypair, PublicKey};
+use serde::{Deserialize, Serialize};
+use ursa_utils::{hex, unhex, unix_now};
+
+/// Topic purges are gossiped on unless configured otherwise.
+pub const URSA_PURGE: &str = "/ursa/purge";
+
+/// Seconds a purge message is accepted for after it was signed.
+const MAX_AGE_SECS: u64 = 24 * 60 * 60;
+
+/// Clocks of the signer may be ahead by this many seconds.
+const MAX_CLOCK_SKEW_SECS: u64 = 5 * 60;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PurgeConfig {
+    /// Hex of the protobuf encoded public keys allowed to sign purges.
+    pub admin_keys: Vec<String>,
+    /// Optional. Topic purges are gossiped on, `/ursa/purge` when unset.
+    pub topic: Option<String>,
+}
+
+impl PurgeConfig {
+    pub fn topic(&self) -> &str {
+        self.topic.as_deref().unwrap_or(URSA_PURGE)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PurgeMessage {
+    /// Root cids to evict.
+    pub cids: Vec<String>,
+    /// Context ids of index provider advertisements, the content stored under them
+    /// is evicted.
+    pub context_ids: Vec<String>,
+    /// Unix time in seconds the purge was signed at.
+    pub timestamp: u64,
+    /// Hex of the protobuf encoded public key of the signer.
+    pub public_key: String,
+    /// Hex of the signature of the signer over the other fields.
+    pub signature: String,
+}
+
+impl PurgeMessage {
+    pub fn sign(keypair: &Keypair, cids: Vec<String>, context_ids: Vec<String>) -> Result<Self> {
+        let mut message = Self {
+            cids,
+            context_ids,
+            timestamp: unix_now(),
+            public_key: hex(&keypair.public().to_protobuf_encoding()),
+            signature: String::new(),
+        };
+        message.signature = hex(&keypair.sign(&message.signed_bytes())?);
+        Ok(message)
+    }
+
+    /// Check that the message is recent and signed by one of `admin_keys`.
+    pub fn verify(&self, admin_keys: &[String]) -> Result<()> {
+        if !admin_keys
+            .iter()
+            .any(|key| key.eq_ignore_ascii_case(&self.public_key))
+        {
+            return Err(anyhow!("The purge is not signed by an admin key"));
+        }
+        let public_key = unhex(&self.public_key)
+            .and_then(|key| PublicKey::from_protobuf_encoding(&key).ok())
+            .ok_or_else(|| anyhow!("The public key of the purge does not decode"))?;
+        let signed = unhex(&self.signature)
+            .map_or(false, |sig| public_key.verify(&self.signed_bytes(), &sig));
+        if !signed {
+            return Err(anyhow!("The purge has an invalid signature"));
+        }
+        let now = unix_now();
+        if self.timestamp + MAX_AGE_SECS < now || self.timestamp > now + MAX_CLOCK_SKEW_SECS {
+            return Err(anyhow!("The purge signed at {} is stale", self.timestamp));
+        }
+        Ok(())
+    }
+
+    /// Every list and string is prefixed with its length, context ids being free-form
+    /// no two purges sign the same bytes.
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut bytes = b"ursa-purge".to_vec();
+        for list in [&self.cids, &self.context_ids] {
+            bytes.extend_from_slice(&(list.len() as u64).to_be_bytes());
+            for field in list {
+                bytes.extend_from_slice(&(field.len() as u64).to_be_bytes());
+                bytes.extend_from_slice(field.as_bytes());
+            }
+        }
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_purge_message() {
+        let admin = Keypair::generate_ed25519();
+        let admin_keys = vec![hex(&admin.public().to_protobuf_encoding())];
+        let message = PurgeMessage::sign(
+            &admin,
+            vec!["bafy".to_string()],
+            vec!["context".to_string()],
+        )
+        .unwrap();
+        assert!(message.verify(&admin_keys).is_ok());
+
+        let mut widened = message.clone();
+        widened.cids.push("bafz".to_string());
+        assert!(widened.verify(&admin_keys).is_err());
+
+        let other = PurgeMessage::sign(&Keypair::generate_ed25519(), vec![], vec![]).unwrap();
+        assert!(other.verify(&admin_keys).is_err());
+
+        let mut stale = message;
+        stale.timestamp -= MAX_AGE_SECS + 1;
+        assert!(stale.verify(&admin_keys).is_err());
+    }
+
+    #[test]
+    fn test_purge_signed_bytes_are_unambiguous() {
+        let signed_bytes = |cids: &[&str], context_ids: &[&str]| {
+            PurgeMessage {
+                cids: cids.iter().map(|s| s.to_string()).collect(),
+                context_ids: context_ids.iter().map(|s| s.to_string()).collect(),
+                timestamp: 1,
+                public_key: String::new(),
+                signature: String::new(),
+            }
+            .signed_bytes()
+        };
+        assert_ne!(signed_bytes(&["a,b"], &[]), signed_bytes(&["a", "b"], &[]));
+        assert_ne!(
+            signed_bytes(&["a"], &["b:c"]),
+            signed_bytes(&["a:b"], &["c"])
+        );
+        assert_ne!(signed_bytes(&[], &["a"]), signed_bytes(&["a"], &[]));
+    }
+}
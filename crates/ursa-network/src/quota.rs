@@ -0,0 +1,159 @@
+//! Per-peer quotas on the exchange protocol.
+//!
+//! Every inbound [`UrsaExchangeRequest`](crate::codec::protocol::UrsaExchangeRequest)
+//! counts towards the quota of its peer for the current window. Requests over the
+//! quota are dropped unanswered, and a peer going far enough over it in a window is
+//! disconnected.
+//!
+//! The window of a peer outlives its connections, so reconnecting does not reset the
+//! count. Windows are dropped once they ended, swept once per window length.
+
+use fnv::FnvHashMap;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct RequestQuotaConfig {
+    /// Requests a peer may make per window. 0 disables the quotas.
+    pub max_requests: u32,
+    pub window_secs: u64,
+    /// Requests over the quota in one window after which the peer is disconnected.
+    /// 0 only drops them.
+    pub disconnect_over: u32,
+}
+
+impl Default for RequestQuotaConfig {
+    fn default() -> Self {
+        Self {
+            max_requests: 600,
+            window_secs: 60,
+            disconnect_over: 600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaCheck {
+    Allowed,
+    /// The request is over the quota, `first` for the first one of the window.
+    Throttled {
+        requests: u32,
+        first: bool,
+    },
+    /// The peer just went far enough over the quota to be disconnected.
+    Disconnect {
+        requests: u32,
+    },
+}
+
+struct Window {
+    start: Instant,
+    requests: u32,
+}
+
+#[derive(Default)]
+pub struct RequestQuotas {
+    config: RequestQuotaConfig,
+    peers: FnvHashMap<PeerId, Window>,
+    /// When the ended windows were last dropped.
+    swept: Option<Instant>,
+}
+
+impl RequestQuotas {
+    pub fn new(config: RequestQuotaConfig) -> Self {
+        Self {
+            config,
+            peers: Default::default(),
+            swept: None,
+        }
+    }
+
+    /// Count a request of `peer`.
+    pub fn check(&mut self, peer: PeerId) -> QuotaCheck {
+        self.check_at(peer, Instant::now())
+    }
+
+    fn check_at(&mut self, peer: PeerId, now: Instant) -> QuotaCheck {
+        if self.config.max_requests == 0 {
+            return QuotaCheck::Allowed;
+        }
+        let length = Duration::from_secs(self.config.window_secs.max(1));
+        match self.swept {
+            Some(swept) if now.duration_since(swept) < length => {}
+            _ => {
+                self.peers
+                    .retain(|_, window| now.duration_since(window.start) < length);
+                self.swept = Some(now);
+            }
+        }
+        let window = self.peers.entry(peer).or_insert(Window {
+            start: now,
+            requests: 0,
+        });
+        if now.duration_since(window.start) >= length {
+            window.start = now;
+            window.requests = 0;
+        }
+        window.requests = window.requests.saturating_add(1);
+
+        let requests = window.requests;
+        match requests.saturating_sub(self.config.max_requests) {
+            0 => QuotaCheck::Allowed,
+            over if over == self.config.disconnect_over => QuotaCheck::Disconnect { requests },
+            over => QuotaCheck::Throttled {
+                requests,
+                first: over == 1,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_quotas() {
+        let mut quotas = RequestQuotas::new(RequestQuotaConfig {
+            max_requests: 2,
+            window_secs: 10,
+            disconnect_over: 2,
+        });
+        let (peer, other) = (PeerId::random(), PeerId::random());
+        let start = Instant::now();
+
+        assert_eq!(quotas.check_at(peer, start), QuotaCheck::Allowed);
+        assert_eq!(quotas.check_at(peer, start), QuotaCheck::Allowed);
+        assert_eq!(
+            quotas.check_at(peer, start),
+            QuotaCheck::Throttled {
+                requests: 3,
+                first: true
+            }
+        );
+        assert_eq!(quotas.check_at(other, start), QuotaCheck::Allowed);
+        assert_eq!(
+            quotas.check_at(peer, start),
+            QuotaCheck::Disconnect { requests: 4 }
+        );
+        // requests still in flight are only dropped
+        assert_eq!(
+            quotas.check_at(peer, start),
+            QuotaCheck::Throttled {
+                requests: 5,
+                first: false
+            }
+        );
+
+        // the next window starts over, the ended window of the other peer is dropped
+        let later = start + Duration::from_secs(10);
+        assert_eq!(quotas.check_at(peer, later), QuotaCheck::Allowed);
+        assert_eq!(quotas.peers.len(), 1);
+
+        let mut unlimited = RequestQuotas::default();
+        unlimited.config.max_requests = 0;
+        assert_eq!(unlimited.check_at(peer, start), QuotaCheck::Allowed);
+    }
+}
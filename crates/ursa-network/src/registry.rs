@@ -0,0 +1,241 @@
+//! Node registry.
+//!
+//! With a registry configured the node posts a signed [`Heartbeat`] with its peer id,
+//! external addresses and capacity to `<url>/register` once it is up and whenever its
+//! addresses change, and to `<url>/heartbeat` every `heartbeat_secs` in between, so
+//! the registry can keep an authoritative list of the live nodes. Only http
+//! registries are supported, a contract is reached through an http service in front
+//! of it.
+
+use anyhow::{anyhow, Result};
+use async_std::task;
+use libp2p::{
+    identity::{Keypair, PublicKey},
+    Multiaddr, PeerId,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+use tracing::{debug, warn};
+use ursa_utils::{hex, unhex, unix_now};
+
+/// Seconds between heartbeats unless configured otherwise.
+const DEFAULT_HEARTBEAT_SECS: u64 = 60;
+
+/// Wait for the listeners to come up before registering.
+const REGISTER_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RegistryConfig {
+    /// Base url of the registry service.
+    pub url: String,
+    /// Optional. Seconds between heartbeats, 60 when unset.
+    pub heartbeat_secs: Option<u64>,
+    /// Optional. Storage the node offers to the network.
+    pub storage_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capacity {
+    pub storage_bytes: Option<u64>,
+    /// The global upload cap of the node.
+    pub upload_bytes_per_sec: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Heartbeat {
+    pub peer_id: String,
+    pub addresses: Vec<String>,
+    pub capacity: Capacity,
+    /// Incremented with every heartbeat, so registries can drop replays.
+    pub sequence: u64,
+    /// Unix time in seconds the heartbeat was signed at.
+    pub timestamp: u64,
+    /// Hex of the protobuf encoded public key of the node.
+    pub public_key: String,
+    /// Hex of the signature of the node over the other fields.
+    pub signature: String,
+}
+
+impl Heartbeat {
+    pub fn sign(
+        keypair: &Keypair,
+        addresses: &[Multiaddr],
+        capacity: Capacity,
+        sequence: u64,
+    ) -> Result<Self> {
+        let public_key = keypair.public();
+        let mut heartbeat = Self {
+            peer_id: PeerId::from(public_key.clone()).to_string(),
+            addresses: addresses.iter().map(Multiaddr::to_string).collect(),
+            capacity,
+            sequence,
+            timestamp: unix_now(),
+            public_key: hex(&public_key.to_protobuf_encoding()),
+            signature: String::new(),
+        };
+        heartbeat.signature = hex(&keypair.sign(&heartbeat.signed_bytes())?);
+        Ok(heartbeat)
+    }
+
+    /// Check the signature and that the key belongs to the peer id.
+    pub fn verify(&self) -> Result<()> {
+        let public_key = unhex(&self.public_key)
+            .and_then(|key| PublicKey::from_protobuf_encoding(&key).ok())
+            .ok_or_else(|| anyhow!("The public key of {} does not decode", self.peer_id))?;
+        if PeerId::from(public_key.clone()).to_string() != self.peer_id {
+            return Err(anyhow!(
+                "The heartbeat of {} is signed by another key",
+                self.peer_id
+            ));
+        }
+        let signed = unhex(&self.signature)
+            .map_or(false, |sig| public_key.verify(&self.signed_bytes(), &sig));
+        if !signed {
+            return Err(anyhow!(
+                "The heartbeat of {} has an invalid signature",
+                self.peer_id
+            ));
+        }
+        Ok(())
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        let optional = |value: Option<u64>| value.map(|v| v.to_string()).unwrap_or_default();
+        format!(
+            "ursa-heartbeat:{}:{}:{}:{}:{}:{}",
+            self.peer_id,
+            self.addresses.join(","),
+            optional(self.capacity.storage_bytes),
+            optional(self.capacity.upload_bytes_per_sec),
+            self.sequence,
+            self.timestamp
+        )
+        .into_bytes()
+    }
+}
+
+pub struct Registry {
+    config: RegistryConfig,
+    keypair: Keypair,
+    capacity: Capacity,
+    addrs: RwLock<Vec<Multiaddr>>,
+    sequence: AtomicU64,
+    /// Whether the registry has the current addresses of the node.
+    registered: AtomicBool,
+}
+
+impl Registry {
+    /// Registry client of the node with `keypair`, uploading at most
+    /// `upload_bytes_per_sec`.
+    pub fn new(
+        config: RegistryConfig,
+        keypair: Keypair,
+        upload_bytes_per_sec: Option<u64>,
+    ) -> Self {
+        // a restarted node starts over above the sequences it used before
+        let sequence = unix_now() * 1000;
+        let capacity = Capacity {
+            storage_bytes: config.storage_bytes,
+            upload_bytes_per_sec,
+        };
+        Self {
+            config,
+            keypair,
+            capacity,
+            addrs: Default::default(),
+            sequence: AtomicU64::new(sequence),
+            registered: AtomicBool::new(false),
+        }
+    }
+
+    /// Register again with the new external addresses of the node.
+    pub fn set_addrs(&self, addrs: Vec<Multiaddr>) {
+        let mut current = self.addrs.write().unwrap();
+        if *current != addrs {
+            *current = addrs;
+            self.registered.store(false, Ordering::SeqCst);
+        }
+    }
+
+    fn heartbeat(&self) -> Result<Heartbeat> {
+        let sequence = self.sequence.fetch_add(1, Ordering::SeqCst) + 1;
+        let addrs = self.addrs.read().unwrap().clone();
+        Heartbeat::sign(&self.keypair, &addrs, self.capacity.clone(), sequence)
+    }
+
+    /// Register, or send a heartbeat when the registry is up to date.
+    async fn beat(&self) -> Result<()> {
+        let registered = self.registered.swap(true, Ordering::SeqCst);
+        let path = if registered { "heartbeat" } else { "register" };
+        let url = format!("{}/{path}", self.config.url.trim_end_matches('/'));
+        let result = async {
+            let body = surf::Body::from_json(&self.heartbeat()?).map_err(|e| anyhow!(e))?;
+            let res = surf::post(&url).body(body).await.map_err(|e| anyhow!(e))?;
+            if !res.status().is_success() {
+                return Err(anyhow!("{url} answered {}", res.status()));
+            }
+            Ok(())
+        }
+        .await;
+        if result.is_err() {
+            // a failed heartbeat may mean the registry forgot the node
+            self.registered.store(false, Ordering::SeqCst);
+        }
+        result
+    }
+
+    /// Register and send heartbeats until the node stops.
+    pub fn start(self: Arc<Self>) {
+        task::spawn(async move {
+            let interval = self
+                .config
+                .heartbeat_secs
+                .unwrap_or(DEFAULT_HEARTBEAT_SECS)
+                .max(1);
+            task::sleep(REGISTER_DELAY).await;
+            loop {
+                match self.beat().await {
+                    Ok(()) => debug!("Sent a heartbeat to {}", self.config.url),
+                    Err(err) => warn!("Failed to reach the registry: {:?}", err),
+                }
+                task::sleep(Duration::from_secs(interval)).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heartbeat() {
+        let keypair = Keypair::generate_ed25519();
+        let registry = Registry::new(
+            RegistryConfig {
+                url: "http://registry.local".to_string(),
+                heartbeat_secs: None,
+                storage_bytes: Some(1 << 40),
+            },
+            keypair,
+            None,
+        );
+        registry.set_addrs(vec!["/ip4/1.2.3.4/tcp/6009".parse().unwrap()]);
+
+        let first = registry.heartbeat().unwrap();
+        assert!(first.verify().is_ok());
+        assert_eq!(first.addresses, vec!["/ip4/1.2.3.4/tcp/6009".to_string()]);
+        assert_eq!(first.capacity.storage_bytes, Some(1 << 40));
+        assert!(registry.heartbeat().unwrap().sequence > first.sequence);
+
+        let mut moved = first.clone();
+        moved.addresses = vec!["/ip4/5.6.7.8/tcp/6009".to_string()];
+        assert!(moved.verify().is_err());
+    }
+}
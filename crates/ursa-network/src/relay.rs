@@ -0,0 +1,170 @@
+//! Resource limits of the relay server.
+//!
+//! Every reservation and circuit held on the relay costs the node a connection and
+//! bandwidth, so both are capped in total and per peer, and a circuit is closed once
+//! it is open for `max_circuit_duration_secs` or relayed `max_circuit_bytes`. Denied
+//! requests are counted and published with the peers and the limit they hit, so
+//! operators can spot peers abusing the relay.
+
+use libp2p::{relay::v2::relay::Config as RelayConfig, PeerId};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct RelayLimitsConfig {
+    /// Reservations held on the relay at once.
+    pub max_reservations: usize,
+    pub max_reservations_per_peer: usize,
+    /// Seconds a reservation lasts before the peer has to renew it.
+    pub reservation_ttl_secs: u64,
+    /// Circuits relayed at once.
+    pub max_circuits: usize,
+    pub max_circuits_per_peer: usize,
+    /// Seconds after which a circuit is closed.
+    pub max_circuit_duration_secs: u64,
+    /// Bytes relayed in each direction after which a circuit is closed.
+    pub max_circuit_bytes: u64,
+}
+
+impl Default for RelayLimitsConfig {
+    fn default() -> Self {
+        // the limits libp2p ships with
+        Self {
+            max_reservations: 128,
+            max_reservations_per_peer: 4,
+            reservation_ttl_secs: 60 * 60,
+            max_circuits: 16,
+            max_circuits_per_peer: 4,
+            max_circuit_duration_secs: 2 * 60,
+            max_circuit_bytes: 1 << 17,
+        }
+    }
+}
+
+impl RelayLimitsConfig {
+    pub fn relay_config(&self) -> RelayConfig {
+        RelayConfig {
+            max_reservations: self.max_reservations,
+            max_reservations_per_peer: self.max_reservations_per_peer,
+            reservation_duration: Duration::from_secs(self.reservation_ttl_secs),
+            max_circuits: self.max_circuits,
+            max_circuits_per_peer: self.max_circuits_per_peer,
+            max_circuit_duration: Duration::from_secs(self.max_circuit_duration_secs),
+            max_circuit_bytes: self.max_circuit_bytes,
+            ..RelayConfig::default()
+        }
+    }
+
+    /// The limit most likely to have denied a reservation, with its value, given the
+    /// reservations held on the relay. The relay does not say which one it was.
+    pub fn reservation_limit(&self, reservations: usize) -> (&'static str, usize) {
+        if reservations >= self.max_reservations {
+            ("max_reservations", self.max_reservations)
+        } else {
+            ("max_reservations_per_peer", self.max_reservations_per_peer)
+        }
+    }
+
+    /// The limit most likely to have denied a circuit, with its value, given the
+    /// circuits open on the relay and whether the destination holds a reservation.
+    pub fn circuit_limit(&self, circuits: usize, reserved: bool) -> (&'static str, usize) {
+        if !reserved {
+            ("no_reservation", 0)
+        } else if circuits >= self.max_circuits {
+            ("max_circuits", self.max_circuits)
+        } else {
+            ("max_circuits_per_peer", self.max_circuits_per_peer)
+        }
+    }
+}
+
+/// A circuit relayed by this node, as listed by `ursa_relay_circuits`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayCircuit {
+    pub src_peer_id: String,
+    pub dst_peer_id: String,
+    /// Seconds the circuit is open for.
+    pub duration_secs: u64,
+    /// Limits the circuit was opened with.
+    pub max_duration_secs: u64,
+    pub max_bytes: u64,
+}
+
+/// Circuits open on the relay server, oldest first.
+#[derive(Default)]
+pub struct RelayCircuits(Vec<(PeerId, PeerId, Instant)>);
+
+impl RelayCircuits {
+    pub fn open(&mut self, src: PeerId, dst: PeerId) {
+        self.0.push((src, dst, Instant::now()));
+    }
+
+    /// Close the oldest circuit from `src` to `dst`, returning how long it was open.
+    pub fn close(&mut self, src: &PeerId, dst: &PeerId) -> Option<Duration> {
+        let index = self.0.iter().position(|(s, d, _)| s == src && d == dst)?;
+        Some(self.0.remove(index).2.elapsed())
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn list(&self, limits: &RelayLimitsConfig) -> Vec<RelayCircuit> {
+        self.0
+            .iter()
+            .map(|(src, dst, opened)| RelayCircuit {
+                src_peer_id: src.to_string(),
+                dst_peer_id: dst.to_string(),
+                duration_secs: opened.elapsed().as_secs(),
+                max_duration_secs: limits.max_circuit_duration_secs,
+                max_bytes: limits.max_circuit_bytes,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relay_limits() {
+        let config = RelayLimitsConfig {
+            max_circuits: 2,
+            max_circuit_duration_secs: 30,
+            ..RelayLimitsConfig::default()
+        };
+        let relay = config.relay_config();
+        assert_eq!(relay.max_circuits, 2);
+        assert_eq!(relay.max_circuit_duration, Duration::from_secs(30));
+        assert_eq!(relay.reservation_duration, Duration::from_secs(3600));
+
+        assert_eq!(config.reservation_limit(128), ("max_reservations", 128));
+        assert_eq!(config.reservation_limit(3).0, "max_reservations_per_peer");
+        assert_eq!(config.circuit_limit(0, false).0, "no_reservation");
+        assert_eq!(config.circuit_limit(2, true), ("max_circuits", 2));
+        assert_eq!(config.circuit_limit(1, true).0, "max_circuits_per_peer");
+    }
+
+    #[test]
+    fn test_relay_circuits() {
+        let (src, dst) = (PeerId::random(), PeerId::random());
+        let mut circuits = RelayCircuits::default();
+        circuits.open(src, dst);
+        circuits.open(src, dst);
+        assert_eq!(circuits.len(), 2);
+
+        let listed = circuits.list(&RelayLimitsConfig::default());
+        assert_eq!(listed[0].src_peer_id, src.to_string());
+        assert_eq!(listed[0].max_duration_secs, 120);
+
+        assert!(circuits.close(&dst, &src).is_none());
+        assert!(circuits.close(&src, &dst).is_some());
+        assert_eq!(circuits.len(), 1);
+    }
+}
@@ -0,0 +1,143 @@
+//! Ursa proactive replication.
+//!
+//! The [`ReplicationManager`] keeps a sliding request count per root cid. Once a cid
+//! crosses the configured threshold it is considered hot, and the service asks the
+//! nearest peers to replicate it through the exchange protocol so popular content
+//! is not bottlenecked on a single cache node.
+
+use cid::Cid;
+use fnv::FnvHashMap;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct ReplicationConfig {
+    /// Requests within one window that mark a cid as hot. 0 disables replication.
+    pub threshold: u64,
+    /// Length of the request window in seconds.
+    pub window_secs: u64,
+    /// Number of peers a hot cid is pushed to.
+    pub replicas: usize,
+    /// Accept replication requests from other peers, which pull the dag into the
+    /// local store. Off by default, any peer could fill the disk otherwise.
+    pub accept_replicas: bool,
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 100,
+            window_secs: 60,
+            replicas: 3,
+            accept_replicas: false,
+        }
+    }
+}
+
+/// Gossiped once hot content has been pushed to other peers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplicationAnnouncement {
+    pub cid: String,
+    pub providers: Vec<String>,
+}
+
+struct RequestWindow {
+    start: Instant,
+    count: u64,
+    replicated: bool,
+}
+
+pub struct ReplicationManager {
+    config: ReplicationConfig,
+    windows: FnvHashMap<Cid, RequestWindow>,
+}
+
+impl ReplicationManager {
+    pub fn new(config: ReplicationConfig) -> Self {
+        Self {
+            config,
+            windows: Default::default(),
+        }
+    }
+
+    pub fn replicas(&self) -> usize {
+        self.config.replicas
+    }
+
+    pub fn accepts_replicas(&self) -> bool {
+        self.config.accept_replicas
+    }
+
+    /// Record a request for `cid`.
+    ///
+    /// Returns true the first time the cid crosses the threshold within a window.
+    pub fn record(&mut self, cid: Cid) -> bool {
+        self.record_at(cid, Instant::now())
+    }
+
+    fn record_at(&mut self, cid: Cid, now: Instant) -> bool {
+        if self.config.threshold == 0 || self.config.replicas == 0 {
+            return false;
+        }
+
+        let window = Duration::from_secs(self.config.window_secs);
+        self.windows
+            .retain(|_, w| now.duration_since(w.start) < window);
+
+        let entry = self.windows.entry(cid).or_insert(RequestWindow {
+            start: now,
+            count: 0,
+            replicated: false,
+        });
+        entry.count += 1;
+
+        if !entry.replicated && entry.count >= self.config.threshold {
+            entry.replicated = true;
+            return true;
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn cid() -> Cid {
+        Cid::from_str("bafkreif2opfibjypwkjzzry3jbibcjqcjwnpoqpeiqw75eu3s3u3zbdszq").unwrap()
+    }
+
+    fn manager(threshold: u64) -> ReplicationManager {
+        ReplicationManager::new(ReplicationConfig {
+            threshold,
+            window_secs: 10,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_hot_once_per_window() {
+        let mut manager = manager(3);
+        let now = Instant::now();
+
+        assert!(!manager.record_at(cid(), now));
+        assert!(!manager.record_at(cid(), now));
+        assert!(manager.record_at(cid(), now));
+        assert!(!manager.record_at(cid(), now));
+
+        // the window expired, the cid has to become hot again
+        let later = now + Duration::from_secs(11);
+        assert!(!manager.record_at(cid(), later));
+        assert!(!manager.record_at(cid(), later));
+        assert!(manager.record_at(cid(), later));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut manager = manager(0);
+        assert!(!manager.record_at(cid(), Instant::now()));
+    }
+}
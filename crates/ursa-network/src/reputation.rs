@@ -0,0 +1,400 @@
+//! Ursa peer reputation.
+//!
+//! The [`ReputationStore`] accumulates how reliably each peer answers pings and
+//! bitswap queries, together with the last gossipsub score seen for it, and persists
+//! the result to the peerstore column family, under `reputation/<peer id>`, so a
+//! restarted node still knows which peers to prefer when dialing and when picking
+//! bitswap providers. Only the reputations that changed are written. Reputations
+//! kept in the json file of `reputation_path` by earlier versions are imported while
+//! the peerstore holds none.
+//!
+//! Providers are also ranked by how fast they are: moving averages of the ping round
+//! trip time and of the throughput of car files pulled from them, so the providers
+//! likely to answer first are asked first.
+//!
+//! The service writes the changes every [`FLUSH_INTERVAL`], off the swarm loop.
+
+use anyhow::Result;
+use ipld_blockstore::BlockStore;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::Path,
+    str::FromStr,
+    time::Duration,
+};
+use tracing::{debug, warn};
+use ursa_store::{columns::Column, Store};
+
+/// Time between two writes of the changed reputations.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Peerstore key of the ids of the peers with a stored reputation.
+const REPUTATION_IDS_KEY: &str = "reputations";
+
+/// Weight of a new measurement in the moving averages.
+const SMOOTHING: f64 = 0.2;
+
+/// Round trip time assumed for peers never pinged, in milliseconds.
+const UNKNOWN_RTT_MS: f64 = 100.0;
+
+/// Throughput assumed for peers nothing was pulled from, in bytes per second.
+const UNKNOWN_THROUGHPUT: f64 = 1024.0 * 1024.0;
+
+/// Size of the transfer providers are compared on.
+const TYPICAL_TRANSFER_BYTES: f64 = 1024.0 * 1024.0;
+
+/// Transfers smaller than this are dominated by the round trip, not the bandwidth.
+const MIN_TRANSFER_BYTES: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PeerReputation {
+    pub ping_success: u64,
+    pub ping_failure: u64,
+    /// Outcomes of bitswap queries the peer was a provider for.
+    pub bitswap_success: u64,
+    pub bitswap_failure: u64,
+    /// Last gossipsub peer score seen before the peer disconnected.
+    pub gossip_score: f64,
+    /// Bitswap queries the peer served corrupt or oversized blocks for.
+    #[serde(default)]
+    pub invalid_blocks: u64,
+    /// Gossip messages from the peer rejected for their size or topic.
+    #[serde(default)]
+    pub invalid_gossip: u64,
+    /// Moving average of the ping round trip time, in milliseconds.
+    #[serde(default)]
+    pub rtt_ms: Option<f64>,
+    /// Moving average of the throughput of car transfers from the peer, in bytes per
+    /// second.
+    #[serde(default)]
+    pub throughput: Option<f64>,
+}
+
+/// Success ratio with a uniform prior, so unknown peers start at 0.5.
+fn ratio(success: u64, failure: u64) -> f64 {
+    (success as f64 + 1.0) / ((success + failure) as f64 + 2.0)
+}
+
+impl PeerReputation {
+    /// Score between 0 and 1, higher is better.
+    pub fn score(&self) -> f64 {
+        let ping = ratio(self.ping_success, self.ping_failure);
+        let bitswap = ratio(self.bitswap_success, self.bitswap_failure);
+        // gossipsub scores are unbounded, only penalize peers that misbehaved
+        let gossip = if self.gossip_score < 0.0 { 0.5 } else { 1.0 };
+        // corrupt blocks are never an accident, every one halves the score
+        let invalid = 0.5f64.powi(self.invalid_blocks.min(32) as i32);
+        // a forwarding peer may not have checked the message, so each costs less
+        let spam = 0.9f64.powi(self.invalid_gossip.min(64) as i32);
+
+        ping * bitswap * gossip * invalid * spam
+    }
+
+    /// Factor between 0 and 1 for how fast the peer is expected to deliver a typical
+    /// transfer, a round trip plus the bytes at its throughput, higher is better.
+    pub fn speed(&self) -> f64 {
+        let rtt_ms = self.rtt_ms.unwrap_or(UNKNOWN_RTT_MS);
+        let throughput = self.throughput.unwrap_or(UNKNOWN_THROUGHPUT).max(1.0);
+        let expected_ms = rtt_ms + TYPICAL_TRANSFER_BYTES / throughput * 1000.0;
+        1000.0 / (1000.0 + expected_ms)
+    }
+}
+
+fn average(current: Option<f64>, sample: f64) -> Option<f64> {
+    Some(current.map_or(sample, |current| current + SMOOTHING * (sample - current)))
+}
+
+/// Reputations of the known peers, persisted by saving their
+/// [`snapshot`](Self::snapshot)s.
+#[derive(Default)]
+pub struct ReputationStore {
+    peers: HashMap<PeerId, PeerReputation>,
+    /// Peers whose reputation changed since the last snapshot.
+    dirty: HashSet<PeerId>,
+    /// Whether peers were added since the last snapshot.
+    added: bool,
+}
+
+/// Changed reputations to write to the peerstore.
+pub struct Snapshot {
+    /// Ids of all the peers, when some were added.
+    ids: Option<Vec<String>>,
+    peers: Vec<(String, PeerReputation)>,
+}
+
+impl Snapshot {
+    /// Write the changed reputations to `store`, at once.
+    pub fn save<S: BlockStore + Sync + Send + 'static>(&self, store: &Store<S>) -> Result<()> {
+        let mut values = Vec::with_capacity(self.peers.len() + 1);
+        for (peer, reputation) in &self.peers {
+            values.push((reputation_key(peer), serde_json::to_vec(reputation)?));
+        }
+        if let Some(ids) = &self.ids {
+            values.push((REPUTATION_IDS_KEY.to_string(), serde_json::to_vec(ids)?));
+        }
+        store.column(Column::Peers).bulk_write(&values)?;
+        debug!("Persisted {} peer reputations", self.peers.len());
+        Ok(())
+    }
+}
+
+fn reputation_key(peer: &str) -> String {
+    format!("reputation/{peer}")
+}
+
+impl ReputationStore {
+    /// Load the reputations stored in the peerstore of `store`, or those of the
+    /// `legacy` json file while the peerstore holds none.
+    pub fn load<S: BlockStore + Sync + Send + 'static>(
+        store: &Store<S>,
+        legacy: Option<&Path>,
+    ) -> Result<Self> {
+        let peers = store.column(Column::Peers);
+        let ids: Vec<String> = match peers.read(REPUTATION_IDS_KEY)? {
+            Some(ids) => serde_json::from_slice(&ids)?,
+            None => return Ok(legacy.map(Self::import).unwrap_or_default()),
+        };
+        let mut reputations = Self::default();
+        for id in ids {
+            let (peer, reputation) = match (PeerId::from_str(&id), peers.read(reputation_key(&id))?)
+            {
+                (Ok(peer), Some(reputation)) => (peer, reputation),
+                _ => continue,
+            };
+            reputations
+                .peers
+                .insert(peer, serde_json::from_slice(&reputation)?);
+        }
+        Ok(reputations)
+    }
+
+    /// Reputations of the json file at `path`, all of them to be written to the
+    /// peerstore.
+    fn import(path: &Path) -> Self {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("Failed to read peer reputations from {:?}: {:?}", path, e);
+                }
+                return Self::default();
+            }
+        };
+        let peers: HashMap<PeerId, PeerReputation> =
+            serde_json::from_slice::<HashMap<String, PeerReputation>>(&bytes)
+                .map_err(|e| warn!("Failed to parse peer reputations: {:?}", e))
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|(peer, reputation)| {
+                    PeerId::from_str(&peer).ok().map(|peer| (peer, reputation))
+                })
+                .collect();
+        Self {
+            dirty: peers.keys().copied().collect(),
+            added: !peers.is_empty(),
+            peers,
+        }
+    }
+
+    pub fn get(&self, peer: &PeerId) -> Option<&PeerReputation> {
+        self.peers.get(peer)
+    }
+
+    /// Score of `peer`, unknown peers get the neutral score.
+    pub fn score(&self, peer: &PeerId) -> f64 {
+        self.peers
+            .get(peer)
+            .map(PeerReputation::score)
+            .unwrap_or_else(|| PeerReputation::default().score())
+    }
+
+    /// Order `peers` from the best to the worst provider, by their reputation and
+    /// their speed.
+    pub fn rank<I: IntoIterator<Item = PeerId>>(&self, peers: I) -> Vec<PeerId> {
+        let neutral = PeerReputation::default();
+        let mut peers: Vec<(f64, PeerId)> = peers
+            .into_iter()
+            .map(|peer| {
+                let reputation = self.peers.get(&peer).unwrap_or(&neutral);
+                (reputation.score() * reputation.speed(), peer)
+            })
+            .collect();
+        peers.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        peers.into_iter().map(|(_, peer)| peer).collect()
+    }
+
+    pub fn record_ping(&mut self, peer: PeerId, success: bool) {
+        let reputation = self.entry(peer);
+        if success {
+            reputation.ping_success += 1;
+        } else {
+            reputation.ping_failure += 1;
+        }
+    }
+
+    pub fn record_rtt(&mut self, peer: PeerId, rtt: Duration) {
+        let reputation = self.entry(peer);
+        reputation.rtt_ms = average(reputation.rtt_ms, rtt.as_secs_f64() * 1000.0);
+    }
+
+    /// Record a transfer of `bytes` from `peer` that took `elapsed`.
+    pub fn record_transfer(&mut self, peer: PeerId, bytes: u64, elapsed: Duration) {
+        if bytes < MIN_TRANSFER_BYTES || elapsed.is_zero() {
+            return;
+        }
+        let reputation = self.entry(peer);
+        let throughput = bytes as f64 / elapsed.as_secs_f64();
+        reputation.throughput = average(reputation.throughput, throughput);
+    }
+
+    pub fn record_bitswap(&mut self, peer: PeerId, success: bool) {
+        let reputation = self.entry(peer);
+        if success {
+            reputation.bitswap_success += 1;
+        } else {
+            reputation.bitswap_failure += 1;
+        }
+    }
+
+    pub fn record_invalid_block(&mut self, peer: PeerId) {
+        self.entry(peer).invalid_blocks += 1;
+    }
+
+    pub fn record_invalid_gossip(&mut self, peer: PeerId) {
+        self.entry(peer).invalid_gossip += 1;
+    }
+
+    pub fn record_gossip_score(&mut self, peer: PeerId, score: f64) {
+        self.entry(peer).gossip_score = score;
+    }
+
+    /// Reputation of `peer` to update, marked as changed.
+    fn entry(&mut self, peer: PeerId) -> &mut PeerReputation {
+        self.dirty.insert(peer);
+        let added = &mut self.added;
+        self.peers.entry(peer).or_insert_with(|| {
+            *added = true;
+            PeerReputation::default()
+        })
+    }
+
+    /// Take the reputations to write if they changed since the last snapshot.
+    pub fn snapshot(&mut self) -> Option<Snapshot> {
+        if self.dirty.is_empty() {
+            return None;
+        }
+        let peers = std::mem::take(&mut self.dirty)
+            .into_iter()
+            .filter_map(|peer| {
+                let reputation = self.peers.get(&peer)?.clone();
+                Some((peer.to_base58(), reputation))
+            })
+            .collect();
+        let ids = std::mem::take(&mut self.added)
+            .then(|| self.peers.keys().map(PeerId::to_base58).collect());
+        Some(Snapshot { ids, peers })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::MemoryDB;
+    use libp2p::identity::Keypair;
+    use std::sync::Arc;
+
+    fn peer() -> PeerId {
+        PeerId::from(Keypair::generate_ed25519().public())
+    }
+
+    #[test]
+    fn test_rank() {
+        let mut store = ReputationStore::default();
+        let (good, bad, unknown) = (peer(), peer(), peer());
+
+        for _ in 0..5 {
+            store.record_bitswap(good, true);
+            store.record_ping(good, true);
+            store.record_bitswap(bad, false);
+        }
+        store.record_gossip_score(bad, -10.0);
+
+        assert_eq!(store.rank([bad, unknown, good]), vec![good, unknown, bad]);
+
+        let corrupt = peer();
+        store.record_invalid_block(corrupt);
+        assert!(store.score(&corrupt) < store.score(&unknown));
+    }
+
+    #[test]
+    fn test_rank_by_speed() {
+        let mut store = ReputationStore::default();
+        let (near, far, fast) = (peer(), peer(), peer());
+        for peer in [near, far, fast] {
+            store.record_ping(peer, true);
+        }
+        store.record_rtt(near, Duration::from_millis(10));
+        store.record_rtt(far, Duration::from_millis(400));
+        store.record_rtt(fast, Duration::from_millis(400));
+        // a 10MiB car file in a second outweighs the longer round trip
+        store.record_transfer(fast, 10 * 1024 * 1024, Duration::from_secs(1));
+        assert_eq!(store.rank([far, near, fast]), vec![fast, near, far]);
+
+        // averaged, a single slow ping does not undo the history
+        store.record_rtt(near, Duration::from_millis(1000));
+        let rtt = store.get(&near).unwrap().rtt_ms.unwrap();
+        assert!(rtt > 10.0 && rtt < 400.0);
+    }
+
+    #[test]
+    fn test_persist() {
+        let db = Store::new(Arc::new(MemoryDB::default()));
+        let (peer, other) = (peer(), peer());
+
+        let mut store = ReputationStore::load(&db, None).unwrap();
+        store.record_ping(peer, true);
+        store.record_bitswap(peer, false);
+        store.record_ping(other, true);
+        store.snapshot().unwrap().save(&db).unwrap();
+        // nothing changed since
+        assert!(store.snapshot().is_none());
+
+        // only the changed reputation is written, the ids stay as they are
+        store.record_ping(other, false);
+        let snapshot = store.snapshot().unwrap();
+        assert!(snapshot.ids.is_none());
+        assert_eq!(snapshot.peers.len(), 1);
+        snapshot.save(&db).unwrap();
+
+        let store = ReputationStore::load(&db, None).unwrap();
+        let reputation = store.get(&peer).unwrap();
+        assert_eq!(reputation.ping_success, 1);
+        assert_eq!(reputation.bitswap_failure, 1);
+        assert_eq!(store.get(&other).unwrap().ping_failure, 1);
+    }
+
+    #[test]
+    fn test_import_legacy_file() {
+        let path = std::env::temp_dir().join("ursa_reputation_test.json");
+        let peer = peer();
+        let reputation = PeerReputation {
+            ping_success: 3,
+            ..Default::default()
+        };
+        let legacy = HashMap::from([(peer.to_base58(), reputation.clone())]);
+        fs::write(&path, serde_json::to_vec(&legacy).unwrap()).unwrap();
+
+        let db = Store::new(Arc::new(MemoryDB::default()));
+        let mut store = ReputationStore::load(&db, Some(&path)).unwrap();
+        assert_eq!(store.get(&peer), Some(&reputation));
+        store.snapshot().unwrap().save(&db).unwrap();
+        let _ = fs::remove_file(&path);
+
+        // the peerstore is read once it holds reputations
+        let store = ReputationStore::load(&db, Some(&path)).unwrap();
+        assert_eq!(store.get(&peer), Some(&reputation));
+    }
+}
@@ -0,0 +1,137 @@
+//! Peers the bitswap blocks are received from.
+//!
+//! Bitswap does not tell the store which peer sent a block, but every query of the
+//! node asks a single peer, so the blocks of a query come from that peer. The root of
+//! each query is expected from its peer, and as the blocks of a sync are stored their
+//! links are expected from the same peer. A block the store rejects is charged to the
+//! peer it was expected from, rather than to whichever query completes next. Blocks
+//! linked from content stored before the query started are not tracked, their
+//! rejections are logged without a sender.
+
+use fnv::{FnvHashMap, FnvHashSet};
+use libipld::{store::DefaultParams, Block, Cid};
+use libp2p::PeerId;
+use libp2p_bitswap::BitswapStore;
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct SendersState {
+    /// Peer each block is expected from, with the root of the query asking for it.
+    expected: FnvHashMap<Cid, (PeerId, Cid)>,
+    /// Blocks the store rejected, with their sender when known.
+    rejected: Vec<(Cid, Option<PeerId>)>,
+}
+
+/// Senders of the blocks of the running bitswap queries.
+#[derive(Clone, Default)]
+pub struct BlockSenders(Arc<Mutex<SendersState>>);
+
+impl BlockSenders {
+    /// Expect `root` and the blocks it links to from `peer`.
+    pub fn expect(&self, root: Cid, peer: PeerId) {
+        self.0.lock().unwrap().expected.insert(root, (peer, root));
+    }
+
+    /// Forget the blocks expected for the query of `root`, once it completed.
+    pub fn finish(&self, root: &Cid) {
+        self.0
+            .lock()
+            .unwrap()
+            .expected
+            .retain(|_, (_, expected)| expected != root);
+    }
+
+    /// Expect the links of the stored `block` from the peer that sent it.
+    fn stored(&self, block: &Block<DefaultParams>) {
+        let mut state = self.0.lock().unwrap();
+        let sender = match state.expected.get(block.cid()) {
+            Some(sender) => *sender,
+            None => return,
+        };
+        let mut links = FnvHashSet::default();
+        if block.references(&mut links).is_ok() {
+            for link in links {
+                state.expected.entry(link).or_insert(sender);
+            }
+        }
+    }
+
+    fn rejected(&self, cid: &Cid) {
+        let mut state = self.0.lock().unwrap();
+        let sender = state.expected.get(cid).map(|(peer, _)| *peer);
+        state.rejected.push((*cid, sender));
+    }
+
+    /// Blocks rejected since the last call, with the peers that sent them.
+    pub fn take(&self) -> Vec<(Cid, Option<PeerId>)> {
+        std::mem::take(&mut self.0.lock().unwrap().rejected)
+    }
+}
+
+/// Bitswap store noting the senders of the blocks `B` stores or rejects.
+pub struct SenderStorage<B>(pub B, pub BlockSenders);
+
+impl<B: BitswapStore<Params = DefaultParams>> BitswapStore for SenderStorage<B> {
+    type Params = DefaultParams;
+
+    fn contains(&mut self, cid: &Cid) -> libipld::Result<bool> {
+        self.0.contains(cid)
+    }
+
+    fn get(&mut self, cid: &Cid) -> libipld::Result<Option<Vec<u8>>> {
+        self.0.get(cid)
+    }
+
+    fn insert(&mut self, block: &Block<Self::Params>) -> libipld::Result<()> {
+        match self.0.insert(block) {
+            Ok(()) => {
+                self.1.stored(block);
+                Ok(())
+            }
+            Err(err) => {
+                self.1.rejected(block.cid());
+                Err(err)
+            }
+        }
+    }
+
+    fn missing_blocks(&mut self, cid: &Cid) -> libipld::Result<Vec<Cid>> {
+        self.0.missing_blocks(cid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::MemoryDB;
+    use libipld::{cbor::DagCborCodec, ipld, multihash::Code};
+    use ursa_store::{BitswapStorage, Store};
+
+    #[test]
+    fn test_block_senders() {
+        let store = Arc::new(Store::new(Arc::new(MemoryDB::default())));
+        let senders = BlockSenders::default();
+        let mut storage = SenderStorage(BitswapStorage(store), senders.clone());
+
+        let encode =
+            |ipld| Block::<DefaultParams>::encode(DagCborCodec, Code::Blake3_256, &ipld).unwrap();
+        let leaf = encode(ipld!("leaf"));
+        let root = encode(ipld!({ "leaf": *leaf.cid() }));
+        let other = encode(ipld!("other"));
+        let (peer, stranger) = (PeerId::random(), PeerId::random());
+        senders.expect(*root.cid(), peer);
+        senders.expect(*other.cid(), stranger);
+        storage.insert(&root).unwrap();
+
+        // the corrupt leaf came from the peer asked for its root
+        let corrupt = Block::<DefaultParams>::new_unchecked(*leaf.cid(), b"corrupt".to_vec());
+        assert!(storage.insert(&corrupt).is_err());
+        assert_eq!(senders.take(), vec![(*leaf.cid(), Some(peer))]);
+        assert!(senders.take().is_empty());
+
+        senders.finish(root.cid());
+        assert!(storage.insert(&corrupt).is_err());
+        assert_eq!(senders.take(), vec![(*leaf.cid(), None)]);
+        assert_eq!(senders.0.lock().unwrap().expected.len(), 1);
+    }
+}
@@ -14,21 +14,30 @@
 use anyhow::{anyhow, Result};
 
 use async_std::{
-    channel::{unbounded, Receiver, Sender},
-    task,
+    channel::{bounded, unbounded, Receiver, Sender},
+    future, task,
 };
 
 use cid::Cid;
 use fnv::FnvHashMap;
 use forest_ipld::Ipld;
-use futures::{channel::oneshot, select};
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{join_all, try_join_all},
+    select, FutureExt,
+};
+use futures_timer::Delay;
 use futures_util::stream::StreamExt;
 use ipld_blockstore::BlockStore;
 use libipld::DefaultParams;
 use libp2p::{
     autonat::NatStatus,
-    core::multiaddr::Protocol,
-    gossipsub::{GossipsubMessage, IdentTopic as Topic, TopicHash},
+    core::{
+        multiaddr::Protocol,
+        muxing::StreamMuxerBox,
+        transport::{Boxed, ListenerId},
+    },
+    gossipsub::{GossipsubMessage, IdentTopic as Topic},
     identity::Keypair,
     relay::v2::client::Client as RelayClient,
     request_response::{RequestId, ResponseChannel},
@@ -36,41 +45,90 @@ use libp2p::{
     Multiaddr, PeerId, Swarm,
 };
 use libp2p_bitswap::{BitswapEvent, BitswapStore};
-use rand::seq::SliceRandom;
 use std::{
+    cmp::Ordering,
     collections::HashSet,
+    fmt, iter,
     num::{NonZeroU8, NonZeroUsize},
     str::FromStr,
     sync::Arc,
+    time::{Duration, Instant},
 };
 use tracing::{debug, error, info, warn};
 use ursa_index_provider::{
     advertisement::{Advertisement, MAX_ENTRIES},
+    announce::AnnounceStatus,
+    config::AnnounceMode,
     provider::{Provider, ProviderInterface},
 };
 use ursa_metrics::events::{track, MetricEvent};
 use ursa_store::{BitswapStorage, Dag, Store};
 
 use crate::{
-    behaviour::{Behaviour, BehaviourEvent, BitswapInfo, BlockSenderChannel},
-    codec::protocol::{UrsaExchangeRequest, UrsaExchangeResponse},
+    accounting::Accounting,
+    acl::{Acl, AclStorage},
+    behaviour::{Behaviour, BehaviourEvent, BitswapInfo, BlockSenderChannel, NetworkEvent},
+    cache_summary::CachedRoots,
+    codec::{
+        car::{self, CarSource},
+        protocol::{
+            CarStream, ContentProvider, DagSelector, RequestType, ResponseType,
+            UrsaExchangeRequest, UrsaExchangeResponse, MAX_CAR_SIZE,
+        },
+    },
+    dag_sync::{DagSyncManager, SyncProgress, SyncStep},
+    events::{NodeEvent, NodeEvents},
+    gossipsub::{GossipTopicStat, PublishQueue, TopicPeers},
+    hints::{ContentAnnouncement, URSA_CONTENT},
+    info::NodeInfo,
+    ingest::{self, IngestAnnouncement, IngestCache, Ingested},
+    inventory::ProtocolInventory,
+    names::{self, NameRecord, URSA_NAMES},
+    purge::PurgeMessage,
+    registry::Registry,
+    relay::RelayCircuit,
+    replication::{ReplicationAnnouncement, ReplicationManager},
+    reputation::{self, ReputationStore},
+    senders::{BlockSenders, SenderStorage},
+    shaping::{ShapedStorage, Shaper},
     transport::UrsaTransport,
+    worker::WorkerPool,
     NetworkConfig,
 };
 use metrics::Label;
-use ursa_utils::convert_cid;
+use ursa_utils::{ToCid, ToIpldCid};
 
 pub const URSA_GLOBAL: &str = "/ursa/global";
 pub const MESSAGE_PROTOCOL: &[u8] = b"/ursa/message/0.0.1";
 pub const LOCAL_ADDRESSES: [&'static str; 2] = ["/ip4/127.0.0.1/tcp/6009", "/ip4/0.0.0.0/tcp/6009"];
+/// Number of peers asked for providers before falling back to a Kademlia walk.
+const FIND_CONTENT_FANOUT: usize = 3;
+/// Time given to peers to answer a `FindContent` request.
+const FIND_CONTENT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Interval at which queued gossip publishes are checked for a retry.
+const PUBLISH_RETRY_TICK: Duration = Duration::from_secs(1);
+/// Longest wait for a block bitswap found to be written, and the longest delay
+/// between two checks.
+const STORED_TIMEOUT: Duration = Duration::from_secs(5);
+const STORED_MAX_BACKOFF: Duration = Duration::from_millis(200);
 
 pub enum UrsaCommand {
     GetBitswap {
         cid: Cid,
         query: BitswapType,
+        /// Provider hints as `/p2p` addresses. When given, only these peers are
+        /// queried instead of routing the query over the connected peers.
+        providers: Vec<Multiaddr>,
         sender: BlockSenderChannel<()>,
     },
 
+    /// Cancel the bitswap queries and the dag sync of `cid`, failing its waiters
+    /// with [`Cancelled`]. Answers whether a query was running.
+    CancelBitswap {
+        cid: Cid,
+        sender: oneshot::Sender<bool>,
+    },
+
     Put {
         cid: Cid,
         sender: oneshot::Sender<Result<()>>,
@@ -85,6 +143,28 @@ pub enum UrsaCommand {
         sender: oneshot::Sender<Result<Vec<Cid>>>,
     },
 
+    /// Stop providing the content under root cids that was deleted or evicted, and
+    /// advertise its removal to the indexer.
+    Unindex {
+        cids: Vec<Cid>,
+        sender: oneshot::Sender<Result<Vec<Cid>>>,
+    },
+
+    /// Stop providing the root cids grouped under `context_id`, advertising their
+    /// removal with a single ad. Answers with the roots of the context.
+    UnindexContext {
+        context_id: Vec<u8>,
+        sender: oneshot::Sender<Result<Vec<Cid>>>,
+    },
+
+    /// Group root cids under `context_id`. Those already stored are withdrawn from the
+    /// context they were advertised under and advertised again under the new one.
+    SetContext {
+        cids: Vec<Cid>,
+        context_id: Vec<u8>,
+        sender: oneshot::Sender<Result<()>>,
+    },
+
     SendRequest {
         peer_id: PeerId,
         request: UrsaExchangeRequest,
@@ -101,6 +181,94 @@ pub enum UrsaCommand {
         topic: Topic,
         message: GossipsubMessage,
     },
+
+    /// Content under a root cid was requested from this node.
+    ContentRequested { cid: Cid },
+
+    GossipStat {
+        sender: oneshot::Sender<Vec<GossipTopicStat>>,
+    },
+
+    /// Subscribers of the gossipsub topics, or of `topic`.
+    TopicPeers {
+        topic: Option<String>,
+        sender: oneshot::Sender<Vec<TopicPeers>>,
+    },
+
+    /// Circuits relayed by this node.
+    RelayCircuits {
+        sender: oneshot::Sender<Vec<RelayCircuit>>,
+    },
+
+    /// Agents and protocols of the identified peers.
+    PeerProtocols {
+        protocol: Option<String>,
+        sender: oneshot::Sender<ProtocolInventory>,
+    },
+
+    /// Identity, addresses and reachability of this node.
+    NodeInfo { sender: oneshot::Sender<NodeInfo> },
+
+    /// Pending and failed indexer announcements.
+    ProviderStatus {
+        sender: oneshot::Sender<AnnounceStatus>,
+    },
+
+    /// Context ids the root `cids` are advertised under, in the same order.
+    ContextIds {
+        cids: Vec<Cid>,
+        sender: oneshot::Sender<Result<Vec<Vec<u8>>>>,
+    },
+
+    /// Report the progress of the dag sync of `root`. The sender is dropped once the
+    /// sync finishes.
+    WatchSync {
+        root: Cid,
+        sender: mpsc::UnboundedSender<SyncProgress>,
+    },
+
+    /// Pull the blocks under `root` from `peer_id` as a single car file.
+    GetCar {
+        peer_id: PeerId,
+        root: Cid,
+        selector: DagSelector,
+        sender: oneshot::Sender<Result<Vec<Cid>>>,
+    },
+
+    /// Find the providers of `cid`, asking the nearest peers first.
+    FindContent {
+        cid: Cid,
+        sender: BlockSenderChannel<Vec<ContentProvider>>,
+    },
+
+    /// Find the providers of `cid` with a Kademlia walk, streaming them as they are
+    /// found. The sender is dropped when the walk completes.
+    GetProviders {
+        cid: Cid,
+        sender: mpsc::UnboundedSender<ContentProvider>,
+    },
+
+    /// Point the name of this node at `cid` for `ttl_secs`, answering with the
+    /// signed record.
+    PublishName {
+        cid: Cid,
+        ttl_secs: u64,
+        sender: oneshot::Sender<Result<NameRecord>>,
+    },
+
+    /// Find the newest record of `name`.
+    ResolveName {
+        name: String,
+        sender: oneshot::Sender<Option<NameRecord>>,
+    },
+
+    /// Sign a purge of `cids` and `context_ids` and gossip it to the nodes obeying
+    /// the key of this node, answering with the signed message.
+    Purge {
+        cids: Vec<String>,
+        context_ids: Vec<String>,
+        sender: oneshot::Sender<Result<PurgeMessage>>,
+    },
 }
 
 pub enum BitswapType {
@@ -108,12 +276,28 @@ pub enum BitswapType {
     Sync,
 }
 
+/// A bitswap query was cancelled before it completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled(pub Cid);
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The query for {} was cancelled", self.0)
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
 #[derive(Debug)]
 pub enum UrsaEvent {
     /// An event trigger when remote peer connects.
     PeerConnected(PeerId),
     /// An event trigger when remote peer disconnects.
     PeerDisconnected(PeerId),
+    /// An event trigger when the swarm starts listening on a new address.
+    NewListenAddr(Multiaddr),
+    /// The addresses other hosts can reach the node on changed.
+    ExternalAddrsChanged(Vec<Multiaddr>),
     BitswapEvent(BitswapEvent),
     /// A Gossip message request was received from a peer.
     GossipsubMessage(GossipsubMessage),
@@ -125,6 +309,270 @@ pub enum UrsaEvent {
     },
 }
 
+/// Results of store work done by the [`WorkerPool`], applied to the swarm on the service loop.
+enum WorkResult {
+    /// Answer an exchange request.
+    Response {
+        channel: ResponseChannel<UrsaExchangeResponse>,
+        response: UrsaExchangeResponse,
+    },
+    /// Gossip an index provider announcement, falling back to http.
+    Announce(Vec<u8>),
+    /// A block wanted by a dag sync arrived, or could not be found.
+    SyncProgress {
+        block: Cid,
+        found: bool,
+        /// Blocks under `block` that are not stored yet.
+        missing: Vec<Cid>,
+        /// Size of `block`.
+        bytes: u64,
+    },
+    /// A peer asked to replicate `root`, which is not stored yet.
+    Replicate { root: Cid, peer: PeerId },
+    /// The dag under `root` was ingested or synced, announce it.
+    ContentStored { root: Cid, size: u64 },
+    /// An advertisement announced on the ingest topic was fetched.
+    Ingested(Ingested),
+}
+
+/// Want the next blocks of the running dag syncs and answer the finished ones.
+fn apply_sync_step(
+    behaviour: &mut Behaviour<DefaultParams>,
+    channels: &mut FnvHashMap<Cid, Vec<BlockSenderChannel<()>>>,
+    watchers: &mut FnvHashMap<Cid, Vec<mpsc::UnboundedSender<SyncProgress>>>,
+    step: SyncStep,
+) {
+    for (cid, providers) in step.wants {
+        behaviour.get_block(cid, providers.into_iter());
+    }
+
+    for (root, complete) in step.done {
+        debug!("dag sync of {} finished, complete: {}", root, complete);
+        watchers.remove(&root);
+        for chan in channels.remove(&root).unwrap_or_default() {
+            let result = if complete {
+                Ok(())
+            } else {
+                Err(anyhow!(
+                    "The dag under {:?} could not be fetched from any peer",
+                    root
+                ))
+            };
+            if chan.send(result).is_err() {
+                error!("[SyncStep] - Bitswap response channel send failed");
+            }
+        }
+    }
+}
+
+/// Send `progress` to the watchers of its root, forgetting the ones that went away.
+fn notify_sync(
+    watchers: &mut FnvHashMap<Cid, Vec<mpsc::UnboundedSender<SyncProgress>>>,
+    progress: SyncProgress,
+) {
+    if let Some(senders) = watchers.get_mut(&progress.root) {
+        senders.retain(|sender| sender.unbounded_send(progress.clone()).is_ok());
+        if senders.is_empty() {
+            watchers.remove(&progress.root);
+        }
+    }
+}
+
+/// Strip the `/p2p/<peer id>` suffix of an address for the indexer, keeping its
+/// transport. Relayed addresses are not announced.
+fn announce_address(addr: &Multiaddr) -> Option<Multiaddr> {
+    if addr
+        .iter()
+        .any(|protocol| matches!(protocol, Protocol::P2pCircuit))
+    {
+        return None;
+    }
+    Some(
+        addr.iter()
+            .take_while(|protocol| !matches!(protocol, Protocol::P2p(_)))
+            .collect(),
+    )
+}
+
+/// Whether `cid` is stored, checked on a store thread.
+async fn is_stored<S>(store: &Arc<Store<S>>, cid: Cid) -> bool
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    store
+        .blocking(move |store| store.blockstore().has(&cid).unwrap_or(false))
+        .await
+        .unwrap_or(false)
+}
+
+/// Wait for `cid` to be written, checking with a growing delay for [`STORED_TIMEOUT`].
+async fn wait_stored<S>(store: &Arc<Store<S>>, cid: Cid) -> bool
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    let started = Instant::now();
+    let mut backoff = Duration::from_millis(5);
+    while !is_stored(store, cid).await {
+        if started.elapsed() >= STORED_TIMEOUT {
+            return false;
+        }
+        task::sleep(backoff).await;
+        backoff = (backoff * 2).min(STORED_MAX_BACKOFF);
+    }
+    true
+}
+
+/// Create the advertisement `ad` of `entries` and publish it.
+async fn advertise<S>(provider: &Provider<S>, ad: Advertisement, entries: &[Ipld]) -> Result<()>
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    let id = provider.create(ad).await?;
+    for chunk in entries.chunks(MAX_ENTRIES) {
+        let entries_bytes = forest_encoding::to_vec(&chunk)?;
+        provider.add_chunk(entries_bytes, id).await?;
+    }
+    provider.publish(id).await
+}
+
+/// The blocks under `root` selected by `selector`, read from the store as the car file
+/// is written to the peer. Dags over [`MAX_CAR_SIZE`] are refused, the peer would not
+/// take them.
+async fn car_source<S>(store: &Arc<Store<S>>, root: Cid, selector: DagSelector) -> Result<CarSource>
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    let blocks = store
+        .blocking(move |store| -> Result<_> {
+            Ok(match selector {
+                DagSelector::All => store
+                    .dag_blocks(&root.to_ipld_cid())?
+                    .into_iter()
+                    .map(|(cid, size)| (cid.to_cid(), size))
+                    .collect(),
+                DagSelector::Root => {
+                    let data = store
+                        .blockstore()
+                        .get(&root)?
+                        .ok_or_else(|| anyhow!("The block {} is not stored locally", root))?;
+                    vec![(root, data.len())]
+                }
+            })
+        })
+        .await??;
+
+    let source = CarSource::from_store(store, root, blocks);
+    if source.len() > MAX_CAR_SIZE {
+        return Err(anyhow!("The dag {} is over {} bytes", root, MAX_CAR_SIZE));
+    }
+    Ok(source)
+}
+
+/// Peer id of a `/p2p` address.
+fn provider_peer(addr: &Multiaddr) -> Option<PeerId> {
+    addr.iter().find_map(|protocol| match protocol {
+        Protocol::P2p(hash) => PeerId::from_multihash(hash).ok(),
+        _ => None,
+    })
+}
+
+/// Whether a listen address can be dialed by other hosts.
+fn is_routable(addr: &Multiaddr) -> bool {
+    addr.iter().all(|protocol| match protocol {
+        Protocol::Ip4(ip) => !ip.is_unspecified() && !ip.is_loopback(),
+        Protocol::Ip6(ip) => !ip.is_unspecified() && !ip.is_loopback(),
+        _ => true,
+    })
+}
+
+/// Addresses other hosts can reach the node on: the public address confirmed by
+/// autonat and the routable listen addresses, relayed ones excluded.
+fn external_addrs(swarm: &Swarm<Behaviour<DefaultParams>>) -> Vec<Multiaddr> {
+    let mut addrs: Vec<Multiaddr> = swarm
+        .behaviour()
+        .public_address()
+        .cloned()
+        .into_iter()
+        .collect();
+    for addr in swarm.listeners().filter(|addr| is_routable(addr)) {
+        if !addrs.contains(addr) {
+            addrs.push(addr.clone());
+        }
+    }
+    addrs.retain(|addr| !addr.iter().any(|protocol| protocol == Protocol::P2pCircuit));
+    addrs
+}
+
+/// Push the addresses and protocols of the node to the connected peers, so their
+/// address books do not wait for the periodic identify.
+fn push_identify(swarm: &mut Swarm<Behaviour<DefaultParams>>) {
+    let peers: Vec<PeerId> = swarm.connected_peers().copied().collect();
+    if !peers.is_empty() {
+        debug!("Pushing identify to {} peers", peers.len());
+        swarm.behaviour_mut().push_identify(peers);
+    }
+}
+
+/// Addresses other nodes can fetch content from this node on.
+fn provider_addrs(swarm: &Swarm<Behaviour<DefaultParams>>) -> Vec<String> {
+    let mut addrs: Vec<String> = swarm
+        .behaviour()
+        .public_address()
+        .map(Multiaddr::to_string)
+        .into_iter()
+        .collect();
+    for addr in swarm.listeners().filter(|addr| is_routable(addr)) {
+        let addr = addr.to_string();
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+    addrs
+}
+
+/// Publish `message` on `topic`, queueing it for a retry when the topic has too few
+/// peers yet.
+fn publish_or_queue(
+    behaviour: &mut Behaviour<DefaultParams>,
+    queue: &mut PublishQueue,
+    topic: Topic,
+    message: GossipsubMessage,
+) -> Result<()> {
+    let err = match behaviour.publish(topic.clone(), message.clone()) {
+        Ok(_) => return Ok(()),
+        Err(err) => err,
+    };
+    if behaviour.can_publish(&topic.hash()) || !queue.push(topic, message) {
+        return Err(err);
+    }
+    debug!("Queued a publish until the mesh is ready: {:?}", err);
+    Ok(())
+}
+
+/// Hand the current external addresses to the provider and the registry, and publish
+/// them on the event bus when they changed.
+async fn refresh_external_addrs<S>(
+    swarm: &Swarm<Behaviour<DefaultParams>>,
+    provider: &Provider<S>,
+    registry: Option<&Registry>,
+    event_sender: &Sender<UrsaEvent>,
+) where
+    S: BlockStore + Sync + Send + 'static,
+{
+    let addrs = external_addrs(swarm);
+    if let Some(registry) = registry {
+        registry.set_addrs(addrs.clone());
+    }
+    if provider.set_network_addrs(addrs.clone()).await
+        && event_sender
+            .send(UrsaEvent::ExternalAddrsChanged(addrs))
+            .await
+            .is_err()
+    {
+        warn!("[UrsaEvent::ExternalAddrsChanged] - failed to send external addresses");
+    }
+}
+
 pub struct UrsaService<S> {
     /// Store
     store: Arc<Store<S>>,
@@ -138,10 +586,42 @@ pub struct UrsaService<S> {
     event_sender: Sender<UrsaEvent>,
     /// Handles events received by the ursa network
     event_receiver: Receiver<UrsaEvent>,
+    /// Serializable events for dashboards.
+    node_events: NodeEvents,
     /// hashmap for keeping track of rpc response channels
     response_channels: FnvHashMap<Cid, Vec<BlockSenderChannel<()>>>,
+    /// Response channels of dag syncs, keyed by root cid.
+    sync_channels: FnvHashMap<Cid, Vec<BlockSenderChannel<()>>>,
+    /// Listeners of the progress of dag syncs, keyed by root cid.
+    sync_watchers: FnvHashMap<Cid, Vec<mpsc::UnboundedSender<SyncProgress>>>,
+    /// Block by block dag syncs, unset when a sync is a single bitswap query.
+    dag_syncs: Option<DagSyncManager>,
     /// index provider
     index_provider: Provider<S>,
+    /// Tracks request rates to replicate hot content.
+    replication: ReplicationManager,
+    /// Runs store heavy work off the service loop.
+    workers: WorkerPool<WorkResult>,
+    /// Results of the work done by `workers`.
+    work_results: Receiver<WorkResult>,
+    /// Private dags and who may retrieve them.
+    acl: Arc<Acl>,
+    /// Upload caps of the node.
+    shaper: Arc<Shaper>,
+    /// Bytes served per api key, peer and cid.
+    accounting: Arc<Accounting>,
+    /// Registers the node with the configured registry.
+    registry: Option<Arc<Registry>>,
+    /// Signs the name records of the node.
+    keypair: Keypair,
+    /// Name record last published by the node.
+    published: Option<NameRecord>,
+    /// Gossip publishes waiting for peers on their topic.
+    publish_queue: PublishQueue,
+    /// Whether ingested and synced dags are announced on the content topic.
+    announce_content: bool,
+    /// Providers announced to the indexers, unset when the ingest topic is not followed.
+    ingest: Option<IngestCache>,
 }
 
 impl<S> UrsaService<S>
@@ -159,14 +639,26 @@ where
     /// For ursa behaviour we use [`Behaviour`].
     ///
     /// We construct a [`Swarm`] with [`UrsaTransport`] and [`Behaviour`]
-    /// listening on [`NetworkConfig`] `swarm_addr`.
+    /// listening on every [`NetworkConfig`] `swarm_addrs` entry.
     ///
     pub fn new(
         keypair: Keypair,
         config: &NetworkConfig,
         store: Arc<Store<S>>,
         index_provider: Provider<S>,
-    ) -> Self {
+    ) -> Result<Self> {
+        Self::with_transport(keypair, config, store, index_provider, None)
+    }
+
+    /// Service over `transport` when given, instead of the [`UrsaTransport`] of the
+    /// config, e.g. the memory transport of the tests.
+    pub(crate) fn with_transport(
+        keypair: Keypair,
+        config: &NetworkConfig,
+        store: Arc<Store<S>>,
+        index_provider: Provider<S>,
+        transport: Option<Boxed<(PeerId, StreamMuxerBox)>>,
+    ) -> Result<Self> {
         let local_peer_id = PeerId::from(keypair.public());
 
         let (relay_transport, relay_client) = if config.relay_client {
@@ -181,11 +673,43 @@ where
             (None, None)
         };
 
-        let transport = UrsaTransport::new(&keypair, config, relay_transport);
+        let shaper = Arc::new(Shaper::new(&config.bandwidth));
+        let transport = transport.unwrap_or_else(|| {
+            UrsaTransport::new(&keypair, config, relay_transport, shaper.global())
+        });
 
-        let bitswap_store = BitswapStorage(store.clone());
+        // starting with no entries would make every private dag public
+        let acl = Arc::new(
+            Acl::load(&store)
+                .map_err(|err| anyhow!("Failed to load the access control lists: {:?}", err))?,
+        );
+        let accounting = Arc::new(Accounting::new(config.accounting.clone()));
+        Arc::clone(&accounting).schedule(store.clone());
+        let senders = BlockSenders::default();
+        let bitswap_store = ShapedStorage(
+            SenderStorage(
+                AclStorage(BitswapStorage(store.clone()), Arc::clone(&acl)),
+                senders.clone(),
+            ),
+            Arc::clone(&shaper),
+        );
 
-        let behaviour = Behaviour::new(&keypair, config, bitswap_store, relay_client);
+        let mut behaviour = Behaviour::new(
+            &keypair,
+            config,
+            bitswap_store,
+            senders,
+            relay_client,
+            Arc::clone(&shaper),
+        );
+        match CachedRoots::load(&store) {
+            Ok(roots) => behaviour.set_cached_roots(roots),
+            Err(err) => error!("Failed to load the cached roots: {:?}", err),
+        }
+        match ReputationStore::load(&store, config.reputation_path.as_deref()) {
+            Ok(reputation) => behaviour.set_reputation(reputation),
+            Err(err) => error!("Failed to load the peer reputations: {:?}", err),
+        }
 
         let limits = ConnectionLimits::default()
             .with_max_pending_incoming(Some(2 << 9))
@@ -197,16 +721,31 @@ where
         let mut swarm = SwarmBuilder::new(transport, behaviour, local_peer_id)
             .notify_handler_buffer_size(NonZeroUsize::new(2 << 7).unwrap())
             .connection_event_buffer_size(2 << 7)
-            .dial_concurrency_factor(NonZeroU8::new(8).unwrap())
+            .dial_concurrency_factor(
+                NonZeroU8::new(config.dialing.concurrency_factor)
+                    .unwrap_or(NonZeroU8::new(8).unwrap()),
+            )
             .connection_limits(limits)
             .executor(Box::new(|future| {
                 task::spawn(future);
             }))
             .build();
 
-        swarm.listen_on(config.swarm_addr.clone()).unwrap();
+        for addr in &config.swarm_addrs {
+            if let Err(error) = swarm.listen_on(addr.clone()) {
+                error!("Failed to listen on {}: {}", addr, error);
+            }
+        }
+
+        // dial the bootstrap nodes with the best reputation first
+        let mut bootstrap_nodes = config.bootstrap_nodes.clone();
+        let reputation = swarm.behaviour().reputation();
+        bootstrap_nodes.sort_by(|a, b| {
+            let score = |addr: &Multiaddr| provider_peer(addr).map(|peer| reputation.score(&peer));
+            score(b).partial_cmp(&score(a)).unwrap_or(Ordering::Equal)
+        });
 
-        for to_dial in &config.bootstrap_nodes {
+        for to_dial in &bootstrap_nodes {
             swarm
                 .dial(to_dial.clone())
                 .map_err(|err| anyhow!("{}", err))
@@ -219,29 +758,108 @@ where
             warn!("Failed to subscribe with topic: {}", error);
         }
 
+        if let Err(error) = swarm.behaviour_mut().subscribe(&Topic::new(URSA_NAMES)) {
+            warn!("Failed to subscribe to the names topic: {}", error);
+        }
+
+        if config.content_hints.max_hints > 0 {
+            if let Err(error) = swarm.behaviour_mut().subscribe(&Topic::new(URSA_CONTENT)) {
+                warn!("Failed to subscribe to the content topic: {}", error);
+            }
+        }
+
+        if let Some(purge) = &config.purge {
+            if let Err(error) = swarm.behaviour_mut().subscribe(&Topic::new(purge.topic())) {
+                warn!("Failed to subscribe to the purge topic: {}", error);
+            }
+        }
+
+        if config.ingest.is_some() {
+            let topic = Topic::new(index_provider.announce_topic());
+            if let Err(error) = swarm.behaviour_mut().subscribe(&topic) {
+                warn!("Failed to subscribe to the ingest topic: {}", error);
+            }
+        }
+
         // boostrap with kademlia
         if let Err(error) = swarm.behaviour_mut().bootstrap() {
             warn!("Failed to bootstrap with Kademlia: {}", error);
         }
 
         let (event_sender, event_receiver) = unbounded();
-        let (command_sender, command_receiver) = unbounded();
+        let (command_sender, command_receiver) = bounded(config.command_queue_size.max(1));
+        let (workers, work_results) = WorkerPool::new(&config.workers);
+        let registry = config.registry.clone().map(|registry| {
+            Arc::new(Registry::new(
+                registry,
+                keypair.clone(),
+                config.bandwidth.global,
+            ))
+        });
+        let published = names::load_published(&store).unwrap_or_else(|err| {
+            error!("Failed to load the published name record: {:?}", err);
+            None
+        });
 
-        UrsaService {
+        Ok(UrsaService {
             swarm,
             store,
             command_sender,
             command_receiver,
             event_sender,
             event_receiver,
+            node_events: Default::default(),
             response_channels: Default::default(),
+            sync_channels: Default::default(),
+            sync_watchers: Default::default(),
+            dag_syncs: (config.sync_parallelism > 0)
+                .then(|| DagSyncManager::new(config.sync_parallelism)),
             index_provider,
-        }
+            replication: ReplicationManager::new(config.replication.clone()),
+            workers,
+            work_results,
+            acl,
+            shaper,
+            accounting,
+            registry,
+            keypair,
+            published,
+            publish_queue: PublishQueue::new(&config.gossip),
+            announce_content: config.content_hints.announce,
+            ingest: config.ingest.as_ref().map(IngestCache::new),
+        })
     }
 
     pub fn command_sender(&self) -> &Sender<UrsaCommand> {
         &self.command_sender
     }
+
+    /// Events of the node, for dashboards.
+    pub fn node_events(&self) -> NodeEvents {
+        self.node_events.clone()
+    }
+
+    /// Access control lists of the dags served by the node.
+    pub fn acl(&self) -> Arc<Acl> {
+        Arc::clone(&self.acl)
+    }
+
+    /// Upload caps, shared with the http gateway.
+    pub fn shaper(&self) -> Arc<Shaper> {
+        Arc::clone(&self.shaper)
+    }
+
+    /// Bandwidth accounting, shared with the http gateway.
+    pub fn accounting(&self) -> Arc<Accounting> {
+        Arc::clone(&self.accounting)
+    }
+
+    /// Dial `addr`, the connection is established once the service is started.
+    #[cfg(test)]
+    pub(crate) fn dial(&mut self, addr: Multiaddr) -> Result<()> {
+        self.swarm.dial(addr).map_err(|err| anyhow!("{}", err))
+    }
+
     /// Start the ursa network service loop.
     ///
     /// Poll `swarm` and `command_receiver` from [`UrsaService`].
@@ -254,15 +872,36 @@ where
         info!("Node starting up with peerId {:?}", peer_id);
 
         let mut swarm = self.swarm.fuse();
-        let mut blockstore = BitswapStorage(self.store.clone());
+        let command_queue = self.command_receiver.clone();
         let mut command_receiver = self.command_receiver.fuse();
+        let mut work_results = self.work_results.fuse();
+
+        if let Some(registry) = &self.registry {
+            Arc::clone(registry).start();
+        }
+
+        // records live in the dht for a while only, put the name again on start
+        if let Some(record) = self
+            .published
+            .clone()
+            .filter(|record| record.verify().is_ok())
+        {
+            if let Err(err) = swarm.get_mut().behaviour_mut().publish_name(record) {
+                warn!("Failed to republish the name of the node: {:?}", err);
+            }
+        }
+
+        // listener on the relay autorelay picked, and the relay
+        let mut relay_listener: Option<(ListenerId, PeerId)> = None;
+        let mut publish_retry = Delay::new(PUBLISH_RETRY_TICK).fuse();
+        let mut state_flush = Delay::new(reputation::FLUSH_INTERVAL).fuse();
 
         loop {
             select! {
                 event = swarm.next() => {
                     if let Some(event) = event {
                         match event {
-                            SwarmEvent::Behaviour(event) => match event {
+                            SwarmEvent::Behaviour(NetworkEvent::Ursa(event)) => match event {
                                 BehaviourEvent::Bitswap(BitswapInfo {cid, query_id, block_found })=> {
                                     swarm.get_mut().behaviour_mut().cancel(query_id);
                                     let labels = vec![
@@ -272,29 +911,67 @@ where
                                     ];
 
                                     track(MetricEvent::Bitswap, Some(labels), None);
+                                    self.node_events.publish(NodeEvent::BitswapCompleted { cid: cid.to_string(), found: block_found });
 
-                                    if let Some (chans) = self.response_channels.remove(&cid) {
-                                        // TODO: in some cases, the insert takes few milliseconds after query complete is received
-                                        // wait for block to be inserted
-                                        let bitswap_cid = convert_cid(cid.to_bytes());
-                                        if let true = block_found { loop { if blockstore.contains(&bitswap_cid).unwrap() { break; } } }
-
-                                        for chan in chans.into_iter(){
-                                            if blockstore.contains(&bitswap_cid).unwrap() {
-                                                if chan.send(Ok(())).is_err() {
-                                                    error!("[BehaviourEvent::Bitswap] - Bitswap response channel send failed");
-                                                }
+                                    let chans = self.response_channels.remove(&cid).unwrap_or_default();
+                                    let wanted = self.dag_syncs.as_ref().map_or(false, |syncs| syncs.is_wanted(&cid));
+                                    if !wanted {
+                                        // a single query sync is over once its query completes
+                                        self.sync_watchers.remove(&cid);
+                                    }
+                                    if !chans.is_empty() || wanted {
+                                        let store = self.store.clone();
+                                        self.workers.submit(async move {
+                                            // the insert may land a few milliseconds after the query completes
+                                            let found = if block_found {
+                                                wait_stored(&store, cid).await
                                             } else {
-                                                error!("[BehaviourEvent::Bitswap] - block not found.");
-                                                if chan.send(Err(anyhow!("The requested block with cid {:?} is not found with any peers", cid))).is_err() {
+                                                is_stored(&store, cid).await
+                                            };
+                                            for chan in chans.into_iter() {
+                                                let result = if found {
+                                                    Ok(())
+                                                } else {
+                                                    error!("[BehaviourEvent::Bitswap] - block not found.");
+                                                    Err(anyhow!("The requested block with cid {:?} is not found with any peers", cid))
+                                                };
+                                                if chan.send(result).is_err() {
                                                     error!("[BehaviourEvent::Bitswap] - Bitswap response channel send failed");
                                                 }
                                             }
-                                        }
+
+                                            if !wanted {
+                                                return None;
+                                            }
+                                            // expand the block into the rest of its dag
+                                            let (missing, bytes) = store.blocking(move |store| {
+                                                let mut blockstore = BitswapStorage(Arc::clone(store));
+                                                let bitswap_cid = cid.to_ipld_cid();
+                                                let missing = if found {
+                                                    blockstore.missing_blocks(&bitswap_cid).unwrap_or_else(|err| {
+                                                        warn!("[BehaviourEvent::Bitswap] - cannot read the links of {}: {:?}", cid, err);
+                                                        vec![]
+                                                    })
+                                                } else {
+                                                    vec![]
+                                                };
+                                                let bytes = blockstore.get(&bitswap_cid).ok().flatten().map_or(0, |data| data.len() as u64);
+                                                (missing, bytes)
+                                            }).await.unwrap_or_default();
+                                            Some(WorkResult::SyncProgress {
+                                                block: cid,
+                                                found,
+                                                missing: missing.iter().map(ToCid::to_cid).collect(),
+                                                bytes,
+                                            })
+                                        }).await;
                                     } else {
                                         debug!("[BehaviourEvent::Bitswap] - Received Bitswap response, but response channel cannot be found");
                                     }
                     },
+                                BehaviourEvent::BitswapProgress { cid, missing } => {
+                                    notify_sync(&mut self.sync_watchers, SyncProgress { root: cid, bytes: 0, missing });
+                                }
                                 BehaviourEvent::GossipMessage {
                                     peer,
                                     topic,
@@ -310,6 +987,28 @@ where
 
                                     track(MetricEvent::GossipMessage, Some(labels), None);
 
+                                    if let Some(cache) = &mut self.ingest {
+                                        if topic == Topic::new(provider.announce_topic()).hash() {
+                                            match IngestAnnouncement::parse(&message.data) {
+                                                Ok(Some(announcement)) if cache.announced(announcement.provider, announcement.ad) => {
+                                                    let config = cache.config().clone();
+                                                    let workers = self.workers.clone();
+                                                    // the fetch is network bound, keep it off the store workers
+                                                    task::spawn(async move {
+                                                        let ad = announcement.ad;
+                                                        match ingest::fetch(announcement, &config).await {
+                                                            Ok(Some(ingested)) => workers.submit(async move { Some(WorkResult::Ingested(ingested)) }).await,
+                                                            Ok(None) => {}
+                                                            Err(err) => debug!("[BehaviourEvent::Gossip] - failed to fetch the advertisement {}: {:?}", ad, err),
+                                                        }
+                                                    });
+                                                }
+                                                Ok(_) => {}
+                                                Err(err) => debug!("[BehaviourEvent::Gossip] - invalid ingest announcement from {}: {:?}", peer, err),
+                                            }
+                                        }
+                                    }
+
                                     if swarm_mut.is_connected(&peer) {
                                         let status = self
                                             .event_sender
@@ -332,7 +1031,27 @@ where
 
                                     track(MetricEvent::RequestMessage, Some(labels), None);
 
-                                    if self
+                                    if let UrsaExchangeRequest(RequestType::Replicate(root)) = &request {
+                                        let accepted = match Cid::from_str(root) {
+                                            Ok(cid) if self.replication.accepts_replicas() => {
+                                                let store = self.store.clone();
+                                                self.workers.submit(async move {
+                                                    (!is_stored(&store, cid).await).then_some(WorkResult::Replicate { root: cid, peer })
+                                                }).await;
+                                                true
+                                            }
+                                            Ok(_) => false,
+                                            Err(err) => {
+                                                warn!("[BehaviourEvent::RequestMessage] - invalid replication cid {}: {:?}", root, err);
+                                                false
+                                            }
+                                        };
+
+                                        let response = UrsaExchangeResponse(ResponseType::ReplicateResponse(accepted));
+                                        if let Err(err) = swarm.get_mut().behaviour_mut().send_response(channel, response) {
+                                            warn!("[BehaviourEvent::RequestMessage] - {:?}", err);
+                                        }
+                                    } else if self
                                         .event_sender
                                         .send(UrsaEvent::RequestMessage { request, channel })
                                         .await
@@ -345,6 +1064,7 @@ where
                                     debug!("[BehaviourEvent::PeerConnected] - Peer connected {:?}", peer);
 
                                     track(MetricEvent::PeerConnected, None, None);
+                                    self.node_events.publish(NodeEvent::PeerConnected { peer: peer.to_string() });
 
                                     if self
                                         .event_sender
@@ -359,6 +1079,7 @@ where
                                     debug!("[BehaviourEvent::PeerDisconnected] - Peer disconnected {:?}", peer);
 
                                     track(MetricEvent::PeerDisconnected, None, None);
+                                    self.node_events.publish(NodeEvent::PeerDisconnected { peer: peer.to_string() });
 
                                     if self
                                         .event_sender
@@ -371,26 +1092,25 @@ where
                                 }
                                 BehaviourEvent::NatStatusChanged{ old, new } => {
                                     let swarm = swarm.get_mut();
+                                    self.node_events.publish(match &new {
+                                        NatStatus::Public(addr) => NodeEvent::NatStatusChanged { status: "public".to_string(), address: Some(addr.to_string()) },
+                                        NatStatus::Private => NodeEvent::NatStatusChanged { status: "private".to_string(), address: None },
+                                        NatStatus::Unknown => NodeEvent::NatStatusChanged { status: "unknown".to_string(), address: None },
+                                    });
 
                                     match (old, new) {
-                                        (NatStatus::Unknown, NatStatus::Private) => {
-                                            let behaviour = swarm.behaviour_mut();
-                                            if behaviour.is_relay_client_enabled() {
-                                                // get random bootstrap node and listen on their relay
-                                                if let Some((relay_peer, relay_addr)) = behaviour
-                                                    .discovery()
-                                                    .bootstrap_addrs()
-                                                    .choose(&mut rand::thread_rng())
-                                                {
-                                                    let addr = relay_addr.clone().with(Protocol::P2p((*relay_peer).into())).with(Protocol::P2pCircuit);
-                                                    warn!("Private NAT detected. Establishing public relay address on peer {}", addr);
-                                                    swarm.listen_on(addr)
-                                                        .expect("failed to listen on relay");
-                                                }
-                                            }
+                                        (_, NatStatus::Private) => {
+                                            warn!("Private NAT detected. Looking for a relay to listen through");
+                                            swarm.behaviour_mut().want_relay(true);
                                         },
                                         (_, NatStatus::Public(addr)) => {
                                             info!("Public Nat verified! Public listening address: {}", addr);
+                                            push_identify(swarm);
+                                            swarm.behaviour_mut().want_relay(false);
+                                            if let Some((listener, _)) = relay_listener.take() {
+                                                swarm.remove_listener(listener);
+                                            }
+                                            refresh_external_addrs(swarm, &provider, self.registry.as_deref(), &self.event_sender).await;
                                             let public_address = addr.clone();
                                             swarm.behaviour_mut().publish_ad(public_address);
                                         },
@@ -407,73 +1127,269 @@ where
                                     debug!("Relay reservation closed for peer {}", peer_id);
                                     track(MetricEvent::RelayReservationClosed, None, None);
                                 }
-                                BehaviourEvent::RelayCircuitOpened => {
-                                    debug!("Relay circuit opened");
+                                BehaviourEvent::RelayCircuitOpened { src_peer_id, dst_peer_id, max_duration, max_bytes } => {
+                                    debug!("Relay circuit opened from {} to {}, limited to {:?} and {} bytes", src_peer_id, dst_peer_id, max_duration, max_bytes);
                                     track(MetricEvent::RelayCircuitOpened, None, None);
+                                    self.node_events.publish(NodeEvent::RelayCircuitOpened {
+                                        src: src_peer_id.to_string(),
+                                        dst: dst_peer_id.to_string(),
+                                        max_duration_secs: max_duration.as_secs(),
+                                        max_bytes,
+                                    });
                                 }
-                                BehaviourEvent::RelayCircuitClosed => {
-                                    debug!("Relay circuit closed");
+                                BehaviourEvent::RelayCircuitClosed { src_peer_id, dst_peer_id, duration, error } => {
+                                    debug!("Relay circuit from {} to {} closed after {:?}: {:?}", src_peer_id, dst_peer_id, duration, error);
                                     track(MetricEvent::RelayCircuitClosed, None, None);
+                                    self.node_events.publish(NodeEvent::RelayCircuitClosed {
+                                        src: src_peer_id.to_string(),
+                                        dst: dst_peer_id.to_string(),
+                                        duration_secs: duration.as_secs(),
+                                        error,
+                                    });
                                 }
-                                BehaviourEvent::StartPublish { public_address } => {
-                                    let mut address = Multiaddr::empty();
-                                    for protocol in public_address.into_iter() {
-                                        match protocol {
-                                            Protocol::Ip6(ip) => address.push(Protocol::Ip6(ip)),
-                                            Protocol::Ip4(ip) => address.push(Protocol::Ip4(ip)),
-                                            Protocol::Tcp(port) =>  address.push(Protocol::Tcp(port)),
-                                            _ => {},
+                                BehaviourEvent::ProtocolsChanged => {
+                                    push_identify(swarm.get_mut());
+                                }
+                                BehaviourEvent::RelaySelected { relay_peer_id, addr } => {
+                                    let swarm = swarm.get_mut();
+                                    if let Some((listener, _)) = relay_listener.take() {
+                                        swarm.remove_listener(listener);
+                                    }
+                                    match swarm.listen_on(addr.clone()) {
+                                        Ok(listener) => {
+                                            info!("Listening through the relay {}", addr);
+                                            relay_listener = Some((listener, relay_peer_id));
+                                            self.node_events.publish(NodeEvent::RelaySelected {
+                                                relay: relay_peer_id.to_string(),
+                                                address: addr.to_string(),
+                                            });
+                                        }
+                                        Err(err) => {
+                                            warn!("[BehaviourEvent::RelaySelected] - failed to listen on {}: {:?}", addr, err);
+                                            swarm.behaviour_mut().relay_failed(relay_peer_id);
                                         }
                                     }
-                                    let root_cids = provider.get_mut_root_cids();
-                                    let mut cid_queue = root_cids.write().await;
-                                    while !cid_queue.is_empty() {
-                                        let root_cid = cid_queue.pop_front().unwrap();
-                                        let context_id = root_cid.to_bytes();
-                                        info!("creating advertisement for cids under root cid: {:?}", root_cid);
-
-                                        info!("inserting the chunks");
-                                        let addresses: Vec<String> = [address.clone()].iter().map(|m| m.to_string()).collect();
-                                        let ad = Advertisement::new(context_id.clone(), peer_id, addresses, false);
-                                        let id = provider.create(ad).await.unwrap();
-
-                                        let dag = self.store.dag_traversal(&(convert_cid(root_cid.to_bytes())))?;
-                                        let entries = dag.iter().map(|d| return Ipld::Bytes(d.0.hash().to_bytes())).collect::<Vec<Ipld>>();
-                                        let chunks: Vec<&[Ipld]> = entries.chunks(MAX_ENTRIES).collect();
-
-                                        for chunk in chunks.iter() {
-                                            let entries_bytes = forest_encoding::to_vec(&chunk)?;
-                                            provider.add_chunk(entries_bytes, id).await.expect(" adding chunk to ad should not fail");
+                                }
+                                BehaviourEvent::RelayDenied { src_peer_id, dst_peer_id, limit, max } => {
+                                    warn!("[BehaviourEvent::RelayDenied] - {} to {:?} denied by {} = {}", src_peer_id, dst_peer_id, limit, max);
+                                    let labels = vec![
+                                        Label::new("peer", src_peer_id.to_string()),
+                                        Label::new("limit", limit),
+                                    ];
+                                    track(MetricEvent::RelayRequestDenied, Some(labels), None);
+                                    self.node_events.publish(NodeEvent::RelayDenied {
+                                        peer: src_peer_id.to_string(),
+                                        dst: dst_peer_id.map(|peer| peer.to_string()),
+                                        limit: limit.to_string(),
+                                        max,
+                                    });
+                                }
+                                BehaviourEvent::PeerThrottled { peer, requests, disconnected } => {
+                                    warn!("[BehaviourEvent::PeerThrottled] - {} made {} requests this window", peer, requests);
+                                    self.node_events.publish(NodeEvent::PeerThrottled { peer: peer.to_string(), requests, disconnected });
+                                }
+                                BehaviourEvent::Purge(purge) => {
+                                    info!("[BehaviourEvent::Purge] - purging {} cids and {} contexts", purge.cids.len(), purge.context_ids.len());
+                                    self.node_events.publish(NodeEvent::PurgeReceived { cids: purge.cids, context_ids: purge.context_ids });
+                                }
+                                BehaviourEvent::CarRequest { peer, root, selector, channel } => {
+                                    debug!("[BehaviourEvent::CarRequest] - {} asked for {} ({:?})", peer, root, selector);
+
+                                    let store = self.store.clone();
+                                    let acl = Arc::clone(&self.acl);
+                                    let accounting = Arc::clone(&self.accounting);
+                                    self.workers.submit(async move {
+                                        let source = match Cid::from_str(&root) {
+                                            Ok(cid) if !acl.allows_peer(&cid, &peer) => {
+                                                debug!("[BehaviourEvent::CarRequest] - {} is private to {}", root, peer);
+                                                None
+                                            }
+                                            Ok(cid) => car_source(&store, cid, selector).await.map_err(|err| {
+                                                debug!("[BehaviourEvent::CarRequest] - cannot serve {}: {:?}", root, err);
+                                            }).ok(),
+                                            Err(err) => {
+                                                warn!("[BehaviourEvent::CarRequest] - invalid root cid {}: {:?}", root, err);
+                                                None
+                                            }
+                                        };
+
+                                        if let Some(source) = &source {
+                                            accounting.record(&root, None, Some(&peer.to_string()), source.len());
                                         }
-                                        info!("Publishing the advertisement now");
-                                        provider.publish(id).await.expect("publishing the ad should not fail");
-                                        if let Ok(announce_msg) = provider.create_announce_msg(peer_id).await {
-                                            let i_topic_hash = TopicHash::from_raw("indexer/ingest/mainnet");
-                                            let i_topic = Topic::new("indexer/ingest/mainnet");
-                                            let g_msg = GossipsubMessage {data:announce_msg.clone(), source: None, sequence_number: None, topic: i_topic_hash };
-                                            match swarm.get_mut().behaviour_mut().publish(i_topic, g_msg.clone()) {
-                                                Ok(res) => {
-                                                    info!("gossiping the new advertisement done : {:}", res);
-                                                },
-                                                Err(e) => {
-                                                    warn!("there was an error while gossiping the announcement, will try to announce via http");
-                                                    warn!("{:?}", e);
-                                                    // make an http announcement if gossiping fails
-                                                    let _ = provider.announce_http_message(announce_msg).await;
+                                        let response = UrsaExchangeResponse(ResponseType::GetCarResponse(CarStream::new(root, source)));
+                                        Some(WorkResult::Response { channel, response })
+                                    }).await;
+                                }
+                                BehaviourEvent::FindContent { peer, cid, channel } => {
+                                    debug!("[BehaviourEvent::FindContent] - {} asked for providers of {}", peer, cid);
+
+                                    match Cid::from_str(&cid) {
+                                        Ok(cid) => {
+                                            let behaviour = swarm.get_mut().behaviour_mut();
+                                            let mut providers = behaviour.known_providers(&cid);
+                                            let addrs = provider_addrs(swarm.get_ref());
+                                            // private content is only offered to the peers allowed to pull it
+                                            let allowed = self.acl.allows_peer(&cid, &peer);
+                                            let store = self.store.clone();
+                                            self.workers.submit(async move {
+                                                if allowed && is_stored(&store, cid).await {
+                                                    providers.insert(0, ContentProvider { peer_id: peer_id.to_string(), addrs });
                                                 }
+                                                let response = UrsaExchangeResponse(ResponseType::FindContentResponse(providers));
+                                                Some(WorkResult::Response { channel, response })
+                                            }).await;
+                                        }
+                                        Err(err) => {
+                                            warn!("[BehaviourEvent::FindContent] - invalid cid {}: {:?}", cid, err);
+                                            let response = UrsaExchangeResponse(ResponseType::FindContentResponse(vec![]));
+                                            if let Err(err) = swarm.get_mut().behaviour_mut().send_response(channel, response) {
+                                                warn!("[BehaviourEvent::FindContent] - {:?}", err);
                                             }
                                         }
                                     }
                                 }
+                                BehaviourEvent::StartPublish { public_address } => {
+                                    let mut announce_addrs: Vec<Multiaddr> = announce_address(&public_address).into_iter().collect();
+                                    for listen_addr in swarm.get_ref().listeners().filter(|addr| is_routable(addr)) {
+                                        let addr = match announce_address(listen_addr) {
+                                            Some(addr) => addr,
+                                            None => continue,
+                                        };
+                                        if !announce_addrs.contains(&addr) {
+                                            announce_addrs.push(addr);
+                                        }
+                                    }
+                                    // the ads and the head are signed by the provider key, which need not be the node identity
+                                    let provider_id = provider.peer_id();
+                                    // a batch already waiting on its window will pick the new roots up
+                                    if provider.begin_batch() {
+                                        let provider = provider.clone();
+                                        let store = self.store.clone();
+                                        let job = async move {
+                                            provider.end_batch();
+                                            let mut announce_msg = None;
+                                            // removals go first, re-advertised roots must not be removed again
+                                            let removed_contexts = provider.get_mut_removed_contexts();
+                                            let mut removed_queue = removed_contexts.write().await;
+                                            while let Some(context_id) = removed_queue.pop_front() {
+                                                info!("creating removal advertisement for context id: {:?}", context_id);
+                                                let addresses: Vec<String> = announce_addrs.iter().map(|m| m.to_string()).collect();
+                                                let ad = Advertisement::new(context_id.clone(), provider_id, addresses, true);
+                                                // signing may fail with a remote signer, the removal and the queued
+                                                // roots wait for the next publish
+                                                if let Err(e) = advertise(&provider, ad, &[]).await {
+                                                    error!("publishing the removal advertisement failed: {:?}", e);
+                                                    removed_queue.push_front(context_id);
+                                                    return announce_msg.map(WorkResult::Announce);
+                                                }
+                                                announce_msg = provider.create_announce_msg(provider_id).await.ok().or(announce_msg);
+                                            }
+                                            drop(removed_queue);
+
+                                            let batches = provider.take_batch().await.unwrap_or_else(|e| {
+                                                error!("reading the contexts of the queued roots failed: {:?}", e);
+                                                vec![]
+                                            });
+                                            for (context_id, root_cids) in batches {
+                                                info!("creating advertisement for cids under root cids: {:?}", root_cids);
+
+                                                let mut seen = HashSet::new();
+                                                let mut entries = vec![];
+                                                for root_cid in &root_cids {
+                                                    let root = root_cid.to_ipld_cid();
+                                                    let dag = match store.blocking(move |store| store.dag_traversal(&root)).await.and_then(|dag| dag) {
+                                                        Ok(dag) => dag,
+                                                        Err(err) => {
+                                                            warn!("[BehaviourEvent::StartPublish] - cannot traverse {}: {:?}", root_cid, err);
+                                                            continue;
+                                                        }
+                                                    };
+                                                    // dags of a batch may share blocks
+                                                    for (cid, _) in dag {
+                                                        if seen.insert(cid) {
+                                                            entries.push(Ipld::Bytes(cid.hash().to_bytes()));
+                                                        }
+                                                    }
+                                                }
+                                                if entries.is_empty() {
+                                                    continue;
+                                                }
+
+                                                info!("inserting the chunks");
+                                                let addresses: Vec<String> = announce_addrs.iter().map(|m| m.to_string()).collect();
+                                                let ad = Advertisement::new(context_id, provider_id, addresses, false);
+                                                info!("Publishing the advertisement now");
+                                                if let Err(e) = advertise(&provider, ad, &entries).await {
+                                                    error!("publishing the advertisement failed, queueing its roots again: {:?}", e);
+                                                    provider.get_mut_root_cids().write().await.extend(root_cids);
+                                                    continue;
+                                                }
+                                                announce_msg = provider.create_announce_msg(provider_id).await.ok().or(announce_msg);
+                                            }
+                                            if provider.needs_compaction() {
+                                                match provider.compact().await {
+                                                    Ok(Some(head)) => {
+                                                        info!("ad chain compacted, new head {}", head);
+                                                        announce_msg = provider.create_announce_msg(provider_id).await.ok().or(announce_msg);
+                                                    }
+                                                    Ok(None) => {}
+                                                    Err(e) => error!("compacting the ad chain failed: {:?}", e),
+                                                }
+                                            }
+                                            // a chain loaded from the store, e.g. republished under a rotated key
+                                            if provider.take_restored() && announce_msg.is_none() {
+                                                announce_msg = provider.create_announce_msg(provider_id).await.ok();
+                                            }
+                                            announce_msg.map(WorkResult::Announce)
+                                        };
+
+                                        let window = provider.batch_window();
+                                        if window.is_zero() {
+                                            self.workers.submit(job).await;
+                                        } else {
+                                            // wait out the window off the loop, coalescing the roots put meanwhile
+                                            let workers = self.workers.clone();
+                                            task::spawn(async move {
+                                                task::sleep(window).await;
+                                                workers.submit(job).await;
+                                            });
+                                        }
+                                    }
+                                }
                             },
+                            SwarmEvent::Behaviour(event) => swarm.get_mut().behaviour_mut().handle_event(event),
+                            SwarmEvent::NewListenAddr { address, .. } => {
+                                info!("Listening on {}", address);
+
+                                if self
+                                    .event_sender
+                                    .send(UrsaEvent::NewListenAddr(address.clone()))
+                                    .await
+                                    .is_err()
+                                {
+                                    warn!("[SwarmEvent::NewListenAddr] - failed to send listen address: {:?}", address);
+                                }
+                                // covers the circuit address of a new relay reservation too
+                                push_identify(swarm.get_mut());
+                                refresh_external_addrs(swarm.get_ref(), &provider, self.registry.as_deref(), &self.event_sender).await;
+                            }
+                            SwarmEvent::ExpiredListenAddr { address, .. } => {
+                                info!("No longer listening on {}", address);
+                                push_identify(swarm.get_mut());
+                                refresh_external_addrs(swarm.get_ref(), &provider, self.registry.as_deref(), &self.event_sender).await;
+                            }
+                            SwarmEvent::ListenerClosed { listener_id, reason, .. } => {
+                                if let Some((_, relay)) = relay_listener.filter(|(id, _)| *id == listener_id) {
+                                    warn!("The listener on the relay {} closed: {:?}", relay, reason);
+                                    relay_listener = None;
+                                    swarm.get_mut().behaviour_mut().relay_failed(relay);
+                                }
+                            }
                             // Do we need to handle any of the below events?
                             SwarmEvent::Dialing { .. }
                             | SwarmEvent::BannedPeer { .. }
-                            | SwarmEvent::NewListenAddr { .. }
                             | SwarmEvent::ListenerError { .. }
-                            | SwarmEvent::ListenerClosed { .. }
                             | SwarmEvent::ConnectionClosed { .. }
-                            | SwarmEvent::ExpiredListenAddr { .. }
                             | SwarmEvent::IncomingConnection { .. }
                             | SwarmEvent::ConnectionEstablished { .. }
                             | SwarmEvent::IncomingConnectionError { .. }
@@ -481,28 +1397,195 @@ where
                         }
                     }
                 },
+                _ = publish_retry => {
+                    if !self.publish_queue.is_empty() {
+                        let behaviour = swarm.get_mut().behaviour_mut();
+                        let (published, dropped) = self.publish_queue.retry(|topic, message| {
+                            behaviour.can_publish(&topic.hash())
+                                && behaviour.publish(topic.clone(), message.clone()).is_ok()
+                        });
+                        if published + dropped > 0 {
+                            debug!("Published {} queued gossip messages, dropped {} expired ones", published, dropped);
+                        }
+                    }
+                    publish_retry = Delay::new(PUBLISH_RETRY_TICK).fuse();
+                }
+                _ = state_flush => {
+                    // written off the event loop, both only when they changed
+                    let behaviour = swarm.get_mut().behaviour_mut();
+                    if let Some(snapshot) = behaviour.reputation_mut().snapshot() {
+                        let store = self.store.clone();
+                        task::spawn(async move {
+                            let result = store.blocking(move |store| snapshot.save(store)).await;
+                            if let Err(e) = result.and_then(|result| result) {
+                                warn!("Failed to persist peer reputations: {:?}", e);
+                            }
+                        });
+                    }
+                    if let Some(snapshot) = behaviour.cached_roots_mut().snapshot() {
+                        let store = self.store.clone();
+                        task::spawn(async move {
+                            let result = store.blocking(move |store| CachedRoots::save(store, &snapshot)).await;
+                            if let Err(e) = result.and_then(|result| result) {
+                                warn!("Failed to persist the cached roots: {:?}", e);
+                            }
+                        });
+                    }
+                    state_flush = Delay::new(reputation::FLUSH_INTERVAL).fuse();
+                }
+                result = work_results.next() => {
+                    match result {
+                        Some(WorkResult::Response { channel, response }) => {
+                            if let Err(err) = swarm.get_mut().behaviour_mut().send_response(channel, response) {
+                                warn!("[WorkResult::Response] - {:?}", err);
+                            }
+                        }
+                        Some(WorkResult::SyncProgress { block, found, missing, bytes }) => {
+                            if let Some(syncs) = &mut self.dag_syncs {
+                                let mut step = syncs.on_block(block, found, missing);
+                                for (root, missing) in std::mem::take(&mut step.progress) {
+                                    notify_sync(&mut self.sync_watchers, SyncProgress { root, bytes, missing });
+                                }
+                                apply_sync_step(swarm.get_mut().behaviour_mut(), &mut self.sync_channels, &mut self.sync_watchers, step);
+                            }
+                        }
+                        Some(WorkResult::Replicate { root, peer }) => {
+                            info!("Replicating {} from peer {}", root, peer);
+                            let behaviour = swarm.get_mut().behaviour_mut();
+                            match &mut self.dag_syncs {
+                                Some(syncs) => {
+                                    let step = syncs.start(root, vec![peer]);
+                                    apply_sync_step(behaviour, &mut self.sync_channels, &mut self.sync_watchers, step);
+                                }
+                                None => behaviour.sync_block(root, vec![peer]),
+                            }
+                        }
+                        Some(WorkResult::ContentStored { root, size }) => {
+                            let announcement = ContentAnnouncement {
+                                cid: root.to_string(),
+                                size,
+                                peer_id: swarm.get_ref().local_peer_id().to_string(),
+                                addrs: provider_addrs(swarm.get_ref()),
+                            };
+                            let topic = Topic::new(URSA_CONTENT);
+                            let message = GossipsubMessage {
+                                source: None,
+                                data: serde_json::to_vec(&announcement)?,
+                                sequence_number: None,
+                                topic: topic.hash(),
+                            };
+                            if let Err(error) = publish_or_queue(swarm.get_mut().behaviour_mut(), &mut self.publish_queue, topic, message) {
+                                warn!("[WorkResult::ContentStored] - Failed to announce {}: {:?}", root, error);
+                            }
+                        }
+                        Some(WorkResult::Ingested(ingested)) => {
+                            if let Some(cache) = &mut self.ingest {
+                                debug!("{} advertised {} multihashes", ingested.provider, ingested.entries.len());
+                                cache.insert(ingested);
+                            }
+                        }
+                        Some(WorkResult::Announce(announce_msg)) => {
+                            let head = provider.head().await.map(|cid| cid.to_string());
+                            let mode = provider.announce_mode();
+                            let mut announce_http = mode != AnnounceMode::Gossipsub;
+                            if mode != AnnounceMode::Http {
+                                let i_topic = Topic::new(provider.announce_topic());
+                                let g_msg = GossipsubMessage {data:announce_msg.clone(), source: None, sequence_number: None, topic: i_topic.hash() };
+                                match swarm.get_mut().behaviour_mut().publish(i_topic, g_msg) {
+                                    Ok(res) => {
+                                        info!("gossiping the new advertisement done : {:}", res);
+                                        self.node_events.publish(NodeEvent::ProviderAnnounced { head: head.clone(), via: "gossipsub".to_string() });
+                                    },
+                                    Err(e) => {
+                                        warn!("there was an error while gossiping the announcement, will try to announce via http");
+                                        warn!("{:?}", e);
+                                        // make an http announcement if gossiping fails
+                                        announce_http = true;
+                                    }
+                                }
+                            }
+                            if announce_http {
+                                self.node_events.publish(NodeEvent::ProviderAnnounced { head, via: "http".to_string() });
+                                let provider = provider.clone();
+                                task::spawn(async move { provider.announce_http_message(announce_msg).await });
+                            }
+                        }
+                        None => warn!("[WorkResult] - the store workers stopped"),
+                    }
+                },
                 command = command_receiver.next() => {
+                    track(MetricEvent::CommandQueueDepth, None, Some(command_queue.len() as f64));
                     if let Some(command) = command {
                         match command {
-                            UrsaCommand::GetBitswap { cid, query, sender } => {
-                                let peers = swarm.get_mut().behaviour_mut().peers();
+                            UrsaCommand::GetBitswap { cid, query, providers, sender } => {
+                                let behaviour = swarm.get_mut().behaviour_mut();
+                                let mut peers = HashSet::new();
+                                for addr in providers {
+                                    match provider_peer(&addr) {
+                                        Some(peer) => {
+                                            // bare peer ids are dialed through the addresses we already know
+                                            if addr.iter().any(|protocol| !matches!(protocol, Protocol::P2p(_))) {
+                                                behaviour.discovery().add_address(&peer, addr);
+                                            }
+                                            peers.insert(peer);
+                                        }
+                                        None => warn!("[UrsaCommand::GetBitswap] - ignoring provider hint without a peer id: {}", addr),
+                                    }
+                                }
+                                // the provider announced to the indexers is asked before the connected peers
+                                if let Some((peer, addrs)) = peers.is_empty().then(|| self.ingest.as_ref()?.provider(&cid)).flatten() {
+                                    for addr in addrs {
+                                        behaviour.discovery().add_address(&peer, addr);
+                                    }
+                                    peers.insert(peer);
+                                }
+                                if peers.is_empty() {
+                                    let connected = behaviour.peers();
+                                    peers = behaviour.route(&cid, connected);
+                                }
                                 if peers.is_empty() {
                                     error!("There were no peers provided and the block does not exist in local store");
                                     let _ = sender.send(Err(anyhow!("There were no peers provided and the block does not exist in local store")));
                                 }
                                 else {
-                                    if let Some(chans) = self.response_channels.get_mut(&cid) {
-                                        chans.push(sender);
-                                    } else {
-                                        self.response_channels.insert(cid, vec![sender]);
-                                    }
-                                    match query{
-                                        BitswapType::Get => swarm.get_mut().behaviour_mut().get_block(cid, peers.iter().copied()),
-                                        BitswapType::Sync => swarm.get_mut().behaviour_mut().sync_block(cid, peers.into_iter().collect()),
+                                    let behaviour = swarm.get_mut().behaviour_mut();
+                                    match (query, &mut self.dag_syncs) {
+                                        (BitswapType::Get, _) => {
+                                            self.response_channels.entry(cid).or_default().push(sender);
+                                            behaviour.get_block(cid, peers.iter().copied());
+                                        }
+                                        (BitswapType::Sync, Some(syncs)) => {
+                                            self.sync_channels.entry(cid).or_default().push(sender);
+                                            let step = syncs.start(cid, peers.into_iter().collect());
+                                            apply_sync_step(behaviour, &mut self.sync_channels, &mut self.sync_watchers, step);
+                                        }
+                                        (BitswapType::Sync, None) => {
+                                            self.response_channels.entry(cid).or_default().push(sender);
+                                            behaviour.sync_block(cid, peers.into_iter().collect());
+                                        }
                                     }
-
                                 }
                             },
+                            UrsaCommand::CancelBitswap { cid, sender } => {
+                                let behaviour = swarm.get_mut().behaviour_mut();
+                                let mut running = behaviour.cancel_block(&cid);
+                                if let Some(blocks) = self.dag_syncs.as_mut().and_then(|syncs| syncs.cancel(&cid)) {
+                                    running = true;
+                                    for block in blocks {
+                                        behaviour.cancel_block(&block);
+                                    }
+                                }
+                                self.sync_watchers.remove(&cid);
+                                let channels = self.response_channels.remove(&cid).into_iter().chain(self.sync_channels.remove(&cid)).flatten();
+                                for chan in channels {
+                                    if chan.send(Err(Cancelled(cid).into())).is_err() {
+                                        warn!("[UrsaCommand::CancelBitswap] - failed to fail a waiter of {}", cid);
+                                    }
+                                }
+                                if sender.send(running).is_err() {
+                                    warn!("[UrsaCommand::CancelBitswap] - failed to send the cancellation of {}", cid);
+                                }
+                            }
                             UrsaCommand::Put { cid, sender } => {},
                             UrsaCommand::GetPeers { sender } => {
                                 let peers = swarm.get_mut().behaviour_mut().peers();
@@ -511,6 +1594,23 @@ where
                             UrsaCommand::Index { cids, sender } => {
                                 // TODO: start providing via gossip and/or publish ad to the indexer
                                 let root_cid = cids[0];
+                                swarm.get_mut().behaviour_mut().add_cached_root(root_cid);
+                                if let Err(err) = swarm.get_mut().behaviour_mut().discovery().start_providing(&root_cid) {
+                                    warn!("[UrsaCommand::Index] - failed to provide {} in the dht: {:?}", root_cid, err);
+                                }
+                                // private dags are not announced to everyone
+                                if self.announce_content && !self.acl.is_private(&root_cid) {
+                                    let store = self.store.clone();
+                                    self.workers.submit(async move {
+                                        match store.blocking(move |store| store.dag_stat(&root_cid.to_ipld_cid())).await {
+                                            Ok(Ok(stat)) => Some(WorkResult::ContentStored { root: root_cid, size: stat.size }),
+                                            Ok(Err(err)) | Err(err) => {
+                                                debug!("[UrsaCommand::Index] - not announcing {}: {:?}", root_cid, err);
+                                                None
+                                            }
+                                        }
+                                    }).await;
+                                }
                                 let root_cids = provider.get_mut_root_cids();
                                 let mut rlock = root_cids.write().await;
                                 rlock.push_back(root_cid);
@@ -524,18 +1624,263 @@ where
                                 }
                                 let _channel = sender.send(Ok(cids));
                             }
+                            UrsaCommand::Unindex { cids, sender } => {
+                                let mut removed = Ok(());
+                                for root_cid in &cids {
+                                    let behaviour = swarm.get_mut().behaviour_mut();
+                                    behaviour.remove_cached_root(root_cid);
+                                    behaviour.discovery().stop_providing(root_cid);
+                                    if let Err(e) = provider.remove_root_cid(*root_cid).await {
+                                        removed = Err(e);
+                                        break;
+                                    }
+                                }
+                                match swarm.get_mut().behaviour_mut().public_address().cloned() {
+                                    Some(public_address) => swarm.get_mut().behaviour_mut().publish_ad(public_address)?,
+                                    None => warn!("[UrsaCommand::Unindex] - Public address not available, the removal will be advertised once it is"),
+                                }
+                                let _channel = sender.send(removed.map(|_| cids));
+                            }
+                            UrsaCommand::UnindexContext { context_id, sender } => {
+                                let removed = provider.remove_context(context_id).await;
+                                let roots = removed.as_deref().unwrap_or_default();
+                                for root_cid in roots {
+                                    let behaviour = swarm.get_mut().behaviour_mut();
+                                    behaviour.remove_cached_root(root_cid);
+                                    behaviour.discovery().stop_providing(root_cid);
+                                }
+                                match swarm.get_mut().behaviour_mut().public_address().cloned() {
+                                    Some(public_address) => swarm.get_mut().behaviour_mut().publish_ad(public_address)?,
+                                    None => warn!("[UrsaCommand::UnindexContext] - Public address not available, the removal will be advertised once it is"),
+                                }
+                                let _channel = sender.send(removed);
+                            }
+                            UrsaCommand::SetContext { cids, context_id, sender } => {
+                                let store = &self.store;
+                                let grouped: Result<bool> = async {
+                                    let mut readvertise = false;
+                                    for root_cid in &cids {
+                                        if provider.context_id(root_cid).await? == context_id {
+                                            continue;
+                                        }
+                                        let stored = store.blockstore().has(root_cid)?;
+                                        if stored {
+                                            provider.remove_root_cid(*root_cid).await?;
+                                        }
+                                        provider.set_context(*root_cid, context_id.clone()).await?;
+                                        if stored {
+                                            provider.get_mut_root_cids().write().await.push_back(*root_cid);
+                                            readvertise = true;
+                                        }
+                                    }
+                                    Ok(readvertise)
+                                }.await;
+                                if let Ok(true) = grouped {
+                                    match swarm.get_mut().behaviour_mut().public_address().cloned() {
+                                        Some(public_address) => swarm.get_mut().behaviour_mut().publish_ad(public_address)?,
+                                        None => warn!("[UrsaCommand::SetContext] - Public address not available, the roots will be advertised once it is"),
+                                    }
+                                }
+                                let _channel = sender.send(grouped.map(|_| ()));
+                            }
                             UrsaCommand::SendRequest { peer_id, request, channel } => {
                                 let _ = swarm.get_mut().behaviour_mut().send_request(peer_id, request, channel);
                             },
                             UrsaCommand::SendResponse { request_id, response, channel } => todo!(),
                             UrsaCommand::GossipsubMessage { topic, message } => {
-                                if let Err(error) = swarm.get_mut().behaviour_mut().publish(topic.clone(), message.clone()) {
+                                if let Err(error) = publish_or_queue(swarm.get_mut().behaviour_mut(), &mut self.publish_queue, topic, message) {
                                     warn!(
                                         "[UrsaCommand::GossipsubMessage] - Failed to publish message to topic {:?} with error {:?}:",
                                         URSA_GLOBAL, error
                                     );
                                 }
                             }
+                            UrsaCommand::GetCar { peer_id, root, selector, sender } => {
+                                let (tx, rx) = oneshot::channel();
+                                let request = UrsaExchangeRequest(RequestType::GetCar { root: root.to_string(), selector });
+                                if let Err(err) = swarm.get_mut().behaviour_mut().send_request(peer_id, request, tx) {
+                                    warn!("[UrsaCommand::GetCar] - {:?}", err);
+                                }
+
+                                // import the car off the event loop once the peer responded
+                                let store = self.store.clone();
+                                task::spawn(async move {
+                                    let result = match rx.await {
+                                        Ok(Ok(UrsaExchangeResponse(ResponseType::GetCarResponse(CarStream { file: Some(car), .. })))) if !car.is_empty() => {
+                                            car::import_spooled(&store, &car, root).await
+                                        }
+                                        Ok(Ok(UrsaExchangeResponse(ResponseType::GetCarResponse(_)))) => Err(anyhow!("Peer {} does not have {}", peer_id, root)),
+                                        Ok(Ok(response)) => Err(anyhow!("Unexpected response from {}: {:?}", peer_id, response)),
+                                        Ok(Err(err)) => Err(err),
+                                        Err(err) => Err(anyhow!("{:?}", err)),
+                                    };
+                                    if sender.send(result).is_err() {
+                                        warn!("[UrsaCommand::GetCar] - failed to send the car result for {}", root);
+                                    }
+                                });
+                            }
+                            UrsaCommand::FindContent { cid, sender } => {
+                                let behaviour = swarm.get_mut().behaviour_mut();
+                                let mut providers = behaviour.known_providers(&cid);
+                                let request = UrsaExchangeRequest(RequestType::FindContent { cid: cid.to_string() });
+                                let peers: Vec<PeerId> = behaviour
+                                    .nearest_peers()
+                                    .into_iter()
+                                    .filter(|peer| behaviour.supports(peer, request.feature()))
+                                    .take(FIND_CONTENT_FANOUT)
+                                    .collect();
+
+                                let mut responses = vec![];
+                                for peer in peers {
+                                    let (tx, rx) = oneshot::channel();
+                                    if let Err(err) = behaviour.send_request(peer, request.clone(), tx) {
+                                        warn!("[UrsaCommand::FindContent] - {:?}", err);
+                                    }
+                                    responses.push(rx);
+                                }
+
+                                // merge the answers off the event loop, fall back to the dht if nobody knows
+                                let command_sender = self.command_sender.clone();
+                                task::spawn(async move {
+                                    let responses = join_all(responses.into_iter().map(|rx| future::timeout(FIND_CONTENT_TIMEOUT, rx))).await;
+                                    for response in responses {
+                                        if let Ok(Ok(Ok(UrsaExchangeResponse(ResponseType::FindContentResponse(found))))) = response {
+                                            for provider in found {
+                                                if !providers.iter().any(|p| p.peer_id == provider.peer_id) {
+                                                    providers.push(provider);
+                                                }
+                                            }
+                                        }
+                                    }
+
+                                    if !providers.is_empty() {
+                                        if sender.send(Ok(providers)).is_err() {
+                                            warn!("[UrsaCommand::FindContent] - failed to send the providers of {}", cid);
+                                        }
+                                    } else {
+                                        debug!("[UrsaCommand::FindContent] - no peer knows {}, walking the dht", cid);
+                                        let (tx, rx) = mpsc::unbounded();
+                                        if let Err(err) = command_sender.send(UrsaCommand::GetProviders { cid, sender: tx }).await {
+                                            warn!("[UrsaCommand::FindContent] - {:?}", err);
+                                        }
+                                        if sender.send(Ok(rx.collect().await)).is_err() {
+                                            warn!("[UrsaCommand::FindContent] - failed to send the providers of {}", cid);
+                                        }
+                                    }
+                                });
+                            }
+                            UrsaCommand::GetProviders { cid, sender } => {
+                                swarm.get_mut().behaviour_mut().find_providers(&cid, sender);
+                            }
+                            UrsaCommand::PublishName { cid, ttl_secs, sender } => {
+                                let sequence = self.published.as_ref().map_or(1, |record| record.sequence + 1);
+                                let result = NameRecord::sign(&self.keypair, &cid, sequence, ttl_secs).and_then(|record| {
+                                    names::save_published(&self.store, &record)?;
+                                    swarm.get_mut().behaviour_mut().publish_name(record.clone())?;
+                                    Ok(record)
+                                });
+                                if let Ok(record) = &result {
+                                    info!("Published name {} -> {} at sequence {}", record.name, record.cid, record.sequence);
+                                    self.published = Some(record.clone());
+                                }
+                                if sender.send(result).is_err() {
+                                    warn!("[UrsaCommand::PublishName] - failed to send the name record");
+                                }
+                            }
+                            UrsaCommand::ResolveName { name, sender } => {
+                                swarm.get_mut().behaviour_mut().resolve_name(name, sender);
+                            }
+                            UrsaCommand::Purge { cids, context_ids, sender } => {
+                                let result = PurgeMessage::sign(&self.keypair, cids, context_ids).and_then(|purge| {
+                                    swarm.get_mut().behaviour_mut().publish_purge(&purge)?;
+                                    Ok(purge)
+                                });
+                                if let Ok(purge) = &result {
+                                    info!("Gossiped a purge of {} cids and {} contexts", purge.cids.len(), purge.context_ids.len());
+                                }
+                                if sender.send(result).is_err() {
+                                    warn!("[UrsaCommand::Purge] - failed to send the purge");
+                                }
+                            }
+                            UrsaCommand::GossipStat { sender } => {
+                                let stat = swarm.get_mut().behaviour_mut().gossip_stat();
+                                if sender.send(stat).is_err() {
+                                    warn!("[UrsaCommand::GossipStat] - failed to send gossip stats");
+                                }
+                            }
+                            UrsaCommand::TopicPeers { topic, sender } => {
+                                let peers = swarm.get_ref().behaviour().topic_peers(topic.as_deref());
+                                if sender.send(peers).is_err() {
+                                    warn!("[UrsaCommand::TopicPeers] - failed to send the topic peers");
+                                }
+                            }
+                            UrsaCommand::PeerProtocols { protocol, sender } => {
+                                let inventory = swarm.get_ref().behaviour().peer_protocols(protocol.as_deref());
+                                if sender.send(inventory).is_err() {
+                                    warn!("[UrsaCommand::PeerProtocols] - failed to send the peer protocols");
+                                }
+                            }
+                            UrsaCommand::RelayCircuits { sender } => {
+                                let circuits = swarm.get_mut().behaviour().relay_circuits();
+                                if sender.send(circuits).is_err() {
+                                    warn!("[UrsaCommand::RelayCircuits] - failed to send relay circuits");
+                                }
+                            }
+                            UrsaCommand::WatchSync { root, sender } => {
+                                self.sync_watchers.retain(|_, senders| {
+                                    senders.retain(|sender| !sender.is_closed());
+                                    !senders.is_empty()
+                                });
+                                self.sync_watchers.entry(root).or_default().push(sender);
+                            }
+                            UrsaCommand::NodeInfo { sender } => {
+                                let swarm = swarm.get_mut();
+                                let listen_addrs = swarm.listeners().cloned().collect();
+                                let info = swarm.behaviour().node_info(*swarm.local_peer_id(), listen_addrs);
+                                if sender.send(info).is_err() {
+                                    warn!("[UrsaCommand::NodeInfo] - failed to send node info");
+                                }
+                            }
+                            UrsaCommand::ProviderStatus { sender } => {
+                                let status = provider.announce_status().await;
+                                if sender.send(status).is_err() {
+                                    warn!("[UrsaCommand::ProviderStatus] - failed to send provider status");
+                                }
+                            }
+                            UrsaCommand::ContextIds { cids, sender } => {
+                                let context_ids = try_join_all(cids.iter().map(|cid| provider.context_id(cid))).await;
+                                if sender.send(context_ids).is_err() {
+                                    warn!("[UrsaCommand::ContextIds] - failed to send context ids");
+                                }
+                            }
+                            UrsaCommand::ContentRequested { cid } => {
+                                if self.replication.record(cid) {
+                                    let behaviour = swarm.get_mut().behaviour_mut();
+                                    let replicas = behaviour.replicate(cid, self.replication.replicas());
+                                    info!("{} is hot, replicating to {:?}", cid, replicas);
+
+                                    if !replicas.is_empty() {
+                                        let announcement = ReplicationAnnouncement {
+                                            cid: cid.to_string(),
+                                            providers: iter::once(peer_id).chain(replicas).map(|p| p.to_string()).collect(),
+                                        };
+                                        self.node_events.publish(NodeEvent::ContentReplicated {
+                                            cid: announcement.cid.clone(),
+                                            providers: announcement.providers.clone(),
+                                        });
+                                        let topic = Topic::new(URSA_GLOBAL);
+                                        let message = GossipsubMessage {
+                                            source: None,
+                                            data: serde_json::to_vec(&announcement)?,
+                                            sequence_number: None,
+                                            topic: topic.hash(),
+                                        };
+                                        if let Err(error) = publish_or_queue(behaviour, &mut self.publish_queue, topic, message) {
+                                            warn!("[UrsaCommand::ContentRequested] - Failed to gossip replication of {}: {:?}", cid, error);
+                                        }
+                                    }
+                                }
+                            }
                         }
                     }
                 },
@@ -550,14 +1895,14 @@ mod tests {
 
     use crate::codec::protocol::RequestType;
     use async_std::{fs::File, io::BufReader, sync::RwLock};
-    use db::{rocks::RocksDb, rocks_config::RocksDbConfig};
+    use db::{rocks::RocksDb, rocks_config::RocksDbConfig, MemoryDB};
     use fvm_ipld_car::{load_car, CarReader};
     use libipld::{cbor::DagCborCodec, ipld, multihash::Code, Block, DefaultParams, Ipld};
     use simple_logger::SimpleLogger;
     use std::{str::FromStr, thread, time::Duration, vec};
     use tracing::log::LevelFilter;
     use ursa_index_provider::config::ProviderConfig;
-    use ursa_store::Store;
+    use ursa_store::{columns::Column, Store};
 
     fn create_block(ipld: Ipld) -> Block<DefaultParams> {
         Block::encode(DagCborCodec, Code::Blake3_256, &ipld).unwrap()
@@ -585,7 +1930,7 @@ mod tests {
         );
 
         let service =
-            UrsaService::new(keypair, &config, Arc::clone(&store), index_provider.clone());
+            UrsaService::new(keypair, &config, Arc::clone(&store), index_provider.clone()).unwrap();
 
         (service, local_peer_id)
     }
@@ -619,6 +1964,26 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_announce_address() {
+        let peer = PeerId::random();
+        for (addr, expected) in [
+            ("/ip4/1.2.3.4/tcp/6009", Some("/ip4/1.2.3.4/tcp/6009")),
+            ("/ip6/::2/tcp/6010", Some("/ip6/::2/tcp/6010")),
+        ] {
+            let addr: Multiaddr = format!("{addr}/p2p/{peer}").parse().unwrap();
+            assert_eq!(
+                announce_address(&addr),
+                expected.map(|addr| addr.parse().unwrap())
+            );
+        }
+
+        let relayed: Multiaddr = format!("/ip4/1.2.3.4/tcp/6009/p2p/{peer}/p2p-circuit")
+            .parse()
+            .unwrap();
+        assert_eq!(announce_address(&relayed), None);
+    }
+
     // Network Starts
     #[test]
     fn test_network_start() {
@@ -638,6 +2003,24 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_corrupt_acl_refused() {
+        let store = Arc::new(Store::new(Arc::new(MemoryDB::default())));
+        store
+            .column(Column::Metadata)
+            .write(crate::acl::ACL_KEY, b"not json".to_vec())
+            .unwrap();
+        let keypair = Keypair::generate_ed25519();
+        let index_provider = Provider::new(
+            keypair.clone(),
+            Arc::new(RwLock::new(MemoryDB::default())),
+            ProviderConfig::default(),
+        );
+
+        let service = UrsaService::new(keypair, &NetworkConfig::default(), store, index_provider);
+        assert!(service.is_err());
+    }
+
     #[async_std::test]
     async fn test_network_gossip() {
         setup_logger(LevelFilter::Debug);
@@ -651,7 +2034,7 @@ mod tests {
 
         let (node_1, _) = network_init(&mut config, Arc::clone(&store));
 
-        config.swarm_addr = "/ip4/0.0.0.0/tcp/6010".parse().unwrap();
+        config.swarm_addrs = vec!["/ip4/0.0.0.0/tcp/6010".parse().unwrap()];
         let (node_2, _) = network_init(&mut config, Arc::clone(&store));
 
         let node_1_sender = node_1.command_sender.clone();
@@ -708,7 +2091,7 @@ mod tests {
 
         let (node_1, _) = network_init(&mut config, Arc::clone(&store));
 
-        config.swarm_addr = "/ip4/0.0.0.0/tcp/6010".parse().unwrap();
+        config.swarm_addrs = vec!["/ip4/0.0.0.0/tcp/6010".parse().unwrap()];
         let (node_2, _) = network_init(&mut config, Arc::clone(&store));
 
         task::spawn(async {
@@ -720,11 +2103,17 @@ mod tests {
         let mut swarm_2 = node_2.swarm.fuse();
 
         loop {
-            if let Some(SwarmEvent::Behaviour(BehaviourEvent::PeerConnected(peer_id))) =
-                swarm_2.next().await
-            {
-                info!("Node 2 PeerConnected: {:?}", peer_id);
-                break;
+            match swarm_2.next().await {
+                Some(SwarmEvent::Behaviour(NetworkEvent::Ursa(BehaviourEvent::PeerConnected(
+                    peer_id,
+                )))) => {
+                    info!("Node 2 PeerConnected: {:?}", peer_id);
+                    break;
+                }
+                Some(SwarmEvent::Behaviour(event)) => {
+                    swarm_2.get_mut().behaviour_mut().handle_event(event)
+                }
+                _ => {}
             }
         }
     }
@@ -741,7 +2130,7 @@ mod tests {
 
         let (node_1, _) = network_init(&mut config, Arc::clone(&store));
 
-        config.swarm_addr = "/ip4/0.0.0.0/tcp/6010".parse().unwrap();
+        config.swarm_addrs = vec!["/ip4/0.0.0.0/tcp/6010".parse().unwrap()];
         let (node_2, _) = network_init(&mut config, Arc::clone(&store));
 
         task::spawn(async {
@@ -753,11 +2142,17 @@ mod tests {
         let mut swarm_2 = node_2.swarm.fuse();
 
         loop {
-            if let Some(SwarmEvent::Behaviour(BehaviourEvent::PeerConnected(peer_id))) =
-                swarm_2.next().await
-            {
-                info!("Node 2 PeerConnected: {:?}", peer_id);
-                break;
+            match swarm_2.next().await {
+                Some(SwarmEvent::Behaviour(NetworkEvent::Ursa(BehaviourEvent::PeerConnected(
+                    peer_id,
+                )))) => {
+                    info!("Node 2 PeerConnected: {:?}", peer_id);
+                    break;
+                }
+                Some(SwarmEvent::Behaviour(event)) => {
+                    swarm_2.get_mut().behaviour_mut().handle_event(event)
+                }
+                _ => {}
             }
         }
     }
@@ -774,7 +2169,7 @@ mod tests {
 
         let (node_1, _) = network_init(&mut config, Arc::clone(&store));
 
-        config.swarm_addr = "/ip4/0.0.0.0/tcp/6010".parse().unwrap();
+        config.swarm_addrs = vec!["/ip4/0.0.0.0/tcp/6010".parse().unwrap()];
         let (node_2, peer_2) = network_init(&mut config, Arc::clone(&store));
 
         let node_1_sender = node_1.command_sender.clone();
@@ -801,11 +2196,17 @@ mod tests {
         let mut swarm_2 = node_2.swarm.fuse();
 
         loop {
-            if let Some(SwarmEvent::Behaviour(BehaviourEvent::RequestMessage { request, .. })) =
-                swarm_2.next().await
-            {
-                info!("Node 2 RequestMessage: {:?}", request);
-                break;
+            match swarm_2.next().await {
+                Some(SwarmEvent::Behaviour(NetworkEvent::Ursa(
+                    BehaviourEvent::RequestMessage { request, .. },
+                ))) => {
+                    info!("Node 2 RequestMessage: {:?}", request);
+                    break;
+                }
+                Some(SwarmEvent::Behaviour(event)) => {
+                    swarm_2.get_mut().behaviour_mut().handle_event(event)
+                }
+                _ => {}
             }
         }
     }
@@ -827,7 +2228,7 @@ mod tests {
 
         let (node_1, _) = network_init(&mut config, Arc::clone(&store1));
 
-        config.swarm_addr = "/ip4/0.0.0.0/tcp/6010".parse().unwrap();
+        config.swarm_addrs = vec!["/ip4/0.0.0.0/tcp/6010".parse().unwrap()];
         let (node_2, _) = network_init(&mut config, Arc::clone(&store2));
 
         let node_2_sender = node_2.command_sender.clone();
@@ -849,8 +2250,9 @@ mod tests {
 
         let (sender, receiver) = oneshot::channel();
         let msg = UrsaCommand::GetBitswap {
-            cid: convert_cid(block.cid().to_bytes()),
+            cid: block.cid().to_cid(),
             query: BitswapType::Get,
+            providers: vec![],
             sender,
         };
         node_2_sender.send(msg).await.unwrap();
@@ -859,9 +2261,7 @@ mod tests {
             info!("waiting for msg on block receive channel...");
             let value = receiver.await.expect("Unable to receive from channel");
             if let Ok(_val) = value {
-                let store_2_block = bitswap_store_2
-                    .get(&convert_cid(block.cid().to_bytes()))
-                    .unwrap();
+                let store_2_block = bitswap_store_2.get(block.cid()).unwrap();
                 assert_eq!(store_2_block, Some(block.data().to_vec()));
             }
         });
@@ -879,7 +2279,7 @@ mod tests {
 
         let block = get_block(&b"hello world"[..]);
 
-        config.swarm_addr = "/ip4/0.0.0.0/tcp/6010".parse().unwrap();
+        config.swarm_addrs = vec!["/ip4/0.0.0.0/tcp/6010".parse().unwrap()];
         let (node_2, _) = network_init(&mut config, Arc::clone(&store2));
 
         let node_2_sender = node_2.command_sender.clone();
@@ -902,8 +2302,9 @@ mod tests {
         let (sender, receiver) = oneshot::channel();
 
         let msg = UrsaCommand::GetBitswap {
-            cid: convert_cid(block.cid().to_bytes()),
+            cid: block.cid().to_cid(),
             query: BitswapType::Get,
+            providers: vec![],
             sender,
         };
         node_2_sender.send(msg).await.unwrap();
@@ -938,8 +2339,8 @@ mod tests {
 
         let block = get_block(&b"hello world"[..]);
         info!("inserting block into bitswap store for node");
-        let cid = convert_cid(block.cid().to_bytes());
-        let string_cid = Cid::to_string(&cid);
+        let cid = *block.cid();
+        let string_cid = cid.to_string();
         info!("block cid to string : {:?}", string_cid);
 
         if let Err(err) = bitswap_store.insert(&block) {
@@ -950,7 +2351,7 @@ mod tests {
         } else {
             info!("block inserted successfully");
         }
-        info!("{:?}", bitswap_store.contains(&convert_cid(cid.to_bytes())))
+        info!("{:?}", bitswap_store.contains(&cid))
     }
 
     #[async_std::test]
@@ -967,7 +2368,7 @@ mod tests {
         let cid =
             Cid::from_str("bafkreif2opfibjypwkjzzry3jbibcjqcjwnpoqpeiqw75eu3s3u3zbdszq").unwrap();
 
-        if let Ok(res) = bitswap_store_1.contains(&convert_cid(cid.to_bytes())) {
+        if let Ok(res) = bitswap_store_1.contains(&cid.to_ipld_cid()) {
             println!("block exists in current db: {:?}", res);
         }
     }
@@ -1000,7 +2401,7 @@ mod tests {
 
         let (node_1, _) = network_init(&mut config, Arc::clone(&store1));
 
-        config.swarm_addr = "/ip4/0.0.0.0/tcp/6010".parse().unwrap();
+        config.swarm_addrs = vec!["/ip4/0.0.0.0/tcp/6010".parse().unwrap()];
         let (node_2, _) = network_init(&mut config, Arc::clone(&store2));
         let node_2_sender = node_2.command_sender.clone();
 
@@ -1024,6 +2425,7 @@ mod tests {
         let msg = UrsaCommand::GetBitswap {
             cid: cids[0],
             query: BitswapType::Sync,
+            providers: vec![],
             sender,
         };
         node_2_sender.send(msg).await.unwrap();
@@ -1033,9 +2435,7 @@ mod tests {
             let value = receiver.await.expect("Unable to receive from channel");
             if let Ok(_val) = value {
                 for cid in cids_vec {
-                    assert!(bitswap_store2
-                        .contains(&convert_cid(cid.to_bytes()))
-                        .unwrap());
+                    assert!(bitswap_store2.contains(&cid.to_ipld_cid()).unwrap());
                 }
             }
         });
@@ -0,0 +1,435 @@
+//! Outbound bandwidth shaping.
+//!
+//! Uploads are capped by token buckets, one per [`TrafficClass`] and a global one
+//! over all of them, so a node serving at its uplink budget still has room to answer
+//! pings, dht queries and rpc calls. Buckets refill at their rate and hold at most
+//! one second of it, a cap of `None` leaves the traffic unshaped.
+//!
+//! - The global cap is enforced on every libp2p connection by [`Shaped`], which the
+//!   transport wraps the tcp and relayed connections in.
+//! - Bitswap blocks are budgeted by [`ShapedStorage`] as the bitswap database thread
+//!   reads them, before they are queued on a connection. The thread serves every
+//!   request, so it does not wait for tokens: a block over the budget is answered as
+//!   missing, and the peer asks again or asks another provider.
+//! - Gossip publishes over the cap are refused, gossipsub has no way to defer them.
+//! - Car files streamed by the http gateway are paced by [`ShapedStream`], against
+//!   the gateway cap and the global one, their bytes do not go through libp2p.
+
+use bytes::Bytes;
+use futures::{ready, AsyncRead, AsyncWrite, Stream};
+use futures_timer::Delay;
+use libipld::{Block, Cid};
+use libp2p_bitswap::BitswapStore;
+use serde::{Deserialize, Serialize};
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+/// Smallest write waited for on a connection out of tokens, about one packet, so an
+/// empty bucket does not wake the connection for every byte it refills.
+const MIN_WRITE: usize = 1500;
+
+/// Upload caps in bytes per second.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct BandwidthConfig {
+    /// Cap over all the traffic below, and everything else sent to peers.
+    pub global: Option<u64>,
+    /// Blocks served over bitswap.
+    pub bitswap: Option<u64>,
+    /// Car files streamed by the http gateway.
+    pub gateway: Option<u64>,
+    /// Messages published on gossipsub.
+    pub gossip: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrafficClass {
+    Bitswap,
+    Gateway,
+    Gossip,
+}
+
+pub struct TokenBucket {
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    /// Tokens, one per byte, added every second.
+    rate: f64,
+    /// Tokens held at most.
+    burst: f64,
+    /// Negative while callers wait for the bytes they reserved.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Bucket of `rate` bytes per second, starting full.
+    pub fn new(rate: u64) -> Self {
+        let rate = rate.max(1) as f64;
+        Self {
+            state: Mutex::new(BucketState {
+                rate,
+                burst: rate,
+                tokens: rate,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Refill at `rate` bytes per second from now on, the tokens held capped to the
+    /// new burst.
+    pub fn set_rate(&self, rate: u64) {
+        let mut state = self.refilled();
+        state.rate = rate.max(1) as f64;
+        state.burst = state.rate;
+        state.tokens = state.tokens.min(state.burst);
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.state.lock().unwrap().rate as u64
+    }
+
+    fn refilled(&self) -> std::sync::MutexGuard<'_, BucketState> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.updated).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * state.rate).min(state.burst);
+        state.updated = now;
+        state
+    }
+
+    /// Bytes that can be sent right away.
+    pub fn available(&self) -> usize {
+        self.refilled().tokens.max(0.0) as usize
+    }
+
+    pub fn take(&self, bytes: usize) {
+        self.refilled().tokens -= bytes as f64;
+    }
+
+    /// Take `bytes` whether or not they are available, returning how long to wait
+    /// before sending them.
+    pub fn reserve(&self, bytes: usize) -> Duration {
+        let mut state = self.refilled();
+        state.tokens -= bytes as f64;
+        state.wait(state.tokens)
+    }
+
+    /// Take `bytes` if the bucket holds them, or is full for sends above the burst.
+    pub fn try_take(&self, bytes: usize) -> bool {
+        let mut state = self.refilled();
+        if state.tokens < (bytes as f64).min(state.burst) {
+            return false;
+        }
+        state.tokens -= bytes as f64;
+        true
+    }
+
+    /// Time until `bytes` are available, up to a full bucket.
+    pub fn wait_for(&self, bytes: usize) -> Duration {
+        let state = self.refilled();
+        state.wait(state.tokens - (bytes as f64).min(state.burst))
+    }
+}
+
+impl BucketState {
+    fn wait(&self, tokens: f64) -> Duration {
+        Duration::from_secs_f64((-tokens).max(0.0) / self.rate)
+    }
+}
+
+/// Token buckets of the configured caps.
+#[derive(Default)]
+pub struct Shaper {
+    global: Option<Arc<TokenBucket>>,
+    bitswap: Option<TokenBucket>,
+    gateway: Option<TokenBucket>,
+    gossip: Option<TokenBucket>,
+}
+
+impl Shaper {
+    pub fn new(config: &BandwidthConfig) -> Self {
+        Self {
+            global: config.global.map(|rate| Arc::new(TokenBucket::new(rate))),
+            bitswap: config.bitswap.map(TokenBucket::new),
+            gateway: config.gateway.map(TokenBucket::new),
+            gossip: config.gossip.map(TokenBucket::new),
+        }
+    }
+
+    /// Bucket of the global cap, for the connections of the transport.
+    pub fn global(&self) -> Option<Arc<TokenBucket>> {
+        self.global.clone()
+    }
+
+    /// Change the cap of `class`, or the global one when none is given. Only caps
+    /// configured when the node started can be changed, returns false for the others.
+    pub fn set_rate(&self, class: Option<TrafficClass>, rate: u64) -> bool {
+        let bucket = match class {
+            Some(class) => self.bucket(class),
+            None => self.global.as_deref(),
+        };
+        match bucket {
+            Some(bucket) => {
+                bucket.set_rate(rate);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn bucket(&self, class: TrafficClass) -> Option<&TokenBucket> {
+        match class {
+            TrafficClass::Bitswap => self.bitswap.as_ref(),
+            TrafficClass::Gateway => self.gateway.as_ref(),
+            TrafficClass::Gossip => self.gossip.as_ref(),
+        }
+    }
+
+    /// Reserve `bytes` of `class`, returning how long to wait before sending them.
+    /// Gateway bytes are reserved from the global cap too, the transport only sees
+    /// the others.
+    pub fn reserve(&self, class: TrafficClass, bytes: usize) -> Duration {
+        let mut wait = self
+            .bucket(class)
+            .map_or(Duration::ZERO, |bucket| bucket.reserve(bytes));
+        if class == TrafficClass::Gateway {
+            if let Some(global) = &self.global {
+                wait = wait.max(global.reserve(bytes));
+            }
+        }
+        wait
+    }
+
+    /// Whether `bytes` of `class` may be sent now, taking them if so.
+    pub fn try_take(&self, class: TrafficClass, bytes: usize) -> bool {
+        self.bucket(class)
+            .map_or(true, |bucket| bucket.try_take(bytes))
+    }
+}
+
+/// Connection whose writes take their bytes from a bucket, writing no more than it
+/// holds.
+pub struct Shaped<C> {
+    inner: C,
+    bucket: Option<Arc<TokenBucket>>,
+    delay: Option<Delay>,
+}
+
+impl<C> Shaped<C> {
+    pub fn new(inner: C, bucket: Option<Arc<TokenBucket>>) -> Self {
+        Self {
+            inner,
+            bucket,
+            delay: None,
+        }
+    }
+}
+
+impl<C: AsyncRead + Unpin> AsyncRead for Shaped<C> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<C: AsyncWrite + Unpin> AsyncWrite for Shaped<C> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let bucket = match self.bucket.clone() {
+            Some(bucket) => bucket,
+            None => return Pin::new(&mut self.inner).poll_write(cx, buf),
+        };
+        loop {
+            if let Some(delay) = self.delay.as_mut() {
+                ready!(Pin::new(delay).poll(cx));
+                self.delay = None;
+            }
+            let available = bucket.available();
+            if available == 0 && !buf.is_empty() {
+                let wait = bucket.wait_for(buf.len().min(MIN_WRITE));
+                self.delay = Some(Delay::new(wait));
+                continue;
+            }
+            let len = buf.len().min(available);
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &buf[..len]))?;
+            bucket.take(written);
+            return Poll::Ready(Ok(written));
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Stream of chunks held back until their bytes of `class` are reserved.
+pub struct ShapedStream<S> {
+    inner: S,
+    shaper: Arc<Shaper>,
+    class: TrafficClass,
+    delay: Option<Delay>,
+    /// Chunk waiting for `delay`.
+    held: Option<Bytes>,
+}
+
+impl<S> ShapedStream<S> {
+    pub fn new(inner: S, shaper: Arc<Shaper>, class: TrafficClass) -> Self {
+        Self {
+            inner,
+            shaper,
+            class,
+            delay: None,
+            held: None,
+        }
+    }
+}
+
+impl<S> Stream for ShapedStream<S>
+where
+    S: Stream<Item = io::Result<Bytes>> + Unpin,
+{
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(delay) = self.delay.as_mut() {
+            ready!(Pin::new(delay).poll(cx));
+            self.delay = None;
+            return Poll::Ready(self.held.take().map(Ok));
+        }
+        let chunk = match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+            Some(Ok(chunk)) => chunk,
+            other => return Poll::Ready(other),
+        };
+        let wait = self.shaper.reserve(self.class, chunk.len());
+        if wait.is_zero() {
+            return Poll::Ready(Some(Ok(chunk)));
+        }
+        let mut delay = Delay::new(wait);
+        if Pin::new(&mut delay).poll(cx).is_ready() {
+            return Poll::Ready(Some(Ok(chunk)));
+        }
+        self.delay = Some(delay);
+        self.held = Some(chunk);
+        Poll::Pending
+    }
+}
+
+/// Bitswap store handing out blocks for serving only while the bitswap cap has room
+/// for them, blocks over it are reported missing.
+pub struct ShapedStorage<B>(pub B, pub Arc<Shaper>);
+
+impl<B: BitswapStore> BitswapStore for ShapedStorage<B> {
+    type Params = B::Params;
+
+    fn contains(&mut self, cid: &Cid) -> libipld::Result<bool> {
+        self.0.contains(cid)
+    }
+
+    fn get(&mut self, cid: &Cid) -> libipld::Result<Option<Vec<u8>>> {
+        let block = self.0.get(cid)?;
+        if let Some(data) = &block {
+            if !self.1.try_take(TrafficClass::Bitswap, data.len()) {
+                debug!("Bitswap cap reached, not serving {} for now", cid);
+                return Ok(None);
+            }
+        }
+        Ok(block)
+    }
+
+    fn insert(&mut self, block: &Block<Self::Params>) -> libipld::Result<()> {
+        self.0.insert(block)
+    }
+
+    fn missing_blocks(&mut self, cid: &Cid) -> libipld::Result<Vec<Cid>> {
+        self.0.missing_blocks(cid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::MemoryDB;
+    use futures::{stream, AsyncWriteExt, StreamExt};
+    use libipld::{cbor::DagCborCodec, multihash::Code, store::DefaultParams, Ipld};
+    use ursa_store::{BitswapStorage, Store};
+
+    #[async_std::test]
+    async fn test_shaping() {
+        let bucket = TokenBucket::new(1000);
+        assert_eq!(bucket.available(), 1000);
+        assert!(bucket.try_take(600));
+        assert!(!bucket.try_take(600));
+        let wait = bucket.reserve(900);
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500));
+        bucket.set_rate(10);
+        assert_eq!(bucket.rate(), 10);
+        assert_eq!(bucket.available(), 0);
+
+        // a write is cut to the bytes the bucket holds
+        let bucket = Arc::new(TokenBucket::new(100));
+        let mut conn = Shaped::new(Vec::new(), Some(Arc::clone(&bucket)));
+        assert_eq!(conn.write(&[0; 150]).await.unwrap(), 100);
+        assert_eq!(bucket.available(), 0);
+
+        let shaper = Arc::new(Shaper::new(&BandwidthConfig {
+            gateway: Some(1000),
+            gossip: Some(10),
+            ..Default::default()
+        }));
+        assert!(shaper.try_take(TrafficClass::Bitswap, 1 << 20));
+        assert!(shaper.try_take(TrafficClass::Gossip, 100));
+        assert!(!shaper.try_take(TrafficClass::Gossip, 1));
+        assert!(!shaper.set_rate(None, 1000));
+
+        let chunks = vec![
+            Ok(Bytes::from(vec![1; 1000])),
+            Ok(Bytes::from(vec![2; 100])),
+        ];
+        let start = Instant::now();
+        let shaped: Vec<_> = ShapedStream::new(stream::iter(chunks), shaper, TrafficClass::Gateway)
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(shaped.len(), 2);
+        assert!(start.elapsed() >= Duration::from_millis(90));
+    }
+
+    #[test]
+    fn test_shaped_storage() {
+        let store = Arc::new(Store::new(Arc::new(MemoryDB::default())));
+        let shaper = Arc::new(Shaper::new(&BandwidthConfig {
+            bitswap: Some(100),
+            ..Default::default()
+        }));
+        let mut storage = ShapedStorage(BitswapStorage(store), shaper);
+        let data = Ipld::Bytes(vec![0; 60]);
+        let block = Block::<DefaultParams>::encode(DagCborCodec, Code::Blake3_256, &data).unwrap();
+        storage.insert(&block).unwrap();
+
+        // the second read goes over the budget without waiting for it
+        let start = Instant::now();
+        assert!(storage.get(block.cid()).unwrap().is_some());
+        assert!(storage.get(block.cid()).unwrap().is_none());
+        assert!(start.elapsed() < Duration::from_millis(100));
+        assert!(storage.contains(block.cid()).unwrap());
+    }
+}
@@ -0,0 +1,277 @@
+//! In-memory nodes for the tests.
+//!
+//! A [`TestNode`] runs a [`UrsaService`] over libp2p's memory transport, with its
+//! blocks and provider records in a [`MemoryDB`], so the bitswap, gossip and provider
+//! flows between several nodes are tested without sockets or RocksDB. The nodes are
+//! connected with [`TestNode::dial`] before they start, or all at once by
+//! [`connected_nodes`], and the tests await the [`NodeEvent`]s of a node with
+//! [`TestNode::wait_for`] instead of sleeping. The `faults` of the config are injected
+//! into the memory connections too.
+
+use anyhow::{anyhow, Result};
+use async_std::{
+    channel::Sender,
+    future,
+    sync::RwLock,
+    task::{self, JoinHandle},
+};
+use cid::Cid;
+use db::MemoryDB;
+use futures::channel::oneshot;
+use libipld::{Block, DefaultParams};
+use libp2p::{
+    core::{
+        muxing::StreamMuxerBox,
+        transport::{Boxed, MemoryTransport},
+        upgrade,
+    },
+    identity::Keypair,
+    multiaddr::Protocol,
+    noise, yamux, Multiaddr, PeerId, Transport,
+};
+use libp2p_bitswap::BitswapStore;
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tracing::error;
+use ursa_index_provider::{config::ProviderConfig, provider::Provider};
+use ursa_store::{BitswapStorage, Store};
+
+use crate::{
+    events::NodeEvent,
+    faults::{FaultInjector, Faulty},
+    BitswapType, NetworkConfig, UrsaCommand, UrsaService,
+};
+
+/// Time an awaited event or fetch may take.
+pub const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Memory port of the next node, unique within the test binary.
+static NEXT_PORT: AtomicU64 = AtomicU64::new(1);
+
+/// Config of a node only talking to the nodes it is connected to.
+pub fn test_config() -> NetworkConfig {
+    NetworkConfig {
+        mdns: false,
+        autonat: false,
+        relay_client: false,
+        relay_server: false,
+        bootstrap_nodes: vec![],
+        reputation_path: None,
+        ..Default::default()
+    }
+}
+
+/// Memory transport suffering the faults of `config`.
+fn memory_transport(keypair: &Keypair, config: &NetworkConfig) -> Boxed<(PeerId, StreamMuxerBox)> {
+    let noise_keys = noise::Keypair::<noise::X25519Spec>::new()
+        .into_authentic(keypair)
+        .expect("Signing libp2p-noise static DH keypair failed.");
+    let faults = config
+        .faults
+        .as_ref()
+        .map(|faults| Arc::new(FaultInjector::new(faults)));
+    MemoryTransport::default()
+        .map(move |conn, endpoint| {
+            let faults = faults
+                .as_ref()
+                .map(|faults| faults.connect(endpoint.get_remote_address()));
+            Faulty::new(conn, faults)
+        })
+        .upgrade(upgrade::Version::V1)
+        .authenticate(noise::NoiseConfig::xx(noise_keys).into_authenticated())
+        .multiplex(yamux::YamuxConfig::default())
+        .boxed()
+}
+
+pub struct TestNode {
+    pub peer_id: PeerId,
+    /// Memory address of the node, with its `/p2p` peer id.
+    pub addr: Multiaddr,
+    pub store: Arc<Store<MemoryDB>>,
+    /// Commands of the node, e.g. the gossip messages to publish.
+    pub commands: Sender<UrsaCommand>,
+    /// Subscribed before the start, so no event of the node is missed.
+    events: Receiver<NodeEvent>,
+    /// The service until it is started.
+    service: Option<UrsaService<MemoryDB>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl TestNode {
+    /// Node of `config` listening on a memory address of its own, not started yet.
+    pub fn new(mut config: NetworkConfig) -> Self {
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = PeerId::from(keypair.public());
+        let listen = Multiaddr::from(Protocol::Memory(NEXT_PORT.fetch_add(1, Ordering::SeqCst)));
+        config.swarm_addrs = vec![listen.clone()];
+
+        let store = Arc::new(Store::new(Arc::new(MemoryDB::default())));
+        let provider = Provider::new(
+            keypair.clone(),
+            Arc::new(RwLock::new(MemoryDB::default())),
+            ProviderConfig::default(),
+        );
+        let transport = memory_transport(&keypair, &config);
+        let service = UrsaService::with_transport(
+            keypair,
+            &config,
+            Arc::clone(&store),
+            provider,
+            Some(transport),
+        )
+        .unwrap();
+        Self {
+            peer_id,
+            addr: listen.with(Protocol::P2p(peer_id.into())),
+            store,
+            commands: service.command_sender().clone(),
+            events: service.node_events().subscribe(),
+            service: Some(service),
+            task: None,
+        }
+    }
+
+    /// Connect to `other` once started.
+    pub fn dial(&mut self, other: &TestNode) {
+        self.service
+            .as_mut()
+            .expect("The nodes are dialed before they start")
+            .dial(other.addr.clone())
+            .expect("Dialing a memory address must succeed");
+    }
+
+    /// Run the service loop of the node.
+    pub fn start(&mut self) {
+        if let Some(service) = self.service.take() {
+            self.task = Some(task::spawn(async move {
+                if let Err(err) = service.start().await {
+                    error!("[test_node] - {:?}", err);
+                }
+            }));
+        }
+    }
+
+    /// Stop the service loop of the node.
+    pub async fn stop(mut self) {
+        if let Some(task) = self.task.take() {
+            task.cancel().await;
+        }
+    }
+
+    /// Next event of the node matching `predicate`, the others are skipped.
+    pub async fn wait_for<F>(&mut self, mut predicate: F) -> Result<NodeEvent>
+    where
+        F: FnMut(&NodeEvent) -> bool,
+    {
+        let events = &mut self.events;
+        future::timeout(TEST_TIMEOUT, async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) if predicate(&event) => return Ok(event),
+                    Ok(_) | Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => return Err(anyhow!("The node stopped")),
+                }
+            }
+        })
+        .await
+        .map_err(|_| anyhow!("No matching event within {:?}", TEST_TIMEOUT))?
+    }
+
+    /// Store `block` on the node.
+    pub fn insert(&self, block: &Block<DefaultParams>) -> Result<()> {
+        BitswapStorage(Arc::clone(&self.store)).insert(block)
+    }
+
+    /// Fetch the block `cid`, or the dag under it with [`BitswapType::Sync`], from the
+    /// connected peers.
+    pub async fn fetch(&self, cid: Cid, query: BitswapType) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.commands
+            .send(UrsaCommand::GetBitswap {
+                cid,
+                query,
+                providers: vec![],
+                sender,
+            })
+            .await?;
+        future::timeout(TEST_TIMEOUT, receiver)
+            .await
+            .map_err(|_| anyhow!("{cid} was not fetched within {:?}", TEST_TIMEOUT))??
+    }
+}
+
+/// `count` started nodes of `config`, each connected to all the others.
+pub async fn connected_nodes<F>(count: usize, config: F) -> Result<Vec<TestNode>>
+where
+    F: Fn() -> NetworkConfig,
+{
+    let mut nodes: Vec<TestNode> = (0..count).map(|_| TestNode::new(config())).collect();
+    for later in 1..count {
+        let (earlier, rest) = nodes.split_at_mut(later);
+        for node in earlier.iter() {
+            rest[0].dial(node);
+        }
+    }
+    for node in &mut nodes {
+        node.start();
+    }
+    let peers: Vec<String> = nodes.iter().map(|node| node.peer_id.to_string()).collect();
+    for (i, node) in nodes.iter_mut().enumerate() {
+        let mut missing: HashSet<&String> = peers
+            .iter()
+            .enumerate()
+            .filter(|(j, _)| *j != i)
+            .map(|(_, peer)| peer)
+            .collect();
+        while !missing.is_empty() {
+            if let NodeEvent::PeerConnected { peer } = node
+                .wait_for(|event| matches!(event, NodeEvent::PeerConnected { .. }))
+                .await?
+            {
+                missing.remove(&peer);
+            }
+        }
+    }
+    Ok(nodes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libipld::{cbor::DagCborCodec, ipld, multihash::Code};
+    use ursa_utils::ToCid;
+
+    #[async_std::test]
+    async fn test_memory_nodes() {
+        let mut nodes = connected_nodes(2, test_config).await.unwrap();
+        let block =
+            Block::encode(DagCborCodec, Code::Blake3_256, &ipld!({ "hello": "swarm" })).unwrap();
+        nodes[0].insert(&block).unwrap();
+
+        let cid = block.cid().to_cid();
+        nodes[1].fetch(cid, BitswapType::Get).await.unwrap();
+        assert!(nodes[1].store.blockstore().has(&cid).unwrap());
+        let completed = nodes[1]
+            .wait_for(|event| matches!(event, NodeEvent::BitswapCompleted { .. }))
+            .await
+            .unwrap();
+        assert_eq!(
+            completed,
+            NodeEvent::BitswapCompleted {
+                cid: cid.to_string(),
+                found: true
+            }
+        );
+
+        for node in nodes {
+            node.stop().await;
+        }
+    }
+}
@@ -9,6 +9,7 @@ use libp2p::{
         muxing::StreamMuxerBox,
         transport::{upgrade, Boxed, OrTransport},
         upgrade::SelectUpgrade,
+        ConnectedPoint,
     },
     dns::DnsConfig,
     identity::Keypair,
@@ -18,22 +19,50 @@ use libp2p::{
     yamux, PeerId, Transport,
 };
 
-use crate::config::NetworkConfig;
+use std::{sync::Arc, time::Duration};
+
+use crate::{
+    config::NetworkConfig,
+    faults::{FaultInjector, Faulty},
+    proxy::ProxyTransport,
+    shaping::{Shaped, TokenBucket},
+};
 
 pub struct UrsaTransport;
 
+/// Wrap the connections in the upload cap and the injected faults.
+fn wrap<C>(
+    faults: Option<Arc<FaultInjector>>,
+    upload_cap: Option<Arc<TokenBucket>>,
+) -> impl FnOnce(C, ConnectedPoint) -> Faulty<Shaped<C>> + Clone {
+    move |conn, endpoint| {
+        let faults = faults
+            .as_ref()
+            .map(|faults| faults.connect(endpoint.get_remote_address()));
+        Faulty::new(Shaped::new(conn, upload_cap.clone()), faults)
+    }
+}
+
 impl UrsaTransport {
     /// Creates a new [`UrsaTransport`].
     ///
-    /// Defaults to QUIC transport over TCP.
-    /// If QUIC fails to establish a connection, we fail over to TCP.
+    /// Dials and listens over TCP only, and through the relays when the relay client
+    /// is on. QUIC and websocket addresses are refused when the config is read.
+    /// Writes to every connection take their bytes from `upload_cap` when set, and
+    /// suffer the faults of the config.
     pub fn new(
         keypair: &Keypair,
         config: &NetworkConfig,
         relay_transport: Option<ClientTransport>,
+        upload_cap: Option<Arc<TokenBucket>>,
     ) -> Boxed<(PeerId, StreamMuxerBox)> {
         let id_keys = keypair;
         let local_peer_id = PeerId::from(keypair.public());
+        let timeout = Duration::from_secs(config.dialing.timeout_secs);
+        let faults = config
+            .faults
+            .as_ref()
+            .map(|faults| Arc::new(FaultInjector::new(faults)));
 
         let tcp = {
             let noise = {
@@ -57,17 +86,22 @@ impl UrsaTransport {
 
             let tcp = TcpTransport::new(GenTcpConfig::new());
             let tcp = block_on(DnsConfig::system(tcp)).unwrap();
+            let tcp = ProxyTransport::new(tcp, config.proxy.clone());
 
             if let Some(relay) = relay_transport {
                 tcp.or_transport(relay)
+                    .map(wrap(faults, upload_cap))
                     .upgrade(upgrade::Version::V1)
                     .authenticate(noise)
                     .multiplex(mplex)
+                    .timeout(timeout)
                     .boxed()
             } else {
-                tcp.upgrade(upgrade::Version::V1)
+                tcp.map(wrap(faults, upload_cap))
+                    .upgrade(upgrade::Version::V1)
                     .authenticate(noise)
                     .multiplex(mplex)
+                    .timeout(timeout)
                     .boxed()
             }
         };
@@ -0,0 +1,128 @@
+//! Ursa store worker pool.
+//!
+//! Store heavy work such as dag traversals, car writes and advertisement creation
+//! would stall the swarm if it ran on the service loop. The [`WorkerPool`] runs that
+//! work on a fixed number of tasks fed by a bounded queue, and hands the results back
+//! to the loop so only the swarm mutations happen there. A full queue makes the loop
+//! wait, which is tracked as backpressure. The jobs make their RocksDB calls through
+//! `Store::blocking`, so a slow disk holds up the jobs but not the executor threads.
+
+use async_std::{
+    channel::{bounded, unbounded, Receiver, Sender, TrySendError},
+    task,
+};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use tracing::{debug, warn};
+use ursa_metrics::events::{track, MetricEvent};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct WorkerConfig {
+    /// Number of tasks running store work concurrently.
+    pub workers: usize,
+    /// Jobs queued before the service loop waits for a free worker.
+    pub queue_size: usize,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        Self {
+            workers: 4,
+            queue_size: 128,
+        }
+    }
+}
+
+type Job<T> = BoxFuture<'static, Option<T>>;
+
+pub struct WorkerPool<T> {
+    jobs: Sender<Job<T>>,
+}
+
+impl<T> Clone for WorkerPool<T> {
+    fn clone(&self) -> Self {
+        Self {
+            jobs: self.jobs.clone(),
+        }
+    }
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+    /// Spawn the workers, returning the pool and the receiver of the job results.
+    pub fn new(config: &WorkerConfig) -> (Self, Receiver<T>) {
+        let (jobs, queue) = bounded::<Job<T>>(config.queue_size.max(1));
+        // results stay unbounded, the loop may be waiting on a full job queue while
+        // the workers hand their results back
+        let (results, output) = unbounded();
+
+        for worker in 0..config.workers.max(1) {
+            let queue = queue.clone();
+            let results = results.clone();
+            task::spawn(async move {
+                while let Ok(job) = queue.recv().await {
+                    track(MetricEvent::WorkerJobStarted, None, None);
+                    if let Some(result) = job.await {
+                        if results.send(result).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+                debug!("store worker {} stopped", worker);
+            });
+        }
+
+        (Self { jobs }, output)
+    }
+
+    /// Queue `job`, waiting for room if every worker is busy and the queue is full.
+    pub async fn submit<F>(&self, job: F)
+    where
+        F: Future<Output = Option<T>> + Send + 'static,
+    {
+        let job: Job<T> = Box::pin(job);
+        // count the job before a worker can pick it up, so the depth never goes negative
+        track(MetricEvent::WorkerJobQueued, None, None);
+        let sent = match self.jobs.try_send(job) {
+            Ok(()) => true,
+            Err(TrySendError::Full(job)) => {
+                track(MetricEvent::WorkerQueueFull, None, None);
+                self.jobs.send(job).await.is_ok()
+            }
+            Err(TrySendError::Closed(_)) => false,
+        };
+
+        if !sent {
+            track(MetricEvent::WorkerJobStarted, None, None);
+            warn!("the store workers stopped, dropping job");
+        }
+    }
+
+    /// Number of jobs waiting for a worker.
+    pub fn queued(&self) -> usize {
+        self.jobs.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn test_worker_pool() {
+        let config = WorkerConfig {
+            workers: 2,
+            queue_size: 1,
+        };
+        let (pool, results) = WorkerPool::new(&config);
+
+        for i in 0..4u32 {
+            pool.submit(async move { (i % 2 == 0).then_some(i) }).await;
+        }
+
+        let mut done = vec![results.recv().await.unwrap(), results.recv().await.unwrap()];
+        done.sort_unstable();
+        assert_eq!(done, vec![0, 2]);
+    }
+}
@@ -1,11 +1,57 @@
 use jsonrpc_v2::Error;
 
 use ursa_rpc_server::{
+    api::{NetworkAccessLogParams, NetworkAccessLogResult, NETWORK_ACCESS_LOG},
+    api::{NetworkAccountingParams, NetworkAccountingResult, NETWORK_ACCOUNTING},
     api::{
-        NetworkGetFileParams, NetworkPutFileParams, NetworkPutFileResult, NETWORK_GET_FILE,
-        NETWORK_PUT_FILE,
+        NetworkAclListParams, NetworkAclListResult, NetworkAclRemoveParams, NetworkAclRemoveResult,
+        NetworkAclSetParams, NetworkAclSetResult, NETWORK_ACL_LIST, NETWORK_ACL_REMOVE,
+        NETWORK_ACL_SET,
+    },
+    api::{
+        NetworkApiKeyUsageParams, NetworkApiKeyUsageResult, NetworkCreateApiKeyParams,
+        NetworkCreateApiKeyResult, NetworkRevokeApiKeyParams, NetworkRevokeApiKeyResult,
+        NETWORK_API_KEY_USAGE, NETWORK_CREATE_API_KEY, NETWORK_REVOKE_API_KEY,
+    },
+    api::{NetworkCancelParams, NetworkCancelResult, NETWORK_CANCEL},
+    api::{
+        NetworkConfigGetParams, NetworkConfigGetResult, NetworkConfigSetParams,
+        NetworkConfigSetResult, NETWORK_CONFIG_GET, NETWORK_CONFIG_SET,
+    },
+    api::{NetworkDagStatParams, NetworkDagStatResult, NETWORK_DAG_STAT},
+    api::{NetworkDiagnosticsParams, NetworkDiagnosticsResult, NETWORK_DIAGNOSTICS},
+    api::{NetworkFindProvidersParams, NetworkFindProvidersResult, NETWORK_FIND_PROVIDERS},
+    api::{
+        NetworkGetFileParams, NetworkGetFileResult, NetworkPutFileParams, NetworkPutFileResult,
+        NETWORK_GET_FILE, NETWORK_PUT_FILE,
     },
     api::{NetworkGetParams, NetworkGetResult, NETWORK_GET},
+    api::{NetworkGossipStatParams, NetworkGossipStatResult, NETWORK_GOSSIP_STAT},
+    api::{NetworkListContentParams, NetworkListContentResult, NETWORK_LIST_CONTENT},
+    api::{
+        NetworkNamePublishParams, NetworkNamePublishResult, NetworkNameResolveParams,
+        NetworkNameResolveResult, NETWORK_NAME_PUBLISH, NETWORK_NAME_RESOLVE,
+    },
+    api::{NetworkNodeInfoParams, NetworkNodeInfoResult, NETWORK_NODE_INFO},
+    api::{NetworkOperationStatusParams, NetworkOperationStatusResult, NETWORK_OPERATION_STATUS},
+    api::{NetworkPeerProtocolsParams, NetworkPeerProtocolsResult, NETWORK_PEER_PROTOCOLS},
+    api::{
+        NetworkPrefetchParams, NetworkPrefetchResult, NetworkPrefetchStatusParams,
+        NetworkPrefetchStatusResult, NETWORK_PREFETCH, NETWORK_PREFETCH_STATUS,
+    },
+    api::{NetworkProviderStatusParams, NetworkProviderStatusResult, NETWORK_PROVIDER_STATUS},
+    api::{NetworkPurgeParams, NetworkPurgeResult, NETWORK_PURGE},
+    api::{NetworkPutUrlParams, NetworkPutUrlResult, NETWORK_PUT_URL},
+    api::{NetworkReceiptsParams, NetworkReceiptsResult, NETWORK_RECEIPTS},
+    api::{NetworkRelayCircuitsParams, NetworkRelayCircuitsResult, NETWORK_RELAY_CIRCUITS},
+    api::{NetworkRemoveParams, NetworkRemoveResult, NETWORK_REMOVE},
+    api::{NetworkRepoCompactParams, NetworkRepoCompactResult, NETWORK_REPO_COMPACT},
+    api::{NetworkResolveParams, NetworkResolveResult, NETWORK_RESOLVE},
+    api::{NetworkSetContextParams, NetworkSetContextResult, NETWORK_SET_CONTEXT},
+    api::{NetworkSignUrlParams, NetworkSignUrlResult, NETWORK_SIGN_URL},
+    api::{NetworkTopicPeersParams, NetworkTopicPeersResult, NETWORK_TOPIC_PEERS},
+    api::{NetworkUnindexContextParams, NetworkUnindexContextResult, NETWORK_UNINDEX_CONTEXT},
+    api::{NetworkVerifyParams, NetworkVerifyResult, NETWORK_VERIFY},
 };
 
 use crate::{
@@ -19,10 +65,172 @@ pub async fn get_block(params: NetworkGetParams) -> Result<NetworkGetResult> {
     call(NETWORK_GET, params, Post).await
 }
 
-pub async fn get_file(params: NetworkGetFileParams) -> Result<()> {
+pub async fn get_file(params: NetworkGetFileParams) -> Result<NetworkGetFileResult> {
     call(NETWORK_GET_FILE, params, Put).await
 }
 
 pub async fn put_file(params: NetworkPutFileParams) -> Result<NetworkPutFileResult> {
     call(NETWORK_PUT_FILE, params, Put).await
 }
+
+pub async fn prefetch(params: NetworkPrefetchParams) -> Result<NetworkPrefetchResult> {
+    call(NETWORK_PREFETCH, params, Post).await
+}
+
+pub async fn prefetch_status(
+    params: NetworkPrefetchStatusParams,
+) -> Result<NetworkPrefetchStatusResult> {
+    call(NETWORK_PREFETCH_STATUS, params, Post).await
+}
+
+pub async fn access_log(params: NetworkAccessLogParams) -> Result<NetworkAccessLogResult> {
+    call(NETWORK_ACCESS_LOG, params, Post).await
+}
+
+pub async fn receipts(params: NetworkReceiptsParams) -> Result<NetworkReceiptsResult> {
+    call(NETWORK_RECEIPTS, params, Post).await
+}
+
+pub async fn accounting(params: NetworkAccountingParams) -> Result<NetworkAccountingResult> {
+    call(NETWORK_ACCOUNTING, params, Post).await
+}
+
+pub async fn topic_peers(params: NetworkTopicPeersParams) -> Result<NetworkTopicPeersResult> {
+    call(NETWORK_TOPIC_PEERS, params, Post).await
+}
+
+pub async fn gossip_stat(params: NetworkGossipStatParams) -> Result<NetworkGossipStatResult> {
+    call(NETWORK_GOSSIP_STAT, params, Post).await
+}
+
+pub async fn find_providers(
+    params: NetworkFindProvidersParams,
+) -> Result<NetworkFindProvidersResult> {
+    call(NETWORK_FIND_PROVIDERS, params, Post).await
+}
+
+pub async fn node_info(params: NetworkNodeInfoParams) -> Result<NetworkNodeInfoResult> {
+    call(NETWORK_NODE_INFO, params, Post).await
+}
+
+pub async fn relay_circuits(
+    params: NetworkRelayCircuitsParams,
+) -> Result<NetworkRelayCircuitsResult> {
+    call(NETWORK_RELAY_CIRCUITS, params, Post).await
+}
+
+pub async fn peer_protocols(
+    params: NetworkPeerProtocolsParams,
+) -> Result<NetworkPeerProtocolsResult> {
+    call(NETWORK_PEER_PROTOCOLS, params, Post).await
+}
+
+pub async fn put_url(params: NetworkPutUrlParams) -> Result<NetworkPutUrlResult> {
+    call(NETWORK_PUT_URL, params, Post).await
+}
+
+pub async fn operation_status(
+    params: NetworkOperationStatusParams,
+) -> Result<NetworkOperationStatusResult> {
+    call(NETWORK_OPERATION_STATUS, params, Post).await
+}
+
+pub async fn cancel(params: NetworkCancelParams) -> Result<NetworkCancelResult> {
+    call(NETWORK_CANCEL, params, Post).await
+}
+
+pub async fn remove(params: NetworkRemoveParams) -> Result<NetworkRemoveResult> {
+    call(NETWORK_REMOVE, params, Post).await
+}
+
+pub async fn set_context(params: NetworkSetContextParams) -> Result<NetworkSetContextResult> {
+    call(NETWORK_SET_CONTEXT, params, Post).await
+}
+
+pub async fn unindex_context(
+    params: NetworkUnindexContextParams,
+) -> Result<NetworkUnindexContextResult> {
+    call(NETWORK_UNINDEX_CONTEXT, params, Post).await
+}
+
+pub async fn purge(params: NetworkPurgeParams) -> Result<NetworkPurgeResult> {
+    call(NETWORK_PURGE, params, Post).await
+}
+
+pub async fn provider_status(
+    params: NetworkProviderStatusParams,
+) -> Result<NetworkProviderStatusResult> {
+    call(NETWORK_PROVIDER_STATUS, params, Post).await
+}
+
+pub async fn create_api_key(
+    params: NetworkCreateApiKeyParams,
+) -> Result<NetworkCreateApiKeyResult> {
+    call(NETWORK_CREATE_API_KEY, params, Post).await
+}
+
+pub async fn revoke_api_key(
+    params: NetworkRevokeApiKeyParams,
+) -> Result<NetworkRevokeApiKeyResult> {
+    call(NETWORK_REVOKE_API_KEY, params, Post).await
+}
+
+pub async fn api_key_usage(params: NetworkApiKeyUsageParams) -> Result<NetworkApiKeyUsageResult> {
+    call(NETWORK_API_KEY_USAGE, params, Post).await
+}
+
+pub async fn sign_url(params: NetworkSignUrlParams) -> Result<NetworkSignUrlResult> {
+    call(NETWORK_SIGN_URL, params, Post).await
+}
+
+pub async fn acl_set(params: NetworkAclSetParams) -> Result<NetworkAclSetResult> {
+    call(NETWORK_ACL_SET, params, Post).await
+}
+
+pub async fn acl_remove(params: NetworkAclRemoveParams) -> Result<NetworkAclRemoveResult> {
+    call(NETWORK_ACL_REMOVE, params, Post).await
+}
+
+pub async fn acl_list(params: NetworkAclListParams) -> Result<NetworkAclListResult> {
+    call(NETWORK_ACL_LIST, params, Post).await
+}
+
+pub async fn list_content(params: NetworkListContentParams) -> Result<NetworkListContentResult> {
+    call(NETWORK_LIST_CONTENT, params, Post).await
+}
+
+pub async fn dag_stat(params: NetworkDagStatParams) -> Result<NetworkDagStatResult> {
+    call(NETWORK_DAG_STAT, params, Post).await
+}
+
+pub async fn verify(params: NetworkVerifyParams) -> Result<NetworkVerifyResult> {
+    call(NETWORK_VERIFY, params, Post).await
+}
+
+pub async fn resolve(params: NetworkResolveParams) -> Result<NetworkResolveResult> {
+    call(NETWORK_RESOLVE, params, Post).await
+}
+
+pub async fn name_publish(params: NetworkNamePublishParams) -> Result<NetworkNamePublishResult> {
+    call(NETWORK_NAME_PUBLISH, params, Post).await
+}
+
+pub async fn name_resolve(params: NetworkNameResolveParams) -> Result<NetworkNameResolveResult> {
+    call(NETWORK_NAME_RESOLVE, params, Post).await
+}
+
+pub async fn repo_compact(params: NetworkRepoCompactParams) -> Result<NetworkRepoCompactResult> {
+    call(NETWORK_REPO_COMPACT, params, Post).await
+}
+
+pub async fn config_get(params: NetworkConfigGetParams) -> Result<NetworkConfigGetResult> {
+    call(NETWORK_CONFIG_GET, params, Post).await
+}
+
+pub async fn config_set(params: NetworkConfigSetParams) -> Result<NetworkConfigSetResult> {
+    call(NETWORK_CONFIG_SET, params, Post).await
+}
+
+pub async fn diagnostics(params: NetworkDiagnosticsParams) -> Result<NetworkDiagnosticsResult> {
+    call(NETWORK_DIAGNOSTICS, params, Post).await
+}
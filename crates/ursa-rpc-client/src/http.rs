@@ -0,0 +1,123 @@
+//! Typed functions for the http routes, see `/openapi.json` on a node for the full
+//! description of each.
+
+use anyhow::{anyhow, Result};
+use cid::Cid;
+use serde::Deserialize;
+use surf::StatusCode;
+use ursa_rpc_server::{
+    api_keys::API_KEY_HEADER, config::ServerConfig, error::ApiError, operations::OperationId,
+};
+
+/// Response header carrying the operation id of an upload.
+const OPERATION_HEADER: &str = "x-ursa-operation";
+
+const MULTIPART_BOUNDARY: &str = "ursa-client-upload";
+
+pub struct UploadResult {
+    /// Root cids of the car file, as reported by the node.
+    pub roots: String,
+    /// Id of the put, to follow with `operation_status`.
+    pub operation: Option<OperationId>,
+}
+
+fn url(path: &str) -> String {
+    let ServerConfig { port, addr, .. } = ServerConfig::default();
+    format!("http://{}:{}{}", addr, port, path)
+}
+
+/// Upload a car file to the node, counting against `api_key` when given.
+pub async fn upload_car(car: Vec<u8>, api_key: Option<&str>) -> Result<UploadResult> {
+    let mut res = send_upload("/", car, api_key).await?;
+    if res.status() != StatusCode::Ok {
+        return Err(error_of(&mut res, "Upload failed").await);
+    }
+    let message: String = res.body_json().await.map_err(|e| anyhow!(e.to_string()))?;
+
+    Ok(UploadResult {
+        roots: message,
+        operation: res
+            .header(OPERATION_HEADER)
+            .and_then(|values| values.last().as_str().parse().ok()),
+    })
+}
+
+/// Upload a car file to the node without waiting for the put, returning the id to
+/// follow it with.
+pub async fn upload_car_background(car: Vec<u8>, api_key: Option<&str>) -> Result<OperationId> {
+    #[derive(Deserialize)]
+    struct Accepted {
+        operation: OperationId,
+    }
+
+    let mut res = send_upload("/?background=true", car, api_key).await?;
+    if res.status() != StatusCode::Accepted {
+        return Err(error_of(&mut res, "Upload failed").await);
+    }
+    let accepted: Accepted = res.body_json().await.map_err(|e| anyhow!(e.to_string()))?;
+    Ok(accepted.operation)
+}
+
+async fn send_upload(path: &str, car: Vec<u8>, api_key: Option<&str>) -> Result<surf::Response> {
+    let mut body = format!(
+        "--{MULTIPART_BOUNDARY}\r\n\
+         Content-Disposition: form-data; name=\"file\"; filename=\"upload.car\"\r\n\
+         Content-Type: application/vnd.curl.car\r\n\r\n"
+    )
+    .into_bytes();
+    body.extend(car);
+    body.extend(format!("\r\n--{MULTIPART_BOUNDARY}--\r\n").into_bytes());
+
+    let mut request = surf::post(url(path))
+        .header(
+            "content-type",
+            format!("multipart/form-data; boundary={MULTIPART_BOUNDARY}"),
+        )
+        .body(body);
+    if let Some(api_key) = api_key {
+        request = request.header(API_KEY_HEADER, api_key);
+    }
+    request.await.map_err(|e| anyhow!(e.to_string()))
+}
+
+/// Error of a failed response, the [`ApiError`] of the node when the body is one, so
+/// callers can downcast to branch on its code.
+async fn error_of(res: &mut surf::Response, context: &str) -> anyhow::Error {
+    let status = res.status();
+    let body = res.body_string().await.unwrap_or_default();
+    match serde_json::from_str::<ApiError>(&body) {
+        Ok(err) => anyhow!(err).context(format!("{context} with {status}")),
+        Err(_) => anyhow!("{context} with {status}: {body}"),
+    }
+}
+
+/// Download the dag under `cid` as a car file.
+pub async fn get_car(cid: &Cid) -> Result<Vec<u8>> {
+    let mut res = surf::get(url(&format!("/{}", cid)))
+        .await
+        .map_err(|e| anyhow!(e.to_string()))?;
+    if res.status() != StatusCode::Ok {
+        return Err(error_of(&mut res, &format!("Getting {} failed", cid)).await);
+    }
+    res.body_bytes().await.map_err(|e| anyhow!(e.to_string()))
+}
+
+/// Whether the node can serve the content under `cid`.
+pub async fn has_content(cid: &Cid) -> Result<bool> {
+    let res = surf::head(url(&format!("/{}", cid)))
+        .await
+        .map_err(|e| anyhow!(e.to_string()))?;
+    match res.status() {
+        StatusCode::Ok => Ok(true),
+        StatusCode::NotFound => Ok(false),
+        status => Err(anyhow!("Checking {} failed with {}", cid, status)),
+    }
+}
+
+/// OpenAPI document of the node.
+pub async fn openapi() -> Result<serde_json::Value> {
+    surf::get(url("/openapi.json"))
+        .recv_json()
+        .await
+        .map_err(|e| anyhow!(e.to_string()))
+}
@@ -1,4 +1,5 @@
 pub mod functions;
+pub mod http;
 
 use anyhow::Result;
 use jsonrpc_v2::{Error, Id, RequestObject, V2};
@@ -6,13 +7,16 @@ use jsonrpc_v2::{Error, Id, RequestObject, V2};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use tracing::{debug, error, info};
-use ursa_rpc_server::config::ServerConfig;
+use ursa_rpc_server::{config::ServerConfig, error::ApiError};
 
 /// Error object in a response
 #[derive(Deserialize)]
 pub struct JsonRpcError {
     pub code: i64,
     pub message: String,
+    /// Typed error of the node, with its machine-readable code.
+    #[serde(default)]
+    pub data: Option<ApiError>,
 }
 
 #[derive(Deserialize)]
@@ -48,7 +52,7 @@ where
             .with_id(1)
             .finish();
 
-        let ServerConfig { port, addr } = ServerConfig::default();
+        let ServerConfig { port, addr, .. } = ServerConfig::default();
         let api_url = format!("http://{}:{}/rpc/v0", addr, port);
 
         info!("Using JSON-RPC v2 HTTP URL: {}", api_url);
@@ -102,7 +106,10 @@ where
             match rpc_res {
                 JsonRpcResponse::Result { result, .. } => Ok(result),
                 JsonRpcResponse::Error { error, .. } => Err(Error::Full {
-                    data: None,
+                    data: match error.data {
+                        Some(data) => Some(Box::new(data)),
+                        None => None,
+                    },
                     code: error.code,
                     message: error.message,
                 }),
@@ -136,10 +143,8 @@ mod tests {
 
     use crate::functions::{get_block, put_file};
 
-    use cid::Cid;
     use libipld::{cbor::DagCborCodec, ipld, multihash::Code, Block, DefaultParams, Ipld};
     use ursa_rpc_server::api::{NetworkGetParams, NetworkPutFileParams};
-    use ursa_utils::convert_cid;
 
     fn create_block(ipld: Ipld) -> Block<DefaultParams> {
         Block::encode(DagCborCodec, Code::Blake3_256, &ipld).unwrap()
@@ -157,10 +162,12 @@ mod tests {
     async fn test_rpc_get_cid() {
         setup_logger(LevelFilter::Info);
         let block = create_block(ipld!(&b"hello world"[..]));
-        let cid = convert_cid(block.cid().to_bytes());
-        let string_cid = Cid::to_string(&cid);
+        let string_cid = block.cid().to_string();
         let params = NetworkGetParams {
             cid: string_cid.clone(),
+            timeout_ms: None,
+            providers: vec![],
+            api_key: None,
         };
         match get_block(params).await {
             Ok(v) => {
@@ -177,6 +184,9 @@ mod tests {
         setup_logger(LevelFilter::Info);
         let params = NetworkPutFileParams {
             path: "./car_files/ursa_major.car".to_string(),
+            background: false,
+            ignore: vec![],
+            api_key: None,
         };
         match put_file(params).await {
             Ok(v) => {
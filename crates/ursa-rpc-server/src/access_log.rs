@@ -0,0 +1,356 @@
+//! Access log for served content.
+//!
+//! Every retrieval is kept in an in-memory ring buffer, queryable through the
+//! `ursa_access_log` admin rpc, and optionally appended as a json line to a
+//! size-rotated log file for offline analytics. The file is written on a thread of its
+//! own, retrievals only queue their entry and drop it when the writer falls behind.
+
+use bytes::Bytes;
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        mpsc::{sync_channel, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    thread::{self, JoinHandle},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::{error, warn};
+use ursa_metrics::events::{track, MetricEvent};
+use ursa_network::accounting::Accounting;
+
+use crate::{config::AccessLogConfig, receipts::Receipts};
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AccessLogEntry {
+    /// Unix time in milliseconds at which the retrieval started.
+    pub timestamp: u64,
+    pub cid: String,
+    pub bytes: u64,
+    /// Client ip, peer id, or the interface used when neither is known.
+    pub client: String,
+    pub duration_ms: u64,
+    /// Whether the content was already stored locally.
+    pub cache_hit: bool,
+}
+
+impl AccessLogEntry {
+    /// Start an entry for a retrieval that begins now.
+    pub fn start(cid: String, client: String, cache_hit: bool) -> PendingAccess {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+
+        PendingAccess {
+            entry: AccessLogEntry {
+                timestamp,
+                cid,
+                bytes: 0,
+                client,
+                duration_ms: 0,
+                cache_hit,
+            },
+            started: Instant::now(),
+        }
+    }
+}
+
+/// An entry whose duration is measured until it is finished.
+pub struct PendingAccess {
+    entry: AccessLogEntry,
+    started: Instant,
+}
+
+impl PendingAccess {
+    pub fn finish(mut self, bytes: u64) -> AccessLogEntry {
+        self.entry.bytes = bytes;
+        self.entry.duration_ms = self.started.elapsed().as_millis() as u64;
+        self.entry
+    }
+}
+
+/// Entries queued for the log file before new ones are dropped.
+const WRITE_QUEUE_SIZE: usize = 4096;
+
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+pub struct AccessLog {
+    config: AccessLogConfig,
+    buffer: Mutex<VecDeque<AccessLogEntry>>,
+    /// Queue of the thread writing the log file, with its handle.
+    writer: Option<(SyncSender<AccessLogEntry>, JoinHandle<()>)>,
+}
+
+impl Default for AccessLog {
+    /// An in-memory access log without a log file.
+    fn default() -> Self {
+        Self::new(AccessLogConfig {
+            path: None,
+            ..Default::default()
+        })
+    }
+}
+
+impl AccessLog {
+    pub fn new(config: AccessLogConfig) -> Self {
+        let file = config.path.as_ref().and_then(|path| match open(path) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                error!("Failed to open the access log at {:?}: {:?}", path, e);
+                None
+            }
+        });
+        let writer = file.map(|mut file| {
+            let (sender, receiver) = sync_channel::<AccessLogEntry>(WRITE_QUEUE_SIZE);
+            let (max_file_size, max_files) = (config.max_file_size, config.max_files);
+            let handle = thread::spawn(move || {
+                for entry in receiver {
+                    if let Err(e) = write(&mut file, &entry, max_file_size, max_files) {
+                        warn!("Failed to write the access log: {:?}", e);
+                    }
+                }
+            });
+            (sender, handle)
+        });
+
+        Self {
+            buffer: Mutex::new(VecDeque::with_capacity(config.buffer_size)),
+            writer,
+            config,
+        }
+    }
+
+    pub fn record(&self, entry: AccessLogEntry) {
+        if let Some((sender, _)) = &self.writer {
+            match sender.try_send(entry.clone()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    warn!("The access log file is behind, dropping the entry")
+                }
+                Err(TrySendError::Disconnected(_)) => {}
+            }
+        }
+
+        if self.config.buffer_size == 0 {
+            return;
+        }
+        let mut buffer = self.buffer.lock().unwrap();
+        if buffer.len() == self.config.buffer_size {
+            buffer.pop_front();
+        }
+        buffer.push_back(entry);
+    }
+
+    /// The most recent entries first, optionally only those for `cid`.
+    pub fn recent(&self, limit: usize, cid: Option<&str>) -> Vec<AccessLogEntry> {
+        self.buffer
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|entry| cid.map_or(true, |cid| entry.cid == cid))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
+
+impl Drop for AccessLog {
+    /// Write the queued entries before the log goes away.
+    fn drop(&mut self) {
+        if let Some((sender, handle)) = self.writer.take() {
+            drop(sender);
+            let _ = handle.join();
+        }
+    }
+}
+
+fn write(
+    file: &mut LogFile,
+    entry: &AccessLogEntry,
+    max_file_size: u64,
+    max_files: usize,
+) -> io::Result<()> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+
+    if file.size > 0 && file.size + line.len() as u64 > max_file_size {
+        rotate(&file.path, max_files)?;
+        *file = open(&file.path)?;
+    }
+
+    file.file.write_all(&line)?;
+    file.size += line.len() as u64;
+    Ok(())
+}
+
+fn open(path: &Path) -> io::Result<LogFile> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+
+    Ok(LogFile {
+        path: path.to_path_buf(),
+        file,
+        size,
+    })
+}
+
+/// Shift `access.log` to `access.log.1`, `access.log.1` to `access.log.2` and so on,
+/// dropping the oldest file beyond `max_files`.
+fn rotate(path: &Path, max_files: usize) -> io::Result<()> {
+    let rotated = |n: usize| PathBuf::from(format!("{}.{}", path.display(), n));
+
+    if max_files == 0 {
+        return fs::remove_file(path);
+    }
+
+    let _ = fs::remove_file(rotated(max_files));
+    for n in (1..max_files).rev() {
+        let from = rotated(n);
+        if from.exists() {
+            fs::rename(from, rotated(n + 1))?;
+        }
+    }
+    fs::rename(path, rotated(1))
+}
+
+/// Counts the bytes of a streamed response and records the access once it is dropped,
+/// whether the stream completed or the client went away.
+pub struct LoggedStream<S> {
+    inner: S,
+    bytes: u64,
+    pending: Option<PendingAccess>,
+    log: Arc<AccessLog>,
+    /// Receipts the bytes are recorded as delivered to, with the client key.
+    receipt: Option<(Arc<Receipts>, String)>,
+    /// Accounting the bytes are billed to, with the id of the api key.
+    accounting: Option<(Arc<Accounting>, Option<String>)>,
+}
+
+impl<S> LoggedStream<S> {
+    pub fn new(inner: S, pending: PendingAccess, log: Arc<AccessLog>) -> Self {
+        track(MetricEvent::StreamOpened, None, None);
+        Self {
+            inner,
+            bytes: 0,
+            pending: Some(pending),
+            log,
+            receipt: None,
+            accounting: None,
+        }
+    }
+
+    /// Record the bytes as a delivery to `client_key`, pending until it sends a receipt.
+    pub fn with_receipt(mut self, receipts: Arc<Receipts>, client_key: String) -> Self {
+        self.receipt = Some((receipts, client_key));
+        self
+    }
+
+    /// Account the bytes to the cid and to the api key with id `key_id`.
+    pub fn with_accounting(mut self, accounting: Arc<Accounting>, key_id: Option<String>) -> Self {
+        self.accounting = Some((accounting, key_id));
+        self
+    }
+}
+
+impl<S> Stream for LoggedStream<S>
+where
+    S: Stream<Item = io::Result<Bytes>> + Unpin,
+{
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = Pin::new(&mut self.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(bytes))) = &item {
+            self.bytes += bytes.len() as u64;
+        }
+        item
+    }
+}
+
+impl<S> Drop for LoggedStream<S> {
+    fn drop(&mut self) {
+        track(MetricEvent::StreamClosed, None, None);
+        if let Some(pending) = self.pending.take() {
+            let entry = pending.finish(self.bytes);
+            if let Some((receipts, client_key)) = self.receipt.take() {
+                receipts.delivered(&client_key, &entry.cid, entry.bytes);
+            }
+            if let Some((accounting, key_id)) = self.accounting.take() {
+                accounting.record(&entry.cid, key_id.as_deref(), None, entry.bytes);
+            }
+            self.log.record(entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(cid: &str) -> AccessLogEntry {
+        AccessLogEntry::start(cid.to_string(), "127.0.0.1".to_string(), true).finish(10)
+    }
+
+    #[test]
+    fn test_ring_buffer() {
+        let log = AccessLog::new(AccessLogConfig {
+            path: None,
+            buffer_size: 2,
+            ..Default::default()
+        });
+        log.record(entry("a"));
+        log.record(entry("b"));
+        log.record(entry("a"));
+
+        let recent = log.recent(10, None);
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].cid, "a");
+        assert_eq!(recent[1].cid, "b");
+        assert_eq!(log.recent(10, Some("a")).len(), 1);
+        assert_eq!(log.recent(1, None).len(), 1);
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = std::env::temp_dir().join("ursa_access_log_test");
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("access.log");
+
+        let line_len = serde_json::to_vec(&entry("a")).unwrap().len() as u64 + 1;
+        let log = AccessLog::new(AccessLogConfig {
+            path: Some(path.clone()),
+            max_file_size: line_len * 2,
+            max_files: 1,
+            buffer_size: 0,
+        });
+        for _ in 0..5 {
+            log.record(entry("a"));
+        }
+        // dropping the log waits for the writer
+        drop(log);
+
+        assert_eq!(fs::metadata(&path).unwrap().len(), line_len);
+        assert_eq!(
+            fs::metadata(dir.join("access.log.1")).unwrap().len(),
+            line_len * 2
+        );
+        assert!(!dir.join("access.log.2").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,86 +1,1770 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{BufWriter, Write},
+    mem,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
 use async_std::{
-    channel::{unbounded, Sender},
+    channel::{Sender, TrySendError},
     fs::create_dir_all,
-    io::{BufReader, WriteExt},
-    sync::RwLock,
+    future::timeout,
+    io::{self, BufReader, WriteExt},
+    task,
 };
 
 use anyhow::{anyhow, Result};
 use async_std::fs::File;
 use async_trait::async_trait;
-use axum::body::StreamBody;
 use cid::Cid;
-use futures::{channel::oneshot, AsyncRead};
-use fvm_ipld_car::{load_car, CarHeader};
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{join_all, FutureExt},
+    stream::BoxStream,
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, StreamExt,
+};
 use ipld_blockstore::BlockStore;
 use libipld::Cid as lCid;
+use libp2p::{Multiaddr, PeerId};
 use serde::{Deserialize, Serialize};
-use tokio_util::{compat::TokioAsyncWriteCompatExt, io::ReaderStream};
-use tracing::info;
-use ursa_network::{BitswapType, UrsaCommand};
-use ursa_store::{Dag, Store};
-use ursa_utils::convert_cid;
+use serde_json::Value;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+use ursa_index_provider::announce::AnnounceStatus;
+use ursa_metrics::events::{track, MetricEvent};
+use ursa_network::{
+    accounting::{Accounting, Rollup, UsageKind, UsageQuery},
+    acl::{Acl, AclEntry},
+    events::{NodeEvent, NodeEvents},
+    gossipsub::{GossipTopicStat, TopicPeers},
+    info::NodeInfo,
+    inventory::ProtocolInventory,
+    names::{NameRecord, DEFAULT_NAME_TTL_SECS},
+    purge::PurgeMessage,
+    relay::RelayCircuit,
+    shaping::Shaper,
+    BitswapType, Cancelled, ContentProvider, UrsaCommand,
+};
+use ursa_store::{
+    columns::{Column, ColumnDb},
+    scrub::Scrubber,
+    Dag, DagStat, Store,
+};
+use ursa_utils::{ToCid, ToIpldCid};
+
+use crate::{
+    access_log::{AccessLog, AccessLogEntry},
+    api_keys::{ApiKeyUsage, ApiKeys},
+    car::{BlockLoader, BlockStream, CarStream},
+    compaction::Compactor,
+    config::{
+        ApiKeyConfig, CarImportConfig, ChunkHash, DnsLinkConfig, OverflowPolicy, PutFileConfig,
+        PutUrlConfig, ReceiptConfig, RenderCacheConfig, SignedUrlConfig,
+    },
+    content::{
+        context_bytes, context_string, paginate, ContentEntry, ContentFilter, ContentIndex,
+        ContentPage,
+    },
+    diagnostics::{Diagnostics, DiagnosticsReport},
+    directory::{self, DirEntry},
+    dnslink::{DnsLink, DnsLinkTarget},
+    download,
+    error::ApiError,
+    import,
+    operations::{OperationId, OperationKind, OperationStatus, Operations, ProgressReader},
+    origin::Origin,
+    prefetch::PrefetchTracker,
+    receipts::{DeliveryReceipt, Receipts},
+    render_cache::RenderCache,
+    resolve::{self, Resolved},
+    settings::{SettingChange, Settings},
+    signed_url::{SignedUrl, SignedUrls},
+    singleflight::SingleFlight,
+    unixfs,
+    webhooks::{WebhookEvent, Webhooks},
+};
+
+pub const MAX_BLOCK_SIZE: usize = 1048576;
+pub const MAX_CHUNK_SIZE: usize = 104857600;
+pub const DEFAULT_CHUNK_SIZE: usize = 10 * 1024 * 1024; // chunk to ~10MB CARs
+/// Chunked leaves written to the store at once.
+const CHUNK_BATCH: usize = 16;
+
+/// Network Api
+#[derive(Deserialize, Serialize)]
+pub struct NetworkGetParams {
+    pub cid: String,
+    /// Optional. Time in milliseconds to wait for the block before giving up.
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// Optional. Peer ids or `/p2p` addresses of peers known to hold the block.
+    #[serde(default)]
+    pub providers: Vec<String>,
+    /// Optional. Api key of the request, needed for blocks of private dags.
+    #[serde(default)]
+    pub api_key: Option<String>,
+}
+
+pub type NetworkGetResult = Vec<u8>;
+pub const NETWORK_GET: &str = "ursa_get_cid";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkPutFileParams {
+    /// A car file, a raw file or a directory.
+    pub path: String,
+    /// Optional. Answer with the operation id right away instead of waiting for the put.
+    #[serde(default)]
+    pub background: bool,
+    /// Optional. Globs of the entries left out of a directory.
+    #[serde(default)]
+    pub ignore: Vec<String>,
+    /// Optional. Api key the stored bytes count against.
+    #[serde(default)]
+    pub api_key: Option<String>,
+}
+
+pub type NetworkPutFileResult = OperationResult;
+pub const NETWORK_PUT_FILE: &str = "ursa_put_file";
+
+/// Answer of a put, get or prefetch tracked as an operation.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct OperationResult {
+    /// Id to follow the progress with, see `ursa_operation_status`.
+    pub operation: OperationId,
+    /// Root cids of the content, empty while a put or get runs in the background, the
+    /// queued ones of a prefetch.
+    pub cids: Vec<String>,
+}
+
+/// How content downloaded by `ursa_put_url` is ingested.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PutUrlFormat {
+    /// A car when served as `application/vnd.ipld.car` or from a `.car` path, a raw file otherwise.
+    Auto,
+    Car,
+    /// A raw file, chunked into a UnixFS dag.
+    File,
+}
+
+impl Default for PutUrlFormat {
+    fn default() -> Self {
+        PutUrlFormat::Auto
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkPutUrlParams {
+    /// Http(s) url of the content.
+    pub url: String,
+    #[serde(default)]
+    pub format: PutUrlFormat,
+    /// Optional. Hex sha2-256 checksum the downloaded bytes must match.
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// Optional. Api key the stored bytes count against.
+    #[serde(default)]
+    pub api_key: Option<String>,
+}
+
+pub type NetworkPutUrlResult = Vec<String>;
+pub const NETWORK_PUT_URL: &str = "ursa_put_url";
+
+/// How `ursa_get_file` writes the content out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GetFileFormat {
+    /// The dag as a `<cid>.car` file.
+    Car,
+    /// The bytes of the UnixFS file under the root as a `<cid>` file.
+    Raw,
+}
+
+impl Default for GetFileFormat {
+    fn default() -> Self {
+        GetFileFormat::Car
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkGetFileParams {
+    pub path: String,
+    pub cid: String,
+    /// Optional. Answer with the operation id right away instead of waiting for the get.
+    #[serde(default)]
+    pub background: bool,
+    #[serde(default)]
+    pub format: GetFileFormat,
+}
+
+pub type NetworkGetFileResult = OperationResult;
+pub const NETWORK_GET_FILE: &str = "ursa_get_file";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkPrefetchParams {
+    pub cids: Vec<String>,
+    /// Optional `/p2p` addresses of peers known to hold the content.
+    #[serde(default)]
+    pub providers: Vec<String>,
+    /// Optional. Api key the prefetched bytes count against.
+    #[serde(default)]
+    pub api_key: Option<String>,
+}
+
+pub type NetworkPrefetchResult = OperationResult;
+pub const NETWORK_PREFETCH: &str = "ursa_prefetch";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkPrefetchStatusParams {
+    /// Root cids to report on, all prefetched cids when empty.
+    #[serde(default)]
+    pub cids: Vec<String>,
+}
+
+pub type NetworkPrefetchStatusResult = Vec<PrefetchProgress>;
+pub const NETWORK_PREFETCH_STATUS: &str = "ursa_prefetch_status";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkRemoveParams {
+    pub cids: Vec<String>,
+    /// The `admin_token` of the server config.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+/// The root cids that were removed.
+pub type NetworkRemoveResult = Vec<String>;
+pub const NETWORK_REMOVE: &str = "ursa_remove";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkSetContextParams {
+    /// Root cids to group.
+    pub cids: Vec<String>,
+    /// Context id to advertise them under, a cid or hex bytes.
+    pub context_id: String,
+    /// The `admin_token` of the server config.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+pub type NetworkSetContextResult = ();
+pub const NETWORK_SET_CONTEXT: &str = "ursa_set_context";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkUnindexContextParams {
+    /// Context id whose roots are no longer advertised, a cid or hex bytes.
+    pub context_id: String,
+    /// The `admin_token` of the server config.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+/// The root cids of the context, still stored but no longer advertised.
+pub type NetworkUnindexContextResult = Vec<String>;
+pub const NETWORK_UNINDEX_CONTEXT: &str = "ursa_unindex_context";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkPurgeParams {
+    /// Root cids to evict on every node obeying the key of this node.
+    pub cids: Vec<String>,
+    /// Context ids whose content is evicted.
+    pub context_ids: Vec<String>,
+    /// The `admin_token` of the server config.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+pub type NetworkPurgeResult = PurgeMessage;
+pub const NETWORK_PURGE: &str = "ursa_purge";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkAccessLogParams {
+    /// The `admin_token` of the server config.
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Maximum number of entries, newest first. Defaults to 100.
+    pub limit: Option<usize>,
+    /// Only return retrievals of this cid.
+    pub cid: Option<String>,
+}
+
+pub type NetworkAccessLogResult = Vec<AccessLogEntry>;
+pub const NETWORK_ACCESS_LOG: &str = "ursa_access_log";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkReceiptsParams {
+    /// The `admin_token` of the server config.
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Maximum number of receipts, newest first. Defaults to 100.
+    pub limit: Option<usize>,
+    /// Only return receipts for this cid.
+    pub cid: Option<String>,
+}
+
+pub type NetworkReceiptsResult = Vec<DeliveryReceipt>;
+pub const NETWORK_RECEIPTS: &str = "ursa_receipts";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkAccountingParams {
+    /// The `admin_token` of the server config.
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Only rollups of `api_key`, `peer` or `cid`.
+    pub kind: Option<UsageKind>,
+    /// Only rollups of this api key id, peer id or cid.
+    pub id: Option<String>,
+    /// Only rollups starting at or after this unix time.
+    pub since: Option<u64>,
+    /// Only rollups starting before this unix time.
+    pub until: Option<u64>,
+}
+
+pub type NetworkAccountingResult = Vec<Rollup>;
+pub const NETWORK_ACCOUNTING: &str = "ursa_accounting";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkGossipStatParams {
+    /// Only report this topic.
+    pub topic: Option<String>,
+}
+
+pub type NetworkGossipStatResult = Vec<GossipTopicStat>;
+pub const NETWORK_GOSSIP_STAT: &str = "ursa_gossip_stat";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkTopicPeersParams {
+    /// Only report this topic.
+    pub topic: Option<String>,
+}
+
+pub type NetworkTopicPeersResult = Vec<TopicPeers>;
+pub const NETWORK_TOPIC_PEERS: &str = "ursa_topic_peers";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkFindProvidersParams {
+    pub cid: String,
+    /// Optional. Time in milliseconds to walk the dht for, returning the providers found so far.
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+}
+
+pub type NetworkFindProvidersResult = Vec<ContentProvider>;
+pub const NETWORK_FIND_PROVIDERS: &str = "ursa_find_providers";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkNodeInfoParams {}
+
+pub type NetworkNodeInfoResult = NodeInfo;
+pub const NETWORK_NODE_INFO: &str = "ursa_node_info";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkPeerProtocolsParams {
+    /// Optional. Only list the peers speaking this protocol, the counts cover every peer.
+    pub protocol: Option<String>,
+}
+
+pub type NetworkPeerProtocolsResult = ProtocolInventory;
+pub const NETWORK_PEER_PROTOCOLS: &str = "ursa_peer_protocols";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkRelayCircuitsParams {}
+
+pub type NetworkRelayCircuitsResult = Vec<RelayCircuit>;
+pub const NETWORK_RELAY_CIRCUITS: &str = "ursa_relay_circuits";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkProviderStatusParams {}
+
+pub type NetworkProviderStatusResult = AnnounceStatus;
+pub const NETWORK_PROVIDER_STATUS: &str = "ursa_provider_status";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkOperationStatusParams {
+    pub id: OperationId,
+}
+
+pub type NetworkOperationStatusResult = OperationStatus;
+pub const NETWORK_OPERATION_STATUS: &str = "ursa_operation_status";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkCancelParams {
+    pub id: OperationId,
+}
+
+/// Whether the operation was running and got cancelled.
+pub type NetworkCancelResult = bool;
+pub const NETWORK_CANCEL: &str = "ursa_cancel";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkCreateApiKeyParams {
+    /// Name of the tenant the key is for.
+    pub name: String,
+    /// Optional. Bytes the key may upload and prefetch.
+    #[serde(default)]
+    pub quota_bytes: Option<u64>,
+    /// Optional. Requests per minute the key may make.
+    #[serde(default)]
+    pub rate_limit: Option<u32>,
+    /// The `admin_token` of the server config.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ApiKeyCreated {
+    pub id: String,
+    /// Secret sent in the `x-api-key` header, it cannot be shown again.
+    pub key: String,
+}
+
+pub type NetworkCreateApiKeyResult = ApiKeyCreated;
+pub const NETWORK_CREATE_API_KEY: &str = "ursa_create_api_key";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkRevokeApiKeyParams {
+    pub id: String,
+    /// The `admin_token` of the server config.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+/// Whether the key exists.
+pub type NetworkRevokeApiKeyResult = bool;
+pub const NETWORK_REVOKE_API_KEY: &str = "ursa_revoke_api_key";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkApiKeyUsageParams {
+    /// Only report this key.
+    #[serde(default)]
+    pub id: Option<String>,
+    /// The `admin_token` of the server config.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+pub type NetworkApiKeyUsageResult = Vec<ApiKeyUsage>;
+pub const NETWORK_API_KEY_USAGE: &str = "ursa_api_key_usage";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkSignUrlParams {
+    pub cid: String,
+    /// Optional. Seconds the url is valid for, the configured default when unset.
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+    /// The `admin_token` of the server config.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+pub type NetworkSignUrlResult = SignedUrl;
+pub const NETWORK_SIGN_URL: &str = "ursa_sign_url";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkAclSetParams {
+    /// Root cid of the dag to make private.
+    pub cid: String,
+    /// Peer ids allowed to pull the dag.
+    #[serde(default)]
+    pub peers: Vec<String>,
+    /// Ids of the api keys allowed to retrieve the dag over http.
+    #[serde(default)]
+    pub api_keys: Vec<String>,
+    /// The `admin_token` of the server config.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+pub type NetworkAclSetResult = AclEntry;
+pub const NETWORK_ACL_SET: &str = "ursa_acl_set";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkAclRemoveParams {
+    pub cid: String,
+    /// The `admin_token` of the server config.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+/// Whether the dag was private.
+pub type NetworkAclRemoveResult = bool;
+pub const NETWORK_ACL_REMOVE: &str = "ursa_acl_remove";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkAclListParams {
+    /// The `admin_token` of the server config.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+pub type NetworkAclListResult = Vec<AclEntry>;
+pub const NETWORK_ACL_LIST: &str = "ursa_acl_list";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkListContentParams {
+    /// Optional. `next_cursor` of the previous page, the first page when unset.
+    #[serde(default)]
+    pub cursor: Option<String>,
+    /// Optional. Entries per page, 100 when unset and at most 1000.
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// Optional. Api key allowed to see the private content, see `ursa_acl_set`.
+    #[serde(default)]
+    pub api_key: Option<String>,
+    #[serde(flatten)]
+    pub filter: ContentFilter,
+}
+
+pub type NetworkListContentResult = ContentPage;
+pub const NETWORK_LIST_CONTENT: &str = "ursa_list_content";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkDagStatParams {
+    pub cid: String,
+    /// Optional. Fetch the dag from the network when it is not stored.
+    #[serde(default)]
+    pub fetch: bool,
+    /// Optional. Api key the fetched bytes count against.
+    #[serde(default)]
+    pub api_key: Option<String>,
+}
+
+pub type NetworkDagStatResult = DagStat;
+pub const NETWORK_DAG_STAT: &str = "ursa_dag_stat";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkVerifyParams {
+    pub cid: String,
+    /// Optional. Fetch the missing and corrupt blocks from the network again.
+    #[serde(default)]
+    pub repair: bool,
+    /// The `admin_token` of the server config, required with `repair`.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+/// Integrity of a stored dag.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerifyReport {
+    /// Blocks read and hashed again.
+    pub blocks: usize,
+    pub missing: Vec<String>,
+    /// Blocks whose data does not hash to their cid.
+    pub corrupt: Vec<String>,
+    /// With `repair`, whether the damaged blocks were fetched again and the dag now
+    /// verifies.
+    pub repaired: bool,
+}
+
+pub type NetworkVerifyResult = VerifyReport;
+pub const NETWORK_VERIFY: &str = "ursa_verify";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkResolveParams {
+    pub cid: String,
+    /// Path of map keys, list indices and UnixFS names, e.g. `photos/2022/cover.jpg`.
+    pub path: String,
+}
+
+pub type NetworkResolveResult = Resolved;
+pub const NETWORK_RESOLVE: &str = "ursa_resolve";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkNamePublishParams {
+    pub cid: String,
+    /// Seconds the record stays valid, 48 hours by default.
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+    /// The `admin_token` of the server config.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+pub type NetworkNamePublishResult = NameRecord;
+pub const NETWORK_NAME_PUBLISH: &str = "ursa_name_publish";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkNameResolveParams {
+    /// Peer id of the publisher.
+    pub name: String,
+}
+
+pub type NetworkNameResolveResult = NameRecord;
+pub const NETWORK_NAME_RESOLVE: &str = "ursa_name_resolve";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkRepoCompactParams {
+    /// Column families to compact, all of them when empty.
+    #[serde(default)]
+    pub columns: Vec<String>,
+    /// The `admin_token` of the server config.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+pub type NetworkRepoCompactResult = OperationId;
+pub const NETWORK_REPO_COMPACT: &str = "ursa_repo_compact";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkConfigGetParams {
+    /// The `admin_token` of the server config.
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Dotted paths of the settings to read, every runtime setting when empty.
+    #[serde(default)]
+    pub keys: Vec<String>,
+}
+
+pub type NetworkConfigGetResult = BTreeMap<String, Value>;
+pub const NETWORK_CONFIG_GET: &str = "ursa_config_get";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkConfigSetParams {
+    /// The `admin_token` of the server config.
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Dotted path of the setting in the config file.
+    pub key: String,
+    pub value: Value,
+}
+
+pub type NetworkConfigSetResult = SettingChange;
+pub const NETWORK_CONFIG_SET: &str = "ursa_config_set";
+
+#[derive(Deserialize, Serialize)]
+pub struct NetworkDiagnosticsParams {}
+
+pub type NetworkDiagnosticsResult = DiagnosticsReport;
+pub const NETWORK_DIAGNOSTICS: &str = "ursa_diagnostics";
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum PrefetchStatus {
+    Queued,
+    Fetching,
+    Done { blocks: usize },
+    Failed { error: String },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PrefetchProgress {
+    pub cid: String,
+    pub status: PrefetchStatus,
+}
+
+/// Abstraction of Ursa's server commands
+#[async_trait]
+pub trait NetworkInterface: Sync + Send + 'static {
+    /// Get a bitswap block from the network, asking only `providers` when given
+    /// instead of the connected peers, and failing once `deadline` passed. Blocks of
+    /// private dags are only returned for an `api_key` on their acl entry
+    async fn get(
+        &self,
+        cid: Cid,
+        providers: Vec<Multiaddr>,
+        deadline: Option<Duration>,
+        api_key: Option<String>,
+    ) -> Result<Option<Vec<u8>>>;
+
+    /// Fetch the dag under `root_cid` when it is not stored, and stream its blocks
+    /// read from the store one at a time
+    async fn get_data(&self, root_cid: Cid) -> Result<BlockStream>;
+
+    /// get the file locally via cli in `format`, reporting the sync progress under
+    /// `operation`
+    async fn get_file(
+        &self,
+        path: String,
+        cid: Cid,
+        format: GetFileFormat,
+        operation: Option<OperationId>,
+    ) -> Result<()>;
+
+    /// Stream the dag under `root_cid` as a car file, handing the block data read from
+    /// the store to the body without copying it.
+    async fn stream(&self, root_cid: Cid) -> Result<CarStream>;
+
+    /// Put a car file and start providing to the network, reporting the bytes read
+    /// under `operation`
+    async fn put_car<R: AsyncRead + Send + Unpin>(
+        &self,
+        reader: R,
+        operation: Option<OperationId>,
+    ) -> Result<Vec<Cid>>;
+
+    /// Put a car file, or a raw file chunked into a UnixFS dag, using a local path.
+    /// The stored bytes count against `api_key`
+    async fn put_file(
+        &self,
+        path: String,
+        ignore: Vec<String>,
+        api_key: Option<String>,
+        operation: Option<OperationId>,
+    ) -> Result<Vec<Cid>>;
+
+    /// Download a car or raw file from `url` on the server, then store and index it.
+    /// The stored bytes count against `api_key`
+    async fn put_url(
+        &self,
+        url: String,
+        format: PutUrlFormat,
+        sha256: Option<String>,
+        api_key: Option<String>,
+    ) -> Result<Vec<Cid>>;
+
+    /// Sync the dags under `cids`, resolving once all of them finished or failed.
+    /// The synced bytes count against `api_key`, the progress is reported under
+    /// `operation`
+    async fn prefetch(
+        &self,
+        cids: Vec<Cid>,
+        providers: Vec<Multiaddr>,
+        api_key: Option<String>,
+        operation: Option<OperationId>,
+    ) -> Result<()>;
+
+    /// Progress of prefetched root cids
+    async fn prefetch_status(&self, cids: Vec<Cid>) -> Result<Vec<PrefetchProgress>>;
+
+    /// Delete the dags under `cids` and advertise their removal to the indexer, with the
+    /// admin `token`. Blocks also under other stored roots are kept.
+    async fn remove(&self, token: Option<String>, cids: Vec<Cid>) -> Result<Vec<Cid>>;
+
+    /// Advertise `cids` under `context_id`, so they can be withdrawn together
+    async fn set_context(
+        &self,
+        token: Option<String>,
+        cids: Vec<Cid>,
+        context_id: String,
+    ) -> Result<()>;
+
+    /// Advertise the removal of the roots grouped under `context_id` with a single
+    /// ad, keeping their content
+    async fn unindex_context(&self, token: Option<String>, context_id: String) -> Result<Vec<Cid>>;
+
+    /// Gossip a purge of `cids` and `context_ids` signed by this node, evicting them
+    /// here too, with the admin `token`
+    async fn purge(
+        &self,
+        token: Option<String>,
+        cids: Vec<Cid>,
+        context_ids: Vec<String>,
+    ) -> Result<PurgeMessage>;
+
+    /// Recent retrievals, newest first
+    async fn access_log(
+        &self,
+        token: Option<String>,
+        limit: usize,
+        cid: Option<String>,
+    ) -> Result<Vec<AccessLogEntry>>;
+
+    /// Recent delivery receipts accepted from clients, newest first, with the admin `token`
+    async fn receipts(
+        &self,
+        token: Option<String>,
+        limit: usize,
+        cid: Option<String>,
+    ) -> Result<Vec<DeliveryReceipt>>;
+
+    /// Rollups of the bytes served per api key, peer and cid, oldest first, with the
+    /// admin `token`
+    async fn accounting(&self, token: Option<String>, query: UsageQuery) -> Result<Vec<Rollup>>;
+
+    /// Gossipsub mesh and message statistics per topic
+    async fn gossip_stat(&self, topic: Option<String>) -> Result<Vec<GossipTopicStat>>;
+
+    /// Subscribers per gossipsub topic and whether publishing on it reaches enough peers
+    async fn topic_peers(&self, topic: Option<String>) -> Result<Vec<TopicPeers>>;
+
+    /// Providers of `cid` found by a Kademlia walk, stopping early once `deadline` passed
+    async fn find_providers(
+        &self,
+        cid: Cid,
+        deadline: Option<Duration>,
+    ) -> Result<Vec<ContentProvider>>;
+
+    /// Providers of `cid` as the walk finds them, ending once `deadline` passed. A peer
+    /// found both locally and by the walk comes twice
+    async fn provider_stream(
+        &self,
+        cid: Cid,
+        deadline: Option<Duration>,
+    ) -> Result<BoxStream<'static, ContentProvider>>;
+
+    /// Identity, addresses and reachability of the node
+    async fn node_info(&self) -> Result<NodeInfo>;
+
+    /// Agent versions and protocols of the connected peers, as reported by identify
+    async fn peer_protocols(&self, protocol: Option<String>) -> Result<ProtocolInventory>;
+
+    /// Circuits relayed by the relay server of this node, oldest first
+    async fn relay_circuits(&self) -> Result<Vec<RelayCircuit>>;
+
+    /// Whether published advertisements were announced to the indexer
+    async fn provider_status(&self) -> Result<AnnounceStatus>;
+
+    /// Register a long-running put or get, returning the id its progress is reported under
+    fn start_operation(&self, kind: OperationKind, cid: Option<Cid>) -> OperationId;
+
+    /// Latest progress of an operation
+    async fn operation_status(&self, id: OperationId) -> Result<Option<OperationStatus>>;
+
+    /// Cancel a running get or prefetch, stopping its bitswap queries and failing the
+    /// requests waiting on them
+    async fn cancel(&self, id: OperationId) -> Result<bool>;
+
+    /// Admit a request storing `bytes` under the rate limit and quota of `api_key`
+    fn admit(&self, api_key: Option<&str>, bytes: u64) -> Result<()>;
+
+    /// Create an api key, returning the secret
+    async fn create_api_key(
+        &self,
+        token: Option<String>,
+        name: String,
+        quota_bytes: Option<u64>,
+        rate_limit: Option<u32>,
+    ) -> Result<ApiKeyCreated>;
+
+    /// Revoke an api key, returning whether it exists
+    async fn revoke_api_key(&self, token: Option<String>, id: String) -> Result<bool>;
+
+    /// Usage of api key `id`, or of every key
+    async fn api_key_usage(
+        &self,
+        token: Option<String>,
+        id: Option<String>,
+    ) -> Result<Vec<ApiKeyUsage>>;
+
+    /// Sign a retrieval url of `cid` valid for `ttl_secs`, with the admin `token`
+    async fn sign_url(
+        &self,
+        token: Option<String>,
+        cid: Cid,
+        ttl_secs: Option<u64>,
+    ) -> Result<SignedUrl>;
+
+    /// Make the dag under `cid` private to `peers` and `api_keys`, with the admin `token`
+    async fn acl_set(
+        &self,
+        token: Option<String>,
+        cid: Cid,
+        peers: Vec<PeerId>,
+        api_keys: Vec<String>,
+    ) -> Result<AclEntry>;
+
+    /// Make the dag under `cid` public again, returning whether it was private
+    async fn acl_remove(&self, token: Option<String>, cid: Cid) -> Result<bool>;
+
+    /// The private dags
+    async fn acl_list(&self, token: Option<String>) -> Result<Vec<AclEntry>>;
+
+    /// List the stored root cids in cid order, starting after `cursor`. Private roots
+    /// are only listed to the api keys allowed to retrieve them.
+    async fn list_content(
+        &self,
+        cursor: Option<String>,
+        limit: usize,
+        filter: ContentFilter,
+        api_key: Option<String>,
+    ) -> Result<ContentPage>;
+
+    /// Block count, size and depth of the dag under `root_cid`, fetched first when
+    /// `fetch` is set. The fetched bytes count against `api_key`, as for a prefetch.
+    async fn dag_stat(
+        &self,
+        root_cid: Cid,
+        fetch: bool,
+        api_key: Option<String>,
+    ) -> Result<DagStat>;
+
+    /// Hash the blocks of the stored dag under `root_cid` again, fetching the missing
+    /// and corrupt ones again when `repair` is set with the admin `token`
+    async fn verify(
+        &self,
+        token: Option<String>,
+        root_cid: Cid,
+        repair: bool,
+    ) -> Result<VerifyReport>;
+
+    /// Resolve the IPLD `path` under the stored `root_cid`.
+    async fn resolve(&self, root_cid: Cid, path: String) -> Result<Resolved>;
+
+    /// Point the name of this node at `root_cid`, answering with the signed record, with
+    /// the admin `token`.
+    async fn name_publish(
+        &self,
+        token: Option<String>,
+        root_cid: Cid,
+        ttl_secs: Option<u64>,
+    ) -> Result<NameRecord>;
+
+    /// Newest record of the name of the peer `name`.
+    async fn name_resolve(&self, name: PeerId) -> Result<NameRecord>;
+
+    /// Compact `columns` of the database in the background, returning the operation
+    /// the progress is reported under, with the admin `token`.
+    fn repo_compact(&self, token: Option<String>, columns: Vec<Column>) -> Result<OperationId>;
+
+    /// Current values of the runtime settings named by `keys`, all of them when empty
+    fn config_get(
+        &self,
+        token: Option<String>,
+        keys: Vec<String>,
+    ) -> Result<BTreeMap<String, Value>>;
+
+    /// Apply `value` to the runtime setting `key` and write it to the config file
+    fn config_set(&self, token: Option<String>, key: String, value: Value)
+        -> Result<SettingChange>;
+
+    /// Self-check of the ports, NAT, bootstrap connections, disk, store, clock and
+    /// indexers of the node
+    async fn diagnostics(&self) -> Result<DiagnosticsReport>;
+}
+
+/// A command was rejected because the network command queue is full.
+#[derive(Debug)]
+pub struct QueueFull;
+
+impl fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The network is overloaded, try again later")
+    }
+}
+
+impl std::error::Error for QueueFull {}
+
+/// Whether `err` was caused by a full network command queue.
+pub fn is_queue_full(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<QueueFull>().is_some()
+}
+
+pub struct NodeNetworkInterface<S>
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    pub store: Arc<Store<S>>,
+    pub network_send: Sender<UrsaCommand>,
+    pub origin: Origin,
+    pub access_log: Arc<AccessLog>,
+    /// What to do when `network_send` is full.
+    overflow: OverflowPolicy,
+    put_url: PutUrlConfig,
+    put_file: PutFileConfig,
+    car_import: CarImportConfig,
+    /// Progress of long-running puts and gets.
+    pub operations: Arc<Operations>,
+    /// Events of the network service, streamed on `/events`.
+    pub node_events: NodeEvents,
+    /// In-flight network fetches, see [`FetchKey`].
+    inflight: Arc<SingleFlight<FetchKey>>,
+    prefetch: Arc<PrefetchTracker>,
+    webhooks: Arc<Webhooks>,
+    pub api_keys: Arc<ApiKeys<S>>,
+    pub signed_urls: Arc<SignedUrls>,
+    /// Private dags, shared with the network service.
+    pub acl: Arc<Acl>,
+    /// Upload caps, shared with the network service.
+    pub shaper: Arc<Shaper>,
+    /// Bytes served per api key, peer and cid, shared with the network service.
+    pub accounting: Arc<Accounting>,
+    /// Root cids stored on the node.
+    pub content: Arc<ContentIndex<S>>,
+    /// Resolves the domains of `/ipns` urls.
+    pub dnslink: Arc<DnsLink>,
+    /// Deliveries over http and the receipts acknowledging them.
+    pub receipts: Arc<Receipts>,
+    /// Car files of popular roots rendered to disk.
+    pub render_cache: Arc<RenderCache>,
+    compactor: Arc<Compactor>,
+    /// Values of the config file changeable at runtime.
+    pub settings: Arc<Settings>,
+    /// What the self-check is run against.
+    diagnostics: Arc<Diagnostics>,
+}
+
+impl<S> Clone for NodeNetworkInterface<S>
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    fn clone(&self) -> Self {
+        Self {
+            store: Arc::clone(&self.store),
+            network_send: self.network_send.clone(),
+            origin: self.origin.clone(),
+            access_log: Arc::clone(&self.access_log),
+            overflow: self.overflow,
+            put_url: self.put_url.clone(),
+            put_file: self.put_file.clone(),
+            car_import: self.car_import.clone(),
+            operations: Arc::clone(&self.operations),
+            node_events: self.node_events.clone(),
+            inflight: Arc::clone(&self.inflight),
+            prefetch: Arc::clone(&self.prefetch),
+            webhooks: Arc::clone(&self.webhooks),
+            api_keys: Arc::clone(&self.api_keys),
+            signed_urls: Arc::clone(&self.signed_urls),
+            acl: Arc::clone(&self.acl),
+            shaper: Arc::clone(&self.shaper),
+            accounting: Arc::clone(&self.accounting),
+            content: Arc::clone(&self.content),
+            dnslink: Arc::clone(&self.dnslink),
+            receipts: Arc::clone(&self.receipts),
+            render_cache: Arc::clone(&self.render_cache),
+            compactor: Arc::clone(&self.compactor),
+            settings: Arc::clone(&self.settings),
+            diagnostics: Arc::clone(&self.diagnostics),
+        }
+    }
+}
+
+impl<S> NodeNetworkInterface<S>
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    pub fn new(
+        store: Arc<Store<S>>,
+        network_send: Sender<UrsaCommand>,
+        origin: Origin,
+        access_log: AccessLog,
+        overflow: OverflowPolicy,
+        put_url: PutUrlConfig,
+    ) -> Self {
+        let api_keys = ApiKeys::new(Arc::clone(&store), ApiKeyConfig::default());
+        let content = ContentIndex::load(Arc::clone(&store)).unwrap_or_else(|e| {
+            warn!("Cannot load the content listing, starting an empty one: {e}");
+            ContentIndex::new(Arc::clone(&store))
+        });
+        let operations: Arc<Operations> = Default::default();
+        let compactor = Compactor::new(None, Arc::clone(&operations));
+        Self {
+            store,
+            network_send,
+            origin,
+            access_log: Arc::new(access_log),
+            overflow,
+            put_url,
+            put_file: Default::default(),
+            car_import: Default::default(),
+            operations,
+            node_events: Default::default(),
+            inflight: Default::default(),
+            prefetch: Default::default(),
+            webhooks: Default::default(),
+            api_keys: Arc::new(api_keys),
+            signed_urls: Default::default(),
+            acl: Default::default(),
+            shaper: Default::default(),
+            accounting: Default::default(),
+            content: Arc::new(content),
+            dnslink: Default::default(),
+            receipts: Default::default(),
+            render_cache: Default::default(),
+            compactor: Arc::new(compactor),
+            settings: Default::default(),
+            diagnostics: Default::default(),
+        }
+    }
+
+    /// Stream the events of `node_events` instead of those of a service of its own.
+    pub fn with_node_events(mut self, node_events: NodeEvents) -> Self {
+        self.node_events = node_events;
+        self
+    }
+
+    /// Notify `webhooks` of the content put, requested and removed through this
+    /// interface.
+    pub fn with_webhooks(mut self, webhooks: Arc<Webhooks>) -> Self {
+        self.webhooks = webhooks;
+        self
+    }
+
+    /// Enforce api keys as configured by `config`.
+    pub fn with_api_keys(mut self, config: ApiKeyConfig) -> Self {
+        self.api_keys = Arc::new(ApiKeys::new(Arc::clone(&self.store), config));
+        self
+    }
+
+    /// Sign and check retrieval urls as configured by `config`.
+    pub fn with_signed_urls(mut self, config: SignedUrlConfig) -> Self {
+        self.signed_urls = Arc::new(SignedUrls::new(config));
+        self
+    }
+
+    /// Check retrievals against `acl` instead of an acl of its own.
+    pub fn with_acl(mut self, acl: Arc<Acl>) -> Self {
+        self.acl = acl;
+        self
+    }
+
+    /// Pace the car files streamed over http by the caps of `shaper`.
+    pub fn with_shaper(mut self, shaper: Arc<Shaper>) -> Self {
+        self.shaper = shaper;
+        self
+    }
+
+    /// Account the car files streamed over http in `accounting`.
+    pub fn with_accounting(mut self, accounting: Arc<Accounting>) -> Self {
+        self.accounting = accounting;
+        self
+    }
+
+    /// Resolve `/ipns` domains as configured by `config`.
+    pub fn with_dnslink(mut self, config: DnsLinkConfig) -> Self {
+        self.dnslink = Arc::new(DnsLink::new(config));
+        self
+    }
+
+    /// Track deliveries and accept receipts as configured by `config`.
+    pub fn with_receipts(mut self, config: ReceiptConfig) -> Self {
+        self.receipts = Arc::new(Receipts::new(config));
+        self
+    }
+
+    /// Cache the car files of popular roots as configured by `config`.
+    pub fn with_render_cache(mut self, config: RenderCacheConfig) -> Self {
+        self.render_cache = Arc::new(RenderCache::new(config));
+        self
+    }
+
+    /// Put car files through a pipeline of `config.workers` writers.
+    pub fn with_put_file(mut self, config: PutFileConfig) -> Self {
+        self.put_file = config;
+        self
+    }
+
+    pub fn with_car_import(mut self, config: CarImportConfig) -> Self {
+        self.car_import = config;
+        self
+    }
+
+    /// Compact the column families of `db` on request, and daily at `hour` UTC when
+    /// set.
+    pub fn with_compaction(mut self, db: ColumnDb, hour: Option<u8>) -> Self {
+        let compactor = Arc::new(Compactor::new(Some(db), Arc::clone(&self.operations)));
+        if let Some(hour) = hour {
+            Arc::clone(&compactor).schedule(hour);
+        }
+        self.compactor = compactor;
+        self
+    }
+
+    /// Let the admins change `settings` over the rpc.
+    pub fn with_settings(mut self, settings: Arc<Settings>) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Run the self-check against the config of `diagnostics`.
+    pub fn with_diagnostics(mut self, diagnostics: Diagnostics) -> Self {
+        self.diagnostics = Arc::new(diagnostics);
+        self
+    }
+
+    /// Hash `blocks_per_hour` blocks of `db` every hour when set, notifying the webhooks
+    /// of the corrupt ones.
+    pub fn with_scrubbing(self, db: ColumnDb, blocks_per_hour: Option<usize>) -> Self {
+        if let Some(count) = blocks_per_hour.filter(|count| *count > 0) {
+            let scrubber = Arc::new(Scrubber::new(db));
+            let webhooks = Arc::clone(&self.webhooks);
+            task::spawn(async move {
+                loop {
+                    task::sleep(Duration::from_secs(60 * 60)).await;
+                    let scrubber = Arc::clone(&scrubber);
+                    match task::spawn_blocking(move || scrubber.scrub(count)).await {
+                        Ok(report) => {
+                            for cid in report.corrupt {
+                                webhooks.notify(WebhookEvent::Corrupted {
+                                    cid: cid.to_string(),
+                                });
+                            }
+                        }
+                        Err(e) => warn!("Scrubbing the store failed: {e}"),
+                    }
+                }
+            });
+        }
+        self
+    }
+
+    /// Root cid an `/ipns/<name>` url points at, `name` being the peer id of a
+    /// publisher or a domain with a DNSLink record.
+    pub async fn resolve_ipns(&self, name: &str) -> Result<Cid> {
+        let peer = match PeerId::from_str(name) {
+            Ok(peer) => peer,
+            Err(_) => match self.dnslink.resolve(name).await? {
+                DnsLinkTarget::Cid(cid) => return Ok(cid),
+                DnsLinkTarget::Name(name) => PeerId::from_str(&name).map_err(|_| {
+                    anyhow!(ApiError::not_found(format!("{name} is not a known name")))
+                })?,
+            },
+        };
+        self.name_resolve(peer).await?.root()
+    }
+
+    /// Entries of the stored block `cid` when it is a UnixFS directory.
+    pub async fn list_directory(&self, cid: Cid) -> Result<Option<Vec<DirEntry>>> {
+        self.store
+            .blocking(move |store| directory::list(store, cid))
+            .await?
+    }
+
+    /// Evict the content named in the purges the network service accepts.
+    pub fn watch_purges(self: &Arc<Self>) {
+        let interface = Arc::clone(self);
+        let mut events = self.node_events.subscribe();
+        task::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(NodeEvent::PurgeReceived { cids, context_ids }) => {
+                        let cids = cids
+                            .iter()
+                            .filter_map(|cid| Cid::from_str(cid).ok())
+                            .collect();
+                        if let Err(err) = interface.evict(cids, context_ids).await {
+                            warn!("Failed to evict purged content: {:?}", err);
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Skipped {skipped} node events, purges may be missed")
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    /// Run the self-check once `delay` after startup, when the node had the time to
+    /// connect, and log the checks that did not pass.
+    pub fn self_check(self: &Arc<Self>, delay: Duration) {
+        let interface = Arc::clone(self);
+        task::spawn(async move {
+            task::sleep(delay).await;
+            match interface.diagnostics().await {
+                Ok(report) => {
+                    for check in report.problems() {
+                        warn!("Self-check {}: {}", check.name, check.message);
+                    }
+                    if report.ok {
+                        info!("Self-check passed, see `ursa doctor` for details");
+                    }
+                }
+                Err(e) => warn!("Running the self-check failed: {e}"),
+            }
+        });
+    }
+
+    /// Remove the stored roots among `cids` and those advertised under `context_ids`.
+    async fn evict(&self, cids: Vec<Cid>, context_ids: Vec<String>) -> Result<Vec<Cid>> {
+        let mut roots: Vec<Cid> = cids
+            .into_iter()
+            .filter(|cid| self.store.blockstore().has(cid).unwrap_or(false))
+            .collect();
+        if !context_ids.is_empty() {
+            let entries = self.content.list(None, &ContentFilter::default());
+            for entry in self.with_contexts(entries).await? {
+                let purged = entry
+                    .context_id
+                    .as_ref()
+                    .map_or(false, |context_id| context_ids.contains(context_id));
+                if purged {
+                    roots.push(Cid::try_from(entry.cid.as_str())?);
+                }
+            }
+        }
+        roots.sort();
+        roots.dedup();
+        if roots.is_empty() {
+            return Ok(roots);
+        }
+        info!("Purging {} roots", roots.len());
+        self.remove_roots(roots).await
+    }
+
+    /// Unindex the dags under `cids`, then delete their blocks but those also under the
+    /// roots kept.
+    async fn remove_roots(&self, cids: Vec<Cid>) -> Result<Vec<Cid>> {
+        let (sender, receiver) = oneshot::channel();
+        let request = UrsaCommand::Unindex {
+            cids: cids.clone(),
+            sender,
+        };
+        self.send_command(request).await?;
+        receiver.await??;
+
+        let mut retained = vec![];
+        for entry in self.content.list(None, &ContentFilter::default()) {
+            let cid = Cid::try_from(entry.cid.as_str())?;
+            if !cids.contains(&cid) {
+                retained.push(cid.to_ipld_cid());
+            }
+        }
+        let retained = Arc::new(retained);
+        for root_cid in &cids {
+            let (root, retained) = (root_cid.to_ipld_cid(), Arc::clone(&retained));
+            let deleted = self
+                .store
+                .blocking(move |store| store.delete_dag(&root, &retained))
+                .await??;
+            info!("Removed {deleted} blocks under {root_cid}");
+            self.content.remove(root_cid)?;
+            self.render_cache.remove(root_cid);
+            self.webhooks.evicted(*root_cid);
+        }
+        Ok(cids)
+    }
+
+    /// Queue `command` for the network service, applying the overflow policy when
+    /// the queue is full. Rejected commands fail with [`QueueFull`].
+    async fn send_command(&self, command: UrsaCommand) -> Result<()> {
+        let result = match self.overflow {
+            OverflowPolicy::FailFast => match self.network_send.try_send(command) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(_)) => Err(anyhow!(QueueFull)),
+                Err(TrySendError::Closed(_)) => Err(anyhow!("The network service is down")),
+            },
+            OverflowPolicy::Wait { timeout_ms } => {
+                let send = self.network_send.send(command);
+                match timeout(Duration::from_millis(timeout_ms), send).await {
+                    Ok(Ok(())) => Ok(()),
+                    Ok(Err(_)) => Err(anyhow!("The network service is down")),
+                    Err(_) => Err(anyhow!(QueueFull)),
+                }
+            }
+        };
+
+        let depth = self.network_send.len() as f64;
+        track(MetricEvent::CommandQueueDepth, None, Some(depth));
+        if result.as_ref().err().map_or(false, is_queue_full) {
+            track(MetricEvent::CommandRejected, None, None);
+        }
+        result
+    }
+
+    /// Report the progress of the dag sync of `root_cid` under `operation`.
+    async fn watch_sync(&self, root_cid: Cid, operation: OperationId) {
+        if self.store.blockstore().has(&root_cid).unwrap_or(false) {
+            return;
+        }
+
+        let (sender, mut receiver) = mpsc::unbounded();
+        let request = UrsaCommand::WatchSync {
+            root: root_cid,
+            sender,
+        };
+        if let Err(e) = self.send_command(request).await {
+            warn!("Failed to watch the sync of {root_cid}: {e}");
+            return;
+        }
+
+        let operations = Arc::clone(&self.operations);
+        task::spawn(async move {
+            while let Some(progress) = receiver.next().await {
+                operations.update(operation, |status| {
+                    status.blocks += 1;
+                    status.bytes += progress.bytes;
+                    status.missing = Some(progress.missing);
+                });
+            }
+        });
+    }
+
+    /// Write the dag under `root_cid` as a car file into the `path` directory.
+    async fn write_car_file(&self, path: String, root_cid: Cid) -> Result<()> {
+        info!("getting and storing the file at: {path}");
+
+        let mut car = self.stream(root_cid).await?;
+        let file_path = PathBuf::from(path).join(format!("{}.car", root_cid));
+        create_dir_all(file_path.parent().unwrap()).await?;
+        let mut file = File::create(file_path).await?;
+        while let Some(chunk) = car.next().await {
+            file.write_all(&chunk?).await?;
+        }
+        file.flush().await?;
+        Ok(())
+    }
+
+    /// Write the UnixFS file under `root_cid` into the `path` directory, reassembled
+    /// into its original bytes.
+    async fn write_raw_file(&self, path: String, root_cid: Cid) -> Result<()> {
+        info!("getting and storing the file at: {path}");
+
+        // only the fetch is needed, the blocks are read back by the UnixFS walk
+        self.get_data(root_cid).await?;
+        let file_path = PathBuf::from(path).join(root_cid.to_string());
+        create_dir_all(file_path.parent().unwrap()).await?;
+        let written = self
+            .store
+            .blocking(move |store| -> Result<u64> {
+                let mut file = BufWriter::new(std::fs::File::create(file_path)?);
+                let written = unixfs::write_file(store, root_cid, &mut file)?;
+                file.flush()?;
+                Ok(written)
+            })
+            .await??;
+        info!("Wrote {written} bytes of the file {root_cid}");
+        Ok(())
+    }
+
+    /// Announce the stored dags under `cids` to the network, listing them as pinned
+    /// content or as a cache.
+    async fn index(&self, cids: Vec<Cid>, pinned: bool) -> Result<Vec<Cid>> {
+        // the stored blocks may belong to private dags that were incomplete
+        let acl = Arc::clone(&self.acl);
+        if let Err(e) = self.store.blocking(move |store| acl.refresh(store)).await {
+            warn!("Failed to refresh the acl: {e:?}");
+        }
+        let (sender, receiver) = oneshot::channel();
+        let request = UrsaCommand::Index {
+            cids: cids.clone(),
+            sender,
+        };
+
+        self.send_command(request).await?;
+        match receiver.await {
+            Ok(_) => {
+                for cid in &cids {
+                    self.list(*cid, pinned).await;
+                    self.webhooks.notify(WebhookEvent::Ingested {
+                        cid: cid.to_string(),
+                    });
+                }
+                Ok(cids)
+            }
+            Err(e) => Err(anyhow!(format!(
+                "The PUT failed, please check server logs {:?}",
+                e
+            ))),
+        }
+    }
+
+    /// Chunk the raw file read from `reader` into a UnixFS dag of `chunk_size` leaves
+    /// hashed with `hash`, storing its blocks as they fill up, and return the root cid.
+    /// Fails once more than `max_size` bytes are read. The bytes read and the blocks
+    /// written are reported as progress of `operation`.
+    async fn put_chunked<R: AsyncRead + Unpin>(
+        &self,
+        mut reader: R,
+        source: &str,
+        (chunk_size, hash, max_size): (usize, ChunkHash, u64),
+        operation: Option<OperationId>,
+    ) -> Result<Cid> {
+        let mut encoder = unixfs::FileEncoder::new(chunk_size, hash.code())?;
+        let (mut buffer, mut blocks) = (vec![0; 64 * 1024], vec![]);
+        let mut total = 0;
+        loop {
+            let read = reader.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            total += read as u64;
+            if total > max_size {
+                return Err(anyhow!(
+                    "{source} is larger than the limit of {max_size} bytes"
+                ));
+            }
+            if let Some(id) = operation {
+                self.operations.add_bytes(id, read as u64);
+            }
+            encoder.update(&buffer[..read], &mut blocks);
+            if blocks.len() >= CHUNK_BATCH {
+                self.write_blocks(mem::take(&mut blocks), operation).await?;
+            }
+        }
+        let root = encoder.finish(&mut blocks);
+        self.write_blocks(blocks, operation).await?;
+        info!("Chunked {source} into the dag {root}");
+        Ok(root)
+    }
+
+    async fn write_blocks(
+        &self,
+        blocks: Vec<(Cid, Vec<u8>)>,
+        operation: Option<OperationId>,
+    ) -> Result<()> {
+        let count = blocks.len();
+        self.store
+            .blocking(move |store| -> Result<()> {
+                for (cid, data) in blocks {
+                    store.blockstore().write(cid.to_bytes(), data)?;
+                }
+                Ok(())
+            })
+            .await??;
+        if let Some(id) = operation {
+            self.operations.add_blocks(id, count);
+        }
+        Ok(())
+    }
+
+    /// Encode the directory at `path` as a UnixFS dag, then store and index it.
+    async fn put_directory(
+        &self,
+        path: &str,
+        ignore: Vec<String>,
+        operation: Option<OperationId>,
+    ) -> Result<Vec<Cid>> {
+        info!("Putting the directory on network: {path}");
+        let dir = PathBuf::from(path);
+        let (chunk_size, hash) = (self.put_url.chunk_size, self.put_url.hash.code());
+        let max_size = self.put_url.max_size;
+        let (root, count) = self
+            .store
+            .blocking(move |store| -> Result<(Cid, usize)> {
+                let (root, blocks) =
+                    unixfs::encode_directory(&dir, &ignore, chunk_size, hash, max_size)?;
+                let count = blocks.len();
+                for (cid, data) in blocks {
+                    store.blockstore().write(cid.to_bytes(), data)?;
+                }
+                Ok((root, count))
+            })
+            .await??;
+        if let Some(id) = operation {
+            self.operations.add_blocks(id, count);
+        }
+        info!("Put the directory {path} as the dag {root}");
+        self.index(vec![root], true).await
+    }
+
+    /// Finish the put operation `operation` with the root cid of `result`.
+    fn finish_put(&self, operation: Option<OperationId>, result: &Result<Vec<Cid>>) {
+        if let Some(id) = operation {
+            if let Ok(cids) = result {
+                self.operations.update(id, |status| {
+                    status.cid = cids.first().map(Cid::to_string);
+                });
+            }
+            self.operations.finish(id, result);
+        }
+    }
+
+    /// Let the network service track request rates for replication.
+    async fn track_request(&self, cid: Cid) {
+        self.webhooks.requested(cid);
+        if let Err(e) = self.content.touch(&cid) {
+            warn!("Failed to note the access of {cid}: {e}");
+        }
+        let request = UrsaCommand::ContentRequested { cid };
+        if let Err(e) = self.send_command(request).await {
+            warn!("Failed to track request for {cid}: {e}");
+        }
+    }
+
+    /// Cids and sizes of the blocks of the stored dag under `root_cid`, listed on a
+    /// store thread.
+    async fn dag(&self, root_cid: Cid) -> Result<Vec<(lCid, usize)>> {
+        let root = root_cid.to_ipld_cid();
+        self.store
+            .blocking(move |store| store.dag_blocks(&root))
+            .await?
+    }
+
+    /// Bytes of the stored blocks of the dag under `root_cid`.
+    async fn dag_size(&self, root_cid: Cid) -> Result<u64> {
+        let blocks = self.dag(root_cid).await?;
+        Ok(blocks.iter().map(|(_, size)| *size as u64).sum())
+    }
+
+    /// Stream of the blocks of the stored dag under `root_cid`, each read on a store
+    /// thread when the previous one was taken.
+    async fn block_stream(&self, root_cid: Cid) -> Result<BlockStream> {
+        let blocks = self.dag(root_cid).await?;
+        let store = Arc::clone(&self.store);
+        let load: BlockLoader = Arc::new(move |cid: lCid| {
+            let store = Arc::clone(&store);
+            async move {
+                let data: Option<Vec<u8>> = store
+                    .blocking(move |store| store.blockstore().get(&cid.to_cid()))
+                    .await??;
+                data.ok_or_else(|| anyhow!("Block {cid} of the dag is no longer stored"))
+            }
+            .boxed()
+        });
+        Ok(BlockStream::new(blocks, load))
+    }
 
-pub const MAX_BLOCK_SIZE: usize = 1048576;
-pub const MAX_CHUNK_SIZE: usize = 104857600;
-pub const DEFAULT_CHUNK_SIZE: usize = 10 * 1024 * 1024; // chunk to ~10MB CARs
+    /// Add the stored dag under `root_cid` to the content listing.
+    async fn list(&self, root_cid: Cid, pinned: bool) {
+        let result = self
+            .dag_size(root_cid)
+            .await
+            .and_then(|size| self.content.record(root_cid, size, pinned));
+        if let Err(e) = result {
+            warn!("Failed to list {root_cid} as stored content: {e}");
+        }
+    }
 
-/// Network Api
-#[derive(Deserialize, Serialize)]
-pub struct NetworkGetParams {
-    pub cid: String,
-}
+    /// Put the car file, raw file or directory at `path`.
+    async fn put_path(
+        &self,
+        path: String,
+        ignore: Vec<String>,
+        operation: Option<OperationId>,
+    ) -> Result<Vec<Cid>> {
+        if async_std::path::Path::new(&path).is_dir().await {
+            let result = self.put_directory(&path, ignore, operation).await;
+            self.finish_put(operation, &result);
+            return result;
+        }
 
-pub type NetworkGetResult = Vec<u8>;
-pub const NETWORK_GET: &str = "ursa_get_cid";
+        info!("Putting the file on network: {path}");
+        let file = match File::open(path.clone()).await {
+            Ok(file) => file,
+            Err(e) => {
+                let result = Err(anyhow!("Cannot open {path}: {e}"));
+                if let Some(id) = operation {
+                    self.operations.finish(id, &result);
+                }
+                return result;
+            }
+        };
+        let mut reader = BufReader::new(file);
+        let is_car = path.ends_with(".car") || reader.fill_buf().await.map_or(false, is_car);
+        if is_car {
+            return self.put_car(reader, operation).await;
+        }
 
-#[derive(Deserialize, Serialize)]
-pub struct NetworkPutFileParams {
-    pub path: String,
-}
+        let config = &self.put_file;
+        let limits = (config.chunk_size, config.hash, config.max_size);
+        let result = match reader.get_ref().metadata().await {
+            Ok(metadata) if metadata.len() > config.max_size => Err(anyhow!(
+                "{path} is larger than the limit of {} bytes",
+                config.max_size
+            )),
+            Ok(_) => match self.put_chunked(reader, &path, limits, operation).await {
+                Ok(root) => self.index(vec![root], true).await,
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(anyhow!("Cannot read {path}: {e}")),
+        };
+        self.finish_put(operation, &result);
+        result
+    }
 
-pub type NetworkPutFileResult = String;
-pub const NETWORK_PUT_FILE: &str = "ursa_put_file";
+    /// Count the stored size of the dag under `root_cid` against `api_key`.
+    async fn charge_dag(&self, api_key: &str, root_cid: Cid) {
+        let bytes = match self.dag_size(root_cid).await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                warn!("Cannot size {root_cid} for its api key: {e}");
+                return;
+            }
+        };
+        if let Err(e) = self.api_keys.charge(api_key, bytes) {
+            warn!("Failed to charge {bytes} bytes of {root_cid} to its api key: {e}");
+        }
+    }
 
-#[derive(Deserialize, Serialize)]
-pub struct NetworkGetFileParams {
-    pub path: String,
-    pub cid: String,
-}
-pub const NETWORK_GET_FILE: &str = "ursa_get_file";
+    /// Set the context ids `entries` are advertised under.
+    async fn with_contexts(&self, mut entries: Vec<ContentEntry>) -> Result<Vec<ContentEntry>> {
+        let cids = entries
+            .iter()
+            .map(|entry| Ok(Cid::try_from(entry.cid.as_str())?))
+            .collect::<Result<Vec<Cid>>>()?;
+        let (sender, receiver) = oneshot::channel();
+        self.send_command(UrsaCommand::ContextIds { cids, sender })
+            .await?;
+        for (entry, context_id) in entries.iter_mut().zip(receiver.await??) {
+            entry.context_id = Some(context_string(&context_id));
+        }
+        Ok(entries)
+    }
 
-/// Abstraction of Ursa's server commands
-#[async_trait]
-pub trait NetworkInterface: Sync + Send + 'static {
-    /// Get a bitswap block from the network
-    async fn get(&self, cid: Cid) -> Result<Option<Vec<u8>>>;
+    /// Make sure the full dag under `root_cid` is stored locally.
+    ///
+    /// Returns the number of blocks in the dag.
+    async fn sync(&self, root_cid: Cid, providers: Vec<Multiaddr>) -> Result<usize> {
+        if !self.store.blockstore().has(&root_cid)? {
+            self.inflight
+                .run(
+                    fetch_key(root_cid, true, &providers),
+                    self.fetch(root_cid, BitswapType::Sync, providers),
+                )
+                .await?;
+        }
+        let dag = self.dag(root_cid).await?;
+        Ok(dag.len())
+    }
 
-    async fn get_data(&self, root_cid: Cid) -> Result<Vec<(lCid, Vec<u8>)>>;
+    /// Fetch `cid` over bitswap, falling back to the origin when the query
+    /// fails or does not complete within the origin's bitswap timeout.
+    async fn fetch(&self, cid: Cid, query: BitswapType, providers: Vec<Multiaddr>) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        let request = UrsaCommand::GetBitswap {
+            cid,
+            query,
+            providers,
+            sender,
+        };
 
-    /// get the file locally via cli
-    async fn get_file(&self, path: String, cid: Cid) -> Result<()>;
+        // use network sender to send command
+        self.send_command(request).await?;
 
-    // stream the car file from server
-    async fn stream(
-        &self,
-        root_cid: Cid,
-    ) -> Result<StreamBody<ReaderStream<tokio::io::DuplexStream>>>;
+        let result = if self.origin.is_enabled() {
+            match timeout(self.origin.bitswap_timeout(), receiver).await {
+                Ok(res) => res?,
+                Err(_) => Err(anyhow!("The bitswap query for {} timed out", cid)),
+            }
+        } else {
+            receiver.await?
+        };
+
+        match result {
+            Ok(()) => Ok(()),
+            // a cancelled fetch does not write the content from the origin either
+            Err(e) if e.downcast_ref::<Cancelled>().is_some() => Err(e),
+            Err(e) if self.origin.is_enabled() => {
+                warn!("Bitswap could not get {cid}, falling back to origin: {e:?}");
+                let cids = self
+                    .origin
+                    .pull(&self.store, &cid, &self.car_import)
+                    .await?;
+                self.index(cids, false).await?;
+                Ok(())
+            }
+            Err(e) => Err(anyhow!(
+                "The bitswap failed, please check server logs {:?}",
+                e
+            )),
+        }
+    }
+}
 
-    /// Put a car file and start providing to the network
-    async fn put_car<R: AsyncRead + Send + Unpin>(&self, reader: R) -> Result<Vec<Cid>>;
+/// Whether `head`, the start of a file, is the header of a car file: a varint length
+/// followed by a dag-cbor map of the roots and the version.
+fn is_car(head: &[u8]) -> bool {
+    let header = match head.iter().position(|byte| byte & 0x80 == 0) {
+        Some(end) => &head[end + 1..],
+        None => return false,
+    };
+    let has_key = |key: &[u8]| header.windows(key.len()).any(|window| window == key);
+    header.first() == Some(&0xa2) && has_key(b"roots") && has_key(b"version")
+}
 
-    // Put a file using a local path
-    async fn put_file(&self, path: String) -> Result<Vec<Cid>>;
+fn parse_context(context_id: &str) -> Result<Vec<u8>> {
+    context_bytes(context_id).ok_or_else(|| {
+        anyhow!(ApiError::invalid_params(format!(
+            "{context_id} is neither a cid nor hex bytes"
+        )))
+    })
 }
-#[derive(Clone)]
-pub struct NodeNetworkInterface<S>
-where
-    S: BlockStore + Sync + Send + 'static,
-{
-    pub store: Arc<Store<S>>,
-    pub network_send: Sender<UrsaCommand>,
+
+/// Key of an in-flight fetch: the cid, whether the full dag is synced, and the
+/// provider hints. A fetch with hints only asks those peers, so fetches of the same
+/// cid with other hints do not wait on it.
+type FetchKey = (Cid, bool, Vec<Multiaddr>);
+
+fn fetch_key(cid: Cid, sync: bool, providers: &[Multiaddr]) -> FetchKey {
+    let mut providers = providers.to_vec();
+    providers.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+    providers.dedup();
+    (cid, sync, providers)
 }
 
 #[async_trait]
@@ -88,146 +1772,704 @@ impl<S> NetworkInterface for NodeNetworkInterface<S>
 where
     S: BlockStore + Sync + Send + 'static,
 {
-    async fn get(&self, cid: Cid) -> Result<Option<Vec<u8>>> {
-        if !self.store.blockstore().has(&cid).unwrap() {
+    async fn get(
+        &self,
+        cid: Cid,
+        providers: Vec<Multiaddr>,
+        deadline: Option<Duration>,
+        api_key: Option<String>,
+    ) -> Result<Option<Vec<u8>>> {
+        let key_id = api_key
+            .as_deref()
+            .and_then(|secret| self.api_keys.key_id(secret).ok());
+        if !self.acl.allows_key(&cid, key_id.as_deref()) {
+            return Err(anyhow!(ApiError::forbidden(format!(
+                "{cid} is private, send an api key allowed to retrieve it"
+            ))));
+        }
+        self.track_request(cid).await;
+        let cache_hit = self.store.blockstore().has(&cid).unwrap();
+        let access = AccessLogEntry::start(cid.to_string(), "rpc".to_string(), cache_hit);
+        if !cache_hit {
             info!("Requesting block with the cid {cid:?}");
-            let (sender, receiver) = oneshot::channel();
-            let request = UrsaCommand::GetBitswap {
-                cid,
-                query: BitswapType::Get,
-                sender,
+            let interface = self.clone();
+            let fetch = async move {
+                interface
+                    .inflight
+                    .run(
+                        fetch_key(cid, false, &providers),
+                        interface.fetch(cid, BitswapType::Get, providers),
+                    )
+                    .await
             };
-
-            // use network sender to send command
-            self.network_send.send(request).await?;
-            if let Err(e) = receiver.await? {
-                return Err(anyhow!(
-                    "The bitswap failed, please check server logs {:?}",
-                    e
-                ));
+            match deadline {
+                // the fetch keeps running for the other waiters when this caller gives up
+                Some(deadline) => timeout(deadline, task::spawn(fetch))
+                    .await
+                    .map_err(|_| anyhow!("The block {cid} was not found within {deadline:?}"))??,
+                None => fetch.await?,
             }
         }
-        self.store.blockstore().get(&cid)
+        let block = self.store.blockstore().get(&cid)?;
+        let bytes = block.as_ref().map_or(0, |b| b.len() as u64);
+        self.access_log.record(access.finish(bytes));
+        Ok(block)
     }
 
-    async fn get_data(&self, root_cid: Cid) -> Result<Vec<(lCid, Vec<u8>)>> {
+    async fn get_data(&self, root_cid: Cid) -> Result<BlockStream> {
+        self.track_request(root_cid).await;
         if !self.store.blockstore().has(&root_cid).unwrap() {
-            let (sender, receiver) = oneshot::channel();
-            let request = UrsaCommand::GetBitswap {
-                cid: root_cid,
-                query: BitswapType::Sync,
-                sender,
+            self.inflight
+                .run(
+                    fetch_key(root_cid, true, &[]),
+                    self.fetch(root_cid, BitswapType::Sync, vec![]),
+                )
+                .await?;
+            self.list(root_cid, false).await;
+        }
+        let blocks = self.block_stream(root_cid).await?;
+        info!("Dag traversal done, now streaming the file");
+
+        Ok(blocks)
+    }
+
+    async fn stream(&self, root_cid: Cid) -> Result<CarStream> {
+        // fetch before answering so a failed or rejected fetch surfaces as an error
+        let blocks = self.get_data(root_cid).await?;
+        CarStream::new(root_cid, blocks)
+    }
+
+    /// Used through CLI
+    async fn get_file(
+        &self,
+        path: String,
+        root_cid: Cid,
+        format: GetFileFormat,
+        operation: Option<OperationId>,
+    ) -> Result<()> {
+        if let Some(id) = operation {
+            self.operations.add_roots(id, &[root_cid]);
+            self.watch_sync(root_cid, id).await;
+        }
+        let result = match format {
+            GetFileFormat::Car => self.write_car_file(path, root_cid).await,
+            GetFileFormat::Raw => self.write_raw_file(path, root_cid).await,
+        };
+        if let Some(id) = operation {
+            self.operations.finish(id, &result);
+        }
+        result
+    }
+
+    async fn put_car<R: AsyncRead + Send + Unpin>(
+        &self,
+        reader: R,
+        operation: Option<OperationId>,
+    ) -> Result<Vec<Cid>> {
+        let id = match operation {
+            Some(id) => id,
+            None => {
+                let cids = import::import_car(&self.store, reader, &self.car_import, None).await?;
+                info!("The inserted cids are: {cids:?}");
+                return self.index(cids, true).await;
+            }
+        };
+
+        let reader = ProgressReader::new(reader, Arc::clone(&self.operations), id);
+        let progress = Some((Arc::clone(&self.operations), id));
+        let result = match import::import_car(&self.store, reader, &self.car_import, progress).await
+        {
+            Ok(cids) => {
+                info!("The inserted cids are: {cids:?}");
+                self.operations.update(id, |status| {
+                    status.cid = cids.first().map(Cid::to_string);
+                });
+                self.index(cids, true).await
+            }
+            Err(e) => Err(e),
+        };
+        self.operations.finish(id, &result);
+        result
+    }
+
+    /// Used through CLI
+    async fn put_file(
+        &self,
+        path: String,
+        ignore: Vec<String>,
+        api_key: Option<String>,
+        operation: Option<OperationId>,
+    ) -> Result<Vec<Cid>> {
+        let result = self.put_path(path, ignore, operation).await;
+        if let (Ok(roots), Some(api_key)) = (&result, api_key) {
+            for root in roots {
+                self.charge_dag(&api_key, *root).await;
+            }
+        }
+        result
+    }
+
+    async fn put_url(
+        &self,
+        url: String,
+        format: PutUrlFormat,
+        sha256: Option<String>,
+        api_key: Option<String>,
+    ) -> Result<Vec<Cid>> {
+        info!("Putting the content of {url} on network");
+        let deadline = Duration::from_millis(self.put_url.timeout_ms);
+        let config = &self.put_url;
+        let stored = async {
+            let (mut download, is_car) =
+                download::download(&url, config.max_size, &config.allowed_hosts).await?;
+            let is_car = match format {
+                PutUrlFormat::Auto => is_car,
+                PutUrlFormat::Car => true,
+                PutUrlFormat::File => false,
+            };
+            let cids = if is_car {
+                let cids =
+                    import::import_car(&self.store, &mut download, &self.car_import, None).await?;
+                // the checksum covers whatever follows the last block too
+                io::copy(&mut download, &mut io::sink()).await?;
+                info!("The inserted cids are: {cids:?}");
+                cids
+            } else {
+                let limits = (config.chunk_size, config.hash, config.max_size);
+                vec![self.put_chunked(&mut download, &url, limits, None).await?]
             };
+            Ok::<_, anyhow::Error>((cids, download.sha256()))
+        };
+        let (cids, actual) = timeout(deadline, stored)
+            .await
+            .map_err(|_| anyhow!("Downloading {url} took longer than {deadline:?}"))??;
 
-            // use network sender to send command
-            self.network_send.send(request).await?;
-            if let Err(e) = receiver.await? {
+        if let Some(expected) = sha256 {
+            if !actual.eq_ignore_ascii_case(expected.trim()) {
                 return Err(anyhow!(
-                    "The bitswap failed, please check server logs {:?}",
-                    e
+                    "Checksum mismatch for {url}: expected {expected}, got {actual}"
                 ));
             }
         }
-        let dag = self
-            .store
-            .dag_traversal(&convert_cid(root_cid.to_bytes()))?;
-        info!("Dag traversal done, now streaming the file");
-
-        Ok(dag)
+        let roots = self.index(cids, true).await?;
+        if let Some(api_key) = api_key {
+            for root in &roots {
+                self.charge_dag(&api_key, *root).await;
+            }
+        }
+        Ok(roots)
     }
 
-    async fn stream(
+    async fn prefetch(
         &self,
-        root_cid: Cid,
-    ) -> Result<StreamBody<ReaderStream<tokio::io::DuplexStream>>> {
-        let header = CarHeader {
-            roots: vec![root_cid],
-            version: 1,
+        cids: Vec<Cid>,
+        providers: Vec<Multiaddr>,
+        api_key: Option<String>,
+        operation: Option<OperationId>,
+    ) -> Result<()> {
+        self.prefetch.queue(&cids).await;
+        if let Some(id) = operation {
+            self.operations.add_roots(id, &cids);
+        }
+
+        let statuses = join_all(cids.into_iter().map(|cid| {
+            let providers = providers.clone();
+            let api_key = api_key.as_deref();
+            async move {
+                let _permit = self.prefetch.acquire().await;
+                self.prefetch.set(cid, PrefetchStatus::Fetching).await;
+                if let Some(id) = operation {
+                    self.watch_sync(cid, id).await;
+                }
+
+                let status = match self.sync(cid, providers).await {
+                    Ok(blocks) => {
+                        self.list(cid, true).await;
+                        if let Some(api_key) = api_key {
+                            self.charge_dag(api_key, cid).await;
+                        }
+                        PrefetchStatus::Done { blocks }
+                    }
+                    Err(e) => {
+                        warn!("Prefetch of {cid} failed: {e:?}");
+                        PrefetchStatus::Failed {
+                            error: e.to_string(),
+                        }
+                    }
+                };
+                self.prefetch.set(cid, status.clone()).await;
+                status
+            }
+        }))
+        .await;
+
+        let failed = statuses
+            .iter()
+            .filter(|status| matches!(status, PrefetchStatus::Failed { .. }))
+            .count();
+        let result = if failed == 0 {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "{failed} of {} prefetched roots failed",
+                statuses.len()
+            ))
         };
+        if let Some(id) = operation {
+            self.operations.finish(id, &result);
+        }
+        result
+    }
+
+    async fn prefetch_status(&self, cids: Vec<Cid>) -> Result<Vec<PrefetchProgress>> {
+        Ok(self.prefetch.progress(&cids).await)
+    }
 
-        let (tx, mut rx) = unbounded();
-        let (writer, reader) = tokio::io::duplex(1024 * 100);
+    async fn remove(&self, token: Option<String>, cids: Vec<Cid>) -> Result<Vec<Cid>> {
+        self.settings.authorize(token.as_deref())?;
+        self.remove_roots(cids).await
+    }
 
-        let body = axum::body::StreamBody::new(ReaderStream::new(reader));
+    async fn set_context(
+        &self,
+        token: Option<String>,
+        cids: Vec<Cid>,
+        context_id: String,
+    ) -> Result<()> {
+        self.settings.authorize(token.as_deref())?;
+        let context_id = parse_context(&context_id)?;
+        let (sender, receiver) = oneshot::channel();
+        self.send_command(UrsaCommand::SetContext {
+            cids,
+            context_id,
+            sender,
+        })
+        .await?;
+        receiver.await?
+    }
 
-        async_std::task::spawn(async move {
-            header
-                .write_stream_async(&mut writer.compat_write(), &mut rx)
-                .await
-                .unwrap()
-        });
-        let dag = self.get_data(root_cid).await.unwrap();
+    async fn unindex_context(&self, token: Option<String>, context_id: String) -> Result<Vec<Cid>> {
+        self.settings.authorize(token.as_deref())?;
+        let context_id = parse_context(&context_id)?;
+        let (sender, receiver) = oneshot::channel();
+        self.send_command(UrsaCommand::UnindexContext { context_id, sender })
+            .await?;
+        receiver.await?
+    }
+
+    async fn purge(
+        &self,
+        token: Option<String>,
+        cids: Vec<Cid>,
+        context_ids: Vec<String>,
+    ) -> Result<PurgeMessage> {
+        self.settings.authorize(token.as_deref())?;
+        let (sender, receiver) = oneshot::channel();
+        self.send_command(UrsaCommand::Purge {
+            cids: cids.iter().map(Cid::to_string).collect(),
+            context_ids: context_ids.clone(),
+            sender,
+        })
+        .await?;
+        let purge = receiver.await??;
+        self.evict(cids, context_ids).await?;
+        Ok(purge)
+    }
+
+    async fn access_log(
+        &self,
+        token: Option<String>,
+        limit: usize,
+        cid: Option<String>,
+    ) -> Result<Vec<AccessLogEntry>> {
+        // client addresses are not for the public listener
+        self.settings.authorize(token.as_deref())?;
+        Ok(self.access_log.recent(limit, cid.as_deref()))
+    }
+
+    async fn receipts(
+        &self,
+        token: Option<String>,
+        limit: usize,
+        cid: Option<String>,
+    ) -> Result<Vec<DeliveryReceipt>> {
+        // client keys are not for the public listener either
+        self.settings.authorize(token.as_deref())?;
+        Ok(self.receipts.recent(limit, cid.as_deref()))
+    }
+
+    async fn accounting(&self, token: Option<String>, query: UsageQuery) -> Result<Vec<Rollup>> {
+        // billing data of the tenants is not for the public listener
+        self.settings.authorize(token.as_deref())?;
+        let accounting = Arc::clone(&self.accounting);
+        self.store
+            .blocking(move |store| accounting.rollups(store, &query))
+            .await?
+    }
+
+    async fn gossip_stat(&self, topic: Option<String>) -> Result<Vec<GossipTopicStat>> {
+        let (sender, receiver) = oneshot::channel();
+        self.send_command(UrsaCommand::GossipStat { sender })
+            .await?;
+        let mut stat = receiver.await?;
+        if let Some(topic) = topic {
+            stat.retain(|s| s.topic == topic);
+        }
+        Ok(stat)
+    }
+
+    async fn topic_peers(&self, topic: Option<String>) -> Result<Vec<TopicPeers>> {
+        let (sender, receiver) = oneshot::channel();
+        self.send_command(UrsaCommand::TopicPeers { topic, sender })
+            .await?;
+        Ok(receiver.await?)
+    }
 
-        for (cid, data) in dag {
-            tx.send((convert_cid(cid.to_bytes()), data)).await.unwrap();
+    async fn find_providers(
+        &self,
+        cid: Cid,
+        deadline: Option<Duration>,
+    ) -> Result<Vec<ContentProvider>> {
+        let mut found = self.provider_stream(cid, deadline).await?;
+        let mut providers: Vec<ContentProvider> = vec![];
+        while let Some(provider) = found.next().await {
+            // a peer may be found both locally and by the walk
+            match providers.iter_mut().find(|p| p.peer_id == provider.peer_id) {
+                Some(known) => {
+                    for addr in provider.addrs {
+                        if !known.addrs.contains(&addr) {
+                            known.addrs.push(addr);
+                        }
+                    }
+                }
+                None => providers.push(provider),
+            }
         }
-        drop(tx);
+        Ok(providers)
+    }
 
-        Ok(body)
+    async fn provider_stream(
+        &self,
+        cid: Cid,
+        deadline: Option<Duration>,
+    ) -> Result<BoxStream<'static, ContentProvider>> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.send_command(UrsaCommand::GetProviders { cid, sender })
+            .await?;
+        Ok(match deadline {
+            Some(deadline) => receiver.take_until(task::sleep(deadline)).boxed(),
+            None => receiver.boxed(),
+        })
     }
 
-    /// Used through CLI
-    async fn get_file(&self, path: String, root_cid: Cid) -> Result<()> {
-        info!("getting and storing the file at: {path}");
+    async fn node_info(&self) -> Result<NodeInfo> {
+        let (sender, receiver) = oneshot::channel();
+        self.send_command(UrsaCommand::NodeInfo { sender }).await?;
+        Ok(receiver.await?)
+    }
 
-        let header = CarHeader {
-            roots: vec![root_cid],
-            version: 1,
+    async fn peer_protocols(&self, protocol: Option<String>) -> Result<ProtocolInventory> {
+        let (sender, receiver) = oneshot::channel();
+        self.send_command(UrsaCommand::PeerProtocols { protocol, sender })
+            .await?;
+        Ok(receiver.await?)
+    }
+
+    async fn relay_circuits(&self) -> Result<Vec<RelayCircuit>> {
+        let (sender, receiver) = oneshot::channel();
+        self.send_command(UrsaCommand::RelayCircuits { sender })
+            .await?;
+        Ok(receiver.await?)
+    }
+
+    async fn provider_status(&self) -> Result<AnnounceStatus> {
+        let (sender, receiver) = oneshot::channel();
+        self.send_command(UrsaCommand::ProviderStatus { sender })
+            .await?;
+        Ok(receiver.await?)
+    }
+
+    fn start_operation(&self, kind: OperationKind, cid: Option<Cid>) -> OperationId {
+        self.operations.start(kind, cid)
+    }
+
+    async fn operation_status(&self, id: OperationId) -> Result<Option<OperationStatus>> {
+        Ok(self.operations.status(id))
+    }
+
+    async fn cancel(&self, id: OperationId) -> Result<bool> {
+        if self.operations.status(id).is_none() {
+            return Err(ApiError::not_found(format!("No operation with id {id}")).into());
+        }
+        let roots = match self.operations.cancel(id) {
+            Some(roots) => roots,
+            None => return Ok(false),
         };
+        for root in roots {
+            let (sender, receiver) = oneshot::channel();
+            self.send_command(UrsaCommand::CancelBitswap { cid: root, sender })
+                .await?;
+            if !receiver.await? {
+                info!("Operation {id} was cancelled before the sync of {root} started");
+            }
+        }
+        Ok(true)
+    }
+
+    fn admit(&self, api_key: Option<&str>, bytes: u64) -> Result<()> {
+        self.api_keys.admit(api_key, bytes)
+    }
+
+    async fn create_api_key(
+        &self,
+        token: Option<String>,
+        name: String,
+        quota_bytes: Option<u64>,
+        rate_limit: Option<u32>,
+    ) -> Result<ApiKeyCreated> {
+        self.settings.authorize(token.as_deref())?;
+        let (id, key) = self.api_keys.create(name, quota_bytes, rate_limit)?;
+        info!("Created api key {id}");
+        Ok(ApiKeyCreated { id, key })
+    }
+
+    async fn revoke_api_key(&self, token: Option<String>, id: String) -> Result<bool> {
+        self.settings.authorize(token.as_deref())?;
+        self.api_keys.revoke(&id)
+    }
+
+    async fn api_key_usage(
+        &self,
+        token: Option<String>,
+        id: Option<String>,
+    ) -> Result<Vec<ApiKeyUsage>> {
+        self.settings.authorize(token.as_deref())?;
+        self.api_keys.usage(id.as_deref())
+    }
+
+    async fn sign_url(
+        &self,
+        token: Option<String>,
+        cid: Cid,
+        ttl_secs: Option<u64>,
+    ) -> Result<SignedUrl> {
+        self.settings.authorize(token.as_deref())?;
+        self.signed_urls.sign_for(&cid, ttl_secs)
+    }
+
+    async fn acl_set(
+        &self,
+        token: Option<String>,
+        cid: Cid,
+        peers: Vec<PeerId>,
+        api_keys: Vec<String>,
+    ) -> Result<AclEntry> {
+        self.settings.authorize(token.as_deref())?;
+        let entry = self.acl.set(&self.store, cid, peers, api_keys)?;
+        info!("{cid} is private to {entry:?}");
+        Ok(entry)
+    }
+
+    async fn acl_remove(&self, token: Option<String>, cid: Cid) -> Result<bool> {
+        self.settings.authorize(token.as_deref())?;
+        self.acl.remove(&self.store, &cid)
+    }
 
-        let buffer: Arc<RwLock<Vec<u8>>> = Default::default();
-        let (tx, mut rx) = unbounded();
+    async fn acl_list(&self, token: Option<String>) -> Result<Vec<AclEntry>> {
+        self.settings.authorize(token.as_deref())?;
+        Ok(self.acl.entries())
+    }
 
-        let buffer_cloned = buffer.clone();
-        let write_task = async_std::task::spawn(async move {
-            header
-                .write_stream_async(&mut *buffer_cloned.write().await, &mut rx)
-                .await
-                .unwrap()
+    async fn list_content(
+        &self,
+        cursor: Option<String>,
+        limit: usize,
+        filter: ContentFilter,
+        api_key: Option<String>,
+    ) -> Result<ContentPage> {
+        let key_id = api_key
+            .as_deref()
+            .and_then(|secret| self.api_keys.key_id(secret).ok());
+        let mut entries = self.content.list(cursor.as_deref(), &filter);
+        // listing a private root would give away the cid the acl hides
+        entries.retain(|entry| {
+            Cid::from_str(&entry.cid)
+                .map_or(false, |cid| self.acl.allows_key(&cid, key_id.as_deref()))
         });
-        let dag = self.get_data(root_cid).await.unwrap();
+        // the context filter needs the context of every entry, not just of one page
+        let mut page = match &filter.context_id {
+            Some(context_id) => {
+                let mut entries = self.with_contexts(entries).await?;
+                entries.retain(|entry| entry.context_id.as_ref() == Some(context_id));
+                return Ok(paginate(entries, limit));
+            }
+            None => paginate(entries, limit),
+        };
+        page.items = self.with_contexts(page.items).await?;
+        Ok(page)
+    }
+
+    async fn dag_stat(
+        &self,
+        root_cid: Cid,
+        fetch: bool,
+        api_key: Option<String>,
+    ) -> Result<DagStat> {
+        if !self.store.blockstore().has(&root_cid)? {
+            if !fetch {
+                return Err(anyhow!(ApiError::not_found(format!(
+                    "{root_cid} is not stored on this node"
+                ))));
+            }
+            // a fetch is a prefetch, under the same key requirement, rate limit and quota
+            self.admit(api_key.as_deref(), 0)?;
+            self.sync(root_cid, vec![]).await?;
+            self.list(root_cid, false).await;
+            if let Some(api_key) = &api_key {
+                self.charge_dag(api_key, root_cid).await;
+            }
+        }
+        let root = root_cid.to_ipld_cid();
+        self.store
+            .blocking(move |store| store.dag_stat(&root))
+            .await?
+    }
 
-        for (cid, data) in dag {
-            tx.send((convert_cid(cid.to_bytes()), data)).await.unwrap();
+    async fn verify(
+        &self,
+        token: Option<String>,
+        root_cid: Cid,
+        repair: bool,
+    ) -> Result<VerifyReport> {
+        // a repair deletes blocks and syncs, a plain verify only reads
+        if repair {
+            self.settings.authorize(token.as_deref())?;
+        }
+        let root = root_cid.to_ipld_cid();
+        let found = self
+            .store
+            .blocking(move |store| store.verify_dag(&root))
+            .await??;
+        let mut report = VerifyReport {
+            blocks: found.blocks,
+            missing: found.missing.iter().map(lCid::to_string).collect(),
+            corrupt: found.corrupt.iter().map(lCid::to_string).collect(),
+            repaired: false,
+        };
+        if found.is_intact() {
+            info!("{root_cid} verified, {} blocks", found.blocks);
+            return Ok(report);
+        }
+        warn!(
+            "{root_cid} has {} missing and {} corrupt blocks",
+            found.missing.len(),
+            found.corrupt.len()
+        );
+        if !repair {
+            return Ok(report);
         }
-        drop(tx);
-        write_task.await;
 
-        let buffer: Vec<_> = buffer.read().await.clone();
-        let file_path = PathBuf::from(path).join(format!("{}.car", root_cid));
-        create_dir_all(file_path.parent().unwrap()).await?;
-        let mut file = File::create(file_path).await.unwrap();
-        file.write_all(&buffer).await?;
-        Ok(())
+        // corrupt blocks are deleted so the sync of the root fetches them like the missing
+        let corrupt = found.corrupt;
+        self.store
+            .blocking(move |store| -> Result<()> {
+                for cid in corrupt {
+                    store.blockstore().delete(cid.to_bytes())?;
+                }
+                Ok(())
+            })
+            .await??;
+        self.inflight
+            .run(
+                fetch_key(root_cid, true, &[]),
+                self.fetch(root_cid, BitswapType::Sync, vec![]),
+            )
+            .await?;
+        report.repaired = self
+            .store
+            .blocking(move |store| store.verify_dag(&root))
+            .await??
+            .is_intact();
+        Ok(report)
     }
 
-    async fn put_car<R: AsyncRead + Send + Unpin>(&self, reader: R) -> Result<Vec<Cid>> {
-        let cids = load_car(self.store.blockstore(), reader).await?;
+    async fn resolve(&self, root_cid: Cid, path: String) -> Result<Resolved> {
+        self.store
+            .blocking(move |store| resolve::resolve(store, root_cid, &path))
+            .await?
+    }
 
-        info!("The inserted cids are: {cids:?}");
+    async fn name_publish(
+        &self,
+        token: Option<String>,
+        root_cid: Cid,
+        ttl_secs: Option<u64>,
+    ) -> Result<NameRecord> {
+        // the record is signed with the node key
+        self.settings.authorize(token.as_deref())?;
+        let (sender, receiver) = oneshot::channel();
+        self.send_command(UrsaCommand::PublishName {
+            cid: root_cid,
+            ttl_secs: ttl_secs.unwrap_or(DEFAULT_NAME_TTL_SECS),
+            sender,
+        })
+        .await?;
+        receiver.await?
+    }
 
+    async fn name_resolve(&self, name: PeerId) -> Result<NameRecord> {
         let (sender, receiver) = oneshot::channel();
-        let request = UrsaCommand::Index {
-            cids: cids.clone(),
+        self.send_command(UrsaCommand::ResolveName {
+            name: name.to_string(),
             sender,
+        })
+        .await?;
+        receiver.await?.ok_or_else(|| {
+            anyhow!(ApiError::not_found(format!(
+                "No valid record of the name {name} was found"
+            )))
+        })
+    }
+
+    fn repo_compact(&self, token: Option<String>, columns: Vec<Column>) -> Result<OperationId> {
+        self.settings.authorize(token.as_deref())?;
+        let columns = if columns.is_empty() {
+            Column::ALL.to_vec()
+        } else {
+            columns
         };
+        self.compactor.start(columns)
+    }
 
-        self.network_send.send(request).await?;
-        match receiver.await {
-            Ok(_) => Ok(cids),
-            Err(e) => Err(anyhow!(format!(
-                "The PUT failed, please check server logs {:?}",
-                e
-            ))),
-        }
+    fn config_get(
+        &self,
+        token: Option<String>,
+        keys: Vec<String>,
+    ) -> Result<BTreeMap<String, Value>> {
+        self.settings.authorize(token.as_deref())?;
+        self.settings.get(&keys)
     }
 
-    /// Used through CLI
-    async fn put_file(&self, path: String) -> Result<Vec<Cid>> {
-        info!("Putting the file on network: {path}");
-        let file = File::open(path.clone()).await?;
-        let reader = BufReader::new(file);
-        self.put_car(reader).await
+    fn config_set(
+        &self,
+        token: Option<String>,
+        key: String,
+        value: Value,
+    ) -> Result<SettingChange> {
+        self.settings.authorize(token.as_deref())?;
+        self.settings.set(&key, value)
+    }
+
+    async fn diagnostics(&self) -> Result<DiagnosticsReport> {
+        let info = self.node_info().await?;
+        let (sender, receiver) = oneshot::channel();
+        self.send_command(UrsaCommand::GetPeers { sender }).await?;
+        let peers = receiver.await?;
+        let announces = self.provider_status().await.ok();
+        Ok(self
+            .diagnostics
+            .run(&self.store, &info, &peers, announces.as_ref())
+            .await)
     }
 }
 
@@ -260,6 +2502,40 @@ mod tests {
         Arc::new(Store::new(Arc::clone(&db)))
     }
 
+    #[test]
+    fn test_is_car() {
+        // {"roots": [], "version": 1} after its varint length
+        let mut car = vec![0x11, 0xa2, 0x65];
+        car.extend_from_slice(b"roots");
+        car.extend_from_slice(&[0x80, 0x67]);
+        car.extend_from_slice(b"version");
+        car.push(0x01);
+        assert!(is_car(&car));
+        assert!(!is_car(b"hello ursa"));
+        assert!(!is_car(&[]));
+    }
+
+    #[test]
+    fn test_fetch_key() {
+        let cid =
+            Cid::from_str("bafkreif2opfibjypwkjzzry3jbibcjqcjwnpoqpeiqw75eu3s3u3zbdszq").unwrap();
+        let a: Multiaddr = "/ip4/10.0.0.1/tcp/6009".parse().unwrap();
+        let b: Multiaddr = "/ip4/10.0.0.2/tcp/6009".parse().unwrap();
+
+        assert_eq!(
+            fetch_key(cid, false, &[a.clone(), b.clone()]),
+            fetch_key(cid, false, &[b.clone(), a.clone(), b])
+        );
+        assert_ne!(
+            fetch_key(cid, false, &[a.clone()]),
+            fetch_key(cid, false, &[])
+        );
+        assert_ne!(
+            fetch_key(cid, false, &[a.clone()]),
+            fetch_key(cid, true, &[a])
+        );
+    }
+
     #[async_std::test]
     async fn test_stream() -> Result<()> {
         setup_logger(LevelFilter::Info);
@@ -279,7 +2555,7 @@ mod tests {
         );
 
         let service =
-            UrsaService::new(keypair, &config, Arc::clone(&store), index_provider.clone());
+            UrsaService::new(keypair, &config, Arc::clone(&store), index_provider.clone()).unwrap();
         let rpc_sender = service.command_sender().clone();
 
         // Start libp2p service
@@ -289,13 +2565,17 @@ mod tests {
             }
         });
 
-        let interface = Arc::new(NodeNetworkInterface {
+        let interface = Arc::new(NodeNetworkInterface::new(
             store,
-            network_send: rpc_sender,
-        });
+            rpc_sender,
+            Origin::default(),
+            AccessLog::default(),
+            OverflowPolicy::default(),
+            PutUrlConfig::default(),
+        ));
 
         let cids = interface
-            .put_file("../../car_files/text_b.car".to_string())
+            .put_file("../../car_files/text_b.car".to_string(), vec![], None, None)
             .await?;
         interface.stream(cids[0]).await?;
 
@@ -0,0 +1,313 @@
+//! Api keys for multi-tenant gateways.
+//!
+//! Every key carries an optional byte quota and an optional rate limit in requests per
+//! minute, enforced on uploads and prefetches. Keys are stored in the node database
+//! under `api_key/<id>`, with only the sha2-256 of the secret kept, so the secret is
+//! shown once when the key is created. The id is the start of that hash and is what
+//! admins revoke and report usage by.
+
+use anyhow::{anyhow, Result};
+use fnv::FnvHashMap;
+use ipld_blockstore::BlockStore;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use ursa_store::{columns::Column, Store};
+use ursa_utils::hex;
+
+use crate::config::ApiKeyConfig;
+
+/// Request header carrying the api key.
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// Database key of the ids of every created key.
+const KEY_IDS: &str = "api_keys";
+
+/// Length of the rate limit window.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApiKeyUsage {
+    pub id: String,
+    pub name: String,
+    /// Bytes the key may upload and prefetch, unlimited when unset.
+    pub quota_bytes: Option<u64>,
+    /// Requests per minute, unlimited when unset.
+    pub rate_limit: Option<u32>,
+    pub used_bytes: u64,
+    pub requests: u64,
+    pub revoked: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredKey {
+    /// Hex sha2-256 of the secret.
+    hash: String,
+    #[serde(flatten)]
+    usage: ApiKeyUsage,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiKeyError {
+    Missing,
+    Invalid,
+    Revoked,
+    RateLimited,
+    QuotaExceeded { used: u64, quota: u64 },
+}
+
+impl fmt::Display for ApiKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiKeyError::Missing => write!(f, "An api key is required"),
+            ApiKeyError::Invalid => write!(f, "Unknown api key"),
+            ApiKeyError::Revoked => write!(f, "The api key was revoked"),
+            ApiKeyError::RateLimited => write!(f, "Too many requests for the api key"),
+            ApiKeyError::QuotaExceeded { used, quota } => write!(
+                f,
+                "The api key quota is used up, {used} of {quota} bytes were used"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ApiKeyError {}
+
+/// The api key error `err` was caused by, if any.
+pub fn api_key_error(err: &anyhow::Error) -> Option<&ApiKeyError> {
+    err.downcast_ref::<ApiKeyError>()
+}
+
+pub struct ApiKeys<S>
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    store: Arc<Store<S>>,
+    config: ApiKeyConfig,
+    /// Start and request count of the current rate window of each key, also
+    /// serializing the updates of stored keys.
+    windows: Mutex<FnvHashMap<String, (Instant, u32)>>,
+}
+
+impl<S> ApiKeys<S>
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    pub fn new(store: Arc<Store<S>>, config: ApiKeyConfig) -> Self {
+        Self {
+            store,
+            config,
+            windows: Default::default(),
+        }
+    }
+
+    /// Create a key, returning its id and secret.
+    pub fn create(
+        &self,
+        name: String,
+        quota_bytes: Option<u64>,
+        rate_limit: Option<u32>,
+    ) -> Result<(String, String)> {
+        let mut secret = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let secret = hex(&secret);
+        let hash = hex(&Sha256::digest(secret.as_bytes()));
+        let id = key_id(&hash);
+
+        let _windows = self.windows.lock().unwrap();
+        let mut ids = self.ids()?;
+        ids.push(id.clone());
+        self.save(&StoredKey {
+            hash,
+            usage: ApiKeyUsage {
+                id: id.clone(),
+                name,
+                quota_bytes,
+                rate_limit,
+                used_bytes: 0,
+                requests: 0,
+                revoked: false,
+            },
+        })?;
+        self.store
+            .column(Column::Metadata)
+            .write(KEY_IDS, serde_json::to_vec(&ids)?)?;
+        Ok((id, secret))
+    }
+
+    /// Revoke key `id`, returning whether it exists.
+    pub fn revoke(&self, id: &str) -> Result<bool> {
+        let _windows = self.windows.lock().unwrap();
+        match self.load(id)? {
+            Some(mut key) => {
+                key.usage.revoked = true;
+                self.save(&key)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Usage of key `id`, or of every key when unset.
+    pub fn usage(&self, id: Option<&str>) -> Result<Vec<ApiKeyUsage>> {
+        let ids = match id {
+            Some(id) => vec![id.to_string()],
+            None => self.ids()?,
+        };
+        let mut usage = vec![];
+        for id in ids {
+            if let Some(key) = self.load(&id)? {
+                usage.push(key.usage);
+            }
+        }
+        Ok(usage)
+    }
+
+    /// Admit a request made with `secret` that stores `bytes`, counting it against
+    /// the rate limit and quota of the key. Requests without a key are admitted unless
+    /// keys are required.
+    pub fn admit(&self, secret: Option<&str>, bytes: u64) -> Result<()> {
+        let secret = match secret {
+            Some(secret) => secret,
+            None if self.config.required => return Err(anyhow!(ApiKeyError::Missing)),
+            None => return Ok(()),
+        };
+
+        let mut windows = self.windows.lock().unwrap();
+        let mut key = self.authenticate(secret)?;
+
+        if let Some(limit) = key.usage.rate_limit {
+            let now = Instant::now();
+            let window = windows.entry(key.usage.id.clone()).or_insert((now, 0));
+            if now.duration_since(window.0) >= RATE_WINDOW {
+                *window = (now, 0);
+            }
+            if window.1 >= limit {
+                return Err(anyhow!(ApiKeyError::RateLimited));
+            }
+            check_quota(&key.usage, bytes)?;
+            window.1 += 1;
+        } else {
+            check_quota(&key.usage, bytes)?;
+        }
+
+        key.usage.requests += 1;
+        key.usage.used_bytes += bytes;
+        self.save(&key)
+    }
+
+    /// Count `bytes` stored for `secret` after the fact, for requests whose size is
+    /// only known once they finished.
+    pub fn charge(&self, secret: &str, bytes: u64) -> Result<()> {
+        let _windows = self.windows.lock().unwrap();
+        let mut key = self.authenticate(secret)?;
+        key.usage.used_bytes += bytes;
+        self.save(&key)
+    }
+
+    /// Id of the key with `secret`, failing for unknown and revoked keys.
+    pub fn key_id(&self, secret: &str) -> Result<String> {
+        Ok(self.authenticate(secret)?.usage.id)
+    }
+
+    fn authenticate(&self, secret: &str) -> Result<StoredKey> {
+        let hash = hex(&Sha256::digest(secret.as_bytes()));
+        let key = match self.load(&key_id(&hash))? {
+            Some(key) if key.hash == hash => key,
+            _ => return Err(anyhow!(ApiKeyError::Invalid)),
+        };
+        if key.usage.revoked {
+            return Err(anyhow!(ApiKeyError::Revoked));
+        }
+        Ok(key)
+    }
+
+    fn ids(&self) -> Result<Vec<String>> {
+        match self.store.column(Column::Metadata).read(KEY_IDS)? {
+            Some(ids) => Ok(serde_json::from_slice(&ids)?),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn load(&self, id: &str) -> Result<Option<StoredKey>> {
+        match self
+            .store
+            .column(Column::Metadata)
+            .read(format!("api_key/{id}"))?
+        {
+            Some(key) => Ok(Some(serde_json::from_slice(&key)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn save(&self, key: &StoredKey) -> Result<()> {
+        self.store.column(Column::Metadata).write(
+            format!("api_key/{}", key.usage.id),
+            serde_json::to_vec(key)?,
+        )?;
+        Ok(())
+    }
+}
+
+/// Whether `bytes` more fit the quota of `usage`. Requests of unknown size, counted
+/// as zero bytes, only fit while some of the quota is left.
+fn check_quota(usage: &ApiKeyUsage, bytes: u64) -> Result<()> {
+    match usage.quota_bytes {
+        Some(quota) if usage.used_bytes >= quota || usage.used_bytes + bytes > quota => {
+            Err(anyhow!(ApiKeyError::QuotaExceeded {
+                used: usage.used_bytes,
+                quota,
+            }))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn key_id(hash: &str) -> String {
+    hash[..16].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::{rocks::RocksDb, rocks_config::RocksDbConfig};
+
+    #[test]
+    fn test_api_key_limits() {
+        let db = RocksDb::open("api_keys_db", &RocksDbConfig::default()).unwrap();
+        let keys = ApiKeys::new(
+            Arc::new(Store::new(Arc::new(db))),
+            ApiKeyConfig { required: true },
+        );
+        let (id, secret) = keys
+            .create("tenant".to_string(), Some(100), Some(2))
+            .unwrap();
+
+        let err = keys.admit(None, 0).unwrap_err();
+        assert_eq!(api_key_error(&err), Some(&ApiKeyError::Missing));
+        let err = keys.admit(Some("guess"), 0).unwrap_err();
+        assert_eq!(api_key_error(&err), Some(&ApiKeyError::Invalid));
+
+        keys.admit(Some(&secret), 60).unwrap();
+        let err = keys.admit(Some(&secret), 60).unwrap_err();
+        assert!(matches!(
+            api_key_error(&err),
+            Some(ApiKeyError::QuotaExceeded { used: 60, .. })
+        ));
+        keys.admit(Some(&secret), 40).unwrap();
+        let err = keys.admit(Some(&secret), 0).unwrap_err();
+        assert_eq!(api_key_error(&err), Some(&ApiKeyError::RateLimited));
+
+        let usage = keys.usage(Some(&id)).unwrap();
+        assert_eq!((usage[0].used_bytes, usage[0].requests), (100, 2));
+
+        assert!(keys.revoke(&id).unwrap());
+        let err = keys.charge(&secret, 1).unwrap_err();
+        assert_eq!(api_key_error(&err), Some(&ApiKeyError::Revoked));
+    }
+}
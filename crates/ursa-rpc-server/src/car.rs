@@ -0,0 +1,223 @@
+//! Car file streaming.
+//!
+//! Writing a car file with `fvm_ipld_car` copies every block into a pipe and out of it
+//! again before it reaches the http body. [`CarStream`] frames the blocks itself
+//! instead: the header and the length and cid prefix of each block are encoded into
+//! small buffers, and the block data read from the store is handed to the body as it
+//! is, without being copied. The blocks come from a [`BlockStream`], which reads them
+//! from the store one at a time as the body is sent, so serving a dag takes memory
+//! for the list of its cids rather than for all of its data.
+
+use anyhow::Result;
+use bytes::Bytes;
+use cid::Cid;
+use futures::{future::BoxFuture, ready, FutureExt, Stream};
+use libipld::{cbor::DagCborCodec, codec::Codec, ipld, Cid as lCid};
+use std::{
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    vec::IntoIter,
+};
+use ursa_utils::ToIpldCid;
+
+/// Reads the data of a block.
+pub type BlockLoader = Arc<dyn Fn(lCid) -> BoxFuture<'static, Result<Vec<u8>>> + Send + Sync>;
+
+/// Blocks of a dag, each read with the loader once the previous one was taken.
+pub struct BlockStream {
+    /// Cids of the blocks left, with their sizes.
+    blocks: IntoIter<(lCid, usize)>,
+    load: BlockLoader,
+    loading: Option<(lCid, BoxFuture<'static, Result<Vec<u8>>>)>,
+}
+
+impl BlockStream {
+    pub fn new(blocks: Vec<(lCid, usize)>, load: BlockLoader) -> Self {
+        Self {
+            blocks: blocks.into_iter(),
+            load,
+            loading: None,
+        }
+    }
+
+    /// Blocks not yielded yet, with their sizes.
+    pub fn remaining(&self) -> &[(lCid, usize)] {
+        self.blocks.as_slice()
+    }
+
+    /// Blocks not yielded yet, the one being read included.
+    pub fn len(&self) -> usize {
+        self.blocks.len() + usize::from(self.loading.is_some())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Stream for BlockStream {
+    type Item = Result<(lCid, Vec<u8>)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.loading.is_none() {
+            let cid = match self.blocks.next() {
+                Some((cid, _)) => cid,
+                None => return Poll::Ready(None),
+            };
+            let load = (self.load)(cid);
+            self.loading = Some((cid, load));
+        }
+        let (cid, load) = self.loading.as_mut().expect("a block is being read");
+        let cid = *cid;
+        let data = ready!(load.poll_unpin(cx));
+        self.loading = None;
+        Poll::Ready(Some(data.map(|data| (cid, data))))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len(), Some(self.len()))
+    }
+}
+
+/// Car v1 file of a dag, one chunk per header, block prefix and block data.
+pub struct CarStream {
+    header: Option<Bytes>,
+    blocks: BlockStream,
+    /// Data of the block whose prefix was just yielded.
+    data: Option<Bytes>,
+    size: u64,
+}
+
+impl CarStream {
+    pub fn new(root: Cid, blocks: BlockStream) -> Result<Self> {
+        let header = ipld!({ "roots": [root.to_ipld_cid()], "version": 1 });
+        let header = DagCborCodec.encode(&header)?;
+        let mut framed = Vec::with_capacity(header.len() + 2);
+        write_varint(header.len() as u64, &mut framed);
+        framed.extend_from_slice(&header);
+
+        let mut size = framed.len() as u64;
+        let mut varint = Vec::with_capacity(10);
+        for (cid, data_len) in blocks.remaining() {
+            let len = cid.encoded_len() + data_len;
+            varint.clear();
+            write_varint(len as u64, &mut varint);
+            size += (varint.len() + len) as u64;
+        }
+        Ok(Self {
+            header: Some(framed.into()),
+            blocks,
+            data: None,
+            size,
+        })
+    }
+
+    /// Bytes of the whole car file.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl Stream for CarStream {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(header) = self.header.take() {
+            return Poll::Ready(Some(Ok(header)));
+        }
+        if let Some(data) = self.data.take() {
+            return Poll::Ready(Some(Ok(data)));
+        }
+        let (cid, data) = match ready!(Pin::new(&mut self.blocks).poll_next(cx)) {
+            Some(Ok(block)) => block,
+            Some(Err(err)) => {
+                return Poll::Ready(Some(Err(io::Error::new(io::ErrorKind::Other, err))))
+            }
+            None => return Poll::Ready(None),
+        };
+        let cid = cid.to_bytes();
+        let mut prefix = Vec::with_capacity(cid.len() + 10);
+        write_varint((cid.len() + data.len()) as u64, &mut prefix);
+        prefix.extend_from_slice(&cid);
+        self.data = Some(data.into());
+        Poll::Ready(Some(Ok(prefix.into())))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let pending = usize::from(self.header.is_some()) + usize::from(self.data.is_some());
+        let chunks = pending + 2 * self.blocks.len();
+        (chunks, Some(chunks))
+    }
+}
+
+/// Append `n` as an unsigned LEB128 varint.
+fn write_varint(mut n: u64, buf: &mut Vec<u8>) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use fvm_ipld_car::CarHeader;
+    use libipld::{multihash::Code, Block, DefaultParams};
+    use std::collections::HashMap;
+    use ursa_utils::ToCid;
+
+    #[async_std::test]
+    async fn test_car_stream() {
+        let leaf =
+            Block::<DefaultParams>::encode(DagCborCodec, Code::Blake3_256, &ipld!("leaf")).unwrap();
+        let large = vec![7u8; 300];
+        let root = Block::<DefaultParams>::encode(
+            DagCborCodec,
+            Code::Blake3_256,
+            &ipld!({ "leaf": *leaf.cid(), "data": large }),
+        )
+        .unwrap();
+        let blocks: Vec<(lCid, Vec<u8>)> = [&root, &leaf]
+            .iter()
+            .map(|block| (*block.cid(), block.data().to_vec()))
+            .collect();
+        let stored: HashMap<lCid, Vec<u8>> = blocks.iter().cloned().collect();
+        let load: BlockLoader = Arc::new(move |cid| {
+            let data = stored.get(&cid).cloned();
+            async move { data.ok_or_else(|| anyhow::anyhow!("{cid} is not stored")) }.boxed()
+        });
+        let root_cid = root.cid().to_cid();
+
+        let (tx, mut rx) = futures::channel::mpsc::unbounded();
+        for (cid, data) in &blocks {
+            tx.unbounded_send((cid.to_cid(), data.clone())).unwrap();
+        }
+        drop(tx);
+        let mut expected = vec![];
+        CarHeader {
+            roots: vec![root_cid],
+            version: 1,
+        }
+        .write_stream_async(&mut expected, &mut rx)
+        .await
+        .unwrap();
+
+        let sizes = blocks
+            .iter()
+            .map(|(cid, data)| (*cid, data.len()))
+            .collect();
+        let stream = CarStream::new(root_cid, BlockStream::new(sizes, load)).unwrap();
+        assert_eq!(stream.size_hint(), (5, Some(5)));
+        assert_eq!(stream.size(), expected.len() as u64);
+        let chunks: Vec<Bytes> = stream.map(Result::unwrap).collect().await;
+        assert_eq!(chunks.concat(), expected);
+    }
+}
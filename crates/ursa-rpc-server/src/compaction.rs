@@ -0,0 +1,131 @@
+//! Repo compaction.
+//!
+//! Evicting content leaves its space on disk until RocksDB compacts the files it was
+//! written to, long after a big eviction on a quiet node. `ursa_repo_compact`
+//! compacts column families right away, and `database_config.compaction_hour`
+//! compacts all of them every day at an off-peak hour. A compaction is reported as
+//! an operation: the column being compacted, the columns left in `missing`, and the
+//! bytes reclaimed so far.
+
+use anyhow::{anyhow, Result};
+use async_std::task;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{info, warn};
+use ursa_store::columns::{Column, ColumnDb};
+
+use crate::{
+    error::{ApiError, ErrorCode},
+    operations::{OperationId, OperationKind, Operations},
+};
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+pub struct Compactor {
+    /// Database compacted, none when the store is not a [`ColumnDb`].
+    db: Option<ColumnDb>,
+    operations: Arc<Operations>,
+    /// Whether a compaction is running, only one runs at a time.
+    running: AtomicBool,
+}
+
+impl Compactor {
+    pub fn new(db: Option<ColumnDb>, operations: Arc<Operations>) -> Self {
+        Self {
+            db,
+            operations,
+            running: AtomicBool::new(false),
+        }
+    }
+
+    /// Compact `columns` in the background, returning the id of the operation the
+    /// progress is reported under.
+    pub fn start(self: &Arc<Self>, columns: Vec<Column>) -> Result<OperationId> {
+        let db = self.db.clone().ok_or_else(|| {
+            anyhow!(ApiError::new(
+                ErrorCode::Unavailable,
+                "The store of this node cannot be compacted"
+            ))
+        })?;
+        if self.running.swap(true, Ordering::SeqCst) {
+            return Err(anyhow!(ApiError::new(
+                ErrorCode::Unavailable,
+                "A compaction is running already"
+            )));
+        }
+
+        let id = self.operations.start(OperationKind::Compact, None);
+        let compactor = Arc::clone(self);
+        task::spawn_blocking(move || {
+            let result = compactor.compact(&db, id, &columns);
+            compactor.operations.finish(id, &result);
+            compactor.running.store(false, Ordering::SeqCst);
+        });
+        Ok(id)
+    }
+
+    fn compact(&self, db: &ColumnDb, id: OperationId, columns: &[Column]) -> Result<u64> {
+        let mut reclaimed = 0;
+        for (i, column) in columns.iter().enumerate() {
+            self.operations.update(id, |status| {
+                status.column = Some(column.name().to_string());
+                status.missing = Some(columns.len() - i);
+            });
+            let bytes = db.column(*column).compact()?;
+            reclaimed += bytes;
+            self.operations.update(id, |status| status.bytes += bytes);
+        }
+        self.operations.update(id, |status| {
+            status.column = None;
+            status.missing = Some(0);
+        });
+        info!("Compaction reclaimed {reclaimed} bytes");
+        Ok(reclaimed)
+    }
+
+    /// Compact every column family daily at `hour` UTC.
+    pub fn schedule(self: Arc<Self>, hour: u8) {
+        task::spawn(async move {
+            loop {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |elapsed| elapsed.as_secs());
+                task::sleep(Duration::from_secs(secs_until(hour, now))).await;
+                if let Err(e) = self.start(Column::ALL.to_vec()) {
+                    warn!("The scheduled compaction did not start: {e}");
+                }
+            }
+        });
+    }
+}
+
+/// Seconds from the unix time `now` to the next start of `hour` UTC.
+fn secs_until(hour: u8, now: u64) -> u64 {
+    let target = u64::from(hour % 24) * 60 * 60;
+    match (target + SECS_PER_DAY - now % SECS_PER_DAY) % SECS_PER_DAY {
+        0 => SECS_PER_DAY,
+        secs => secs,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secs_until() {
+        let midnight = 19_000 * SECS_PER_DAY;
+        assert_eq!(secs_until(3, midnight), 3 * 60 * 60);
+        assert_eq!(secs_until(3, midnight + 4 * 60 * 60), 23 * 60 * 60);
+        // starting right at the hour waits for the next day
+        assert_eq!(secs_until(0, midnight), SECS_PER_DAY);
+
+        let compactor = Arc::new(Compactor::new(None, Default::default()));
+        assert!(compactor.start(vec![Column::Blocks]).is_err());
+    }
+}
@@ -1,14 +1,56 @@
+use cid::multihash::Code;
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 
 #[derive(Deserialize, Serialize, Debug)]
+#[serde(default)]
 pub struct ServerConfig {
     pub port: u16,
     pub addr: String,
+    /// Time in milliseconds in-flight requests, such as car downloads, get to finish on
+    /// shutdown before they are aborted.
+    pub shutdown_grace_ms: u64,
+    /// Origin used to pull content on cache misses.
+    pub origin: OriginConfig,
+    /// Log of served content.
+    pub access_log: AccessLogConfig,
+    /// What requests do when the network command queue is full.
+    pub command_overflow: OverflowPolicy,
+    /// Limits of content pulled from urls with `ursa_put_url`.
+    pub put_url: PutUrlConfig,
+    /// Limits of raw files chunked by `ursa_put_file`.
+    pub put_file: PutFileConfig,
+    /// Pipeline car files are put through.
+    pub car_import: CarImportConfig,
+    /// Urls notified of content lifecycle events.
+    pub webhooks: WebhookConfig,
+    /// Api keys of uploads and prefetches.
+    pub api_keys: ApiKeyConfig,
+    /// Signed, expiring retrieval urls.
+    pub signed_urls: SignedUrlConfig,
+    /// Serving `/ipns/<domain>` from DNSLink records.
+    pub dnslink: DnsLinkConfig,
+    /// Signed acknowledgments of the content delivered over http.
+    pub receipts: ReceiptConfig,
+    /// Popular car files rendered to disk, for the http content routes.
+    pub render_cache: RenderCacheConfig,
+    /// Optional. Certificate the listener serves https with, plain http when unset.
+    pub tls: Option<TlsConfig>,
+    /// Optional. Separate listener for the rpc and uploads, leaving only content
+    /// retrieval on `port`. Everything is served on `port` when unset.
+    pub admin: Option<AdminConfig>,
+    /// Optional. Token the admin rpcs, like `ursa_config_set`, `ursa_remove` and the
+    /// api key rpcs, are called with, all refused when unset.
+    pub admin_token: Option<String>,
 }
 
 impl ServerConfig {
     pub fn new(port: u16, addr: String) -> Self {
-        Self { port, addr }
+        Self {
+            port,
+            addr,
+            ..Default::default()
+        }
     }
 }
 impl Default for ServerConfig {
@@ -16,6 +58,314 @@ impl Default for ServerConfig {
         Self {
             port: 4069,
             addr: "0.0.0.0".to_string(),
+            shutdown_grace_ms: 30_000,
+            origin: OriginConfig::default(),
+            access_log: AccessLogConfig::default(),
+            command_overflow: OverflowPolicy::default(),
+            put_url: PutUrlConfig::default(),
+            put_file: PutFileConfig::default(),
+            car_import: CarImportConfig::default(),
+            webhooks: WebhookConfig::default(),
+            api_keys: ApiKeyConfig::default(),
+            signed_urls: SignedUrlConfig::default(),
+            dnslink: DnsLinkConfig::default(),
+            receipts: ReceiptConfig::default(),
+            render_cache: RenderCacheConfig::default(),
+            tls: None,
+            admin: None,
+            admin_token: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TlsConfig {
+    /// Pem file of the certificate chain.
+    pub cert_path: PathBuf,
+    /// Pem file of the private key.
+    pub key_path: PathBuf,
+    /// Optional. Udp port experimental HTTP/3 is served on, with the same certificate.
+    pub http3_port: Option<u16>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct AdminConfig {
+    pub port: u16,
+    /// Address the admin listener binds, keep it on localhost unless the port is
+    /// firewalled.
+    pub addr: String,
+    /// Optional. Certificate the admin listener serves https with.
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            port: 4068,
+            addr: "127.0.0.1".to_string(),
+            tls: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct OriginConfig {
+    /// Optional. Http origin or ipfs gateway used when content is missing locally
+    /// and on the network, e.g. https://ipfs.io. Disabled by default.
+    pub ipfs_gateway: Option<String>,
+    /// Time in milliseconds to wait on bitswap before falling back to the origin.
+    pub bitswap_timeout_ms: u64,
+}
+
+impl Default for OriginConfig {
+    fn default() -> Self {
+        Self {
+            ipfs_gateway: None,
+            bitswap_timeout_ms: 10_000,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct AccessLogConfig {
+    /// Optional. File the access log is appended to as json lines.
+    pub path: Option<PathBuf>,
+    /// Size in bytes after which the log file is rotated.
+    pub max_file_size: u64,
+    /// Number of rotated log files to keep.
+    pub max_files: usize,
+    /// Number of recent entries kept in memory for the `ursa_access_log` rpc.
+    pub buffer_size: usize,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            max_file_size: 100 * 1024 * 1024,
+            max_files: 5,
+            buffer_size: 10_000,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Reject the request with a 503 as soon as the queue is full.
+    FailFast,
+    /// Wait up to `timeout_ms` milliseconds for room in the queue before rejecting.
+    Wait { timeout_ms: u64 },
+}
+
+impl Default for OverflowPolicy {
+    fn default() -> Self {
+        OverflowPolicy::Wait { timeout_ms: 5_000 }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct PutUrlConfig {
+    /// Largest download in bytes accepted by `ursa_put_url`.
+    pub max_size: u64,
+    /// Time in milliseconds a download may take.
+    pub timeout_ms: u64,
+    /// Size in bytes of the leaves downloaded files are chunked into.
+    pub chunk_size: usize,
+    /// Hash function of the cids of the chunked blocks.
+    pub hash: ChunkHash,
+    /// Hosts downloaded from even though they resolve to loopback, private or
+    /// link-local addresses, which are refused otherwise.
+    pub allowed_hosts: Vec<String>,
+}
+
+impl Default for PutUrlConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 1024 * 1024 * 1024,
+            timeout_ms: 5 * 60 * 1000,
+            chunk_size: 256 * 1024,
+            hash: ChunkHash::default(),
+            allowed_hosts: vec![],
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct PutFileConfig {
+    /// Largest raw file in bytes chunked by `ursa_put_file`.
+    pub max_size: u64,
+    /// Size in bytes of the leaves raw files are chunked into.
+    pub chunk_size: usize,
+    /// Hash function of the cids of the chunked blocks.
+    pub hash: ChunkHash,
+}
+
+impl Default for PutFileConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 1024 * 1024 * 1024,
+            chunk_size: 256 * 1024,
+            hash: ChunkHash::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct CarImportConfig {
+    /// Tasks checking and writing batches of blocks while the car file is decoded.
+    pub workers: usize,
+    /// Blocks written to the store at once.
+    pub batch_size: usize,
+    /// Bytes of block data a single car file may hold.
+    pub max_bytes: u64,
+    /// Blocks a single car file may hold.
+    pub max_blocks: usize,
+    /// Largest block in bytes, larger ones cannot be served over bitswap.
+    pub max_block_size: usize,
+}
+
+impl Default for CarImportConfig {
+    fn default() -> Self {
+        Self {
+            workers: 4,
+            batch_size: 256,
+            max_bytes: 1024 * 1024 * 1024,
+            max_blocks: 1_000_000,
+            max_block_size: 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkHash {
+    /// The hash ipfs chunks files with, so both produce the same cids.
+    #[serde(rename = "sha2-256")]
+    Sha2_256,
+    #[serde(rename = "blake3")]
+    Blake3,
+}
+
+impl Default for ChunkHash {
+    fn default() -> Self {
+        ChunkHash::Sha2_256
+    }
+}
+
+impl ChunkHash {
+    pub fn code(self) -> Code {
+        match self {
+            ChunkHash::Sha2_256 => Code::Sha2_256,
+            ChunkHash::Blake3 => Code::Blake3_256,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct WebhookConfig {
+    /// Urls every event is POSTed to. Webhooks are disabled when empty.
+    pub urls: Vec<String>,
+    /// Optional. Key the bodies are signed with in the `x-ursa-signature` header.
+    pub secret: Option<String>,
+    /// Requests of a cid after which a `requested` event is sent, zero disables it.
+    pub request_threshold: u64,
+    /// Cids whose requests are counted, the least recently requested is forgotten past it.
+    pub max_tracked: usize,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            urls: vec![],
+            secret: None,
+            request_threshold: 1000,
+            max_tracked: 100_000,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ApiKeyConfig {
+    /// Reject uploads and prefetches without an api key. Requests carrying a key are
+    /// held to its limits either way.
+    pub required: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct SignedUrlConfig {
+    /// Optional. Key retrieval urls are signed with, signing is disabled when unset.
+    pub secret: Option<String>,
+    /// Only serve content at signed urls, for private gateways.
+    pub required: bool,
+    /// Seconds signed urls are valid for unless asked otherwise.
+    pub default_ttl_secs: u64,
+}
+
+impl Default for SignedUrlConfig {
+    fn default() -> Self {
+        Self {
+            secret: None,
+            required: false,
+            default_ttl_secs: 60 * 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct DnsLinkConfig {
+    /// Resolve `/ipns/<domain>` with the dns servers of the system.
+    pub enabled: bool,
+    /// Seconds a dnslink answer is cached at most, even when its ttl is longer.
+    pub max_ttl_secs: u64,
+}
+
+impl Default for DnsLinkConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_ttl_secs: 60 * 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ReceiptConfig {
+    /// Optional. File accepted receipts are appended to as json lines.
+    pub path: Option<PathBuf>,
+    /// Refuse http retrievals that do not name a client key.
+    pub require: bool,
+    /// Unacknowledged deliveries after which a client is refused, 0 for no limit.
+    pub max_unacknowledged: usize,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct RenderCacheConfig {
+    /// Optional. Directory the car files are rendered to, no caching when unset.
+    pub path: Option<PathBuf>,
+    /// Size in bytes the cached files are kept under.
+    pub max_bytes: u64,
+    /// Requests of a root after which its car file is cached.
+    pub min_requests: u32,
+}
+
+impl Default for RenderCacheConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            max_bytes: 10 * 1024 * 1024 * 1024,
+            min_requests: 2,
         }
     }
 }
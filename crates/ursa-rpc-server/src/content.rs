@@ -0,0 +1,359 @@
+//! Inventory of the content stored on the node.
+//!
+//! Every root cid put, prefetched or fetched for a retrieval is listed with the size
+//! of its dag, served by `ursa_list_content`. Content put or prefetched by the
+//! operator is pinned, content fetched on demand for a retrieval is only cached.
+//! Each entry is kept in the node database under a key of its own, next to the list
+//! of the listed cids, so a change only writes the entry it is about. Access times
+//! are tracked in memory and only written once they are more than a minute behind.
+
+use anyhow::Result;
+use cid::Cid;
+use ipld_blockstore::BlockStore;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex},
+};
+use ursa_store::{columns::Column, Store};
+use ursa_utils::{hex, unix_now};
+
+/// Database key of the listed root cids.
+const CONTENT_IDS_KEY: &str = "content_ids";
+
+/// Database key all entries were kept under before they got a key each.
+const LEGACY_CONTENT_KEY: &str = "content";
+
+/// Seconds an access time may lag behind in the database.
+const ACCESS_FLUSH_SECS: u64 = 60;
+
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContentEntry {
+    /// Root cid of the dag.
+    pub cid: String,
+    /// Bytes of the stored blocks of the dag.
+    pub size: u64,
+    /// Whether the content was put or prefetched, rather than cached for a retrieval.
+    pub pinned: bool,
+    /// Unix time in seconds the content was stored at.
+    pub added_at: u64,
+    /// Unix time in seconds of the last retrieval.
+    pub last_access: Option<u64>,
+    /// Context id the content is advertised under, the root cid unless grouped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContentFilter {
+    pub pinned: Option<bool>,
+    pub context_id: Option<String>,
+    /// Only content retrieved at or after this unix time in seconds.
+    pub accessed_since: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContentPage {
+    pub items: Vec<ContentEntry>,
+    /// Cursor of the next page, unset on the last one.
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Default)]
+struct ContentState {
+    entries: BTreeMap<String, ContentEntry>,
+    /// Entries whose access time was not written yet.
+    touched: BTreeSet<String>,
+    /// Unix time in seconds the access times were last written at.
+    flushed_at: u64,
+}
+
+pub struct ContentIndex<S>
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    store: Arc<Store<S>>,
+    state: Mutex<ContentState>,
+}
+
+impl<S> ContentIndex<S>
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    /// An empty listing.
+    pub fn new(store: Arc<Store<S>>) -> Self {
+        Self {
+            store,
+            state: Default::default(),
+        }
+    }
+
+    /// Load the entries stored in `store`.
+    pub fn load(store: Arc<Store<S>>) -> Result<Self> {
+        let pins = store.column(Column::Pins);
+        let mut entries = BTreeMap::new();
+        if let Some(legacy) = pins.read(LEGACY_CONTENT_KEY)? {
+            let legacy: Vec<ContentEntry> = serde_json::from_slice(&legacy)?;
+            entries.extend(legacy.into_iter().map(|entry| (entry.cid.clone(), entry)));
+        }
+        let ids: Vec<String> = match pins.read(CONTENT_IDS_KEY)? {
+            Some(ids) => serde_json::from_slice(&ids)?,
+            None => vec![],
+        };
+        for id in ids {
+            if let Some(entry) = pins.read(entry_key(&id))? {
+                entries.insert(id, serde_json::from_slice(&entry)?);
+            }
+        }
+
+        let index = Self {
+            store,
+            state: Mutex::new(ContentState {
+                entries,
+                touched: BTreeSet::new(),
+                flushed_at: unix_now(),
+            }),
+        };
+        if pins.exists(LEGACY_CONTENT_KEY)? {
+            let state = index.state.lock().unwrap();
+            let mut values = vec![(CONTENT_IDS_KEY.as_bytes().to_vec(), ids_value(&state)?)];
+            for entry in state.entries.values() {
+                values.push((
+                    entry_key(&entry.cid).into_bytes(),
+                    serde_json::to_vec(entry)?,
+                ));
+            }
+            pins.bulk_write(&values)?;
+            pins.delete(LEGACY_CONTENT_KEY)?;
+        }
+        Ok(index)
+    }
+
+    /// List the dag under `root` with `size` bytes. Pinned content stays pinned when
+    /// it is stored again as a cache.
+    pub fn record(&self, root: Cid, size: u64, pinned: bool) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let id = root.to_string();
+        let listed = state.entries.contains_key(&id);
+        let entry = state
+            .entries
+            .entry(id.clone())
+            .or_insert_with(|| ContentEntry {
+                cid: id.clone(),
+                size,
+                pinned,
+                added_at: unix_now(),
+                last_access: None,
+                context_id: None,
+            });
+        entry.size = size;
+        entry.pinned |= pinned;
+        let mut values = vec![(entry_key(&id).into_bytes(), serde_json::to_vec(entry)?)];
+        if !listed {
+            values.push((CONTENT_IDS_KEY.as_bytes().to_vec(), ids_value(&state)?));
+        }
+        state.touched.remove(&id);
+        self.store.column(Column::Pins).bulk_write(&values)?;
+        Ok(())
+    }
+
+    /// Drop `root` from the listing, returning whether it was listed.
+    pub fn remove(&self, root: &Cid) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        let id = root.to_string();
+        if state.entries.remove(&id).is_none() {
+            return Ok(false);
+        }
+        state.touched.remove(&id);
+        let pins = self.store.column(Column::Pins);
+        pins.write(CONTENT_IDS_KEY, ids_value(&state)?)?;
+        pins.delete(entry_key(&id))?;
+        Ok(true)
+    }
+
+    /// Note a retrieval of `root`.
+    pub fn touch(&self, root: &Cid) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let now = unix_now();
+        let id = root.to_string();
+        match state.entries.get_mut(&id) {
+            Some(entry) => entry.last_access = Some(now),
+            None => return Ok(()),
+        }
+        state.touched.insert(id);
+        if now.saturating_sub(state.flushed_at) >= ACCESS_FLUSH_SECS {
+            self.flush(&mut state)?;
+        }
+        Ok(())
+    }
+
+    /// Entries after `cursor` in cid order, matching the pinned and access filters.
+    pub fn list(&self, cursor: Option<&str>, filter: &ContentFilter) -> Vec<ContentEntry> {
+        let state = self.state.lock().unwrap();
+        state
+            .entries
+            .values()
+            .filter(|entry| cursor.map_or(true, |cursor| entry.cid.as_str() > cursor))
+            .filter(|entry| filter.pinned.map_or(true, |pinned| entry.pinned == pinned))
+            .filter(|entry| {
+                filter.accessed_since.map_or(true, |since| {
+                    entry.last_access.map_or(false, |access| access >= since)
+                })
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Write the entries whose access time changed.
+    fn flush(&self, state: &mut ContentState) -> Result<()> {
+        let mut values = vec![];
+        for id in &state.touched {
+            if let Some(entry) = state.entries.get(id) {
+                values.push((entry_key(id).into_bytes(), serde_json::to_vec(entry)?));
+            }
+        }
+        self.store.column(Column::Pins).bulk_write(&values)?;
+        state.touched.clear();
+        state.flushed_at = unix_now();
+        Ok(())
+    }
+}
+
+/// Database key of the entry of the root cid `id`.
+fn entry_key(id: &str) -> String {
+    format!("content/{id}")
+}
+
+fn ids_value(state: &ContentState) -> Result<Vec<u8>> {
+    let ids: Vec<&String> = state.entries.keys().collect();
+    Ok(serde_json::to_vec(&ids)?)
+}
+
+/// First `limit` of `entries`, with the cursor of the rest when there is any.
+pub fn paginate(mut entries: Vec<ContentEntry>, limit: usize) -> ContentPage {
+    let limit = limit.clamp(1, MAX_PAGE_SIZE);
+    let next_cursor = if entries.len() > limit {
+        entries.truncate(limit);
+        entries.last().map(|entry| entry.cid.clone())
+    } else {
+        None
+    };
+    ContentPage {
+        items: entries,
+        next_cursor,
+    }
+}
+
+/// Readable form of a context id, the cid it is made of or its hex.
+pub fn context_string(context_id: &[u8]) -> String {
+    match Cid::try_from(context_id) {
+        Ok(cid) => cid.to_string(),
+        Err(_) => hex(context_id),
+    }
+}
+
+/// Context id printed by [`context_string`], a cid or hex bytes.
+pub fn context_bytes(context_id: &str) -> Option<Vec<u8>> {
+    if let Ok(cid) = Cid::try_from(context_id) {
+        return Some(cid.to_bytes());
+    }
+    if context_id.is_empty() || context_id.len() % 2 != 0 {
+        return None;
+    }
+    (0..context_id.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(context_id.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cid::multihash::{Code, MultihashDigest};
+    use db::{rocks::RocksDb, rocks_config::RocksDbConfig};
+
+    #[test]
+    fn test_content_listing() {
+        let db = RocksDb::open("content_db", &RocksDbConfig::default()).unwrap();
+        let store = Arc::new(Store::new(Arc::new(db)));
+        let index = ContentIndex::load(Arc::clone(&store)).unwrap();
+
+        let mut cids: Vec<Cid> = [&b"a"[..], b"b", b"c"]
+            .iter()
+            .map(|data| Cid::new_v1(0x55, Code::Sha2_256.digest(data)))
+            .collect();
+        cids.sort_by_key(Cid::to_string);
+        for cid in &cids {
+            index.record(*cid, 10, false).unwrap();
+        }
+        // pinned content stays pinned when cached again
+        index.record(cids[1], 20, true).unwrap();
+        index.record(cids[1], 20, false).unwrap();
+        index.touch(&cids[0]).unwrap();
+
+        let first = paginate(index.list(None, &ContentFilter::default()), 2);
+        assert_eq!(first.items.len(), 2);
+        let rest = paginate(
+            index.list(first.next_cursor.as_deref(), &ContentFilter::default()),
+            2,
+        );
+        assert_eq!((rest.items.len(), rest.next_cursor), (1, None));
+
+        let pinned = ContentFilter {
+            pinned: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(index.list(None, &pinned)[0].cid, cids[1].to_string());
+        let accessed = ContentFilter {
+            accessed_since: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(index.list(None, &accessed)[0].cid, cids[0].to_string());
+
+        assert!(index.remove(&cids[2]).unwrap());
+        // entries survive a restart
+        let index = ContentIndex::load(Arc::clone(&store)).unwrap();
+        assert_eq!(index.list(None, &ContentFilter::default()).len(), 2);
+        assert!(!store
+            .column(Column::Pins)
+            .exists(entry_key(&cids[2].to_string()))
+            .unwrap());
+
+        // entries kept under a single key get a key each
+        let legacy = index.list(None, &ContentFilter::default());
+        for entry in &legacy {
+            assert!(index
+                .remove(&Cid::try_from(entry.cid.as_str()).unwrap())
+                .unwrap());
+        }
+        store
+            .column(Column::Pins)
+            .write(LEGACY_CONTENT_KEY, serde_json::to_vec(&legacy).unwrap())
+            .unwrap();
+        let index = ContentIndex::load(Arc::clone(&store)).unwrap();
+        assert_eq!(index.list(None, &ContentFilter::default()), legacy);
+        assert!(!store
+            .column(Column::Pins)
+            .exists(LEGACY_CONTENT_KEY)
+            .unwrap());
+        let index = ContentIndex::load(Arc::clone(&store)).unwrap();
+        assert_eq!(index.list(None, &ContentFilter::default()), legacy);
+        for entry in &legacy {
+            index
+                .remove(&Cid::try_from(entry.cid.as_str()).unwrap())
+                .unwrap();
+        }
+
+        assert_eq!(context_string(&cids[0].to_bytes()), cids[0].to_string());
+        assert_eq!(
+            context_bytes(&cids[0].to_string()),
+            Some(cids[0].to_bytes())
+        );
+        assert_eq!(context_string(b"videos"), "766964656f73");
+        assert_eq!(context_bytes("766964656f73"), Some(b"videos".to_vec()));
+        assert_eq!(context_bytes("videos"), None);
+    }
+}
@@ -0,0 +1,502 @@
+//! Node self-check.
+//!
+//! Most misconfigurations do not fail anything at startup: a node behind a NAT,
+//! cut off from its bootstrap nodes or unable to reach its indexers runs along, only
+//! never serving anything. `ursa doctor`, or `ursa_diagnostics`, runs a [`Check`] of
+//! each of the listen ports, the NAT status, the bootstrap connections, the disk
+//! space and health of the store, the clock and the indexers, and answers with a
+//! [`DiagnosticsReport`] of them. The node also runs the checks once after startup
+//! and logs those that did not pass.
+//!
+//! The clock is compared to the `Date` header of the indexer answers, it is not
+//! checked without an indexer.
+
+use anyhow::{anyhow, Result};
+use async_std::{future, task};
+use ipld_blockstore::BlockStore;
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Arc,
+    time::Duration,
+};
+use ursa_index_provider::announce::AnnounceStatus;
+use ursa_network::info::{NatInfo, NodeInfo};
+use ursa_store::{columns::Column, Store};
+use ursa_utils::unix_now;
+
+/// Seconds an indexer may take to answer.
+const REQUEST_TIMEOUT_SECS: u64 = 10;
+/// Free disk space under which the store fails to grow.
+const MIN_FREE_BYTES: u64 = 1 << 30;
+/// Share of the disk left free under which the space is reported low.
+const LOW_FREE_PERCENT: u64 = 10;
+/// Clock skews over which signed urls and receipts start expiring early or late.
+const CLOCK_SKEW_WARN_SECS: u64 = 10;
+const CLOCK_SKEW_FAIL_SECS: u64 = 60;
+/// Metadata key the store check writes and removes.
+const PROBE_KEY: &str = "diagnostics/probe";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Pass,
+    /// Works, but likely not as intended.
+    Warn,
+    Fail,
+    /// Does not apply to the config of the node.
+    Skip,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Check {
+    pub name: String,
+    pub status: CheckStatus,
+    pub message: String,
+}
+
+impl Check {
+    fn new(name: &str, status: CheckStatus, message: impl Into<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiagnosticsReport {
+    /// Whether none of the checks failed.
+    pub ok: bool,
+    pub checks: Vec<Check>,
+}
+
+impl DiagnosticsReport {
+    pub fn new(checks: Vec<Check>) -> Self {
+        Self {
+            ok: checks.iter().all(|check| check.status != CheckStatus::Fail),
+            checks,
+        }
+    }
+
+    /// Checks that failed or warned.
+    pub fn problems(&self) -> impl Iterator<Item = &Check> {
+        self.checks
+            .iter()
+            .filter(|check| matches!(check.status, CheckStatus::Warn | CheckStatus::Fail))
+    }
+}
+
+/// What the checks are run against, taken from the config.
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics {
+    pub swarm_addrs: Vec<Multiaddr>,
+    pub bootstrap_nodes: Vec<Multiaddr>,
+    /// Bootstrap nodes do not dial the other bootstrap nodes.
+    pub bootstrapper: bool,
+    pub database_path: PathBuf,
+    pub indexer_urls: Vec<String>,
+}
+
+impl Diagnostics {
+    /// Run every check, from the info of the node, its connected peers and the
+    /// announcements to the indexers.
+    pub async fn run<S>(
+        &self,
+        store: &Arc<Store<S>>,
+        info: &NodeInfo,
+        peers: &HashSet<PeerId>,
+        announces: Option<&AnnounceStatus>,
+    ) -> DiagnosticsReport
+    where
+        S: BlockStore + Sync + Send + 'static,
+    {
+        let mut checks = vec![
+            check_ports(&self.swarm_addrs, info),
+            check_nat(info),
+            self.check_bootstrap(peers),
+            check_disk(self.database_path.clone()).await,
+            check_store(store).await,
+        ];
+        let (clock, indexers) = self.check_indexers(announces).await;
+        checks.push(clock);
+        checks.push(indexers);
+        DiagnosticsReport::new(checks)
+    }
+
+    fn check_bootstrap(&self, peers: &HashSet<PeerId>) -> Check {
+        let name = "bootstrap";
+        if self.bootstrapper {
+            return Check::new(name, CheckStatus::Skip, "The node is a bootstrap node");
+        }
+        let bootstrap: Vec<PeerId> = self.bootstrap_nodes.iter().filter_map(peer_id).collect();
+        if bootstrap.is_empty() {
+            return Check::new(name, CheckStatus::Skip, "No bootstrap nodes configured");
+        }
+        let connected = bootstrap.iter().filter(|peer| peers.contains(peer)).count();
+        let message = format!(
+            "Connected to {connected} of {} bootstrap nodes, {} peers in total",
+            bootstrap.len(),
+            peers.len()
+        );
+        match connected {
+            0 => Check::new(name, CheckStatus::Fail, message),
+            _ => Check::new(name, CheckStatus::Pass, message),
+        }
+    }
+
+    /// Whether the indexers answer and their clocks agree with ours.
+    async fn check_indexers(&self, announces: Option<&AnnounceStatus>) -> (Check, Check) {
+        if self.indexer_urls.is_empty() {
+            return (
+                Check::new("clock", CheckStatus::Skip, "No indexer to compare with"),
+                Check::new("indexers", CheckStatus::Skip, "No indexers configured"),
+            );
+        }
+        let mut unreachable = vec![];
+        let mut skew: Option<u64> = None;
+        for url in &self.indexer_urls {
+            match fetch_date(url).await {
+                Ok(date) => {
+                    if let Some(date) = date {
+                        let skew_secs = unix_now().abs_diff(date);
+                        skew = Some(skew.map_or(skew_secs, |skew| skew.max(skew_secs)));
+                    }
+                }
+                Err(e) => unreachable.push(format!("{url} ({e})")),
+            }
+        }
+
+        let clock = match skew {
+            None => Check::new(
+                "clock",
+                CheckStatus::Skip,
+                "No indexer answered with a date",
+            ),
+            Some(skew) => {
+                let message = format!("The clock is {skew}s off the indexers");
+                let status = if skew > CLOCK_SKEW_FAIL_SECS {
+                    CheckStatus::Fail
+                } else if skew > CLOCK_SKEW_WARN_SECS {
+                    CheckStatus::Warn
+                } else {
+                    CheckStatus::Pass
+                };
+                Check::new("clock", status, message)
+            }
+        };
+
+        let failed: usize = announces
+            .map(|status| {
+                status
+                    .indexers
+                    .iter()
+                    .map(|indexer| indexer.failed.len())
+                    .sum()
+            })
+            .unwrap_or(0);
+        let indexers = if !unreachable.is_empty() {
+            Check::new(
+                "indexers",
+                CheckStatus::Fail,
+                format!("Cannot reach {}", unreachable.join(", ")),
+            )
+        } else if failed > 0 {
+            Check::new(
+                "indexers",
+                CheckStatus::Warn,
+                format!(
+                    "The indexers are reachable, but {failed} announcements ran out of retries"
+                ),
+            )
+        } else {
+            Check::new(
+                "indexers",
+                CheckStatus::Pass,
+                format!("Reached {} indexers", self.indexer_urls.len()),
+            )
+        };
+        (clock, indexers)
+    }
+}
+
+/// Whether the node listens on the ports of `swarm_addrs`.
+fn check_ports(swarm_addrs: &[Multiaddr], info: &NodeInfo) -> Check {
+    let name = "ports";
+    let listening: Vec<(&'static str, u16)> = info
+        .listen_addrs
+        .iter()
+        .filter_map(|addr| addr.parse().ok())
+        .filter_map(|addr| port(&addr))
+        .collect();
+    let expected: Vec<(&'static str, u16)> = swarm_addrs
+        .iter()
+        .filter_map(port)
+        // port 0 is any free port
+        .filter(|(_, port)| *port != 0)
+        .collect();
+    if expected.is_empty() {
+        return Check::new(name, CheckStatus::Skip, "No fixed swarm ports configured");
+    }
+    let missing: Vec<String> = expected
+        .iter()
+        .filter(|port| !listening.contains(port))
+        .map(|(transport, port)| format!("{transport}/{port}"))
+        .collect();
+    if missing.is_empty() {
+        Check::new(
+            name,
+            CheckStatus::Pass,
+            format!("Listening on {}", info.listen_addrs.join(", ")),
+        )
+    } else {
+        Check::new(
+            name,
+            CheckStatus::Fail,
+            format!("Not listening on {}", missing.join(", ")),
+        )
+    }
+}
+
+/// Transport and port of `addr`.
+fn port(addr: &Multiaddr) -> Option<(&'static str, u16)> {
+    addr.iter().find_map(|protocol| match protocol {
+        Protocol::Tcp(port) => Some(("tcp", port)),
+        Protocol::Udp(port) => Some(("udp", port)),
+        _ => None,
+    })
+}
+
+fn peer_id(addr: &Multiaddr) -> Option<PeerId> {
+    addr.iter().find_map(|protocol| match protocol {
+        Protocol::P2p(hash) => PeerId::from_multihash(hash).ok(),
+        _ => None,
+    })
+}
+
+/// Whether the other peers can dial the node, directly or through a relay.
+fn check_nat(info: &NodeInfo) -> Check {
+    let name = "nat";
+    match &info.nat {
+        NatInfo::Disabled => Check::new(name, CheckStatus::Skip, "AutoNAT is disabled"),
+        NatInfo::Unknown => Check::new(
+            name,
+            CheckStatus::Warn,
+            "AutoNAT has not determined the NAT status yet",
+        ),
+        NatInfo::Public { address } => Check::new(
+            name,
+            CheckStatus::Pass,
+            format!("Publicly reachable on {address}"),
+        ),
+        NatInfo::Private if !info.relay.relayed_addrs.is_empty() => Check::new(
+            name,
+            CheckStatus::Warn,
+            format!(
+                "Behind a NAT, reachable through {}",
+                info.relay.relayed_addrs.join(", ")
+            ),
+        ),
+        NatInfo::Private => Check::new(
+            name,
+            CheckStatus::Fail,
+            "Behind a NAT and not listening through a relay, forward the swarm ports or enable relay_client",
+        ),
+    }
+}
+
+/// Free space of the disk the database is on.
+async fn check_disk(path: PathBuf) -> Check {
+    let name = "disk";
+    match task::spawn_blocking(move || disk_space(&path)).await {
+        Ok((total, available)) => {
+            let message = format!("{} MiB free of {} MiB", available >> 20, total >> 20);
+            let status = if available < MIN_FREE_BYTES {
+                CheckStatus::Fail
+            } else if available.saturating_mul(100) < total.saturating_mul(LOW_FREE_PERCENT) {
+                CheckStatus::Warn
+            } else {
+                CheckStatus::Pass
+            };
+            Check::new(name, status, message)
+        }
+        Err(e) => Check::new(
+            name,
+            CheckStatus::Skip,
+            format!("Cannot tell the free disk space: {e}"),
+        ),
+    }
+}
+
+/// Total and available bytes of the file system holding `path`, as reported by `df`.
+fn disk_space(path: &Path) -> Result<(u64, u64)> {
+    let output = Command::new("df").arg("-Pk").arg(path).output()?;
+    if !output.status.success() {
+        return Err(anyhow!(String::from_utf8_lossy(&output.stderr)
+            .trim()
+            .to_string()));
+    }
+    parse_df(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| anyhow!("Unexpected output of df"))
+}
+
+/// Total and available bytes of the POSIX output of `df -Pk`.
+fn parse_df(output: &str) -> Option<(u64, u64)> {
+    let fields: Vec<&str> = output.lines().nth(1)?.split_whitespace().collect();
+    let total: u64 = fields.get(1)?.parse().ok()?;
+    let available: u64 = fields.get(3)?.parse().ok()?;
+    Some((total * 1024, available * 1024))
+}
+
+/// Whether the store takes a write and reads it back.
+async fn check_store<S>(store: &Arc<Store<S>>) -> Check
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    let name = "store";
+    let probe = store
+        .blocking(|store| -> Result<Option<Vec<u8>>> {
+            let metadata = store.column(Column::Metadata);
+            metadata.write(PROBE_KEY, b"probe")?;
+            let read = metadata.read(PROBE_KEY)?;
+            metadata.delete(PROBE_KEY)?;
+            Ok(read)
+        })
+        .await
+        .and_then(|probe| probe);
+    match probe {
+        Ok(Some(value)) if value == b"probe" => {
+            Check::new(name, CheckStatus::Pass, "The store is writable")
+        }
+        Ok(_) => Check::new(
+            name,
+            CheckStatus::Fail,
+            "The store did not read back what was written",
+        ),
+        Err(e) => Check::new(
+            name,
+            CheckStatus::Fail,
+            format!("Cannot write to the store: {e}"),
+        ),
+    }
+}
+
+/// `Date` of the answer of the indexer at `url`, any answer meaning it is reachable.
+async fn fetch_date(url: &str) -> Result<Option<u64>> {
+    let res = future::timeout(
+        Duration::from_secs(REQUEST_TIMEOUT_SECS),
+        surf::get(url.trim_end_matches('/')),
+    )
+    .await
+    .map_err(|_| anyhow!("timed out"))?
+    .map_err(|e| anyhow!(e.to_string()))?;
+    Ok(res
+        .header("Date")
+        .and_then(|date| parse_http_date(date.last().as_str())))
+}
+
+/// Unix seconds of an RFC 7231 date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn parse_http_date(date: &str) -> Option<u64> {
+    let fields: Vec<&str> = date.split_whitespace().collect();
+    if fields.len() != 6 || fields[5] != "GMT" {
+        return None;
+    }
+    let day: u64 = fields[1].parse().ok()?;
+    let month = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ]
+    .iter()
+    .position(|month| *month == fields[2])? as u64
+        + 1;
+    let year: u64 = fields[3].parse().ok()?;
+    let time: Vec<u64> = fields[4]
+        .split(':')
+        .map(|part| part.parse().ok())
+        .collect::<Option<_>>()?;
+    if time.len() != 3 || year < 1970 || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // days since the epoch of the civil date, March being the first month of a year
+    let (year, month) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    Some(days * 86_400 + time[0] * 3600 + time[1] * 60 + time[2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ursa_network::info::RelayInfo;
+
+    #[test]
+    fn test_checks() {
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(784_111_777)
+        );
+        assert_eq!(
+            parse_http_date("Tue, 29 Feb 2000 12:00:00 GMT"),
+            Some(951_825_600)
+        );
+        assert_eq!(parse_http_date("yesterday"), None);
+
+        let df = "Filesystem 1024-blocks Used Available Capacity Mounted on\n\
+                  /dev/sda1 1000 900 100 90% /\n";
+        assert_eq!(parse_df(df), Some((1000 * 1024, 100 * 1024)));
+
+        let mut info = NodeInfo {
+            peer_id: PeerId::random().to_string(),
+            agent_version: "ursa/test".to_string(),
+            listen_addrs: vec!["/ip4/127.0.0.1/tcp/6009".to_string()],
+            observed_addrs: vec![],
+            nat: NatInfo::Private,
+            relay: RelayInfo::default(),
+            protocols: vec![],
+            features: vec![],
+        };
+        let swarm_addrs: Vec<Multiaddr> = vec!["/ip4/0.0.0.0/tcp/6009".parse().unwrap()];
+        assert_eq!(check_ports(&swarm_addrs, &info).status, CheckStatus::Pass);
+        let quic: Vec<Multiaddr> = vec!["/ip4/0.0.0.0/udp/4890/quic".parse().unwrap()];
+        assert_eq!(check_ports(&quic, &info).status, CheckStatus::Fail);
+
+        assert_eq!(check_nat(&info).status, CheckStatus::Fail);
+        info.relay.relayed_addrs = vec!["/ip4/1.2.3.4/tcp/6009/p2p-circuit".to_string()];
+        assert_eq!(check_nat(&info).status, CheckStatus::Warn);
+
+        let bootstrap = PeerId::random();
+        let diagnostics = Diagnostics {
+            bootstrap_nodes: vec![format!("/ip4/1.2.3.4/tcp/6009/p2p/{bootstrap}")
+                .parse()
+                .unwrap()],
+            ..Default::default()
+        };
+        let mut peers = HashSet::from([PeerId::random()]);
+        assert_eq!(
+            diagnostics.check_bootstrap(&peers).status,
+            CheckStatus::Fail
+        );
+        peers.insert(bootstrap);
+        assert_eq!(
+            diagnostics.check_bootstrap(&peers).status,
+            CheckStatus::Pass
+        );
+
+        let report =
+            DiagnosticsReport::new(vec![check_nat(&info), diagnostics.check_bootstrap(&peers)]);
+        assert!(report.ok);
+        assert_eq!(report.problems().count(), 1);
+    }
+}
@@ -0,0 +1,252 @@
+//! UnixFS directory listings.
+//!
+//! `/ipfs/<cid>/<path>` resolving to a UnixFS directory answers with a listing of its
+//! entries, as html for browsers or as json when the request accepts
+//! `application/json`. Sizes are the cumulative sizes of the linked dags, the links
+//! carry them, so listing a directory reads only its own block. Sharded directories
+//! are not supported.
+
+use anyhow::{anyhow, Result};
+use cid::Cid;
+use ipld_blockstore::BlockStore;
+use libipld::{store::DefaultParams, Block, Ipld, IpldCodec};
+use serde::{Deserialize, Serialize};
+use ursa_store::Store;
+use ursa_utils::ToIpldCid;
+
+use crate::{error::ApiError, unixfs::get_varint};
+
+const DAG_PB: u64 = 0x70;
+
+/// UnixFS `Data.Type` of a directory, and of a sharded one.
+const UNIXFS_DIRECTORY: u64 = 1;
+const UNIXFS_HAMT_SHARD: u64 = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirEntry {
+    pub name: String,
+    pub cid: String,
+    /// Size of the dag of the entry.
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Listing {
+    /// Root cid of the url.
+    pub root: String,
+    /// Path of the directory under the root, without leading or trailing slashes.
+    pub path: String,
+    /// Cid of the directory.
+    pub cid: String,
+    pub entries: Vec<DirEntry>,
+}
+
+/// Entries of the directory `cid`, or `None` when the block is not a directory.
+pub fn list<S>(store: &Store<S>, cid: Cid) -> Result<Option<Vec<DirEntry>>>
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    let cid = cid.to_ipld_cid();
+    if cid.codec() != DAG_PB {
+        return Ok(None);
+    }
+    let data = store.blockstore().read(cid.to_bytes())?.ok_or_else(|| {
+        anyhow!(ApiError::not_found(format!(
+            "The block {cid} is not stored on this node"
+        )))
+    })?;
+    let node = match Block::<DefaultParams>::new(cid, data)?.decode::<IpldCodec, Ipld>()? {
+        Ipld::Map(node) => node,
+        _ => return Ok(None),
+    };
+    match node.get("Data") {
+        Some(Ipld::Bytes(data)) => match data_type(data) {
+            Some(UNIXFS_DIRECTORY) => {}
+            Some(UNIXFS_HAMT_SHARD) => {
+                return Err(anyhow!(ApiError::invalid_params(format!(
+                    "{cid} is a sharded directory, which cannot be listed"
+                ))))
+            }
+            _ => return Ok(None),
+        },
+        _ => return Ok(None),
+    }
+
+    let links = match node.get("Links") {
+        Some(Ipld::List(links)) => links.as_slice(),
+        _ => &[],
+    };
+    let mut entries: Vec<DirEntry> = links
+        .iter()
+        .filter_map(|link| match link {
+            Ipld::Map(link) => match (link.get("Name"), link.get("Hash")) {
+                (Some(Ipld::String(name)), Some(Ipld::Link(cid))) => Some(DirEntry {
+                    name: name.clone(),
+                    cid: cid.to_string(),
+                    size: match link.get("Tsize") {
+                        Some(Ipld::Integer(size)) => *size as u64,
+                        _ => 0,
+                    },
+                }),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(Some(entries))
+}
+
+/// `Data.Type` of the protobuf encoded UnixFS `data`.
+fn data_type(data: &[u8]) -> Option<u64> {
+    let mut data = data;
+    while !data.is_empty() {
+        let key = get_varint(&mut data)?;
+        match key & 7 {
+            0 => {
+                let value = get_varint(&mut data)?;
+                if key >> 3 == 1 {
+                    return Some(value);
+                }
+            }
+            2 => {
+                let len = get_varint(&mut data)? as usize;
+                data = data.get(len..)?;
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+
+impl Listing {
+    /// The listing as an html page linking to the entries.
+    pub fn to_html(&self) -> String {
+        let base = if self.path.is_empty() {
+            format!("/ipfs/{}", self.root)
+        } else {
+            format!("/ipfs/{}/{}", self.root, encode_path(&self.path))
+        };
+        let title = escape(&format!("/ipfs/{}/{}", self.root, self.path));
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{title}</title></head>\n\
+             <body>\n<h1>Index of {title}</h1>\n<p>{}</p>\n<table>\n",
+            escape(&self.cid)
+        );
+        if let Some((parent, _)) = base.rsplit_once('/').filter(|_| !self.path.is_empty()) {
+            html.push_str(&format!(
+                "<tr><td><a href=\"{}/\">..</a></td><td></td><td></td></tr>\n",
+                escape(parent)
+            ));
+        }
+        for entry in &self.entries {
+            html.push_str(&format!(
+                "<tr><td><a href=\"{}/{}\">{}</a></td><td>{}</td><td>{}</td></tr>\n",
+                escape(&base),
+                escape(&encode_path(&entry.name)),
+                escape(&entry.name),
+                human_size(entry.size),
+                escape(&entry.cid)
+            ));
+        }
+        html.push_str("</table>\n</body>\n</html>\n");
+        html
+    }
+}
+
+/// Percent encode `path` for a url, keeping the slashes.
+fn encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::{rocks::RocksDb, rocks_config::RocksDbConfig};
+    use libipld::{ipld, multihash::Code, pb::DagPbCodec};
+    use std::{collections::BTreeMap, sync::Arc};
+    use ursa_utils::ToCid;
+
+    #[test]
+    fn test_list() {
+        let db = RocksDb::open("directory_db", &RocksDbConfig::default()).unwrap();
+        let store = Store::new(Arc::new(db));
+
+        let file = Block::<DefaultParams>::encode(
+            DagPbCodec,
+            Code::Sha2_256,
+            &ipld!({ "Data": Ipld::Bytes(vec![0x08, 0x02]), "Links": [] }),
+        )
+        .unwrap();
+        let link = |name: &str| {
+            let mut link = BTreeMap::new();
+            link.insert("Hash".to_string(), Ipld::Link(*file.cid()));
+            link.insert("Name".to_string(), Ipld::String(name.to_string()));
+            link.insert("Tsize".to_string(), Ipld::Integer(2048));
+            Ipld::Map(link)
+        };
+        let dir = Block::<DefaultParams>::encode(
+            DagPbCodec,
+            Code::Sha2_256,
+            &ipld!({
+                "Data": Ipld::Bytes(vec![0x08, 0x01]),
+                "Links": [link("b <i>.txt"), link("a.txt")],
+            }),
+        )
+        .unwrap();
+        for block in [&file, &dir] {
+            store
+                .blockstore()
+                .write(block.cid().to_bytes(), block.data())
+                .unwrap();
+        }
+
+        assert_eq!(list(&store, file.cid().to_cid()).unwrap(), None);
+        let entries = list(&store, dir.cid().to_cid()).unwrap().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "a.txt");
+        assert_eq!(entries[1].size, 2048);
+
+        let listing = Listing {
+            root: dir.cid().to_string(),
+            path: "docs".to_string(),
+            cid: dir.cid().to_string(),
+            entries,
+        };
+        let html = listing.to_html();
+        assert!(html.contains("2.0 KiB"));
+        assert!(html.contains("/docs/b%20%3Ci%3E.txt\">b &lt;i&gt;.txt</a>"));
+    }
+}
@@ -0,0 +1,165 @@
+//! DNSLink resolution.
+//!
+//! `GET /ipns/<domain>` serves the content the `_dnslink.<domain>` TXT record points
+//! at, a record like `dnslink=/ipfs/<cid>`. Records pointing at `/ipns/<domain>` are
+//! followed, records pointing at `/ipns/<peer id>` resolve through the names of the
+//! nodes. Answers are cached for the ttl of the TXT record, capped by the config.
+
+use anyhow::{anyhow, Result};
+use cid::Cid;
+use fnv::FnvHashMap;
+use std::{
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::{debug, error};
+use trust_dns_resolver::TokioAsyncResolver;
+
+use crate::{config::DnsLinkConfig, error::ApiError};
+
+/// Domains followed before a chain of `/ipns/<domain>` records is given up on.
+const MAX_DEPTH: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsLinkTarget {
+    /// `/ipfs/<cid>`
+    Cid(Cid),
+    /// `/ipns/<name>`, a domain or the peer id of a publisher.
+    Name(String),
+}
+
+/// Target of a `dnslink=` TXT value.
+pub fn parse_dnslink(txt: &str) -> Option<DnsLinkTarget> {
+    let value = txt.trim().strip_prefix("dnslink=")?;
+    let mut segments = value.split('/').filter(|s| !s.is_empty());
+    match (segments.next()?, segments.next()?) {
+        ("ipfs", cid) => Cid::from_str(cid).ok().map(DnsLinkTarget::Cid),
+        ("ipns", name) => Some(DnsLinkTarget::Name(name.to_string())),
+        _ => None,
+    }
+}
+
+pub struct DnsLink {
+    config: DnsLinkConfig,
+    resolver: Option<TokioAsyncResolver>,
+    /// Targets of the domains looked up and when they expire.
+    cache: Mutex<FnvHashMap<String, (DnsLinkTarget, Instant)>>,
+}
+
+impl Default for DnsLink {
+    fn default() -> Self {
+        Self::new(DnsLinkConfig::default())
+    }
+}
+
+impl DnsLink {
+    pub fn new(config: DnsLinkConfig) -> Self {
+        let resolver = if config.enabled {
+            TokioAsyncResolver::tokio_from_system_conf()
+                .map_err(|err| error!("Cannot set up the dns resolver: {err}"))
+                .ok()
+        } else {
+            None
+        };
+        Self {
+            config,
+            resolver,
+            cache: Default::default(),
+        }
+    }
+
+    /// Resolve `domain` to a cid, or to the peer id of a name when the chain of
+    /// records ends at one.
+    pub async fn resolve(&self, domain: &str) -> Result<DnsLinkTarget> {
+        let mut domain = domain.trim_end_matches('.').to_lowercase();
+        for _ in 0..MAX_DEPTH {
+            match self.lookup(&domain).await? {
+                DnsLinkTarget::Name(name) if name.contains('.') => domain = name,
+                target => return Ok(target),
+            }
+        }
+        Err(anyhow!(ApiError::not_found(format!(
+            "The dnslink of {domain} is nested too deep"
+        ))))
+    }
+
+    async fn lookup(&self, domain: &str) -> Result<DnsLinkTarget> {
+        if let Some(target) = self.cached(domain, Instant::now()) {
+            return Ok(target);
+        }
+        let resolver = self
+            .resolver
+            .as_ref()
+            .ok_or_else(|| anyhow!(ApiError::not_found("DNSLink resolution is disabled")))?;
+
+        let not_found = || {
+            anyhow!(ApiError::not_found(format!(
+                "{domain} has no dnslink record"
+            )))
+        };
+        let txt = resolver
+            .txt_lookup(format!("_dnslink.{domain}."))
+            .await
+            .map_err(|err| {
+                debug!("TXT lookup of {domain} failed: {err}");
+                not_found()
+            })?;
+        let target = txt
+            .iter()
+            .find_map(|record| parse_dnslink(&record.to_string()))
+            .ok_or_else(not_found)?;
+
+        let max_ttl = Instant::now() + Duration::from_secs(self.config.max_ttl_secs);
+        self.cache.lock().unwrap().insert(
+            domain.to_string(),
+            (target.clone(), txt.valid_until().min(max_ttl)),
+        );
+        Ok(target)
+    }
+
+    fn cached(&self, domain: &str, now: Instant) -> Option<DnsLinkTarget> {
+        let mut cache = self.cache.lock().unwrap();
+        match cache.get(domain) {
+            Some((target, expires)) if *expires > now => Some(target.clone()),
+            Some(_) => {
+                cache.remove(domain);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dnslink() {
+        let cid = "bafkreif2opfibjypwkjzzry3jbibcjqcjwnpoqpeiqw75eu3s3u3zbdszq";
+        assert_eq!(
+            parse_dnslink(&format!("dnslink=/ipfs/{cid}")),
+            Some(DnsLinkTarget::Cid(Cid::from_str(cid).unwrap()))
+        );
+        assert_eq!(
+            parse_dnslink("dnslink=/ipns/docs.example.com"),
+            Some(DnsLinkTarget::Name("docs.example.com".to_string()))
+        );
+        assert_eq!(parse_dnslink("v=spf1 -all"), None);
+        assert_eq!(parse_dnslink("dnslink=/ipfs/nope"), None);
+
+        let dnslink = DnsLink::new(DnsLinkConfig {
+            enabled: false,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        dnslink.cache.lock().unwrap().insert(
+            "example.com".to_string(),
+            (DnsLinkTarget::Name("example.org".to_string()), now),
+        );
+        // expired answers are looked up again
+        assert_eq!(dnslink.cached("example.com", now), None);
+        assert!(dnslink.cache.lock().unwrap().is_empty());
+    }
+}
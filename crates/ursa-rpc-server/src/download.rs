@@ -0,0 +1,245 @@
+//! Downloads of `ursa_put_url`.
+//!
+//! The url is fetched by the server, so it must not become a way to reach what only
+//! the server can: the host is resolved first and the download refused when any of
+//! its addresses is loopback, private, link-local or otherwise not globally routable,
+//! unless the host is in `server_config.put_url.allowed_hosts`. The request then
+//! connects to the checked address rather than resolving the host again, so a second
+//! answer of the name server cannot point it elsewhere. Redirects are not followed, a
+//! `3xx` fails the download like any other non-success status.
+//!
+//! The body is not buffered. [`Download`] reads it as it arrives, counting the bytes
+//! against the size limit and hashing them for the checksum of the request.
+
+use anyhow::{anyhow, Result};
+use async_std::net::ToSocketAddrs;
+use futures::{io, AsyncRead};
+use http_client::isahc::IsahcClient;
+use isahc::{config::ResolveMap, HttpClient};
+use sha2::{Digest, Sha256};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use surf::Url;
+use ursa_utils::hex;
+
+/// Body of a download, read as it arrives.
+pub struct Download {
+    body: surf::Body,
+    max_size: u64,
+    read: u64,
+    hasher: Sha256,
+}
+
+impl Download {
+    /// Hex sha2-256 of the bytes read so far.
+    pub fn sha256(&self) -> String {
+        hex(&self.hasher.clone().finalize())
+    }
+}
+
+impl AsyncRead for Download {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let read = match Pin::new(&mut this.body).poll_read(cx, buf) {
+            Poll::Ready(Ok(read)) => read,
+            other => return other,
+        };
+        this.read += read as u64;
+        if this.read > this.max_size {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "The download is larger than the limit of {} bytes",
+                    this.max_size
+                ),
+            )));
+        }
+        this.hasher.update(&buf[..read]);
+        Poll::Ready(Ok(read))
+    }
+}
+
+/// Start downloading `url`, failing when its host is not allowed or it announces more
+/// than `max_size` bytes.
+///
+/// Also returns whether the content is a car, judged by its content type or path.
+pub async fn download(
+    url: &str,
+    max_size: u64,
+    allowed_hosts: &[String],
+) -> Result<(Download, bool)> {
+    let parsed = Url::parse(url).map_err(|e| anyhow!("Invalid url {url}: {e}"))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(anyhow!("Only http(s) urls can be put, got {url}"));
+    }
+    let checked = check_host(&parsed, allowed_hosts).await?;
+
+    let mut builder = HttpClient::builder();
+    if let (Some(addr), Some(host)) = (checked, parsed.host_str()) {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        builder = builder.dns_resolve(ResolveMap::new().add(host, addr.port(), addr.ip()));
+    }
+    let client = builder
+        .build()
+        .map_err(|e| anyhow!("Cannot build the client for {url}: {e}"))?;
+    let mut res = surf::Client::with_http_client(IsahcClient::from_client(client))
+        .get(url)
+        .await
+        .map_err(|e| anyhow!("Request to {} failed: {}", url, e))?;
+    if !res.status().is_success() {
+        return Err(anyhow!("{} responded with status {}", url, res.status()));
+    }
+    if res.len().map_or(false, |len| len as u64 > max_size) {
+        return Err(anyhow!(
+            "{url} is larger than the limit of {max_size} bytes"
+        ));
+    }
+
+    let is_car = res
+        .content_type()
+        .map_or(false, |mime| mime.essence() == "application/vnd.ipld.car")
+        || url
+            .split(['?', '#'])
+            .next()
+            .map_or(false, |path| path.ends_with(".car"));
+
+    let download = Download {
+        body: res.take_body(),
+        max_size,
+        read: 0,
+        hasher: Sha256::new(),
+    };
+    Ok((download, is_car))
+}
+
+/// Refuse `url` when its host resolves to an address that is not globally routable,
+/// unless the host is allowed.
+///
+/// Returns the checked address the request must connect to, `None` for an allowed host.
+async fn check_host(url: &Url, allowed_hosts: &[String]) -> Result<Option<SocketAddr>> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("The url {url} has no host"))?;
+    if allowed_hosts
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(host))
+    {
+        return Ok(None);
+    }
+
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs = (host.trim_start_matches('[').trim_end_matches(']'), port)
+        .to_socket_addrs()
+        .await
+        .map_err(|e| anyhow!("Cannot resolve {host}: {e}"))?;
+    let mut resolved = None;
+    for addr in addrs {
+        if !is_global(addr.ip()) {
+            return Err(anyhow!(
+                "{host} resolves to the non-public address {}, add it to allowed_hosts to put from it",
+                addr.ip()
+            ));
+        }
+        resolved.get_or_insert(addr);
+    }
+    match resolved {
+        Some(addr) => Ok(Some(addr)),
+        None => Err(anyhow!("{host} does not resolve to any address")),
+    }
+}
+
+/// Whether `ip` is reachable over the internet, rather than on the host or its
+/// networks.
+fn is_global(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_global_v4(ip),
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_global_v4(ip);
+            }
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // unique local fc00::/7
+                || first & 0xfe00 == 0xfc00
+                // link-local fe80::/10
+                || first & 0xffc0 == 0xfe80
+                // documentation 2001:db8::/32
+                || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+        }
+    }
+}
+
+fn is_global_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // "this network" 0.0.0.0/8
+        || a == 0
+        // shared address space 100.64.0.0/10
+        || (a == 100 && b & 0xc0 == 64)
+        // benchmarking 198.18.0.0/15
+        || (a == 198 && b & 0xfe == 18)
+        // reserved 240.0.0.0/4
+        || a >= 240)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_global() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_global(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["1.1.1.1", "93.184.216.34", "2606:4700::1111"] {
+            assert!(is_global(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[async_std::test]
+    async fn test_check_host() {
+        let url = Url::parse("http://127.0.0.1:8080/file").unwrap();
+        assert!(check_host(&url, &[]).await.is_err());
+        assert_eq!(
+            check_host(&url, &["127.0.0.1".to_string()]).await.unwrap(),
+            None
+        );
+
+        let url = Url::parse("http://1.1.1.1:8080/file").unwrap();
+        assert_eq!(
+            check_host(&url, &[]).await.unwrap(),
+            Some("1.1.1.1:8080".parse().unwrap())
+        );
+
+        let url = Url::parse("http://[::1]/file").unwrap();
+        assert!(check_host(&url, &[]).await.is_err());
+        assert!(check_host(&url, &["[::1]".to_string()]).await.is_ok());
+    }
+}
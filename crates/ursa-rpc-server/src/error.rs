@@ -0,0 +1,274 @@
+//! Error model shared by the http routes and the JSON-RPC methods.
+//!
+//! Failures are reported as an [`ApiError`] carrying a machine-readable
+//! [`ErrorCode`], so clients can branch on `code` instead of parsing messages. Http
+//! routes answer with the error as json body and the status of its code, JSON-RPC
+//! error objects carry it in their `data`. Both report the id of the request, also
+//! sent back in the [`REQUEST_ID_HEADER`] header of every response.
+
+use axum::{
+    http::{HeaderValue, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use hyper::StatusCode;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
+use ursa_utils::hex;
+
+use crate::{
+    api::is_queue_full,
+    api_keys::{api_key_error, ApiKeyError},
+    signed_url::SignatureError,
+};
+
+/// Header carrying the id of a request, taken from the request when the gateway in
+/// front of the node set one.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Malformed params, e.g. a cid or peer id that does not parse.
+    InvalidParams,
+    NotFound,
+    /// Missing, unknown or revoked api key.
+    Unauthorized,
+    /// The signed url or the acl of the content does not grant access.
+    Forbidden,
+    RateLimited,
+    QuotaExceeded,
+    /// The node is too busy to take the request.
+    Unavailable,
+    /// Well formed content that cannot be used, e.g. a car file missing its roots.
+    Unprocessable,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidParams => StatusCode::BAD_REQUEST,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden | ErrorCode::QuotaExceeded => StatusCode::FORBIDDEN,
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::Unprocessable => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Code of the JSON-RPC error object, the reserved ones for invalid params and
+    /// internal errors, and server errors from -32001 down for the others.
+    pub fn rpc_code(self) -> i64 {
+        match self {
+            ErrorCode::InvalidParams => -32602,
+            ErrorCode::Internal => -32603,
+            ErrorCode::NotFound => -32001,
+            ErrorCode::Unauthorized => -32002,
+            ErrorCode::Forbidden => -32003,
+            ErrorCode::RateLimited => -32004,
+            ErrorCode::QuotaExceeded => -32005,
+            ErrorCode::Unavailable => -32006,
+            ErrorCode::Unprocessable => -32007,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    /// Code specific context, e.g. the used and allowed bytes of an exceeded quota.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            details: None,
+            request_id: None,
+        }
+    }
+
+    pub fn invalid_params(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::InvalidParams, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::NotFound, message)
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Forbidden, message)
+    }
+
+    pub fn with_details(mut self, details: Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    pub fn with_request_id(mut self, request_id: &RequestId) -> Self {
+        self.request_id = Some(request_id.0.clone());
+        self
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        if let Some(err) = err.downcast_ref::<ApiError>() {
+            return err.clone();
+        }
+        if is_queue_full(&err) {
+            return ApiError::new(ErrorCode::Unavailable, err.to_string());
+        }
+        if let Some(err) = err.downcast_ref::<SignatureError>() {
+            return err.clone().into();
+        }
+        match api_key_error(&err) {
+            Some(err) => err.clone().into(),
+            None => ApiError::new(ErrorCode::Internal, format!("{err:#}")),
+        }
+    }
+}
+
+impl From<ApiKeyError> for ApiError {
+    fn from(err: ApiKeyError) -> Self {
+        match err {
+            ApiKeyError::RateLimited => ApiError::new(ErrorCode::RateLimited, err.to_string()),
+            ApiKeyError::QuotaExceeded { used, quota } => {
+                ApiError::new(ErrorCode::QuotaExceeded, err.to_string())
+                    .with_details(json!({ "used_bytes": used, "quota_bytes": quota }))
+            }
+            ApiKeyError::Missing | ApiKeyError::Invalid | ApiKeyError::Revoked => {
+                ApiError::new(ErrorCode::Unauthorized, err.to_string())
+            }
+        }
+    }
+}
+
+impl From<SignatureError> for ApiError {
+    fn from(err: SignatureError) -> Self {
+        let reason = match err {
+            SignatureError::Missing => "missing",
+            SignatureError::Invalid => "invalid",
+            SignatureError::Expired => "expired",
+        };
+        ApiError::forbidden(err.to_string()).with_details(json!({ "signature": reason }))
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.code.status(), Json(self)).into_response()
+    }
+}
+
+impl From<ApiError> for jsonrpc_v2::Error {
+    fn from(err: ApiError) -> Self {
+        jsonrpc_v2::Error::Full {
+            code: err.code.rpc_code(),
+            message: err.message.clone(),
+            data: Some(Box::new(err)),
+        }
+    }
+}
+
+/// JSON-RPC error of `err`, for `map_err` in the method handlers.
+pub fn rpc_error(err: anyhow::Error) -> jsonrpc_v2::Error {
+    ApiError::from(err).into()
+}
+
+/// Id of the request being handled, an extension of every request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+/// Attach a [`RequestId`] to the request, and send it back in the response.
+pub async fn request_id<B>(mut req: Request<B>, next: Next<B>) -> impl IntoResponse {
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+        .unwrap_or_else(|| {
+            let mut id = [0u8; 8];
+            rand::thread_rng().fill_bytes(&mut id);
+            hex(&id)
+        });
+    req.extensions_mut().insert(RequestId(id.clone()));
+
+    let mut response = next.run(req).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+/// Set the request id on the [`ApiError`]s in the error objects of a JSON-RPC
+/// response, a single response object or a batch of them.
+pub fn stamp_rpc_errors(response: &mut Value, request_id: &RequestId) {
+    let objects: Vec<&mut Value> = match response {
+        Value::Array(objects) => objects.iter_mut().collect(),
+        object => vec![object],
+    };
+    for object in objects {
+        if let Some(Value::Object(data)) = object.pointer_mut("/error/data") {
+            if data.contains_key("code") {
+                data.insert("request_id".to_string(), json!(request_id.0));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_error_codes() {
+        let err = ApiError::from(anyhow!(ApiKeyError::QuotaExceeded {
+            used: 10,
+            quota: 10
+        }));
+        assert_eq!(err.code, ErrorCode::QuotaExceeded);
+        assert_eq!(err.code.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            err.details,
+            Some(json!({ "used_bytes": 10, "quota_bytes": 10 }))
+        );
+
+        let err = ApiError::from(anyhow!("disk on fire"));
+        assert_eq!(err.code, ErrorCode::Internal);
+        assert_eq!(
+            serde_json::to_value(&err).unwrap(),
+            json!({ "code": "internal", "message": "disk on fire" })
+        );
+
+        let mut response = json!([
+            { "jsonrpc": "2.0", "result": 1, "id": 1 },
+            { "jsonrpc": "2.0", "error": { "code": -32001, "message": "gone", "data": { "code": "not_found", "message": "gone" } }, "id": 2 }
+        ]);
+        stamp_rpc_errors(&mut response, &RequestId("abc".to_string()));
+        assert_eq!(response[1]["error"]["data"]["request_id"], "abc");
+        assert!(response[0].get("error").is_none());
+    }
+}
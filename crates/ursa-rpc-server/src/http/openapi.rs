@@ -0,0 +1,570 @@
+//! OpenAPI document of the http routes.
+//!
+//! Served at `/openapi.json` so integrators can generate clients for the upload and
+//! retrieval routes instead of hand-rolling the requests. The JSON-RPC methods behind
+//! `/rpc/v0` are listed in the description of that single route.
+
+use axum::Json;
+use serde_json::{json, Value};
+
+/// Methods served on `/rpc/v0`.
+const RPC_METHODS: &[&str] = &[
+    "ursa_get_cid",
+    "ursa_get_file",
+    "ursa_put_file",
+    "ursa_put_url",
+    "ursa_prefetch",
+    "ursa_prefetch_status",
+    "ursa_remove",
+    "ursa_set_context",
+    "ursa_unindex_context",
+    "ursa_purge",
+    "ursa_access_log",
+    "ursa_receipts",
+    "ursa_accounting",
+    "ursa_gossip_stat",
+    "ursa_topic_peers",
+    "ursa_find_providers",
+    "ursa_node_info",
+    "ursa_relay_circuits",
+    "ursa_peer_protocols",
+    "ursa_provider_status",
+    "ursa_operation_status",
+    "ursa_cancel",
+    "ursa_create_api_key",
+    "ursa_revoke_api_key",
+    "ursa_api_key_usage",
+    "ursa_sign_url",
+    "ursa_acl_set",
+    "ursa_acl_remove",
+    "ursa_acl_list",
+    "ursa_list_content",
+    "ursa_dag_stat",
+    "ursa_verify",
+    "ursa_resolve",
+    "ursa_name_publish",
+    "ursa_name_resolve",
+    "ursa_repo_compact",
+    "ursa_config_get",
+    "ursa_config_set",
+    "ursa_diagnostics",
+];
+
+pub async fn openapi_handler() -> Json<Value> {
+    Json(document())
+}
+
+pub fn document() -> Value {
+    let cid = json!({
+        "name": "cid",
+        "in": "path",
+        "required": true,
+        "description": "Root cid of the content",
+        "schema": { "type": "string" }
+    });
+    let error = |description: &str| {
+        json!({
+            "description": description,
+            "content": {
+                "application/json": { "schema": { "$ref": "#/components/schemas/Error" } }
+            }
+        })
+    };
+
+    let upload = json!({
+        "post": {
+            "summary": "Upload a car file",
+            "operationId": "upload",
+            "parameters": [{
+                "name": "x-api-key",
+                "in": "header",
+                "required": false,
+                "description": "Api key the upload counts against, required when the node enforces keys",
+                "schema": { "type": "string" }
+            }, {
+                "name": "background",
+                "in": "query",
+                "required": false,
+                "description": "Answer with the operation id once the car file arrived instead of waiting for the put",
+                "schema": { "type": "boolean" }
+            }],
+            "requestBody": {
+                "required": true,
+                "content": {
+                    "multipart/form-data": {
+                        "schema": {
+                            "type": "object",
+                            "properties": {
+                                "file": {
+                                    "type": "string",
+                                    "format": "binary",
+                                    "description": "Car file, sent with the application/vnd.curl.car content type"
+                                }
+                            }
+                        }
+                    }
+                }
+            },
+            "responses": {
+                "200": {
+                    "description": "Root cids of the stored car file",
+                    "headers": {
+                        "x-ursa-operation": {
+                            "description": "Id of the put, see the /operations websocket",
+                            "schema": { "type": "integer" }
+                        }
+                    },
+                    "content": { "application/json": { "schema": { "type": "string" } } }
+                },
+                "202": {
+                    "description": "The put runs in the background, follow it with ursa_operation_status",
+                    "headers": {
+                        "x-ursa-operation": {
+                            "description": "Id of the put, see the /operations websocket",
+                            "schema": { "type": "integer" }
+                        }
+                    },
+                    "content": {
+                        "application/json": {
+                            "schema": {
+                                "type": "object",
+                                "properties": { "operation": { "type": "integer" } }
+                            }
+                        }
+                    }
+                },
+                "400": error("No file, or a file that is not a car file"),
+                "401": error("Missing, unknown or revoked api key"),
+                "403": error("The quota of the api key is used up"),
+                "422": error("The car file does not contain the roots of its header"),
+                "429": error("The rate limit of the api key was hit"),
+                "500": error("Storing the car file failed"),
+                "503": error("The node is too busy to take the upload")
+            }
+        }
+    });
+
+    let content = json!({
+        "get": {
+            "summary": "Download content as a car file",
+            "operationId": "get",
+            "parameters": [
+                cid.clone(),
+                {
+                    "name": "x-api-key",
+                    "in": "header",
+                    "required": false,
+                    "description": "Api key allowed to retrieve private content, see ursa_acl_set",
+                    "schema": { "type": "string" }
+                },
+                {
+                    "name": "exp",
+                    "in": "query",
+                    "required": false,
+                    "description": "Unix time in seconds a signed url expires at, see ursa_sign_url",
+                    "schema": { "type": "integer" }
+                },
+                {
+                    "name": "sig",
+                    "in": "query",
+                    "required": false,
+                    "description": "Signature of a signed url",
+                    "schema": { "type": "string" }
+                }
+            ],
+            "responses": {
+                "200": {
+                    "description": "The dag under the cid",
+                    "content": {
+                        "application/vnd.curl.car": {
+                            "schema": { "type": "string", "format": "binary" }
+                        }
+                    }
+                },
+                "400": error("Invalid cid"),
+                "403": error("The url signature is missing, invalid or expired, or the content is private to other api keys"),
+                "404": error("The content is not available"),
+                "500": error("Fetching the content failed"),
+                "503": error("The node is too busy to fetch the content")
+            }
+        },
+        "head": {
+            "summary": "Check whether content can be downloaded",
+            "operationId": "head",
+            "parameters": [cid],
+            "responses": {
+                "200": { "description": "The content is available" },
+                "404": { "description": "The content is not available" }
+            }
+        }
+    });
+
+    // the same route under the path gateways serve it at
+    let mut ipfs = content.clone();
+    ipfs["get"]["operationId"] = json!("ipfs_get");
+    ipfs["head"]["operationId"] = json!("ipfs_head");
+
+    let ipns = json!({
+        "get": {
+            "summary": "Download the content a name or a DNSLink domain points at",
+            "operationId": "ipns",
+            "parameters": [{
+                "name": "name",
+                "in": "path",
+                "required": true,
+                "description": "Peer id of a publisher, see ursa_name_publish, or a domain with a _dnslink TXT record",
+                "schema": { "type": "string" }
+            }],
+            "responses": {
+                "200": {
+                    "description": "The dag under the resolved cid",
+                    "content": {
+                        "application/vnd.curl.car": {
+                            "schema": { "type": "string", "format": "binary" }
+                        }
+                    }
+                },
+                "403": error("The content is private to other api keys"),
+                "404": error("The name does not resolve, or the content is not available"),
+                "500": error("Fetching the content failed")
+            }
+        }
+    });
+
+    let ipfs_path = json!({
+        "get": {
+            "summary": "List a UnixFS directory, or download an entry under a root cid",
+            "operationId": "ipfs_path",
+            "parameters": [
+                cid,
+                {
+                    "name": "path",
+                    "in": "path",
+                    "required": true,
+                    "description": "Path of UnixFS directory entries under the root, empty for the root itself",
+                    "schema": { "type": "string" }
+                }
+            ],
+            "responses": {
+                "200": {
+                    "description": "A listing of the entries with their names, cids and sizes when the path is a directory, the dag of the entry otherwise",
+                    "content": {
+                        "text/html": { "schema": { "type": "string" } },
+                        "application/json": { "schema": { "type": "object" } },
+                        "application/vnd.curl.car": {
+                            "schema": { "type": "string", "format": "binary" }
+                        }
+                    }
+                },
+                "400": error("Invalid cid, or a sharded directory"),
+                "403": error("The url signature is missing, invalid or expired, or the content is private to other api keys"),
+                "404": error("Nothing is at the path, or the content is not available")
+            }
+        }
+    });
+
+    let receipts = json!({
+        "post": {
+            "summary": "Acknowledge a delivery with a receipt signed by the client key",
+            "description": "The signature covers `ursa-receipt:<cid>:<bytes>:<timestamp>:<client_key>`, see the x-ursa-client-key header of retrievals",
+            "operationId": "receipts",
+            "requestBody": {
+                "required": true,
+                "content": {
+                    "application/json": {
+                        "schema": {
+                            "type": "object",
+                            "required": ["cid", "bytes", "timestamp", "client_key", "signature"],
+                            "properties": {
+                                "cid": { "type": "string" },
+                                "bytes": {
+                                    "type": "integer",
+                                    "description": "Bytes of the car file the client received"
+                                },
+                                "timestamp": {
+                                    "type": "integer",
+                                    "description": "Unix time in milliseconds the receipt was signed at"
+                                },
+                                "client_key": {
+                                    "type": "string",
+                                    "description": "Hex of the protobuf encoded public key of the client"
+                                },
+                                "signature": { "type": "string", "description": "Hex signature" }
+                            }
+                        }
+                    }
+                }
+            },
+            "responses": {
+                "200": {
+                    "description": "The receipt was accepted",
+                    "content": {
+                        "application/json": {
+                            "schema": {
+                                "type": "object",
+                                "properties": {
+                                    "cid": { "type": "string" },
+                                    "accepted": { "type": "boolean" }
+                                }
+                            }
+                        }
+                    }
+                },
+                "400": error("The receipt acknowledges more bytes than were delivered"),
+                "401": error("The signature does not match the client key"),
+                "404": error("No delivery of the cid to the client is pending")
+            }
+        }
+    });
+
+    let accounting = json!({
+        "get": {
+            "summary": "Export the accounting rollups for settlement",
+            "description": "Same as ursa_accounting, as json or csv",
+            "operationId": "accounting",
+            "parameters": [
+                {
+                    "name": "x-ursa-admin-token",
+                    "in": "header",
+                    "required": true,
+                    "description": "admin_token of the server config",
+                    "schema": { "type": "string" }
+                },
+                {
+                    "name": "kind",
+                    "in": "query",
+                    "required": false,
+                    "description": "Only export the rollups of api keys, peers or cids",
+                    "schema": { "type": "string", "enum": ["api_key", "peer", "cid"] }
+                },
+                {
+                    "name": "id",
+                    "in": "query",
+                    "required": false,
+                    "description": "Only export the rollups of this api key, peer or cid",
+                    "schema": { "type": "string" }
+                },
+                {
+                    "name": "since",
+                    "in": "query",
+                    "required": false,
+                    "description": "Only rollups starting at or after this unix time in seconds",
+                    "schema": { "type": "integer" }
+                },
+                {
+                    "name": "until",
+                    "in": "query",
+                    "required": false,
+                    "description": "Only rollups starting before this unix time in seconds",
+                    "schema": { "type": "integer" }
+                },
+                {
+                    "name": "format",
+                    "in": "query",
+                    "required": false,
+                    "schema": { "type": "string", "enum": ["json", "csv"] }
+                }
+            ],
+            "responses": {
+                "200": {
+                    "description": "The rollups",
+                    "content": {
+                        "application/json": { "schema": { "type": "array", "items": { "type": "object" } } },
+                        "text/csv": { "schema": { "type": "string" } }
+                    }
+                },
+                "400": error("Unknown format or kind"),
+                "401": error("Missing or invalid admin token"),
+                "403": error("No admin_token is configured")
+            }
+        }
+    });
+
+    let operations = json!({
+        "get": {
+            "summary": "Websocket streaming the progress of puts, gets and compactions",
+            "operationId": "operations",
+            "parameters": [{
+                "name": "id",
+                "in": "query",
+                "required": false,
+                "description": "Only stream this operation",
+                "schema": { "type": "integer" }
+            }],
+            "responses": {
+                "101": { "description": "Switching to the websocket, one json status per message" }
+            }
+        }
+    });
+
+    let events = json!({
+        "get": {
+            "summary": "Server-sent events of the node",
+            "description": "Peer connections, nat changes, bitswap completions and provider announcements, one json object per event with its kind in `type`",
+            "operationId": "events",
+            "responses": {
+                "200": {
+                    "description": "Event stream",
+                    "content": { "text/event-stream": { "schema": { "type": "string" } } }
+                }
+            }
+        }
+    });
+
+    let providers = json!({
+        "get": {
+            "summary": "Stream the providers of a cid while the dht walk finds them",
+            "description": "Newline delimited json objects with a `peer_id` and its `addrs`, a peer found both locally and by the walk comes twice. Same as ursa_find_providers, without waiting for the walk to end",
+            "operationId": "providers",
+            "parameters": [
+                {
+                    "name": "cid",
+                    "in": "path",
+                    "required": true,
+                    "schema": { "type": "string" }
+                },
+                {
+                    "name": "timeout_ms",
+                    "in": "query",
+                    "required": false,
+                    "description": "Time in milliseconds to walk the dht for",
+                    "schema": { "type": "integer" }
+                }
+            ],
+            "responses": {
+                "200": {
+                    "description": "Provider stream",
+                    "content": { "application/x-ndjson": { "schema": { "type": "string" } } }
+                },
+                "400": error("The cid cannot be parsed")
+            }
+        }
+    });
+
+    let rpc = json!({
+        "post": {
+            "summary": "JSON-RPC 2.0 endpoint, batches included",
+            "description": format!(
+                "Methods: {}. Error objects carry the Error schema in their data",
+                RPC_METHODS.join(", ")
+            ),
+            "operationId": "rpc",
+            "requestBody": {
+                "required": true,
+                "content": { "application/json": { "schema": { "type": "object" } } }
+            },
+            "responses": {
+                "200": {
+                    "description": "Response object, or array of them for batches",
+                    "content": { "application/json": { "schema": { "type": "object" } } }
+                },
+                "204": { "description": "Every request of the batch was a notification" }
+            }
+        }
+    });
+
+    let openapi = json!({
+        "get": {
+            "summary": "This document",
+            "operationId": "openapi",
+            "responses": {
+                "200": {
+                    "description": "OpenAPI document",
+                    "content": { "application/json": { "schema": { "type": "object" } } }
+                }
+            }
+        }
+    });
+
+    let error_schema = json!({
+        "type": "object",
+        "required": ["code", "message"],
+        "properties": {
+            "code": {
+                "type": "string",
+                "enum": [
+                    "invalid_params",
+                    "not_found",
+                    "unauthorized",
+                    "forbidden",
+                    "rate_limited",
+                    "quota_exceeded",
+                    "unavailable",
+                    "unprocessable",
+                    "internal"
+                ]
+            },
+            "message": { "type": "string" },
+            "details": { "type": "object" },
+            "request_id": {
+                "type": "string",
+                "description": "Also sent in the x-request-id response header"
+            }
+        }
+    });
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Ursa node",
+            "version": env!("CARGO_PKG_VERSION")
+        },
+        "paths": {
+            "/": upload,
+            "/{cid}": content,
+            "/ipfs/{cid}": ipfs,
+            "/ipfs/{cid}/{path}": ipfs_path,
+            "/ipns/{name}": ipns,
+            "/receipts": receipts,
+            "/operations": operations,
+            "/events": events,
+            "/accounting": accounting,
+            "/providers/{cid}": providers,
+            "/rpc/v0": rpc,
+            "/openapi.json": openapi
+        },
+        "components": {
+            "schemas": { "Error": error_schema }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_document_lists_routes() {
+        let document = document();
+        let paths = document["paths"].as_object().unwrap();
+        for path in [
+            "/",
+            "/{cid}",
+            "/ipfs/{cid}",
+            "/ipfs/{cid}/{path}",
+            "/ipns/{name}",
+            "/receipts",
+            "/operations",
+            "/events",
+            "/accounting",
+            "/providers/{cid}",
+            "/rpc/v0",
+            "/openapi.json",
+        ] {
+            assert!(paths.contains_key(path), "{path} is not documented");
+        }
+        assert!(document["paths"]["/rpc/v0"]["post"]["description"]
+            .as_str()
+            .unwrap()
+            .contains("ursa_node_info"));
+
+        // operation ids are unique across the document
+        let mut ids = std::collections::HashSet::new();
+        for path in paths.values() {
+            for operation in path.as_object().unwrap().values() {
+                let id = operation["operationId"].as_str().unwrap();
+                assert!(ids.insert(id.to_string()), "{id} is used twice");
+            }
+        }
+    }
+}
@@ -1,115 +1,631 @@
 pub const BASE_PATH: &str = "./car_files";
 
-use crate::api::{NetworkInterface, NodeNetworkInterface};
-use anyhow::{anyhow, Error};
-use async_std::io::Cursor;
+use crate::{
+    access_log::{AccessLogEntry, LoggedStream},
+    api::{NetworkInterface, NodeNetworkInterface},
+    api_keys::API_KEY_HEADER,
+    directory::Listing,
+    error::{request_id, ApiError, RequestId},
+    http::openapi::openapi_handler,
+    operations::{OperationId, OperationKind},
+    receipts::{DeliveryReceipt, CLIENT_KEY_HEADER},
+    render_cache::{read_file, ByteRange, Cached, RangeStream},
+    settings::ADMIN_TOKEN_HEADER,
+};
+use async_std::{io::Cursor, task};
 use axum::{
-    extract::{Multipart, Path},
-    http::header::{CONTENT_DISPOSITION, CONTENT_TYPE},
-    response::{IntoResponse, Response},
+    body::StreamBody,
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Multipart, Path, Query,
+    },
+    http::{
+        header::{
+            ACCEPT, ACCEPT_RANGES, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE,
+            CONTENT_TYPE, RANGE,
+        },
+        HeaderMap,
+    },
+    middleware,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
     routing::{get, post},
     Extension, Json, Router,
 };
+use bytes::Bytes;
 use cid::Cid;
+use futures::{stream, stream::BoxStream, Stream, StreamExt};
 use hyper::StatusCode;
 use ipld_blockstore::BlockStore;
-use std::{str::FromStr, sync::Arc};
-use tracing::{error, info};
+use serde::Deserialize;
+use serde_json::json;
+use std::{convert::Infallible, io, str::FromStr, sync::Arc, time::Duration};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info, warn};
+use ursa_metrics::events::{track, MetricEvent};
+use ursa_network::{
+    accounting::{to_csv, UsageKind, UsageQuery},
+    shaping::{ShapedStream, TrafficClass},
+};
+use ursa_utils::unix_now;
+
+/// Response header carrying the operation id of an upload.
+const OPERATION_HEADER: &str = "x-ursa-operation";
 
 pub fn init<S: BlockStore + Sync + Send + 'static>() -> Router {
+    content::<S>().merge(admin::<S>())
+}
+
+/// Routes serving content, exposed on the public listener.
+pub fn content<S: BlockStore + Sync + Send + 'static>() -> Router {
     Router::new()
-        .route("/", post(upload_handler::<S>))
+        .route("/openapi.json", get(openapi_handler))
         .route("/:cid", get(get_handler::<S>))
+        .route("/ipfs/:cid", get(get_handler::<S>))
+        .route("/ipfs/:cid/*path", get(ipfs_path_handler::<S>))
+        .route("/ipns/:name", get(ipns_handler::<S>))
+        .route("/receipts", post(receipt_handler::<S>))
+        .layer(middleware::from_fn(request_id))
+}
+
+/// Routes changing what the node stores, only exposed on the admin listener when
+/// there is one.
+pub fn admin<S: BlockStore + Sync + Send + 'static>() -> Router {
+    Router::new()
+        .route("/", post(upload_handler::<S>))
+        .route("/operations", get(operations_handler::<S>))
+        .route("/events", get(events_handler::<S>))
+        .route("/accounting", get(accounting_handler::<S>))
+        .route("/providers/:cid", get(providers_handler::<S>))
+        .layer(middleware::from_fn(request_id))
+}
+
+/// Client address as forwarded by the gateway in front of the node.
+fn client_address(headers: &HeaderMap) -> String {
+    headers
+        .get("x-forwarded-for")
+        .or_else(|| headers.get("x-real-ip"))
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .map(|value| value.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
 }
 
-pub enum NetworkError {
-    NotFoundError(Error),
-    InternalError(Error),
+#[derive(Deserialize)]
+pub struct UploadQuery {
+    /// Answer with the operation id once the car file arrived instead of waiting for
+    /// the put.
+    #[serde(default)]
+    background: bool,
 }
-impl IntoResponse for NetworkError {
-    fn into_response(self) -> Response {
-        match self {
-            NetworkError::NotFoundError(e) => {
-                return (StatusCode::NOT_FOUND, e.to_string()).into_response()
+
+pub async fn upload_handler<S>(
+    headers: HeaderMap,
+    Query(query): Query<UploadQuery>,
+    mut buf: Multipart,
+    Extension(interface): Extension<Arc<NodeNetworkInterface<S>>>,
+    Extension(request_id): Extension<RequestId>,
+) -> Response
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    info!("uploading file via http");
+    let field = match buf.next_field().await {
+        Ok(Some(field)) => field,
+        _ => {
+            return ApiError::invalid_params("No files found")
+                .with_request_id(&request_id)
+                .into_response()
+        }
+    };
+    if field.content_type() != Some("application/vnd.curl.car") {
+        return ApiError::invalid_params(
+            "Content type do not match. Only .car files can be uploaded",
+        )
+        .with_request_id(&request_id)
+        .into_response();
+    }
+
+    // registered before the body is read, so the upload shows on /operations from the
+    // start
+    let operation = interface.start_operation(OperationKind::Put, None);
+    let with_operation = |response: Response| {
+        ([(OPERATION_HEADER, operation.to_string())], response).into_response()
+    };
+    let failed = |err: anyhow::Error| {
+        interface.operations.finish(operation, &Err::<(), _>(&err));
+        with_operation(
+            ApiError::from(err)
+                .with_request_id(&request_id)
+                .into_response(),
+        )
+    };
+
+    let data = match field.bytes().await {
+        Ok(data) => data,
+        Err(err) => return failed(anyhow::anyhow!("Cannot read the upload: {err}")),
+    };
+    let api_key = headers
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok());
+    if let Err(err) = interface.admit(api_key, data.len() as u64) {
+        return failed(err);
+    }
+    let size = data.len() as f64;
+    let reader = Cursor::new(data.to_vec());
+
+    if query.background {
+        let interface = Arc::clone(&interface);
+        task::spawn(async move {
+            match interface.put_car(reader, Some(operation)).await {
+                Ok(_) => track(MetricEvent::UploadBytes, None, Some(size)),
+                Err(err) => error!("{:?}", err),
             }
-            NetworkError::InternalError(e) => {
-                return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        });
+        return with_operation(
+            (
+                StatusCode::ACCEPTED,
+                Json(json!({ "operation": operation })),
+            )
+                .into_response(),
+        );
+    }
+
+    let response = match interface.put_car(reader, Some(operation)).await {
+        Err(err) => {
+            error!("{:?}", err);
+            ApiError::from(err)
+                .with_request_id(&request_id)
+                .into_response()
+        }
+        Ok(res) => {
+            track(MetricEvent::UploadBytes, None, Some(size));
+            (StatusCode::OK, Json(format!("{:?}", res))).into_response()
+        }
+    };
+    with_operation(response)
+}
+
+#[derive(Deserialize)]
+pub struct OperationsQuery {
+    /// Only report this operation, all of them when unset.
+    id: Option<OperationId>,
+}
+
+/// Stream the progress of puts and gets over a websocket as json status messages.
+pub async fn operations_handler<S>(
+    ws: WebSocketUpgrade,
+    Query(query): Query<OperationsQuery>,
+    Extension(interface): Extension<Arc<NodeNetworkInterface<S>>>,
+) -> Response
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    ws.on_upgrade(move |socket| send_operations(socket, interface, query.id))
+}
+
+async fn send_operations<S>(
+    mut socket: WebSocket,
+    interface: Arc<NodeNetworkInterface<S>>,
+    id: Option<OperationId>,
+) where
+    S: BlockStore + Sync + Send + 'static,
+{
+    let mut events = interface.operations.subscribe();
+    // report where the operation stands before its next change
+    if let Some(status) = id.and_then(|id| interface.operations.status(id)) {
+        let message = Message::Text(serde_json::to_string(&status).unwrap());
+        if socket.send(message).await.is_err() {
+            return;
+        }
+    }
+
+    loop {
+        let status = match events.recv().await {
+            Ok(status) => status,
+            Err(RecvError::Lagged(skipped)) => {
+                info!("Operations websocket skipped {skipped} progress events");
+                continue;
             }
+            Err(RecvError::Closed) => return,
         };
+        if id.map_or(false, |id| id != status.id) {
+            continue;
+        }
+        let message = Message::Text(serde_json::to_string(&status).unwrap());
+        if socket.send(message).await.is_err() {
+            // the client went away
+            return;
+        }
     }
 }
 
-pub async fn upload_handler<S>(
-    mut buf: Multipart,
+/// Stream the events of the node as server-sent json events, for dashboards that
+/// cannot use websockets.
+pub async fn events_handler<S>(
     Extension(interface): Extension<Arc<NodeNetworkInterface<S>>>,
-) -> impl IntoResponse
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>>
 where
     S: BlockStore + Sync + Send + 'static,
 {
-    info!("uploading file via http");
-    if let Some(field) = buf.next_field().await.unwrap() {
-        let content_type = field.content_type().unwrap().to_string();
-        if content_type == "application/vnd.curl.car".to_string() {
-            let data = field.bytes().await.unwrap();
-            let vec_data = data.to_vec();
-            let reader = Cursor::new(&vec_data);
-
-            return match interface.put_car(reader).await {
-                Err(err) => {
-                    error!("{:?}", err);
-                    (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        Json(format!("{:?}", err)),
-                    )
+    let events = stream::unfold(interface.node_events.subscribe(), |mut events| async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    info!("Events stream skipped {skipped} node events");
+                    continue;
                 }
-                Ok(res) => (StatusCode::OK, Json(format!("{:?}", res))),
+                Err(RecvError::Closed) => return None,
             };
-        } else {
-            (
-                StatusCode::BAD_REQUEST,
-                Json("Content type do not match. Only .car files can be uploaded".to_string()),
-            )
+            match Event::default().json_data(&event) {
+                Ok(event) => return Some((Ok(event), events)),
+                Err(err) => error!("Cannot encode node event {:?}: {:?}", event, err),
+            }
         }
-    } else {
-        (StatusCode::BAD_REQUEST, Json("No files found".to_string()))
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+#[derive(Deserialize)]
+pub struct ProvidersQuery {
+    /// Time in milliseconds to walk the dht for.
+    timeout_ms: Option<u64>,
+}
+
+/// Stream the providers of a cid as newline delimited json, each sent as soon as the
+/// dht walk finds it.
+pub async fn providers_handler<S>(
+    Path(cid_str): Path<String>,
+    Query(query): Query<ProvidersQuery>,
+    Extension(interface): Extension<Arc<NodeNetworkInterface<S>>>,
+    Extension(request_id): Extension<RequestId>,
+) -> Result<Response, ApiError>
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    let cid = Cid::from_str(&cid_str).map_err(|_| {
+        ApiError::invalid_params(format!("Invalid cid {cid_str}"))
+            .with_details(json!({ "cid": cid_str }))
+            .with_request_id(&request_id)
+    })?;
+    let providers = interface
+        .provider_stream(cid, query.timeout_ms.map(Duration::from_millis))
+        .await
+        .map_err(|e| ApiError::from(e).with_request_id(&request_id))?;
+    let lines = providers.map(|provider| {
+        let mut line = serde_json::to_vec(&provider)?;
+        line.push(b'\n');
+        Ok::<_, io::Error>(Bytes::from(line))
+    });
+    Ok((
+        [(CONTENT_TYPE, "application/x-ndjson")],
+        StreamBody::new(lines),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct AccountingQuery {
+    kind: Option<UsageKind>,
+    id: Option<String>,
+    since: Option<u64>,
+    until: Option<u64>,
+    /// `json` or `csv`, json when unset.
+    format: Option<String>,
+}
+
+/// Export the accounting rollups for settlement, as json or as csv, with the admin
+/// token in [`ADMIN_TOKEN_HEADER`].
+pub async fn accounting_handler<S>(
+    Query(query): Query<AccountingQuery>,
+    headers: HeaderMap,
+    Extension(interface): Extension<Arc<NodeNetworkInterface<S>>>,
+    Extension(request_id): Extension<RequestId>,
+) -> Result<Response, ApiError>
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    let usage = UsageQuery {
+        kind: query.kind,
+        id: query.id,
+        since: query.since,
+        until: query.until,
+    };
+    let token = headers
+        .get(ADMIN_TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let rollups = interface
+        .accounting(token, usage)
+        .await
+        .map_err(|e| ApiError::from(e).with_request_id(&request_id))?;
+    match query.format.as_deref() {
+        None | Some("json") => Ok(Json(rollups).into_response()),
+        Some("csv") => Ok(([(CONTENT_TYPE, "text/csv")], to_csv(&rollups)).into_response()),
+        Some(format) => Err(ApiError::invalid_params(format!(
+            "Unknown format {format}, expected json or csv"
+        ))
+        .with_request_id(&request_id)),
     }
 }
 
+#[derive(Deserialize)]
+pub struct SignatureQuery {
+    /// Expiry of a signed url, in unix seconds.
+    exp: Option<u64>,
+    sig: Option<String>,
+}
+
 pub async fn get_handler<S>(
     Path(cid_str): Path<String>,
+    Query(signature): Query<SignatureQuery>,
+    headers: HeaderMap,
     Extension(interface): Extension<Arc<NodeNetworkInterface<S>>>,
-) -> Result<impl IntoResponse, NetworkError>
+    Extension(request_id): Extension<RequestId>,
+) -> Result<impl IntoResponse, ApiError>
 where
     S: BlockStore + Sync + Send + 'static,
 {
     info!("Streaming file over http");
     if let Ok(cid) = Cid::from_str(&cid_str) {
-        let mut res = Response::builder();
-        return match interface.stream(cid).await {
-            Ok(body) => {
-                let headers = res.headers_mut().unwrap();
-                headers.insert(
-                    CONTENT_TYPE,
-                    "application/vnd.curl.car; charset=utf-8".parse().unwrap(),
-                );
-                headers.insert(
-                    CONTENT_DISPOSITION,
-                    format!("attachment; filename=\"{}.car\"", cid_str)
-                        .parse()
-                        .unwrap(),
-                );
-
-                Ok(res.status(StatusCode::OK).body(body).unwrap())
-            }
-            Err(err) => {
-                error!("{:?}", err);
-                Err(NetworkError::InternalError(anyhow!("{}", err)))
-            }
-        };
+        serve_car(cid, cid, signature, headers, interface, request_id).await
     } else {
-        return Err(NetworkError::InternalError(anyhow!(
+        Err(ApiError::invalid_params(format!(
+            "Invalid Cid String, Cannot Parse {} to CID",
+            &cid_str
+        ))
+        .with_details(json!({ "cid": cid_str }))
+        .with_request_id(&request_id))
+    }
+}
+
+/// Serve the entry at `path` under a root cid, a listing when it is a UnixFS
+/// directory and its car file otherwise.
+pub async fn ipfs_path_handler<S>(
+    Path((cid_str, path)): Path<(String, String)>,
+    Query(signature): Query<SignatureQuery>,
+    headers: HeaderMap,
+    Extension(interface): Extension<Arc<NodeNetworkInterface<S>>>,
+    Extension(request_id): Extension<RequestId>,
+) -> Result<Response, ApiError>
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    let with_id = |err: anyhow::Error| ApiError::from(err).with_request_id(&request_id);
+    let root = Cid::from_str(&cid_str).map_err(|_| {
+        ApiError::invalid_params(format!(
             "Invalid Cid String, Cannot Parse {} to CID",
             &cid_str
-        )));
+        ))
+        .with_details(json!({ "cid": cid_str }))
+        .with_request_id(&request_id)
+    })?;
+    authorize(&root, &signature, &headers, &interface)
+        .map_err(|e| e.with_request_id(&request_id))?;
+
+    if !interface.store.blockstore().has(&root).unwrap_or(false) {
+        interface.get_data(root).await.map_err(with_id)?;
+    }
+    let path = path.trim_matches('/').to_string();
+    let resolved = interface
+        .resolve(root, path.clone())
+        .await
+        .map_err(with_id)?;
+    if !resolved.remaining_path.is_empty() {
+        return Err(
+            ApiError::not_found(format!("{root} has no UnixFS entry at /{path}"))
+                .with_request_id(&request_id),
+        );
+    }
+    let cid = Cid::from_str(&resolved.cid).map_err(|e| with_id(anyhow::anyhow!(e)))?;
+
+    match interface.list_directory(cid).await.map_err(with_id)? {
+        Some(entries) => {
+            let listing = Listing {
+                root: root.to_string(),
+                path,
+                cid: cid.to_string(),
+                entries,
+            };
+            let json = headers
+                .get(ACCEPT)
+                .and_then(|value| value.to_str().ok())
+                .map_or(false, |accept| accept.contains("application/json"));
+            if json {
+                Ok(Json(listing).into_response())
+            } else {
+                Ok(Html(listing.to_html()).into_response())
+            }
+        }
+        None => serve_car(root, cid, signature, headers, interface, request_id).await,
+    }
+}
+
+/// Serve the content a name or a DNSLink domain points at, like `/<cid>` does.
+pub async fn ipns_handler<S>(
+    Path(name): Path<String>,
+    Query(signature): Query<SignatureQuery>,
+    headers: HeaderMap,
+    Extension(interface): Extension<Arc<NodeNetworkInterface<S>>>,
+    Extension(request_id): Extension<RequestId>,
+) -> Result<impl IntoResponse, ApiError>
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    info!("Resolving /ipns/{name}");
+    let cid = interface
+        .resolve_ipns(&name)
+        .await
+        .map_err(|e| ApiError::from(e).with_request_id(&request_id))?;
+    serve_car(cid, cid, signature, headers, interface, request_id).await
+}
+
+/// Accept the receipt of a client for content streamed to it.
+pub async fn receipt_handler<S>(
+    Extension(interface): Extension<Arc<NodeNetworkInterface<S>>>,
+    Extension(request_id): Extension<RequestId>,
+    Json(receipt): Json<DeliveryReceipt>,
+) -> Result<impl IntoResponse, ApiError>
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    let cid = receipt.cid.clone();
+    interface
+        .receipts
+        .acknowledge(receipt)
+        .map_err(|e| e.with_request_id(&request_id))?;
+    Ok(Json(json!({ "cid": cid, "accepted": true })))
+}
+
+/// Check that the signature of the url and the acl of the content under `root` allow
+/// the request, returning the id of its api key.
+fn authorize<S>(
+    root: &Cid,
+    signature: &SignatureQuery,
+    headers: &HeaderMap,
+    interface: &NodeNetworkInterface<S>,
+) -> Result<Option<String>, ApiError>
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    interface
+        .signed_urls
+        .verify(root, signature.exp, signature.sig.as_deref(), unix_now())
+        .map_err(ApiError::from)?;
+    let key_id = headers
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|secret| interface.api_keys.key_id(secret).ok());
+    // a valid signed url grants access to private content as well
+    if signature.sig.is_none() && !interface.acl.allows_key(root, key_id.as_deref()) {
+        return Err(ApiError::forbidden(format!("{root} is private"))
+            .with_details(json!({ "cid": root.to_string() })));
     }
+    Ok(key_id)
+}
+
+/// Stream the dag under `cid`, found under `root`, as a car file once the signature
+/// of the url and the acl of the root allow it.
+async fn serve_car<S>(
+    root: Cid,
+    cid: Cid,
+    signature: SignatureQuery,
+    headers: HeaderMap,
+    interface: Arc<NodeNetworkInterface<S>>,
+    request_id: RequestId,
+) -> Result<Response, ApiError>
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    let key_id = authorize(&root, &signature, &headers, &interface)
+        .map_err(|e| e.with_request_id(&request_id))?;
+
+    let client_key = headers
+        .get(CLIENT_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    interface
+        .receipts
+        .check(client_key.as_deref())
+        .map_err(|e| e.with_request_id(&request_id))?;
+
+    let cache_hit = interface.store.blockstore().has(&cid).unwrap_or(false);
+    let range = headers.get(RANGE).and_then(|value| value.to_str().ok());
+    let (stream, range, size) = car_body(&interface, cid, range).await.map_err(|err| {
+        error!("{:?}", err);
+        ApiError::from(err).with_request_id(&request_id)
+    })?;
+    let (start, end) = match range {
+        ByteRange::Full => (0, size),
+        ByteRange::Partial(start, end) => (start, end),
+        ByteRange::Unsatisfiable => {
+            let content_range = format!("bytes */{size}");
+            return Ok((
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(CONTENT_RANGE, content_range)],
+            )
+                .into_response());
+        }
+    };
+
+    let access = AccessLogEntry::start(cid.to_string(), client_address(&headers), cache_hit);
+    let stream = ShapedStream::new(stream, Arc::clone(&interface.shaper), TrafficClass::Gateway);
+    let mut stream = LoggedStream::new(stream, access, Arc::clone(&interface.access_log))
+        .with_accounting(Arc::clone(&interface.accounting), key_id);
+    if let Some(client_key) = client_key {
+        stream = stream.with_receipt(Arc::clone(&interface.receipts), client_key);
+    }
+    let body = StreamBody::new(stream);
+    let mut res = Response::builder();
+    let headers = res.headers_mut().unwrap();
+    headers.insert(
+        CONTENT_TYPE,
+        "application/vnd.curl.car; charset=utf-8".parse().unwrap(),
+    );
+    headers.insert(
+        CONTENT_DISPOSITION,
+        format!("attachment; filename=\"{}.car\"", cid)
+            .parse()
+            .unwrap(),
+    );
+    headers.insert(ACCEPT_RANGES, "bytes".parse().unwrap());
+    headers.insert(CONTENT_LENGTH, (end - start).into());
+    let status = if let ByteRange::Partial(..) = range {
+        let content_range = format!("bytes {start}-{}/{size}", end - 1);
+        headers.insert(CONTENT_RANGE, content_range.parse().unwrap());
+        StatusCode::PARTIAL_CONTENT
+    } else {
+        StatusCode::OK
+    };
+
+    Ok(res.status(status).body(body).unwrap().into_response())
+}
+
+/// The car file of `cid` cut to the `range` header, served from the render cache when
+/// it holds the file. Popular roots are rendered into the cache on the side.
+async fn car_body<S>(
+    interface: &NodeNetworkInterface<S>,
+    cid: Cid,
+    range: Option<&str>,
+) -> anyhow::Result<(BoxStream<'static, io::Result<Bytes>>, ByteRange, u64)>
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    let cache = &interface.render_cache;
+    match cache.lookup(&cid) {
+        Cached::Hit { path, size } => {
+            let range = ByteRange::parse(range, size);
+            let (start, end) = match range {
+                ByteRange::Partial(start, end) => (start, end),
+                _ => (0, size),
+            };
+            match read_file(path, start, end).await {
+                Ok(file) => return Ok((file.boxed(), range, size)),
+                Err(err) => {
+                    warn!("Failed to read the cached car file of {cid}: {:?}", err);
+                    cache.remove(&cid);
+                }
+            }
+        }
+        Cached::Admit => match interface.stream(cid).await {
+            Ok(car) => cache.render(cid, car),
+            Err(err) => {
+                cache.remove(&cid);
+                return Err(err);
+            }
+        },
+        Cached::Miss => {}
+    }
+
+    let car = interface.stream(cid).await?;
+    let size = car.size();
+    let range = ByteRange::parse(range, size);
+    let body = match range {
+        ByteRange::Partial(start, end) => RangeStream::new(car, start, end).boxed(),
+        _ => car.boxed(),
+    };
+    Ok((body, range, size))
 }
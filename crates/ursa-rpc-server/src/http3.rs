@@ -0,0 +1,110 @@
+//! Experimental HTTP/3.
+//!
+//! With `http3_port` set on a tls listener, the same routes are served over QUIC on
+//! that udp port, and the tcp listener advertises it to browsers in an `Alt-Svc`
+//! header. Every request runs on a stream of its own, so a slow object does not hold
+//! up the others fetched on the connection.
+
+use anyhow::{anyhow, Result};
+use axum::body::BoxBody;
+use bytes::{Buf, Bytes};
+use h3::{quic::BidiStream, server::RequestStream};
+use hyper::{body::HttpBody, Body, Request, Response};
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+use tower::{Service, ServiceExt};
+use tracing::{debug, info};
+
+use crate::{config::TlsConfig, server::tls_config};
+
+/// Serve `service` over HTTP/3 on the udp port `address`, until the process exits.
+pub async fn serve<T>(address: SocketAddr, tls: &TlsConfig, service: T) -> Result<()>
+where
+    T: Service<Request<Body>, Response = Response<BoxBody>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    T::Future: Send + 'static,
+{
+    let mut crypto = tls_config(tls)?;
+    crypto.alpn_protocols = vec![b"h3".to_vec()];
+    let config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    let endpoint = quinn::Endpoint::server(config, address)?;
+    info!("listening on {} with http/3", address);
+
+    while let Some(connecting) = endpoint.accept().await {
+        let service = service.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve_connection(connecting, service).await {
+                debug!("HTTP/3 connection closed: {:?}", err);
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn serve_connection<T>(connecting: quinn::Connecting, service: T) -> Result<()>
+where
+    T: Service<Request<Body>, Response = Response<BoxBody>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    T::Future: Send + 'static,
+{
+    let connection = h3_quinn::Connection::new(connecting.await?);
+    let mut connection = h3::server::Connection::new(connection).await?;
+    while let Some((request, stream)) = connection.accept().await? {
+        let service = service.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve_request(request, stream, service).await {
+                debug!("HTTP/3 request failed: {:?}", err);
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn serve_request<T, S>(
+    request: Request<()>,
+    stream: RequestStream<S, Bytes>,
+    service: T,
+) -> Result<()>
+where
+    T: Service<Request<Body>, Response = Response<BoxBody>, Error = Infallible>,
+    S: BidiStream<Bytes>,
+    S::RecvStream: Send + 'static,
+{
+    let (mut send, mut recv) = stream.split();
+    // hand the request body to the routes as it arrives, uploads are not buffered
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        loop {
+            match recv.recv_data().await {
+                Ok(Some(mut data)) => {
+                    let chunk = data.copy_to_bytes(data.remaining());
+                    if sender.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => return,
+                Err(err) => {
+                    debug!("HTTP/3 request body failed: {:?}", err);
+                    sender.abort();
+                    return;
+                }
+            }
+        }
+    });
+
+    let (parts, ()) = request.into_parts();
+    let response = match service.oneshot(Request::from_parts(parts, body)).await {
+        Ok(response) => response,
+        Err(never) => match never {},
+    };
+    let (parts, mut body) = response.into_parts();
+    send.send_response(Response::from_parts(parts, ())).await?;
+    while let Some(chunk) = body.data().await {
+        send.send_data(chunk.map_err(|e| anyhow!(e))?).await?;
+    }
+    send.finish().await?;
+    Ok(())
+}
@@ -0,0 +1,354 @@
+//! Parallel car import.
+//!
+//! `load_car` reads a frame, writes its block and only then reads the next one, so a
+//! put goes at the pace of single block writes. [`import_car`] splits the work up:
+//! the calling task decodes the frames into batches of `batch_size` blocks, and
+//! `workers` tasks check that the blocks of a batch hash to their cids and write the
+//! batch to the store in one write, on the store threads. Every root of the car
+//! header has to be among the decoded blocks, so a car file cut short or naming
+//! roots it does not carry is not indexed and advertised as content that cannot be
+//! served.
+//!
+//! A car file going over `max_bytes` of block data, `max_blocks` blocks, or holding a
+//! block over `max_block_size`, fails with a `quota_exceeded` error as soon as the
+//! frame crossing the limit is decoded, so one request cannot fill the disk. The
+//! blocks written before stay in the store.
+//!
+//! The blocks of every written batch are counted as progress of the operation of the
+//! put, when it has one.
+
+use anyhow::{anyhow, Result};
+use async_std::{
+    channel::{bounded, Sender},
+    task,
+};
+use cid::Cid;
+use futures::AsyncRead;
+use fvm_ipld_car::CarReader;
+use ipld_blockstore::BlockStore;
+use libipld::{store::DefaultParams, Block};
+use serde_json::json;
+use std::{collections::HashSet, mem, sync::Arc};
+use ursa_store::{InvalidBlock, Store};
+use ursa_utils::ToIpldCid;
+
+use crate::{
+    config::CarImportConfig,
+    error::{ApiError, ErrorCode},
+    operations::{OperationId, Operations},
+};
+
+type Batch = Vec<(Cid, Vec<u8>)>;
+
+/// Store the blocks of the car file read from `reader`, returning the roots of its
+/// header. The written blocks are reported as progress of `operation`.
+pub async fn import_car<S, R>(
+    store: &Arc<Store<S>>,
+    reader: R,
+    config: &CarImportConfig,
+    operation: Option<(Arc<Operations>, OperationId)>,
+) -> Result<Vec<Cid>>
+where
+    S: BlockStore + Sync + Send + 'static,
+    R: AsyncRead + Send + Unpin,
+{
+    let mut car = CarReader::new(reader).await?;
+    let roots = car.header.roots.clone();
+
+    let workers = config.workers.max(1);
+    let (batches, queue) = bounded::<Batch>(workers);
+    let workers: Vec<_> = (0..workers)
+        .map(|_| {
+            let (store, queue) = (Arc::clone(store), queue.clone());
+            let operation = operation.clone();
+            task::spawn(async move {
+                let mut written = 0;
+                while let Ok(batch) = queue.recv().await {
+                    let len = batch.len();
+                    let result = store.blocking(move |store| write_batch(store, batch)).await;
+                    if let Err(e) = result.and_then(|result| result) {
+                        // no further batches are decoded for nothing
+                        queue.close();
+                        return Err(e);
+                    }
+                    written += len;
+                    if let Some((operations, id)) = &operation {
+                        operations.add_blocks(*id, len);
+                    }
+                }
+                Ok(written)
+            })
+        })
+        .collect();
+    drop(queue);
+
+    let mut missing: HashSet<Cid> = roots.iter().copied().collect();
+    let decoded = decode(&mut car, &batches, config, &mut missing).await;
+    drop(batches);
+    let mut written = 0;
+    let mut failure = None;
+    for worker in workers {
+        match worker.await {
+            Ok(len) => written += len,
+            Err(e) => {
+                failure.get_or_insert(e);
+            }
+        }
+    }
+    if let Some(e) = failure {
+        return Err(e);
+    }
+    let decoded = decoded?;
+    if written != decoded {
+        return Err(anyhow!(
+            "Only {written} of the {decoded} blocks of the car file were written"
+        ));
+    }
+
+    if !missing.is_empty() {
+        let missing: Vec<String> = roots
+            .iter()
+            .filter(|root| missing.contains(root))
+            .map(Cid::to_string)
+            .collect();
+        return Err(anyhow!(ApiError::new(
+            ErrorCode::Unprocessable,
+            format!(
+                "The car file does not contain its roots {}",
+                missing.join(", ")
+            ),
+        )
+        .with_details(json!({ "missing_roots": missing }))));
+    }
+    Ok(roots)
+}
+
+/// Send the blocks of `car` in batches, returning how many were sent. The roots among
+/// them are removed from `missing`.
+async fn decode<R>(
+    car: &mut CarReader<R>,
+    batches: &Sender<Batch>,
+    config: &CarImportConfig,
+    missing: &mut HashSet<Cid>,
+) -> Result<usize>
+where
+    R: AsyncRead + Send + Unpin,
+{
+    let batch_size = config.batch_size.max(1);
+    let (mut sent, mut bytes) = (0, 0);
+    let mut batch = Vec::with_capacity(batch_size);
+    while let Some(block) = car.next_block().await? {
+        let size = block.data.len();
+        bytes += size as u64;
+        if size > config.max_block_size {
+            return Err(limit_error(
+                format!("The block {} is {size} bytes", block.cid),
+                "max_block_size",
+                config.max_block_size as u64,
+            ));
+        }
+        if bytes > config.max_bytes {
+            return Err(limit_error(
+                "The car file holds more block data than allowed".to_string(),
+                "max_bytes",
+                config.max_bytes,
+            ));
+        }
+        if sent + batch.len() >= config.max_blocks {
+            return Err(limit_error(
+                "The car file holds more blocks than allowed".to_string(),
+                "max_blocks",
+                config.max_blocks as u64,
+            ));
+        }
+        missing.remove(&block.cid);
+        batch.push((block.cid, block.data));
+        if batch.len() == batch_size {
+            sent += batch.len();
+            if batches.send(mem::take(&mut batch)).await.is_err() {
+                // a worker failed and closed the queue
+                return Ok(sent);
+            }
+        }
+    }
+    if !batch.is_empty() {
+        sent += batch.len();
+        let _ = batches.send(batch).await;
+    }
+    Ok(sent)
+}
+
+/// Quota error of a car file going over the `limit` of `max`.
+fn limit_error(message: String, limit: &str, max: u64) -> anyhow::Error {
+    anyhow!(ApiError::new(
+        ErrorCode::QuotaExceeded,
+        format!("{message}, over the {limit} of {max}")
+    )
+    .with_details(json!({ "limit": limit, "max": max })))
+}
+
+/// Check the blocks of `batch` against their cids and write them all at once.
+fn write_batch<S>(store: &Store<S>, batch: Batch) -> Result<()>
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    let mut values = Vec::with_capacity(batch.len());
+    for (cid, data) in batch {
+        let (_, data) = Block::<DefaultParams>::new(cid.to_ipld_cid(), data)
+            .map_err(|_| InvalidBlock::HashMismatch(cid.to_ipld_cid()))?
+            .into_inner();
+        values.push((cid.to_bytes(), data));
+    }
+    store.blockstore().bulk_write(&values)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::OperationKind;
+    use db::{rocks::RocksDb, rocks_config::RocksDbConfig, MemoryDB};
+    use futures::io::Cursor;
+    use fvm_ipld_car::CarHeader;
+    use libipld::{cbor::DagCborCodec, ipld, multihash::Code, Ipld};
+    use ursa_utils::ToCid;
+
+    async fn car_file(roots: Vec<Cid>, blocks: &[Block<DefaultParams>]) -> Vec<u8> {
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        for block in blocks {
+            sender
+                .unbounded_send((block.cid().to_cid(), block.data().to_vec()))
+                .unwrap();
+        }
+        drop(sender);
+        let mut car = vec![];
+        CarHeader { roots, version: 1 }
+            .write_stream_async(&mut car, &mut receiver)
+            .await
+            .unwrap();
+        car
+    }
+
+    /// A root linking to five leaves, the root first.
+    fn dag() -> Vec<Block<DefaultParams>> {
+        let leaves: Vec<Block<DefaultParams>> = (0..5)
+            .map(|i| Block::encode(DagCborCodec, Code::Sha2_256, &ipld!({ "leaf": i })).unwrap())
+            .collect();
+        let links: Vec<Ipld> = leaves.iter().map(|leaf| Ipld::Link(*leaf.cid())).collect();
+        let root = Block::encode(DagCborCodec, Code::Sha2_256, &ipld!({ "links": links })).unwrap();
+        let mut blocks = vec![root];
+        blocks.extend(leaves);
+        blocks
+    }
+
+    fn config() -> CarImportConfig {
+        CarImportConfig {
+            workers: 3,
+            batch_size: 2,
+            ..Default::default()
+        }
+    }
+
+    #[async_std::test]
+    async fn test_import_car() {
+        let db = RocksDb::open("import_db", &RocksDbConfig::default()).unwrap();
+        let store = Arc::new(Store::new(Arc::new(db)));
+        let config = config();
+        let blocks = dag();
+        let root = blocks[0].clone();
+
+        let car = car_file(vec![root.cid().to_cid()], &blocks).await;
+        let operations = Arc::new(Operations::default());
+        let id = operations.start(OperationKind::Put, None);
+        let roots = import_car(
+            &store,
+            Cursor::new(car),
+            &config,
+            Some((Arc::clone(&operations), id)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(roots, vec![root.cid().to_cid()]);
+        assert_eq!(operations.status(id).unwrap().blocks, blocks.len());
+        for block in &blocks {
+            assert!(store.blockstore().has(&block.cid().to_cid()).unwrap());
+        }
+    }
+
+    #[async_std::test]
+    async fn test_missing_roots() {
+        let store = Arc::new(Store::new(Arc::new(MemoryDB::default())));
+        let blocks = dag();
+        let root = blocks[0].cid().to_cid();
+
+        // a root missing from the blocks is an error, even when it is stored already
+        let car = car_file(vec![root], &blocks).await;
+        import_car(&store, Cursor::new(car), &config(), None)
+            .await
+            .unwrap();
+        let car = car_file(vec![root], &blocks[1..]).await;
+        let err = import_car(&store, Cursor::new(car), &config(), None)
+            .await
+            .unwrap_err();
+        let err = err.downcast_ref::<ApiError>().unwrap();
+        assert_eq!(err.code, ErrorCode::Unprocessable);
+        assert_eq!(
+            err.details,
+            Some(json!({ "missing_roots": [root.to_string()] }))
+        );
+    }
+
+    #[async_std::test]
+    async fn test_import_limits() {
+        let store = Arc::new(Store::new(Arc::new(MemoryDB::default())));
+        let blocks = dag();
+        let root = blocks[0].cid().to_cid();
+        let bytes: usize = blocks.iter().map(|block| block.data().len()).sum();
+        let largest = blocks.iter().map(|block| block.data().len()).max().unwrap();
+
+        // a car right at the limits is imported
+        let exact = CarImportConfig {
+            max_blocks: blocks.len(),
+            max_bytes: bytes as u64,
+            max_block_size: largest,
+            ..config()
+        };
+        let car = car_file(vec![root], &blocks).await;
+        import_car(&store, Cursor::new(car), &exact, None)
+            .await
+            .unwrap();
+
+        let over = [
+            (
+                CarImportConfig {
+                    max_blocks: blocks.len() - 1,
+                    ..exact.clone()
+                },
+                json!({ "limit": "max_blocks", "max": blocks.len() - 1 }),
+            ),
+            (
+                CarImportConfig {
+                    max_bytes: bytes as u64 - 1,
+                    ..exact.clone()
+                },
+                json!({ "limit": "max_bytes", "max": bytes - 1 }),
+            ),
+            (
+                CarImportConfig {
+                    max_block_size: largest - 1,
+                    ..exact.clone()
+                },
+                json!({ "limit": "max_block_size", "max": largest - 1 }),
+            ),
+        ];
+        for (limited, details) in over {
+            let car = car_file(vec![root], &blocks).await;
+            let err = import_car(&store, Cursor::new(car), &limited, None)
+                .await
+                .unwrap_err();
+            let err = err.downcast_ref::<ApiError>().unwrap();
+            assert_eq!(err.code, ErrorCode::QuotaExceeded);
+            assert_eq!(err.details, Some(details));
+        }
+    }
+}
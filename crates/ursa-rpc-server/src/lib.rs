@@ -1,8 +1,31 @@
+pub mod access_log;
 pub mod api;
+pub mod api_keys;
+pub mod car;
+pub mod compaction;
 pub mod config;
+pub mod content;
+pub mod diagnostics;
+pub mod directory;
+pub mod dnslink;
+mod download;
+pub mod error;
 pub mod http;
+mod http3;
+mod import;
+pub mod operations;
+pub mod origin;
+mod prefetch;
+pub mod receipts;
+pub mod render_cache;
+pub mod resolve;
 pub mod rpc;
 pub mod server;
 mod service;
+pub mod settings;
+pub mod signed_url;
+mod singleflight;
+mod unixfs;
+pub mod webhooks;
 
 pub use self::rpc::*;
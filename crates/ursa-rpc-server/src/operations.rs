@@ -0,0 +1,303 @@
+//! Long-running operation tracking.
+//!
+//! Puts and dag syncing gets can take minutes for large content. Each of them is
+//! registered with [`Operations`] under an [`OperationId`] and reports the blocks and
+//! bytes it handled so far, compactions the bytes they reclaimed. The latest status can be polled with
+//! `ursa_operation_status`, and every change is broadcast to the `/operations`
+//! websocket so UIs can render progress bars. Gets and prefetches register the root
+//! cids they sync, so `ursa_cancel` can stop them.
+
+use cid::Cid;
+use futures::AsyncRead;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+use tokio::sync::broadcast;
+
+pub type OperationId = u64;
+
+/// Finished operations kept for `ursa_operation_status`.
+const MAX_FINISHED: usize = 1024;
+
+/// Buffered progress events per websocket subscriber.
+const EVENT_BUFFER: usize = 256;
+
+/// Bytes read between two progress events of a put.
+const BYTES_PER_EVENT: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OperationKind {
+    Put,
+    Get,
+    Prefetch,
+    Compact,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum OperationState {
+    Running,
+    Done,
+    Failed { error: String },
+    Cancelled,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OperationStatus {
+    pub id: OperationId,
+    pub kind: OperationKind,
+    /// Root cid of the content, once known.
+    pub cid: Option<String>,
+    pub state: OperationState,
+    /// Blocks fetched from the network or written to the store so far.
+    pub blocks: usize,
+    /// Bytes fetched or read so far.
+    pub bytes: u64,
+    /// Blocks of the dag still missing, reported while syncing, or column families
+    /// left to compact.
+    pub missing: Option<usize>,
+    /// Column family being compacted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub column: Option<String>,
+}
+
+pub struct Operations {
+    next_id: AtomicU64,
+    statuses: Mutex<BTreeMap<OperationId, OperationStatus>>,
+    /// Root cids synced by the running operations that can be cancelled.
+    roots: Mutex<HashMap<OperationId, Vec<Cid>>>,
+    events: broadcast::Sender<OperationStatus>,
+}
+
+impl Default for Operations {
+    fn default() -> Self {
+        let (events, _) = broadcast::channel(EVENT_BUFFER);
+        Self {
+            next_id: AtomicU64::new(1),
+            statuses: Default::default(),
+            roots: Default::default(),
+            events,
+        }
+    }
+}
+
+impl Operations {
+    /// Register a running operation.
+    pub fn start(&self, kind: OperationKind, cid: Option<Cid>) -> OperationId {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let status = OperationStatus {
+            id,
+            kind,
+            cid: cid.map(|cid| cid.to_string()),
+            state: OperationState::Running,
+            blocks: 0,
+            bytes: 0,
+            missing: None,
+            column: None,
+        };
+
+        let mut statuses = self.statuses.lock().unwrap();
+        statuses.insert(id, status.clone());
+        self.prune(&mut statuses);
+        drop(statuses);
+
+        let _ = self.events.send(status);
+        id
+    }
+
+    /// Apply `update` to the status of `id` and broadcast the result.
+    pub fn update<F: FnOnce(&mut OperationStatus)>(&self, id: OperationId, update: F) {
+        let status = {
+            let mut statuses = self.statuses.lock().unwrap();
+            match statuses.get_mut(&id) {
+                Some(status) => {
+                    update(status);
+                    status.clone()
+                }
+                None => return,
+            }
+        };
+        let _ = self.events.send(status);
+    }
+
+    /// Count `bytes` read by `id`, broadcasting once every [`BYTES_PER_EVENT`].
+    pub fn add_bytes(&self, id: OperationId, bytes: u64) {
+        let status = {
+            let mut statuses = self.statuses.lock().unwrap();
+            match statuses.get_mut(&id) {
+                Some(status) => {
+                    let before = status.bytes / BYTES_PER_EVENT;
+                    status.bytes += bytes;
+                    (status.bytes / BYTES_PER_EVENT != before).then(|| status.clone())
+                }
+                None => None,
+            }
+        };
+        if let Some(status) = status {
+            let _ = self.events.send(status);
+        }
+    }
+
+    /// Count `blocks` written by `id` and broadcast the result.
+    pub fn add_blocks(&self, id: OperationId, blocks: usize) {
+        if blocks > 0 {
+            self.update(id, |status| status.blocks += blocks);
+        }
+    }
+
+    /// Make `id` cancellable, by cancelling the syncs of `roots`.
+    pub fn add_roots(&self, id: OperationId, roots: &[Cid]) {
+        self.roots
+            .lock()
+            .unwrap()
+            .entry(id)
+            .or_default()
+            .extend_from_slice(roots);
+    }
+
+    /// Mark the running `id` as cancelled, returning the roots whose syncs to cancel.
+    /// `None` when `id` is not running or cannot be cancelled.
+    pub fn cancel(&self, id: OperationId) -> Option<Vec<Cid>> {
+        let running = self
+            .status(id)
+            .map_or(false, |status| status.state == OperationState::Running);
+        if !running {
+            return None;
+        }
+        let roots = self.roots.lock().unwrap().remove(&id)?;
+        self.update(id, |status| status.state = OperationState::Cancelled);
+        Some(roots)
+    }
+
+    /// Mark `id` as finished with `result`, unless it was cancelled.
+    pub fn finish<T, E: std::fmt::Display>(&self, id: OperationId, result: &Result<T, E>) {
+        self.roots.lock().unwrap().remove(&id);
+        self.update(id, |status| {
+            if status.state == OperationState::Cancelled {
+                return;
+            }
+            status.state = match result {
+                Ok(_) => OperationState::Done,
+                Err(err) => OperationState::Failed {
+                    error: err.to_string(),
+                },
+            };
+        });
+    }
+
+    pub fn status(&self, id: OperationId) -> Option<OperationStatus> {
+        self.statuses.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Receive every status change from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<OperationStatus> {
+        self.events.subscribe()
+    }
+
+    /// Forget the oldest finished operations beyond [`MAX_FINISHED`].
+    fn prune(&self, statuses: &mut BTreeMap<OperationId, OperationStatus>) {
+        let finished: Vec<OperationId> = statuses
+            .values()
+            .filter(|status| status.state != OperationState::Running)
+            .map(|status| status.id)
+            .collect();
+        for id in finished
+            .iter()
+            .take(finished.len().saturating_sub(MAX_FINISHED))
+        {
+            statuses.remove(id);
+        }
+    }
+}
+
+/// Reader reporting the bytes read through it as progress of an operation.
+pub struct ProgressReader<R> {
+    inner: R,
+    operations: Arc<Operations>,
+    id: OperationId,
+}
+
+impl<R> ProgressReader<R> {
+    pub fn new(inner: R, operations: Arc<Operations>, id: OperationId) -> Self {
+        Self {
+            inner,
+            operations,
+            id,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ProgressReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(read)) = &poll {
+            self.operations.add_bytes(self.id, *read as u64);
+        }
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::AsyncReadExt;
+    use std::str::FromStr;
+
+    #[async_std::test]
+    async fn test_operation_progress() {
+        let operations = Arc::new(Operations::default());
+        let mut events = operations.subscribe();
+
+        let id = operations.start(OperationKind::Put, None);
+        assert_eq!(events.recv().await.unwrap().state, OperationState::Running);
+
+        let data = vec![0u8; BYTES_PER_EVENT as usize + 1];
+        let mut reader = ProgressReader::new(&data[..], Arc::clone(&operations), id);
+        let mut read = vec![];
+        reader.read_to_end(&mut read).await.unwrap();
+        assert!(events.recv().await.unwrap().bytes >= BYTES_PER_EVENT);
+
+        operations.add_blocks(id, 3);
+        assert_eq!(events.recv().await.unwrap().blocks, 3);
+
+        operations.finish::<_, String>(id, &Ok(()));
+        let status = operations.status(id).unwrap();
+        assert_eq!(status.state, OperationState::Done);
+        assert_eq!(status.bytes, data.len() as u64);
+    }
+
+    #[test]
+    fn test_cancel_operation() {
+        let operations = Operations::default();
+        let cid =
+            Cid::from_str("bafkreif2opfibjypwkjzzry3jbibcjqcjwnpoqpeiqw75eu3s3u3zbdszq").unwrap();
+
+        let put = operations.start(OperationKind::Put, None);
+        assert_eq!(operations.cancel(put), None);
+
+        let get = operations.start(OperationKind::Get, Some(cid));
+        operations.add_roots(get, &[cid]);
+        assert_eq!(operations.cancel(get), Some(vec![cid]));
+        assert_eq!(operations.cancel(get), None);
+
+        // the failure of the cancelled sync does not overwrite the state
+        operations.finish(get, &Err("The query was cancelled"));
+        assert_eq!(
+            operations.status(get).unwrap().state,
+            OperationState::Cancelled
+        );
+    }
+}
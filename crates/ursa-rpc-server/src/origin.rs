@@ -0,0 +1,176 @@
+//! Origin fallback for cache misses.
+//!
+//! When a cid can be found neither in the local store nor via bitswap, the
+//! [`Origin`] pulls the content as a CAR from the configured http origin or ipfs
+//! gateway so the node can ingest and serve it, like a pull-through CDN cache. The
+//! car is streamed through the same checks and limits as car uploads, and has to be
+//! rooted at the cid asked for.
+
+use anyhow::{anyhow, Result};
+use cid::Cid;
+use ipld_blockstore::BlockStore;
+use std::{sync::Arc, time::Duration};
+use tracing::info;
+use ursa_store::Store;
+
+use crate::{
+    config::{CarImportConfig, OriginConfig},
+    import,
+};
+
+#[derive(Clone, Debug, Default)]
+pub struct Origin {
+    config: OriginConfig,
+}
+
+impl Origin {
+    pub fn new(config: OriginConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.ipfs_gateway.is_some()
+    }
+
+    /// How long to wait on the network before hitting the origin.
+    pub fn bitswap_timeout(&self) -> Duration {
+        Duration::from_millis(self.config.bitswap_timeout_ms)
+    }
+
+    /// Pull the dag under `cid` from the origin into `store`, returning the roots of
+    /// the car file.
+    pub async fn pull<S>(
+        &self,
+        store: &Arc<Store<S>>,
+        cid: &Cid,
+        limits: &CarImportConfig,
+    ) -> Result<Vec<Cid>>
+    where
+        S: BlockStore + Sync + Send + 'static,
+    {
+        let car = self.fetch_car(cid).await?;
+        let roots = import::import_car(store, car, limits, None).await?;
+        if !roots.contains(cid) {
+            return Err(anyhow!(
+                "The origin answered {} with a car file rooted at {:?}",
+                cid,
+                roots
+            ));
+        }
+        Ok(roots)
+    }
+
+    /// Request the full dag under `cid` as a car file, the response streams its body.
+    async fn fetch_car(&self, cid: &Cid) -> Result<surf::Response> {
+        let gateway = self
+            .config
+            .ipfs_gateway
+            .as_ref()
+            .ok_or_else(|| anyhow!("No origin configured"))?;
+        let url = format!("{}/ipfs/{}?format=car", gateway.trim_end_matches('/'), cid);
+
+        info!("Fetching {cid} from origin {url}");
+        let res = surf::get(&url)
+            .header("Accept", "application/vnd.ipld.car")
+            .await
+            .map_err(|e| anyhow!("Origin request to {} failed: {}", url, e))?;
+
+        if !res.status().is_success() {
+            return Err(anyhow!(
+                "Origin responded with status {} for {}",
+                res.status(),
+                cid
+            ));
+        }
+
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::{
+        io::{ReadExt, WriteExt},
+        net::TcpListener,
+        task,
+    };
+    use db::{rocks::RocksDb, rocks_config::RocksDbConfig};
+    use fvm_ipld_car::CarHeader;
+    use libipld::{cbor::DagCborCodec, ipld, multihash::Code, store::DefaultParams, Block, Ipld};
+    use ursa_utils::ToCid;
+
+    /// Origin answering every request with `body`.
+    async fn serve(body: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        task::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request).await;
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/vnd.ipld.car\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(&body).await;
+            }
+        });
+        format!("http://{addr}")
+    }
+
+    async fn car_file(root: Cid, blocks: &[Block<DefaultParams>]) -> Vec<u8> {
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        for block in blocks {
+            sender
+                .unbounded_send((block.cid().to_cid(), block.data().to_vec()))
+                .unwrap();
+        }
+        drop(sender);
+        let mut car = vec![];
+        CarHeader {
+            roots: vec![root],
+            version: 1,
+        }
+        .write_stream_async(&mut car, &mut receiver)
+        .await
+        .unwrap();
+        car
+    }
+
+    #[async_std::test]
+    async fn test_pull() {
+        let db = RocksDb::open("origin_db", &RocksDbConfig::default()).unwrap();
+        let store = Arc::new(Store::new(Arc::new(db)));
+        let limits = CarImportConfig::default();
+
+        let leaf = Block::encode(DagCborCodec, Code::Sha2_256, &ipld!({ "leaf": 1 })).unwrap();
+        let root = Block::encode(
+            DagCborCodec,
+            Code::Sha2_256,
+            &ipld!({ "link": Ipld::Link(*leaf.cid()) }),
+        )
+        .unwrap();
+        let root_cid = root.cid().to_cid();
+        let blocks = [root.clone(), leaf.clone()];
+
+        let origin = Origin::new(OriginConfig {
+            ipfs_gateway: Some(serve(car_file(root_cid, &blocks).await).await),
+            ..Default::default()
+        });
+        let roots = origin.pull(&store, &root_cid, &limits).await.unwrap();
+        assert_eq!(roots, vec![root_cid]);
+        assert!(store.blockstore().has(&leaf.cid().to_cid()).unwrap());
+
+        // a car rooted elsewhere does not stand in for the cid
+        let other = leaf.cid().to_cid();
+        assert!(origin.pull(&store, &other, &limits).await.is_err());
+
+        // nor one going over the import limits
+        let limited = CarImportConfig {
+            max_blocks: 1,
+            ..Default::default()
+        };
+        assert!(origin.pull(&store, &root_cid, &limited).await.is_err());
+    }
+}
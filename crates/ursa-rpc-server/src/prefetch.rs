@@ -0,0 +1,170 @@
+//! Cache warming.
+//!
+//! [`PrefetchTracker`] records the progress of every root cid handed to
+//! `ursa_prefetch` and caps how many of them are synced at the same time, so an
+//! operator pre-positioning a large batch does not starve regular retrievals.
+//! Finished prefetches are forgotten after [`STATUS_TTL`], or sooner, oldest first,
+//! once more than [`MAX_STATUSES`] cids are tracked.
+
+use async_std::sync::RwLock;
+use cid::Cid;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::api::{PrefetchProgress, PrefetchStatus};
+
+/// Number of root cids synced concurrently across all prefetch requests.
+pub const PREFETCH_CONCURRENCY: usize = 4;
+/// Time the result of a finished prefetch is kept for.
+pub const STATUS_TTL: Duration = Duration::from_secs(60 * 60);
+/// Cids tracked before finished ones are forgotten early.
+pub const MAX_STATUSES: usize = 10_000;
+
+pub(crate) struct PrefetchTracker {
+    /// Status of each cid, with the time it was last updated.
+    statuses: RwLock<HashMap<Cid, (PrefetchStatus, Instant)>>,
+    permits: Semaphore,
+}
+
+impl Default for PrefetchTracker {
+    fn default() -> Self {
+        Self::new(PREFETCH_CONCURRENCY)
+    }
+}
+
+impl PrefetchTracker {
+    pub fn new(concurrency: usize) -> Self {
+        Self {
+            statuses: Default::default(),
+            permits: Semaphore::new(concurrency),
+        }
+    }
+
+    /// Mark `cids` as queued, replacing the result of any previous prefetch.
+    pub async fn queue(&self, cids: &[Cid]) {
+        let mut statuses = self.statuses.write().await;
+        let now = Instant::now();
+        for cid in cids {
+            statuses.insert(*cid, (PrefetchStatus::Queued, now));
+        }
+        prune(&mut statuses, now);
+    }
+
+    /// Wait for a sync slot.
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        self.permits
+            .acquire()
+            .await
+            .expect("the prefetch semaphore is never closed")
+    }
+
+    pub async fn set(&self, cid: Cid, status: PrefetchStatus) {
+        let mut statuses = self.statuses.write().await;
+        let now = Instant::now();
+        statuses.insert(cid, (status, now));
+        prune(&mut statuses, now);
+    }
+
+    /// Progress of `cids`, or of every tracked cid when `cids` is empty.
+    pub async fn progress(&self, cids: &[Cid]) -> Vec<PrefetchProgress> {
+        let statuses = self.statuses.read().await;
+        let progress = |(cid, (status, _)): (&Cid, &(PrefetchStatus, Instant))| PrefetchProgress {
+            cid: cid.to_string(),
+            status: status.clone(),
+        };
+
+        if cids.is_empty() {
+            statuses.iter().map(progress).collect()
+        } else {
+            cids.iter()
+                .filter_map(|cid| statuses.get_key_value(cid))
+                .map(progress)
+                .collect()
+        }
+    }
+}
+
+fn is_finished(status: &PrefetchStatus) -> bool {
+    matches!(
+        status,
+        PrefetchStatus::Done { .. } | PrefetchStatus::Failed { .. }
+    )
+}
+
+/// Forget the expired results, and the oldest ones while over [`MAX_STATUSES`].
+/// Queued and running prefetches are kept.
+fn prune(statuses: &mut HashMap<Cid, (PrefetchStatus, Instant)>, now: Instant) {
+    statuses.retain(|_, (status, updated)| {
+        !is_finished(status) || now.duration_since(*updated) < STATUS_TTL
+    });
+    if statuses.len() <= MAX_STATUSES {
+        return;
+    }
+    let mut finished: Vec<(Instant, Cid)> = statuses
+        .iter()
+        .filter(|(_, (status, _))| is_finished(status))
+        .map(|(cid, (_, updated))| (*updated, *cid))
+        .collect();
+    finished.sort();
+    let excess = statuses.len() - MAX_STATUSES;
+    for (_, cid) in finished.into_iter().take(excess) {
+        statuses.remove(&cid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[async_std::test]
+    async fn test_progress() {
+        let tracker = PrefetchTracker::new(1);
+        let first =
+            Cid::from_str("bafkreif2opfibjypwkjzzry3jbibcjqcjwnpoqpeiqw75eu3s3u3zbdszq").unwrap();
+        let second =
+            Cid::from_str("bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku").unwrap();
+
+        tracker.queue(&[first, second]).await;
+        tracker.set(first, PrefetchStatus::Done { blocks: 3 }).await;
+
+        let progress = tracker.progress(&[first]).await;
+        assert_eq!(progress.len(), 1);
+        assert_eq!(progress[0].status, PrefetchStatus::Done { blocks: 3 });
+        assert_eq!(tracker.progress(&[]).await.len(), 2);
+
+        // a single slot is handed out one at a time
+        let permit = tracker.acquire().await;
+        assert!(tracker.permits.try_acquire().is_err());
+        drop(permit);
+        assert!(tracker.permits.try_acquire().is_ok());
+    }
+
+    #[test]
+    fn test_prune() {
+        use cid::multihash::{Code, MultihashDigest};
+
+        let start = Instant::now();
+        let cid = |i: u64| Cid::new_v1(0x55, Code::Sha2_256.digest(&i.to_be_bytes()));
+        let mut statuses = HashMap::new();
+        statuses.insert(cid(0), (PrefetchStatus::Done { blocks: 1 }, start));
+        statuses.insert(cid(1), (PrefetchStatus::Fetching, start));
+        for i in 2..MAX_STATUSES as u64 + 3 {
+            let status = PrefetchStatus::Failed {
+                error: "unreachable".to_string(),
+            };
+            statuses.insert(cid(i), (status, start + Duration::from_millis(i)));
+        }
+        prune(&mut statuses, start + STATUS_TTL);
+
+        // expired and oldest results go first, running prefetches stay
+        assert_eq!(statuses.len(), MAX_STATUSES);
+        assert!(!statuses.contains_key(&cid(0)));
+        assert!(statuses.contains_key(&cid(1)));
+        assert!(!statuses.contains_key(&cid(2)));
+        assert!(statuses.contains_key(&cid(MAX_STATUSES as u64 + 2)));
+    }
+}
@@ -0,0 +1,254 @@
+//! Delivery receipts.
+//!
+//! Clients retrieving over http name their key in the `x-ursa-client-key` header,
+//! the hex of their protobuf encoded libp2p public key. The bytes streamed to each
+//! key are kept as pending deliveries until the client posts a [`DeliveryReceipt`]
+//! to `/receipts`, signed with that key, acknowledging the cid and the bytes it
+//! received. Accepted receipts are kept for the `ursa_receipts` rpc and appended
+//! as json lines to an accounting log, to be claimed as proof of delivery.
+//!
+//! With `require` set, retrievals without a client key are refused, and with
+//! `max_unacknowledged` a client holding that many unacknowledged deliveries is
+//! refused until it acknowledges them.
+
+use anyhow::{anyhow, Result};
+use fnv::FnvHashMap;
+use libp2p::identity::{Keypair, PublicKey};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{error, warn};
+use ursa_utils::{hex, unhex};
+
+use crate::{
+    config::ReceiptConfig,
+    error::{ApiError, ErrorCode},
+};
+
+/// Header naming the key of the client a retrieval is delivered to.
+pub const CLIENT_KEY_HEADER: &str = "x-ursa-client-key";
+
+/// Accepted receipts kept in memory for the `ursa_receipts` rpc.
+const RECENT_RECEIPTS: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeliveryReceipt {
+    pub cid: String,
+    /// Bytes of the car file the client received.
+    pub bytes: u64,
+    /// Unix time in milliseconds the client signed the receipt at.
+    pub timestamp: u64,
+    /// Hex of the protobuf encoded public key of the client.
+    pub client_key: String,
+    /// Hex of the signature of the client over the other fields.
+    pub signature: String,
+}
+
+impl DeliveryReceipt {
+    /// Acknowledge the delivery of `bytes` of `cid` with the key of a client.
+    pub fn sign(keypair: &Keypair, cid: &str, bytes: u64) -> Result<Self> {
+        let mut receipt = Self {
+            cid: cid.to_string(),
+            bytes,
+            timestamp: now_ms(),
+            client_key: hex(&keypair.public().to_protobuf_encoding()),
+            signature: String::new(),
+        };
+        receipt.signature = hex(&keypair.sign(&receipt.signed_bytes())?);
+        Ok(receipt)
+    }
+
+    pub fn verify(&self) -> Result<()> {
+        let public_key = unhex(&self.client_key)
+            .and_then(|key| PublicKey::from_protobuf_encoding(&key).ok())
+            .ok_or_else(|| anyhow!("The client key of the receipt does not decode"))?;
+        let signed = unhex(&self.signature)
+            .map_or(false, |sig| public_key.verify(&self.signed_bytes(), &sig));
+        if !signed {
+            return Err(anyhow!(
+                "The receipt for {} has an invalid signature",
+                self.cid
+            ));
+        }
+        Ok(())
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        format!(
+            "ursa-receipt:{}:{}:{}:{}",
+            self.cid, self.bytes, self.timestamp, self.client_key
+        )
+        .into_bytes()
+    }
+}
+
+pub struct Receipts {
+    config: ReceiptConfig,
+    /// Bytes delivered and not acknowledged yet, by client key and cid.
+    pending: Mutex<FnvHashMap<(String, String), u64>>,
+    recent: Mutex<VecDeque<DeliveryReceipt>>,
+    file: Mutex<Option<File>>,
+}
+
+impl Default for Receipts {
+    fn default() -> Self {
+        Self::new(ReceiptConfig::default())
+    }
+}
+
+impl Receipts {
+    pub fn new(config: ReceiptConfig) -> Self {
+        let file = config.path.as_ref().and_then(|path| {
+            let opened = path
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| OpenOptions::new().create(true).append(true).open(path));
+            opened
+                .map_err(|e| error!("Failed to open the receipt log at {:?}: {:?}", path, e))
+                .ok()
+        });
+        Self {
+            config,
+            pending: Default::default(),
+            recent: Default::default(),
+            file: Mutex::new(file),
+        }
+    }
+
+    /// Check that a retrieval by the client with `client_key` may be served.
+    pub fn check(&self, client_key: Option<&str>) -> Result<(), ApiError> {
+        let client_key = match client_key {
+            Some(client_key) => client_key,
+            None if self.config.require => {
+                return Err(ApiError::new(
+                    ErrorCode::Unauthorized,
+                    format!("Retrievals must name a client key in {CLIENT_KEY_HEADER}"),
+                ))
+            }
+            None => return Ok(()),
+        };
+        let max = self.config.max_unacknowledged;
+        let pending = self.pending.lock().unwrap();
+        if max > 0 && pending.keys().filter(|(key, _)| key == client_key).count() >= max {
+            return Err(ApiError::new(
+                ErrorCode::QuotaExceeded,
+                format!("The client has {max} unacknowledged deliveries"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Record `bytes` of `cid` streamed to the client with `client_key`.
+    pub fn delivered(&self, client_key: &str, cid: &str, bytes: u64) {
+        if bytes == 0 {
+            return;
+        }
+        *self
+            .pending
+            .lock()
+            .unwrap()
+            .entry((client_key.to_string(), cid.to_string()))
+            .or_default() += bytes;
+    }
+
+    /// Accept `receipt` for a pending delivery of at least its bytes.
+    pub fn acknowledge(&self, receipt: DeliveryReceipt) -> Result<(), ApiError> {
+        receipt
+            .verify()
+            .map_err(|e| ApiError::new(ErrorCode::Unauthorized, e.to_string()))?;
+        {
+            let mut pending = self.pending.lock().unwrap();
+            let key = (receipt.client_key.clone(), receipt.cid.clone());
+            match pending.get(&key) {
+                Some(delivered) if *delivered >= receipt.bytes => {
+                    pending.remove(&key);
+                }
+                Some(delivered) => {
+                    return Err(ApiError::invalid_params(format!(
+                        "The receipt acknowledges {} bytes of {}, {} were delivered",
+                        receipt.bytes, receipt.cid, delivered
+                    )))
+                }
+                None => {
+                    return Err(ApiError::not_found(format!(
+                        "No delivery of {} to the client is pending",
+                        receipt.cid
+                    )))
+                }
+            }
+        }
+
+        if let Some(file) = self.file.lock().unwrap().as_mut() {
+            let written = serde_json::to_vec(&receipt).map(|mut line| {
+                line.push(b'\n');
+                file.write_all(&line)
+            });
+            if !matches!(written, Ok(Ok(()))) {
+                warn!("Failed to write the receipt for {} to the log", receipt.cid);
+            }
+        }
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == RECENT_RECEIPTS {
+            recent.pop_front();
+        }
+        recent.push_back(receipt);
+        Ok(())
+    }
+
+    /// The most recent receipts first, optionally only those for `cid`.
+    pub fn recent(&self, limit: usize, cid: Option<&str>) -> Vec<DeliveryReceipt> {
+        self.recent
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|receipt| cid.map_or(true, |cid| receipt.cid == cid))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_receipts() {
+        let receipts = Receipts::new(ReceiptConfig {
+            path: None,
+            require: true,
+            max_unacknowledged: 1,
+        });
+        let keypair = Keypair::generate_ed25519();
+        let receipt = DeliveryReceipt::sign(&keypair, "bafy", 100).unwrap();
+        let key = receipt.client_key.clone();
+        assert!(receipts.check(None).is_err());
+        assert!(receipts.check(Some(&key)).is_ok());
+
+        // nothing was delivered yet
+        assert!(receipts.acknowledge(receipt.clone()).is_err());
+        receipts.delivered(&key, "bafy", 60);
+        assert!(receipts.check(Some(&key)).is_err());
+        assert!(receipts.acknowledge(receipt.clone()).is_err());
+
+        receipts.delivered(&key, "bafy", 40);
+        let mut forged = receipt.clone();
+        forged.bytes = 10;
+        assert!(receipts.acknowledge(forged).is_err());
+        assert!(receipts.acknowledge(receipt.clone()).is_ok());
+        assert!(receipts.check(Some(&key)).is_ok());
+        assert_eq!(receipts.recent(10, Some("bafy")), vec![receipt]);
+    }
+}
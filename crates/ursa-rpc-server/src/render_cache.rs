@@ -0,0 +1,477 @@
+//! Cache of rendered car files.
+//!
+//! Every retrieval over http walks the dag and frames its blocks into a car file
+//! again. Once a root was asked for `min_requests` times its car file is rendered
+//! into a directory of its own, separate from the blockstore, and later requests are
+//! served from that file. `Range` requests, as video players make for every segment,
+//! seek into the file instead of re-stitching the dag. The least recently served
+//! files are removed to stay under `max_bytes`.
+
+use anyhow::{anyhow, Result};
+use async_std::{
+    fs as async_fs,
+    io::{prelude::SeekExt, ReadExt, SeekFrom},
+    task,
+};
+use bytes::Bytes;
+use cid::Cid;
+use fnv::{FnvHashMap, FnvHashSet};
+use futures::{stream, AsyncWriteExt, Stream, StreamExt};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+use tracing::{debug, error, warn};
+
+use crate::config::RenderCacheConfig;
+
+/// Bytes read from a cached file per chunk.
+const READ_CHUNK: usize = 256 * 1024;
+
+/// Roots whose requests are counted towards admission, the counts start over past it.
+const MAX_COUNTED: usize = 100_000;
+
+/// Range of a `Range: bytes=...` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    /// No range, or one that is not understood, serve everything.
+    Full,
+    /// From the first byte up to, not including, the second.
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    /// Range of a body of `size` bytes asked for by `header`. Only single ranges are
+    /// served, others get the whole body.
+    pub fn parse(header: Option<&str>, size: u64) -> Self {
+        let spec = match header.and_then(|header| header.trim().strip_prefix("bytes=")) {
+            Some(spec) if !spec.contains(',') => spec,
+            _ => return ByteRange::Full,
+        };
+        let (start, end) = match spec.split_once('-') {
+            Some(bounds) => bounds,
+            None => return ByteRange::Full,
+        };
+        let (start, end) = match (start.trim(), end.trim()) {
+            ("", suffix) => match u64::from_str(suffix) {
+                Ok(suffix) if suffix > 0 => (size.saturating_sub(suffix), size),
+                Ok(_) => return ByteRange::Unsatisfiable,
+                Err(_) => return ByteRange::Full,
+            },
+            (start, "") => match u64::from_str(start) {
+                Ok(start) => (start, size),
+                Err(_) => return ByteRange::Full,
+            },
+            (start, end) => match (u64::from_str(start), u64::from_str(end)) {
+                (Ok(start), Ok(end)) if start <= end => (start, size.min(end + 1)),
+                _ => return ByteRange::Full,
+            },
+        };
+        if start >= size {
+            return ByteRange::Unsatisfiable;
+        }
+        ByteRange::Partial(start, end)
+    }
+}
+
+/// Outcome of looking a root up in the cache.
+pub enum Cached {
+    Hit {
+        path: PathBuf,
+        size: u64,
+    },
+    /// Not cached yet, but asked for often enough to be.
+    Admit,
+    Miss,
+}
+
+struct Entry {
+    size: u64,
+    /// Tick of the last request served from the file.
+    used: u64,
+}
+
+#[derive(Default)]
+struct State {
+    entries: FnvHashMap<Cid, Entry>,
+    total: u64,
+    tick: u64,
+    /// Requests of the roots not cached yet.
+    requests: FnvHashMap<Cid, u32>,
+    /// Roots being rendered into the cache.
+    rendering: FnvHashSet<Cid>,
+}
+
+pub struct RenderCache {
+    config: RenderCacheConfig,
+    /// Bytes of files kept, `max_bytes` of the config until changed.
+    max_bytes: AtomicU64,
+    state: Mutex<State>,
+}
+
+impl Default for RenderCache {
+    fn default() -> Self {
+        Self::new(RenderCacheConfig::default())
+    }
+}
+
+impl RenderCache {
+    /// Open the cache directory, keeping the files rendered before a restart.
+    pub fn new(config: RenderCacheConfig) -> Self {
+        let mut state = State::default();
+        if let Some(dir) = &config.path {
+            if let Err(err) = load(dir, &mut state) {
+                error!("Failed to open the render cache at {:?}: {:?}", dir, err);
+            }
+        }
+        let cache = Self {
+            max_bytes: AtomicU64::new(config.max_bytes),
+            config,
+            state: Mutex::new(state),
+        };
+        cache.evict(&mut cache.state.lock().unwrap());
+        cache
+    }
+
+    /// Count a request of `root` and tell how to serve it.
+    pub fn lookup(&self, root: &Cid) -> Cached {
+        if self.config.path.is_none() {
+            return Cached::Miss;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.tick += 1;
+        let tick = state.tick;
+        if let Some(entry) = state.entries.get_mut(root) {
+            entry.used = tick;
+            let size = entry.size;
+            return Cached::Hit {
+                path: self.file(root),
+                size,
+            };
+        }
+        if state.rendering.contains(root) {
+            return Cached::Miss;
+        }
+        if state.requests.len() >= MAX_COUNTED {
+            state.requests.clear();
+        }
+        let requests = state.requests.entry(*root).or_default();
+        *requests += 1;
+        if *requests < self.config.min_requests.max(1) {
+            return Cached::Miss;
+        }
+        state.requests.remove(root);
+        state.rendering.insert(*root);
+        Cached::Admit
+    }
+
+    /// Render `car`, the car file of an admitted `root`, into the cache in the
+    /// background.
+    pub fn render<S>(self: &Arc<Self>, root: Cid, car: S)
+    where
+        S: Stream<Item = io::Result<Bytes>> + Send + Unpin + 'static,
+    {
+        let cache = Arc::clone(self);
+        task::spawn(async move {
+            let result = cache.write(&root, car).await;
+            let mut state = cache.state.lock().unwrap();
+            // the content was removed while it rendered
+            let wanted = state.rendering.remove(&root);
+            match result {
+                Ok(_) if !wanted => {
+                    let _ = fs::remove_file(cache.file(&root));
+                }
+                Ok(size) => {
+                    debug!("Cached the car file of {root}, {size} bytes");
+                    state.tick += 1;
+                    let used = state.tick;
+                    state.total += size;
+                    state.entries.insert(root, Entry { size, used });
+                    cache.evict(&mut state);
+                }
+                Err(err) => warn!("Failed to cache the car file of {root}: {:?}", err),
+            }
+        });
+    }
+
+    /// Drop the cached car file of `root`, when its content is removed.
+    pub fn remove(&self, root: &Cid) {
+        let mut state = self.state.lock().unwrap();
+        state.requests.remove(root);
+        state.rendering.remove(root);
+        if let Some(entry) = state.entries.remove(root) {
+            state.total -= entry.size;
+            if let Err(err) = fs::remove_file(self.file(root)) {
+                warn!("Failed to remove the cached car file of {root}: {:?}", err);
+            }
+        }
+    }
+
+    async fn write<S>(&self, root: &Cid, mut car: S) -> Result<u64>
+    where
+        S: Stream<Item = io::Result<Bytes>> + Unpin,
+    {
+        let path = self.file(root);
+        let partial = path.with_extension("car.tmp");
+        let mut file = async_fs::File::create(&partial).await?;
+        let mut size = 0;
+        let result: Result<()> = async {
+            while let Some(chunk) = car.next().await {
+                let chunk = chunk?;
+                size += chunk.len() as u64;
+                if size > self.max_bytes() {
+                    return Err(anyhow!("The car file of {root} is over the cache size"));
+                }
+                file.write_all(&chunk).await?;
+            }
+            file.flush().await?;
+            Ok(())
+        }
+        .await;
+        match result {
+            Ok(()) => {
+                async_fs::rename(&partial, &path).await?;
+                Ok(size)
+            }
+            Err(err) => {
+                let _ = async_fs::remove_file(&partial).await;
+                Err(err)
+            }
+        }
+    }
+
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes.load(Ordering::SeqCst)
+    }
+
+    /// Keep `max_bytes` of files from now on, removing the least recently served ones
+    /// past it right away.
+    pub fn set_max_bytes(&self, max_bytes: u64) {
+        self.max_bytes.store(max_bytes, Ordering::SeqCst);
+        self.evict(&mut self.state.lock().unwrap());
+    }
+
+    /// Remove the least recently served files until the cache fits.
+    fn evict(&self, state: &mut State) {
+        while state.total > self.max_bytes() {
+            let oldest = match state.entries.iter().min_by_key(|(_, entry)| entry.used) {
+                Some((root, _)) => *root,
+                None => return,
+            };
+            if let Some(entry) = state.entries.remove(&oldest) {
+                state.total -= entry.size;
+            }
+            if let Err(err) = fs::remove_file(self.file(&oldest)) {
+                warn!("Failed to evict the cached car file of {oldest}: {:?}", err);
+            }
+        }
+    }
+
+    fn file(&self, root: &Cid) -> PathBuf {
+        let dir = self.config.path.clone().unwrap_or_default();
+        dir.join(format!("{root}.car"))
+    }
+}
+
+/// Register the car files in `dir`, oldest first, removing partial ones.
+fn load(dir: &Path, state: &mut State) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let mut files = vec![];
+    for file in fs::read_dir(dir)? {
+        let path = file?.path();
+        let metadata = fs::metadata(&path)?;
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+        if name.ends_with(".car.tmp") {
+            fs::remove_file(&path)?;
+            continue;
+        }
+        if let Some(root) = name
+            .strip_suffix(".car")
+            .and_then(|root| Cid::from_str(root).ok())
+        {
+            files.push((metadata.modified()?, root, metadata.len()));
+        }
+    }
+    files.sort_by_key(|(modified, _, _)| *modified);
+    for (_, root, size) in files {
+        state.tick += 1;
+        state.total += size;
+        state.entries.insert(
+            root,
+            Entry {
+                size,
+                used: state.tick,
+            },
+        );
+    }
+    Ok(())
+}
+
+/// The bytes of the file at `path` from `start`, up to `end`.
+pub async fn read_file(
+    path: PathBuf,
+    start: u64,
+    end: u64,
+) -> io::Result<impl Stream<Item = io::Result<Bytes>> + Send + Unpin + 'static> {
+    let mut file = async_fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(start)).await?;
+    let chunks = stream::unfold((file, end - start), |(mut file, remaining)| async move {
+        if remaining == 0 {
+            return None;
+        }
+        let mut chunk = vec![0; READ_CHUNK.min(remaining as usize)];
+        match file.read(&mut chunk).await {
+            Ok(0) => Some((
+                Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                (file, 0),
+            )),
+            Ok(read) => {
+                chunk.truncate(read);
+                Some((Ok(Bytes::from(chunk)), (file, remaining - read as u64)))
+            }
+            Err(err) => Some((Err(err), (file, 0))),
+        }
+    });
+    Ok(Box::pin(chunks))
+}
+
+/// The bytes of `inner` from `start` up to `end`.
+pub struct RangeStream<S> {
+    inner: S,
+    /// Bytes still to skip.
+    skip: u64,
+    /// Bytes still to yield after those.
+    remaining: u64,
+}
+
+impl<S> RangeStream<S> {
+    pub fn new(inner: S, start: u64, end: u64) -> Self {
+        Self {
+            inner,
+            skip: start,
+            remaining: end.saturating_sub(start),
+        }
+    }
+}
+
+impl<S> Stream for RangeStream<S>
+where
+    S: Stream<Item = io::Result<Bytes>> + Unpin,
+{
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if self.remaining == 0 {
+                return Poll::Ready(None);
+            }
+            let mut chunk = match Pin::new(&mut self.inner).poll_next(cx) {
+                Poll::Ready(Some(Ok(chunk))) => chunk,
+                other => return other,
+            };
+            let len = chunk.len() as u64;
+            if self.skip >= len {
+                self.skip -= len;
+                continue;
+            }
+            let chunk = chunk.split_off(self.skip as usize);
+            self.skip = 0;
+            let take = self.remaining.min(chunk.len() as u64);
+            self.remaining -= take;
+            return Poll::Ready(Some(Ok(chunk.slice(..take as usize))));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_byte_range() {
+        assert_eq!(ByteRange::parse(None, 100), ByteRange::Full);
+        assert_eq!(
+            ByteRange::parse(Some("bytes=10-19"), 100),
+            ByteRange::Partial(10, 20)
+        );
+        assert_eq!(
+            ByteRange::parse(Some("bytes=90-"), 100),
+            ByteRange::Partial(90, 100)
+        );
+        assert_eq!(
+            ByteRange::parse(Some("bytes=-30"), 100),
+            ByteRange::Partial(70, 100)
+        );
+        assert_eq!(
+            ByteRange::parse(Some("bytes=50-500"), 100),
+            ByteRange::Partial(50, 100)
+        );
+        assert_eq!(
+            ByteRange::parse(Some("bytes=100-"), 100),
+            ByteRange::Unsatisfiable
+        );
+        assert_eq!(
+            ByteRange::parse(Some("bytes=0-1,5-6"), 100),
+            ByteRange::Full
+        );
+    }
+
+    #[async_std::test]
+    async fn test_render_cache() {
+        let dir = PathBuf::from("ursa_render_cache");
+        let _ = fs::remove_dir_all(&dir);
+        let cache = Arc::new(RenderCache::new(RenderCacheConfig {
+            path: Some(dir.clone()),
+            max_bytes: 15,
+            min_requests: 2,
+        }));
+        let root =
+            Cid::from_str("bafkreif2opfibjypwkjzzry3jbibcjqcjwnpoqpeiqw75eu3s3u3zbdszq").unwrap();
+        let chunks = || {
+            stream::iter(vec![
+                Ok(Bytes::from_static(b"0123")),
+                Ok(Bytes::from_static(b"456789")),
+            ])
+        };
+
+        assert!(matches!(cache.lookup(&root), Cached::Miss));
+        assert!(matches!(cache.lookup(&root), Cached::Admit));
+        cache.render(root, chunks());
+        while cache.state.lock().unwrap().rendering.contains(&root) {
+            task::sleep(std::time::Duration::from_millis(5)).await;
+        }
+
+        let (path, size) = match cache.lookup(&root) {
+            Cached::Hit { path, size } => (path, size),
+            _ => panic!("the car file is cached"),
+        };
+        assert_eq!(size, 10);
+        let part: Vec<_> = read_file(path, 3, 7).await.unwrap().collect().await;
+        let part: Vec<Bytes> = part.into_iter().map(Result::unwrap).collect();
+        assert_eq!(part.concat(), b"3456");
+
+        let ranged: Vec<_> = RangeStream::new(chunks(), 3, 7).collect().await;
+        let ranged: Vec<Bytes> = ranged.into_iter().map(Result::unwrap).collect();
+        assert_eq!(ranged.concat(), b"3456");
+
+        // a smaller cache removes the files over it right away
+        cache.set_max_bytes(5);
+        assert!(matches!(cache.lookup(&root), Cached::Miss));
+        cache.set_max_bytes(15);
+
+        cache.remove(&root);
+        assert!(matches!(cache.lookup(&root), Cached::Miss));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,152 @@
+//! IPLD path resolution.
+//!
+//! A path like `<cid>/photos/2022/cover.jpg` is resolved one segment at a time: map
+//! keys and list indices of dag-cbor blocks, and link names of dag-pb nodes such as
+//! UnixFS directories. Every link followed moves on to the linked block. The blocks
+//! have to be stored locally, and sharded UnixFS directories are not supported.
+
+use anyhow::{anyhow, Result};
+use cid::Cid;
+use ipld_blockstore::BlockStore;
+use libipld::{store::DefaultParams, Block, Cid as lCid, Ipld, IpldCodec};
+use serde::{Deserialize, Serialize};
+use ursa_store::Store;
+use ursa_utils::ToIpldCid;
+
+use crate::error::ApiError;
+
+const RAW: u64 = 0x55;
+const DAG_PB: u64 = 0x70;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Resolved {
+    /// Cid of the block the path ends in.
+    pub cid: String,
+    /// Part of the path within that block, empty when the path ends on a link.
+    pub remaining_path: String,
+}
+
+/// Resolve `path` starting at the block `root`.
+pub fn resolve<S>(store: &Store<S>, root: Cid, path: &str) -> Result<Resolved>
+where
+    S: BlockStore + Sync + Send + 'static,
+{
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let mut cid: lCid = root.to_ipld_cid();
+    let mut rest = &segments[..];
+
+    'blocks: loop {
+        if rest.is_empty() || cid.codec() == RAW {
+            return Ok(resolved(cid, rest));
+        }
+
+        let data = store.blockstore().read(cid.to_bytes())?.ok_or_else(|| {
+            anyhow!(ApiError::not_found(format!(
+                "The block {cid} is not stored on this node"
+            )))
+        })?;
+        let mut node: Ipld = Block::<DefaultParams>::new(cid, data)?.decode::<IpldCodec, Ipld>()?;
+
+        for (i, segment) in rest.iter().enumerate() {
+            let next = if cid.codec() == DAG_PB {
+                pb_link(&node, segment)
+            } else {
+                child(&node, segment)
+            };
+            match next {
+                Some(Ipld::Link(link)) => {
+                    cid = link;
+                    rest = &rest[i + 1..];
+                    continue 'blocks;
+                }
+                Some(value) => node = value,
+                None => {
+                    return Err(anyhow!(ApiError::not_found(format!(
+                        "{cid} has nothing at /{}",
+                        rest[..=i].join("/")
+                    ))))
+                }
+            }
+        }
+        // the path ends on a value within this block
+        return Ok(resolved(cid, rest));
+    }
+}
+
+fn resolved(cid: lCid, rest: &[&str]) -> Resolved {
+    Resolved {
+        cid: cid.to_string(),
+        remaining_path: rest.join("/"),
+    }
+}
+
+/// Value of `node` under the map key or list index `segment`.
+fn child(node: &Ipld, segment: &str) -> Option<Ipld> {
+    match node {
+        Ipld::Map(map) => map.get(segment).cloned(),
+        Ipld::List(list) => segment
+            .parse::<usize>()
+            .ok()
+            .and_then(|i| list.get(i))
+            .cloned(),
+        _ => None,
+    }
+}
+
+/// Link of the dag-pb `node` named `name`.
+fn pb_link(node: &Ipld, name: &str) -> Option<Ipld> {
+    let links = match node {
+        Ipld::Map(map) => match map.get("Links") {
+            Some(Ipld::List(links)) => links,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    links.iter().find_map(|link| match link {
+        Ipld::Map(link) if matches!(link.get("Name"), Some(Ipld::String(n)) if n == name) => {
+            link.get("Hash").cloned()
+        }
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::{rocks::RocksDb, rocks_config::RocksDbConfig};
+    use libipld::{cbor::DagCborCodec, ipld, multihash::Code};
+    use std::sync::Arc;
+    use ursa_utils::ToCid;
+
+    #[test]
+    fn test_resolve() {
+        let db = RocksDb::open("resolve_db", &RocksDbConfig::default()).unwrap();
+        let store = Store::new(Arc::new(db));
+
+        let leaf =
+            Block::<DefaultParams>::encode(DagCborCodec, Code::Blake3_256, &ipld!("leaf")).unwrap();
+        let root = Block::<DefaultParams>::encode(
+            DagCborCodec,
+            Code::Blake3_256,
+            &ipld!({ "dir": { "file": *leaf.cid() }, "list": [1, 2] }),
+        )
+        .unwrap();
+        for block in [&leaf, &root] {
+            store
+                .blockstore()
+                .write(block.cid().to_bytes(), block.data())
+                .unwrap();
+        }
+        let root_cid: Cid = root.cid().to_cid();
+
+        let target = resolve(&store, root_cid, "/dir/file").unwrap();
+        assert_eq!(target.cid, leaf.cid().to_string());
+        assert_eq!(target.remaining_path, "");
+
+        let value = resolve(&store, root_cid, "list/1").unwrap();
+        assert_eq!(value.cid, root.cid().to_string());
+        assert_eq!(value.remaining_path, "list/1");
+
+        assert!(resolve(&store, root_cid, "dir/nope").is_err());
+    }
+}
@@ -1,23 +1,54 @@
+use async_std::task;
 use axum::{
     middleware,
     routing::{post, put},
     Router,
 };
 use cid::Cid;
-use std::{str::FromStr, sync::Arc};
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use std::{str::FromStr, sync::Arc, time::Duration};
 use ursa_metrics::middleware::track_metrics;
+use ursa_network::accounting::UsageQuery;
+use ursa_store::columns::Column;
 
 use jsonrpc_v2::{Data, Error, Params};
+use serde_json::json;
 
 use crate::{
     api::{
-        NetworkGetFileParams, NetworkGetParams, NetworkGetResult, NetworkInterface,
-        NetworkPutFileParams, NetworkPutFileResult,
+        NetworkAccessLogParams, NetworkAccessLogResult, NetworkAccountingParams,
+        NetworkAccountingResult, NetworkAclListParams, NetworkAclListResult,
+        NetworkAclRemoveParams, NetworkAclRemoveResult, NetworkAclSetParams, NetworkAclSetResult,
+        NetworkApiKeyUsageParams, NetworkApiKeyUsageResult, NetworkCancelParams,
+        NetworkCancelResult, NetworkConfigGetParams, NetworkConfigGetResult,
+        NetworkConfigSetParams, NetworkConfigSetResult, NetworkCreateApiKeyParams,
+        NetworkCreateApiKeyResult, NetworkDagStatParams, NetworkDagStatResult,
+        NetworkDiagnosticsParams, NetworkDiagnosticsResult, NetworkFindProvidersParams,
+        NetworkFindProvidersResult, NetworkGetFileParams, NetworkGetFileResult, NetworkGetParams,
+        NetworkGetResult, NetworkGossipStatParams, NetworkGossipStatResult, NetworkInterface,
+        NetworkListContentParams, NetworkListContentResult, NetworkNamePublishParams,
+        NetworkNamePublishResult, NetworkNameResolveParams, NetworkNameResolveResult,
+        NetworkNodeInfoParams, NetworkNodeInfoResult, NetworkOperationStatusParams,
+        NetworkOperationStatusResult, NetworkPeerProtocolsParams, NetworkPeerProtocolsResult,
+        NetworkPrefetchParams, NetworkPrefetchResult, NetworkPrefetchStatusParams,
+        NetworkPrefetchStatusResult, NetworkProviderStatusParams, NetworkProviderStatusResult,
+        NetworkPurgeParams, NetworkPurgeResult, NetworkPutFileParams, NetworkPutFileResult,
+        NetworkPutUrlParams, NetworkPutUrlResult, NetworkReceiptsParams, NetworkReceiptsResult,
+        NetworkRelayCircuitsParams, NetworkRelayCircuitsResult, NetworkRemoveParams,
+        NetworkRemoveResult, NetworkRepoCompactParams, NetworkRepoCompactResult,
+        NetworkResolveParams, NetworkResolveResult, NetworkRevokeApiKeyParams,
+        NetworkRevokeApiKeyResult, NetworkSetContextParams, NetworkSetContextResult,
+        NetworkSignUrlParams, NetworkSignUrlResult, NetworkTopicPeersParams,
+        NetworkTopicPeersResult, NetworkUnindexContextParams, NetworkUnindexContextResult,
+        NetworkVerifyParams, NetworkVerifyResult, OperationResult,
     },
+    content::DEFAULT_PAGE_SIZE,
+    error::{request_id, rpc_error, ApiError},
+    operations::OperationKind,
     rpc::rpc::rpc_handler,
 };
 
-use tracing::error;
+use tracing::{error, warn};
 pub type Result<T> = anyhow::Result<T, Error>;
 
 pub fn init() -> Router {
@@ -25,6 +56,7 @@ pub fn init() -> Router {
         .route("/rpc/v0", put(rpc_handler))
         .route("/rpc/v0", post(rpc_handler))
         .route_layer(middleware::from_fn(track_metrics))
+        .layer(middleware::from_fn(request_id))
 }
 
 pub async fn get_cid_handler<I>(
@@ -34,35 +66,48 @@ pub async fn get_cid_handler<I>(
 where
     I: NetworkInterface,
 {
-    if let Ok(cid) = Cid::from_str(&params.cid) {
-        match data.0.get(cid).await {
-            Err(err) => Err(Error::internal(err)),
-            Ok(res) => Ok(res.unwrap()),
-        }
-    } else {
-        error!("Invalid Cid String, Cannot Parse {} to CID", &params.cid);
-        return Err(Error::INVALID_PARAMS);
+    let cid = parse_cid(&params.cid)?;
+    let providers = parse_providers(&params.providers)?;
+    let deadline = params.timeout_ms.map(Duration::from_millis);
+    match data.0.get(cid, providers, deadline, params.api_key).await {
+        Err(err) => Err(rpc_error(err)),
+        Ok(res) => Ok(res.unwrap()),
     }
 }
 pub async fn get_file_handler<I>(
     data: Data<Arc<I>>,
     Params(params): Params<NetworkGetFileParams>,
-) -> Result<()>
+) -> Result<NetworkGetFileResult>
 where
     I: NetworkInterface,
 {
     let path = params.path;
-    if let Ok(cid) = Cid::from_str(&params.cid) {
-        match data.0.get_file(path, cid).await {
-            Err(err) => {
+    let format = params.format;
+    let cid = parse_cid(&params.cid)?;
+    let operation = data.0.start_operation(OperationKind::Get, Some(cid));
+
+    if params.background {
+        let interface = Arc::clone(&data.0);
+        task::spawn(async move {
+            if let Err(err) = interface.get_file(path, cid, format, Some(operation)).await {
                 error!("{:?}", err);
-                return Err(Error::internal(err));
             }
-            _ => Ok(()),
+        });
+        return Ok(OperationResult {
+            operation,
+            cids: vec![],
+        });
+    }
+
+    match data.0.get_file(path, cid, format, Some(operation)).await {
+        Err(err) => {
+            error!("{:?}", err);
+            Err(rpc_error(err))
         }
-    } else {
-        error!("Invalid Cid String, Cannot Parse {} to CID", &params.cid);
-        return Err(Error::INVALID_PARAMS);
+        Ok(()) => Ok(OperationResult {
+            operation,
+            cids: vec![cid.to_string()],
+        }),
     }
 }
 
@@ -74,12 +119,600 @@ where
     I: NetworkInterface,
 {
     let path = params.path;
+    let ignore = params.ignore;
+    let api_key = params.api_key;
+    data.0.admit(api_key.as_deref(), 0).map_err(rpc_error)?;
+    let operation = data.0.start_operation(OperationKind::Put, None);
+
+    if params.background {
+        let interface = Arc::clone(&data.0);
+        task::spawn(async move {
+            if let Err(err) = interface
+                .put_file(path, ignore, api_key, Some(operation))
+                .await
+            {
+                error!("{:?}", err);
+            }
+        });
+        return Ok(OperationResult {
+            operation,
+            cids: vec![],
+        });
+    }
 
-    match data.0.put_file(path).await {
+    match data
+        .0
+        .put_file(path, ignore, api_key, Some(operation))
+        .await
+    {
         Err(err) => {
             error!("{:?}", err);
-            return Err(Error::internal(err));
+            Err(rpc_error(err))
         }
-        Ok(res) => Ok(res.iter().map(|c| Cid::from(c).to_string()).collect()),
+        Ok(res) => Ok(OperationResult {
+            operation,
+            cids: res.iter().map(Cid::to_string).collect(),
+        }),
     }
 }
+
+pub async fn put_url_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkPutUrlParams>,
+) -> Result<NetworkPutUrlResult>
+where
+    I: NetworkInterface,
+{
+    data.0
+        .admit(params.api_key.as_deref(), 0)
+        .map_err(rpc_error)?;
+    match data
+        .0
+        .put_url(params.url, params.format, params.sha256, params.api_key)
+        .await
+    {
+        Err(err) => {
+            error!("{:?}", err);
+            Err(rpc_error(err))
+        }
+        Ok(res) => Ok(res.iter().map(Cid::to_string).collect()),
+    }
+}
+
+fn parse_cid(cid: &str) -> Result<Cid> {
+    Cid::from_str(cid).map_err(|_| {
+        let message = format!("Invalid Cid String, Cannot Parse {} to CID", cid);
+        error!("{}", message);
+        Error::from(ApiError::invalid_params(message).with_details(json!({ "cid": cid })))
+    })
+}
+
+fn parse_peer(peer: &str) -> Result<PeerId> {
+    PeerId::from_str(peer).map_err(|_| {
+        let message = format!("Invalid peer id, Cannot Parse {} to PeerId", peer);
+        error!("{}", message);
+        Error::from(ApiError::invalid_params(message).with_details(json!({ "peer": peer })))
+    })
+}
+
+fn parse_columns(columns: &[String]) -> Result<Vec<Column>> {
+    columns
+        .iter()
+        .map(|name| {
+            Column::from_name(name).ok_or_else(|| {
+                let message = format!("Unknown column family {name}");
+                Error::from(
+                    ApiError::invalid_params(message).with_details(json!({ "column": name })),
+                )
+            })
+        })
+        .collect()
+}
+
+fn parse_cids(cids: &[String]) -> Result<Vec<Cid>> {
+    cids.iter().map(|cid| parse_cid(cid)).collect()
+}
+
+/// Parse provider hints given as multiaddrs or bare peer ids.
+fn parse_providers(providers: &[String]) -> Result<Vec<Multiaddr>> {
+    providers
+        .iter()
+        .map(|provider| {
+            Multiaddr::from_str(provider)
+                .ok()
+                .or_else(|| {
+                    PeerId::from_str(provider)
+                        .ok()
+                        .map(|peer| Multiaddr::empty().with(Protocol::P2p(peer.into())))
+                })
+                .ok_or_else(|| {
+                    let message = format!(
+                        "Invalid provider, Cannot Parse {} to PeerId or Multiaddr",
+                        provider
+                    );
+                    error!("{}", message);
+                    Error::from(
+                        ApiError::invalid_params(message)
+                            .with_details(json!({ "provider": provider })),
+                    )
+                })
+        })
+        .collect()
+}
+
+pub async fn prefetch_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkPrefetchParams>,
+) -> Result<NetworkPrefetchResult>
+where
+    I: NetworkInterface,
+{
+    let cids = parse_cids(&params.cids)?;
+    let providers = parse_providers(&params.providers)?;
+    data.0
+        .admit(params.api_key.as_deref(), 0)
+        .map_err(rpc_error)?;
+
+    let queued = cids.iter().map(Cid::to_string).collect();
+    let operation = data
+        .0
+        .start_operation(OperationKind::Prefetch, cids.first().copied());
+    let interface = Arc::clone(&data.0);
+    task::spawn(async move {
+        if let Err(err) = interface
+            .prefetch(cids, providers, params.api_key, Some(operation))
+            .await
+        {
+            warn!("Prefetch failed: {:?}", err);
+        }
+    });
+
+    Ok(OperationResult {
+        operation,
+        cids: queued,
+    })
+}
+
+pub async fn remove_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkRemoveParams>,
+) -> Result<NetworkRemoveResult>
+where
+    I: NetworkInterface,
+{
+    let cids = parse_cids(&params.cids)?;
+    let removed = data.0.remove(params.token, cids).await.map_err(rpc_error)?;
+    Ok(removed.iter().map(Cid::to_string).collect())
+}
+
+pub async fn set_context_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkSetContextParams>,
+) -> Result<NetworkSetContextResult>
+where
+    I: NetworkInterface,
+{
+    let cids = parse_cids(&params.cids)?;
+    data.0
+        .set_context(params.token, cids, params.context_id)
+        .await
+        .map_err(rpc_error)
+}
+
+pub async fn unindex_context_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkUnindexContextParams>,
+) -> Result<NetworkUnindexContextResult>
+where
+    I: NetworkInterface,
+{
+    let roots = data
+        .0
+        .unindex_context(params.token, params.context_id)
+        .await
+        .map_err(rpc_error)?;
+    Ok(roots.iter().map(Cid::to_string).collect())
+}
+
+pub async fn purge_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkPurgeParams>,
+) -> Result<NetworkPurgeResult>
+where
+    I: NetworkInterface,
+{
+    let cids = parse_cids(&params.cids)?;
+    data.0
+        .purge(params.token, cids, params.context_ids)
+        .await
+        .map_err(rpc_error)
+}
+
+pub async fn prefetch_status_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkPrefetchStatusParams>,
+) -> Result<NetworkPrefetchStatusResult>
+where
+    I: NetworkInterface,
+{
+    let cids = parse_cids(&params.cids)?;
+    data.0.prefetch_status(cids).await.map_err(rpc_error)
+}
+
+pub async fn access_log_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkAccessLogParams>,
+) -> Result<NetworkAccessLogResult>
+where
+    I: NetworkInterface,
+{
+    let limit = params.limit.unwrap_or(100);
+    data.0
+        .access_log(params.token, limit, params.cid)
+        .await
+        .map_err(rpc_error)
+}
+
+pub async fn receipts_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkReceiptsParams>,
+) -> Result<NetworkReceiptsResult>
+where
+    I: NetworkInterface,
+{
+    let limit = params.limit.unwrap_or(100);
+    data.0
+        .receipts(params.token, limit, params.cid)
+        .await
+        .map_err(rpc_error)
+}
+
+pub async fn accounting_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkAccountingParams>,
+) -> Result<NetworkAccountingResult>
+where
+    I: NetworkInterface,
+{
+    let query = UsageQuery {
+        kind: params.kind,
+        id: params.id,
+        since: params.since,
+        until: params.until,
+    };
+    data.0
+        .accounting(params.token, query)
+        .await
+        .map_err(rpc_error)
+}
+
+pub async fn gossip_stat_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkGossipStatParams>,
+) -> Result<NetworkGossipStatResult>
+where
+    I: NetworkInterface,
+{
+    data.0.gossip_stat(params.topic).await.map_err(rpc_error)
+}
+
+pub async fn topic_peers_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkTopicPeersParams>,
+) -> Result<NetworkTopicPeersResult>
+where
+    I: NetworkInterface,
+{
+    data.0.topic_peers(params.topic).await.map_err(rpc_error)
+}
+
+pub async fn find_providers_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkFindProvidersParams>,
+) -> Result<NetworkFindProvidersResult>
+where
+    I: NetworkInterface,
+{
+    let cid = parse_cid(&params.cid)?;
+    let deadline = params.timeout_ms.map(Duration::from_millis);
+    data.0
+        .find_providers(cid, deadline)
+        .await
+        .map_err(rpc_error)
+}
+
+pub async fn node_info_handler<I>(
+    data: Data<Arc<I>>,
+    Params(_params): Params<NetworkNodeInfoParams>,
+) -> Result<NetworkNodeInfoResult>
+where
+    I: NetworkInterface,
+{
+    data.0.node_info().await.map_err(rpc_error)
+}
+
+pub async fn peer_protocols_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkPeerProtocolsParams>,
+) -> Result<NetworkPeerProtocolsResult>
+where
+    I: NetworkInterface,
+{
+    data.0
+        .peer_protocols(params.protocol)
+        .await
+        .map_err(rpc_error)
+}
+
+pub async fn relay_circuits_handler<I>(
+    data: Data<Arc<I>>,
+    Params(_params): Params<NetworkRelayCircuitsParams>,
+) -> Result<NetworkRelayCircuitsResult>
+where
+    I: NetworkInterface,
+{
+    data.0.relay_circuits().await.map_err(rpc_error)
+}
+
+pub async fn provider_status_handler<I>(
+    data: Data<Arc<I>>,
+    Params(_params): Params<NetworkProviderStatusParams>,
+) -> Result<NetworkProviderStatusResult>
+where
+    I: NetworkInterface,
+{
+    data.0.provider_status().await.map_err(rpc_error)
+}
+
+pub async fn operation_status_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkOperationStatusParams>,
+) -> Result<NetworkOperationStatusResult>
+where
+    I: NetworkInterface,
+{
+    match data.0.operation_status(params.id).await {
+        Ok(Some(status)) => Ok(status),
+        Ok(None) => {
+            error!("No operation with id {}", params.id);
+            Err(ApiError::not_found(format!("No operation with id {}", params.id)).into())
+        }
+        Err(err) => Err(rpc_error(err)),
+    }
+}
+
+pub async fn cancel_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkCancelParams>,
+) -> Result<NetworkCancelResult>
+where
+    I: NetworkInterface,
+{
+    data.0.cancel(params.id).await.map_err(rpc_error)
+}
+
+pub async fn create_api_key_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkCreateApiKeyParams>,
+) -> Result<NetworkCreateApiKeyResult>
+where
+    I: NetworkInterface,
+{
+    data.0
+        .create_api_key(
+            params.token,
+            params.name,
+            params.quota_bytes,
+            params.rate_limit,
+        )
+        .await
+        .map_err(rpc_error)
+}
+
+pub async fn revoke_api_key_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkRevokeApiKeyParams>,
+) -> Result<NetworkRevokeApiKeyResult>
+where
+    I: NetworkInterface,
+{
+    data.0
+        .revoke_api_key(params.token, params.id)
+        .await
+        .map_err(rpc_error)
+}
+
+pub async fn api_key_usage_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkApiKeyUsageParams>,
+) -> Result<NetworkApiKeyUsageResult>
+where
+    I: NetworkInterface,
+{
+    data.0
+        .api_key_usage(params.token, params.id)
+        .await
+        .map_err(rpc_error)
+}
+
+pub async fn sign_url_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkSignUrlParams>,
+) -> Result<NetworkSignUrlResult>
+where
+    I: NetworkInterface,
+{
+    let cid = parse_cid(&params.cid)?;
+    data.0
+        .sign_url(params.token, cid, params.ttl_secs)
+        .await
+        .map_err(rpc_error)
+}
+
+pub async fn acl_set_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkAclSetParams>,
+) -> Result<NetworkAclSetResult>
+where
+    I: NetworkInterface,
+{
+    let cid = parse_cid(&params.cid)?;
+    let peers = params
+        .peers
+        .iter()
+        .map(|peer| parse_peer(peer))
+        .collect::<Result<Vec<_>>>()?;
+    data.0
+        .acl_set(params.token, cid, peers, params.api_keys)
+        .await
+        .map_err(rpc_error)
+}
+
+pub async fn acl_remove_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkAclRemoveParams>,
+) -> Result<NetworkAclRemoveResult>
+where
+    I: NetworkInterface,
+{
+    let cid = parse_cid(&params.cid)?;
+    data.0
+        .acl_remove(params.token, cid)
+        .await
+        .map_err(rpc_error)
+}
+
+pub async fn acl_list_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkAclListParams>,
+) -> Result<NetworkAclListResult>
+where
+    I: NetworkInterface,
+{
+    data.0.acl_list(params.token).await.map_err(rpc_error)
+}
+
+pub async fn list_content_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkListContentParams>,
+) -> Result<NetworkListContentResult>
+where
+    I: NetworkInterface,
+{
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    data.0
+        .list_content(params.cursor, limit, params.filter, params.api_key)
+        .await
+        .map_err(rpc_error)
+}
+
+pub async fn dag_stat_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkDagStatParams>,
+) -> Result<NetworkDagStatResult>
+where
+    I: NetworkInterface,
+{
+    let cid = parse_cid(&params.cid)?;
+    data.0
+        .dag_stat(cid, params.fetch, params.api_key)
+        .await
+        .map_err(rpc_error)
+}
+
+pub async fn verify_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkVerifyParams>,
+) -> Result<NetworkVerifyResult>
+where
+    I: NetworkInterface,
+{
+    let cid = parse_cid(&params.cid)?;
+    data.0
+        .verify(params.token, cid, params.repair)
+        .await
+        .map_err(rpc_error)
+}
+
+pub async fn resolve_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkResolveParams>,
+) -> Result<NetworkResolveResult>
+where
+    I: NetworkInterface,
+{
+    let cid = parse_cid(&params.cid)?;
+    data.0.resolve(cid, params.path).await.map_err(rpc_error)
+}
+
+pub async fn name_publish_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkNamePublishParams>,
+) -> Result<NetworkNamePublishResult>
+where
+    I: NetworkInterface,
+{
+    let cid = parse_cid(&params.cid)?;
+    data.0
+        .name_publish(params.token, cid, params.ttl_secs)
+        .await
+        .map_err(rpc_error)
+}
+
+pub async fn name_resolve_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkNameResolveParams>,
+) -> Result<NetworkNameResolveResult>
+where
+    I: NetworkInterface,
+{
+    let name = parse_peer(&params.name)?;
+    data.0.name_resolve(name).await.map_err(rpc_error)
+}
+
+pub async fn repo_compact_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkRepoCompactParams>,
+) -> Result<NetworkRepoCompactResult>
+where
+    I: NetworkInterface,
+{
+    let columns = parse_columns(&params.columns)?;
+    data.0
+        .repo_compact(params.token, columns)
+        .map_err(rpc_error)
+}
+
+pub async fn config_get_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkConfigGetParams>,
+) -> Result<NetworkConfigGetResult>
+where
+    I: NetworkInterface,
+{
+    data.0
+        .config_get(params.token, params.keys)
+        .map_err(rpc_error)
+}
+
+pub async fn config_set_handler<I>(
+    data: Data<Arc<I>>,
+    Params(params): Params<NetworkConfigSetParams>,
+) -> Result<NetworkConfigSetResult>
+where
+    I: NetworkInterface,
+{
+    data.0
+        .config_set(params.token, params.key, params.value)
+        .map_err(rpc_error)
+}
+
+pub async fn diagnostics_handler<I>(
+    data: Data<Arc<I>>,
+    Params(_params): Params<NetworkDiagnosticsParams>,
+) -> Result<NetworkDiagnosticsResult>
+where
+    I: NetworkInterface,
+{
+    data.0.diagnostics().await.map_err(rpc_error)
+}
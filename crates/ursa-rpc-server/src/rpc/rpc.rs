@@ -1,55 +1,44 @@
 use std::sync::Arc;
 
-use anyhow::Result;
 use axum::{
+    body::Bytes,
     http::StatusCode,
     response::{IntoResponse, Response},
     Extension, Json,
 };
-use jsonrpc_v2::{Data, Error, MapRouter, RequestObject, ResponseObject, ResponseObjects, Server};
+use jsonrpc_v2::{Data, MapRouter, ResponseObjects, Server};
 
-use crate::{api::NetworkInterface, config::ServerConfig};
+use crate::{
+    api::NetworkInterface,
+    error::{stamp_rpc_errors, RequestId},
+};
 
 use super::routes::network;
 
 #[derive(Clone)]
 pub struct RpcServer(Arc<Server<MapRouter>>);
 
-pub enum ServerErrors {
-    ApiError(Error),
-}
-impl IntoResponse for ServerErrors {
-    fn into_response(self) -> Response {
-        let body = match self {
-            ServerErrors::ApiError(e) => Json(e),
-        };
-        (StatusCode::INTERNAL_SERVER_ERROR, body).into_response()
-    }
-}
-
+/// Handle a JSON-RPC 2.0 request or batch of requests.
+///
+/// Failed calls are answered with an error object carrying the id of their request,
+/// so a single failure does not fail the rest of a batch. Parse errors and invalid
+/// requests are answered the same way, and batches of notifications get no content.
+/// Errors of the methods carry an [`ApiError`](crate::error::ApiError) with the id of
+/// the request in their `data`.
 pub async fn rpc_handler(
-    Json(req): Json<RequestObject>,
     Extension(server): Extension<RpcServer>,
-) -> Result<Json<ResponseObjects>, ServerErrors> {
-    match server.0.handle(req).await {
-        ResponseObjects::One(r) => match r {
-            ResponseObject::Result {
-                jsonrpc,
-                result,
-                id,
-            } => Ok(Json(ResponseObjects::One(ResponseObject::Result {
-                jsonrpc,
-                result,
-                id,
-            }))),
-            ResponseObject::Error {
-                jsonrpc: _,
-                error,
-                id: _,
-            } => Err(ServerErrors::ApiError(error)),
+    Extension(request_id): Extension<RequestId>,
+    body: Bytes,
+) -> Response {
+    match server.0.handle(body).await {
+        ResponseObjects::Empty => StatusCode::NO_CONTENT.into_response(),
+        response => match serde_json::to_value(&response) {
+            Ok(mut response) => {
+                stamp_rpc_errors(&mut response, &request_id);
+                Json(response).into_response()
+            }
+            Err(_) => Json(response).into_response(),
         },
-        ResponseObjects::Many(_) => todo!(),
-        ResponseObjects::Empty => todo!(),
     }
 }
 
@@ -62,8 +51,134 @@ impl RpcServer {
             .with_data(Data::new(interface))
             .with_method("ursa_get_cid", network::get_cid_handler::<I>)
             .with_method("ursa_get_file", network::get_file_handler::<I>)
-            .with_method("ursa_put_file", network::put_file_handler::<I>);
+            .with_method("ursa_put_file", network::put_file_handler::<I>)
+            .with_method("ursa_put_url", network::put_url_handler::<I>)
+            .with_method("ursa_prefetch", network::prefetch_handler::<I>)
+            .with_method(
+                "ursa_prefetch_status",
+                network::prefetch_status_handler::<I>,
+            )
+            .with_method("ursa_remove", network::remove_handler::<I>)
+            .with_method("ursa_set_context", network::set_context_handler::<I>)
+            .with_method(
+                "ursa_unindex_context",
+                network::unindex_context_handler::<I>,
+            )
+            .with_method("ursa_purge", network::purge_handler::<I>)
+            .with_method("ursa_access_log", network::access_log_handler::<I>)
+            .with_method("ursa_receipts", network::receipts_handler::<I>)
+            .with_method("ursa_accounting", network::accounting_handler::<I>)
+            .with_method("ursa_gossip_stat", network::gossip_stat_handler::<I>)
+            .with_method("ursa_topic_peers", network::topic_peers_handler::<I>)
+            .with_method("ursa_find_providers", network::find_providers_handler::<I>)
+            .with_method("ursa_node_info", network::node_info_handler::<I>)
+            .with_method("ursa_relay_circuits", network::relay_circuits_handler::<I>)
+            .with_method("ursa_peer_protocols", network::peer_protocols_handler::<I>)
+            .with_method(
+                "ursa_provider_status",
+                network::provider_status_handler::<I>,
+            )
+            .with_method(
+                "ursa_operation_status",
+                network::operation_status_handler::<I>,
+            )
+            .with_method("ursa_cancel", network::cancel_handler::<I>)
+            .with_method("ursa_create_api_key", network::create_api_key_handler::<I>)
+            .with_method("ursa_revoke_api_key", network::revoke_api_key_handler::<I>)
+            .with_method("ursa_api_key_usage", network::api_key_usage_handler::<I>)
+            .with_method("ursa_sign_url", network::sign_url_handler::<I>)
+            .with_method("ursa_acl_set", network::acl_set_handler::<I>)
+            .with_method("ursa_acl_remove", network::acl_remove_handler::<I>)
+            .with_method("ursa_acl_list", network::acl_list_handler::<I>)
+            .with_method("ursa_list_content", network::list_content_handler::<I>)
+            .with_method("ursa_dag_stat", network::dag_stat_handler::<I>)
+            .with_method("ursa_verify", network::verify_handler::<I>)
+            .with_method("ursa_resolve", network::resolve_handler::<I>)
+            .with_method("ursa_name_publish", network::name_publish_handler::<I>)
+            .with_method("ursa_name_resolve", network::name_resolve_handler::<I>)
+            .with_method("ursa_repo_compact", network::repo_compact_handler::<I>)
+            .with_method("ursa_config_get", network::config_get_handler::<I>)
+            .with_method("ursa_config_set", network::config_set_handler::<I>)
+            .with_method("ursa_diagnostics", network::diagnostics_handler::<I>);
 
         RpcServer(server.finish())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonrpc_v2::{Error, Params};
+    use serde_json::{json, Value};
+
+    async fn echo(Params(params): Params<Vec<u64>>) -> Result<Vec<u64>, Error> {
+        Ok(params)
+    }
+
+    async fn call(body: &str) -> (StatusCode, Option<Value>) {
+        let server = RpcServer(Server::new().with_method("echo", echo).finish());
+        let response = rpc_handler(
+            Extension(server),
+            Extension(RequestId("request".to_string())),
+            Bytes::from(body.to_string()),
+        )
+        .await;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).ok())
+    }
+
+    #[tokio::test]
+    async fn test_mixed_batch() {
+        let (status, response) = call(
+            r#"[
+                {"jsonrpc": "2.0", "method": "echo", "params": [1], "id": 1},
+                {"jsonrpc": "2.0", "method": "missing", "id": 2},
+                {"jsonrpc": "2.0", "method": "echo", "params": ["one"], "id": 3},
+                {"jsonrpc": "2.0", "method": "echo", "params": [4]}
+            ]"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        // the notification is not answered
+        let responses = response.unwrap().as_array().unwrap().clone();
+        assert_eq!(responses.len(), 3);
+        let by_id = |id: u64| {
+            responses
+                .iter()
+                .find(|response| response["id"] == json!(id))
+                .unwrap()
+                .clone()
+        };
+        assert_eq!(by_id(1)["result"], json!([1]));
+        assert_eq!(by_id(2)["error"]["code"], json!(-32601));
+        assert_eq!(by_id(3)["error"]["code"], json!(-32602));
+    }
+
+    #[tokio::test]
+    async fn test_empty_batch() {
+        let (status, response) = call("[]").await;
+        assert_eq!(status, StatusCode::OK);
+        let response = response.unwrap();
+        assert_eq!(response["error"]["code"], json!(-32600));
+        assert_eq!(response["id"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn test_notification() {
+        let (status, response) =
+            call(r#"{"jsonrpc": "2.0", "method": "echo", "params": [1]}"#).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(response, None);
+    }
+
+    #[tokio::test]
+    async fn test_parse_error() {
+        let (status, response) = call(r#"{"jsonrpc": "2.0", "method""#).await;
+        assert_eq!(status, StatusCode::OK);
+        let response = response.unwrap();
+        assert_eq!(response["error"]["code"], json!(-32700));
+        assert_eq!(response["id"], Value::Null);
+    }
+}
@@ -1,16 +1,30 @@
-use anyhow::Result;
-use axum::{Extension, Router};
+use anyhow::{anyhow, Result};
+use axum::{body::BoxBody, middleware, Extension, Router};
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use hyper::{
+    header::{HeaderValue, ALT_SVC},
+    Body, Request, Response,
+};
 use ipld_blockstore::BlockStore;
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    convert::Infallible,
+    fs::File,
+    io::BufReader,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+use tokio::task;
+use tower::{Service, ServiceExt};
+use ursa_metrics::middleware::track_metrics;
 
 use crate::{
     api::NodeNetworkInterface,
-    config::ServerConfig,
-    http,
+    config::{ServerConfig, TlsConfig},
+    http, http3,
     rpc::{self, rpc::RpcServer},
     service::MultiplexService,
 };
-use tracing::info;
+use tracing::{error, info};
 
 pub struct Server<S>
 where
@@ -18,6 +32,7 @@ where
 {
     rpc_server: RpcServer,
     interface: Arc<NodeNetworkInterface<S>>,
+    handle: Handle,
 }
 
 impl<S> Server<S>
@@ -28,36 +43,158 @@ where
         Self {
             rpc_server: RpcServer::new(Arc::clone(&interface)),
             interface: interface.clone(),
+            handle: Handle::new(),
         }
     }
 
+    /// Handle to shut the listeners down with, see [`Handle::graceful_shutdown`].
+    ///
+    /// A graceful shutdown stops accepting connections right away and lets the open
+    /// ones finish within the grace period, [`Server::start`] returns once they did.
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+
     pub async fn start(&self, config: ServerConfig) -> Result<()> {
         info!("Server (Rpc and http) starting up");
         let rpc_router = Router::new()
             .merge(rpc::routes::network::init())
             .layer(Extension(self.rpc_server.clone()));
 
-        let http = Router::new()
-            .merge(http::routes::network::init::<S>())
+        let content = Router::new()
+            .merge(http::routes::network::content::<S>())
+            .route_layer(middleware::from_fn(track_metrics))
             .layer(Extension(self.interface.clone()));
+        let admin = Router::new()
+            .merge(http::routes::network::admin::<S>())
+            .layer(Extension(self.interface.clone()));
+
+        let http_address = socket_address(&config.addr, config.port)?;
+
+        match config.admin {
+            None => {
+                let service = MultiplexService::new(content.merge(admin), rpc_router);
+                serve(http_address, config.tls.as_ref(), service, &self.handle).await
+            }
+            Some(admin_config) => {
+                let admin_address = socket_address(&admin_config.addr, admin_config.port)?;
+                let service = MultiplexService::new(admin, rpc_router);
+                futures::try_join!(
+                    serve(http_address, config.tls.as_ref(), content, &self.handle),
+                    serve(
+                        admin_address,
+                        admin_config.tls.as_ref(),
+                        service,
+                        &self.handle
+                    ),
+                )?;
+                Ok(())
+            }
+        }
+    }
+}
 
-        let http_address = SocketAddr::from(([0, 0, 0, 0], config.port));
+fn socket_address(addr: &str, port: u16) -> Result<SocketAddr> {
+    let ip: IpAddr = addr
+        .parse()
+        .map_err(|e| anyhow!("Invalid listen address {}: {}", addr, e))?;
+    Ok(SocketAddr::new(ip, port))
+}
 
-        let service = MultiplexService::new(http, rpc_router);
+/// Protocols offered to tls clients, in order of preference.
+const ALPN_PROTOCOLS: [&[u8]; 2] = [b"h2", b"http/1.1"];
 
-        info!("listening on {}", http_address);
-        axum::Server::bind(&http_address)
-            .serve(tower::make::Shared::new(service))
-            .await?;
+/// Rustls config of the certificate and key of `tls`, without alpn protocols.
+pub(crate) fn tls_config(tls: &TlsConfig) -> Result<rustls::ServerConfig> {
+    let mut certs = BufReader::new(File::open(&tls.cert_path)?);
+    let certs = rustls_pemfile::certs(&mut certs)?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+    // pkcs8 keys, or the rsa keys openssl writes
+    let key = std::fs::read(&tls.key_path)?;
+    let key = rustls_pemfile::pkcs8_private_keys(&mut key.as_slice())?
+        .into_iter()
+        .chain(rustls_pemfile::rsa_private_keys(&mut key.as_slice())?)
+        .next()
+        .ok_or_else(|| anyhow!("No private key in {:?}", tls.key_path))?;
+    Ok(rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, rustls::PrivateKey(key))?)
+}
 
-        Ok(())
+/// Serve `service` on `address`, over https when `tls` is set.
+///
+/// HTTP/2 is negotiated with alpn over tls and used by plaintext clients sending its
+/// preface, HTTP/1.1 otherwise. With `http3_port` set HTTP/3 is served too.
+async fn serve<T>(
+    address: SocketAddr,
+    tls: Option<&TlsConfig>,
+    service: T,
+    handle: &Handle,
+) -> Result<()>
+where
+    T: Service<Request<Body>, Response = Response<BoxBody>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    T::Future: Send + 'static,
+{
+    match tls {
+        Some(tls) => {
+            let mut rustls_config = tls_config(tls)?;
+            rustls_config.alpn_protocols = ALPN_PROTOCOLS.iter().map(|p| p.to_vec()).collect();
+            let rustls_config = RustlsConfig::from_config(Arc::new(rustls_config));
+            let service = match tls.http3_port {
+                Some(port) => {
+                    let h3_address = SocketAddr::new(address.ip(), port);
+                    let h3_tls = tls.clone();
+                    let h3_service = service.clone();
+                    // the quic endpoint is not covered by the handle, it stops with
+                    // the process
+                    task::spawn(async move {
+                        if let Err(err) = http3::serve(h3_address, &h3_tls, h3_service).await {
+                            error!("HTTP/3 listener on {} failed: {:?}", h3_address, err);
+                        }
+                    });
+                    // tell browsers they can switch to HTTP/3
+                    let alt_svc = HeaderValue::from_str(&format!("h3=\":{port}\"; ma=86400"))?;
+                    service
+                        .map_response(move |mut response: Response<BoxBody>| {
+                            response.headers_mut().insert(ALT_SVC, alt_svc.clone());
+                            response
+                        })
+                        .boxed_clone()
+                }
+                None => service.boxed_clone(),
+            };
+            info!("listening on {} with tls", address);
+            axum_server::bind_rustls(address, rustls_config)
+                .handle(handle.clone())
+                .serve(tower::make::Shared::new(service))
+                .await?;
+        }
+        None => {
+            info!("listening on {}", address);
+            axum_server::bind(address)
+                .handle(handle.clone())
+                .serve(tower::make::Shared::new(service))
+                .await?;
+        }
     }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use crate::{
+        access_log::AccessLog,
+        config::{OverflowPolicy, PutUrlConfig},
+        origin::Origin,
+    };
     use async_std::sync::RwLock;
     use db::{rocks::RocksDb, rocks_config::RocksDbConfig};
     use libp2p::{identity::Keypair, PeerId};
@@ -85,7 +222,7 @@ mod tests {
         );
 
         let service =
-            UrsaService::new(keypair, &config, Arc::clone(&store), index_provider.clone());
+            UrsaService::new(keypair, &config, Arc::clone(&store), index_provider.clone()).unwrap();
 
         (service, local_peer_id)
     }
@@ -99,10 +236,7 @@ mod tests {
             .init()
             .unwrap();
 
-        let config = ServerConfig {
-            port: 4069,
-            addr: "0.0.0.0".to_string(),
-        };
+        let config = ServerConfig::new(4069, "0.0.0.0".to_string());
 
         let db = RocksDb::open("test_db", &RocksDbConfig::default())
             .expect("Opening RocksDB must succeed");
@@ -113,10 +247,14 @@ mod tests {
         let (ursa_node, _) = ursa_network_init(&network_config, Arc::clone(&store));
         let ursa_node_sender = ursa_node.command_sender().clone();
 
-        let interface = Arc::new(NodeNetworkInterface {
+        let interface = Arc::new(NodeNetworkInterface::new(
             store,
-            network_send: ursa_node_sender,
-        });
+            ursa_node_sender,
+            Origin::default(),
+            AccessLog::default(),
+            OverflowPolicy::default(),
+            PutUrlConfig::default(),
+        ));
 
         let rpc = Server::new(interface);
 
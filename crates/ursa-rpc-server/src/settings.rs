@@ -0,0 +1,228 @@
+//! Runtime settings.
+//!
+//! A few values of the config file can be changed on a running node, e.g. the log
+//! level while chasing a problem or a bandwidth cap during an incident, without a
+//! restart dropping every connection. Each value is registered under its dotted
+//! path in the config file, e.g. `network_config.bandwidth.gateway`, with a function
+//! checking a new value and applying it to the running services. `ursa_config_set`
+//! then writes the value back to the config file, so it outlives a restart.
+//!
+//! Both `ursa_config_get` and `ursa_config_set` take the `admin_token` of the server
+//! config in their params, and are refused while none is configured, like the other
+//! admin rpcs checked with [`Settings::authorize`].
+
+use anyhow::{anyhow, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeMap, sync::Mutex};
+use tracing::{info, warn};
+
+use crate::error::{ApiError, ErrorCode};
+
+/// Header carrying the admin token on the admin http routes.
+pub const ADMIN_TOKEN_HEADER: &str = "x-ursa-admin-token";
+
+/// Checks a new value of a setting and applies it, failing on values it does not
+/// take.
+pub type Apply = Box<dyn Fn(&Value) -> Result<()> + Send + Sync>;
+
+/// Writes a changed value to the config file, given its dotted path.
+pub type Persist = Box<dyn Fn(&str, &Value) -> Result<()> + Send + Sync>;
+
+struct Setting {
+    value: Value,
+    apply: Apply,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SettingChange {
+    pub key: String,
+    pub previous: Value,
+    pub value: Value,
+    /// Whether the value was written to the config file.
+    pub persisted: bool,
+}
+
+#[derive(Default)]
+pub struct Settings {
+    /// Token the callers have to pass, none refuses every call.
+    token: Option<String>,
+    settings: Mutex<BTreeMap<String, Setting>>,
+    persist: Option<Persist>,
+}
+
+impl Settings {
+    pub fn new(token: Option<String>) -> Self {
+        Self {
+            token: token.filter(|token| !token.is_empty()),
+            ..Default::default()
+        }
+    }
+
+    /// Write the changed values with `persist`.
+    pub fn with_persist<F>(mut self, persist: F) -> Self
+    where
+        F: Fn(&str, &Value) -> Result<()> + Send + Sync + 'static,
+    {
+        self.persist = Some(Box::new(persist));
+        self
+    }
+
+    /// Make `key` changeable, its current value being `value`.
+    pub fn register<T, F>(&self, key: &str, value: T, apply: F)
+    where
+        T: Serialize,
+        F: Fn(&Value) -> Result<()> + Send + Sync + 'static,
+    {
+        let setting = Setting {
+            value: serde_json::to_value(value).unwrap_or_default(),
+            apply: Box::new(apply),
+        };
+        self.settings
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), setting);
+    }
+
+    /// Check `token` against the admin token.
+    pub fn authorize(&self, token: Option<&str>) -> Result<()> {
+        let expected = self.token.as_deref().ok_or_else(|| {
+            anyhow!(ApiError::new(
+                ErrorCode::Forbidden,
+                "Admin rpcs are refused without admin_token in the server config"
+            ))
+        })?;
+        // comparing the hashes does not tell how much of the token matched
+        match token {
+            Some(token) if Sha256::digest(token) == Sha256::digest(expected) => Ok(()),
+            _ => Err(anyhow!(ApiError::new(
+                ErrorCode::Unauthorized,
+                "Missing or invalid admin token"
+            ))),
+        }
+    }
+
+    /// Current values of `keys`, of every setting when empty.
+    pub fn get(&self, keys: &[String]) -> Result<BTreeMap<String, Value>> {
+        let settings = self.settings.lock().unwrap();
+        if keys.is_empty() {
+            return Ok(settings
+                .iter()
+                .map(|(key, setting)| (key.clone(), setting.value.clone()))
+                .collect());
+        }
+        keys.iter()
+            .map(|key| match settings.get(key) {
+                Some(setting) => Ok((key.clone(), setting.value.clone())),
+                None => Err(unknown(key, &settings)),
+            })
+            .collect()
+    }
+
+    /// Apply `value` to `key` and write it to the config file.
+    pub fn set(&self, key: &str, value: Value) -> Result<SettingChange> {
+        let mut settings = self.settings.lock().unwrap();
+        if !settings.contains_key(key) {
+            return Err(unknown(key, &settings));
+        }
+        let setting = settings.get_mut(key).unwrap();
+        (setting.apply)(&value).map_err(|e| match e.downcast::<ApiError>() {
+            Ok(e) => anyhow!(e),
+            Err(e) => anyhow!(ApiError::new(
+                ErrorCode::InvalidParams,
+                format!("Invalid value {value} of {key}: {e}")
+            )),
+        })?;
+        let previous = std::mem::replace(&mut setting.value, value.clone());
+        info!("Changed {key} from {previous} to {value}");
+
+        let persisted = match &self.persist {
+            Some(persist) => match persist(key, &value) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("{key} was changed but not written to the config file: {e}");
+                    false
+                }
+            },
+            None => false,
+        };
+        Ok(SettingChange {
+            key: key.to_string(),
+            previous,
+            value,
+            persisted,
+        })
+    }
+}
+
+/// Value of a setting as `T`, for the [`Apply`] functions.
+pub fn parse<T: DeserializeOwned>(value: &Value) -> Result<T> {
+    Ok(serde_json::from_value(value.clone())?)
+}
+
+fn unknown(key: &str, settings: &BTreeMap<String, Setting>) -> anyhow::Error {
+    anyhow!(ApiError::new(
+        ErrorCode::InvalidParams,
+        format!("{key} cannot be changed at runtime")
+    )
+    .with_details(json!({ "settings": settings.keys().collect::<Vec<_>>() })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    };
+
+    #[test]
+    fn test_settings() {
+        let persisted = Arc::new(Mutex::new(vec![]));
+        let written = Arc::clone(&persisted);
+        let settings = Settings::new(Some("secret".to_string())).with_persist(move |key, value| {
+            written
+                .lock()
+                .unwrap()
+                .push((key.to_string(), value.clone()));
+            Ok(())
+        });
+        let applied = Arc::new(AtomicU64::new(5));
+        let rate = Arc::clone(&applied);
+        settings.register("provider_config.publish_batch_ms", 5, move |value| {
+            rate.store(parse(value)?, Ordering::SeqCst);
+            Ok(())
+        });
+
+        assert!(settings.authorize(Some("secret")).is_ok());
+        assert!(settings.authorize(Some("guess")).is_err());
+        assert!(settings.authorize(None).is_err());
+        assert!(Settings::new(None).authorize(Some("")).is_err());
+
+        let change = settings
+            .set("provider_config.publish_batch_ms", json!(100))
+            .unwrap();
+        assert_eq!((change.previous, change.persisted), (json!(5), true));
+        assert_eq!(applied.load(Ordering::SeqCst), 100);
+        assert_eq!(
+            persisted.lock().unwrap()[0],
+            ("provider_config.publish_batch_ms".to_string(), json!(100))
+        );
+
+        // invalid values are neither applied nor persisted
+        let err = settings
+            .set("provider_config.publish_batch_ms", json!(-1))
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ApiError>().unwrap().code,
+            ErrorCode::InvalidParams
+        );
+        assert!(settings.set("server_config.port", json!(80)).is_err());
+        assert_eq!(
+            settings.get(&[]).unwrap()["provider_config.publish_batch_ms"],
+            json!(100)
+        );
+        assert_eq!(persisted.lock().unwrap().len(), 1);
+    }
+}
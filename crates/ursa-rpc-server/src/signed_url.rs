@@ -0,0 +1,147 @@
+//! Signed, expiring retrieval urls.
+//!
+//! Operators of private gateways hand out time-limited access to content with urls
+//! like `/<cid>?exp=<unix seconds>&sig=<hex>`, where `sig` is the HMAC-SHA256 of
+//! `<cid>:<exp>` under the configured secret. With `required` set the content routes
+//! only serve such urls, otherwise unsigned retrievals keep working and only a
+//! signature that is present has to be valid.
+
+use anyhow::{anyhow, Result};
+use cid::Cid;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fmt;
+use ursa_utils::{hex, unhex, unix_now};
+
+use crate::config::SignedUrlConfig;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedUrl {
+    /// Path and query of the url, relative to the content listener.
+    pub path: String,
+    /// Unix time in seconds the url expires at.
+    pub exp: u64,
+    pub sig: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignatureError {
+    Missing,
+    Invalid,
+    Expired,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::Missing => write!(f, "The url must be signed"),
+            SignatureError::Invalid => write!(f, "The url signature is invalid"),
+            SignatureError::Expired => write!(f, "The signed url expired"),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+#[derive(Default)]
+pub struct SignedUrls {
+    config: SignedUrlConfig,
+}
+
+impl SignedUrls {
+    pub fn new(config: SignedUrlConfig) -> Self {
+        Self { config }
+    }
+
+    /// Sign a retrieval url of `cid` valid until `exp`.
+    pub fn sign(&self, cid: &Cid, exp: u64) -> Result<SignedUrl> {
+        let mac = match self.mac(cid, exp) {
+            Some(mac) => mac,
+            None => return Err(anyhow!("No secret is configured to sign urls with")),
+        };
+        let sig = hex(&mac.finalize().into_bytes());
+        Ok(SignedUrl {
+            path: format!("/{cid}?exp={exp}&sig={sig}"),
+            exp,
+            sig,
+        })
+    }
+
+    /// Sign a retrieval url of `cid` valid for `ttl_secs`, or the configured default.
+    pub fn sign_for(&self, cid: &Cid, ttl_secs: Option<u64>) -> Result<SignedUrl> {
+        let ttl = ttl_secs.unwrap_or(self.config.default_ttl_secs);
+        self.sign(cid, unix_now().saturating_add(ttl))
+    }
+
+    /// Check whether a retrieval of `cid` with the `exp` and `sig` of its url may be
+    /// served at unix time `now`.
+    pub fn verify(
+        &self,
+        cid: &Cid,
+        exp: Option<u64>,
+        sig: Option<&str>,
+        now: u64,
+    ) -> Result<(), SignatureError> {
+        let (exp, sig) = match (exp, sig) {
+            (Some(exp), Some(sig)) => (exp, sig),
+            (None, None) if !self.config.required => return Ok(()),
+            (None, None) => return Err(SignatureError::Missing),
+            _ => return Err(SignatureError::Invalid),
+        };
+
+        let mac = self.mac(cid, exp).ok_or(SignatureError::Invalid)?;
+        let sig = unhex(sig).ok_or(SignatureError::Invalid)?;
+        mac.verify_slice(&sig)
+            .map_err(|_| SignatureError::Invalid)?;
+        if exp < now {
+            return Err(SignatureError::Expired);
+        }
+        Ok(())
+    }
+
+    fn mac(&self, cid: &Cid, exp: u64) -> Option<Hmac<Sha256>> {
+        let secret = self.config.secret.as_ref()?;
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac takes keys of any size");
+        mac.update(format!("{cid}:{exp}").as_bytes());
+        Some(mac)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_signed_url() {
+        let cid =
+            Cid::from_str("bafkreif2opfibjypwkjzzry3jbibcjqcjwnpoqpeiqw75eu3s3u3zbdszq").unwrap();
+        let urls = SignedUrls::new(SignedUrlConfig {
+            secret: Some("secret".to_string()),
+            required: true,
+            default_ttl_secs: 3600,
+        });
+
+        let url = urls.sign(&cid, 100).unwrap();
+        assert_eq!(url.path, format!("/{cid}?exp=100&sig={}", url.sig));
+        assert_eq!(urls.verify(&cid, Some(100), Some(&url.sig), 99), Ok(()));
+        assert_eq!(
+            urls.verify(&cid, Some(100), Some(&url.sig), 101),
+            Err(SignatureError::Expired)
+        );
+        assert_eq!(
+            urls.verify(&cid, Some(200), Some(&url.sig), 99),
+            Err(SignatureError::Invalid)
+        );
+        assert_eq!(
+            urls.verify(&cid, None, None, 99),
+            Err(SignatureError::Missing)
+        );
+
+        // unsigned urls are served by public gateways
+        assert_eq!(SignedUrls::default().verify(&cid, None, None, 99), Ok(()));
+        assert!(SignedUrls::default().sign(&cid, 100).is_err());
+    }
+}
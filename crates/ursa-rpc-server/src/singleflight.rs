@@ -0,0 +1,181 @@
+//! Request coalescing.
+//!
+//! [`SingleFlight`] makes sure only one fetch runs per key at a time. Callers that
+//! arrive while a fetch is in flight wait for it and share its result instead of
+//! issuing their own network query. When the caller running the fetch goes away
+//! before it finishes, one of the waiters runs its own fetch in its place.
+
+use anyhow::{anyhow, Result};
+use futures::{channel::oneshot, Future};
+use std::{collections::HashMap, hash::Hash, sync::Mutex};
+
+type Waiter = oneshot::Sender<Result<(), String>>;
+
+pub(crate) struct SingleFlight<K> {
+    inflight: Mutex<HashMap<K, Vec<Waiter>>>,
+}
+
+impl<K> Default for SingleFlight<K> {
+    fn default() -> Self {
+        Self {
+            inflight: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K> SingleFlight<K>
+where
+    K: Eq + Hash + Clone,
+{
+    /// Run `fetch` unless a fetch for `key` is already in flight, in which case
+    /// wait for that one to finish instead.
+    pub async fn run<F>(&self, key: K, fetch: F) -> Result<()>
+    where
+        F: Future<Output = Result<()>>,
+    {
+        loop {
+            let waiter = {
+                let mut inflight = self.inflight.lock().unwrap();
+                match inflight.get_mut(&key) {
+                    Some(waiters) => {
+                        let (sender, receiver) = oneshot::channel();
+                        waiters.push(sender);
+                        Some(receiver)
+                    }
+                    None => {
+                        inflight.insert(key.clone(), Vec::new());
+                        None
+                    }
+                }
+            };
+
+            match waiter {
+                Some(receiver) => match receiver.await {
+                    Ok(result) => return result.map_err(|e| anyhow!(e)),
+                    // the leader was dropped mid-fetch, the first waiter back leads
+                    Err(_) => continue,
+                },
+                None => break,
+            }
+        }
+
+        let mut flight = Flight {
+            group: self,
+            key,
+            result: None,
+        };
+        let result = fetch.await;
+        flight.result = Some(result.as_ref().map(|_| ()).map_err(|e| format!("{:?}", e)));
+        result
+    }
+}
+
+/// Hands the leader's result to the waiters, also when the leader is dropped mid-fetch.
+struct Flight<'a, K: Eq + Hash> {
+    group: &'a SingleFlight<K>,
+    key: K,
+    result: Option<Result<(), String>>,
+}
+
+impl<'a, K: Eq + Hash> Drop for Flight<'a, K> {
+    fn drop(&mut self) {
+        let waiters = self
+            .group
+            .inflight
+            .lock()
+            .unwrap()
+            .remove(&self.key)
+            .unwrap_or_default();
+
+        // without a result the senders are dropped and the waiters retry
+        if let Some(result) = &self.result {
+            for waiter in waiters {
+                let _ = waiter.send(result.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    #[async_std::test]
+    async fn test_concurrent_runs_share_one_fetch() {
+        let group = Arc::new(SingleFlight::default());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let (release, released) = oneshot::channel::<()>();
+
+        let leader = {
+            let group = group.clone();
+            let calls = calls.clone();
+            task::spawn(async move {
+                group
+                    .run(1, async move {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        released.await.unwrap();
+                        Ok(())
+                    })
+                    .await
+            })
+        };
+        task::sleep(Duration::from_millis(50)).await;
+
+        let follower = {
+            let group = group.clone();
+            let calls = calls.clone();
+            task::spawn(async move {
+                group
+                    .run(1, async move {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        Ok(())
+                    })
+                    .await
+            })
+        };
+        task::sleep(Duration::from_millis(50)).await;
+
+        release.send(()).unwrap();
+        assert!(leader.await.is_ok());
+        assert!(follower.await.is_ok());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[async_std::test]
+    async fn test_waiter_takes_over_a_dropped_fetch() {
+        let group = Arc::new(SingleFlight::default());
+
+        let leader = {
+            let group = group.clone();
+            task::spawn(async move { group.run(1, futures::future::pending::<Result<()>>()).await })
+        };
+        task::sleep(Duration::from_millis(50)).await;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let follower = {
+            let group = group.clone();
+            let calls = calls.clone();
+            task::spawn(async move {
+                group
+                    .run(1, async move {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        Ok(())
+                    })
+                    .await
+            })
+        };
+        task::sleep(Duration::from_millis(50)).await;
+
+        leader.cancel().await;
+        assert!(follower.await.is_ok());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,516 @@
+//! UnixFS file encoding.
+//!
+//! Raw files ingested through `ursa_put_url` are split into fixed size raw leaves and
+//! linked together under balanced dag-pb UnixFS file nodes, the layout ipfs uses with
+//! raw leaves, so the resulting root cid can be fetched and served like any other dag.
+//! The blocks are hashed with sha2-256 like ipfs does by default, or with blake3.
+//! Directories put with `ursa_put_file` are encoded the same way, every file chunked
+//! and linked by name under a dag-pb directory node. [`write_file`] goes the other
+//! way, writing the bytes of a stored UnixFS file out one block at a time.
+
+use anyhow::{anyhow, Result};
+use cid::{
+    multihash::{Code, MultihashDigest},
+    Cid,
+};
+use ipld_blockstore::BlockStore;
+use libipld::{store::DefaultParams, Block, Ipld, IpldCodec};
+use std::{fs, io::Write, mem, path::Path};
+use ursa_store::Store;
+use ursa_utils::ToIpldCid;
+
+use crate::error::ApiError;
+
+const RAW: u64 = 0x55;
+const DAG_PB: u64 = 0x70;
+
+/// Maximum number of links per UnixFS node, as in the go-ipfs balanced layout.
+const MAX_LINKS: usize = 174;
+
+/// UnixFS `Data.Type` of raw data, of a directory and of a file.
+const UNIXFS_RAW: u64 = 0;
+const UNIXFS_DIRECTORY: u64 = 1;
+const UNIXFS_FILE: u64 = 2;
+
+struct Node {
+    cid: Cid,
+    /// Size of the encoded blocks under and including this node.
+    tsize: u64,
+    /// Size of the file content under this node.
+    filesize: u64,
+}
+
+/// Encode `data` as a UnixFS file with `hash`, returning the root cid and every block
+/// of the dag.
+pub fn encode_file(
+    data: &[u8],
+    chunk_size: usize,
+    hash: Code,
+) -> Result<(Cid, Vec<(Cid, Vec<u8>)>)> {
+    let mut encoder = FileEncoder::new(chunk_size, hash)?;
+    let mut blocks = vec![];
+    encoder.update(data, &mut blocks);
+    let root = encoder.finish(&mut blocks);
+    Ok((root, blocks))
+}
+
+/// [`encode_file`] over data arriving in pieces. The leaves are handed out as soon as
+/// they fill up, only their cids and sizes are kept to build the nodes above them.
+pub struct FileEncoder {
+    chunk_size: usize,
+    hash: Code,
+    pending: Vec<u8>,
+    leaves: Vec<Node>,
+}
+
+impl FileEncoder {
+    pub fn new(chunk_size: usize, hash: Code) -> Result<Self> {
+        if chunk_size == 0 {
+            return Err(anyhow!("The chunk size must not be zero"));
+        }
+        Ok(Self {
+            chunk_size,
+            hash,
+            pending: Vec::with_capacity(chunk_size),
+            leaves: vec![],
+        })
+    }
+
+    /// Append `data` to the file, pushing the leaves it completes to `blocks`.
+    pub fn update(&mut self, mut data: &[u8], blocks: &mut Vec<(Cid, Vec<u8>)>) {
+        while !data.is_empty() {
+            let take = (self.chunk_size - self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.pending.len() == self.chunk_size {
+                self.leaf(blocks);
+            }
+        }
+    }
+
+    /// Push the last leaf and the nodes above the leaves to `blocks`, returning the
+    /// root cid.
+    pub fn finish(mut self, blocks: &mut Vec<(Cid, Vec<u8>)>) -> Cid {
+        if !self.pending.is_empty() {
+            self.leaf(blocks);
+        }
+        let mut layer = self.leaves;
+        if layer.is_empty() {
+            layer.push(file_node(&[], self.hash, blocks));
+        }
+        while layer.len() > 1 {
+            layer = layer
+                .chunks(MAX_LINKS)
+                .map(|children| file_node(children, self.hash, blocks))
+                .collect();
+        }
+        layer[0].cid
+    }
+
+    fn leaf(&mut self, blocks: &mut Vec<(Cid, Vec<u8>)>) {
+        let chunk = mem::replace(&mut self.pending, Vec::with_capacity(self.chunk_size));
+        let cid = Cid::new_v1(RAW, self.hash.digest(&chunk));
+        self.leaves.push(Node {
+            cid,
+            tsize: chunk.len() as u64,
+            filesize: chunk.len() as u64,
+        });
+        blocks.push((cid, chunk));
+    }
+}
+
+/// Encode the directory at `dir` as a UnixFS directory, chunking its files with
+/// `hash` and returning the root cid and every block of the dag.
+///
+/// Entries matching one of the `ignore` globs are left out: a pattern with a `/`
+/// is matched against the path under `dir`, any other against the entry name.
+/// Symlinks are skipped, and the files may add up to `max_size` bytes.
+pub fn encode_directory(
+    dir: &Path,
+    ignore: &[String],
+    chunk_size: usize,
+    hash: Code,
+    max_size: u64,
+) -> Result<(Cid, Vec<(Cid, Vec<u8>)>)> {
+    if chunk_size == 0 {
+        return Err(anyhow!("The chunk size must not be zero"));
+    }
+    let mut blocks = vec![];
+    let mut budget = max_size;
+    let root = directory(dir, "", ignore, chunk_size, hash, &mut budget, &mut blocks)?;
+    Ok((root.cid, blocks))
+}
+
+fn directory(
+    dir: &Path,
+    prefix: &str,
+    ignore: &[String],
+    chunk_size: usize,
+    hash: Code,
+    budget: &mut u64,
+    blocks: &mut Vec<(Cid, Vec<u8>)>,
+) -> Result<Node> {
+    let mut entries = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|name| anyhow!("{} is not a valid utf-8 name", name.to_string_lossy()))?;
+        let relative = if prefix.is_empty() {
+            name.clone()
+        } else {
+            format!("{prefix}/{name}")
+        };
+        if is_ignored(ignore, &name, &relative) {
+            continue;
+        }
+
+        let file_type = entry.file_type()?;
+        let node = if file_type.is_dir() {
+            directory(
+                &entry.path(),
+                &relative,
+                ignore,
+                chunk_size,
+                hash,
+                budget,
+                blocks,
+            )?
+        } else if file_type.is_file() {
+            *budget = budget
+                .checked_sub(entry.metadata()?.len())
+                .ok_or_else(|| anyhow!("The directory is larger than the limit at {relative}"))?;
+            let data = fs::read(entry.path())?;
+            let (cid, file_blocks) = encode_file(&data, chunk_size, hash)?;
+            let tsize = file_blocks
+                .iter()
+                .map(|(_, block)| block.len() as u64)
+                .sum();
+            blocks.extend(file_blocks);
+            Node {
+                cid,
+                tsize,
+                filesize: data.len() as u64,
+            }
+        } else {
+            continue;
+        };
+        entries.push((name, node));
+    }
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(directory_node(&entries, hash, blocks))
+}
+
+/// Encode a dag-pb directory node linking to `entries` by name.
+fn directory_node(
+    entries: &[(String, Node)],
+    hash: Code,
+    blocks: &mut Vec<(Cid, Vec<u8>)>,
+) -> Node {
+    let mut unixfs = vec![];
+    put_varint_field(&mut unixfs, 1, UNIXFS_DIRECTORY);
+
+    let mut node = vec![];
+    for (name, child) in entries {
+        let mut link = vec![];
+        put_bytes_field(&mut link, 1, &child.cid.to_bytes());
+        put_bytes_field(&mut link, 2, name.as_bytes());
+        put_varint_field(&mut link, 3, child.tsize);
+        put_bytes_field(&mut node, 2, &link);
+    }
+    put_bytes_field(&mut node, 1, &unixfs);
+
+    let cid = Cid::new_v1(DAG_PB, hash.digest(&node));
+    let tsize = node.len() as u64 + entries.iter().map(|(_, child)| child.tsize).sum::<u64>();
+    blocks.push((cid, node));
+
+    Node {
+        cid,
+        tsize,
+        filesize: 0,
+    }
+}
+
+fn is_ignored(ignore: &[String], name: &str, relative: &str) -> bool {
+    ignore.iter().any(|pattern| {
+        if pattern.contains('/') {
+            glob_match(pattern.trim_matches('/'), relative)
+        } else {
+            glob_match(pattern, name)
+        }
+    })
+}
+
+/// Whether `text` matches `pattern`, where `*` stands for any run of characters and
+/// `?` for any one character.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // where the last `*` was seen, to widen it on a mismatch
+    let mut star = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Encode a dag-pb file node linking to `children`.
+fn file_node(children: &[Node], hash: Code, blocks: &mut Vec<(Cid, Vec<u8>)>) -> Node {
+    let filesize: u64 = children.iter().map(|child| child.filesize).sum();
+
+    let mut unixfs = vec![];
+    put_varint_field(&mut unixfs, 1, UNIXFS_FILE);
+    put_varint_field(&mut unixfs, 3, filesize);
+    for child in children {
+        put_varint_field(&mut unixfs, 4, child.filesize);
+    }
+
+    // dag-pb puts the links before the data
+    let mut node = vec![];
+    for child in children {
+        let mut link = vec![];
+        put_bytes_field(&mut link, 1, &child.cid.to_bytes());
+        put_bytes_field(&mut link, 2, &[]);
+        put_varint_field(&mut link, 3, child.tsize);
+        put_bytes_field(&mut node, 2, &link);
+    }
+    put_bytes_field(&mut node, 1, &unixfs);
+
+    let cid = Cid::new_v1(DAG_PB, hash.digest(&node));
+    let tsize = node.len() as u64 + children.iter().map(|child| child.tsize).sum::<u64>();
+    blocks.push((cid, node));
+
+    Node {
+        cid,
+        tsize,
+        filesize,
+    }
+}
+
+/// Write the content of the stored UnixFS file under `root` to `out`, returning the
+/// bytes written.
+pub fn write_file<S, W>(store: &Store<S>, root: Cid, out: &mut W) -> Result<u64>
+where
+    S: BlockStore + Sync + Send + 'static,
+    W: Write,
+{
+    let mut written = 0;
+    // links are pushed last to first so the leaves come out in file order
+    let mut pending = vec![root.to_ipld_cid()];
+    while let Some(cid) = pending.pop() {
+        let data = store.blockstore().read(cid.to_bytes())?.ok_or_else(|| {
+            anyhow!(ApiError::not_found(format!(
+                "The block {cid} is not stored on this node"
+            )))
+        })?;
+        if cid.codec() == RAW {
+            out.write_all(&data)?;
+            written += data.len() as u64;
+            continue;
+        }
+
+        let not_a_file = || {
+            anyhow!(ApiError::invalid_params(format!(
+                "{root} is not a UnixFS file"
+            )))
+        };
+        if cid.codec() != DAG_PB {
+            return Err(not_a_file());
+        }
+        let node = match Block::<DefaultParams>::new(cid, data)?.decode::<IpldCodec, Ipld>()? {
+            Ipld::Map(node) => node,
+            _ => return Err(not_a_file()),
+        };
+        let content = match node.get("Data") {
+            Some(Ipld::Bytes(data)) => match file_data(data) {
+                Some((UNIXFS_RAW | UNIXFS_FILE, content)) => content,
+                _ => return Err(not_a_file()),
+            },
+            _ => return Err(not_a_file()),
+        };
+        out.write_all(content)?;
+        written += content.len() as u64;
+
+        if let Some(Ipld::List(links)) = node.get("Links") {
+            for link in links.iter().rev() {
+                match link {
+                    Ipld::Map(link) => match link.get("Hash") {
+                        Some(Ipld::Link(cid)) => pending.push(*cid),
+                        _ => return Err(not_a_file()),
+                    },
+                    _ => return Err(not_a_file()),
+                }
+            }
+        }
+    }
+    Ok(written)
+}
+
+/// `Data.Type` and `Data.Data` of the protobuf encoded UnixFS `data`.
+fn file_data(data: &[u8]) -> Option<(u64, &[u8])> {
+    let (mut data_type, mut content) = (None, &[][..]);
+    let mut data = data;
+    while !data.is_empty() {
+        let key = get_varint(&mut data)?;
+        match key & 7 {
+            0 => {
+                let value = get_varint(&mut data)?;
+                if key >> 3 == 1 {
+                    data_type = Some(value);
+                }
+            }
+            2 => {
+                let len = get_varint(&mut data)? as usize;
+                if key >> 3 == 2 {
+                    content = data.get(..len)?;
+                }
+                data = data.get(len..)?;
+            }
+            _ => return None,
+        }
+    }
+    Some((data_type?, content))
+}
+
+pub(crate) fn get_varint(data: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+    for (i, byte) in data.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            *data = &data[i + 1..];
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_varint_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+    put_varint(buf, field << 3);
+    put_varint(buf, value);
+}
+
+fn put_bytes_field(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_varint(buf, field << 3 | 2);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::{rocks::RocksDb, rocks_config::RocksDbConfig};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_encode_file() {
+        // a single chunk is stored as a bare raw block
+        let (root, blocks) = encode_file(b"hello ursa", 1024, Code::Sha2_256).unwrap();
+        assert_eq!(root.codec(), RAW);
+        assert_eq!(root.hash().code(), u64::from(Code::Sha2_256));
+        assert_eq!(blocks, vec![(root, b"hello ursa".to_vec())]);
+
+        // more chunks than fit in one node need a second level
+        let data = vec![7u8; MAX_LINKS + 1];
+        let (root, blocks) = encode_file(&data, 1, Code::Blake3_256).unwrap();
+        assert_eq!(root.codec(), DAG_PB);
+        // every leaf, the two nodes above them and the root
+        assert_eq!(blocks.len(), MAX_LINKS + 1 + 2 + 1);
+        assert_eq!(blocks.last().unwrap().0, root);
+        assert!(blocks
+            .iter()
+            .all(|(cid, _)| cid.hash().code() == u64::from(Code::Blake3_256)));
+
+        let (empty, blocks) = encode_file(&[], 1024, Code::Sha2_256).unwrap();
+        assert_eq!(empty.codec(), DAG_PB);
+        assert_eq!(blocks.len(), 1);
+    }
+
+    #[test]
+    fn test_file_encoder() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        let (root, blocks) = encode_file(&data, 64, Code::Sha2_256).unwrap();
+
+        // fed in pieces not lining up with the chunks, the dag is the same
+        let mut encoder = FileEncoder::new(64, Code::Sha2_256).unwrap();
+        let mut streamed = vec![];
+        for piece in data.chunks(100) {
+            encoder.update(piece, &mut streamed);
+        }
+        assert_eq!(encoder.finish(&mut streamed), root);
+        assert_eq!(streamed, blocks);
+    }
+
+    #[test]
+    fn test_encode_directory() {
+        assert!(glob_match("*.tmp", "draft.tmp"));
+        assert!(glob_match("a?c*", "abcdef"));
+        assert!(!glob_match("*.tmp", "draft.tmp.html"));
+
+        let dir = std::env::temp_dir().join("ursa_unixfs_directory");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("css")).unwrap();
+        fs::write(dir.join("index.html"), b"<h1>ursa</h1>").unwrap();
+        fs::write(dir.join("css/site.css"), b"h1 {}").unwrap();
+
+        let (root, blocks) = encode_directory(&dir, &[], 1024, Code::Sha2_256, 1024).unwrap();
+        assert_eq!(root.codec(), DAG_PB);
+        // the two files, the css directory and the root
+        assert_eq!(blocks.len(), 4);
+        let (index, _) = encode_file(b"<h1>ursa</h1>", 1024, Code::Sha2_256).unwrap();
+        assert!(blocks.iter().any(|(cid, _)| *cid == index));
+
+        // ignored entries leave the dag as it was without them
+        fs::write(dir.join("css/draft.tmp"), b"wip").unwrap();
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        let ignore = vec!["*.tmp".to_string(), ".git".to_string()];
+        let (ignored, _) = encode_directory(&dir, &ignore, 1024, Code::Sha2_256, 1024).unwrap();
+        assert_eq!(ignored, root);
+
+        assert!(encode_directory(&dir, &[], 1024, Code::Sha2_256, 8).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_file() {
+        let db = RocksDb::open("unixfs_db", &RocksDbConfig::default()).unwrap();
+        let store = Store::new(Arc::new(db));
+
+        let data: Vec<u8> = (0..=255u8).cycle().take(MAX_LINKS * 3 + 5).collect();
+        let (root, blocks) = encode_file(&data, 2, Code::Sha2_256).unwrap();
+        for (cid, block) in &blocks {
+            store.blockstore().write(cid.to_bytes(), block).unwrap();
+        }
+
+        let mut out = vec![];
+        assert_eq!(
+            write_file(&store, root, &mut out).unwrap(),
+            data.len() as u64
+        );
+        assert_eq!(out, data);
+
+        let missing = Cid::new_v1(RAW, Code::Sha2_256.digest(b"missing"));
+        assert!(write_file(&store, missing, &mut vec![]).is_err());
+    }
+}
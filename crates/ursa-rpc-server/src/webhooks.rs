@@ -0,0 +1,237 @@
+//! Content lifecycle webhooks.
+//!
+//! External pipelines register urls under `[server_config.webhooks]` to react to the state of
+//! the node instead of polling it. Every [`WebhookEvent`] is POSTed as json to each
+//! url, and with a secret configured the body is signed with HMAC-SHA256 in the
+//! [`SIGNATURE_HEADER`] header. Deliveries are fire and forget, failures are logged.
+//!
+//! Requests are counted for up to `max_tracked` cids, the one requested least recently
+//! is forgotten to make room for another.
+
+use async_std::task;
+use cid::Cid;
+use fnv::FnvHashMap;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+use ursa_network::events::{NodeEvent, NodeEvents};
+use ursa_utils::{hex, unix_now};
+
+use crate::config::WebhookConfig;
+
+/// Header carrying the hex HMAC-SHA256 of the body, prefixed with `sha256=`.
+pub const SIGNATURE_HEADER: &str = "x-ursa-signature";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// Content was stored and indexed by this node.
+    Ingested { cid: String },
+    /// Hot content was pushed to its replicas.
+    Replicated { cid: String, providers: Vec<String> },
+    /// Content was removed from this node.
+    Evicted { cid: String },
+    /// Content was requested `requests` times, sent once per cid.
+    Requested { cid: String, requests: u64 },
+    /// A stored block did not hash to its cid and was quarantined.
+    Corrupted { cid: String },
+}
+
+#[derive(Serialize)]
+struct Delivery<'a> {
+    #[serde(flatten)]
+    event: &'a WebhookEvent,
+    /// Seconds since the unix epoch.
+    timestamp: u64,
+}
+
+#[derive(Default)]
+pub struct Webhooks {
+    config: WebhookConfig,
+    /// Requests of every tracked cid, with the time of the last one.
+    requests: Mutex<FnvHashMap<Cid, (u64, Instant)>>,
+}
+
+impl Webhooks {
+    pub fn new(config: WebhookConfig) -> Self {
+        Self {
+            config,
+            requests: Default::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.config.urls.is_empty()
+    }
+
+    /// Send `event` to every configured url.
+    pub fn notify(&self, event: WebhookEvent) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let timestamp = unix_now();
+        let body = match serde_json::to_vec(&Delivery {
+            event: &event,
+            timestamp,
+        }) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Failed to serialize webhook event {event:?}: {e}");
+                return;
+            }
+        };
+        let signature = self
+            .config
+            .secret
+            .as_ref()
+            .map(|secret| sign(secret, &body));
+
+        for url in self.config.urls.clone() {
+            task::spawn(deliver(url, body.clone(), signature.clone()));
+        }
+    }
+
+    /// Count a request of `cid`, notifying once it reaches the request threshold.
+    pub fn requested(&self, cid: Cid) {
+        self.requested_at(cid, Instant::now())
+    }
+
+    fn requested_at(&self, cid: Cid, now: Instant) {
+        if !self.is_enabled() || self.config.request_threshold == 0 {
+            return;
+        }
+
+        let requests = {
+            let mut counts = self.requests.lock().unwrap();
+            if !counts.contains_key(&cid) && counts.len() >= self.config.max_tracked.max(1) {
+                let oldest = counts
+                    .iter()
+                    .min_by_key(|(_, (_, last))| *last)
+                    .map(|(cid, _)| *cid);
+                if let Some(oldest) = oldest {
+                    counts.remove(&oldest);
+                }
+            }
+            let (count, last) = counts.entry(cid).or_insert((0, now));
+            *count += 1;
+            *last = now;
+            *count
+        };
+        if requests == self.config.request_threshold {
+            self.notify(WebhookEvent::Requested {
+                cid: cid.to_string(),
+                requests,
+            });
+        }
+    }
+
+    /// Notify the eviction of `cid`, restarting its request count.
+    pub fn evicted(&self, cid: Cid) {
+        self.requests.lock().unwrap().remove(&cid);
+        self.notify(WebhookEvent::Evicted {
+            cid: cid.to_string(),
+        });
+    }
+
+    /// Forward the replications of the network service in `node_events`.
+    pub fn watch(self: &Arc<Self>, node_events: &NodeEvents) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let webhooks = Arc::clone(self);
+        let mut events = node_events.subscribe();
+        task::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(NodeEvent::ContentReplicated { cid, providers }) => {
+                        webhooks.notify(WebhookEvent::Replicated { cid, providers })
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Webhooks skipped {skipped} node events")
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+}
+
+/// Hex HMAC-SHA256 of `body` under `secret`, as sent in [`SIGNATURE_HEADER`].
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac takes keys of any size");
+    mac.update(body);
+    format!("sha256={}", hex(&mac.finalize().into_bytes()))
+}
+
+async fn deliver(url: String, body: Vec<u8>, signature: Option<String>) {
+    let mut request = surf::post(&url)
+        .content_type(surf::http::mime::JSON)
+        .body(body);
+    if let Some(signature) = signature {
+        request = request.header(SIGNATURE_HEADER, signature);
+    }
+
+    match request.await {
+        Ok(response) if response.status().is_success() => {}
+        Ok(response) => warn!("Webhook {url} responded with {}", response.status()),
+        Err(e) => warn!("Failed to deliver webhook to {url}: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cid::multihash::{Code, MultihashDigest};
+    use std::{str::FromStr, time::Duration};
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[async_std::test]
+    async fn test_request_threshold() {
+        let webhooks = Webhooks::new(WebhookConfig {
+            urls: vec!["http://127.0.0.1:9/hook".to_string()],
+            secret: None,
+            request_threshold: 2,
+            max_tracked: 2,
+        });
+        let cid =
+            Cid::from_str("bafkreif2opfibjypwkjzzry3jbibcjqcjwnpoqpeiqw75eu3s3u3zbdszq").unwrap();
+
+        webhooks.requested(cid);
+        webhooks.requested(cid);
+        webhooks.requested(cid);
+        assert_eq!(webhooks.requests.lock().unwrap()[&cid].0, 3);
+
+        webhooks.evicted(cid);
+        assert!(webhooks.requests.lock().unwrap().is_empty());
+
+        // the least recently requested cid makes room
+        let others = [b"first", b"other"]
+            .map(|data| Cid::new_v1(0x55, Code::Sha2_256.digest(data.as_slice())));
+        let start = Instant::now();
+        for (i, cid) in [cid, others[0], cid, others[1]].into_iter().enumerate() {
+            webhooks.requested_at(cid, start + Duration::from_secs(i as u64));
+        }
+        let requests = webhooks.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(!requests.contains_key(&others[0]));
+        assert_eq!(requests[&cid].0, 2);
+    }
+}
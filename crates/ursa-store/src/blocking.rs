@@ -0,0 +1,108 @@
+//! Blocking store calls.
+//!
+//! RocksDB calls block the thread they run on, so on an async executor a slow disk
+//! stalls every task sharing the thread, the swarm loop and the http server among
+//! them. A [`BlockingPool`] runs the calls on threads of its own, fed by a bounded
+//! queue. Callers wait for their result, and for room in the queue when the disk
+//! falls behind, without holding up an executor thread.
+
+use anyhow::{anyhow, Result};
+use async_std::{
+    channel::{bounded, Sender},
+    task,
+};
+use std::{
+    panic::{self, AssertUnwindSafe},
+    thread,
+};
+use tracing::debug;
+
+/// Threads and queued calls of the pool of a [`Store`](crate::Store) unless
+/// configured otherwise.
+pub const DEFAULT_BLOCKING_THREADS: usize = 4;
+pub const DEFAULT_BLOCKING_QUEUE_SIZE: usize = 256;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads running blocking store calls. The threads stop once every clone of the
+/// pool is dropped.
+#[derive(Clone)]
+pub struct BlockingPool {
+    jobs: Sender<Job>,
+}
+
+impl Default for BlockingPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_BLOCKING_THREADS, DEFAULT_BLOCKING_QUEUE_SIZE)
+    }
+}
+
+impl BlockingPool {
+    pub fn new(threads: usize, queue_size: usize) -> Self {
+        let (jobs, queue) = bounded::<Job>(queue_size.max(1));
+        for i in 0..threads.max(1) {
+            let queue = queue.clone();
+            thread::Builder::new()
+                .name(format!("ursa-store-{i}"))
+                .spawn(move || {
+                    while let Ok(job) = task::block_on(queue.recv()) {
+                        // a panicking call fails its caller, not the thread
+                        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                    }
+                    debug!("store thread {i} stopped");
+                })
+                .expect("spawning a store thread must succeed");
+        }
+        Self { jobs }
+    }
+
+    /// Run `f` on a store thread, waiting for room when the queue is full.
+    pub async fn run<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = bounded(1);
+        let job: Job = Box::new(move || {
+            let _ = sender.try_send(f());
+        });
+        self.jobs
+            .send(job)
+            .await
+            .map_err(|_| anyhow!("The store threads stopped"))?;
+        receiver
+            .recv()
+            .await
+            .map_err(|_| anyhow!("A store call panicked"))
+    }
+
+    /// Calls waiting for a store thread.
+    pub fn queued(&self) -> usize {
+        self.jobs.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn test_blocking_pool() {
+        let pool = BlockingPool::new(2, 1);
+        let calls: Vec<_> = (0..4usize)
+            .map(|i| {
+                let pool = pool.clone();
+                task::spawn(async move { pool.run(move || i * 2).await })
+            })
+            .collect();
+        for (i, call) in calls.into_iter().enumerate() {
+            assert_eq!(call.await.unwrap(), i * 2);
+        }
+
+        assert!(pool
+            .run(|| -> usize { panic!("disk on fire") })
+            .await
+            .is_err());
+        assert_eq!(pool.run(|| 1).await.unwrap(), 1);
+    }
+}
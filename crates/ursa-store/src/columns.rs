@@ -0,0 +1,315 @@
+//! RocksDB column families.
+//!
+//! Every keyspace of the node lives in a column family of its own, so compacting or
+//! garbage collecting blocks does not rewrite the small keyspaces along with them,
+//! and a backup can copy the metadata without the blocks. A [`ColumnStore`] is the
+//! [`BlockStore`] of a single column family.
+//!
+//! Databases written before the split kept everything in the default column family.
+//! The blocks stay there, [`ColumnDb::migrate`] moves the other keys out once.
+//!
+//! Deletes only write tombstones, the space of evicted blocks is reclaimed once
+//! RocksDB compacts the files holding them. [`ColumnStore::compact`] does so right
+//! away instead of waiting for the background compactions to get there.
+
+use db::{
+    rocks::{Options, DB},
+    rocks_config::RocksDbConfig,
+    Error, Store as DbStore,
+};
+use ipld_blockstore::BlockStore;
+use libipld::Cid;
+use std::{convert::TryFrom, path::Path, sync::Arc};
+
+use crate::config::DatabaseConfig;
+
+/// Write buffer and block cache of the small keyspaces are capped at these sizes.
+const SMALL_WRITE_BUFFER_SIZE: usize = 8 << 20;
+const SMALL_BLOCK_CACHE_SIZE_MB: i32 = 8;
+
+/// Key of the content listing, the only key of the pins keyspace.
+const CONTENT_KEY: &[u8] = b"content";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Column {
+    /// Blocks of the stored dags, keyed by cid.
+    Blocks,
+    /// Listing of the stored content and whether it is pinned.
+    Pins,
+    /// Reputations of other peers.
+    Peers,
+    /// Advertisement chain of the index provider.
+    ProviderAds,
+    /// Access control lists, api keys, published names and other node state.
+    Metadata,
+}
+
+impl Column {
+    pub const ALL: [Column; 5] = [
+        Column::Blocks,
+        Column::Pins,
+        Column::Peers,
+        Column::ProviderAds,
+        Column::Metadata,
+    ];
+
+    /// Column named `name`, the blocks answering to "blocks" as well.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "blocks" => Some(Column::Blocks),
+            _ => Column::ALL.into_iter().find(|column| column.name() == name),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            // blocks of databases written before the split stay where they are
+            Column::Blocks => "default",
+            Column::Pins => "pins",
+            Column::Peers => "peerstore",
+            Column::ProviderAds => "provider_ads",
+            Column::Metadata => "metadata",
+        }
+    }
+
+    /// Options of the column family, the blocks get the sizes of the config and the
+    /// other keyspaces a fraction of them.
+    fn config(self, config: &DatabaseConfig) -> RocksDbConfig {
+        let mut rocks = config.rocks_config();
+        if self != Column::Blocks {
+            rocks.write_buffer_size = rocks.write_buffer_size.min(SMALL_WRITE_BUFFER_SIZE);
+            rocks.optimize_for_point_lookup = rocks
+                .optimize_for_point_lookup
+                .min(SMALL_BLOCK_CACHE_SIZE_MB);
+        }
+        rocks
+    }
+}
+
+/// A RocksDB database with a column family per [`Column`].
+#[derive(Clone)]
+pub struct ColumnDb {
+    db: Arc<DB>,
+}
+
+impl ColumnDb {
+    /// Open the database at `path`, creating the missing column families.
+    pub fn open<P: AsRef<Path>>(path: P, config: &DatabaseConfig) -> Result<Self, Error> {
+        let mut options = Options::from(&config.rocks_config());
+        options.create_missing_column_families(true);
+        let columns = Column::ALL
+            .iter()
+            .map(|column| (column.name(), Options::from(&column.config(config))));
+        let db = DB::open_cf_with_opts(&options, path, columns)?;
+        Ok(Self { db: Arc::new(db) })
+    }
+
+    /// Move the keys other than blocks out of the default column family, the content
+    /// listing to the pins and everything else to the metadata. Does nothing once the
+    /// database has been migrated, returns the number of keys moved.
+    pub fn migrate(&self) -> Result<usize, Error> {
+        let metadata = self.column(Column::Metadata);
+        if metadata.exists(migrated_key(Column::Blocks))? {
+            return Ok(0);
+        }
+
+        let mut keys = vec![];
+        let mut iter = self.db.raw_iterator();
+        iter.seek_to_first();
+        while iter.valid() {
+            if let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+                if Cid::try_from(key).is_err() {
+                    keys.push((key.to_vec(), value.to_vec()));
+                }
+            }
+            iter.next();
+        }
+
+        let blocks = self.column(Column::Blocks);
+        let pins = self.column(Column::Pins);
+        for (key, value) in &keys {
+            if key == CONTENT_KEY {
+                pins.write(key, value)?;
+            } else {
+                metadata.write(key, value)?;
+            }
+            blocks.delete(key)?;
+        }
+        metadata.write(migrated_key(Column::Blocks), b"")?;
+        Ok(keys.len())
+    }
+
+    /// Copy the standalone database at `path` into `column`, once. Returns the number of
+    /// keys copied.
+    pub fn import<P: AsRef<Path>>(&self, column: Column, path: P) -> Result<usize, Error> {
+        let metadata = self.column(Column::Metadata);
+        if metadata.exists(migrated_key(column))? || !path.as_ref().exists() {
+            return Ok(0);
+        }
+
+        let source = DB::open_default(path)?;
+        let target = self.column(column);
+        let mut copied = 0;
+        let mut iter = source.raw_iterator();
+        iter.seek_to_first();
+        while iter.valid() {
+            if let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+                target.write(key, value)?;
+                copied += 1;
+            }
+            iter.next();
+        }
+        metadata.write(migrated_key(column), b"")?;
+        Ok(copied)
+    }
+
+    pub fn column(&self, column: Column) -> ColumnStore {
+        ColumnStore {
+            db: Arc::clone(&self.db),
+            column,
+        }
+    }
+}
+
+/// The keyspace of one column family of a [`ColumnDb`].
+pub struct ColumnStore {
+    db: Arc<DB>,
+    column: Column,
+}
+
+/// Metadata key marking that the keys of `column` were moved into place.
+fn migrated_key(column: Column) -> String {
+    format!("migrated/{}", column.name())
+}
+
+/// Handle of the column family of a [`ColumnStore`], opened with the database.
+macro_rules! cf {
+    ($store:expr) => {
+        $store
+            .db
+            .cf_handle($store.column.name())
+            .expect("column families are created on open")
+    };
+}
+
+impl ColumnStore {
+    pub fn column(&self) -> Column {
+        self.column
+    }
+
+    /// Bytes of the sst files of the column family.
+    pub fn size_on_disk(&self) -> Result<u64, Error> {
+        Ok(self
+            .db
+            .property_int_value_cf(cf!(self), "rocksdb.total-sst-files-size")?
+            .unwrap_or(0))
+    }
+
+    /// Compact the whole column family, returning the bytes reclaimed. Blocks until
+    /// the compaction finished.
+    pub fn compact(&self) -> Result<u64, Error> {
+        let before = self.size_on_disk()?;
+        self.db
+            .compact_range_cf(cf!(self), None::<&[u8]>, None::<&[u8]>);
+        Ok(before.saturating_sub(self.size_on_disk()?))
+    }
+
+    /// Up to `limit` keys and values following the key `after`, from the first key
+    /// when none is given.
+    pub fn scan(
+        &self,
+        after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Error> {
+        let mut iter = self.db.raw_iterator_cf(cf!(self));
+        match after {
+            Some(after) => {
+                iter.seek(after);
+                if iter.valid() && iter.key() == Some(after) {
+                    iter.next();
+                }
+            }
+            None => iter.seek_to_first(),
+        }
+        let mut entries = vec![];
+        while iter.valid() && entries.len() < limit {
+            if let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+                entries.push((key.to_vec(), value.to_vec()));
+            }
+            iter.next();
+        }
+        Ok(entries)
+    }
+}
+
+impl DbStore for ColumnStore {
+    fn read<K>(&self, key: K) -> Result<Option<Vec<u8>>, Error>
+    where
+        K: AsRef<[u8]>,
+    {
+        Ok(self.db.get_cf(cf!(self), key)?)
+    }
+
+    fn write<K, V>(&self, key: K, value: V) -> Result<(), Error>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        Ok(self.db.put_cf(cf!(self), key, value)?)
+    }
+
+    fn delete<K>(&self, key: K) -> Result<(), Error>
+    where
+        K: AsRef<[u8]>,
+    {
+        Ok(self.db.delete_cf(cf!(self), key)?)
+    }
+
+    fn exists<K>(&self, key: K) -> Result<bool, Error>
+    where
+        K: AsRef<[u8]>,
+    {
+        Ok(self.db.get_pinned_cf(cf!(self), key)?.is_some())
+    }
+}
+
+impl BlockStore for ColumnStore {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_columns() {
+        let _ = std::fs::remove_dir_all("ursa_columns_db");
+        let db = ColumnDb::open("ursa_columns_db", &DatabaseConfig::default()).unwrap();
+        let blocks = db.column(Column::Blocks);
+        let metadata = db.column(Column::Metadata);
+
+        blocks.write("key", b"block").unwrap();
+        metadata.write("key", b"metadata").unwrap();
+        assert_eq!(blocks.read("key").unwrap(), Some(b"block".to_vec()));
+        assert_eq!(metadata.read("key").unwrap(), Some(b"metadata".to_vec()));
+
+        metadata.delete("key").unwrap();
+        assert!(!metadata.exists("key").unwrap());
+        assert!(blocks.exists("key").unwrap());
+
+        // keys written before the split move out of the blocks
+        blocks.write(CONTENT_KEY, b"[]").unwrap();
+        assert_eq!(db.migrate().unwrap(), 2);
+        assert_eq!(
+            db.column(Column::Pins).read(CONTENT_KEY).unwrap(),
+            Some(b"[]".to_vec())
+        );
+        assert_eq!(metadata.read("key").unwrap(), Some(b"block".to_vec()));
+        assert!(!blocks.exists(CONTENT_KEY).unwrap());
+        assert_eq!(db.migrate().unwrap(), 0);
+
+        db.column(Column::Blocks).compact().unwrap();
+        assert!(!blocks.exists(CONTENT_KEY).unwrap());
+        assert_eq!(Column::from_name("blocks"), Some(Column::Blocks));
+        assert_eq!(Column::from_name("peerstore"), Some(Column::Peers));
+        assert_eq!(Column::from_name("nope"), None);
+    }
+}
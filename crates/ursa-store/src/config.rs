@@ -0,0 +1,100 @@
+//! RocksDB tuning.
+//!
+//! The defaults of [`RocksDbConfig`] are sized for a large archive machine, a gigabyte
+//! of write buffer alone. Nodes pick a [`DatabaseProfile`] instead, and can override
+//! single options of it.
+
+use db::rocks_config::RocksDbConfig;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DatabaseProfile {
+    /// Small memory footprint for nodes caching content at the edge.
+    EdgeCache,
+    /// Larger caches and stronger compression for nodes keeping a lot of content.
+    Archive,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DatabaseConfig {
+    /// Preset the options below are taken from when unset.
+    pub profile: DatabaseProfile,
+    /// Optional. Size of the block cache in megabytes.
+    pub block_cache_size_mb: Option<u64>,
+    /// Optional. "none", "snappy", "zlib", "bz2", "lz4", "lz4hc" or "zstd".
+    pub compression: Option<String>,
+    /// Optional. Size of the memtable in bytes.
+    pub write_buffer_size: Option<usize>,
+    /// Optional. Files kept open, -1 keeps every file open.
+    pub max_open_files: Option<i32>,
+    /// Optional. Hour of the day, in UTC, every column family is compacted at.
+    pub compaction_hour: Option<u8>,
+    /// Optional. Blocks hashed against their cid every hour, none by default.
+    pub scrub_blocks_per_hour: Option<usize>,
+    /// Optional. Threads running store calls off the async executors, 4 by default.
+    pub blocking_threads: Option<usize>,
+    /// Optional. Store calls queued for those threads before callers wait, 256 by
+    /// default.
+    pub blocking_queue_size: Option<usize>,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            profile: DatabaseProfile::EdgeCache,
+            block_cache_size_mb: None,
+            compression: None,
+            write_buffer_size: None,
+            max_open_files: None,
+            compaction_hour: None,
+            scrub_blocks_per_hour: None,
+            blocking_threads: None,
+            blocking_queue_size: None,
+        }
+    }
+}
+
+impl DatabaseConfig {
+    pub fn rocks_config(&self) -> RocksDbConfig {
+        let (block_cache_size_mb, compression, write_buffer_size, max_open_files) =
+            match self.profile {
+                DatabaseProfile::EdgeCache => (32, "lz4", 64 << 20, 256),
+                DatabaseProfile::Archive => (512, "zstd", 512 << 20, -1),
+            };
+        RocksDbConfig {
+            optimize_for_point_lookup: self.block_cache_size_mb.unwrap_or(block_cache_size_mb)
+                as i32,
+            compression_type: Some(
+                self.compression
+                    .clone()
+                    .unwrap_or_else(|| compression.to_string()),
+            ),
+            write_buffer_size: self.write_buffer_size.unwrap_or(write_buffer_size),
+            max_open_files: self.max_open_files.unwrap_or(max_open_files),
+            ..RocksDbConfig::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rocks_config() {
+        let edge = DatabaseConfig::default().rocks_config();
+        assert_eq!(edge.write_buffer_size, 64 << 20);
+        assert_eq!(edge.compression_type.as_deref(), Some("lz4"));
+
+        let archive = DatabaseConfig {
+            profile: DatabaseProfile::Archive,
+            max_open_files: Some(4096),
+            ..Default::default()
+        }
+        .rocks_config();
+        assert_eq!(archive.optimize_for_point_lookup, 512);
+        assert_eq!(archive.max_open_files, 4096);
+    }
+}
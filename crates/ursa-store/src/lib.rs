@@ -1,3 +1,10 @@
+mod blocking;
+pub mod columns;
+pub mod config;
+pub mod scrub;
 mod store;
+mod validate;
 
+pub use self::blocking::*;
 pub use self::store::*;
+pub use self::validate::*;
@@ -0,0 +1,141 @@
+//! Block scrubbing.
+//!
+//! A block rotting on disk goes unnoticed until a retrieval of it fails. A
+//! [`Scrubber`] reads the blocks keyspace a slice at a time, picking up where the
+//! last slice stopped and starting over past the last block, and hashes every block
+//! against its cid. A corrupt block is moved to `quarantine/<cid>` in the metadata,
+//! so it is not served anymore and is fetched from the network again on its next
+//! retrieval, while its data is kept for inspection.
+
+use db::{Error, Store as DbStore};
+use libipld::{
+    multihash::{Code, MultihashDigest},
+    Cid,
+};
+use std::{convert::TryFrom, sync::Mutex};
+use tracing::error;
+use ursa_metrics::events::{track, MetricEvent};
+
+use crate::columns::{Column, ColumnDb};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScrubReport {
+    /// Blocks hashed.
+    pub checked: usize,
+    /// Blocks that did not hash to their cid, now quarantined.
+    pub corrupt: Vec<Cid>,
+}
+
+pub struct Scrubber {
+    db: ColumnDb,
+    /// Key of the last block checked, none to start at the first block.
+    cursor: Mutex<Option<Vec<u8>>>,
+}
+
+impl Scrubber {
+    pub fn new(db: ColumnDb) -> Self {
+        Self {
+            db,
+            cursor: Mutex::new(None),
+        }
+    }
+
+    /// Hash the next `count` blocks, quarantining the corrupt ones.
+    pub fn scrub(&self, count: usize) -> Result<ScrubReport, Error> {
+        let blocks = self.db.column(Column::Blocks);
+        let metadata = self.db.column(Column::Metadata);
+        let mut cursor = self.cursor.lock().unwrap();
+        let entries = blocks.scan(cursor.as_deref(), count)?;
+        *cursor = match entries.last() {
+            Some((key, _)) if entries.len() == count => Some(key.clone()),
+            // past the last block, the next slice starts over
+            _ => None,
+        };
+
+        let mut report = ScrubReport::default();
+        for (key, data) in entries {
+            // keys written before the column split are not blocks
+            let cid = match Cid::try_from(key.as_slice()) {
+                Ok(cid) => cid,
+                Err(_) => continue,
+            };
+            let code = match Code::try_from(cid.hash().code()) {
+                Ok(code) => code,
+                Err(_) => continue,
+            };
+            report.checked += 1;
+            if code.digest(&data) != *cid.hash() {
+                error!("The data of {cid} does not match its hash, quarantining the block");
+                metadata.write(quarantine_key(&cid), &data)?;
+                blocks.delete(&key)?;
+                report.corrupt.push(cid);
+            }
+        }
+
+        track(
+            MetricEvent::BlocksScrubbed,
+            None,
+            Some(report.checked as f64),
+        );
+        if !report.corrupt.is_empty() {
+            track(
+                MetricEvent::CorruptBlocks,
+                None,
+                Some(report.corrupt.len() as f64),
+            );
+        }
+        Ok(report)
+    }
+}
+
+/// Metadata key the data of a corrupt block is kept under.
+pub fn quarantine_key(cid: &Cid) -> String {
+    format!("quarantine/{cid}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DatabaseConfig;
+    use libipld::{cbor::DagCborCodec, ipld, store::DefaultParams, Block};
+
+    #[test]
+    fn test_scrub() {
+        let _ = std::fs::remove_dir_all("ursa_scrub_db");
+        let db = ColumnDb::open("ursa_scrub_db", &DatabaseConfig::default()).unwrap();
+        let blocks = db.column(Column::Blocks);
+        let cids: Vec<Cid> = (0..3)
+            .map(|i| {
+                let block = Block::<DefaultParams>::encode(
+                    DagCborCodec,
+                    Code::Sha2_256,
+                    &ipld!({ "block": i }),
+                )
+                .unwrap();
+                let data = match i {
+                    1 => b"rotten".to_vec(),
+                    _ => block.data().to_vec(),
+                };
+                blocks.write(block.cid().to_bytes(), data).unwrap();
+                *block.cid()
+            })
+            .collect();
+
+        let scrubber = Scrubber::new(db.clone());
+        let first = scrubber.scrub(2).unwrap();
+        let second = scrubber.scrub(2).unwrap();
+        assert_eq!(first.checked + second.checked, 3);
+        let corrupt: Vec<Cid> = first.corrupt.into_iter().chain(second.corrupt).collect();
+        assert_eq!(corrupt, vec![cids[1]]);
+        assert!(!blocks.exists(cids[1].to_bytes()).unwrap());
+        assert_eq!(
+            db.column(Column::Metadata)
+                .read(quarantine_key(&cids[1]))
+                .unwrap(),
+            Some(b"rotten".to_vec())
+        );
+
+        // the slices start over past the last block
+        assert_eq!(scrubber.scrub(10).unwrap().checked, 2);
+    }
+}
@@ -1,14 +1,23 @@
 use anyhow::anyhow;
-use fnv::FnvHashSet;
+use fnv::{FnvHashMap, FnvHashSet};
 use ipld_blockstore::BlockStore;
 use libipld::store::DefaultParams;
 use libipld::{Block, Cid, Result};
 use libp2p_bitswap::BitswapStore;
-use std::sync::Arc;
-use ursa_utils::convert_cid;
+use metrics::Label;
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Instant};
+use tracing::warn;
+use ursa_metrics::events::{track, MetricEvent};
+
+use crate::{blocking::BlockingPool, columns::Column, validate::validate_block};
 
 pub struct Store<S> {
+    /// Keyspace of the blocks.
     pub db: Arc<S>,
+    /// Keyspaces of the other columns, the blocks keyspace is shared when unset.
+    columns: FnvHashMap<Column, Arc<S>>,
+    blocking: BlockingPool,
 }
 
 impl<S> Store<S>
@@ -16,13 +25,86 @@ where
     S: BlockStore + Send + Sync + 'static,
 {
     pub fn new(db: Arc<S>) -> Self {
-        Self { db }
+        Self {
+            db,
+            columns: FnvHashMap::default(),
+            blocking: BlockingPool::default(),
+        }
+    }
+
+    /// Store with the blocks in `db` and the other keyspaces in `columns`.
+    pub fn with_columns<I>(db: Arc<S>, columns: I) -> Self
+    where
+        I: IntoIterator<Item = (Column, Arc<S>)>,
+    {
+        Self {
+            columns: columns.into_iter().collect(),
+            ..Self::new(db)
+        }
     }
 
     pub fn blockstore(&self) -> &S {
         &self.db
     }
+
+    /// Keyspace of `column`.
+    pub fn column(&self, column: Column) -> &S {
+        self.columns.get(&column).unwrap_or(&self.db)
+    }
+
+    /// Run the store calls of `blocking` on `threads` threads with `queue_size` calls
+    /// queued at most.
+    pub fn with_blocking(mut self, threads: usize, queue_size: usize) -> Self {
+        self.blocking = BlockingPool::new(threads, queue_size);
+        self
+    }
+
+    /// Run `f` on a store thread, off the async executors.
+    pub async fn blocking<F, T>(self: &Arc<Self>, f: F) -> anyhow::Result<T>
+    where
+        F: FnOnce(&Arc<Self>) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let store = Arc::clone(self);
+        self.blocking.run(move || f(&store)).await
+    }
+
+    /// Visit every block of the dag under `root_cid` once, failing when one is missing.
+    fn traverse<F>(&self, root_cid: &Cid, mut visit: F) -> Result<()>
+    where
+        F: FnMut(Cid, Vec<u8>),
+    {
+        let mut current = FnvHashSet::default();
+        let mut refs = FnvHashSet::default();
+        current.insert(*root_cid);
+
+        while let Some(cid) = current.iter().next().copied() {
+            current.remove(&cid);
+            if refs.contains(&cid) {
+                continue;
+            }
+            match self.db.read(cid.to_bytes())? {
+                Some(data) => {
+                    let next_block = Block::<DefaultParams>::new(cid, data).unwrap();
+                    next_block.references(&mut current)?;
+                    // hand the data on, the block only held it to list its links
+                    let (cid, data) = next_block.into_inner();
+                    visit(cid, data);
+                    refs.insert(cid);
+                }
+                None => {
+                    // TODO: handle the case where parts of the dags are missing
+                    return Err(anyhow!(
+                        "Some of the cids for root is missing for the root {:?}",
+                        root_cid
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
 }
+
 pub struct BitswapStorage<P>(pub Arc<Store<P>>)
 where
     P: BlockStore + Sync + Send + 'static;
@@ -38,14 +120,32 @@ where
     }
 
     fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
-        Ok(self.0.db.read(cid.to_bytes()).unwrap())
+        let start = Instant::now();
+        let data = self.0.db.read(cid.to_bytes()).unwrap();
+        let labels = vec![Label::new("hit", data.is_some().to_string())];
+        track(
+            MetricEvent::StoreGet,
+            Some(labels),
+            Some(start.elapsed().as_secs_f64()),
+        );
+        Ok(data)
     }
 
     fn insert(&mut self, block: &Block<Self::Params>) -> Result<()> {
+        if let Err(err) = validate_block(block) {
+            warn!("Rejecting a block received over bitswap: {}", err);
+            return Err(err.into());
+        }
+        let start = Instant::now();
         self.0
             .db
             .write(&block.cid().to_bytes(), block.data())
             .unwrap();
+        track(
+            MetricEvent::StorePut,
+            None,
+            Some(start.elapsed().as_secs_f64()),
+        );
 
         Ok(())
     }
@@ -67,9 +167,51 @@ where
     }
 }
 
+/// Shape of a stored dag.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DagStat {
+    pub blocks: usize,
+    /// Bytes of all blocks.
+    pub size: u64,
+    /// Blocks on the longest path from the root down to a leaf, the root included.
+    pub depth: usize,
+    pub max_block_size: u64,
+}
+
+/// Damaged blocks of a stored dag.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DagVerification {
+    /// Blocks read and hashed again.
+    pub blocks: usize,
+    pub missing: Vec<Cid>,
+    /// Blocks whose data does not hash to their cid.
+    pub corrupt: Vec<Cid>,
+}
+
+impl DagVerification {
+    pub fn is_intact(&self) -> bool {
+        self.missing.is_empty() && self.corrupt.is_empty()
+    }
+}
+
 pub trait Dag {
     /// traverse a dag and get full dag given a root cid
     fn dag_traversal(&self, root_cid: &Cid) -> Result<Vec<(Cid, Vec<u8>)>>;
+
+    /// cids of a dag given a root cid, in traversal order, with the size of each block
+    /// instead of its data
+    fn dag_blocks(&self, root_cid: &Cid) -> Result<Vec<(Cid, usize)>>;
+
+    /// delete the stored blocks of a dag given a root cid, but those also under one of the
+    /// `retained` roots, returning how many were deleted
+    fn delete_dag(&self, root_cid: &Cid, retained: &[Cid]) -> Result<usize>;
+
+    /// block count, size and depth of a dag given a root cid, failing when a block is missing
+    fn dag_stat(&self, root_cid: &Cid) -> Result<DagStat>;
+
+    /// hash every stored block of a dag given a root cid again, walking past the missing
+    /// ones, and report the missing and corrupt blocks
+    fn verify_dag(&self, root_cid: &Cid) -> Result<DagVerification>;
 }
 
 impl<S> Dag for Store<S>
@@ -78,33 +220,124 @@ where
 {
     fn dag_traversal(&self, root_cid: &Cid) -> Result<Vec<(Cid, Vec<u8>)>> {
         let mut res = Vec::new();
-        // get full dag starting with root id
-        let mut current = FnvHashSet::default();
-        let mut refs = FnvHashSet::default();
-        current.insert(convert_cid::<Cid>(root_cid.to_bytes()));
+        self.traverse(root_cid, |cid, data| res.push((cid, data)))?;
+        Ok(res)
+    }
 
-        while let Some(cid) = current.iter().next().copied() {
-            current.remove(&cid);
-            if refs.contains(&cid) {
+    fn dag_blocks(&self, root_cid: &Cid) -> Result<Vec<(Cid, usize)>> {
+        let mut res = Vec::new();
+        // the data is dropped right away, so the listing stays small for large dags
+        self.traverse(root_cid, |cid, data| res.push((cid, data.len())))?;
+        Ok(res)
+    }
+
+    fn delete_dag(&self, root_cid: &Cid, retained: &[Cid]) -> Result<usize> {
+        // mark the stored blocks of the dag
+        let mut stack = vec![*root_cid];
+        let mut seen = FnvHashSet::default();
+        let mut blocks = FnvHashSet::default();
+        while let Some(cid) = stack.pop() {
+            if !seen.insert(cid) {
                 continue;
             }
-            match self.blockstore().get(&convert_cid(cid.to_bytes()))? {
-                Some(data) => {
-                    res.push((convert_cid(cid.to_bytes()), data.clone()));
-                    let next_block = Block::<DefaultParams>::new(cid, data).unwrap();
-                    let _action = next_block.references(&mut current)?;
-                    refs.insert(cid);
+            // blocks missing from a partial sync have nothing to delete
+            if let Some(data) = self.db.read(cid.to_bytes())? {
+                let block = Block::<DefaultParams>::new(cid, data)?;
+                block.references(&mut stack)?;
+                blocks.insert(cid);
+            }
+        }
+
+        // unmark those shared with the retained dags
+        let mut stack: Vec<Cid> = retained
+            .iter()
+            .filter(|cid| *cid != root_cid)
+            .copied()
+            .collect();
+        let mut seen = FnvHashSet::default();
+        while let Some(cid) = stack.pop() {
+            if blocks.is_empty() {
+                break;
+            }
+            if !seen.insert(cid) {
+                continue;
+            }
+            blocks.remove(&cid);
+            if let Some(data) = self.db.read(cid.to_bytes())? {
+                // only the links are read, a corrupt block still keeps what it links to
+                Block::<DefaultParams>::new_unchecked(cid, data).references(&mut stack)?;
+            }
+        }
+
+        for cid in &blocks {
+            self.db.delete(cid.to_bytes())?;
+        }
+        Ok(blocks.len())
+    }
+
+    fn dag_stat(&self, root_cid: &Cid) -> Result<DagStat> {
+        let mut stat = DagStat::default();
+        // links of the visited blocks, and the depth below each block once its links are done
+        let mut links: FnvHashMap<Cid, Vec<Cid>> = FnvHashMap::default();
+        let mut depths: FnvHashMap<Cid, usize> = FnvHashMap::default();
+        let mut stack = vec![*root_cid];
+
+        while let Some(&cid) = stack.last() {
+            if depths.contains_key(&cid) {
+                stack.pop();
+                continue;
+            }
+            match links.get(&cid) {
+                Some(children) => {
+                    let below = children.iter().filter_map(|c| depths.get(c)).max();
+                    depths.insert(cid, 1 + below.copied().unwrap_or(0));
+                    stack.pop();
                 }
                 None => {
-                    // TODO: handle the case where parts of the dags are missing
-                    return Err(anyhow!(
-                        "Some of the cids for root is missing for the root {:?}",
-                        root_cid
-                    ));
+                    let data = self.db.read(cid.to_bytes())?.ok_or_else(|| {
+                        anyhow!("The block {} of the dag {} is not stored", cid, root_cid)
+                    })?;
+                    stat.blocks += 1;
+                    stat.size += data.len() as u64;
+                    stat.max_block_size = stat.max_block_size.max(data.len() as u64);
+
+                    let block = Block::<DefaultParams>::new(cid, data)?;
+                    let mut children = FnvHashSet::default();
+                    block.references(&mut children)?;
+                    let children: Vec<Cid> = children.into_iter().collect();
+                    stack.extend(children.iter().filter(|c| !depths.contains_key(c)));
+                    links.insert(cid, children);
                 }
             }
         }
-        Ok(res)
+        stat.depth = depths[root_cid];
+        Ok(stat)
+    }
+
+    fn verify_dag(&self, root_cid: &Cid) -> Result<DagVerification> {
+        let mut verification = DagVerification::default();
+        let mut stack = vec![*root_cid];
+        let mut seen = FnvHashSet::default();
+
+        while let Some(cid) = stack.pop() {
+            if !seen.insert(cid) {
+                continue;
+            }
+            let data = match self.db.read(cid.to_bytes())? {
+                Some(data) => data,
+                None => {
+                    verification.missing.push(cid);
+                    continue;
+                }
+            };
+            verification.blocks += 1;
+            match Block::<DefaultParams>::new(cid, data) {
+                Ok(block) => block.references(&mut stack)?,
+                // the links of a corrupt block cannot be trusted, its children are not walked
+                Err(_) => verification.corrupt.push(cid),
+            }
+        }
+        Ok(verification)
     }
 }
 
@@ -119,6 +352,72 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_delete_dag() {
+        use libipld::{cbor::DagCborCodec, ipld, multihash::Code};
+
+        let db = Arc::new(
+            RocksDb::open("ursa_delete_db", &RocksDbConfig::default())
+                .expect("Opening RocksDB must succeed"),
+        );
+        let store = Store::new(Arc::clone(&db));
+
+        let leaf =
+            Block::<DefaultParams>::encode(DagCborCodec, Code::Blake3_256, &ipld!("leaf")).unwrap();
+        let root = Block::<DefaultParams>::encode(
+            DagCborCodec,
+            Code::Blake3_256,
+            &ipld!({ "leaf": *leaf.cid() }),
+        )
+        .unwrap();
+        for block in [&leaf, &root] {
+            db.write(block.cid().to_bytes(), block.data()).unwrap();
+        }
+
+        let stat = store.dag_stat(root.cid()).unwrap();
+        assert_eq!((stat.blocks, stat.depth), (2, 2));
+        assert_eq!(stat.size, (leaf.data().len() + root.data().len()) as u64);
+        assert_eq!(stat.max_block_size, root.data().len() as u64);
+
+        let mut blocks = store.dag_blocks(root.cid()).unwrap();
+        blocks.sort_by_key(|(_, size)| *size);
+        assert_eq!(
+            blocks,
+            vec![
+                (*leaf.cid(), leaf.data().len()),
+                (*root.cid(), root.data().len())
+            ]
+        );
+
+        assert!(store.verify_dag(root.cid()).unwrap().is_intact());
+        db.write(leaf.cid().to_bytes(), b"bit rot").unwrap();
+        let verification = store.verify_dag(root.cid()).unwrap();
+        assert_eq!(verification.blocks, 2);
+        assert_eq!(verification.corrupt, vec![*leaf.cid()]);
+        db.write(leaf.cid().to_bytes(), leaf.data()).unwrap();
+
+        // a leaf shared with another dag stays
+        let other = Block::<DefaultParams>::encode(
+            DagCborCodec,
+            Code::Blake3_256,
+            &ipld!({ "other": *leaf.cid() }),
+        )
+        .unwrap();
+        db.write(other.cid().to_bytes(), other.data()).unwrap();
+        assert_eq!(store.delete_dag(root.cid(), &[*other.cid()]).unwrap(), 1);
+        assert!(db.exists(leaf.cid().to_bytes()).unwrap());
+        assert!(!db.exists(root.cid().to_bytes()).unwrap());
+        db.write(root.cid().to_bytes(), root.data()).unwrap();
+
+        assert_eq!(store.delete_dag(root.cid(), &[*root.cid()]).unwrap(), 2);
+        assert!(!db.exists(leaf.cid().to_bytes()).unwrap());
+        assert_eq!(
+            store.verify_dag(root.cid()).unwrap().missing,
+            vec![*root.cid()]
+        );
+        assert_eq!(store.delete_dag(root.cid(), &[]).unwrap(), 0);
+    }
+
     #[async_std::test]
     async fn get_missing_blocks() {
         // SimpleLogger::new()
@@ -137,7 +436,7 @@ mod tests {
         let cid =
             Cid::from_str("bafybeihybv5apjuvkpaw62l34ui7t363pt3hwxbz7rltrpjklvzrbviq5m").unwrap();
 
-        if let Ok(res) = bitswap_store_1.missing_blocks(&convert_cid(cid.to_bytes())) {
+        if let Ok(res) = bitswap_store_1.missing_blocks(&cid) {
             println!("vec of missing blocks: {:?}", res);
         }
     }
@@ -0,0 +1,105 @@
+//! Validation of the blocks received over bitswap.
+//!
+//! Blocks are only persisted when their data hashes to their cid and fits in the
+//! maximum block size. Blocks that fail are not stored, and the insert fails so the
+//! network can penalize the peers that sent them.
+
+use libipld::{
+    store::{DefaultParams, StoreParams},
+    Block, Cid,
+};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidBlock {
+    /// The data does not hash to the cid.
+    HashMismatch(Cid),
+    /// The block is larger than the maximum block size.
+    TooLarge { cid: Cid, size: usize },
+}
+
+impl InvalidBlock {
+    pub fn cid(&self) -> &Cid {
+        match self {
+            InvalidBlock::HashMismatch(cid) | InvalidBlock::TooLarge { cid, .. } => cid,
+        }
+    }
+}
+
+impl fmt::Display for InvalidBlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidBlock::HashMismatch(cid) => {
+                write!(f, "The data of {cid} does not match its hash")
+            }
+            InvalidBlock::TooLarge { cid, size } => write!(
+                f,
+                "{cid} is {size} bytes, more than the maximum of {}",
+                DefaultParams::MAX_BLOCK_SIZE
+            ),
+        }
+    }
+}
+
+impl std::error::Error for InvalidBlock {}
+
+/// Check that `block` hashes to its cid and is not over the maximum block size.
+pub fn validate_block(block: &Block<DefaultParams>) -> Result<(), InvalidBlock> {
+    let cid = *block.cid();
+    let size = block.data().len();
+    if size > DefaultParams::MAX_BLOCK_SIZE {
+        return Err(InvalidBlock::TooLarge { cid, size });
+    }
+    Block::<DefaultParams>::new(cid, block.data().to_vec())
+        .map(|_| ())
+        .map_err(|_| InvalidBlock::HashMismatch(cid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libipld::{
+        cbor::DagCborCodec, ipld, json::DagJsonCodec, multihash::Code, raw::RawCodec, Ipld,
+        IpldCodec,
+    };
+
+    #[test]
+    fn test_validate_block() {
+        let block = Block::<DefaultParams>::encode(DagCborCodec, Code::Blake3_256, &ipld!("block"))
+            .unwrap();
+        assert_eq!(validate_block(&block), Ok(()));
+
+        let corrupt = Block::<DefaultParams>::new_unchecked(*block.cid(), b"corrupt".to_vec());
+        assert_eq!(
+            validate_block(&corrupt),
+            Err(InvalidBlock::HashMismatch(*block.cid()))
+        );
+
+        let size = DefaultParams::MAX_BLOCK_SIZE + 1;
+        let large = Block::<DefaultParams>::new_unchecked(*block.cid(), vec![0; size]);
+        assert_eq!(
+            validate_block(&large),
+            Err(InvalidBlock::TooLarge {
+                cid: *block.cid(),
+                size
+            })
+        );
+
+        // content from other ipfs tooling uses these codecs and hashes
+        for hash in [Code::Sha2_256, Code::Blake3_256] {
+            let blocks = [
+                Block::<DefaultParams>::encode(RawCodec, hash, &ipld!(Ipld::Bytes(vec![1]))),
+                Block::<DefaultParams>::encode(DagCborCodec, hash, &ipld!({ "a": 1 })),
+                Block::<DefaultParams>::encode(DagJsonCodec, hash, &ipld!({ "a": 1 })),
+                Block::<DefaultParams>::encode(
+                    IpldCodec::DagPb,
+                    hash,
+                    &ipld!({ "Data": Ipld::Bytes(vec![8, 2]), "Links": [] }),
+                ),
+            ];
+            for block in blocks {
+                assert_eq!(validate_block(&block.unwrap()), Ok(()));
+            }
+        }
+    }
+}
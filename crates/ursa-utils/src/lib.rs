@@ -1,8 +1,70 @@
-/// convert between different types of cids e.g libapld::Cid to cid::Cid
-pub fn convert_cid<T>(cid: Vec<u8>) -> T
-where
-    T: TryFrom<Vec<u8>>,
-    <T as TryFrom<Vec<u8>>>::Error: std::fmt::Debug,
-{
-    T::try_from(cid).unwrap()
+//! Cid conversions.
+//!
+//! Blocks, bitswap and the dag traversals use the cid of `libipld`, while the network
+//! commands, the car files, the rpc and the forest blockstore use the cid of the `cid`
+//! crate, a newer version of the same type. [`ToCid`] and [`ToIpldCid`] convert between
+//! the two by copying the codec and the digest, without encoding the cid to bytes and
+//! parsing it again.
+//!
+//! It also holds the hex and unix time helpers the other crates share.
+
+mod encoding;
+
+pub use encoding::{hex, unhex, unix_now};
+
+/// Cid of the `cid` crate.
+pub type Cid = cid::Cid;
+
+/// Cid of `libipld`.
+pub type IpldCid = libipld::Cid;
+
+pub trait ToCid {
+    fn to_cid(&self) -> Cid;
+}
+
+pub trait ToIpldCid {
+    fn to_ipld_cid(&self) -> IpldCid;
+}
+
+impl ToCid for IpldCid {
+    fn to_cid(&self) -> Cid {
+        let hash = cid::multihash::Multihash::wrap(self.hash().code(), self.hash().digest())
+            .expect("the digest of a cid fits a multihash");
+        match self.version() {
+            libipld::cid::Version::V0 => Cid::new_v0(hash),
+            libipld::cid::Version::V1 => Ok(Cid::new_v1(self.codec(), hash)),
+        }
+        .expect("a valid cid converts to a valid cid")
+    }
+}
+
+impl ToIpldCid for Cid {
+    fn to_ipld_cid(&self) -> IpldCid {
+        let hash = libipld::multihash::Multihash::wrap(self.hash().code(), self.hash().digest())
+            .expect("the digest of a cid fits a multihash");
+        match self.version() {
+            cid::Version::V0 => IpldCid::new_v0(hash),
+            cid::Version::V1 => Ok(IpldCid::new_v1(self.codec(), hash)),
+        }
+        .expect("a valid cid converts to a valid cid")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_cid_conversions() {
+        for cid in [
+            "QmdfTbBqBPQ7VNxZEYEj14VmRuZBkqFbiwReogJgS1zR1n",
+            "bafkreif2opfibjypwkjzzry3jbibcjqcjwnpoqpeiqw75eu3s3u3zbdszq",
+        ] {
+            let cid = Cid::from_str(cid).unwrap();
+            let ipld_cid = cid.to_ipld_cid();
+            assert_eq!(ipld_cid.to_bytes(), cid.to_bytes());
+            assert_eq!(ipld_cid.to_cid(), cid);
+        }
+    }
 }
@@ -3,6 +3,7 @@ use ursa_index_provider::config::ProviderConfig;
 use ursa_metrics::config::MetricsServiceConfig;
 use ursa_network::NetworkConfig;
 use ursa_rpc_server::config::ServerConfig;
+use ursa_store::config::DatabaseConfig;
 
 use std::{
     fs::{create_dir_all, File},
@@ -23,10 +24,101 @@ pub fn load_config(path: &PathBuf) -> Result<()> {
     Ok(())
 }
 
-#[derive(Default, Serialize, Deserialize, Debug)]
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
 pub struct UrsaConfig {
+    /// "error", "warn", "info", "debug" or "trace".
+    pub log_level: String,
     pub network_config: NetworkConfig,
     pub provider_config: ProviderConfig,
     pub metrics_config: MetricsServiceConfig,
     pub server_config: ServerConfig,
+    /// RocksDB options of the node and index provider databases.
+    pub database_config: DatabaseConfig,
+}
+
+impl Default for UrsaConfig {
+    fn default() -> Self {
+        Self {
+            log_level: default_log_level(),
+            network_config: Default::default(),
+            provider_config: Default::default(),
+            metrics_config: Default::default(),
+            server_config: Default::default(),
+            database_config: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Config written by the first releases, before any of the tables added since.
+    const BASELINE_CONFIG: &str = r#"
+[network_config]
+mdns = false
+relay_server = true
+autonat = true
+relay_client = true
+bootstrapper = false
+swarm_addr = "/ip4/0.0.0.0/tcp/6009"
+bootstrap_nodes = ["/ip4/159.223.211.234/tcp/6009/p2p/12D3KooWDji7xMLia6GAsyr4oiEFD2dd3zSryqNhfxU3Grzs1r9p", "/ip4/146.190.232.131/tcp/6009/p2p/12D3KooWGw8vCj9XayJDMXUiox6pCUFm7oVuWkDJeE2H9SDQVEcM"]
+database_path = "/home/ursa/.ursa/data/ursa_db"
+identity = "default"
+keystore_path = "/home/ursa/.ursa/keystore"
+
+[provider_config]
+local_address = "0.0.0.0"
+port = 8070
+domain = ""
+indexer_url = "https://dev.cid.contact"
+database_path = "/home/ursa/.ursa/data/index_provider_db"
+
+[metrics_config]
+port = "4070"
+api_path = "/metrics"
+
+[server_config]
+port = 4069
+addr = "0.0.0.0"
+"#;
+
+    #[test]
+    fn test_baseline_config() {
+        let config: UrsaConfig = toml::from_str(BASELINE_CONFIG).unwrap();
+        let defaults = UrsaConfig::default();
+
+        assert_eq!(
+            config.network_config,
+            NetworkConfig {
+                database_path: "/home/ursa/.ursa/data/ursa_db".into(),
+                keystore_path: "/home/ursa/.ursa/keystore".into(),
+                ..defaults.network_config
+            }
+        );
+        assert_eq!(
+            config.provider_config,
+            ProviderConfig {
+                database_path: "/home/ursa/.ursa/data/index_provider_db".into(),
+                ..defaults.provider_config
+            }
+        );
+        assert_eq!(config.metrics_config.port, "4070");
+        assert_eq!(config.server_config.port, 4069);
+        assert_eq!(
+            config.server_config.put_url.max_size,
+            defaults.server_config.put_url.max_size
+        );
+        assert_eq!(config.database_config, defaults.database_config);
+        assert_eq!(config.log_level, "info");
+
+        // and an empty one is the defaults
+        let config: UrsaConfig = toml::from_str("").unwrap();
+        assert_eq!(config.network_config, NetworkConfig::default());
+    }
 }
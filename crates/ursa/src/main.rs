@@ -3,28 +3,50 @@ extern crate core;
 mod config;
 mod ursa;
 
-use std::{path::PathBuf, sync::Arc};
+use std::{path::PathBuf, str::FromStr, sync::Arc, time::Duration};
 
 use crate::{
     config::{load_config, UrsaConfig, DEFAULT_CONFIG_PATH_STR},
     ursa::identity::IdentityManager,
 };
 use async_std::{sync::RwLock, task};
-use db::{rocks::RocksDb, rocks_config::RocksDbConfig};
 use dotenv::dotenv;
 use structopt::StructOpt;
-use tracing::{error, info};
-use ursa::{cli_error_and_die, wait_until_ctrlc, Cli, Subcommand};
-use ursa_index_provider::provider::Provider;
+use tracing::{error, info, warn};
+use tracing_subscriber::{filter::LevelFilter, fmt, prelude::*, reload};
+use ursa::{cli_error_and_die, doctor, wait_until_ctrlc, write_setting, Cli, Subcommand};
+use ursa_index_provider::{
+    provider::Provider,
+    signer::{RemoteSigner, Signer},
+};
 use ursa_metrics::metrics;
-use ursa_network::UrsaService;
-use ursa_rpc_server::{api::NodeNetworkInterface, server::Server};
-use ursa_store::Store;
+use ursa_network::{shaping::TrafficClass, UrsaService};
+use ursa_rpc_server::{
+    access_log::AccessLog,
+    api::NodeNetworkInterface,
+    diagnostics::Diagnostics,
+    origin::Origin,
+    server::Server,
+    settings::{self, Settings},
+    webhooks::Webhooks,
+};
+use ursa_store::{
+    columns::{Column, ColumnDb},
+    Store, DEFAULT_BLOCKING_QUEUE_SIZE, DEFAULT_BLOCKING_THREADS,
+};
+
+/// Time the node gets to connect to its peers before its self-check.
+const SELF_CHECK_DELAY: Duration = Duration::from_secs(60);
 
 #[async_std::main]
 async fn main() {
     dotenv().ok();
-    tracing_subscriber::fmt::init();
+    // the level is set from the config once it is read, and by ursa_config_set
+    let (log_filter, log_level) = reload::Layer::new(LevelFilter::INFO);
+    tracing_subscriber::registry()
+        .with(log_filter)
+        .with(fmt::layer())
+        .init();
     load_config(&PathBuf::from(env!("HOME")).join(DEFAULT_CONFIG_PATH_STR));
 
     // Capture Cli inputs
@@ -37,17 +59,27 @@ async fn main() {
                     Subcommand::Rpc(cmd) => {
                         cmd.run().await;
                     }
+                    Subcommand::Doctor => doctor().await,
+                    Subcommand::Key(cmd) => cmd.run(config).await,
                 }
             } else {
                 let UrsaConfig {
+                    log_level: level,
                     network_config,
                     provider_config,
                     metrics_config,
                     mut server_config,
+                    database_config,
                 } = config;
                 if opts.rpc_port.is_some() {
                     server_config.port = opts.rpc_port.unwrap();
                 }
+                match LevelFilter::from_str(&level) {
+                    Ok(filter) => {
+                        let _ = log_level.modify(|current| *current = filter);
+                    }
+                    Err(_) => warn!("Invalid log_level {level}, logging at info"),
+                }
 
                 let keystore_path = network_config.keystore_path.clone();
                 let im = match network_config.identity.clone().as_str() {
@@ -65,27 +97,84 @@ async fn main() {
 
                 info!("Using {:?} as database path", db_path);
 
-                let db = RocksDb::open(db_path, &RocksDbConfig::default())
-                    .expect("Opening RocksDB must succeed");
-                let db = Arc::new(db);
-                let store = Arc::new(Store::new(Arc::clone(&db)));
-
-                let provider_db_name = provider_config.database_path.clone();
-                let provider_db = RocksDb::open(provider_db_name, &RocksDbConfig::default())
+                let db = ColumnDb::open(db_path, &database_config)
                     .expect("Opening RocksDB must succeed");
-                let index_provider = Provider::new(
-                    keypair.clone(),
+                match db.migrate() {
+                    Ok(0) => {}
+                    Ok(keys) => info!("Moved {keys} keys into their column families"),
+                    Err(e) => error!("Failed to move keys into their column families: {e:?}"),
+                }
+                // the advertisements used to be kept in a database of their own
+                match db.import(Column::ProviderAds, &provider_config.database_path) {
+                    Ok(0) => {}
+                    Ok(keys) => info!("Imported {keys} keys of the index provider database"),
+                    Err(e) => error!("Failed to import the index provider database: {e:?}"),
+                }
+                let store = Arc::new(
+                    Store::with_columns(
+                        Arc::new(db.column(Column::Blocks)),
+                        [Column::Pins, Column::Peers, Column::Metadata]
+                            .map(|column| (column, Arc::new(db.column(column)))),
+                    )
+                    .with_blocking(
+                        database_config
+                            .blocking_threads
+                            .unwrap_or(DEFAULT_BLOCKING_THREADS),
+                        database_config
+                            .blocking_queue_size
+                            .unwrap_or(DEFAULT_BLOCKING_QUEUE_SIZE),
+                    ),
+                );
+                let provider_db = db.column(Column::ProviderAds);
+                // advertisements may be signed with a key of their own, so the network
+                // key can be rotated without breaking the ad chain
+                let provider_keypair = match provider_config.key_path.clone() {
+                    Some(path) => IdentityManager::load_or_new_file(path).current(),
+                    None => keypair.clone(),
+                };
+                let extended_keys = provider_config
+                    .extended_providers
+                    .iter()
+                    .map(|extended| {
+                        IdentityManager::load_or_new_file(extended.key_path.clone()).current()
+                    })
+                    .collect();
+                let mut index_provider = Provider::new(
+                    provider_keypair,
                     Arc::new(RwLock::new(provider_db)),
                     provider_config.clone(),
-                );
+                )
+                .with_extended_keys(extended_keys);
+                // or with a key kept out of the node, e.g. in an HSM
+                if let Some(signer) = provider_config.signer.clone() {
+                    match RemoteSigner::connect(signer).await {
+                        Ok(signer) => {
+                            info!("Signing advertisements as {}", signer.peer_id());
+                            index_provider = index_provider.with_signer(Arc::new(signer));
+                        }
+                        Err(e) => {
+                            cli_error_and_die(&format!("Cannot reach the remote signer: {e}"), 1)
+                        }
+                    }
+                }
+                if let Err(e) = index_provider.load_head().await {
+                    error!("Failed to load the ad chain, starting a new one: {e:?}");
+                }
 
-                let service = UrsaService::new(
+                let service = match UrsaService::new(
                     keypair,
                     &network_config,
                     Arc::clone(&store),
                     index_provider.clone(),
-                );
+                ) {
+                    Ok(service) => service,
+                    Err(e) => cli_error_and_die(&format!("{e:?}"), 1),
+                };
                 let rpc_sender = service.command_sender().clone();
+                let node_events = service.node_events();
+                let acl = service.acl();
+                let shaper = service.shaper();
+                let accounting = service.accounting();
 
                 // Start libp2p service
                 let service_task = task::spawn(async {
@@ -94,11 +183,116 @@ async fn main() {
                     }
                 });
 
-                let interface = Arc::new(NodeNetworkInterface {
-                    store,
-                    network_send: rpc_sender,
+                let webhooks = Arc::new(Webhooks::new(server_config.webhooks.clone()));
+                webhooks.watch(&node_events);
+
+                let config_path = opts.config_path();
+                let settings = Arc::new(
+                    Settings::new(server_config.admin_token.clone()).with_persist(
+                        move |key, value| {
+                            let value = if value.is_null() { None } else { Some(value) };
+                            write_setting(&config_path, key, value)
+                        },
+                    ),
+                );
+
+                let interface = Arc::new(
+                    NodeNetworkInterface::new(
+                        store,
+                        rpc_sender,
+                        Origin::new(server_config.origin.clone()),
+                        AccessLog::new(server_config.access_log.clone()),
+                        server_config.command_overflow,
+                        server_config.put_url.clone(),
+                    )
+                    .with_node_events(node_events)
+                    .with_webhooks(webhooks)
+                    .with_api_keys(server_config.api_keys.clone())
+                    .with_signed_urls(server_config.signed_urls.clone())
+                    .with_dnslink(server_config.dnslink.clone())
+                    .with_receipts(server_config.receipts.clone())
+                    .with_render_cache(server_config.render_cache.clone())
+                    .with_put_file(server_config.put_file.clone())
+                    .with_car_import(server_config.car_import.clone())
+                    .with_compaction(db.clone(), database_config.compaction_hour)
+                    .with_scrubbing(db.clone(), database_config.scrub_blocks_per_hour)
+                    .with_acl(acl)
+                    .with_shaper(shaper)
+                    .with_accounting(accounting)
+                    .with_settings(Arc::clone(&settings))
+                    .with_diagnostics(Diagnostics {
+                        swarm_addrs: network_config.swarm_addrs.clone(),
+                        bootstrap_nodes: network_config.bootstrap_nodes.clone(),
+                        bootstrapper: network_config.bootstrapper,
+                        database_path: network_config.database_path.clone(),
+                        indexer_urls: provider_config.indexer_url.clone(),
+                    }),
+                );
+
+                // values the admins can change without a restart
+                settings.register("log_level", level, move |value| {
+                    let filter = LevelFilter::from_str(&settings::parse::<String>(value)?)?;
+                    log_level.modify(|current| *current = filter)?;
+                    Ok(())
                 });
+                let caps = [
+                    ("global", None, network_config.bandwidth.global),
+                    (
+                        "bitswap",
+                        Some(TrafficClass::Bitswap),
+                        network_config.bandwidth.bitswap,
+                    ),
+                    (
+                        "gateway",
+                        Some(TrafficClass::Gateway),
+                        network_config.bandwidth.gateway,
+                    ),
+                    (
+                        "gossip",
+                        Some(TrafficClass::Gossip),
+                        network_config.bandwidth.gossip,
+                    ),
+                ];
+                // unset caps have no bucket to change
+                for (name, class, rate) in caps {
+                    if let Some(rate) = rate {
+                        let shaper = Arc::clone(&interface.shaper);
+                        let key = format!("network_config.bandwidth.{name}");
+                        settings.register(&key, rate, move |value| {
+                            match settings::parse::<u64>(value)? {
+                                0 => Err(anyhow::anyhow!("a cap of 0 stops the traffic")),
+                                rate => {
+                                    shaper.set_rate(class, rate);
+                                    Ok(())
+                                }
+                            }
+                        });
+                    }
+                }
+                let render_cache = Arc::clone(&interface.render_cache);
+                settings.register(
+                    "server_config.render_cache.max_bytes",
+                    render_cache.max_bytes(),
+                    move |value| {
+                        render_cache.set_max_bytes(settings::parse(value)?);
+                        Ok(())
+                    },
+                );
+                let provider = index_provider.clone();
+                settings.register(
+                    "provider_config.publish_batch_ms",
+                    provider_config.publish_batch_ms,
+                    move |value| {
+                        provider.set_batch_window(Duration::from_millis(settings::parse(value)?));
+                        Ok(())
+                    },
+                );
+
+                interface.watch_purges();
+                interface.self_check(SELF_CHECK_DELAY);
                 let server = Server::new(interface);
+                let server_handle = server.handle();
+                let shutdown_grace = Duration::from_millis(server_config.shutdown_grace_ms);
 
                 // Start multiplex server service(rpc and http)
                 let rpc_task = task::spawn(async move {
@@ -123,13 +317,18 @@ async fn main() {
 
                 wait_until_ctrlc();
 
-                // Gracefully shutdown node & rpc
-                task::spawn(async {
-                    rpc_task.cancel().await;
-                    service_task.cancel().await;
-                    metrics_task.cancel().await;
-                    provider_task.cancel().await;
-                });
+                // Stop taking requests and let the running downloads finish first
+                info!(
+                    "Shutting down, draining http requests for up to {:?}",
+                    shutdown_grace
+                );
+                server_handle.graceful_shutdown(Some(shutdown_grace));
+                rpc_task.await;
+
+                // Then the node and the other services
+                service_task.cancel().await;
+                metrics_task.cancel().await;
+                provider_task.cancel().await;
             }
         }
         Err(e) => {
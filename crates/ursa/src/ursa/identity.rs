@@ -1,14 +1,108 @@
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Nonce,
+};
+use argon2::Argon2;
 use libp2p::identity::Keypair;
 
 use libp2p::PeerId;
 use std::fs::create_dir_all;
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 use std::{
-    fs::{self, File},
-    io::{prelude::*, Result},
-    path::PathBuf,
+    env,
+    fs::{self, File, OpenOptions, Permissions},
+    io::{prelude::*, Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+    sync::Mutex,
 };
-use tracing::{error, info};
+use tracing::{error, info, warn};
+
+/// Environment variable holding the passphrase of the keystore.
+pub const PASSPHRASE_ENV: &str = "URSA_KEYSTORE_PASSPHRASE";
+
+/// PEM tag of the keys encrypted with a passphrase.
+const ENCRYPTED_TAG: &str = "URSA ENCRYPTED KEY";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// Passphrase read from the environment or prompted for, asked for once per run.
+static PASSPHRASE: Mutex<Option<String>> = Mutex::new(None);
+
+/// Passphrase of the keystore, from `URSA_KEYSTORE_PASSPHRASE` or, with `prompt`, typed
+/// in on the terminal. An empty passphrase leaves the keys unencrypted.
+pub fn passphrase(prompt: bool) -> Option<String> {
+    let mut cached = PASSPHRASE.lock().unwrap();
+    if cached.is_none() {
+        *cached = match env::var(PASSPHRASE_ENV) {
+            Ok(passphrase) => Some(passphrase),
+            Err(_) if prompt => match rpassword::prompt_password("Keystore passphrase: ") {
+                Ok(passphrase) => Some(passphrase),
+                Err(e) => {
+                    error!("Failed to read the keystore passphrase: {}", e);
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+    }
+    cached.clone().filter(|passphrase| !passphrase.is_empty())
+}
+
+fn invalid_data<E: ToString>(e: E) -> Error {
+    Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+/// Key derived from `passphrase` and `salt` with argon2.
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Aes256Gcm> {
+    let mut key = [0; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(invalid_data)?;
+    Aes256Gcm::new_from_slice(&key).map_err(invalid_data)
+}
+
+/// `der` encrypted with AES-GCM, prefixed by the salt and the nonce.
+fn encrypt(der: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    let mut salt = [0; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = derive_key(passphrase, &salt)?
+        .encrypt(&nonce, der)
+        .map_err(|_| invalid_data("Failed to encrypt the key"))?;
+    let mut contents = salt.to_vec();
+    contents.extend_from_slice(&nonce);
+    contents.extend(ciphertext);
+    Ok(contents)
+}
+
+fn decrypt(contents: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    if contents.len() < SALT_LEN + NONCE_LEN {
+        return Err(invalid_data("Encrypted key too short"));
+    }
+    let (salt, rest) = contents.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    derive_key(passphrase, salt)?
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| invalid_data("Wrong keystore passphrase or corrupt key"))
+}
+
+/// Ed25519 keypair of a pkcs#8 v2 document.
+fn decode_pkcs8(der: &[u8]) -> Result<Keypair> {
+    if der.len() != 85 {
+        error!("Invalid ed25519 pkcs#8 v2 key length (is the encoding correct?)");
+        return Err(invalid_data("Invalid ed25519 key length"));
+    }
+
+    let mut buf = [0; 64];
+    // private key - offset 16; 32bytes long
+    buf[..32].copy_from_slice(&der[16..48]);
+    // public key - offset 53; 32bytes long
+    buf[32..].copy_from_slice(&der[53..]);
+
+    Ok(Keypair::Ed25519(
+        libp2p::identity::ed25519::Keypair::decode(buf.as_mut()).map_err(invalid_data)?,
+    ))
+}
 
 pub trait Identity {
     fn id(&self) -> PeerId;
@@ -62,50 +156,68 @@ impl Identity for Keypair {
     }
 
     fn save(&self, path: &PathBuf) -> Result<()> {
-        let pem = self.encode_pem();
-        create_dir_all(path.parent().unwrap())?;
-        let mut file = File::create(path)?;
-        file.write_all(pem.as_bytes())?;
-        file.sync_all()?;
-        let mut perms = file.metadata()?.permissions();
-        perms.set_mode(0o600);
-        fs::set_permissions(path, perms)?;
-        Ok(())
+        write_key(self, path, passphrase(false))
     }
 
     fn load(path: &PathBuf) -> Result<Self>
     where
         Self: Sized,
     {
-        // read the file
-        let mut file = File::open(path)?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-
-        let parsed = pem::parse(contents).unwrap();
-        let keypair = match parsed.tag.as_str() {
-            // PEM encoded ed25519 key
-            "PRIVATE KEY" => {
-                if parsed.contents.len() != 85 {
-                    error!("Invalid ed25519 pkcs#8 v2 key length (is the encoding correct?)");
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        "Invalid ed25519 key length",
-                    ));
-                }
+        read_key(path, || passphrase(true))
+    }
+}
 
-                let mut buf = [0; 64];
-                // private key - offset 16; 32bytes long
-                buf[..32].copy_from_slice(&parsed.contents[16..48]);
-                // public key - offset 53; 32bytes long
-                buf[32..].copy_from_slice(&parsed.contents[53..]);
+/// Write `keypair` to `path`, encrypted with `passphrase` when there is one.
+fn write_key(keypair: &Keypair, path: &Path, passphrase: Option<String>) -> Result<()> {
+    let mut pem = keypair.encode_pem();
+    match passphrase {
+        Some(passphrase) => {
+            let der = pem::parse(&pem).map_err(invalid_data)?.contents;
+            pem = pem::encode(&pem::Pem {
+                tag: ENCRYPTED_TAG.to_string(),
+                contents: encrypt(&der, &passphrase)?,
+            });
+        }
+        None => warn!(
+            "Saving {:?} unencrypted, set {} to encrypt the keystore",
+            path, PASSPHRASE_ENV
+        ),
+    }
+    create_dir_all(path.parent().unwrap())?;
+    // created readable by the owner only, and narrowed before writing when it existed
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.set_permissions(Permissions::from_mode(0o600))?;
+    file.write_all(pem.as_bytes())?;
+    file.sync_all()?;
+    Ok(())
+}
 
-                Keypair::Ed25519(libp2p::identity::ed25519::Keypair::decode(buf.as_mut()).unwrap())
-            }
-            _ => panic!("Unsupported key type"),
-        };
+/// Read the key at `path`, asking `passphrase` for the passphrase when it is encrypted.
+fn read_key(path: &Path, passphrase: impl FnOnce() -> Option<String>) -> Result<Keypair> {
+    let mut file = File::open(path)?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
 
-        Ok(keypair)
+    let parsed = pem::parse(contents).map_err(invalid_data)?;
+    match parsed.tag.as_str() {
+        // PEM encoded ed25519 key
+        "PRIVATE KEY" => decode_pkcs8(&parsed.contents),
+        // the same, encrypted with the keystore passphrase
+        ENCRYPTED_TAG => {
+            let passphrase = passphrase().ok_or_else(|| {
+                Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("{:?} is encrypted, set {}", path, PASSPHRASE_ENV),
+                )
+            })?;
+            decode_pkcs8(&decrypt(&parsed.contents, &passphrase)?)
+        }
+        _ => panic!("Unsupported key type"),
     }
 }
 
@@ -175,14 +287,207 @@ impl IdentityManager<Keypair> {
     /// Load or create a new identity
     pub fn load_or_new<S: Into<String> + Clone>(name: S, dir: PathBuf) -> Self {
         let name = name.into();
+        let mut path = dir.join(&name);
+        path.set_extension("pem");
         Self::load(name.clone(), dir.clone()).unwrap_or_else(|| {
+            // a key that fails to load must not be replaced
+            if path.exists() {
+                error!("Cannot load identity `{}` from {:?}", name, path);
+                std::process::exit(1);
+            }
             // if not found
             let im = Self::new(name, dir);
             im
         })
     }
 
+    /// Load the identity stored at `path`, creating it if there is none
+    pub fn load_or_new_file(path: PathBuf) -> Self {
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let dir = path.parent().map(PathBuf::from).unwrap_or_default();
+
+        let identity = match Keypair::load(&path) {
+            Ok(identity) => {
+                info!("Loaded identity `{}` ({})", name, identity.id());
+                identity
+            }
+            Err(e) if path.exists() => {
+                error!("Cannot load identity `{}` from {:?}: {}", name, path, e);
+                std::process::exit(1);
+            }
+            Err(_) => {
+                let identity = Keypair::generate_ed25519();
+                identity.save(&path).unwrap();
+                info!("Created identity `{}` ({})", name, identity.id());
+                identity
+            }
+        };
+
+        Self {
+            name,
+            identity,
+            dir,
+        }
+    }
+
     pub fn current(&self) -> Keypair {
         self.identity.clone()
     }
+
+    /// Path of the key file of the identity.
+    pub fn path(&self) -> PathBuf {
+        let mut path = self.dir.join(&self.name);
+        path.set_extension("pem");
+        path
+    }
+
+    /// Where the next key is kept between [`Self::stage_rotation`] and
+    /// [`Self::commit_rotation`].
+    fn staged_path(&self) -> PathBuf {
+        let mut path = self.dir.join(&self.name);
+        path.set_extension("pem.next");
+        path
+    }
+
+    /// Generate and save the key the identity is rotated to, leaving the current one
+    /// in use until [`Self::commit_rotation`].
+    pub fn stage_rotation(&self) -> Result<Keypair> {
+        let next = Keypair::generate_ed25519();
+        next.save(&self.staged_path())?;
+        Ok(next)
+    }
+
+    /// Drop the key of [`Self::stage_rotation`], keeping the current one.
+    pub fn abort_rotation(&self) -> Result<()> {
+        fs::remove_file(self.staged_path())
+    }
+
+    /// Replace the identity by the key of [`Self::stage_rotation`], returning the
+    /// retired one.
+    ///
+    /// The retired key is kept as `retired/<name>-<peer id>.pem` next to the identity,
+    /// so the signatures it made can still be checked.
+    pub fn commit_rotation(&mut self, next: Keypair) -> Result<Keypair> {
+        let path = self.path();
+        let previous = self.identity.clone();
+        let retired = retired_path(&self.dir, &self.name, &previous.id());
+        create_dir_all(retired.parent().unwrap())?;
+        fs::copy(&path, &retired)?;
+        info!(
+            "Retired identity `{}` ({}) to {:?}",
+            self.name,
+            previous.id(),
+            retired
+        );
+
+        fs::rename(self.staged_path(), &path)?;
+        self.identity = next;
+        info!("Rotated identity `{}` to {}", self.name, self.identity.id());
+        Ok(previous)
+    }
+}
+
+/// Where the key of `peer_id` is kept once `name` is rotated away from it.
+pub fn retired_path(dir: &Path, name: &str, peer_id: &PeerId) -> PathBuf {
+    dir.join("retired")
+        .join(format!("{}-{}.pem", name, peer_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_path(name: &str) -> PathBuf {
+        let path = env::temp_dir()
+            .join("ursa_identity_tests")
+            .join(format!("{}.pem", name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_encrypted_key() {
+        let path = key_path("encrypted");
+        let keypair = Keypair::generate_ed25519();
+        write_key(&keypair, &path, Some("secret".to_string())).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(pem::parse(&contents).unwrap().tag, ENCRYPTED_TAG);
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        let loaded = read_key(&path, || Some("secret".to_string())).unwrap();
+        assert_eq!(loaded.id(), keypair.id());
+
+        let wrong = read_key(&path, || Some("wrong".to_string())).unwrap_err();
+        assert_eq!(wrong.kind(), ErrorKind::InvalidData);
+        let missing = read_key(&path, || None).unwrap_err();
+        assert_eq!(missing.kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_unencrypted_key() {
+        let path = key_path("unencrypted");
+        let keypair = Keypair::generate_ed25519();
+        write_key(&keypair, &path, None).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(pem::parse(&contents).unwrap().tag, "PRIVATE KEY");
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        // no passphrase is asked for
+        let loaded = read_key(&path, || panic!("asked for a passphrase")).unwrap();
+        assert_eq!(loaded.id(), keypair.id());
+    }
+
+    #[test]
+    fn test_staged_rotation() {
+        let dir = env::temp_dir().join("ursa_identity_tests").join("rotation");
+        let _ = fs::remove_dir_all(&dir);
+        let mut im = IdentityManager::new("node", dir.clone());
+        let current = im.current().id();
+
+        // an aborted rotation keeps the current key
+        im.stage_rotation().unwrap();
+        im.abort_rotation().unwrap();
+        let loaded = IdentityManager::load("node", dir.clone()).unwrap();
+        assert_eq!(loaded.current().id(), current);
+
+        let next = im.stage_rotation().unwrap();
+        assert_eq!(
+            IdentityManager::load("node", dir.clone())
+                .unwrap()
+                .current()
+                .id(),
+            current
+        );
+        let previous = im.commit_rotation(next.clone()).unwrap();
+        assert_eq!(previous.id(), current);
+        assert_eq!(im.current().id(), next.id());
+        let loaded = IdentityManager::load("node", dir.clone()).unwrap();
+        assert_eq!(loaded.current().id(), next.id());
+        assert!(retired_path(&dir, "node", &current).exists());
+    }
+
+    #[test]
+    fn test_overwritten_key_narrowed() {
+        let path = key_path("overwritten");
+        create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+
+        write_key(&Keypair::generate_ed25519(), &path, None).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+    }
 }
@@ -0,0 +1,126 @@
+use async_std::sync::RwLock;
+use std::{process, sync::Arc};
+use structopt::StructOpt;
+use tracing::{error, info};
+use ursa_index_provider::provider::Provider;
+use ursa_store::columns::{Column, ColumnDb};
+
+use crate::{
+    config::UrsaConfig,
+    ursa::identity::{passphrase, Identity, IdentityManager},
+};
+
+#[derive(Debug, StructOpt)]
+pub enum KeyCommands {
+    #[structopt(
+        about = "replace the node identity by a new key and republish the ads under it, with the node stopped"
+    )]
+    Rotate {
+        #[structopt(long, about = "Rotate the key of provider_config.key_path instead")]
+        provider: bool,
+    },
+}
+
+impl KeyCommands {
+    pub async fn run(&self, config: UrsaConfig) {
+        match self {
+            Self::Rotate { provider } => rotate(config, *provider).await,
+        }
+    }
+}
+
+async fn rotate(config: UrsaConfig, provider: bool) {
+    let UrsaConfig {
+        network_config,
+        provider_config,
+        database_config,
+        ..
+    } = config;
+    let remote = provider_config.signer.is_some();
+    if remote && provider {
+        error!("The provider key is held by provider_config.signer, rotate it there");
+        process::exit(1);
+    }
+    // the ads are signed by the network identity unless they have a key of their own
+    let (mut im, signs_ads) = match (&provider_config.key_path, provider) {
+        (Some(path), true) => (IdentityManager::load_or_new_file(path.clone()), true),
+        (None, true) => {
+            error!("provider_config.key_path is unset, the ads are signed by the network identity");
+            process::exit(1);
+        }
+        (key_path, false) if network_config.identity != "random" => {
+            match IdentityManager::load(
+                network_config.identity.clone(),
+                network_config.keystore_path.clone(),
+            ) {
+                Some(im) => (im, key_path.is_none() && !remote),
+                None => process::exit(1),
+            }
+        }
+        _ => {
+            error!("The random identity is not kept, there is no key to rotate");
+            process::exit(1);
+        }
+    };
+    // prompted for when the old key is unencrypted, empty saves the new one unencrypted too
+    passphrase(true);
+
+    // a running node holds the database
+    let db = match signs_ads {
+        true => match ColumnDb::open(&network_config.database_path, &database_config) {
+            Ok(db) => Some(db),
+            Err(e) => {
+                error!("Cannot open the database, is the node stopped? {:?}", e);
+                process::exit(1);
+            }
+        },
+        false => None,
+    };
+
+    // the current key stays in use until the ads are republished under the next one
+    let next = match im.stage_rotation() {
+        Ok(next) => next,
+        Err(e) => {
+            error!("Failed to rotate `{}`: {}", im.name, e);
+            process::exit(1);
+        }
+    };
+
+    if let Some(db) = db {
+        let provider = Provider::new(
+            next.clone(),
+            Arc::new(RwLock::new(db.column(Column::ProviderAds))),
+            provider_config,
+        );
+        let republished = match provider.load_head().await {
+            Ok(Some(_)) => provider.republish(&im.current()).await,
+            Ok(None) => Ok(None),
+            Err(e) => Err(e),
+        };
+        match republished {
+            Ok(Some(head)) => info!(
+                "Republished the ads under {}, head {}, announced on the next start",
+                next.id(),
+                head
+            ),
+            Ok(None) => info!("No ads to republish"),
+            Err(e) => {
+                error!(
+                    "Failed to republish the ads under {}, keeping the key {}: {:?}",
+                    next.id(),
+                    im.identity.id(),
+                    e
+                );
+                if let Err(e) = im.abort_rotation() {
+                    error!("Failed to remove the unused key of `{}`: {}", im.name, e);
+                }
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Err(e) = im.commit_rotation(next) {
+        error!("Failed to rotate `{}`: {}", im.name, e);
+        process::exit(1);
+    }
+}
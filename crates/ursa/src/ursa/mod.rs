@@ -1,11 +1,16 @@
+mod key_commands;
 mod rpc_commands;
 
 use crate::config::{UrsaConfig, DEFAULT_CONFIG_PATH_STR};
+use key_commands::KeyCommands;
 use rpc_commands::RpcCommands;
+
+pub use rpc_commands::doctor;
+use serde::Serialize;
 use std::{
     cell::RefCell,
-    fs::File,
-    io::{prelude::*, Result},
+    fs::{self, File},
+    io::{prelude::*, Error, ErrorKind, Result},
     path::{Path, PathBuf},
     process,
     sync::{
@@ -40,6 +45,13 @@ pub struct Cli {
 pub enum Subcommand {
     #[structopt(name = "rpc", about = "run rpc commands from cli")]
     Rpc(RpcCommands),
+    #[structopt(
+        name = "doctor",
+        about = "check the ports, NAT, peers, disk, store, clock and indexers of the running node"
+    )]
+    Doctor,
+    #[structopt(name = "key", about = "manage the keys of the node")]
+    Key(KeyCommands),
 }
 
 /// CLI options
@@ -54,20 +66,31 @@ pub struct CliOpts {
 }
 
 impl CliOpts {
+    /// The config file given on the command line, or the default one.
+    pub fn config_path(&self) -> PathBuf {
+        match &self.config {
+            Some(config_file) => PathBuf::from(config_file),
+            None => PathBuf::from(env!("HOME")).join(DEFAULT_CONFIG_PATH_STR),
+        }
+    }
+
     pub fn to_config(&self) -> Result<UrsaConfig> {
-        let mut path = PathBuf::from(env!("HOME")).join(DEFAULT_CONFIG_PATH_STR);
+        let path = self.config_path();
         if let Some(config_file) = &self.config {
             info!(
                 "Reading configuration from user provided config file {}",
                 config_file
             );
-            path = PathBuf::from(&config_file);
         }
 
         // Read from config file
         let toml = read_file_to_string(&path).unwrap();
         // Parse and return the configuration file
         let toml_str: UrsaConfig = toml::from_str(&toml).unwrap();
+        toml_str
+            .network_config
+            .validate()
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
 
         Ok(toml_str)
     }
@@ -87,6 +110,35 @@ pub fn read_file_to_string(path: &Path) -> Result<String> {
     Ok(string)
 }
 
+/// Write `value` at the dotted `key` of the config file at `path`, removing the key
+/// when there is no value. The other values are kept, the comments of the file are
+/// not.
+pub fn write_setting<V: Serialize>(path: &Path, key: &str, value: Option<V>) -> anyhow::Result<()> {
+    let mut config: toml::Value = toml::from_str(&fs::read_to_string(path)?)?;
+    let mut table = config
+        .as_table_mut()
+        .ok_or_else(|| anyhow::anyhow!("{path:?} is not a toml table"))?;
+    let mut parts: Vec<&str> = key.split('.').collect();
+    let last = parts.pop().unwrap_or(key);
+    for part in parts {
+        table = table
+            .entry(part.to_string())
+            .or_insert_with(|| toml::Value::Table(Default::default()))
+            .as_table_mut()
+            .ok_or_else(|| anyhow::anyhow!("{part} of {key} is not a table"))?;
+    }
+    match value {
+        Some(value) => {
+            table.insert(last.to_string(), toml::Value::try_from(value)?);
+        }
+        None => {
+            table.remove(last);
+        }
+    }
+    fs::write(path, toml::to_string(&config)?)?;
+    Ok(())
+}
+
 pub fn wait_until_ctrlc() {
     let running = Arc::new(AtomicBool::new(true));
     let r = running.clone();
@@ -1,14 +1,55 @@
+use std::process;
 use structopt::StructOpt;
-use tracing::{error, info};
-use ursa_rpc_client::functions::{get_file, put_file};
-use ursa_rpc_server::api::{NetworkGetFileParams, NetworkPutFileParams};
+use tracing::{error, info, warn};
+use ursa_network::accounting::{to_csv, UsageKind};
+use ursa_rpc_client::functions::{
+    accounting, acl_list, acl_remove, acl_set, api_key_usage, cancel, config_get, config_set,
+    create_api_key, dag_stat, diagnostics, get_file, list_content, name_publish, name_resolve,
+    operation_status, peer_protocols, prefetch, prefetch_status, purge, put_file, put_url,
+    relay_circuits, remove, repo_compact, resolve, revoke_api_key, set_context, sign_url,
+    topic_peers, unindex_context, verify,
+};
+use ursa_rpc_server::api::{
+    GetFileFormat, NetworkAccountingParams, NetworkAclListParams, NetworkAclRemoveParams,
+    NetworkAclSetParams, NetworkApiKeyUsageParams, NetworkCancelParams, NetworkConfigGetParams,
+    NetworkConfigSetParams, NetworkCreateApiKeyParams, NetworkDagStatParams,
+    NetworkDiagnosticsParams, NetworkGetFileParams, NetworkListContentParams,
+    NetworkNamePublishParams, NetworkNameResolveParams, NetworkOperationStatusParams,
+    NetworkPeerProtocolsParams, NetworkPrefetchParams, NetworkPrefetchStatusParams,
+    NetworkPurgeParams, NetworkPutFileParams, NetworkPutUrlParams, NetworkRelayCircuitsParams,
+    NetworkRemoveParams, NetworkRepoCompactParams, NetworkResolveParams, NetworkRevokeApiKeyParams,
+    NetworkSetContextParams, NetworkSignUrlParams, NetworkTopicPeersParams,
+    NetworkUnindexContextParams, NetworkVerifyParams, PutUrlFormat,
+};
+use ursa_rpc_server::content::ContentFilter;
+use ursa_rpc_server::diagnostics::CheckStatus;
 
 #[derive(Debug, StructOpt)]
 pub enum RpcCommands {
-    #[structopt(about = "put the file on the node")]
+    #[structopt(
+        about = "put a car file, or a raw file or directory chunked into a UnixFS dag, on the node"
+    )]
     Put {
-        #[structopt(about = "The path to the file")]
+        #[structopt(about = "The path to the file or directory")]
         path: String,
+        #[structopt(long, about = "Return the operation id without waiting for the put")]
+        background: bool,
+        #[structopt(
+            long = "ignore",
+            about = "Glob of the entries left out of a directory, e.g. '*.tmp' or 'drafts/*'"
+        )]
+        ignore: Vec<String>,
+        #[structopt(long, about = "Api key the stored bytes count against")]
+        api_key: Option<String>,
+    },
+    #[structopt(about = "download a car or raw file from a url on the node and put it")]
+    PutUrl {
+        #[structopt(about = "The http(s) url of the content")]
+        url: String,
+        #[structopt(long, about = "Hex sha2-256 checksum of the content")]
+        sha256: Option<String>,
+        #[structopt(long, about = "Api key the stored bytes count against")]
+        api_key: Option<String>,
     },
     #[structopt(
         about = "get the file from network for a given root cid and store it on given path"
@@ -18,31 +59,323 @@ pub enum RpcCommands {
         cid: String,
         #[structopt(about = "The path to sotre the file")]
         path: String,
+        #[structopt(long, about = "Return the operation id without waiting for the get")]
+        background: bool,
+        #[structopt(
+            long,
+            about = "Write the bytes of the UnixFS file under the cid instead of a car file"
+        )]
+        raw: bool,
+    },
+    #[structopt(about = "sync the content under the given root cids in the background")]
+    Prefetch {
+        #[structopt(about = "root cids to prefetch")]
+        cids: Vec<String>,
+        #[structopt(
+            long = "provider",
+            about = "/p2p address of a peer holding the content"
+        )]
+        providers: Vec<String>,
+        #[structopt(long, about = "Api key the prefetched bytes count against")]
+        api_key: Option<String>,
+    },
+    #[structopt(about = "show the progress of prefetched root cids")]
+    PrefetchStatus {
+        #[structopt(about = "root cids to report on, all when empty")]
+        cids: Vec<String>,
+    },
+    #[structopt(about = "delete the content under the given root cids from the node")]
+    Remove {
+        #[structopt(about = "root cids to remove")]
+        cids: Vec<String>,
+        #[structopt(long, about = "admin_token of the server config")]
+        token: String,
+    },
+    #[structopt(about = "advertise root cids under a context id, to withdraw them together")]
+    SetContext {
+        #[structopt(about = "Context id, a cid or hex bytes")]
+        context_id: String,
+        #[structopt(about = "root cids to group")]
+        cids: Vec<String>,
+        #[structopt(long, about = "admin_token of the server config")]
+        token: String,
+    },
+    #[structopt(about = "stop advertising the root cids of a context id, keeping their content")]
+    UnindexContext {
+        #[structopt(about = "Context id, a cid or hex bytes")]
+        context_id: String,
+        #[structopt(long, about = "admin_token of the server config")]
+        token: String,
+    },
+    #[structopt(
+        about = "evict content network-wide on the nodes obeying purges signed by this node"
+    )]
+    Purge {
+        #[structopt(about = "root cids to purge")]
+        cids: Vec<String>,
+        #[structopt(long = "context-id", about = "Context id whose content is purged")]
+        context_ids: Vec<String>,
+        #[structopt(long, about = "admin_token of the server config")]
+        token: String,
+    },
+    #[structopt(about = "show the progress of a put, get or prefetch operation")]
+    Operation {
+        #[structopt(about = "The operation id")]
+        id: u64,
+    },
+    #[structopt(about = "cancel a running get or prefetch operation")]
+    Cancel {
+        #[structopt(about = "The operation id")]
+        id: u64,
+    },
+    #[structopt(about = "create an api key for uploads and prefetches")]
+    CreateApiKey {
+        #[structopt(about = "Name of the tenant")]
+        name: String,
+        #[structopt(long, about = "Bytes the key may upload and prefetch")]
+        quota_bytes: Option<u64>,
+        #[structopt(long, about = "Requests per minute the key may make")]
+        rate_limit: Option<u32>,
+        #[structopt(long, about = "admin_token of the server config")]
+        token: String,
+    },
+    #[structopt(about = "revoke an api key")]
+    RevokeApiKey {
+        #[structopt(about = "The api key id")]
+        id: String,
+        #[structopt(long, about = "admin_token of the server config")]
+        token: String,
+    },
+    #[structopt(about = "show the usage of an api key, of every key when unset")]
+    ApiKeyUsage {
+        #[structopt(about = "The api key id")]
+        id: Option<String>,
+        #[structopt(long, about = "admin_token of the server config")]
+        token: String,
+    },
+    #[structopt(about = "show the bytes served per api key, peer and cid")]
+    Accounting {
+        #[structopt(long, about = "Only api_key, peer or cid rollups")]
+        kind: Option<UsageKind>,
+        #[structopt(long, about = "Only rollups of this api key id, peer id or cid")]
+        id: Option<String>,
+        #[structopt(long, about = "Only rollups starting at or after this unix time")]
+        since: Option<u64>,
+        #[structopt(long, about = "Only rollups starting before this unix time")]
+        until: Option<u64>,
+        #[structopt(long, about = "Print the rollups as csv")]
+        csv: bool,
+        #[structopt(long, about = "admin_token of the server config")]
+        token: String,
+    },
+    #[structopt(about = "sign a retrieval url of the given root cid")]
+    SignUrl {
+        #[structopt(about = "root cid of the content")]
+        cid: String,
+        #[structopt(long, about = "Seconds the url is valid for")]
+        ttl_secs: Option<u64>,
+        #[structopt(long, about = "admin_token of the server config")]
+        token: String,
+    },
+    #[structopt(about = "make the content under the given root cid private")]
+    AclSet {
+        #[structopt(about = "root cid of the content")]
+        cid: String,
+        #[structopt(long = "peer", about = "Peer id allowed to pull the content")]
+        peers: Vec<String>,
+        #[structopt(
+            long = "api-key",
+            about = "Id of an api key allowed to retrieve the content"
+        )]
+        api_keys: Vec<String>,
+        #[structopt(long, about = "admin_token of the server config")]
+        token: String,
+    },
+    #[structopt(about = "make the content under the given root cid public again")]
+    AclRemove {
+        #[structopt(about = "root cid of the content")]
+        cid: String,
+        #[structopt(long, about = "admin_token of the server config")]
+        token: String,
+    },
+    #[structopt(about = "list the private content")]
+    AclList {
+        #[structopt(long, about = "admin_token of the server config")]
+        token: String,
+    },
+    #[structopt(about = "list the circuits relayed by the node")]
+    RelayCircuits,
+    #[structopt(about = "list the subscribers of the gossip topics")]
+    TopicPeers {
+        #[structopt(about = "only list this topic")]
+        topic: Option<String>,
+    },
+    #[structopt(about = "count the agents and protocols of the connected peers")]
+    PeerProtocols {
+        #[structopt(long, about = "list the peers speaking this protocol")]
+        protocol: Option<String>,
+    },
+    #[structopt(about = "list the content stored on the node")]
+    ListContent {
+        #[structopt(
+            long,
+            about = "Start after this cursor, printed with the previous page"
+        )]
+        cursor: Option<String>,
+        #[structopt(long, about = "Entries per page")]
+        limit: Option<usize>,
+        #[structopt(long, about = "Only pinned content")]
+        pinned: bool,
+        #[structopt(long, about = "Only cached content", conflicts_with = "pinned")]
+        cached: bool,
+        #[structopt(long, about = "Only content advertised under this context id")]
+        context_id: Option<String>,
+        #[structopt(long, about = "Only content retrieved since this unix time in seconds")]
+        accessed_since: Option<u64>,
+        #[structopt(long, about = "Api key allowed to see private content")]
+        api_key: Option<String>,
+    },
+    #[structopt(about = "report the block count, size and depth of a dag")]
+    DagStat {
+        #[structopt(about = "root cid of the dag")]
+        cid: String,
+        #[structopt(long, about = "Fetch the dag when it is not stored on the node")]
+        fetch: bool,
+        #[structopt(long, about = "Api key the fetched bytes count against")]
+        api_key: Option<String>,
+    },
+    #[structopt(about = "hash the stored blocks of a dag again and report the damaged ones")]
+    Verify {
+        #[structopt(about = "root cid of the dag")]
+        cid: String,
+        #[structopt(long, about = "Fetch the missing and corrupt blocks from the network")]
+        repair: bool,
+        #[structopt(
+            long,
+            about = "admin_token of the server config, required with --repair"
+        )]
+        token: Option<String>,
+    },
+    #[structopt(about = "resolve an ipld path to the cid it points at")]
+    Resolve {
+        #[structopt(about = "root cid the path starts at")]
+        cid: String,
+        #[structopt(about = "map keys, list indices and UnixFS names separated by /")]
+        path: String,
+    },
+    #[structopt(about = "point the name of this node at a cid")]
+    NamePublish {
+        #[structopt(about = "root cid the name points at")]
+        cid: String,
+        #[structopt(long, about = "Seconds the record stays valid, 48 hours by default")]
+        ttl_secs: Option<u64>,
+        #[structopt(long, about = "admin_token of the server config")]
+        token: String,
+    },
+    #[structopt(about = "find the cid a name points at")]
+    NameResolve {
+        #[structopt(about = "peer id of the publisher")]
+        name: String,
+    },
+    #[structopt(about = "compact the database to reclaim the space of evicted content")]
+    RepoCompact {
+        #[structopt(
+            about = "column families to compact: blocks, pins, peerstore, provider_ads or metadata, all when empty"
+        )]
+        columns: Vec<String>,
+        #[structopt(long, about = "admin_token of the server config")]
+        token: String,
+    },
+    #[structopt(about = "show the settings that can be changed while the node runs")]
+    ConfigGet {
+        #[structopt(about = "dotted paths of the settings, all when empty")]
+        keys: Vec<String>,
+        #[structopt(long, about = "admin_token of the server config")]
+        token: String,
+    },
+    #[structopt(about = "change a setting of the running node and write it to its config file")]
+    ConfigSet {
+        #[structopt(about = "dotted path of the setting, e.g. network_config.bandwidth.gateway")]
+        key: String,
+        #[structopt(about = "new value, parsed as a toml value or taken as a string")]
+        value: String,
+        #[structopt(long, about = "admin_token of the server config")]
+        token: String,
     },
 }
 
+/// `raw` as a toml value, a string when it does not parse as one.
+fn setting_value(raw: &str) -> toml::Value {
+    toml::from_str::<toml::value::Table>(&format!("value = {raw}"))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(raw.to_string()))
+}
+
 impl RpcCommands {
     pub async fn run(&self) {
         match self {
-            Self::Put { path } => {
+            Self::Put {
+                path,
+                background,
+                ignore,
+                api_key,
+            } => {
                 let params = NetworkPutFileParams {
                     path: path.to_string(),
+                    background: *background,
+                    ignore: ignore.clone(),
+                    api_key: api_key.clone(),
                 };
                 match put_file(params).await {
                     Ok(v) => {
-                        info!("Put car file done: {v:?}");
+                        info!("Put file done: {v:?}");
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                };
+            }
+            Self::PutUrl {
+                url,
+                sha256,
+                api_key,
+            } => {
+                let params = NetworkPutUrlParams {
+                    url: url.to_string(),
+                    format: PutUrlFormat::Auto,
+                    sha256: sha256.clone(),
+                    api_key: api_key.clone(),
+                };
+                match put_url(params).await {
+                    Ok(v) => {
+                        info!("Put url done: {v:?}");
                     }
                     Err(_e) => {
                         error!("There was an error while calling the rpc server. Please Check Server Logs")
                     }
                 };
             }
-            Self::Get { cid, path } => {
+            Self::Get {
+                cid,
+                path,
+                background,
+                raw,
+            } => {
                 let params = NetworkGetFileParams {
                     path: path.to_string(),
                     cid: cid.to_string(),
+                    background: *background,
+                    format: if *raw {
+                        GetFileFormat::Raw
+                    } else {
+                        GetFileFormat::Car
+                    },
                 };
                 match get_file(params).await {
+                    Ok(result) if *background => {
+                        info!("Get started as operation {}", result.operation);
+                    }
                     Ok(_result) => {
                         info!("file stored at {path:?}");
                     }
@@ -51,6 +384,579 @@ impl RpcCommands {
                     }
                 };
             }
+            Self::Prefetch {
+                cids,
+                providers,
+                api_key,
+            } => {
+                let params = NetworkPrefetchParams {
+                    cids: cids.clone(),
+                    providers: providers.clone(),
+                    api_key: api_key.clone(),
+                };
+                match prefetch(params).await {
+                    Ok(result) => {
+                        info!(
+                            "Prefetch queued for {:?} as operation {}",
+                            result.cids, result.operation
+                        );
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                };
+            }
+            Self::PrefetchStatus { cids } => {
+                let params = NetworkPrefetchStatusParams { cids: cids.clone() };
+                match prefetch_status(params).await {
+                    Ok(progress) => {
+                        for p in progress {
+                            info!("{}: {:?}", p.cid, p.status);
+                        }
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                };
+            }
+            Self::Remove { cids, token } => {
+                let params = NetworkRemoveParams {
+                    cids: cids.clone(),
+                    token: Some(token.clone()),
+                };
+                match remove(params).await {
+                    Ok(removed) => {
+                        info!("Removed {removed:?}");
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                };
+            }
+            Self::SetContext {
+                context_id,
+                cids,
+                token,
+            } => {
+                let params = NetworkSetContextParams {
+                    cids: cids.clone(),
+                    context_id: context_id.clone(),
+                    token: Some(token.clone()),
+                };
+                match set_context(params).await {
+                    Ok(()) => {
+                        info!("Advertising {cids:?} under {context_id}");
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                };
+            }
+            Self::UnindexContext { context_id, token } => {
+                let params = NetworkUnindexContextParams {
+                    context_id: context_id.clone(),
+                    token: Some(token.clone()),
+                };
+                match unindex_context(params).await {
+                    Ok(roots) => {
+                        info!("No longer advertising {roots:?}");
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                };
+            }
+            Self::Purge {
+                cids,
+                context_ids,
+                token,
+            } => {
+                let params = NetworkPurgeParams {
+                    cids: cids.clone(),
+                    context_ids: context_ids.clone(),
+                    token: Some(token.clone()),
+                };
+                match purge(params).await {
+                    Ok(purge) => {
+                        info!(
+                            "Gossiped a purge of {:?} and contexts {:?} signed by {}",
+                            purge.cids, purge.context_ids, purge.public_key
+                        );
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                };
+            }
+            Self::Operation { id } => {
+                let params = NetworkOperationStatusParams { id: *id };
+                match operation_status(params).await {
+                    Ok(status) => {
+                        info!("Operation {id}: {status:?}");
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                };
+            }
+            Self::Cancel { id } => {
+                let params = NetworkCancelParams { id: *id };
+                match cancel(params).await {
+                    Ok(true) => {
+                        info!("Operation {id} cancelled");
+                    }
+                    Ok(false) => {
+                        info!("Operation {id} is not a running get or prefetch");
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                };
+            }
+            Self::CreateApiKey {
+                name,
+                quota_bytes,
+                rate_limit,
+                token,
+            } => {
+                let params = NetworkCreateApiKeyParams {
+                    name: name.to_string(),
+                    quota_bytes: *quota_bytes,
+                    rate_limit: *rate_limit,
+                    token: Some(token.clone()),
+                };
+                match create_api_key(params).await {
+                    Ok(created) => {
+                        info!(
+                            "Created api key {}, its secret is {}",
+                            created.id, created.key
+                        );
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                };
+            }
+            Self::RevokeApiKey { id, token } => {
+                let params = NetworkRevokeApiKeyParams {
+                    id: id.to_string(),
+                    token: Some(token.clone()),
+                };
+                match revoke_api_key(params).await {
+                    Ok(true) => {
+                        info!("Revoked api key {id}");
+                    }
+                    Ok(false) => {
+                        error!("No api key with id {id}");
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                };
+            }
+            Self::ApiKeyUsage { id, token } => {
+                let params = NetworkApiKeyUsageParams {
+                    id: id.clone(),
+                    token: Some(token.clone()),
+                };
+                match api_key_usage(params).await {
+                    Ok(usage) => {
+                        for key in usage {
+                            info!("{key:?}");
+                        }
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                };
+            }
+            Self::Accounting {
+                kind,
+                id,
+                since,
+                until,
+                csv,
+                token,
+            } => {
+                let params = NetworkAccountingParams {
+                    token: Some(token.clone()),
+                    kind: *kind,
+                    id: id.clone(),
+                    since: *since,
+                    until: *until,
+                };
+                match accounting(params).await {
+                    Ok(rollups) if *csv => {
+                        for line in to_csv(&rollups).lines() {
+                            info!("{line}");
+                        }
+                    }
+                    Ok(rollups) => {
+                        for rollup in rollups {
+                            info!("{rollup:?}");
+                        }
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                };
+            }
+            Self::SignUrl {
+                cid,
+                ttl_secs,
+                token,
+            } => {
+                let params = NetworkSignUrlParams {
+                    cid: cid.to_string(),
+                    ttl_secs: *ttl_secs,
+                    token: Some(token.clone()),
+                };
+                match sign_url(params).await {
+                    Ok(url) => {
+                        info!("Signed url: {}", url.path);
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                };
+            }
+            Self::AclSet {
+                cid,
+                peers,
+                api_keys,
+                token,
+            } => {
+                let params = NetworkAclSetParams {
+                    cid: cid.to_string(),
+                    peers: peers.clone(),
+                    api_keys: api_keys.clone(),
+                    token: Some(token.clone()),
+                };
+                match acl_set(params).await {
+                    Ok(entry) => {
+                        info!("{cid} is private: {entry:?}");
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                };
+            }
+            Self::AclRemove { cid, token } => {
+                let params = NetworkAclRemoveParams {
+                    cid: cid.to_string(),
+                    token: Some(token.clone()),
+                };
+                match acl_remove(params).await {
+                    Ok(true) => {
+                        info!("{cid} is public again");
+                    }
+                    Ok(false) => {
+                        info!("{cid} was not private");
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                };
+            }
+            Self::AclList { token } => {
+                let params = NetworkAclListParams {
+                    token: Some(token.clone()),
+                };
+                match acl_list(params).await {
+                    Ok(entries) => {
+                        for entry in entries {
+                            info!("{entry:?}");
+                        }
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                }
+            }
+            Self::RelayCircuits => {
+                match relay_circuits(NetworkRelayCircuitsParams {}).await {
+                    Ok(circuits) => {
+                        for circuit in circuits {
+                            info!(
+                                "{} -> {} open for {}s of {}s",
+                                circuit.src_peer_id,
+                                circuit.dst_peer_id,
+                                circuit.duration_secs,
+                                circuit.max_duration_secs
+                            );
+                        }
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                }
+            }
+            Self::TopicPeers { topic } => {
+                let params = NetworkTopicPeersParams {
+                    topic: topic.clone(),
+                };
+                match topic_peers(params).await {
+                    Ok(topics) => {
+                        for topic in topics {
+                            info!(
+                                "{}: {} subscribers, {} mesh peers{}{}",
+                                topic.topic,
+                                topic.peers.len(),
+                                topic.mesh_peers,
+                                if topic.subscribed { ", subscribed" } else { "" },
+                                if topic.publishable {
+                                    ""
+                                } else {
+                                    ", too few peers to publish"
+                                }
+                            );
+                            for peer in topic.peers {
+                                info!("  {peer}");
+                            }
+                        }
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                }
+            }
+            Self::PeerProtocols { protocol } => {
+                let params = NetworkPeerProtocolsParams {
+                    protocol: protocol.clone(),
+                };
+                match peer_protocols(params).await {
+                    Ok(inventory) => {
+                        info!("{} identified peers", inventory.identified);
+                        for (agent, peers) in &inventory.agents {
+                            info!("{peers:>6} {agent}");
+                        }
+                        for (name, peers) in &inventory.protocols {
+                            info!("{peers:>6} {name}");
+                        }
+                        if protocol.is_some() {
+                            for peer in &inventory.peers {
+                                info!("{} {}", peer.peer_id, peer.agent_version);
+                            }
+                        }
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                }
+            }
+            Self::ListContent {
+                cursor,
+                limit,
+                pinned,
+                cached,
+                context_id,
+                accessed_since,
+                api_key,
+            } => {
+                let params = NetworkListContentParams {
+                    cursor: cursor.clone(),
+                    limit: *limit,
+                    api_key: api_key.clone(),
+                    filter: ContentFilter {
+                        pinned: match (pinned, cached) {
+                            (true, _) => Some(true),
+                            (_, true) => Some(false),
+                            _ => None,
+                        },
+                        context_id: context_id.clone(),
+                        accessed_since: *accessed_since,
+                    },
+                };
+                match list_content(params).await {
+                    Ok(page) => {
+                        for entry in page.items {
+                            info!("{entry:?}");
+                        }
+                        if let Some(cursor) = page.next_cursor {
+                            info!("More content after --cursor {cursor}");
+                        }
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                }
+            }
+            Self::DagStat {
+                cid,
+                fetch,
+                api_key,
+            } => {
+                let params = NetworkDagStatParams {
+                    cid: cid.to_string(),
+                    fetch: *fetch,
+                    api_key: api_key.clone(),
+                };
+                match dag_stat(params).await {
+                    Ok(stat) => {
+                        info!("{cid}: {stat:?}");
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                }
+            }
+            Self::Verify { cid, repair, token } => {
+                let params = NetworkVerifyParams {
+                    cid: cid.to_string(),
+                    repair: *repair,
+                    token: token.clone(),
+                };
+                match verify(params).await {
+                    Ok(report) if report.missing.is_empty() && report.corrupt.is_empty() => {
+                        info!("{cid}: all {} blocks verified", report.blocks);
+                    }
+                    Ok(report) => {
+                        info!(
+                            "{cid}: {} blocks read, missing {:?}, corrupt {:?}",
+                            report.blocks, report.missing, report.corrupt
+                        );
+                        if *repair {
+                            info!("repaired: {}", report.repaired);
+                        }
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                }
+            }
+            Self::Resolve { cid, path } => {
+                let params = NetworkResolveParams {
+                    cid: cid.to_string(),
+                    path: path.to_string(),
+                };
+                match resolve(params).await {
+                    Ok(resolved) if resolved.remaining_path.is_empty() => {
+                        info!("{}", resolved.cid);
+                    }
+                    Ok(resolved) => {
+                        info!("{}/{}", resolved.cid, resolved.remaining_path);
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                }
+            }
+            Self::NamePublish {
+                cid,
+                ttl_secs,
+                token,
+            } => {
+                let params = NetworkNamePublishParams {
+                    cid: cid.to_string(),
+                    ttl_secs: *ttl_secs,
+                    token: Some(token.clone()),
+                };
+                match name_publish(params).await {
+                    Ok(record) => {
+                        info!(
+                            "{} -> {} (sequence {})",
+                            record.name, record.cid, record.sequence
+                        );
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                }
+            }
+            Self::NameResolve { name } => {
+                let params = NetworkNameResolveParams {
+                    name: name.to_string(),
+                };
+                match name_resolve(params).await {
+                    Ok(record) => {
+                        info!("{}", record.cid);
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                }
+            }
+            Self::RepoCompact { columns, token } => {
+                let params = NetworkRepoCompactParams {
+                    columns: columns.clone(),
+                    token: Some(token.clone()),
+                };
+                match repo_compact(params).await {
+                    Ok(id) => {
+                        info!("Compacting, see ursa rpc operation {id}");
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                }
+            }
+            Self::ConfigGet { keys, token } => {
+                let params = NetworkConfigGetParams {
+                    token: Some(token.clone()),
+                    keys: keys.clone(),
+                };
+                match config_get(params).await {
+                    Ok(settings) => {
+                        for (key, value) in settings {
+                            info!("{key} = {value}");
+                        }
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                }
+            }
+            Self::ConfigSet { key, value, token } => {
+                let value = match setting_value(value).try_into() {
+                    Ok(value) => value,
+                    Err(e) => {
+                        error!("Invalid value {value}: {e}");
+                        return;
+                    }
+                };
+                let params = NetworkConfigSetParams {
+                    token: Some(token.clone()),
+                    key: key.clone(),
+                    value,
+                };
+                match config_set(params).await {
+                    Ok(change) if change.persisted => {
+                        info!("{}: {} -> {}", change.key, change.previous, change.value);
+                    }
+                    Ok(change) => {
+                        info!(
+                            "{}: {} -> {}, not written to the config file",
+                            change.key, change.previous, change.value
+                        );
+                    }
+                    Err(_e) => {
+                        error!("There was an error while calling the rpc server. Please Check Server Logs")
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Run the self-check of the node, exiting with 1 when a check failed.
+pub async fn doctor() {
+    match diagnostics(NetworkDiagnosticsParams {}).await {
+        Ok(report) => {
+            for check in &report.checks {
+                match check.status {
+                    CheckStatus::Pass => info!("[pass] {}: {}", check.name, check.message),
+                    CheckStatus::Skip => info!("[skip] {}: {}", check.name, check.message),
+                    CheckStatus::Warn => warn!("[warn] {}: {}", check.name, check.message),
+                    CheckStatus::Fail => error!("[fail] {}: {}", check.name, check.message),
+                }
+            }
+            if !report.ok {
+                process::exit(1);
+            }
+        }
+        Err(_e) => {
+            error!("There was an error while calling the rpc server. Please Check Server Logs");
+            process::exit(1);
         }
     }
 }